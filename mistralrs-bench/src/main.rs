@@ -2,7 +2,8 @@ use candle_core::Device;
 use clap::Parser;
 use cli_table::{format::Justify, print_stdout, Cell, CellStruct, Style, Table};
 use mistralrs_core::{
-    initialize_logging, Constraint, DeviceLayerMapMetadata, DeviceMapMetadata, Loader,
+    get_attention_implementation, initialize_logging, set_attention_implementation,
+    AttentionImplementation, Constraint, DeviceLayerMapMetadata, DeviceMapMetadata, Loader,
     LoaderBuilder, MistralRs, MistralRsBuilder, ModelDType, ModelSelected, NormalRequest, Request,
     RequestMessage, Response, SamplingParams, SchedulerMethod, TokenSource, Usage,
 };
@@ -55,13 +56,27 @@ fn run_bench(
         temperature: Some(0.1),
         top_k: Some(32),
         top_p: Some(0.1),
+        top_n_sigma: None,
+        mirostat_tau: None,
+        mirostat_eta: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
         max_len: Some(n_gen),
+        max_time: None,
         stop_toks: None,
+        stop_token_ids: None,
+        eos_token_ids: None,
+        min_tokens: None,
         logits_bias: None,
         n_choices: 1,
+        num_beams: None,
+        length_penalty: None,
+        penalty_alpha: None,
+        repetition_penalty: None,
+        repetition_penalty_range: None,
+        no_repeat_ngram_size: None,
+        seed: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -76,6 +91,9 @@ fn run_bench(
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        lora_scale: None,
+        priority: 0,
+        tools: None,
     });
 
     let mut usages = Vec::new();
@@ -98,6 +116,9 @@ fn run_bench(
                     Response::ValidationError(e) => {
                         unreachable!("Got a validation error: {e:?}");
                     }
+                    Response::Busy(e) => {
+                        unreachable!("The engine is busy: {e:?}");
+                    }
                     Response::Done(res) => {
                         usages.push(res.usage);
                     }
@@ -106,6 +127,9 @@ fn run_bench(
                     Response::CompletionDone(res) => {
                         usages.push(res.usage);
                     }
+                    Response::Embeddings(_) => unreachable!(),
+                    Response::Tokenized(_) => unreachable!(),
+                    Response::Detokenized(_) => unreachable!(),
                 },
                 None => unreachable!("Expected a Done response, got None",),
             }
@@ -213,13 +237,27 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         temperature: Some(0.1),
         top_k: Some(32),
         top_p: Some(0.1),
+        top_n_sigma: None,
+        mirostat_tau: None,
+        mirostat_eta: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
         max_len: Some(5),
+        max_time: None,
         stop_toks: None,
+        stop_token_ids: None,
+        eos_token_ids: None,
+        min_tokens: None,
         logits_bias: None,
         n_choices: 1,
+        num_beams: None,
+        length_penalty: None,
+        penalty_alpha: None,
+        repetition_penalty: None,
+        repetition_penalty_range: None,
+        no_repeat_ngram_size: None,
+        seed: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -238,6 +276,9 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        lora_scale: None,
+        priority: 0,
+        tools: None,
     });
 
     sender
@@ -273,8 +314,19 @@ struct Args {
     /// Number of device layers to load and run on GPU(s). All others will be on the CPU.
     /// If one GPU is used, then this value should be an integer. Otherwise, it follows the following pattern:
     /// ORD:NUM;... Where ORD is a unique device ordinal and NUM is the number of layers for that device.
-    #[arg(short, long, value_parser, value_delimiter = ';')]
+    /// The activations are copied across devices at the boundaries between the layers in each forward pass.
+    #[arg(
+        short,
+        long,
+        visible_alias = "num-gpu-layers",
+        value_parser,
+        value_delimiter = ';'
+    )]
     num_device_layers: Option<Vec<String>>,
+
+    /// Attention implementation: `flash` or `eager`. Defaults to `flash` when built with the `flash-attn` feature.
+    #[arg(long)]
+    attention_impl: Option<AttentionImplementation>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -288,6 +340,10 @@ fn main() -> anyhow::Result<()> {
     #[cfg(feature = "flash-attn")]
     let use_flash_attn = true;
 
+    if let Some(attention_impl) = args.attention_impl {
+        set_attention_implementation(attention_impl)?;
+    }
+
     let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
         .with_use_flash_attn(use_flash_attn)
         .build()?;
@@ -307,7 +363,7 @@ fn main() -> anyhow::Result<()> {
         candle_core::utils::with_f16c()
     );
     info!("Sampling method: penalties -> temperature -> topk -> topp -> multinomial");
-    if use_flash_attn {
+    if use_flash_attn && get_attention_implementation() == AttentionImplementation::Flash {
         info!("Using flash attention.");
     }
     if use_flash_attn && loader.get_kind().is_quantized() {
@@ -373,7 +429,7 @@ fn main() -> anyhow::Result<()> {
     )
     .with_no_prefix_cache(true)
     .with_disable_eos_stop(true)
-    .build();
+    .build()?;
 
     info!("Starting warmup run.");
     warmup_run(mistralrs.clone());
@@ -62,24 +62,34 @@ impl ByteTokenizer {
             // DecoderWrapper::Sequence() doesn't let one access the decoders
             // so we resort to json munching
             let v = serde_json::to_value(d).unwrap();
-            if v["type"].as_str() == Some("ByteLevel") {
-                is_byte_level = true;
-            } else if v["type"].as_str() == Some("Sequence") {
-                if let Some(decoders) = v["decoders"].as_array() {
-                    for decoder in decoders {
-                        if decoder["type"].as_str() == Some("ByteFallback") {
-                            is_byte_fallback = true;
-                        } else if decoder["type"].as_str() == Some("Replace")
-                            && decoder["content"].as_str() == Some(" ")
-                        {
-                            if let Some(s) = decoder["pattern"]["String"].as_str() {
-                                let s: Vec<char> = s.chars().collect();
-                                if s.len() == 1 {
-                                    space_ch = s[0];
-                                }
+            let decoders = match v["type"].as_str() {
+                Some("Sequence") => v["decoders"].as_array().cloned().unwrap_or_default(),
+                _ => vec![v],
+            };
+            for decoder in decoders {
+                match decoder["type"].as_str() {
+                    // Byte-level BPE maps every byte to a printable character
+                    Some("ByteLevel") => is_byte_level = true,
+                    // SentencePiece tokens are text, with `<0xXX>` tokens for the bytes of rare characters
+                    Some("ByteFallback") => is_byte_fallback = true,
+                    Some("Metaspace") => {
+                        is_byte_fallback = true;
+                        if let Some(s) = decoder["replacement"].as_str() {
+                            let s: Vec<char> = s.chars().collect();
+                            if s.len() == 1 {
+                                space_ch = s[0];
+                            }
+                        }
+                    }
+                    Some("Replace") if decoder["content"].as_str() == Some(" ") => {
+                        if let Some(s) = decoder["pattern"]["String"].as_str() {
+                            let s: Vec<char> = s.chars().collect();
+                            if s.len() == 1 {
+                                space_ch = s[0];
                             }
                         }
                     }
+                    _ => {}
                 }
             }
         }
@@ -179,3 +189,49 @@ pub(crate) fn build_tok_trie(tokenizer: Tokenizer) -> TokTrie {
     let bt = ByteTokenizer::from_tokenizer(tokenizer).unwrap();
     TokTrie::from(&bt.tokrx_info(), &bt.token_bytes())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokenizers::{
+        decoders::{byte_level::ByteLevel, metaspace::Metaspace, sequence::Sequence},
+        models::wordlevel::WordLevel,
+        DecoderWrapper, Tokenizer,
+    };
+
+    use super::build_tok_trie;
+
+    fn tokenizer(tokens: &[&str], decoder: DecoderWrapper) -> Tokenizer {
+        let vocab = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, tok)| (tok.to_string(), i as u32))
+            .collect::<HashMap<_, _>>();
+        let mut tokenizer = Tokenizer::new(WordLevel::builder().vocab(vocab).build().unwrap());
+        tokenizer.with_decoder(decoder);
+        tokenizer
+    }
+
+    #[test]
+    fn tokens_decode_to_their_bytes() {
+        // "你" is E4 BD A0, and byte-level BPE maps A0 to 'ł'
+        let byte_level = tokenizer(
+            &["ä½", "ł", "Ġhi"],
+            DecoderWrapper::Sequence(Sequence::new(vec![DecoderWrapper::ByteLevel(
+                ByteLevel::default(),
+            )])),
+        );
+        let trie = build_tok_trie(byte_level);
+        assert_eq!(trie.token(0), &[0xE4, 0xBD]);
+        assert_eq!(trie.decode(&[0, 1]), "你".as_bytes());
+        assert_eq!(trie.decode(&[2]), b" hi");
+
+        let metaspace = tokenizer(
+            &["<0xE4>", "<0xBD>", "<0xA0>", "▁hi"],
+            DecoderWrapper::Metaspace(Metaspace::default()),
+        );
+        let trie = build_tok_trie(metaspace);
+        assert_eq!(trie.decode(&[3, 0, 1, 2]), " hi你".as_bytes());
+    }
+}
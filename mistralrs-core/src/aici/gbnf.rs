@@ -0,0 +1,621 @@
+//! Translation of llama.cpp style GBNF grammars into the yacc grammars understood by [`CfgParser`].
+//!
+//! GBNF has no separate lexer, so every character of the grammar is its own lexeme. The character sets used by the
+//! grammar (literal characters, classes and `.`) are split into disjoint atoms, each of which becomes one regex
+//! token over its UTF-8 bytes, so the lexer never has to choose between overlapping tokens. The parser is LR(1), so
+//! ambiguous grammars are resolved the yacc way (preferring shifts) instead of exploring every parse.
+//!
+//! As [`CfgParser`] works on bytes, a model token may span several grammar characters or end in the middle of a
+//! multi-byte character.
+//!
+//! [`CfgParser`]: super::cfg::CfgParser
+
+use std::{collections::HashMap, iter::Peekable, str::Chars};
+
+use anyhow::{bail, Context, Result};
+
+const MAX_CHAR: u32 = 0x10FFFF;
+
+/// Sorted, non-overlapping and non-adjacent inclusive ranges of scalar values.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CharSet(Vec<(u32, u32)>);
+
+impl CharSet {
+    fn new(mut ranges: Vec<(u32, u32)>) -> Self {
+        ranges.sort_unstable();
+        let mut merged: Vec<(u32, u32)> = Vec::new();
+        for (lo, hi) in ranges {
+            match merged.last_mut() {
+                Some((_, last_hi)) if lo <= last_hi.saturating_add(1) => {
+                    *last_hi = (*last_hi).max(hi);
+                }
+                _ => merged.push((lo, hi)),
+            }
+        }
+        Self(merged)
+    }
+
+    fn complement(&self) -> Self {
+        let mut ranges = Vec::new();
+        let mut next = 0;
+        for (lo, hi) in &self.0 {
+            if *lo > next {
+                ranges.push((next, lo - 1));
+            }
+            next = hi + 1;
+        }
+        if next <= MAX_CHAR {
+            ranges.push((next, MAX_CHAR));
+        }
+        Self(ranges)
+    }
+
+    fn contains(&self, c: u32) -> bool {
+        self.0.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c))
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Seq(Vec<Expr>),
+    Alt(Vec<Expr>),
+    Chars(CharSet),
+    Rule(String),
+    /// Repeat between `min` and `max` (unbounded if `None`) times.
+    Repeat(Box<Expr>, usize, Option<usize>),
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    src: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_space(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if c == '#' {
+                while self.chars.next_if(|c| *c != '\n').is_some() {}
+            } else if c.is_whitespace() {
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn name(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        {
+            name.push(c);
+        }
+        name
+    }
+
+    /// Whether the next item is the start of a new rule, `name ::=`.
+    fn at_rule_start(&self) -> bool {
+        let mut chars = self.chars.clone();
+        if chars
+            .next_if(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .is_none()
+        {
+            return false;
+        }
+        while chars
+            .next_if(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .is_some()
+        {}
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        chars.take(3).eq("::=".chars())
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        self.skip_space();
+        for expected in s.chars() {
+            match self.chars.next() {
+                Some(c) if c == expected => {}
+                Some(c) => bail!("Expected `{s}` in GBNF grammar, found `{c}`."),
+                None => bail!("Expected `{s}` in GBNF grammar, found the end of the grammar."),
+            }
+        }
+        Ok(())
+    }
+
+    fn rules(&mut self) -> Result<Vec<(String, Expr)>> {
+        let mut rules = Vec::new();
+        self.skip_space();
+        while self.chars.peek().is_some() {
+            let name = self.name();
+            if name.is_empty() {
+                bail!(
+                    "Expected a rule name in GBNF grammar at `{}`.",
+                    self.remaining()
+                );
+            }
+            self.expect("::=")?;
+            rules.push((name, self.alternatives()?));
+            self.skip_space();
+        }
+        Ok(rules)
+    }
+
+    fn remaining(&self) -> String {
+        self.chars.clone().take(20).collect()
+    }
+
+    fn alternatives(&mut self) -> Result<Expr> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.chars.next_if_eq(&'|').is_some() {
+            alternatives.push(self.sequence()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Expr::Alt(alternatives)
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Expr> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_space();
+            let item = match self.chars.peek().copied() {
+                None | Some('|') | Some(')') => break,
+                Some(_) if self.at_rule_start() => break,
+                Some('"') => {
+                    self.chars.next();
+                    let mut chars = Vec::new();
+                    loop {
+                        match self.chars.next() {
+                            Some('"') => break,
+                            Some('\\') => chars.push(self.escape()?),
+                            Some(c) => chars.push(c as u32),
+                            None => bail!("Unterminated string literal in GBNF grammar."),
+                        }
+                    }
+                    Expr::Seq(
+                        chars
+                            .into_iter()
+                            .map(|c| Expr::Chars(CharSet::new(vec![(c, c)])))
+                            .collect(),
+                    )
+                }
+                Some('[') => {
+                    self.chars.next();
+                    Expr::Chars(self.class()?)
+                }
+                Some('.') => {
+                    self.chars.next();
+                    Expr::Chars(CharSet::new(vec![(0, MAX_CHAR)]))
+                }
+                Some('(') => {
+                    self.chars.next();
+                    let inner = self.alternatives()?;
+                    self.expect(")")?;
+                    inner
+                }
+                Some(_) => {
+                    let name = self.name();
+                    if name.is_empty() {
+                        bail!(
+                            "Unexpected input in GBNF grammar at `{}`.",
+                            self.remaining()
+                        );
+                    }
+                    Expr::Rule(name)
+                }
+            };
+            items.push(self.repetition(item)?);
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Expr::Seq(items)
+        })
+    }
+
+    fn repetition(&mut self, mut item: Expr) -> Result<Expr> {
+        loop {
+            let (min, max) = match self.chars.peek().copied() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.chars.next();
+                    let min = self.number()?;
+                    let max = if self.chars.next_if_eq(&',').is_some() {
+                        self.skip_space();
+                        if self.chars.peek() == Some(&'}') {
+                            None
+                        } else {
+                            Some(self.number()?)
+                        }
+                    } else {
+                        Some(min)
+                    };
+                    self.skip_space();
+                    if self.chars.peek() != Some(&'}') {
+                        bail!("Expected `}}` in GBNF repetition.");
+                    }
+                    if max.is_some_and(|max| max < min) {
+                        bail!("Invalid GBNF repetition `{{{min},{}}}`.", max.unwrap());
+                    }
+                    (min, max)
+                }
+                _ => return Ok(item),
+            };
+            self.chars.next();
+            item = Expr::Repeat(Box::new(item), min, max);
+        }
+    }
+
+    fn number(&mut self) -> Result<usize> {
+        self.skip_space();
+        let mut digits = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
+            digits.push(c);
+        }
+        digits
+            .parse()
+            .with_context(|| format!("Expected a number in GBNF repetition in `{}`.", self.src))
+    }
+
+    fn class(&mut self) -> Result<CharSet> {
+        let negated = self.chars.next_if_eq(&'^').is_some();
+        let mut ranges = Vec::new();
+        loop {
+            let lo = match self.chars.next() {
+                Some(']') => break,
+                Some('\\') => self.escape()?,
+                Some(c) => c as u32,
+                None => bail!("Unterminated character class in GBNF grammar."),
+            };
+            let hi = if self.chars.peek() == Some(&'-')
+                && self.chars.clone().nth(1).is_some_and(|c| c != ']')
+            {
+                self.chars.next();
+                match self.chars.next() {
+                    Some('\\') => self.escape()?,
+                    Some(c) => c as u32,
+                    None => bail!("Unterminated character class in GBNF grammar."),
+                }
+            } else {
+                lo
+            };
+            if hi < lo {
+                bail!("Invalid character range in GBNF grammar.");
+            }
+            ranges.push((lo, hi));
+        }
+        let set = CharSet::new(ranges);
+        Ok(if negated { set.complement() } else { set })
+    }
+
+    fn escape(&mut self) -> Result<u32> {
+        let hex = |parser: &mut Self, n: usize| -> Result<u32> {
+            let digits = (0..n)
+                .filter_map(|_| parser.chars.next())
+                .collect::<String>();
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .filter(|c| *c <= MAX_CHAR)
+                .with_context(|| format!("Invalid escape `{digits}` in GBNF grammar."))
+        };
+        Ok(match self.chars.next() {
+            Some('n') => '\n' as u32,
+            Some('r') => '\r' as u32,
+            Some('t') => '\t' as u32,
+            Some('x') => hex(self, 2)?,
+            Some('u') => hex(self, 4)?,
+            Some('U') => hex(self, 8)?,
+            Some(c) => c as u32,
+            None => bail!("Unterminated escape in GBNF grammar."),
+        })
+    }
+}
+
+/// Split an inclusive range of scalar values into sequences of byte ranges matching their UTF-8 encodings.
+#[allow(clippy::cast_possible_truncation)]
+fn utf8_sequences(lo: u32, hi: u32) -> Vec<Vec<(u8, u8)>> {
+    let mut sequences = Vec::new();
+    let mut todo = vec![(lo, hi)];
+    'outer: while let Some((lo, hi)) = todo.pop() {
+        // Surrogates are not scalar values
+        if lo <= 0xDFFF && hi >= 0xD800 {
+            if lo < 0xD800 {
+                todo.push((lo, 0xD7FF));
+            }
+            if hi > 0xDFFF {
+                todo.push((0xE000, hi));
+            }
+            continue;
+        }
+        // Ranges must have the same encoded length
+        for max in [0x7F, 0x7FF, 0xFFFF] {
+            if lo <= max && hi > max {
+                todo.push((lo, max));
+                todo.push((max + 1, hi));
+                continue 'outer;
+            }
+        }
+        if hi <= 0x7F {
+            sequences.push(vec![(lo as u8, hi as u8)]);
+            continue;
+        }
+        // Each trailing byte must either be fixed or span all continuation bytes
+        for i in 1..4 {
+            let m = (1 << (6 * i)) - 1;
+            if lo & !m != hi & !m {
+                if lo & m != 0 {
+                    todo.push((lo, lo | m));
+                    todo.push(((lo | m) + 1, hi));
+                    continue 'outer;
+                }
+                if hi & m != m {
+                    todo.push((lo, (hi & !m) - 1));
+                    todo.push((hi & !m, hi));
+                    continue 'outer;
+                }
+            }
+        }
+        let encode = |c: u32| {
+            let mut buf = [0; 4];
+            char::from_u32(c)
+                .expect("Surrogates were removed.")
+                .encode_utf8(&mut buf)
+                .as_bytes()
+                .to_vec()
+        };
+        sequences.push(encode(lo).into_iter().zip(encode(hi)).collect());
+    }
+    sequences
+}
+
+/// The yacc regex token matching the UTF-8 encoding of any scalar value in `ranges`.
+fn atom_token(ranges: &[(u32, u32)]) -> String {
+    let byte_range = |(lo, hi): (u8, u8)| {
+        if lo == hi {
+            format!("\\x{lo:02x}")
+        } else {
+            format!("[\\x{lo:02x}-\\x{hi:02x}]")
+        }
+    };
+    let alternatives = ranges
+        .iter()
+        .flat_map(|(lo, hi)| utf8_sequences(*lo, *hi))
+        .map(|seq| seq.into_iter().map(byte_range).collect::<String>())
+        .collect::<Vec<_>>();
+    format!("\"/{}/\"", alternatives.join("|"))
+}
+
+struct YaccWriter {
+    rule_names: HashMap<String, String>,
+    /// The symbol (token or nonterminal) for each character set.
+    sets: HashMap<CharSet, String>,
+    rules: Vec<(String, Vec<Vec<String>>)>,
+}
+
+impl YaccWriter {
+    fn helper(&mut self, alternatives: Vec<Vec<String>>) -> String {
+        let name = format!("g_{}", self.rules.len());
+        self.rules.push((name.clone(), alternatives));
+        name
+    }
+
+    fn alternatives(&mut self, expr: &Expr) -> Result<Vec<Vec<String>>> {
+        match expr {
+            Expr::Alt(alternatives) => alternatives.iter().map(|alt| self.sequence(alt)).collect(),
+            expr => Ok(vec![self.sequence(expr)?]),
+        }
+    }
+
+    fn sequence(&mut self, expr: &Expr) -> Result<Vec<String>> {
+        Ok(match expr {
+            Expr::Seq(items) => {
+                let mut symbols = Vec::new();
+                for item in items {
+                    symbols.extend(self.sequence(item)?);
+                }
+                symbols
+            }
+            Expr::Alt(_) => {
+                let alternatives = self.alternatives(expr)?;
+                vec![self.helper(alternatives)]
+            }
+            Expr::Chars(set) => vec![self.sets[set].clone()],
+            Expr::Rule(name) => vec![self
+                .rule_names
+                .get(name)
+                .with_context(|| format!("Undefined rule `{name}` in GBNF grammar."))?
+                .clone()],
+            Expr::Repeat(item, min, max) => {
+                let item = self.sequence(item)?;
+                let mut symbols = Vec::new();
+                for _ in 0..*min {
+                    symbols.extend(item.iter().cloned());
+                }
+                match max {
+                    // Left recursive, as that keeps the LR parse stack small
+                    None => {
+                        let name = format!("g_{}", self.rules.len());
+                        let repeated = [vec![name.clone()], item].concat();
+                        self.rules.push((name.clone(), vec![vec![], repeated]));
+                        symbols.push(name);
+                    }
+                    Some(max) => {
+                        // item{0,n} is (item item{0,n-1})?
+                        let mut optional: Option<String> = None;
+                        for _ in *min..*max {
+                            let longer = [item.clone(), optional.into_iter().collect()].concat();
+                            optional = Some(self.helper(vec![vec![], longer]));
+                        }
+                        symbols.extend(optional);
+                    }
+                }
+                symbols
+            }
+        })
+    }
+}
+
+fn collect_sets(expr: &Expr, sets: &mut Vec<CharSet>) {
+    match expr {
+        Expr::Seq(items) | Expr::Alt(items) => items.iter().for_each(|e| collect_sets(e, sets)),
+        Expr::Chars(set) => sets.push(set.clone()),
+        Expr::Rule(_) => {}
+        Expr::Repeat(item, _, _) => collect_sets(item, sets),
+    }
+}
+
+/// Translate a GBNF grammar, starting at the `root` rule, into a yacc grammar for [`CfgParser::from_yacc`].
+///
+/// [`CfgParser::from_yacc`]: super::cfg::CfgParser::from_yacc
+pub fn gbnf_to_yacc(gbnf: &str) -> Result<String> {
+    let rules = Parser {
+        chars: gbnf.chars().peekable(),
+        src: gbnf,
+    }
+    .rules()?;
+
+    let mut rule_names = HashMap::new();
+    for (name, _) in &rules {
+        let yacc_name = format!("r_{}", name.replace('-', "__"));
+        if rule_names.insert(name.clone(), yacc_name).is_some() {
+            bail!("Rule `{name}` is defined more than once in GBNF grammar.");
+        }
+    }
+    if !rule_names.contains_key("root") {
+        bail!("GBNF grammar has no `root` rule.");
+    }
+
+    // Split the character sets into disjoint atoms, grouping the elementary ranges by which sets contain them.
+    let mut sets = Vec::new();
+    for (_, expr) in &rules {
+        collect_sets(expr, &mut sets);
+    }
+    sets.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    sets.dedup();
+    let mut bounds = sets
+        .iter()
+        .flat_map(|set| set.0.iter().flat_map(|(lo, hi)| [*lo, hi + 1]))
+        .collect::<Vec<_>>();
+    bounds.sort_unstable();
+    bounds.dedup();
+    let mut atoms: Vec<(Vec<usize>, Vec<(u32, u32)>)> = Vec::new();
+    for window in bounds.windows(2) {
+        let (lo, hi) = (window[0], window[1] - 1);
+        let members = (0..sets.len())
+            .filter(|i| sets[*i].contains(lo))
+            .collect::<Vec<_>>();
+        if members.is_empty() {
+            continue;
+        }
+        match atoms.iter_mut().find(|(m, _)| *m == members) {
+            Some((_, ranges)) => ranges.push((lo, hi)),
+            None => atoms.push((members, vec![(lo, hi)])),
+        }
+    }
+    let atom_tokens = atoms
+        .iter()
+        .map(|(_, ranges)| atom_token(ranges))
+        .collect::<Vec<_>>();
+
+    let mut writer = YaccWriter {
+        rule_names,
+        sets: HashMap::new(),
+        rules: Vec::new(),
+    };
+    for (i, set) in sets.iter().enumerate() {
+        let tokens = atoms
+            .iter()
+            .zip(&atom_tokens)
+            .filter(|((members, _), _)| members.contains(&i))
+            .map(|(_, token)| vec![token.clone()])
+            .collect::<Vec<_>>();
+        let symbol = if tokens.len() == 1 {
+            tokens[0][0].clone()
+        } else {
+            let name = format!("c_{i}");
+            writer.rules.push((name.clone(), tokens));
+            name
+        };
+        writer.sets.insert(set.clone(), symbol);
+    }
+    for (name, expr) in &rules {
+        let alternatives = writer.alternatives(expr)?;
+        writer
+            .rules
+            .push((writer.rule_names[name].clone(), alternatives));
+    }
+
+    let mut yacc = format!("%start {}\n%%\n", writer.rule_names["root"]);
+    for (name, alternatives) in writer.rules {
+        let alternatives = alternatives
+            .into_iter()
+            .map(|symbols| symbols.join(" "))
+            .collect::<Vec<_>>();
+        yacc.push_str(&format!("{name}: {};\n", alternatives.join(" | ")));
+    }
+    Ok(yacc)
+}
+
+#[cfg(test)]
+mod tests {
+    fn accepts(gbnf: &str, text: &str) -> bool {
+        use crate::aici::{
+            cfg::CfgParser,
+            toktree::{Recognizer, SpecialToken},
+        };
+
+        let yacc = super::gbnf_to_yacc(gbnf).unwrap();
+        let mut parser = CfgParser::from_yacc(&yacc).unwrap();
+        text.bytes().all(|b| parser.try_push_byte(b))
+            && parser.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    #[test]
+    fn literals_classes_and_repetition() {
+        let gbnf = r#"
+            # A comma separated list of small numbers
+            root ::= "[" (num ("," ws num)*)? "]"
+            num ::= [1-9] [0-9]? | "0"
+            ws ::= [ \t]*
+        "#;
+        assert!(accepts(gbnf, "[]"));
+        assert!(accepts(gbnf, "[1, 22,0,\t7]"));
+        assert!(!accepts(gbnf, "[1,"));
+        assert!(!accepts(gbnf, "[123]"));
+        assert!(!accepts(gbnf, "[01]"));
+    }
+
+    #[test]
+    fn overlapping_sets_and_bounded_repetition() {
+        let gbnf = r#"
+            root ::= ("ab" | [a-z] "c") [^a-z]{1,2}
+        "#;
+        assert!(accepts(gbnf, "ab1"));
+        assert!(accepts(gbnf, "zc12"));
+        assert!(!accepts(gbnf, "zc123"));
+        assert!(!accepts(gbnf, "ab"));
+    }
+
+    #[test]
+    fn multibyte_characters() {
+        let gbnf = r#"
+            root ::= greeting " " [😀-😂]+
+            greeting ::= "你好" | [à-ÿ]
+        "#;
+        assert!(accepts(gbnf, "你好 😀😂"));
+        assert!(accepts(gbnf, "é 😁"));
+        assert!(!accepts(gbnf, "你 😀"));
+        assert!(!accepts(gbnf, "你好 🙂"));
+    }
+
+    #[test]
+    fn undefined_rules_are_errors() {
+        assert!(super::gbnf_to_yacc("root ::= missing").is_err());
+        assert!(super::gbnf_to_yacc("start ::= \"a\"").is_err());
+    }
+}
@@ -0,0 +1,319 @@
+//! Translation of JSON schemas into GBNF grammars, which are then constrained like any other GBNF grammar.
+//!
+//! Supported are the `type` (including lists of types), `properties`, `required`, `items`, `enum`, `const`, `anyOf`,
+//! `oneOf` and local `$ref` (such as into `$defs` or `definitions`) keywords. Object properties are generated in the
+//! order in which the parsed `properties` map iterates them, and properties which are not listed are not allowed.
+//! Other keywords, such as string formats or length bounds, are ignored, so the output will always be valid JSON but
+//! may not satisfy them.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+/// Primitive rules, with the primitive rules they depend on.
+const PRIMITIVES: &[(&str, &str, &[&str])] = &[
+    ("ws", r#"[ \t\n]*"#, &[]),
+    (
+        "string",
+        r#""\"" ([^"\\\x00-\x1f] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4}))* "\"""#,
+        &[],
+    ),
+    ("integer", r#""-"? ("0" | [1-9] [0-9]*)"#, &[]),
+    (
+        "number",
+        r#"integer ("." [0-9]+)? ([eE] [-+]? [0-9]+)?"#,
+        &["integer"],
+    ),
+    ("boolean", r#""true" | "false""#, &[]),
+    ("null", r#""null""#, &[]),
+    (
+        "value",
+        r#"object | array | string | number | boolean | null"#,
+        &["object", "array", "string", "number", "boolean", "null"],
+    ),
+    (
+        "object",
+        r#""{" ws (string ws ":" ws value ws ("," ws string ws ":" ws value ws)*)? "}""#,
+        &["ws", "string", "value"],
+    ),
+    (
+        "array",
+        r#""[" ws (value ws ("," ws value ws)*)? "]""#,
+        &["ws", "value"],
+    ),
+];
+
+/// A GBNF string literal matching `text` exactly.
+fn literal(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+    rules: Vec<(String, String)>,
+    /// Rule names of the primitives and referenced definitions which were already emitted.
+    emitted: HashMap<String, String>,
+}
+
+impl<'a> Converter<'a> {
+    fn primitive(&mut self, name: &str) -> String {
+        if !self.emitted.contains_key(name) {
+            let (_, body, deps) = PRIMITIVES
+                .iter()
+                .find(|(primitive, _, _)| *primitive == name)
+                .expect("Unknown primitive");
+            self.emitted.insert(name.to_string(), name.to_string());
+            self.rules.push((name.to_string(), body.to_string()));
+            for dep in *deps {
+                self.primitive(dep);
+            }
+        }
+        name.to_string()
+    }
+
+    fn new_rule(&mut self, body: String) -> String {
+        let name = format!("schema-{}", self.rules.len());
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    fn reference(&mut self, reference: &str) -> Result<String> {
+        if let Some(name) = self.emitted.get(reference) {
+            return Ok(name.clone());
+        }
+        let schema = reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .with_context(|| format!("Unresolvable `$ref` `{reference}` in JSON schema."))?;
+        // Reserve the name first, so recursive references resolve to it
+        let name = format!("ref-{}", self.rules.len());
+        self.emitted.insert(reference.to_string(), name.clone());
+        let index = self.rules.len();
+        self.rules.push((name.clone(), String::new()));
+        self.rules[index].1 = self.schema(schema)?;
+        Ok(name)
+    }
+
+    /// The GBNF expression matching `schema`.
+    fn schema(&mut self, schema: &Value) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.primitive("value")),
+            Value::Bool(false) => bail!("JSON schema `false` matches nothing."),
+            Value::Object(schema) => schema,
+            _ => bail!("JSON schema must be an object or a boolean."),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            let reference = reference
+                .as_str()
+                .context("JSON schema `$ref` must be a string.")?;
+            return self.reference(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(&value.to_string()));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .filter(|values| !values.is_empty())
+                .context("JSON schema `enum` must be a non-empty array.")?;
+            let values = values
+                .iter()
+                .map(|value| literal(&value.to_string()))
+                .collect::<Vec<_>>();
+            return Ok(format!("({})", values.join(" | ")));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let schemas = schemas
+                    .as_array()
+                    .filter(|schemas| !schemas.is_empty())
+                    .with_context(|| {
+                        format!("JSON schema `{keyword}` must be a non-empty array.")
+                    })?;
+                let alternatives = schemas
+                    .iter()
+                    .map(|schema| self.schema(schema))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(format!("({})", alternatives.join(" | ")));
+            }
+        }
+
+        let types = match schema.get("type") {
+            None => return Ok(self.primitive("value")),
+            Some(Value::String(ty)) => vec![ty.as_str()],
+            Some(Value::Array(types)) => types
+                .iter()
+                .map(|ty| ty.as_str().context("JSON schema `type` must be a string."))
+                .collect::<Result<Vec<_>>>()?,
+            Some(_) => bail!("JSON schema `type` must be a string or an array of strings."),
+        };
+        let alternatives = types
+            .into_iter()
+            .map(|ty| match ty {
+                "object" => self.object(schema),
+                "array" => match schema.get("items") {
+                    Some(items) => {
+                        let item = self.schema(items)?;
+                        let ws = self.primitive("ws");
+                        Ok(self.new_rule(format!(
+                            r#""[" {ws} ({item} {ws} ("," {ws} {item} {ws})*)? "]""#
+                        )))
+                    }
+                    None => Ok(self.primitive("array")),
+                },
+                "string" | "number" | "integer" | "boolean" | "null" => Ok(self.primitive(ty)),
+                ty => bail!("Unsupported JSON schema type `{ty}`."),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(format!("({})", alternatives.join(" | ")))
+    }
+
+    fn object(&mut self, schema: &serde_json::Map<String, Value>) -> Result<String> {
+        let Some(properties) = schema.get("properties") else {
+            return Ok(self.primitive("object"));
+        };
+        let properties = properties
+            .as_object()
+            .context("JSON schema `properties` must be an object.")?;
+        let required = match schema.get("required") {
+            Some(required) => required
+                .as_array()
+                .context("JSON schema `required` must be an array.")?
+                .iter()
+                .map(|name| {
+                    name.as_str()
+                        .context("JSON schema `required` must contain strings.")
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        if let Some(missing) = required
+            .iter()
+            .find(|name| !properties.contains_key(**name))
+        {
+            bail!("Required property `{missing}` is not in the JSON schema `properties`.");
+        }
+
+        let ws = self.primitive("ws");
+        let mut members = Vec::new();
+        for (name, property) in properties {
+            let value = self.schema(property)?;
+            let key = literal(&Value::String(name.clone()).to_string());
+            members.push((
+                format!("{key} {ws} \":\" {ws} {value} {ws}"),
+                required.contains(&name.as_str()),
+            ));
+        }
+
+        // Build the members back to front: `first` matches the remaining members when none were emitted yet, and
+        // `after` when they need a leading comma. Optional members may be skipped.
+        let (mut first, mut after) = (String::new(), String::new());
+        for (member, required) in members.into_iter().rev() {
+            let emitted = self.new_rule(format!("{member} {after}"));
+            let emitted_after = self.new_rule(format!("\",\" {ws} {member} {after}"));
+            if required {
+                first = emitted;
+                after = emitted_after;
+            } else {
+                first = self.new_rule(format!("{emitted} | {first}"));
+                after = self.new_rule(format!("{emitted_after} | {after}"));
+            }
+        }
+        Ok(self.new_rule(format!("\"{{\" {ws} {first} \"}}\"")))
+    }
+}
+
+/// Translate a JSON schema into a GBNF grammar matching the JSON values it describes.
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String> {
+    let mut converter = Converter {
+        root: schema,
+        rules: Vec::new(),
+        emitted: HashMap::new(),
+    };
+    let root = converter.schema(schema)?;
+    let mut gbnf = format!("root ::= {root}\n");
+    for (name, body) in converter.rules {
+        gbnf.push_str(&format!("{name} ::= {body}\n"));
+    }
+    Ok(gbnf)
+}
+
+#[cfg(test)]
+mod tests {
+    fn accepts(schema: &serde_json::Value, text: &str) -> bool {
+        use crate::aici::{
+            cfg::CfgParser,
+            gbnf::gbnf_to_yacc,
+            toktree::{Recognizer, SpecialToken},
+        };
+
+        let gbnf = super::json_schema_to_gbnf(schema).unwrap();
+        let mut parser = CfgParser::from_yacc(&gbnf_to_yacc(&gbnf).unwrap()).unwrap();
+        text.bytes().all(|b| parser.try_push_byte(b))
+            && parser.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    #[test]
+    fn objects_with_required_and_optional_properties() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "age": { "type": "integer" },
+                "name": { "type": "string" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] } },
+            },
+            "required": ["name"],
+        });
+        assert!(accepts(&schema, r#"{"name": "Ann"}"#));
+        assert!(accepts(&schema, r#"{"age":3,"name":"Ann \"A\""}"#));
+        assert!(accepts(
+            &schema,
+            "{\n  \"name\": \"Ann\",\n  \"tags\": [\"a\", \"b\"]\n}"
+        ));
+        assert!(!accepts(&schema, r#"{"age": 3}"#));
+        assert!(!accepts(&schema, r#"{"name": 3}"#));
+        assert!(!accepts(&schema, r#"{"name": "Ann", "tags": ["c"]}"#));
+        assert!(!accepts(&schema, r#"{"name": "Ann", "other": 1}"#));
+        assert!(!accepts(&schema, r#"{"name": "Ann",}"#));
+    }
+
+    #[test]
+    fn nested_objects_and_refs() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "point": { "$ref": "#/$defs/point" },
+                "value": { "type": ["number", "null"] },
+            },
+            "required": ["point", "value"],
+            "$defs": {
+                "point": {
+                    "type": "object",
+                    "properties": { "x": { "type": "number" }, "y": { "type": "number" } },
+                    "required": ["x", "y"],
+                },
+            },
+        });
+        assert!(accepts(
+            &schema,
+            r#"{"point": {"x": -1.5, "y": 2e3}, "value": null}"#
+        ));
+        assert!(!accepts(&schema, r#"{"point": {"x": 1}, "value": 1}"#));
+        assert!(!accepts(
+            &schema,
+            r#"{"point": {"x": 1, "y": 01}, "value": 1}"#
+        ));
+    }
+}
@@ -1,6 +1,8 @@
 pub(crate) mod bintokens;
 pub(crate) mod bytes;
 pub(crate) mod cfg;
+pub(crate) mod gbnf;
+pub(crate) mod json_schema;
 pub(crate) mod lex;
 pub(crate) mod recognizer;
 pub(crate) mod rx;
@@ -1,11 +1,20 @@
 use std::fmt::Debug;
 
-use crate::{utils::debug::DeviceRepr, ModelDType, TryIntoDType};
+use crate::{tensor_parallel::TensorParallel, utils::debug::DeviceRepr, ModelDType, TryIntoDType};
 use candle_core::{DType, Device, Result, Tensor};
 use candle_nn::VarBuilder;
 use serde::Deserialize;
 use tracing::info;
 
+/// The device of the same kind as `device` with the given ordinal. The CPU has a single device.
+pub fn device_with_ordinal(device: &Device, ordinal: usize) -> Result<Device> {
+    Ok(match device {
+        Device::Cpu => Device::Cpu,
+        Device::Cuda(_) => Device::new_cuda(ordinal)?,
+        Device::Metal(_) => Device::new_metal(ordinal)?,
+    })
+}
+
 #[derive(Debug, Default, Deserialize, Clone)]
 pub struct DeviceLayerMapMetadata {
     pub ordinal: usize,
@@ -17,6 +26,7 @@ pub struct DeviceLayerMapMetadata {
 pub struct DeviceMapMetadata {
     device_layers: Option<Vec<DeviceLayerMapMetadata>>,
     host_layers: Option<usize>,
+    tensor_parallel_size: Option<usize>,
 }
 
 impl DeviceMapMetadata {
@@ -29,6 +39,7 @@ impl DeviceMapMetadata {
                 layers: device_layers,
             }]),
             host_layers: None,
+            tensor_parallel_size: None,
         }
     }
     // TODO(EricLBuehler): For version 0.2.0, replace `from_num_device_layers` with this.
@@ -36,6 +47,7 @@ impl DeviceMapMetadata {
         Self {
             device_layers: Some(device_layers),
             host_layers: None,
+            tensor_parallel_size: None,
         }
     }
     /// A device mapper to not map device.
@@ -43,11 +55,40 @@ impl DeviceMapMetadata {
         Self {
             device_layers: None,
             host_layers: None,
+            tensor_parallel_size: None,
+        }
+    }
+    /// Shard the attention and MLP projections of every layer across the devices of ordinals `0..tensor_parallel_size`.
+    /// The rest of the model is on the device of ordinal 0.
+    pub fn from_tensor_parallel_size(tensor_parallel_size: usize) -> Self {
+        Self {
+            device_layers: None,
+            host_layers: None,
+            tensor_parallel_size: Some(tensor_parallel_size),
         }
     }
     pub fn is_dummy(&self) -> bool {
         self.device_layers.is_none()
     }
+    pub fn is_tensor_parallel(&self) -> bool {
+        self.tensor_parallel_size.is_some()
+    }
+    /// The devices to shard the projections across with tensor parallelism, where `device` is the primary device.
+    pub fn tensor_parallel(&self, device: &Device) -> Result<Option<TensorParallel>> {
+        let Some(size) = self.tensor_parallel_size else {
+            return Ok(None);
+        };
+        if size == 0 {
+            candle_core::bail!("The tensor parallel size must be at least 1.");
+        }
+        let mut devices = vec![device.clone()];
+        for ordinal in 1..size {
+            devices.push(device_with_ordinal(device, ordinal)?);
+        }
+        let tp = TensorParallel::new(devices)?;
+        info!("Sharding the projections of the model across {tp}.");
+        Ok(Some(tp))
+    }
     pub fn into_mapper(
         &self,
         model_layers: usize,
@@ -219,3 +260,37 @@ impl DeviceMapper for DummyDeviceMapper {
             .map_err(|e| candle_core::Error::Msg(format!("{e:?}")))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::{DeviceLayerMapMetadata, DeviceMapMetadata};
+
+    #[test]
+    fn remaining_layers_are_on_the_host() {
+        let metadata =
+            DeviceMapMetadata::from_num_device_layers_multi_gpu(vec![DeviceLayerMapMetadata {
+                ordinal: 0,
+                layers: 3,
+            }]);
+        let mapper = metadata.into_mapper(4, &Device::Cpu).unwrap();
+        for layer in 0..4 {
+            assert!(mapper.device_for(layer, false).is_some());
+        }
+        assert!(mapper.device_for(4, false).is_none());
+
+        // The device layers are clamped to the number of layers of the model
+        let metadata =
+            DeviceMapMetadata::from_num_device_layers_multi_gpu(vec![DeviceLayerMapMetadata {
+                ordinal: 0,
+                layers: 8,
+            }]);
+        assert!(metadata.into_mapper(4, &Device::Cpu).is_ok());
+        // Without device layers, nothing is mapped
+        let mapper = DeviceMapMetadata::dummy()
+            .into_mapper(4, &Device::Cpu)
+            .unwrap();
+        assert!(mapper.device_for(0, false).is_none());
+    }
+}
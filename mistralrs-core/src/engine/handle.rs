@@ -0,0 +1,193 @@
+use std::{collections::BTreeMap, sync::Arc, task::Poll};
+
+use tokio::sync::mpsc::{channel, Receiver};
+
+use crate::{request::Request, response::Response, MistralRs, MistralRsError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Identifies a request added to an [`EngineHandle`].
+pub struct RequestId(pub usize);
+
+/// A response of the engine to a request added to an [`EngineHandle`].
+pub struct StepOutput {
+    pub id: RequestId,
+    pub response: Response,
+    /// Whether this is the last response to the request, after which it is no longer tracked.
+    pub finished: bool,
+}
+
+/// Drives the engine of a [`MistralRs`] without any transport, so that custom servers, such as a gRPC front-end,
+/// submit requests, poll their outputs and abort them directly. The HTTP server is one such consumer.
+///
+/// The engine schedules and runs the sequences on its own thread. Requests added here are batched with those of
+/// any other consumer, and [`EngineHandle::step`] collects the responses the engine sent since the last call.
+pub struct EngineHandle {
+    mistralrs: Arc<MistralRs>,
+    in_flight: BTreeMap<RequestId, Receiver<Response>>,
+}
+
+impl EngineHandle {
+    pub fn new(mistralrs: Arc<MistralRs>) -> Self {
+        Self {
+            mistralrs,
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    /// Submit a request to the engine. Its response channel is replaced by one owned by this handle, so its
+    /// responses are returned by [`EngineHandle::step`]. Requests without responses, such as adapter changes, are
+    /// only forwarded.
+    pub async fn add_request(&mut self, mut request: Request) -> Result<RequestId, MistralRsError> {
+        let id = RequestId(self.mistralrs.next_request_id());
+        let (tx, rx) = channel(10_000);
+        let response = match &mut request {
+            Request::Normal(request) => {
+                request.id = id.0;
+                Some(&mut request.response)
+            }
+            Request::Embedding(request) => Some(&mut request.response),
+            Request::Tokenize(request) => Some(&mut request.response),
+            Request::Detokenize(request) => Some(&mut request.response),
+            Request::ReIsq(_)
+            | Request::ActivateAdapters(_)
+            | Request::AddAdapter { .. }
+            | Request::MergeAdapter(_)
+            | Request::UnmergeAdapter
+            | Request::Drain(_) => None,
+        };
+        if let Some(response) = response {
+            *response = tx;
+            self.in_flight.insert(id, rx);
+        }
+        if self.mistralrs.get_sender()?.send(request).await.is_err() {
+            self.in_flight.remove(&id);
+            return Err(MistralRsError::EnginePoisoned);
+        }
+        Ok(id)
+    }
+
+    /// Wait until the engine responds to at least one unfinished request, and return all of the responses sent so
+    /// far. Returns nothing right away if no request is unfinished.
+    pub async fn step(&mut self) -> Vec<StepOutput> {
+        next_outputs(&mut self.in_flight).await
+    }
+
+    /// Stop tracking a request, whose sequences the engine then cancels at its next step, freeing their KV caches.
+    /// Returns whether the request was unfinished.
+    pub fn abort(&mut self, id: RequestId) -> bool {
+        // Dropping the receiver abandons the sequences
+        self.in_flight.remove(&id).is_some()
+    }
+
+    pub fn num_unfinished_requests(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn mistralrs(&self) -> &Arc<MistralRs> {
+        &self.mistralrs
+    }
+}
+
+/// Whether no more responses follow `response`. Streamed chat completions end with the chunk carrying the usage.
+fn is_last_response(response: &Response) -> bool {
+    match response {
+        Response::Chunk(chunk) => chunk.usage.is_some(),
+        _ => true,
+    }
+}
+
+async fn next_outputs(in_flight: &mut BTreeMap<RequestId, Receiver<Response>>) -> Vec<StepOutput> {
+    if in_flight.is_empty() {
+        return Vec::new();
+    }
+    let outputs = std::future::poll_fn(|cx| {
+        let mut outputs = Vec::new();
+        for (id, rx) in in_flight.iter_mut() {
+            while let Poll::Ready(response) = rx.poll_recv(cx) {
+                let (response, finished) = match response {
+                    Some(response) => {
+                        let finished = is_last_response(&response);
+                        (response, finished)
+                    }
+                    // The sender was dropped without a last response, so the engine stopped
+                    None => (
+                        Response::InternalError(
+                            "The engine stopped before finishing the request.".into(),
+                        ),
+                        true,
+                    ),
+                };
+                outputs.push(StepOutput {
+                    id: *id,
+                    response,
+                    finished,
+                });
+                if finished {
+                    break;
+                }
+            }
+        }
+        if outputs.is_empty() {
+            Poll::Pending
+        } else {
+            Poll::Ready(outputs)
+        }
+    })
+    .await;
+    for output in &outputs {
+        if output.finished {
+            in_flight.remove(&output.id);
+        }
+    }
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tokio::sync::mpsc::channel;
+
+    use super::{next_outputs, RequestId};
+    use crate::response::{Response, TokenizationResponse};
+
+    fn tokenized(tokens: Vec<u32>) -> Response {
+        Response::Tokenized(TokenizationResponse {
+            count: tokens.len(),
+            tokens,
+            max_model_len: 4096,
+        })
+    }
+
+    #[tokio::test]
+    async fn outputs_of_finished_requests() {
+        let mut in_flight = BTreeMap::new();
+        assert!(next_outputs(&mut in_flight).await.is_empty());
+
+        let (tx_a, rx_a) = channel(1);
+        let (tx_b, rx_b) = channel(1);
+        let (tx_c, rx_c) = channel(1);
+        in_flight.insert(RequestId(0), rx_a);
+        in_flight.insert(RequestId(1), rx_b);
+        in_flight.insert(RequestId(2), rx_c);
+
+        tx_b.send(tokenized(vec![1, 2])).await.unwrap();
+        let outputs = next_outputs(&mut in_flight).await;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].id, RequestId(1));
+        assert!(outputs[0].finished);
+        assert!(matches!(&outputs[0].response, Response::Tokenized(resp) if resp.tokens == [1, 2]));
+        assert_eq!(in_flight.len(), 2);
+
+        // A request whose sender is dropped without a response finishes with an error
+        drop(tx_a);
+        tx_c.send(tokenized(vec![3])).await.unwrap();
+        let outputs = next_outputs(&mut in_flight).await;
+        assert_eq!(
+            outputs.iter().map(|o| o.id).collect::<Vec<_>>(),
+            vec![RequestId(0), RequestId(2)]
+        );
+        assert!(matches!(outputs[0].response, Response::InternalError(_)));
+        assert!(in_flight.is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    cmp::Reverse,
     collections::{HashMap, VecDeque},
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -6,33 +7,62 @@ use std::{
     },
     time::{Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::{mpsc::Receiver, Mutex};
+use tokio::sync::{
+    mpsc::{error::TryRecvError, Receiver},
+    Mutex,
+};
 
 use crate::{
-    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
-    pipeline::{AdapterInstruction, CacheInstruction},
-    request::NormalRequest,
-    response::CompletionChoice,
-    CompletionResponse, RequestMessage, Response, DEBUG,
+    aici::{
+        cfg::CfgParser, gbnf::gbnf_to_yacc, json_schema::json_schema_to_gbnf,
+        recognizer::StackRecognizer, rx::RecRx,
+    },
+    metrics::{device_memory_used, EngineMetrics},
+    pipeline::{
+        chat_template::{
+            add_default_system_prompt, drop_oldest_turn, find_special_token, PromptSpecialTokens,
+        },
+        AdapterInstruction, CacheInstruction,
+    },
+    request::{
+        DetokenizationRequest, EmbeddingRequest, NormalRequest, TokenizationPrompt,
+        TokenizationRequest,
+    },
+    response::{
+        CompletionChoice, DetokenizationResponse, Embedding, EmbeddingResponse, EmbeddingUsage,
+        TokenizationResponse,
+    },
+    CompletionResponse, RequestMessage, Response, SamplingParams, DEBUG,
 };
 use candle_core::{Device, Result, Tensor};
 use rand::SeedableRng;
 use rand_isaac::Isaac64Rng;
-use tracing::{info, warn};
+use tokenizers::Tokenizer;
+use tracing::{debug_span, info, warn};
 
 use crate::{
     get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error,
     pipeline::Pipeline,
-    prefix_cacher::PrefixCacheManager,
+    prefix_cacher::{EvictionPolicy, KeyNormalizer, PrefixCacheManager},
     request::Request,
+    respond_to_seq,
     response::{ChatCompletionResponse, Choice, ResponseMessage},
-    sampler::Sampler,
+    sampler::{ContrastiveParams, MirostatParams, RepetitionPenalty, Sampler},
     scheduler::{Scheduler, SchedulerMethod},
-    sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
+    sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState, StopReason},
     Constraint, StopTokens,
 };
 
+mod handle;
+pub use handle::{EngineHandle, RequestId, StepOutput};
+
 const SEED: u64 = 0;
+
+/// Whether `err` is a failed allocation on the device.
+fn is_out_of_memory(err: &candle_core::Error) -> bool {
+    let msg = err.to_string().to_lowercase();
+    msg.contains("out of memory") || msg.contains("out_of_memory")
+}
 /// Terminate all sequences on the next scheduling step. Be sure to reset this.
 pub static TERMINATE_ALL_NEXT_STEP: AtomicBool = AtomicBool::new(false);
 
@@ -46,6 +76,23 @@ pub struct Engine {
     prefix_cacher: PrefixCacheManager,
     is_debug: bool,
     disable_eos_stop: bool,
+    prefill_chunk_size: Option<usize>,
+    default_system_prompt: Option<String>,
+    prompt_special_tokens: PromptSpecialTokens,
+    // With special-token parsing disabled, the tokenizer of completions, which encodes special tokens as plain text
+    plain_text_tokenizer: Option<Arc<Tokenizer>>,
+    metrics: Arc<std::sync::Mutex<EngineMetrics>>,
+    // The adapters of the last activation request, which requests without their own adapters run with.
+    default_adapters: Option<Vec<String>>,
+    max_running: usize,
+    // Prompt length of the warmup batch run before the first request, if enabled
+    warmup_prompt_len: Option<usize>,
+    // Set once the engine runs and its warmup, if any, is finished
+    ready: Arc<AtomicBool>,
+    // When draining, the time after which the running sequences are cancelled
+    drain_deadline: Option<Instant>,
+    // Requests are rejected as busy while this many sequences are waiting to be scheduled
+    max_queued_requests: Option<usize>,
 }
 
 impl Engine {
@@ -58,10 +105,77 @@ impl Engine {
         no_kv_cache: bool,
         no_prefix_cache: bool,
         prefix_cache_n: usize,
+        prefix_cache_eviction_policy: EvictionPolicy,
+        prefix_cache_memory_budget: Option<usize>,
+        prefix_cache_offload_device: Device,
+        prefix_cache_key_normalizer: Option<KeyNormalizer>,
         disable_eos_stop: bool,
+        prefill_chunk_size: Option<usize>,
+        default_system_prompt: Option<String>,
+        add_bos_token: Option<bool>,
+        add_eos_token: Option<bool>,
+        disable_special_token_parsing: bool,
+        warmup_prompt_len: Option<usize>,
+        max_queued_requests: Option<usize>,
+        metrics: Arc<std::sync::Mutex<EngineMetrics>>,
+        ready: Arc<AtomicBool>,
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
         let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
+        let prefill_chunk_size = match prefill_chunk_size {
+            Some(0) => {
+                warn!("Ignoring a prefill chunk size of 0.");
+                None
+            }
+            Some(_) if no_kv_cache || !get_mut_arcmutex!(pipeline).supports_chunked_prefill() => {
+                warn!("Chunked prefill is not supported for this model, prompts will be prefilled at once.");
+                None
+            }
+            chunk_size => chunk_size,
+        };
+        let mut prefix_cacher = match prefix_cache_memory_budget {
+            Some(memory_budget) => PrefixCacheManager::new_with_memory_budget(
+                device,
+                prefix_cache_offload_device,
+                memory_budget,
+                is_xlora,
+                no_prefix_cache,
+                prefix_cache_eviction_policy,
+            ),
+            None => PrefixCacheManager::new(
+                device,
+                prefix_cache_offload_device,
+                prefix_cache_n,
+                is_xlora,
+                no_prefix_cache,
+                prefix_cache_eviction_policy,
+            ),
+        };
+        prefix_cacher.key_normalizer = prefix_cache_key_normalizer;
+        let mut prompt_special_tokens = get_mut_arcmutex!(pipeline)
+            .get_metadata()
+            .prompt_special_tokens
+            .clone();
+        if let Some(add_bos_token) = add_bos_token {
+            prompt_special_tokens.add_bos_token = add_bos_token;
+        }
+        if let Some(add_eos_token) = add_eos_token {
+            prompt_special_tokens.add_eos_token = add_eos_token;
+        }
+        let plain_text_tokenizer = disable_special_token_parsing.then(|| {
+            let mut tokenizer = (*get_mut_arcmutex!(pipeline).tokenizer()).clone();
+            tokenizer.set_encode_special_tokens(true);
+            Arc::new(tokenizer)
+        });
+        let max_queued_requests = match max_queued_requests {
+            Some(0) => {
+                warn!("Ignoring a maximum of 0 queued requests, which would reject every request.");
+                None
+            }
+            max_queued => max_queued,
+        };
+        let SchedulerMethod::Fixed(max_running) = &method;
+        let max_running = **max_running;
         Self {
             rx,
             pipeline,
@@ -69,48 +183,132 @@ impl Engine {
             id: 0,
             truncate_sequence,
             no_kv_cache,
-            prefix_cacher: PrefixCacheManager::new(
-                device,
-                prefix_cache_n,
-                is_xlora,
-                no_prefix_cache,
-            ),
+            prefix_cacher,
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
+            prefill_chunk_size,
+            default_system_prompt,
+            prompt_special_tokens,
+            plain_text_tokenizer,
+            metrics,
+            default_adapters: None,
+            max_running,
+            warmup_prompt_len,
+            ready,
+            drain_deadline: None,
+            max_queued_requests,
         }
     }
 
     pub async fn run(&mut self) {
         let rng = Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(SEED)));
         let mut last_completion_ids: Vec<usize> = vec![];
+        let mut warmup = match self.warmup_prompt_len.take() {
+            Some(prompt_len) => Some((Instant::now(), self.start_warmup(prompt_len).await)),
+            None => {
+                self.ready.store(true, Ordering::SeqCst);
+                None
+            }
+        };
         'lp: loop {
-            while let Ok(request) = self.rx.try_recv() {
-                self.handle_request(request).await;
+            if let Some((start, pending)) = &mut warmup {
+                pending.retain_mut(|rx| match rx.try_recv() {
+                    Err(TryRecvError::Empty) => true,
+                    Ok(Response::InternalError(e)) | Ok(Response::ValidationError(e)) => {
+                        warn!("Warmup request failed: {e}");
+                        false
+                    }
+                    Ok(Response::CompletionModelError(msg, _)) => {
+                        warn!("Warmup request failed: {msg}");
+                        false
+                    }
+                    _ => false,
+                });
+                if pending.is_empty() {
+                    self.finish_warmup(*start);
+                    warmup = None;
+                }
+            }
+            let mut forks = Vec::new();
+            // Requests wait until the warmup is finished, so that they are not batched with it
+            if warmup.is_none() {
+                while let Ok(request) = self.rx.try_recv() {
+                    if matches!(request, Request::Embedding(_)) {
+                        // Embedding runs the model and leaves its KV cache empty
+                        last_completion_ids = vec![];
+                    }
+                    self.handle_request(request).await;
+                }
+            }
+            let drain_expired = self
+                .drain_deadline
+                .is_some_and(|deadline| Instant::now() >= deadline);
+            if drain_expired {
+                for seq in self.scheduler.take_waiting() {
+                    let _ = seq
+                        .responder()
+                        .send(Response::InternalError(
+                            "The engine stopped before the request was scheduled.".into(),
+                        ))
+                        .await;
+                }
             }
             let run_start = Instant::now();
-            let mut scheduled = self.scheduler.schedule();
+            let mut scheduled = debug_span!("schedule").in_scope(|| self.scheduler.schedule());
+            let (mut chunked_prompt, prompt) = Self::split_chunked_prompts(
+                std::mem::take(&mut scheduled.prompt).into_vec(),
+                self.prefill_chunk_size,
+            );
+            scheduled.prompt = prompt.into();
+            if drain_expired {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                for seq in Self::take_drain_canceled(&mut scheduled.completion, &mut chunked_prompt)
+                {
+                    if let Err(e) =
+                        Self::finish_canceled(&*pipeline, &mut self.prefix_cacher, seq).await
+                    {
+                        warn!("Failed to respond to a cancelled sequence: {e}");
+                    }
+                }
+            }
+            let n_running =
+                scheduled.prompt.len() + scheduled.completion.len() + chunked_prompt.len();
+            let toks_before: usize = scheduled
+                .prompt
+                .iter()
+                .chain(scheduled.completion.iter())
+                .chain(chunked_prompt.iter())
+                .map(|seq| seq.len())
+                .sum();
 
             if scheduled.completion.len() > 0 {
                 let current_completion_ids: Vec<usize> =
                     scheduled.completion.iter().map(|seq| *seq.id()).collect();
+                let modifies_cache = scheduled.completion.iter().any(|seq| seq.modifies_cache());
                 let res = {
                     let mut pipeline = get_mut_arcmutex!(self.pipeline);
-                    let pre_op =
-                        if !self.no_kv_cache && last_completion_ids != current_completion_ids {
-                            CacheInstruction::In(
-                                scheduled.completion[0]
-                                    .get_adapters()
-                                    .map(AdapterInstruction::Activate)
-                                    .unwrap_or(AdapterInstruction::None),
-                            )
-                        } else {
-                            CacheInstruction::Nothing(
-                                scheduled.completion[0]
-                                    .get_adapters()
-                                    .map(AdapterInstruction::Activate)
-                                    .unwrap_or(AdapterInstruction::None),
-                            )
-                        };
+                    // A quantized cache is cloned in for every step, rather than only when the batch changes, so that
+                    // the attention reads the same values whether or not the batch changed
+                    let is_quantized = pipeline.get_metadata().kv_cache_bits.is_some();
+                    let pre_op = if !self.no_kv_cache
+                        && (modifies_cache
+                            || is_quantized
+                            || last_completion_ids != current_completion_ids)
+                    {
+                        CacheInstruction::In(
+                            scheduled.completion[0]
+                                .get_adapters()
+                                .map(AdapterInstruction::Activate)
+                                .unwrap_or(AdapterInstruction::None),
+                        )
+                    } else {
+                        CacheInstruction::Nothing(
+                            scheduled.completion[0]
+                                .get_adapters()
+                                .map(AdapterInstruction::Activate)
+                                .unwrap_or(AdapterInstruction::None),
+                        )
+                    };
                     let post_op = if !self.no_kv_cache {
                         CacheInstruction::Out
                     } else {
@@ -120,19 +318,42 @@ impl Engine {
                         }
                     };
 
-                    pipeline
-                        .step(
-                            &mut scheduled.completion,
-                            false,
-                            &mut self.prefix_cacher,
-                            self.disable_eos_stop,
-                            rng.clone(),
-                            pre_op,
-                            post_op,
-                        )
-                        .await
+                    // Sequences re-admitted after a preemption have their KV caches on the CPU
+                    let device = pipeline.device();
+                    match scheduled
+                        .completion
+                        .iter_mut()
+                        .try_for_each(|seq| seq.move_caches(&device))
+                    {
+                        Ok(()) => {
+                            pipeline
+                                .step(
+                                    &mut scheduled.completion,
+                                    false,
+                                    &mut self.prefix_cacher,
+                                    self.disable_eos_stop,
+                                    rng.clone(),
+                                    pre_op,
+                                    post_op,
+                                )
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    }
                 };
 
+                if res.as_ref().is_err_and(is_out_of_memory)
+                    && Self::recover_from_oom(
+                        &self.pipeline,
+                        &self.prefix_cacher,
+                        &mut scheduled.completion,
+                    )
+                {
+                    // The batch changed, or its KV cache was reset
+                    last_completion_ids = vec![];
+                    continue 'lp;
+                }
+
                 handle_pipeline_forward_error!(
                     "completion step",
                     res,
@@ -146,6 +367,13 @@ impl Engine {
             }
 
             if scheduled.prompt.len() > 0 {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time travel has occurred!")
+                    .as_millis();
+                for seq in scheduled.prompt.iter_mut() {
+                    seq.prefill_timestamp.get_or_insert(now);
+                }
                 let logits = {
                     let mut pipeline = get_mut_arcmutex!(self.pipeline);
 
@@ -196,10 +424,85 @@ impl Engine {
                         .duration_since(UNIX_EPOCH)
                         .expect("Time travel has occurred!")
                         .as_millis();
+                    let prefill_start = seq.prefill_timestamp.unwrap_or(seq.timestamp());
+                    #[allow(clippy::cast_precision_loss)]
+                    let prompt_tok_per_sec =
+                        seq.prompt_tokens() as f32 / (now - prefill_start).max(1) as f32;
+                    seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
+                    seq.prompt_timestamp = Some(now);
+                    forks.extend(Self::prefilled_forks(&self.prefix_cacher, seq));
+                }
+                last_completion_ids = vec![];
+            }
+
+            for seq in chunked_prompt.iter_mut() {
+                let chunk_size = self
+                    .prefill_chunk_size
+                    .expect("Chunked prefill is disabled.");
+                let (is_first, is_last) = seq.set_next_prefill_chunk(chunk_size);
+                if is_first {
+                    seq.prefill_timestamp.get_or_insert(
+                        SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .expect("Time travel has occurred!")
+                            .as_millis(),
+                    );
+                }
+                let seqs = std::slice::from_mut(seq);
+                let logits = {
+                    let mut pipeline = get_mut_arcmutex!(self.pipeline);
+                    let adapter_inst = seqs[0]
+                        .get_adapters()
+                        .map(AdapterInstruction::Activate)
+                        .unwrap_or(AdapterInstruction::None);
+
+                    // The first chunk starts from an empty cache, the others continue the sequence's cache
+                    let pre_op = if is_first {
+                        CacheInstruction::Reset {
+                            reset_non_granular: false,
+                            adapter_inst,
+                        }
+                    } else {
+                        CacheInstruction::In(adapter_inst)
+                    };
+                    pipeline
+                        .step(
+                            seqs,
+                            true,
+                            &mut self.prefix_cacher,
+                            self.disable_eos_stop,
+                            rng.clone(),
+                            pre_op,
+                            CacheInstruction::Out,
+                        )
+                        .await
+                };
+
+                handle_pipeline_forward_error!(
+                    "prompt step",
+                    logits,
+                    seqs,
+                    self.pipeline,
+                    'lp,
+                    self.prefix_cacher
+                );
+
+                let seq = &mut seqs[0];
+                if is_last {
+                    seq.set_state(SequenceState::RunningCompletion);
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .expect("Time travel has occurred!")
+                        .as_millis();
+                    let prefill_start = seq.prefill_timestamp.unwrap_or(seq.timestamp());
                     #[allow(clippy::cast_precision_loss)]
-                    let prompt_tok_per_sec = seq.len() as f32 / (now - seq.timestamp()) as f32;
+                    let prompt_tok_per_sec =
+                        seq.prompt_tokens() as f32 / (now - prefill_start).max(1) as f32;
                     seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                     seq.prompt_timestamp = Some(now);
+                    forks.extend(Self::prefilled_forks(&self.prefix_cacher, seq));
+                } else {
+                    seq.advance_prefill_chunk();
                 }
                 last_completion_ids = vec![];
             }
@@ -230,10 +533,38 @@ impl Engine {
                     );
                 }
             }
-            if scheduled.prompt.len() == 0
+            let toks_after: usize = scheduled
+                .prompt
+                .iter()
+                .chain(scheduled.completion.iter())
+                .chain(chunked_prompt.iter())
+                .map(|seq| seq.len())
+                .sum();
+            let is_idle = scheduled.prompt.len() == 0
                 && scheduled.completion.len() == 0
-                && self.scheduler.waiting_len() == 0
-            {
+                && chunked_prompt.is_empty();
+            let has_forks = !forks.is_empty();
+            for fork in forks {
+                self.scheduler.add_seq(fork);
+            }
+            if n_running > 0 {
+                let device = get_mut_arcmutex!(self.pipeline).device();
+                let mut metrics = get_mut_arcmutex!(self.metrics);
+                metrics.running_sequences = n_running;
+                metrics.waiting_sequences = self.scheduler.waiting_len();
+                metrics.generated_tokens += toks_after.saturating_sub(toks_before) as u64;
+                metrics.prefix_cache = self.prefix_cacher.stats();
+                metrics.prefix_cache_device_bytes = self.prefix_cacher.current_device_bytes();
+                metrics.device_memory_used_bytes = device_memory_used(&device);
+                metrics
+                    .step_latency
+                    .observe(run_start.elapsed().as_secs_f64());
+            }
+            if is_idle && !has_forks && self.scheduler.waiting_len() == 0 && warmup.is_none() {
+                if self.drain_deadline.is_some() {
+                    info!("All sequences are finished, stopping the engine.");
+                    break 'lp;
+                }
                 // If there is nothing to do, sleep until a request comes in
                 if let Some(request) = self.rx.recv().await {
                     self.handle_request(request).await;
@@ -242,12 +573,154 @@ impl Engine {
         }
     }
 
+    /// Split the prompt sequences of a step into those prefilled in chunks of `prefill_chunk_size` tokens, one chunk
+    /// per step and each sequence on its own, and the others.
+    fn split_chunked_prompts(
+        prompt: Vec<&mut Sequence>,
+        prefill_chunk_size: Option<usize>,
+    ) -> (Vec<&mut Sequence>, Vec<&mut Sequence>) {
+        prompt.into_iter().partition(|seq| {
+            prefill_chunk_size.is_some_and(|chunk_size| seq.is_chunked_prefill(chunk_size))
+        })
+    }
+
+    /// Take the sequences of a step which are cancelled without running it once the drain deadline has passed: the
+    /// decoding sequences, which finish with the tokens generated so far, and those prefilling their prompt in chunks,
+    /// which would run for several more steps. The other prompts are prefilled in this step, and cancelled after it.
+    fn take_drain_canceled<'a>(
+        completion: &mut Box<[&'a mut Sequence]>,
+        chunked_prompt: &mut Vec<&'a mut Sequence>,
+    ) -> Vec<&'a mut Sequence> {
+        std::mem::take(completion)
+            .into_vec()
+            .into_iter()
+            .chain(std::mem::take(chunked_prompt))
+            .collect()
+    }
+
+    /// Finish a sequence with the tokens it generated so far and send its response, without running the model.
+    async fn finish_canceled(
+        pipeline: &dyn Pipeline,
+        prefix_cacher: &mut PrefixCacheManager,
+        seq: &mut Sequence,
+    ) -> Result<()> {
+        seq.set_state(SequenceState::Done(StopReason::Canceled));
+        respond_to_seq!(
+            pipeline,
+            prefix_cacher,
+            seq,
+            Some(StopReason::Canceled),
+            false
+        );
+        Ok(())
+    }
+
+    /// Submit a batch of the maximum number of running sequences, each prefilling `prompt_len` dummy tokens and
+    /// decoding one token. They run through the same code paths as requests, so the kernels are compiled and the
+    /// caches allocated before the first request comes in. Returns the receivers of their responses.
+    async fn start_warmup(&mut self, prompt_len: usize) -> Vec<Receiver<Response>> {
+        let max_seq_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
+        // Leave room for the two generated tokens
+        let prompt_len = prompt_len.clamp(1, max_seq_len.saturating_sub(2).max(1));
+        let tok = self.prompt_special_tokens.bos_tok.unwrap_or(0);
+        info!(
+            "Warming up with {} sequences of {prompt_len} tokens.",
+            self.max_running
+        );
+        let mut pending = Vec::with_capacity(self.max_running);
+        for id in 0..self.max_running {
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            let request = NormalRequest {
+                messages: RequestMessage::CompletionTokens(vec![tok; prompt_len]),
+                sampling_params: SamplingParams {
+                    min_tokens: Some(2),
+                    max_len: Some(2),
+                    ..SamplingParams::default()
+                },
+                response: tx,
+                return_logprobs: false,
+                is_streaming: false,
+                id,
+                constraint: Constraint::None,
+                suffix: None,
+                adapters: None,
+                lora_scale: None,
+                priority: 0,
+                tools: None,
+            };
+            self.handle_request(Request::Normal(request)).await;
+            pending.push(rx);
+        }
+        pending
+    }
+
+    /// Drop what the warmup left behind, so that only requests are cached and measured.
+    fn finish_warmup(&mut self, start: Instant) {
+        self.prefix_cacher.clear();
+        self.prefix_cacher.reset_stats();
+        *get_mut_arcmutex!(self.metrics) = EngineMetrics::default();
+        self.ready.store(true, Ordering::SeqCst);
+        info!("Warmup finished in {:.2}s.", start.elapsed().as_secs_f64());
+    }
+
+    /// Free device memory after a completion step of `seqs` ran out of it, so that the step can be retried. The prefix
+    /// caches are evicted to the CPU if there are any on the device, and otherwise the lowest priority sequence, the
+    /// most recent one among equals, is preempted: its KV caches are moved to the CPU and the scheduler re-admits it
+    /// once enough running sequences finish. Returns `false` if there is nothing left to free.
+    fn recover_from_oom(
+        pipeline: &Arc<Mutex<dyn Pipeline>>,
+        prefix_cacher: &PrefixCacheManager,
+        seqs: &mut [&mut Sequence],
+    ) -> bool {
+        // The KV cache of the model may have been updated for some layers only
+        get_mut_arcmutex!(pipeline).set_none_cache(false, true);
+
+        if prefix_cacher.current_device_bytes() > 0 {
+            return match prefix_cacher.evict_all_to_cpu() {
+                Ok(_) => {
+                    warn!("Out of device memory, evicted the prefix caches to the CPU.");
+                    true
+                }
+                Err(e) => {
+                    warn!("Out of device memory, and evicting the prefix caches failed: {e:?}");
+                    false
+                }
+            };
+        }
+        if seqs.len() < 2 {
+            return false;
+        }
+        let seq = seqs
+            .iter_mut()
+            .min_by_key(|seq| (seq.aged_priority(), Reverse(*seq.id())))
+            .expect("No sequences to preempt.");
+        if let Err(e) = seq.move_caches(&Device::Cpu) {
+            warn!(
+                "Out of device memory, and preempting sequence {} failed: {e:?}",
+                seq.id()
+            );
+            return false;
+        }
+        seq.set_state(SequenceState::Preempted);
+        warn!(
+            "Out of device memory, preempted sequence {} until running sequences finish.",
+            seq.id()
+        );
+        true
+    }
+
     fn build_sequence_recognizer(constraint: &Constraint) -> anyhow::Result<SequenceRecognizer> {
         let recognizer = match constraint {
             Constraint::Regex(rx) => {
                 SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx)?)?.into())
             }
             Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
+            Constraint::Gbnf(gbnf) => {
+                SequenceRecognizer::Cfg(CfgParser::from_yacc(&gbnf_to_yacc(gbnf)?)?.into())
+            }
+            Constraint::JsonSchema(schema) => SequenceRecognizer::Cfg(
+                CfgParser::from_yacc(&gbnf_to_yacc(&json_schema_to_gbnf(schema)?)?)?.into(),
+            ),
             Constraint::None => SequenceRecognizer::None,
         };
         Ok(recognizer)
@@ -261,7 +734,12 @@ impl Engine {
             Some(bias) => {
                 let mut logits_bias = vec![0.0; vocab_size];
                 for (k, v) in bias {
-                    logits_bias[k as usize] = v;
+                    match logits_bias.get_mut(k as usize) {
+                        Some(logit_bias) => *logit_bias = v,
+                        None => warn!(
+                            "Ignoring logits bias for token {k}, which is outside the vocabulary of size {vocab_size}."
+                        ),
+                    }
                 }
                 Ok(Some(Tensor::from_vec(
                     logits_bias,
@@ -276,12 +754,68 @@ impl Engine {
     async fn handle_request(&mut self, request: Request) {
         match request {
             Request::ActivateAdapters(adapters) => {
-                match get_mut_arcmutex!(self.pipeline).activate_adapters(adapters) {
-                    Ok(n) => info!("Swapped adapters in {n} LoRA layers."),
+                match get_mut_arcmutex!(self.pipeline).activate_adapters(adapters.clone()) {
+                    Ok(n) => {
+                        info!("Swapped adapters in {n} LoRA layers.");
+                        self.default_adapters = Some(adapters);
+                    }
                     Err(e) => warn!("Adapter activation failed: {e:?}"),
                 }
             }
+            Request::AddAdapter { name, path } => {
+                match get_mut_arcmutex!(self.pipeline).add_adapter(name.clone(), &path) {
+                    Ok(n) => info!("Added adapter `{name}` to {n} LoRA layers."),
+                    Err(e) => warn!("Adding adapter `{name}` failed: {e:?}"),
+                }
+            }
+            Request::MergeAdapter(name) => {
+                match get_mut_arcmutex!(self.pipeline).merge_adapter(name.clone()) {
+                    Ok(0) => warn!("Adapter `{name}` is not loaded."),
+                    Ok(n) => {
+                        info!("Merged adapter `{name}` into {n} LoRA layers.");
+                        // The merged adapter is the active one, and the merged weights give slightly different caches
+                        self.prefix_cacher.clear();
+                        self.default_adapters = Some(vec![name]);
+                    }
+                    Err(e) => warn!("Merging adapter `{name}` failed: {e:?}"),
+                }
+            }
+            Request::UnmergeAdapter => match get_mut_arcmutex!(self.pipeline).unmerge_adapter() {
+                Ok(n) => {
+                    info!("Unmerged the adapter of {n} LoRA layers.");
+                    self.prefix_cacher.clear();
+                }
+                Err(e) => warn!("Unmerging the adapter failed: {e:?}"),
+            },
+            Request::Drain(timeout) => {
+                info!(
+                    "Draining: no new requests are accepted, and sequences still running in {}s are cancelled.",
+                    timeout.as_secs_f64()
+                );
+                self.drain_deadline = Some(Instant::now() + timeout);
+            }
+            Request::Normal(request) if self.drain_deadline.is_some() => {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "The engine is shutting down and accepts no new requests.".into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+            }
+            Request::Embedding(request) if self.drain_deadline.is_some() => {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "The engine is shutting down and accepts no new requests.".into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+            }
             Request::Normal(request) => self.add_request(request).await,
+            Request::Embedding(request) => self.embed(request).await,
+            Request::Tokenize(request) => self.tokenize(request).await,
+            Request::Detokenize(request) => self.detokenize(request).await,
             Request::ReIsq(level) => {
                 if let Err(e) = get_mut_arcmutex!(self.pipeline).re_isq_model(level) {
                     warn!("ISQ requantization failed: {e:?}");
@@ -290,7 +824,103 @@ impl Engine {
         }
     }
 
+    async fn embed(&mut self, request: EmbeddingRequest) {
+        let (embeddings, prompt_tokens, model) = {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            let prompt_tokens = pipeline
+                .tokenizer()
+                .encode_batch(request.inputs.clone(), true)
+                .map(|encodings| encodings.iter().map(|e| e.get_ids().len()).sum())
+                .unwrap_or(0);
+            let embeddings = pipeline.embed(request.inputs, request.pooling, request.normalize);
+            (embeddings, prompt_tokens, pipeline.name())
+        };
+        let response = match embeddings {
+            Ok(embeddings) => Response::Embeddings(EmbeddingResponse {
+                data: embeddings
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, embedding)| Embedding {
+                        index,
+                        embedding,
+                        object: "embedding".to_string(),
+                    })
+                    .collect(),
+                model,
+                object: "list".to_string(),
+                usage: EmbeddingUsage {
+                    prompt_tokens,
+                    total_tokens: prompt_tokens,
+                },
+            }),
+            Err(e) => Response::ValidationError(e.into()),
+        };
+        request
+            .response
+            .send(response)
+            .await
+            .expect("Expected receiver.");
+    }
+
+    async fn tokenize(&mut self, request: TokenizationRequest) {
+        let prompt = match request.prompt {
+            TokenizationPrompt::Chat(mut messages) => {
+                // Count the default system prompt, as it is added to the chat completions
+                if let Some(ref system_prompt) = self.default_system_prompt {
+                    add_default_system_prompt(&mut messages, system_prompt);
+                }
+                TokenizationPrompt::Chat(messages)
+            }
+            prompt => prompt,
+        };
+        let response = {
+            let pipeline = &*get_mut_arcmutex!(self.pipeline);
+            match pipeline.tokenize_prompt(prompt, request.tools) {
+                Ok(tokens) => Response::Tokenized(TokenizationResponse {
+                    count: tokens.len(),
+                    tokens,
+                    max_model_len: pipeline.get_metadata().max_seq_len,
+                }),
+                Err(e) => Response::ValidationError(e.into()),
+            }
+        };
+        request
+            .response
+            .send(response)
+            .await
+            .expect("Expected receiver.");
+    }
+
+    async fn detokenize(&mut self, request: DetokenizationRequest) {
+        let text = get_mut_arcmutex!(self.pipeline)
+            .tokenizer()
+            .decode(&request.tokens, request.skip_special_tokens);
+        let response = match text {
+            Ok(text) => Response::Detokenized(DetokenizationResponse { text }),
+            Err(e) => Response::ValidationError(e.to_string().into()),
+        };
+        request
+            .response
+            .send(response)
+            .await
+            .expect("Expected receiver.");
+    }
+
     async fn add_request(&mut self, request: NormalRequest) {
+        if let Some(max_queued) = self.max_queued_requests {
+            let queued = self.scheduler.waiting_len();
+            if queued >= max_queued {
+                request
+                    .response
+                    .send(Response::Busy(
+                        format!("The engine is busy: {queued} sequences are waiting to be scheduled, retry later.")
+                            .into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
         let is_chat = matches!(
             request.messages,
             RequestMessage::Chat(_) | RequestMessage::VisionChat { .. }
@@ -303,11 +933,156 @@ impl Engine {
             }
         );
 
+        // Echoing the prompt with logprobs also scores the prompt tokens
+        let prompt_logprobs = echo_prompt && request.return_logprobs;
+        if prompt_logprobs && !get_mut_arcmutex!(self.pipeline).supports_prompt_logprobs() {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "This model does not support logprobs for the echoed prompt.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        if let Some(lora_scale) = request.lora_scale {
+            let err = if !lora_scale.is_finite() {
+                Some(format!("The LoRA scale must be finite, got {lora_scale}."))
+            } else if !get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .kind
+                .is_adapted_and(|a| a.is_lora())
+            {
+                Some(
+                    "The LoRA scale is only supported for models fine-tuned with LoRA.".to_string(),
+                )
+            } else {
+                None
+            };
+            if let Some(err) = err {
+                request
+                    .response
+                    .send(Response::ValidationError(err.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
+        // Only completions take `best_of`, the other messages generate exactly the choices they return
         let best_of = match request.messages {
             RequestMessage::Completion { best_of, .. } => best_of,
             RequestMessage::Chat(_)
             | RequestMessage::CompletionTokens(_)
-            | RequestMessage::VisionChat { .. } => 1,
+            | RequestMessage::VisionChat { .. } => request.sampling_params.n_choices,
+        };
+
+        if best_of < request.sampling_params.n_choices {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "`best_of` must be at least the number of choices.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        if best_of > request.sampling_params.n_choices && request.is_streaming {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "`best_of` does not support streaming.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+
+        // With a suffix, a completion fills in the middle if the model has FIM tokens. Otherwise, the suffix is
+        // appended to the completion.
+        let fim_tokens = match (&request.messages, &request.suffix) {
+            (RequestMessage::Completion { .. }, Some(_)) => {
+                get_mut_arcmutex!(self.pipeline).get_metadata().fim_tokens
+            }
+            _ => None,
+        };
+        if fim_tokens.is_some() && echo_prompt {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "`echo` is not supported for fill-in-the-middle completions.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+
+        let num_beams = request.sampling_params.num_beams.filter(|n| *n > 1);
+        if let Some(num_beams) = num_beams {
+            let err = if request.is_streaming {
+                Some("Beam search does not support streaming.".to_string())
+            } else if !matches!(request.constraint, Constraint::None) {
+                Some("Beam search does not support grammars.".to_string())
+            } else if request.sampling_params.n_choices > num_beams {
+                Some(format!(
+                    "Cannot return {} choices from {num_beams} beams.",
+                    request.sampling_params.n_choices
+                ))
+            } else if !get_mut_arcmutex!(self.pipeline).supports_beam_search() {
+                Some("This model does not support beam search.".to_string())
+            } else if get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .kv_cache_block_size
+                .is_some()
+            {
+                Some("Beam search does not support a paged KV cache.".to_string())
+            } else {
+                None
+            };
+            if let Some(err) = err {
+                request
+                    .response
+                    .send(Response::ValidationError(err.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
+        let contrastive = match request.sampling_params.penalty_alpha {
+            Some(penalty_alpha) => {
+                let err = if !(0.0..=1.0).contains(&penalty_alpha) {
+                    Some(format!(
+                        "The penalty alpha must be between 0 and 1, got {penalty_alpha}."
+                    ))
+                } else if !request.sampling_params.top_k.is_some_and(|top_k| top_k > 0) {
+                    Some("Contrastive search requires a `top_k` of at least 1.".to_string())
+                } else if num_beams.is_some() {
+                    Some("Contrastive search cannot be combined with beam search.".to_string())
+                } else if !matches!(request.constraint, Constraint::None) {
+                    Some("Contrastive search does not support grammars.".to_string())
+                } else if !get_mut_arcmutex!(self.pipeline).supports_contrastive_search() {
+                    Some("This model does not support contrastive search.".to_string())
+                } else {
+                    None
+                };
+                if let Some(err) = err {
+                    request
+                        .response
+                        .send(Response::ValidationError(err.into()))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                request
+                    .sampling_params
+                    .top_k
+                    .map(|top_k| ContrastiveParams {
+                        top_k,
+                        penalty_alpha,
+                    })
+            }
+            None => None,
         };
         if is_chat
             && !get_mut_arcmutex!(self.pipeline)
@@ -322,6 +1097,25 @@ impl Engine {
             return;
         }
 
+        // Control tokens in the messages would be parsed as such once the chat template is rendered
+        if self.plain_text_tokenizer.is_some() {
+            if let RequestMessage::Chat(messages) | RequestMessage::VisionChat { messages, .. } =
+                &request.messages
+            {
+                let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
+                if let Some(tok) = find_special_token(messages, &tokenizer) {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            format!("The messages contain the special token `{tok}`, which is not allowed as special-token parsing is disabled.").into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+            }
+        }
+
         let images = match request.messages {
             RequestMessage::VisionChat {
                 ref images,
@@ -330,24 +1124,62 @@ impl Engine {
             _ => None,
         };
 
+        // The prompt and the generated tokens must fit in the context length of the model
+        let max_seq_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
+        let max_len = request.sampling_params.max_len;
+        let fits = |prompt_len: usize| prompt_len + max_len.unwrap_or(0) <= max_seq_len;
+        // Prompts given as tokens are used as they are, the others get the BOS and EOS tokens of the model
+        let is_token_prompt = matches!(request.messages, RequestMessage::CompletionTokens(_));
+
         let mut prompt = match request.messages {
-            RequestMessage::Chat(messages)
+            RequestMessage::Chat(mut messages)
             | RequestMessage::VisionChat {
                 images: _,
-                messages,
+                mut messages,
             } => {
+                if let Some(ref system_prompt) = self.default_system_prompt {
+                    add_default_system_prompt(&mut messages, system_prompt);
+                }
                 let pipeline = &*get_mut_arcmutex!(self.pipeline);
-                let template = pipeline.get_processor().process(pipeline, messages, true);
+                let mut template = pipeline.get_processor().process(
+                    pipeline,
+                    messages.clone(),
+                    true,
+                    request.tools.clone(),
+                );
+                // A conversation which is too long loses its oldest turns first. The images of vision messages
+                // are matched to the messages by position, so these are only truncated as tokens.
+                if self.truncate_sequence && images.is_none() {
+                    while template.as_ref().is_ok_and(|prompt| !fits(prompt.len()))
+                        && drop_oldest_turn(&mut messages)
+                    {
+                        template = pipeline.get_processor().process(
+                            pipeline,
+                            messages.clone(),
+                            true,
+                            request.tools.clone(),
+                        );
+                    }
+                }
                 handle_seq_error!(template, request.response)
             }
             RequestMessage::Completion { text, .. } => {
-                let prompt = get_mut_arcmutex!(self.pipeline)
-                    .tokenizer()
-                    .encode(text, false)
-                    .map_err(|e| anyhow::Error::msg(e.to_string()));
+                let tokenizer = match &self.plain_text_tokenizer {
+                    Some(tokenizer) => tokenizer.clone(),
+                    None => get_mut_arcmutex!(self.pipeline).tokenizer(),
+                };
+                let encode = |text: &str| {
+                    tokenizer
+                        .encode(text, false)
+                        .map(|encoding| encoding.get_ids().to_vec())
+                        .map_err(|e| anyhow::Error::msg(e.to_string()))
+                };
+                let prompt = match (fim_tokens, &request.suffix) {
+                    (Some(fim_tokens), Some(suffix)) => encode(&text)
+                        .and_then(|prefix| Ok(fim_tokens.prompt(&prefix, &encode(suffix)?))),
+                    _ => encode(&text),
+                };
                 handle_seq_error!(prompt, request.response)
-                    .get_ids()
-                    .to_vec()
             }
             RequestMessage::CompletionTokens(it) => it,
         };
@@ -361,36 +1193,74 @@ impl Engine {
                 .expect("Expected receiver.");
             return;
         }
+        if !is_token_prompt {
+            self.prompt_special_tokens.apply(&mut prompt);
+        }
 
-        if prompt.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
+        if !fits(prompt.len()) {
             if !self.truncate_sequence {
+                let err = match max_len {
+                    Some(max_len) => format!(
+                        "The prompt ({} tokens) and `max_tokens` ({max_len}) exceed the context length of the model ({max_seq_len} tokens). Reduce the length of the prompt or of `max_tokens`, or consider using `truncate_sequence`.",
+                        prompt.len()
+                    ),
+                    None => format!(
+                        "The prompt ({} tokens) exceeds the context length of the model ({max_seq_len} tokens), perhaps consider using `truncate_sequence`?",
+                        prompt.len()
+                    ),
+                };
                 request
                     .response
-                    .send(Response::ValidationError(
-                        format!("Prompt sequence length is greater than {}, perhaps consider using `truncate_sequence`?", get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len).into(),
-                    )).await.expect("Expected receiver.");
+                    .send(Response::ValidationError(err.into()))
+                    .await
+                    .expect("Expected receiver.");
                 return;
             } else {
+                // Keep the end of the prompt, leaving room for `max_tokens` tokens, or 10 if it is not given or does
+                // not leave any room for the prompt
                 let prompt_len = prompt.len();
-                let max_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
-                let currently_over = prompt_len - max_len;
-                let sampling_max = if let Some(sampling_max) = request.sampling_params.max_len {
-                    if currently_over + sampling_max >= prompt_len {
-                        10
-                    } else {
-                        sampling_max
-                    }
-                } else {
-                    10
-                };
-                prompt = prompt[(currently_over + sampling_max)..].to_vec();
-                warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt.len());
+                let sampling_max = max_len
+                    .filter(|max_len| *max_len < max_seq_len)
+                    .unwrap_or(10);
+                let truncated =
+                    prompt_len - max_seq_len.saturating_sub(sampling_max).min(prompt_len);
+                // A BOS token which starts the prompt is kept, in place of the first token left
+                let bos = prompt.first().copied().filter(|tok| {
+                    !is_token_prompt && Some(*tok) == self.prompt_special_tokens.bos_tok
+                });
+                prompt = prompt[truncated..].to_vec();
+                if let (Some(bos), Some(first)) = (bos, prompt.first_mut()) {
+                    *first = bos;
+                }
+                warn!("Prompt for request {} was {} tokens long, the model maximum length is {max_seq_len}. The first {truncated} tokens were truncated to make space for generation.", request.id, prompt_len);
+            }
+        }
+        // Sequences are batched by their adapters, which are activated before every step. Requests without adapters
+        // run with the activated ones, so that they are not batched with (and run with the adapters of) other requests.
+        let adapters = request
+            .adapters
+            .clone()
+            .or_else(|| self.default_adapters.clone());
+        // Cached prefixes are not run through the model, so they cannot be scored
+        let prefill_cache = if prompt_logprobs {
+            None
+        } else {
+            handle_seq_error!(
+                self.prefix_cacher
+                    .search_for_matching_cache(&prompt, adapters.as_deref()),
+                request.response
+            )
+        };
+        if self.is_debug {
+            if let Some(ref prefill_cache) = prefill_cache {
+                info!(
+                    "Prefix cache matched {} of {} prompt tokens for request {}.",
+                    prefill_cache.matched_len,
+                    prompt.len(),
+                    request.id
+                );
             }
         }
-        let prefill_cache = handle_seq_error!(
-            self.prefix_cacher.search_for_matching_cache(&prompt),
-            request.response
-        );
 
         let topk = request
             .sampling_params
@@ -402,7 +1272,7 @@ impl Engine {
             .get_metadata()
             .num_hidden_layers;
 
-        let (stop_toks, stop_strings) = match request.sampling_params.stop_toks {
+        let (mut stop_toks, stop_strings) = match request.sampling_params.stop_toks {
             None => (vec![], vec![]),
             Some(StopTokens::Ids(ref i)) => {
                 let tok_trie = {
@@ -455,13 +1325,24 @@ impl Engine {
                 (stop_toks, stop_strings)
             }
         };
+        if let Some(ref stop_token_ids) = request.sampling_params.stop_token_ids {
+            stop_toks.extend(stop_token_ids);
+        }
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
-            request.sampling_params.n_choices,
+        // A beam search runs one sequence per beam and `best_of` runs one per candidate, returning the best
+        // `n_choices` of them
+        let n_seqs = num_beams.unwrap_or(best_of);
+        let group = SequenceGroup::new(
+            n_seqs,
             request.is_streaming,
             is_chat,
-            best_of,
-        )));
+            request.sampling_params.n_choices,
+        );
+        let group = Arc::new(tokio::sync::Mutex::new(if num_beams.is_some() {
+            group.with_beam_search()
+        } else {
+            group
+        }));
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time travel has occurred!");
@@ -490,6 +1371,21 @@ impl Engine {
             logits_bias,
             topk,
             topp,
+            request.sampling_params.top_n_sigma,
+            request
+                .sampling_params
+                .mirostat_tau
+                .map(|tau| MirostatParams {
+                    tau,
+                    eta: request.sampling_params.mirostat_eta.unwrap_or(0.1),
+                }),
+            request
+                .sampling_params
+                .repetition_penalty
+                .map(|penalty| RepetitionPenalty {
+                    penalty,
+                    last_n: request.sampling_params.repetition_penalty_range,
+                }),
         );
 
         if request.sampling_params.n_choices == 0 {
@@ -502,9 +1398,99 @@ impl Engine {
                 .expect("Expected receiver.");
             return;
         }
+        if request
+            .sampling_params
+            .repetition_penalty
+            .is_some_and(|penalty| penalty <= 0.)
+        {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "The repetition penalty must be greater than 0.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        // Greedy decoding does not sample, so it cannot target a surprise
+        if request.sampling_params.mirostat_tau.is_some()
+            && request
+                .sampling_params
+                .temperature
+                .is_some_and(|temperature| temperature < 1e-7)
+        {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Mirostat sampling requires a temperature greater than 0.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        if request
+            .sampling_params
+            .eos_token_ids
+            .as_ref()
+            .is_some_and(|eos_token_ids| eos_token_ids.is_empty())
+        {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "At least one EOS token id must be given.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+        if let (Some(min_tokens), Some(max_len)) = (
+            request.sampling_params.min_tokens,
+            request.sampling_params.max_len,
+        ) {
+            if min_tokens > max_len {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        format!(
+                            "`min_tokens` ({min_tokens}) must not be greater than `max_tokens` ({max_len})."
+                        )
+                        .into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+        // Until `min_tokens` tokens are generated, neither EOS nor a stop token may be sampled
+        let min_tokens_suppressed = request.sampling_params.min_tokens.map(|_| {
+            let mut suppressed = match request.sampling_params.eos_token_ids {
+                Some(ref eos_token_ids) => eos_token_ids.clone(),
+                None => get_mut_arcmutex!(self.pipeline)
+                    .get_metadata()
+                    .eos_tok
+                    .clone(),
+            };
+            suppressed.extend(&stop_toks);
+            suppressed
+        });
+
+        // The choices of a request share the prefill of their prompt: the first sequence runs it and the others are
+        // forked from its KV cache. Prompts with images or scored prompt tokens, or whose KV caches are paged, are run
+        // by every sequence.
+        let fork_prompt = n_seqs > 1
+            && num_beams.is_none()
+            && !self.no_kv_cache
+            && get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .kv_cache_block_size
+                .is_none()
+            && !prompt_logprobs
+            && images.is_none()
+            && prompt.len() > 1;
 
         // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
+        let mut seqs = Vec::with_capacity(n_seqs);
+        for response_index in 0..n_seqs {
             let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
                 Ok(recognizer) => recognizer,
                 Err(err) => {
@@ -529,13 +1515,15 @@ impl Engine {
                 stop_toks.clone(),
                 stop_strings.clone(),
                 request.sampling_params.max_len,
+                request.sampling_params.max_time,
                 request.return_logprobs,
                 get_mut_arcmutex!(self.pipeline).get_metadata().is_xlora,
                 group.clone(),
                 response_index,
                 now.as_secs(),
                 recognizer,
-                request.suffix.clone(),
+                // The middle of a FIM completion is returned on its own
+                request.suffix.clone().filter(|_| fim_tokens.is_none()),
                 if echo_prompt {
                     Some(
                         get_mut_arcmutex!(self.pipeline)
@@ -546,9 +1534,53 @@ impl Engine {
                 } else {
                     None
                 },
-                request.adapters.clone(),
+                adapters.clone(),
                 images.clone(),
+                request.priority,
             );
+            let seq = if prompt_logprobs {
+                seq.with_prompt_logprobs()
+            } else {
+                seq
+            };
+            let seq = seq.with_lora_scale(request.lora_scale.unwrap_or(1.));
+            let seq = if num_beams.is_some() {
+                seq.with_beam_search(request.sampling_params.length_penalty.unwrap_or(1.0))
+            } else {
+                seq
+            };
+            let seq = if let Some(contrastive) = contrastive {
+                seq.with_contrastive_search(contrastive)
+            } else {
+                seq
+            };
+            let seq = if let Some(seed) = request.sampling_params.seed {
+                seq.with_seed(seed.wrapping_add(response_index as u64))
+            } else {
+                seq
+            };
+            let seq = if let Some(size) = request.sampling_params.no_repeat_ngram_size {
+                seq.with_no_repeat_ngram_size(size)
+            } else {
+                seq
+            };
+            let seq = if let Some(ref eos_token_ids) = request.sampling_params.eos_token_ids {
+                seq.with_eos_toks(eos_token_ids.clone())
+            } else {
+                seq
+            };
+            let seq = if let (Some(min_tokens), Some(suppressed)) =
+                (request.sampling_params.min_tokens, &min_tokens_suppressed)
+            {
+                seq.with_min_tokens(min_tokens, suppressed.clone())
+            } else {
+                seq
+            };
+            let seq = if request.tools.is_some() && is_chat {
+                seq.with_tool_calls()
+            } else {
+                seq
+            };
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 seq.prefill(
                     prefill_cache.normal,
@@ -559,7 +1591,136 @@ impl Engine {
                 seq
             };
             self.id += 1;
-            self.scheduler.add_seq(seq);
+            seqs.push(seq);
+        }
+        if fork_prompt {
+            let mut seqs = seqs.into_iter();
+            let first = seqs.next().expect("At least one sequence.");
+            self.scheduler.add_seq(first.with_forks(seqs.collect()));
+        } else {
+            for seq in seqs {
+                self.scheduler.add_seq(seq);
+            }
+        }
+    }
+
+    /// The sequences forked from `seq`, whose prompt was just prefilled, starting from its KV cache. If the cache
+    /// cannot be shared, they run the prompt themselves.
+    fn prefilled_forks(prefix_cacher: &PrefixCacheManager, seq: &mut Sequence) -> Vec<Sequence> {
+        let forks = seq.take_forks();
+        if forks.is_empty() {
+            return forks;
         }
+        match prefix_cacher.fork_cache(seq) {
+            Ok(cache) => forks
+                .into_iter()
+                .map(|fork| {
+                    fork.prefill(
+                        cache.normal.clone(),
+                        cache.xlora.clone(),
+                        cache.toks.clone(),
+                    )
+                })
+                .collect(),
+            Err(e) => {
+                warn!(
+                    "Failed to share the prompt cache of sequence {}: {e}",
+                    seq.id()
+                );
+                forks
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokenizers::{models::wordlevel::WordLevel, Tokenizer};
+    use tokio::sync::{mpsc::channel, Mutex};
+
+    use super::Engine;
+    use crate::{
+        sampler::Sampler,
+        sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
+    };
+
+    fn seq(id: usize, n_toks: usize, state: SequenceState) -> Sequence {
+        let sampler = Sampler::new(
+            None,
+            0,
+            Arc::new(Tokenizer::new(WordLevel::default())),
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            None,
+            None,
+            None,
+        );
+        let seq = Sequence::new_waiting(
+            vec![1; n_toks],
+            id,
+            0,
+            1,
+            channel(1).0,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            None,
+            false,
+            false,
+            Arc::new(Mutex::new(SequenceGroup::new(1, false, false, 1))),
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            0,
+        );
+        seq.set_state(state);
+        seq
+    }
+
+    #[test]
+    fn drain_deadline_cancels_chunked_prompts() {
+        let mut long = seq(0, 10, SequenceState::RunningPrompt);
+        let mut prefilling = seq(1, 10, SequenceState::RunningPrompt);
+        prefilling.set_next_prefill_chunk(4);
+        prefilling.advance_prefill_chunk();
+        let mut short = seq(2, 3, SequenceState::RunningPrompt);
+        let mut decoding = seq(3, 12, SequenceState::RunningCompletion);
+
+        // Without chunked prefill, every prompt is prefilled in one step
+        let (chunked, prompt) =
+            Engine::split_chunked_prompts(vec![&mut long, &mut prefilling, &mut short], None);
+        assert!(chunked.is_empty());
+        assert_eq!(prompt.len(), 3);
+
+        let (mut chunked, prompt) =
+            Engine::split_chunked_prompts(vec![&mut long, &mut prefilling, &mut short], Some(4));
+        assert_eq!(
+            chunked.iter().map(|seq| *seq.id()).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert_eq!(
+            prompt.iter().map(|seq| *seq.id()).collect::<Vec<_>>(),
+            vec![2]
+        );
+
+        // The prompt prefilled in one step is left to run, the others are cancelled without another step
+        let mut completion: Box<[&mut Sequence]> = vec![&mut decoding].into();
+        let canceled = Engine::take_drain_canceled(&mut completion, &mut chunked);
+        assert_eq!(
+            canceled.iter().map(|seq| *seq.id()).collect::<Vec<_>>(),
+            vec![3, 0, 1]
+        );
+        assert!(completion.is_empty());
+        assert!(chunked.is_empty());
     }
 }
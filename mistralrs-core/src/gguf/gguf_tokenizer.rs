@@ -28,6 +28,8 @@ pub(crate) struct GgufTokenizerConversion {
     pub bos: Option<String>,
     pub eos: Option<String>,
     pub unk: Option<String>,
+    pub add_bos_token: Option<bool>,
+    pub add_eos_token: Option<bool>,
 }
 
 struct PropsGGUF {
@@ -40,6 +42,7 @@ struct PropsGGUF {
     eos: u32,
     bos: u32,
     add_bos_token: Option<bool>,
+    add_eos_token: Option<bool>,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
@@ -59,6 +62,7 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
             eos: c.get_value("eos_token_id")?,
             bos: c.get_value("bos_token_id")?,
             add_bos_token: c.get_value("add_bos_token").ok(),
+            add_eos_token: c.get_value("add_eos_token").ok(),
         };
 
         Ok(props)
@@ -105,6 +109,8 @@ pub fn convert_gguf_to_hf_tokenizer(content: &Content) -> Result<GgufTokenizerCo
         bos: Some(bos),
         eos: Some(eos),
         unk,
+        add_bos_token: props.add_bos_token,
+        add_eos_token: props.add_eos_token,
     })
 }
 
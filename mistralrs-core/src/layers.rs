@@ -15,6 +15,7 @@ use candle_core::{
     DType, Device, IndexOp, Result, Shape, Tensor, D,
 };
 use candle_nn::{Linear, Module, VarBuilder};
+use serde::{Deserialize, Serialize};
 
 pub use crate::layers_masker::CausalMasker;
 pub use crate::layers_utils::{flash_attn, repeat_kv};
@@ -243,6 +244,432 @@ impl PhiRotaryEmbedding {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct RawRopeScaling {
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    tp: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rope_type: Option<String>,
+    factor: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original_max_position_embeddings: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attention_factor: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    beta_fast: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    beta_slow: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    low_freq_factor: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    high_freq_factor: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawRopeScaling", into = "RawRopeScaling")]
+/// Scaling of the rotary embedding, to run a model past the context length it was trained on. It is the
+/// `rope_scaling` of the `config.json`, as in Hugging Face Transformers, where the type is given by `type` or
+/// `rope_type`.
+pub enum RopeScaling {
+    /// Positions are divided by `factor` (position interpolation), so the context is `factor` times longer.
+    Linear { factor: f64 },
+    /// The base is scaled so that the context is `factor` times longer (NTK-aware scaling). Unlike Transformers,
+    /// which rescales the base as the sequence grows, the base is fixed for the extended context so the KV cache
+    /// stays valid.
+    Dynamic { factor: f64 },
+    /// YaRN: <https://arxiv.org/abs/2309.00071>. Defaults to the `max_position_embeddings` of the model as the
+    /// original context length, and to `0.1 * ln(factor) + 1` as the attention factor.
+    Yarn {
+        factor: f64,
+        original_max_position_embeddings: Option<usize>,
+        attention_factor: Option<f64>,
+        beta_fast: f64,
+        beta_slow: f64,
+    },
+    /// The scaling of Llama 3.1, where only the low frequencies are interpolated.
+    Llama3 {
+        factor: f64,
+        low_freq_factor: f64,
+        high_freq_factor: f64,
+        original_max_position_embeddings: usize,
+    },
+}
+
+impl TryFrom<RawRopeScaling> for RopeScaling {
+    type Error = String;
+
+    fn try_from(raw: RawRopeScaling) -> std::result::Result<Self, Self::Error> {
+        let Some(tp) = raw.rope_type.or(raw.tp) else {
+            return Err("RoPE scaling is missing its `type`.".to_string());
+        };
+        if raw.factor.is_nan() || raw.factor < 1. {
+            return Err(format!(
+                "RoPE scaling factor must be at least 1, got {}.",
+                raw.factor
+            ));
+        }
+        let factor = raw.factor;
+        match tp.as_str() {
+            "linear" => Ok(Self::Linear { factor }),
+            "dynamic" => Ok(Self::Dynamic { factor }),
+            "yarn" => Ok(Self::Yarn {
+                factor,
+                original_max_position_embeddings: raw.original_max_position_embeddings,
+                attention_factor: raw.attention_factor,
+                beta_fast: raw.beta_fast.unwrap_or(32.),
+                beta_slow: raw.beta_slow.unwrap_or(1.),
+            }),
+            "llama3" => match (
+                raw.low_freq_factor,
+                raw.high_freq_factor,
+                raw.original_max_position_embeddings,
+            ) {
+                (Some(low_freq_factor), Some(high_freq_factor), Some(original))
+                    if high_freq_factor > low_freq_factor =>
+                {
+                    Ok(Self::Llama3 {
+                        factor,
+                        low_freq_factor,
+                        high_freq_factor,
+                        original_max_position_embeddings: original,
+                    })
+                }
+                _ => Err("Llama 3 RoPE scaling needs `low_freq_factor`, a greater `high_freq_factor` and `original_max_position_embeddings`.".to_string()),
+            },
+            tp => Err(format!("Unknown RoPE scaling type `{tp}`.")),
+        }
+    }
+}
+
+impl From<RopeScaling> for RawRopeScaling {
+    fn from(scaling: RopeScaling) -> Self {
+        let mut raw = Self {
+            tp: None,
+            rope_type: None,
+            factor: scaling.factor(),
+            original_max_position_embeddings: None,
+            attention_factor: None,
+            beta_fast: None,
+            beta_slow: None,
+            low_freq_factor: None,
+            high_freq_factor: None,
+        };
+        let tp = match scaling {
+            RopeScaling::Linear { .. } => "linear",
+            RopeScaling::Dynamic { .. } => "dynamic",
+            RopeScaling::Yarn {
+                original_max_position_embeddings,
+                attention_factor,
+                beta_fast,
+                beta_slow,
+                ..
+            } => {
+                raw.original_max_position_embeddings = original_max_position_embeddings;
+                raw.attention_factor = attention_factor;
+                raw.beta_fast = Some(beta_fast);
+                raw.beta_slow = Some(beta_slow);
+                "yarn"
+            }
+            RopeScaling::Llama3 {
+                low_freq_factor,
+                high_freq_factor,
+                original_max_position_embeddings,
+                ..
+            } => {
+                raw.low_freq_factor = Some(low_freq_factor);
+                raw.high_freq_factor = Some(high_freq_factor);
+                raw.original_max_position_embeddings = Some(original_max_position_embeddings);
+                "llama3"
+            }
+        };
+        raw.tp = Some(tp.to_string());
+        raw
+    }
+}
+
+impl FromStr for RopeScaling {
+    type Err = String;
+
+    /// Parse a `rope_scaling` JSON object, or `TYPE:FACTOR` such as `linear:2` or `yarn:4`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.trim_start().starts_with('{') {
+            return serde_json::from_str(s).map_err(|e| e.to_string());
+        }
+        let (tp, factor) = s.split_once(':').ok_or_else(|| {
+            format!("RoPE scaling `{s}` should be a JSON object or formatted like `TYPE:FACTOR`")
+        })?;
+        let factor = factor
+            .parse()
+            .map_err(|_| format!("Invalid RoPE scaling factor `{factor}`"))?;
+        serde_json::from_value(serde_json::json!({ "type": tp, "factor": factor }))
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl RopeScaling {
+    pub fn factor(&self) -> f64 {
+        match self {
+            Self::Linear { factor }
+            | Self::Dynamic { factor }
+            | Self::Yarn { factor, .. }
+            | Self::Llama3 { factor, .. } => *factor,
+        }
+    }
+
+    /// The context length of a model with `max_position_embeddings` once scaled.
+    pub fn context_len(&self, max_position_embeddings: usize) -> usize {
+        let scaled = |len: usize| (len as f64 * self.factor()) as usize;
+        match self {
+            Self::Linear { .. } | Self::Dynamic { .. } => scaled(max_position_embeddings),
+            Self::Yarn {
+                original_max_position_embeddings: None,
+                ..
+            } => scaled(max_position_embeddings),
+            Self::Yarn {
+                original_max_position_embeddings: Some(original),
+                ..
+            }
+            | Self::Llama3 {
+                original_max_position_embeddings: original,
+                ..
+            } => max_position_embeddings.max(scaled(*original)),
+        }
+    }
+
+    /// The scaled inverse frequencies of the rotary embedding, and the factor applied to the sin and cos.
+    fn inv_freq(&self, base: f32, dim: usize, max_position_embeddings: usize) -> (Vec<f32>, f32) {
+        let inv_freq = |base: f64| -> Vec<f64> {
+            (0..dim)
+                .step_by(2)
+                .map(|i| 1. / base.powf(i as f64 / dim as f64))
+                .collect()
+        };
+        let base = f64::from(base);
+        let (inv_freq, mscale) = match *self {
+            Self::Linear { factor } => {
+                (inv_freq(base).into_iter().map(|f| f / factor).collect(), 1.)
+            }
+            Self::Dynamic { factor } => {
+                // The base of Transformers once the sequence reaches the extended context
+                let base =
+                    base * (factor * factor - factor + 1.).powf(dim as f64 / (dim as f64 - 2.));
+                (inv_freq(base), 1.)
+            }
+            Self::Yarn {
+                factor,
+                original_max_position_embeddings,
+                attention_factor,
+                beta_fast,
+                beta_slow,
+            } => {
+                let original = original_max_position_embeddings.unwrap_or(max_position_embeddings);
+                let correction_dim = |num_rotations: f64| {
+                    dim as f64
+                        * (original as f64 / (num_rotations * 2. * std::f64::consts::PI)).ln()
+                        / (2. * base.ln())
+                };
+                let low = correction_dim(beta_fast).floor().max(0.);
+                let mut high = correction_dim(beta_slow).ceil().min(dim as f64 - 1.);
+                if low == high {
+                    high += 0.001;
+                }
+                let inv_freq = inv_freq(base)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, f)| {
+                        // 1 for the high frequencies, which are extrapolated, 0 for the interpolated ones
+                        let extrapolation = 1. - ((i as f64 - low) / (high - low)).clamp(0., 1.);
+                        f / factor * (1. - extrapolation) + f * extrapolation
+                    })
+                    .collect();
+                let mscale = attention_factor.unwrap_or(0.1 * factor.ln() + 1.);
+                (inv_freq, mscale)
+            }
+            Self::Llama3 {
+                factor,
+                low_freq_factor,
+                high_freq_factor,
+                original_max_position_embeddings,
+            } => {
+                let original = original_max_position_embeddings as f64;
+                let low_freq_wavelen = original / low_freq_factor;
+                let high_freq_wavelen = original / high_freq_factor;
+                let inv_freq = inv_freq(base)
+                    .into_iter()
+                    .map(|f| {
+                        let wavelen = 2. * std::f64::consts::PI / f;
+                        if wavelen < high_freq_wavelen {
+                            f
+                        } else if wavelen > low_freq_wavelen {
+                            f / factor
+                        } else {
+                            let smooth = (original / wavelen - low_freq_factor)
+                                / (high_freq_factor - low_freq_factor);
+                            (1. - smooth) * f / factor + smooth * f
+                        }
+                    })
+                    .collect();
+                (inv_freq, 1.)
+            }
+        };
+        (
+            inv_freq.into_iter().map(|f: f64| f as f32).collect(),
+            mscale as f32,
+        )
+    }
+}
+
+/// Rotary embedding, which may be scaled by a [`RopeScaling`]. Without scaling, this is the fused
+/// [`candle_nn::RotaryEmbedding`].
+#[derive(Debug, Clone)]
+pub enum ScaledRotaryEmbedding {
+    Unscaled(candle_nn::RotaryEmbedding),
+    Scaled {
+        sin: Tensor,
+        cos: Tensor,
+        is_gpt_neox: bool,
+    },
+}
+
+impl ScaledRotaryEmbedding {
+    /// The tables cover the scaled context length of a model with `max_position_embeddings`.
+    pub fn new(
+        base: f32,
+        head_dim: usize,
+        max_position_embeddings: usize,
+        scaling: Option<&RopeScaling>,
+        dev: &Device,
+        is_gpt_neox: bool,
+        dtype: DType,
+    ) -> Result<Self> {
+        let Some(scaling) = scaling else {
+            return Ok(Self::Unscaled(candle_nn::RotaryEmbedding::new(
+                base,
+                head_dim,
+                max_position_embeddings,
+                dev,
+                is_gpt_neox,
+                dtype,
+            )?));
+        };
+        let max_seq_len = scaling.context_len(max_position_embeddings);
+        let (inv_freq, mscale) = scaling.inv_freq(base, head_dim, max_position_embeddings);
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self::Scaled {
+            sin: (freqs.sin()? * f64::from(mscale))?.to_dtype(dtype)?,
+            cos: (freqs.cos()? * f64::from(mscale))?.to_dtype(dtype)?,
+            is_gpt_neox,
+        })
+    }
+
+    /// Apply the embedding to `q` and `k`, which are `(b_sz * seq_len, num_heads, head_dim)`.
+    pub fn forward(
+        &self,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: &Tensor,
+        q: &mut Tensor,
+        k: &mut Tensor,
+        b_sz: usize,
+    ) -> Result<()> {
+        let (sin, cos, is_gpt_neox) = match self {
+            Self::Unscaled(rope) => {
+                return rope.forward(seqlen_offsets, start_offsets_kernel, q, k, b_sz)
+            }
+            Self::Scaled {
+                sin,
+                cos,
+                is_gpt_neox,
+            } => (sin, cos, *is_gpt_neox),
+        };
+        let apply = |xs: &Tensor| -> Result<Tensor> {
+            let (tokens, num_heads, head_dim) = xs.dims3()?;
+            let seq_len = tokens / b_sz;
+            let xs = xs
+                .reshape((b_sz, seq_len, num_heads, head_dim))?
+                .transpose(1, 2)?;
+            let mut embeds = Vec::new();
+            for (i, offset) in seqlen_offsets.iter().enumerate() {
+                let cos = cos.narrow(0, *offset, seq_len)?;
+                let sin = sin.narrow(0, *offset, seq_len)?;
+                let x = xs.i(i)?.unsqueeze(0)?.contiguous()?;
+                embeds.push(if is_gpt_neox {
+                    candle_nn::rotary_emb::rope(&x, &cos, &sin)?
+                } else {
+                    candle_nn::rotary_emb::rope_i(&x, &cos, &sin)?
+                });
+            }
+            Tensor::cat(&embeds, 0)?
+                .transpose(1, 2)?
+                .reshape((tokens, num_heads, head_dim))
+        };
+        *q = apply(q)?;
+        *k = apply(k)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The implementation of the attention of models loaded with flash attention.
+pub enum AttentionImplementation {
+    /// The fused flash attention V2 kernel.
+    Flash,
+    /// Standard softmax attention, `softmax(QK^T/sqrt(d_k))V`, which materializes the attention weights.
+    Eager,
+}
+
+impl FromStr for AttentionImplementation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "flash" => Ok(Self::Flash),
+            "eager" => Ok(Self::Eager),
+            other => Err(format!(
+                "Unknown attention implementation `{other}`, expected `flash` or `eager`"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for AttentionImplementation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Flash => write!(f, "flash"),
+            Self::Eager => write!(f, "eager"),
+        }
+    }
+}
+
+/// Use eager attention even for models loaded with flash attention.
+static USE_EAGER_ATTENTION: AtomicBool = AtomicBool::new(false);
+
+/// Set the attention implementation, which applies from the next forward pass of every model. Flash attention is only
+/// used by models loaded with it, and is only available with the `flash-attn` feature.
+pub fn set_attention_implementation(implementation: AttentionImplementation) -> Result<()> {
+    if implementation == AttentionImplementation::Flash && !cfg!(feature = "flash-attn") {
+        candle_core::bail!("Flash attention requires the `flash-attn` feature.");
+    }
+    USE_EAGER_ATTENTION.store(
+        implementation == AttentionImplementation::Eager,
+        Ordering::Relaxed,
+    );
+    Ok(())
+}
+
+/// The attention implementation. This defaults to flash attention where it is built.
+pub fn get_attention_implementation() -> AttentionImplementation {
+    if cfg!(feature = "flash-attn") && !USE_EAGER_ATTENTION.load(Ordering::Relaxed) {
+        AttentionImplementation::Flash
+    } else {
+        AttentionImplementation::Eager
+    }
+}
+
 /// Matrix multiplcation, configurable to be via f16 (to use the faster GEMM kernels) optionally.
 pub struct MatMul;
 
@@ -316,7 +743,7 @@ impl ScaledDotProductAttention {
     /// Computes softmax(QK^T*sqrt(d_k))V
     ///
     /// The attention implementation is dispatched as follows:
-    /// 1) If `use_flash_attn == true`, use a flash attention V2 kernel
+    /// 1) If `use_flash_attn == true` and the [`AttentionImplementation`] is flash, use a flash attention V2 kernel
     /// 2) If using CUDA and the cuBLASLt kernel is initialized, then it will use an optimized version.
     /// 3) Otherwise, use the "naive" SDPA implementation.
     #[allow(unused_variables, clippy::too_many_arguments)]
@@ -332,7 +759,7 @@ impl ScaledDotProductAttention {
         b_sz: usize,
         seq_len: usize,
     ) -> Result<Tensor> {
-        if use_flash_attn {
+        if use_flash_attn && get_attention_implementation() == AttentionImplementation::Flash {
             // flash-attn expects (b_sz, seq_len, nheads, head_dim)
             let q = q.transpose(1, 2)?;
             let k = k.transpose(1, 2)?;
@@ -619,4 +1046,204 @@ mod tests {
             )
         }
     }
+
+    #[test]
+    fn rope_scaling_is_parsed() {
+        use std::str::FromStr;
+
+        use super::RopeScaling;
+
+        let scaling: RopeScaling = serde_json::from_str(
+            r#"{"factor": 8.0, "low_freq_factor": 1.0, "high_freq_factor": 4.0, "original_max_position_embeddings": 8192, "rope_type": "llama3"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            scaling,
+            RopeScaling::Llama3 {
+                factor: 8.,
+                low_freq_factor: 1.,
+                high_freq_factor: 4.,
+                original_max_position_embeddings: 8192,
+            }
+        );
+        assert_eq!(scaling.context_len(131072), 131072);
+
+        let scaling = RopeScaling::from_str("yarn:4").unwrap();
+        assert_eq!(
+            scaling,
+            RopeScaling::Yarn {
+                factor: 4.,
+                original_max_position_embeddings: None,
+                attention_factor: None,
+                beta_fast: 32.,
+                beta_slow: 1.,
+            }
+        );
+        assert_eq!(scaling.context_len(32768), 131072);
+        // Serializing keeps the parameters
+        assert_eq!(
+            serde_json::from_value::<RopeScaling>(serde_json::to_value(&scaling).unwrap()).unwrap(),
+            scaling
+        );
+
+        assert_eq!(
+            RopeScaling::from_str(r#"{"type": "linear", "factor": 2}"#).unwrap(),
+            RopeScaling::Linear { factor: 2. }
+        );
+        assert!(RopeScaling::from_str("linear:0.5").is_err());
+        assert!(RopeScaling::from_str("su:2").is_err());
+        assert!(RopeScaling::from_str("llama3:8").is_err());
+    }
+
+    #[test]
+    fn rope_scaling_frequencies() {
+        use super::RopeScaling;
+
+        let base = 10_000f32;
+        let unscaled = |i: usize| 1. / base.powf(i as f32 * 2. / 64.);
+
+        let (inv_freq, mscale) = RopeScaling::Linear { factor: 4. }.inv_freq(base, 64, 4096);
+        assert_eq!(inv_freq.len(), 32);
+        assert!((inv_freq[5] - unscaled(5) / 4.).abs() < 1e-6);
+        assert_eq!(mscale, 1.);
+
+        // The highest frequencies are kept and the lowest are interpolated
+        let (inv_freq, _) = RopeScaling::Llama3 {
+            factor: 8.,
+            low_freq_factor: 1.,
+            high_freq_factor: 4.,
+            original_max_position_embeddings: 8192,
+        }
+        .inv_freq(base, 64, 131072);
+        assert!((inv_freq[0] - unscaled(0)).abs() < 1e-6);
+        assert!((inv_freq[31] - unscaled(31) / 8.).abs() < 1e-9);
+
+        let (inv_freq, mscale) = RopeScaling::Yarn {
+            factor: 4.,
+            original_max_position_embeddings: None,
+            attention_factor: None,
+            beta_fast: 32.,
+            beta_slow: 1.,
+        }
+        .inv_freq(base, 64, 4096);
+        assert!((inv_freq[0] - unscaled(0)).abs() < 1e-6);
+        assert!((inv_freq[31] - unscaled(31) / 4.).abs() < 1e-9);
+        assert!((mscale - (0.1 * 4f32.ln() + 1.)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rope_theta_changes_rotary_tables() {
+        use candle_core::{DType, Device};
+
+        use super::{RopeScaling, ScaledRotaryEmbedding};
+
+        let tables = |base: f32| {
+            let scaling = RopeScaling::Linear { factor: 1. };
+            let rope = ScaledRotaryEmbedding::new(
+                base,
+                8,
+                32,
+                Some(&scaling),
+                &Device::Cpu,
+                true,
+                DType::F32,
+            )
+            .unwrap();
+            let ScaledRotaryEmbedding::Scaled { sin, cos, .. } = rope else {
+                panic!("Expected a scaled rotary embedding.");
+            };
+            (sin.to_vec2::<f32>().unwrap(), cos.to_vec2::<f32>().unwrap())
+        };
+        let (sin_a, cos_a) = tables(10_000.);
+        let (sin_b, cos_b) = tables(500_000.);
+        // The first position is not rotated, and the first frequency is 1 whatever the base
+        assert_eq!(sin_a[0], sin_b[0]);
+        assert_eq!(cos_a[5][0], cos_b[5][0]);
+        // The other frequencies are lower with a higher base
+        assert!((sin_a[5][1] - sin_b[5][1]).abs() > 1e-2);
+        assert!((cos_a[5][1] - cos_b[5][1]).abs() > 1e-2);
+        assert!((sin_a[5][2] - sin_b[5][2]).abs() > 1e-2);
+    }
+
+    #[test]
+    fn unit_rope_scaling_matches_unscaled() {
+        use candle_core::{DType, Device, Tensor};
+
+        use super::{RopeScaling, ScaledRotaryEmbedding};
+
+        let dev = Device::Cpu;
+        let new = |scaling: Option<&RopeScaling>| {
+            ScaledRotaryEmbedding::new(10_000., 8, 32, scaling, &dev, true, DType::F32).unwrap()
+        };
+        let unscaled = new(None);
+        let scaled = new(Some(&RopeScaling::Linear { factor: 1. }));
+        assert!(matches!(scaled, ScaledRotaryEmbedding::Scaled { .. }));
+
+        // 2 sequences of 3 tokens, with 2 query heads and 1 KV head
+        let q = Tensor::arange(0f32, 96., &dev)
+            .unwrap()
+            .reshape((6, 2, 8))
+            .unwrap()
+            .sin()
+            .unwrap();
+        let k = Tensor::arange(0f32, 48., &dev)
+            .unwrap()
+            .reshape((6, 1, 8))
+            .unwrap()
+            .cos()
+            .unwrap();
+        let offsets = [0, 5];
+        let start_offsets_kernel = Tensor::new(&[[0i64, 1, 2], [5, 6, 7]], &dev).unwrap();
+        let rotate = |rope: &ScaledRotaryEmbedding| {
+            let (mut q, mut k) = (q.clone(), k.clone());
+            rope.forward(&offsets, &start_offsets_kernel, &mut q, &mut k, 2)
+                .unwrap();
+            (q, k)
+        };
+        let (q_unscaled, k_unscaled) = rotate(&unscaled);
+        let (q_scaled, k_scaled) = rotate(&scaled);
+        let max_diff = |a: &Tensor, b: &Tensor| {
+            (a - b)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap()
+        };
+        assert_eq!(q_scaled.dims(), &[6, 2, 8]);
+        assert!(max_diff(&q_scaled, &q_unscaled) < 1e-5);
+        assert!(max_diff(&k_scaled, &k_unscaled) < 1e-5);
+    }
+
+    #[test]
+    fn attention_implementation() {
+        use super::{
+            get_attention_implementation, set_attention_implementation, AttentionImplementation,
+        };
+
+        assert_eq!(
+            "eager".parse::<AttentionImplementation>().unwrap(),
+            AttentionImplementation::Eager
+        );
+        assert!("sdpa".parse::<AttentionImplementation>().is_err());
+
+        set_attention_implementation(AttentionImplementation::Eager).unwrap();
+        assert_eq!(
+            get_attention_implementation(),
+            AttentionImplementation::Eager
+        );
+        // Flash attention is the default where it is built
+        let flash = set_attention_implementation(AttentionImplementation::Flash);
+        assert_eq!(flash.is_ok(), cfg!(feature = "flash-attn"));
+        let default = if cfg!(feature = "flash-attn") {
+            AttentionImplementation::Flash
+        } else {
+            AttentionImplementation::Eager
+        };
+        assert_eq!(get_attention_implementation(), default);
+    }
 }
@@ -4,6 +4,8 @@ use std::ops::Add;
 
 use candle_core::{DType, Device, Result, Tensor, WithDType};
 
+use crate::pipeline::DraftTree;
+
 // https://github.com/huggingface/transformers/blob/main/src/transformers/modeling_attn_mask_utils.py
 pub struct CausalMasker;
 
@@ -118,6 +120,37 @@ impl CausalMasker {
         Ok(mask)
     }
 
+    /// The attention bias of a tree of draft tokens, `(1, n_attn_heads, n_tokens, past_kv_len + n_tokens)`, where the
+    /// tokens are the root and then the nodes of `tree`. Every token attends to the cached tokens and, in the tree, to
+    /// itself and its ancestors only.
+    pub fn make_tree_mask_as_attn_bias(
+        &self,
+        tree: &DraftTree,
+        cache: &[Option<(Tensor, Tensor)>],
+        dtype: DType,
+        n_attn_heads: usize,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let past_kv_len = self.calculate_past_kv_len(cache)?;
+        let tgt_len = tree.len() + 1;
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| {
+                (0..past_kv_len + tgt_len).map(move |j| {
+                    u8::from(j >= past_kv_len && !tree.is_visible(i, j - past_kv_len))
+                })
+            })
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, past_kv_len + tgt_len), device)?
+            .expand((1, n_attn_heads, tgt_len, past_kv_len + tgt_len))?;
+        // Mask: 1 means mask out (add -inf), 0 means use from x (add 0.0)
+        let zero = Tensor::new(0.0f32, device)?;
+        masked_fill(
+            &zero.to_dtype(dtype)?.broadcast_as(mask.shape())?,
+            &mask,
+            f32::NEG_INFINITY,
+        )
+    }
+
     pub fn make_causal_mask_with_sliding_window_as_attn_bias(
         &self,
         input_ids: &Tensor,
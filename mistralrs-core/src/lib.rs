@@ -1,9 +1,12 @@
 #![deny(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
+use candle_core::Device;
 use cublaslt::setup_cublas_lt_wrapper;
 use engine::Engine;
-pub use engine::TERMINATE_ALL_NEXT_STEP;
+pub use engine::{EngineHandle, RequestId, StepOutput, TERMINATE_ALL_NEXT_STEP};
 pub use lora::Ordering;
+use metrics::KvCacheProfile;
+pub use metrics::{EngineMetrics, Histogram};
 use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
 #[cfg(feature = "pyo3_macros")]
@@ -15,7 +18,7 @@ use std::{
     io::Write,
     sync::{atomic::AtomicBool, Arc, Mutex, RwLock},
     thread::{self, JoinHandle},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::{channel, Sender};
 
@@ -24,8 +27,10 @@ mod cuda;
 mod device_map;
 mod engine;
 mod lora;
+mod metrics;
 mod model_loader;
 mod ops;
+mod pinned;
 pub use model_loader::{get_model_dtype, get_tgt_non_granular_index, LoaderBuilder};
 mod model_selected;
 pub use model_selected::ModelSelected;
@@ -44,22 +49,33 @@ mod response;
 mod sampler;
 mod scheduler;
 mod sequence;
+pub mod tensor_parallel;
 mod toml_selector;
+mod tools;
 mod utils;
 mod vision_models;
 mod xlora_models;
 
 pub use device_map::{DeviceLayerMapMetadata, DeviceMapMetadata, LayerDeviceMapper};
+pub use layers::{
+    get_attention_implementation, set_attention_implementation, AttentionImplementation,
+    RopeScaling,
+};
 pub use pipeline::{
-    chat_template::ChatTemplate, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig,
-    GGUFArchitecture, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader,
-    Idefics2Loader, LlamaLoader, Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind,
-    ModelPaths, NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig,
-    Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig, SpeculativeLoader,
-    SpeculativePipeline, TokenSource, VisionLoader, VisionLoaderBuilder, VisionLoaderType,
-    VisionModelLoader, VisionSpecificConfig,
+    chat_template::ChatTemplate, AdaptiveGamma, EmbeddingPooling, GGMLLoader, GGMLLoaderBuilder,
+    GGMLSpecificConfig, GGUFArchitecture, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig,
+    GemmaLoader, Idefics2Loader, IsqOverride, LlamaLoader, Loader, LocalModelPaths, MedusaLoader,
+    MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoader, NormalLoaderBuilder,
+    NormalLoaderType, NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader,
+    PromptLookupConfig, PromptLookupLoader, Qwen2Loader, SpeculativeConfig, SpeculativeLoader,
+    SpeculativePipeline, SpeculativeStats, TokenSource, VisionLoader, VisionLoaderBuilder,
+    VisionLoaderType, VisionModelLoader, VisionSpecificConfig,
+};
+pub use prefix_cacher::{EvictionPolicy, KeyNormalizer, PrefixCacheManager, PrefixCacheStats};
+pub use request::{
+    Constraint, DetokenizationRequest, EmbeddingRequest, MessageContent, NormalRequest, Request,
+    RequestMessage, TokenizationPrompt, TokenizationRequest,
 };
-pub use request::{Constraint, MessageContent, NormalRequest, Request, RequestMessage};
 pub use response::Response;
 pub use response::*;
 pub use sampler::{SamplingParams, StopTokens, TopLogprob};
@@ -67,6 +83,7 @@ pub use scheduler::SchedulerMethod;
 use serde::Serialize;
 use tokio::runtime::Runtime;
 use toml_selector::{TomlLoaderArgs, TomlSelector};
+pub use tools::{Function, Tool, ToolType};
 pub use utils::debug::initialize_logging;
 pub use utils::normal::{ModelDType, TryIntoDType};
 
@@ -82,9 +99,13 @@ pub struct MistralRs {
     log: Option<String>,
     id: String,
     creation_time: u64,
+    max_model_len: usize,
     next_request_id: Mutex<RefCell<usize>>,
     reboot_state: RebootState,
     engine_handler: RwLock<JoinHandle<()>>,
+    metrics: Arc<Mutex<EngineMetrics>>,
+    ready: Arc<AtomicBool>,
+    draining: AtomicBool,
 }
 
 #[derive(Clone)]
@@ -95,18 +116,34 @@ struct RebootState {
     no_kv_cache: bool,
     no_prefix_cache: bool,
     prefix_cache_n: usize,
+    prefix_cache_eviction_policy: EvictionPolicy,
+    prefix_cache_memory_budget: Option<usize>,
+    prefix_cache_offload_device: Device,
+    prefix_cache_key_normalizer: Option<KeyNormalizer>,
     disable_eos_stop: bool,
+    prefill_chunk_size: Option<usize>,
+    default_system_prompt: Option<String>,
+    add_bos_token: Option<bool>,
+    add_eos_token: Option<bool>,
+    disable_special_token_parsing: bool,
+    max_queued_requests: Option<usize>,
+    metrics: Arc<Mutex<EngineMetrics>>,
+    ready: Arc<AtomicBool>,
 }
 
 #[derive(Debug)]
 pub enum MistralRsError {
     EnginePoisoned,
     SenderPoisoned,
+    InvalidConfig(String),
 }
 
 impl std::fmt::Display for MistralRsError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", &self)
+        match self {
+            Self::InvalidConfig(msg) => write!(f, "Invalid configuration: {msg}"),
+            _ => write!(f, "{:?}", &self),
+        }
     }
 }
 
@@ -130,8 +167,22 @@ pub struct MistralRsBuilder {
     no_kv_cache: Option<bool>,
     no_prefix_cache: Option<bool>,
     prefix_cache_n: Option<usize>,
+    prefix_cache_eviction_policy: Option<EvictionPolicy>,
+    prefix_cache_memory_budget: Option<usize>,
+    prefix_cache_offload_device: Option<Device>,
+    prefix_cache_key_normalizer: Option<KeyNormalizer>,
     disable_eos_stop: Option<bool>,
+    prefill_chunk_size: Option<usize>,
+    default_system_prompt: Option<String>,
     gemm_full_precision_f16: Option<bool>,
+    kv_cache_bits: Option<u8>,
+    kv_cache_block_size: Option<usize>,
+    add_bos_token: Option<bool>,
+    add_eos_token: Option<bool>,
+    disable_special_token_parsing: Option<bool>,
+    warmup: Option<bool>,
+    warmup_prompt_len: Option<usize>,
+    max_queued_requests: Option<usize>,
 }
 
 impl MistralRsBuilder {
@@ -144,8 +195,22 @@ impl MistralRsBuilder {
             no_kv_cache: None,
             no_prefix_cache: None,
             prefix_cache_n: None,
+            prefix_cache_eviction_policy: None,
+            prefix_cache_memory_budget: None,
+            prefix_cache_offload_device: None,
+            prefix_cache_key_normalizer: None,
             disable_eos_stop: None,
+            prefill_chunk_size: None,
+            default_system_prompt: None,
             gemm_full_precision_f16: None,
+            kv_cache_bits: None,
+            kv_cache_block_size: None,
+            add_bos_token: None,
+            add_eos_token: None,
+            disable_special_token_parsing: None,
+            warmup: None,
+            warmup_prompt_len: None,
+            max_queued_requests: None,
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -168,20 +233,151 @@ impl MistralRsBuilder {
         self.no_prefix_cache = Some(no_prefix_cache);
         self
     }
+    /// Number of prefix caches to hold on the device. If it is not set, it is the number of caches of `max_seq_len`
+    /// tokens which fit in the free device memory once the running sequences have theirs, when that can be measured
+    /// (CUDA only), and 16 otherwise.
     pub fn with_prefix_cache_n(mut self, prefix_cache_n: usize) -> Self {
         self.prefix_cache_n = Some(prefix_cache_n);
         self
     }
+    pub fn with_opt_prefix_cache_n(mut self, prefix_cache_n: Option<usize>) -> Self {
+        self.prefix_cache_n = prefix_cache_n;
+        self
+    }
+    pub fn with_prefix_cache_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.prefix_cache_eviction_policy = Some(policy);
+        self
+    }
+    /// Evict prefix caches based on the number of bytes they use on the device instead of `prefix_cache_n`.
+    pub fn with_prefix_cache_memory_budget(mut self, bytes: usize) -> Self {
+        self.prefix_cache_memory_budget = Some(bytes);
+        self
+    }
+    /// Device which evicted prefix caches are moved to. Defaults to the CPU.
+    pub fn with_prefix_cache_offload_device(mut self, device: Device) -> Self {
+        self.prefix_cache_offload_device = Some(device);
+        self
+    }
+    /// Normalize the tokens before they are used as prefix cache keys. See [`PrefixCacheManager::key_normalizer`]
+    /// for when this is safe.
+    pub fn with_prefix_cache_key_normalizer(
+        mut self,
+        normalizer: impl Fn(&[u32]) -> Vec<u32> + Send + Sync + 'static,
+    ) -> Self {
+        self.prefix_cache_key_normalizer = Some(Arc::new(normalizer));
+        self
+    }
     pub fn with_disable_eos_stop(mut self, disable_eos_stop: bool) -> Self {
         self.disable_eos_stop = Some(disable_eos_stop);
         self
     }
+    /// Prefill prompts longer than `chunk_size` tokens in chunks of that size, one chunk per engine step, so that
+    /// long prompts do not stall the decoding of other sequences. Ignored for models which do not support it.
+    pub fn with_prefill_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.prefill_chunk_size = Some(chunk_size);
+        self
+    }
+    pub fn with_opt_prefill_chunk_size(mut self, chunk_size: Option<usize>) -> Self {
+        self.prefill_chunk_size = chunk_size;
+        self
+    }
+    /// Add a system prompt to every chat request when rendering the chat template. It is merged with the system
+    /// message of the request, if any. As it starts every prompt, the prefix cacher reuses its KV cache.
+    pub fn with_default_system_prompt(mut self, system_prompt: String) -> Self {
+        self.default_system_prompt = Some(system_prompt);
+        self
+    }
+    pub fn with_opt_default_system_prompt(mut self, system_prompt: Option<String>) -> Self {
+        self.default_system_prompt = system_prompt;
+        self
+    }
     pub fn with_gemm_full_precision_f16(mut self, gemm_full_precision: bool) -> Self {
         self.gemm_full_precision_f16 = Some(gemm_full_precision);
         self
     }
+    /// Store the KV caches of the sequences and the prefix cacher quantized to `bits`, 8 or 4, with one scale per head
+    /// and position. They are dequantized for every step, so the attention always reads the quantized values.
+    ///
+    /// # Errors
+    /// When building, if `bits` is neither 8 nor 4.
+    pub fn with_kv_cache_bits(mut self, bits: u8) -> Self {
+        self.kv_cache_bits = Some(bits);
+        self
+    }
+    pub fn with_opt_kv_cache_bits(mut self, bits: Option<u8>) -> Self {
+        self.kv_cache_bits = bits;
+        self
+    }
+    /// Keep the KV caches of the sequences in a pool of blocks of `block_size` positions, gathered for every step,
+    /// rather than in a contiguous tensor per sequence. This disables the prefix cache, and forked choices run their
+    /// own prompt.
+    ///
+    /// # Errors
+    /// When building, if `block_size` is 0, if the KV cache is quantized, or with speculative decoding.
+    pub fn with_kv_cache_block_size(mut self, block_size: usize) -> Self {
+        self.kv_cache_block_size = Some(block_size);
+        self
+    }
+    pub fn with_opt_kv_cache_block_size(mut self, block_size: Option<usize>) -> Self {
+        self.kv_cache_block_size = block_size;
+        self
+    }
+    /// Whether to add the BOS token to the start of prompts which do not already start with it. Defaults to
+    /// `add_bos_token` of the tokenizer config of the model. Prompts given as tokens are never changed.
+    pub fn with_add_bos_token(mut self, add_bos_token: bool) -> Self {
+        self.add_bos_token = Some(add_bos_token);
+        self
+    }
+    pub fn with_opt_add_bos_token(mut self, add_bos_token: Option<bool>) -> Self {
+        self.add_bos_token = add_bos_token;
+        self
+    }
+    /// Whether to add the EOS token to the end of prompts. Defaults to `add_eos_token` of the tokenizer config of
+    /// the model.
+    pub fn with_add_eos_token(mut self, add_eos_token: bool) -> Self {
+        self.add_eos_token = Some(add_eos_token);
+        self
+    }
+    pub fn with_opt_add_eos_token(mut self, add_eos_token: Option<bool>) -> Self {
+        self.add_eos_token = add_eos_token;
+        self
+    }
+    /// Do not parse special tokens in the text of requests, so that users cannot inject control tokens. Special
+    /// tokens in the prompt of a completion are tokenized as plain text, and chat requests whose messages contain
+    /// one are rejected.
+    pub fn with_disable_special_token_parsing(mut self, disable: bool) -> Self {
+        self.disable_special_token_parsing = Some(disable);
+        self
+    }
+    /// Before serving requests, run a batch of the maximum number of running sequences through a short prefill and
+    /// one decode step, so that the kernels are compiled and the caches allocated before the first request. Requests
+    /// sent meanwhile wait for it to finish. The metrics and prefix caches of the warmup are dropped.
+    pub fn with_warmup(mut self, warmup: bool) -> Self {
+        self.warmup = Some(warmup);
+        self
+    }
+    /// Number of prompt tokens of each warmup sequence. Defaults to 32.
+    pub fn with_warmup_prompt_len(mut self, prompt_len: usize) -> Self {
+        self.warmup_prompt_len = Some(prompt_len);
+        self
+    }
+    pub fn with_opt_warmup_prompt_len(mut self, prompt_len: Option<usize>) -> Self {
+        self.warmup_prompt_len = prompt_len;
+        self
+    }
+    /// Reject requests with [`Response::Busy`] instead of queueing them while this many sequences are waiting to be
+    /// scheduled, so that the queue does not grow without bound under overload. A request with `n` choices queues `n`
+    /// sequences. By default, requests are always queued.
+    pub fn with_max_queued_requests(mut self, max_queued_requests: usize) -> Self {
+        self.max_queued_requests = Some(max_queued_requests);
+        self
+    }
+    pub fn with_opt_max_queued_requests(mut self, max_queued_requests: Option<usize>) -> Self {
+        self.max_queued_requests = max_queued_requests;
+        self
+    }
 
-    pub fn build(self) -> Arc<MistralRs> {
+    pub fn build(self) -> Result<Arc<MistralRs>, MistralRsError> {
         MistralRs::new(self)
     }
 }
@@ -223,8 +419,42 @@ fn set_gemm_reduced_precision_f16() {
 #[cfg(not(feature = "cuda"))]
 fn set_gemm_reduced_precision_f16() {}
 
+/// Measure how many tokens of KV cache fit in the memory left on the device of the model and log it, returning the
+/// number of prefix caches to hold on the device. Returns `None` if the size of the KV cache or the free memory is not
+/// known.
+fn profile_kv_cache(pipeline: &dyn Pipeline, method: &SchedulerMethod) -> Option<usize> {
+    let metadata = pipeline.get_metadata();
+    let shape = metadata.kv_cache_shape?;
+    let free_bytes = metrics::device_memory_free(&pipeline.device())?;
+    let SchedulerMethod::Fixed(max_running) = method;
+    let max_running = **max_running;
+    let profile = KvCacheProfile::new(
+        shape.bytes_per_token(),
+        free_bytes,
+        metadata.max_seq_len,
+        max_running,
+    );
+    tracing::info!(
+        "KV cache uses {} bytes per token, {} MiB are free on the device: room for {} tokens.",
+        profile.bytes_per_token,
+        profile.free_bytes / (1024 * 1024),
+        profile.token_budget
+    );
+    if !profile.fits_running(metadata.max_seq_len, max_running) {
+        tracing::warn!(
+            "{max_running} running sequences of {} tokens may not fit in the free device memory, consider lowering the maximum number of running sequences.",
+            metadata.max_seq_len
+        );
+    }
+    tracing::info!(
+        "Holding {} prefix caches on the device.",
+        profile.n_on_device
+    );
+    Some(profile.n_on_device)
+}
+
 impl MistralRs {
-    fn new(config: MistralRsBuilder) -> Arc<Self> {
+    fn new(config: MistralRsBuilder) -> Result<Arc<Self>, MistralRsError> {
         let MistralRsBuilder {
             pipeline,
             method,
@@ -233,10 +463,55 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_eviction_policy,
+            prefix_cache_memory_budget,
+            prefix_cache_offload_device,
+            prefix_cache_key_normalizer,
             disable_eos_stop,
+            prefill_chunk_size,
+            default_system_prompt,
             gemm_full_precision_f16,
+            kv_cache_bits,
+            kv_cache_block_size,
+            add_bos_token,
+            add_eos_token,
+            disable_special_token_parsing,
+            warmup,
+            warmup_prompt_len,
+            max_queued_requests,
         } = config;
 
+        if let Some(bits) = kv_cache_bits {
+            if !matches!(bits, 4 | 8) {
+                return Err(MistralRsError::InvalidConfig(format!(
+                    "Unsupported KV cache quantization bits {bits}, expected 8 or 4."
+                )));
+            }
+            tracing::info!("Quantizing the KV cache to {bits} bits.");
+        }
+        pipeline
+            .try_lock()
+            .unwrap()
+            .set_kv_cache_bits(kv_cache_bits);
+        if let Some(block_size) = kv_cache_block_size {
+            if block_size == 0 {
+                return Err(MistralRsError::InvalidConfig(
+                    "The KV cache block size must not be 0.".to_string(),
+                ));
+            }
+            if kv_cache_bits.is_some() {
+                return Err(MistralRsError::InvalidConfig(
+                    "A paged KV cache cannot be quantized.".to_string(),
+                ));
+            }
+            tracing::info!("Paging the KV cache in blocks of {block_size} positions.");
+        }
+        pipeline
+            .try_lock()
+            .unwrap()
+            .set_kv_cache_block_size(kv_cache_block_size)
+            .map_err(|e| MistralRsError::InvalidConfig(e.to_string()))?;
+
         let model_supports_reduced_gemm = match pipeline.try_lock().unwrap().category() {
             ModelCategory::Text => true,
             ModelCategory::Vision { has_conv2d } => !has_conv2d,
@@ -248,10 +523,21 @@ impl MistralRs {
 
         let truncate_sequence = truncate_sequence.unwrap_or(false);
         let no_kv_cache = no_kv_cache.unwrap_or(false);
-        let no_prefix_cache = no_prefix_cache.unwrap_or(false);
-        let prefix_cache_n = prefix_cache_n.unwrap_or(16);
+        // The prefix cache shares the KV caches of the sequences, which the paged cache manager keeps in its pool
+        let no_prefix_cache = no_prefix_cache.unwrap_or(false) || kv_cache_block_size.is_some();
+        let prefix_cache_n = prefix_cache_n
+            .or_else(|| profile_kv_cache(&*pipeline.try_lock().unwrap(), &method))
+            .unwrap_or(16);
+        let prefix_cache_eviction_policy = prefix_cache_eviction_policy.unwrap_or_default();
+        let prefix_cache_offload_device = prefix_cache_offload_device.unwrap_or(Device::Cpu);
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
+        let disable_special_token_parsing = disable_special_token_parsing.unwrap_or(false);
+        let warmup_prompt_len = warmup
+            .unwrap_or(false)
+            .then(|| warmup_prompt_len.unwrap_or(32));
 
+        let metrics = Arc::new(Mutex::new(EngineMetrics::default()));
+        let ready = Arc::new(AtomicBool::new(false));
         let reboot_state = RebootState {
             pipeline: pipeline.clone(),
             method: method.clone(),
@@ -259,13 +545,28 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_eviction_policy,
+            prefix_cache_memory_budget,
+            prefix_cache_offload_device: prefix_cache_offload_device.clone(),
+            prefix_cache_key_normalizer: prefix_cache_key_normalizer.clone(),
             disable_eos_stop,
+            prefill_chunk_size,
+            default_system_prompt: default_system_prompt.clone(),
+            add_bos_token,
+            add_eos_token,
+            disable_special_token_parsing,
+            max_queued_requests,
+            metrics: metrics.clone(),
+            ready: ready.clone(),
         };
 
         let (tx, rx) = channel(10_000);
 
         let sender = RwLock::new(tx);
         let id = pipeline.try_lock().unwrap().name();
+        let max_model_len = pipeline.try_lock().unwrap().get_metadata().max_seq_len;
+        let engine_metrics = metrics.clone();
+        let engine_ready = ready.clone();
 
         let engine_handler = thread::spawn(move || {
             let rt = Runtime::new().unwrap();
@@ -278,13 +579,26 @@ impl MistralRs {
                     no_kv_cache,
                     no_prefix_cache,
                     prefix_cache_n,
+                    prefix_cache_eviction_policy,
+                    prefix_cache_memory_budget,
+                    prefix_cache_offload_device,
+                    prefix_cache_key_normalizer,
                     disable_eos_stop,
+                    prefill_chunk_size,
+                    default_system_prompt,
+                    add_bos_token,
+                    add_eos_token,
+                    disable_special_token_parsing,
+                    warmup_prompt_len,
+                    max_queued_requests,
+                    engine_metrics,
+                    engine_ready,
                 );
                 engine.run().await;
             });
         });
 
-        Arc::new(Self {
+        Ok(Arc::new(Self {
             sender,
             log,
             id,
@@ -292,10 +606,14 @@ impl MistralRs {
                 .duration_since(UNIX_EPOCH)
                 .expect("Time travel has occurred!")
                 .as_secs(),
+            max_model_len,
             next_request_id: Mutex::new(RefCell::new(0)),
             reboot_state,
             engine_handler: RwLock::new(engine_handler),
-        })
+            metrics,
+            ready,
+            draining: AtomicBool::new(false),
+        }))
     }
 
     /// attempts to reboot the engine, if the sender (only way to communicate with
@@ -328,7 +646,21 @@ impl MistralRs {
                         reboot_state.no_kv_cache,
                         reboot_state.no_prefix_cache,
                         reboot_state.prefix_cache_n,
+                        reboot_state.prefix_cache_eviction_policy,
+                        reboot_state.prefix_cache_memory_budget,
+                        reboot_state.prefix_cache_offload_device,
+                        reboot_state.prefix_cache_key_normalizer,
                         reboot_state.disable_eos_stop,
+                        reboot_state.prefill_chunk_size,
+                        reboot_state.default_system_prompt,
+                        reboot_state.add_bos_token,
+                        reboot_state.add_eos_token,
+                        reboot_state.disable_special_token_parsing,
+                        // The kernels were compiled by the engine which stopped
+                        None,
+                        reboot_state.max_queued_requests,
+                        reboot_state.metrics,
+                        reboot_state.ready,
                     );
                     engine.run().await;
                 });
@@ -351,7 +683,8 @@ impl MistralRs {
     }
 
     pub fn get_sender(&self) -> Result<Sender<Request>, MistralRsError> {
-        if self.engine_dead()? {
+        // A drained engine is stopped for good, so the requests sent to it fail
+        if self.engine_dead()? && !self.draining.load(std::sync::atomic::Ordering::SeqCst) {
             tracing::warn!("Engine is dead, rebooting");
             self.reboot_engine()?
         }
@@ -361,6 +694,33 @@ impl MistralRs {
         }
     }
 
+    /// Whether the engine is running, has finished its warmup, if any, and is not draining, so that requests are served
+    /// right away. The model is loaded before the [`MistralRs`] is built.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(std::sync::atomic::Ordering::SeqCst)
+            && !self.draining.load(std::sync::atomic::Ordering::SeqCst)
+            && matches!(self.engine_dead(), Ok(false))
+    }
+
+    /// Stop accepting requests, and wait for the engine to finish the running and waiting sequences. The sequences
+    /// still running after `timeout` are cancelled, and finish with the tokens generated so far, and those still
+    /// waiting get an error. The engine then stops and is not rebooted, so requests sent afterwards fail.
+    pub async fn drain(&self, timeout: Duration) -> Result<(), MistralRsError> {
+        self.draining
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        let sender = self
+            .sender
+            .read()
+            .map_err(|_| MistralRsError::SenderPoisoned)?
+            .clone();
+        // If the engine already stopped, there is nothing to drain
+        let _ = sender.send(Request::Drain(timeout)).await;
+        while !self.engine_dead()? {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Ok(())
+    }
+
     pub fn get_id(&self) -> String {
         self.id.clone()
     }
@@ -369,6 +729,16 @@ impl MistralRs {
         self.creation_time
     }
 
+    /// Context length of the model, which the prompt and the generated tokens of a request share.
+    pub fn get_max_model_len(&self) -> usize {
+        self.max_model_len
+    }
+
+    /// Get a snapshot of the metrics of the engine, which are updated after every step.
+    pub fn get_metrics(&self) -> EngineMetrics {
+        get_mut_arcmutex!(self.metrics).clone()
+    }
+
     pub fn next_request_id(&self) -> usize {
         let l = self.next_request_id.lock().unwrap();
         let last = &mut *l.borrow_mut();
@@ -377,6 +747,85 @@ impl MistralRs {
         last_v
     }
 
+    fn completion_request(
+        &self,
+        prompt: String,
+        sampling_params: SamplingParams,
+        response: Sender<Response>,
+    ) -> Request {
+        Request::Normal(NormalRequest {
+            id: self.next_request_id(),
+            messages: RequestMessage::Completion {
+                text: prompt,
+                echo_prompt: false,
+                best_of: sampling_params.n_choices,
+            },
+            sampling_params,
+            response,
+            return_logprobs: false,
+            is_streaming: false,
+            constraint: Constraint::None,
+            suffix: None,
+            adapters: None,
+            lora_scale: None,
+            priority: 0,
+            tools: None,
+        })
+    }
+
+    fn completion_text(response: Option<Response>) -> anyhow::Result<String> {
+        match response {
+            Some(Response::CompletionDone(response)) => Ok(response
+                .choices
+                .into_iter()
+                .next()
+                .map(|choice| choice.text)
+                .unwrap_or_default()),
+            Some(Response::InternalError(e))
+            | Some(Response::ValidationError(e))
+            | Some(Response::Busy(e)) => Err(anyhow::anyhow!(e)),
+            Some(Response::CompletionModelError(msg, _)) => anyhow::bail!(msg),
+            Some(_) => anyhow::bail!("Unexpected response to a completion request."),
+            None => anyhow::bail!("The engine stopped before finishing the request."),
+        }
+    }
+
+    /// Generate a completion of `prompt`, blocking until it is finished. The engine runs on its own thread, so this
+    /// needs no async runtime, but it must not be called from within one.
+    pub fn generate_sync(
+        &self,
+        prompt: impl Into<String>,
+        sampling_params: SamplingParams,
+    ) -> anyhow::Result<String> {
+        let (tx, mut rx) = channel(1);
+        self.get_sender()?
+            .blocking_send(self.completion_request(prompt.into(), sampling_params, tx))
+            .map_err(|_| anyhow::anyhow!("The engine stopped before receiving the request."))?;
+        Self::completion_text(rx.blocking_recv())
+    }
+
+    /// Generate completions for a batch of prompts, blocking until all are finished. All requests are submitted
+    /// before waiting, so the scheduler batches them, and the completions are returned in submission order with the
+    /// caller's ids. The same restrictions as for [`MistralRs::generate_sync`] apply.
+    pub fn generate_batch<I>(
+        &self,
+        requests: Vec<(I, String, SamplingParams)>,
+    ) -> anyhow::Result<Vec<(I, String)>> {
+        let sender = self.get_sender()?;
+        let mut pending = Vec::with_capacity(requests.len());
+        for (id, prompt, sampling_params) in requests {
+            let (tx, rx) = channel(1);
+            sender
+                .blocking_send(self.completion_request(prompt, sampling_params, tx))
+                .map_err(|_| anyhow::anyhow!("The engine stopped before receiving the request."))?;
+            pending.push((id, rx));
+        }
+        pending
+            .into_iter()
+            .map(|(id, mut rx)| Ok((id, Self::completion_text(rx.blocking_recv())?)))
+            .collect()
+    }
+
     pub fn maybe_log_request(this: Arc<Self>, repr: String) {
         if let Some(file) = &this.log {
             let mut f = OpenOptions::new()
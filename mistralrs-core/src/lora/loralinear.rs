@@ -1,4 +1,8 @@
-use std::{collections::HashMap, iter::zip, ops::Mul};
+use std::{
+    collections::{HashMap, HashSet},
+    iter::zip,
+    ops::Mul,
+};
 
 use candle_core::{
     bail,
@@ -11,8 +15,8 @@ use either::Either;
 use crate::layers::QLinear;
 
 use super::{
-    apply_scalings_to_x, get_maybe_topk_scalings, make_adapter, Adapter, AdapterSwapper,
-    LinearLayerLike, LoraConfig, LoraLinearConfig, Merge,
+    apply_scalings_to_x, get_maybe_topk_scalings, make_adapter, make_added_adapter, merge_delta,
+    Adapter, AdapterSwapper, LinearLayerLike, LoraConfig, LoraLinearConfig, Merge,
 };
 
 #[derive(Debug)]
@@ -23,7 +27,17 @@ pub struct LoraLinear {
     scale_adapters: Vec<f64>,
     layer_n: usize,
     merged: bool,
+    // The adapter merged by `merge_adapter` and the base weight from before it was merged
+    merged_adapter: Option<(String, QMatMul)>,
     adapters: HashMap<String, Adapter>,
+    // Added adapters which do not target this layer
+    untargeted_adapters: HashSet<String>,
+    // Multiplies the contribution of the adapters, set for each batch
+    lora_scale: f64,
+    linear_config: LoraLinearConfig,
+    // Prefixes of the A and B weights of the adapters of this layer
+    a_prefix: String,
+    b_prefix: String,
 }
 
 impl LoraLinear {
@@ -112,7 +126,13 @@ impl LoraLinear {
                 scale_adapters,
                 layer_n,
                 merged: false,
+                merged_adapter: None,
                 adapters,
+                untargeted_adapters: HashSet::new(),
+                lora_scale: 1.,
+                linear_config: linear_config.clone(),
+                a_prefix: a_vb.prefix(),
+                b_prefix: b_vb.prefix(),
             })
         } else {
             Ok(LoraLinear {
@@ -122,7 +142,13 @@ impl LoraLinear {
                 scale_adapters,
                 layer_n,
                 merged: false,
+                merged_adapter: None,
                 adapters,
+                untargeted_adapters: HashSet::new(),
+                lora_scale: 1.,
+                linear_config: linear_config.clone(),
+                a_prefix: a_vb.prefix(),
+                b_prefix: b_vb.prefix(),
             })
         }
     }
@@ -130,6 +156,13 @@ impl LoraLinear {
 
 impl AdapterSwapper for LoraLinear {
     fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+        if let Some((merged, _)) = &self.merged_adapter {
+            // The merged adapter is the active one
+            if adapter_names != std::slice::from_ref(merged) {
+                bail!("Adapter `{merged}` is merged, it must be unmerged before activating other adapters.");
+            }
+            return Ok(());
+        }
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -140,6 +173,9 @@ impl AdapterSwapper for LoraLinear {
                 b.clear();
                 s.clear();
                 for adapter_name in adapter_names {
+                    if self.untargeted_adapters.contains(adapter_name) {
+                        continue;
+                    }
                     let Adapter {
                         a: a_w,
                         b: b_w,
@@ -157,6 +193,31 @@ impl AdapterSwapper for LoraLinear {
         }
         Ok(())
     }
+    fn _add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<()> {
+        if self.a_adapters.is_right() {
+            bail!("Adapters can only be added to models with `preload_adapters` in their ordering file.");
+        }
+        if let Some(adapter) = make_added_adapter(
+            &self.adapters,
+            name,
+            (&self.a_prefix, &self.b_prefix),
+            vb,
+            cfg,
+            &self.linear_config,
+        )? {
+            self.adapters.insert(name.to_string(), adapter);
+        } else {
+            self.untargeted_adapters.insert(name.to_string());
+        }
+        Ok(())
+    }
+    fn _set_lora_scale(&mut self, scale: f64) -> Result<()> {
+        if (self.merged || self.merged_adapter.is_some()) && scale != 1. {
+            bail!("The adapters of merged layers cannot be scaled.");
+        }
+        self.lora_scale = scale;
+        Ok(())
+    }
     fn can_load(&self) -> bool {
         true
     }
@@ -196,6 +257,37 @@ impl Merge for LoraLinear {
         self.merged = true;
         Ok(())
     }
+
+    fn merge_adapter(&mut self, name: &str) -> Result<usize> {
+        if self.merged {
+            bail!("The adapters of this layer are already merged.");
+        }
+        if let Some((merged, _)) = &self.merged_adapter {
+            bail!("Adapter `{merged}` is merged, it must be unmerged first.");
+        }
+        let Some(adapter) = self.adapters.get(name) else {
+            return Ok(0);
+        };
+        if self.a_adapters.is_right() {
+            bail!("Adapters can only be merged into models with `preload_adapters` in their ordering file.");
+        }
+        let delta = adapter.delta_weight()?;
+        let merged = merge_delta(self.old.inner(), &delta)?;
+        self._activate_adapters(&[name.to_string()])?;
+        let base = std::mem::replace(self.old.inner(), merged);
+        self.merged_adapter = Some((name.to_string(), base));
+        Ok(1)
+    }
+
+    fn unmerge_adapter(&mut self) -> Result<usize> {
+        match self.merged_adapter.take() {
+            Some((_, base)) => {
+                *self.old.inner() = base;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
 }
 
 impl LinearLayerLike for LoraLinear {
@@ -220,9 +312,10 @@ impl LinearLayerLike for LoraLinear {
     ) -> Result<Tensor> {
         let mut result = self.old.forward(input)?;
 
-        if self.merged {
+        if self.merged || self.merged_adapter.is_some() || self.lora_scale == 0. {
             return Ok(result);
         }
+        let global_scaling_weight = global_scaling_weight * self.lora_scale;
 
         if is_scaling_pass.is_some_and(|x| x == 0.) {
             return Ok(result);
@@ -96,6 +96,67 @@ fn make_adapter(
     Ok(Adapter { a, b, scale })
 }
 
+/// Make the adapter `name` of a layer whose adapter weights are under `a_prefix` and `b_prefix`, for
+/// [`AdapterSwapper::_add_adapter`]. Returns `None` if the adapter does not target the layer. The weights are moved
+/// to the dtype and device of the other adapters of the layer.
+fn make_added_adapter(
+    adapters: &HashMap<String, Adapter>,
+    name: &str,
+    (a_prefix, b_prefix): (&str, &str),
+    vb: &VarBuilder,
+    cfg: &LoraConfig,
+    linear_cfg: &LoraLinearConfig,
+) -> Result<Option<Adapter>> {
+    if adapters.contains_key(name) {
+        candle_core::bail!("Adapter `{name}` is already loaded.");
+    }
+    let a_vb = vb.set_prefix(a_prefix);
+    if !a_vb.contains_tensor("weight") {
+        return Ok(None);
+    }
+    let Adapter { a, b, scale } = make_adapter(a_vb, vb.set_prefix(b_prefix), cfg, linear_cfg)?;
+    let Some(other) = adapters.values().next() else {
+        return Ok(Some(Adapter { a, b, scale }));
+    };
+    let (dtype, device) = (other.a.weight().dtype(), other.a.weight().device());
+    let to = |linear: Linear| -> Result<Linear> {
+        Ok(Linear::new(
+            linear.weight().to_dtype(dtype)?.to_device(device)?,
+            None,
+        ))
+    };
+    Ok(Some(Adapter {
+        a: to(a)?,
+        b: to(b)?,
+        scale,
+    }))
+}
+
+impl Adapter {
+    /// The update of the base weight by this adapter: `B * A * scale`.
+    fn delta_weight(&self) -> Result<Tensor> {
+        self.b.weight().matmul(self.a.weight())? * self.scale
+    }
+}
+
+/// The base weight `w` with `delta` added. A quantized weight is dequantized, updated and quantized again to its
+/// original type.
+fn merge_delta(w: &QMatMul, delta: &Tensor) -> Result<QMatMul> {
+    match w {
+        QMatMul::QTensor(q) => {
+            let w = q.dequantize(&q.device())?;
+            let w = (&w + delta.to_dtype(w.dtype())?.to_device(w.device())?)?;
+            QMatMul::from_qtensor(QTensor::quantize(&w, q.dtype())?)
+        }
+        QMatMul::Tensor(w) => Ok(QMatMul::Tensor(
+            (w + delta.to_dtype(w.dtype())?.to_device(w.device())?)?,
+        )),
+        QMatMul::TensorF16(w) => Ok(QMatMul::TensorF16(
+            (w + delta.to_dtype(w.dtype())?.to_device(w.device())?)?,
+        )),
+    }
+}
+
 /// Any layer that is linear-like.
 pub trait LinearLayerLike: Debug + Merge + AdapterSwapper {
     fn inner(&mut self) -> &mut QMatMul;
@@ -116,6 +177,12 @@ pub trait Merge {
     fn get_delta_weight(&self, adapter: usize) -> Result<Tensor>;
     /// Merge the LoRA weights.
     fn merge_weights(&mut self) -> Result<()>;
+    /// Merge the adapter `name` into the base weight and activate it, so that the LoRA computation is skipped until
+    /// it is unmerged. The base weight is kept to be restored by [`Merge::unmerge_adapter`]. Returns the number of
+    /// layers merged: 0 if the adapter does not target this layer.
+    fn merge_adapter(&mut self, name: &str) -> Result<usize>;
+    /// Restore the base weight from before [`Merge::merge_adapter`]. Returns the number of layers unmerged.
+    fn unmerge_adapter(&mut self) -> Result<usize>;
 }
 
 pub trait AdapterSwapper {
@@ -127,7 +194,28 @@ pub trait AdapterSwapper {
             Ok(0)
         }
     }
+    /// Load the adapter `name` into this layer from the weights in `vb`, so that it may be activated. The adapter
+    /// is skipped if it does not target this layer.
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.can_load() {
+            self._add_adapter(name, vb, cfg)?;
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
+    /// Multiply the contribution of the adapters of this layer by `scale`.
+    fn set_lora_scale(&mut self, scale: f64) -> Result<usize> {
+        if self.can_load() {
+            self._set_lora_scale(scale)?;
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
     fn _activate_adapters(&mut self, adapters: &[String]) -> Result<()>;
+    fn _add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<()>;
+    fn _set_lora_scale(&mut self, scale: f64) -> Result<()>;
     fn can_load(&self) -> bool;
 }
 
@@ -138,12 +226,24 @@ impl Merge for Linear {
     fn get_delta_weight(&self, _adapter: usize) -> Result<Tensor> {
         unreachable!()
     }
+    fn merge_adapter(&mut self, _name: &str) -> Result<usize> {
+        Ok(0)
+    }
+    fn unmerge_adapter(&mut self) -> Result<usize> {
+        Ok(0)
+    }
 }
 
 impl AdapterSwapper for Linear {
     fn _activate_adapters(&mut self, _adapter: &[String]) -> Result<()> {
         unreachable!()
     }
+    fn _add_adapter(&mut self, _name: &str, _vb: &VarBuilder, _cfg: &LoraConfig) -> Result<()> {
+        unreachable!()
+    }
+    fn _set_lora_scale(&mut self, _scale: f64) -> Result<()> {
+        unreachable!()
+    }
     fn can_load(&self) -> bool {
         false
     }
@@ -321,3 +421,24 @@ pub fn linear_b(
 pub fn get_lora_cfg(tensor: &QTensor) -> LoraLinearConfig {
     LoraLinearConfig::new(tensor.shape().dims()[1], tensor.shape().dims()[0])
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{quantized::QMatMul, Device, Tensor};
+
+    use super::merge_delta;
+
+    #[test]
+    fn merge_delta_adds_to_the_base_weight() {
+        let dev = Device::Cpu;
+        let w = Tensor::new(&[[1f32, 2.], [3., 4.]], &dev).unwrap();
+        let delta = Tensor::new(&[[0.5f32, 0.], [0., -1.]], &dev).unwrap();
+        let QMatMul::Tensor(merged) = merge_delta(&QMatMul::Tensor(w), &delta).unwrap() else {
+            panic!("Expected an unquantized weight.");
+        };
+        assert_eq!(
+            merged.to_vec2::<f32>().unwrap(),
+            vec![vec![1.5, 2.], vec![3., 3.]]
+        );
+    }
+}
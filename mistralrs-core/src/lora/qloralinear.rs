@@ -1,4 +1,8 @@
-use std::{collections::HashMap, iter::zip, ops::Mul};
+use std::{
+    collections::{HashMap, HashSet},
+    iter::zip,
+    ops::Mul,
+};
 
 use candle_core::{
     bail,
@@ -9,8 +13,8 @@ use candle_nn::{Linear, VarBuilder};
 use either::Either;
 
 use super::{
-    apply_scalings_to_x, get_maybe_topk_scalings, make_adapter, Adapter, AdapterSwapper,
-    LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, Ordering,
+    apply_scalings_to_x, get_maybe_topk_scalings, make_adapter, make_added_adapter, merge_delta,
+    Adapter, AdapterSwapper, LinearLayerLike, LoraConfig, LoraLinearConfig, Merge, Ordering,
 };
 
 #[derive(Debug)]
@@ -21,8 +25,17 @@ pub struct QLoraLinear {
     scale_adapters: Vec<f64>,
     layer_n: usize,
     merged: bool,
+    // The adapter merged by `merge_adapter` and the base weight from before it was merged
+    merged_adapter: Option<(String, QMatMul)>,
     adapters: HashMap<String, Adapter>,
+    // Added adapters which do not target this layer
+    untargeted_adapters: HashSet<String>,
+    // Multiplies the contribution of the adapters, set for each batch
+    lora_scale: f64,
     linear_config: Option<LoraLinearConfig>,
+    // Prefixes of the A and B weights of the adapters of this layer
+    a_prefix: String,
+    b_prefix: String,
 }
 
 /// Specialized QLoRA for no bias
@@ -57,8 +70,13 @@ impl QLoraLinear {
                 scale_adapters: vec![],
                 layer_n: usize::MAX,
                 merged: false,
+                merged_adapter: None,
                 adapters: HashMap::default(),
+                untargeted_adapters: HashSet::new(),
+                lora_scale: 1.,
                 linear_config: None,
+                a_prefix: String::new(),
+                b_prefix: String::new(),
             });
         }
 
@@ -148,8 +166,13 @@ impl QLoraLinear {
                 scale_adapters,
                 layer_n: layer,
                 merged: false,
+                merged_adapter: None,
                 adapters,
+                untargeted_adapters: HashSet::new(),
+                lora_scale: 1.,
                 linear_config: Some(linear_config.clone()),
+                a_prefix: a_vb.prefix(),
+                b_prefix: b_vb.prefix(),
             })
         } else {
             Ok(QLoraLinear {
@@ -159,8 +182,13 @@ impl QLoraLinear {
                 scale_adapters,
                 layer_n: layer,
                 merged: false,
+                merged_adapter: None,
                 adapters,
+                untargeted_adapters: HashSet::new(),
+                lora_scale: 1.,
                 linear_config: Some(linear_config.clone()),
+                a_prefix: a_vb.prefix(),
+                b_prefix: b_vb.prefix(),
             })
         }
     }
@@ -168,6 +196,13 @@ impl QLoraLinear {
 
 impl AdapterSwapper for QLoraLinear {
     fn _activate_adapters(&mut self, adapter_names: &[String]) -> Result<()> {
+        if let Some((merged, _)) = &self.merged_adapter {
+            // The merged adapter is the active one
+            if adapter_names != std::slice::from_ref(merged) {
+                bail!("Adapter `{merged}` is merged, it must be unmerged before activating other adapters.");
+            }
+            return Ok(());
+        }
         match (
             &mut self.a_adapters,
             &mut self.b_adapters,
@@ -178,6 +213,9 @@ impl AdapterSwapper for QLoraLinear {
                 b.clear();
                 s.clear();
                 for adapter_name in adapter_names {
+                    if self.untargeted_adapters.contains(adapter_name) {
+                        continue;
+                    }
                     let Adapter {
                         a: a_w,
                         b: b_w,
@@ -195,6 +233,35 @@ impl AdapterSwapper for QLoraLinear {
         }
         Ok(())
     }
+    fn _add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<()> {
+        if self.a_adapters.is_right() {
+            bail!("Adapters can only be added to models with `preload_adapters` in their ordering file.");
+        }
+        let linear_config = self
+            .linear_config
+            .as_ref()
+            .expect("Adapters are only added to layers which can load them.");
+        if let Some(adapter) = make_added_adapter(
+            &self.adapters,
+            name,
+            (&self.a_prefix, &self.b_prefix),
+            vb,
+            cfg,
+            linear_config,
+        )? {
+            self.adapters.insert(name.to_string(), adapter);
+        } else {
+            self.untargeted_adapters.insert(name.to_string());
+        }
+        Ok(())
+    }
+    fn _set_lora_scale(&mut self, scale: f64) -> Result<()> {
+        if (self.merged || self.merged_adapter.is_some()) && scale != 1. {
+            bail!("The adapters of merged layers cannot be scaled.");
+        }
+        self.lora_scale = scale;
+        Ok(())
+    }
     fn can_load(&self) -> bool {
         self.linear_config.is_some()
     }
@@ -226,6 +293,37 @@ impl Merge for QLoraLinear {
         self.merged = true;
         Ok(())
     }
+
+    fn merge_adapter(&mut self, name: &str) -> Result<usize> {
+        if self.merged {
+            bail!("The adapters of this layer are already merged.");
+        }
+        if let Some((merged, _)) = &self.merged_adapter {
+            bail!("Adapter `{merged}` is merged, it must be unmerged first.");
+        }
+        let Some(adapter) = self.adapters.get(name) else {
+            return Ok(0);
+        };
+        if self.a_adapters.is_right() {
+            bail!("Adapters can only be merged into models with `preload_adapters` in their ordering file.");
+        }
+        let delta = adapter.delta_weight()?;
+        let merged = merge_delta(&self.old, &delta)?;
+        self._activate_adapters(&[name.to_string()])?;
+        let base = std::mem::replace(&mut self.old, merged);
+        self.merged_adapter = Some((name.to_string(), base));
+        Ok(1)
+    }
+
+    fn unmerge_adapter(&mut self) -> Result<usize> {
+        match self.merged_adapter.take() {
+            Some((_, base)) => {
+                self.old = base;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
 }
 
 impl LinearLayerLike for QLoraLinear {
@@ -250,9 +348,10 @@ impl LinearLayerLike for QLoraLinear {
     ) -> Result<Tensor> {
         //No fan_in_fan_out so no weight.transpose(0,1)
         let mut result = self.old.forward(input)?;
-        if self.merged {
+        if self.merged || self.merged_adapter.is_some() || self.lora_scale == 0. {
             return Ok(result);
         }
+        let global_scaling_weight = global_scaling_weight * self.lora_scale;
 
         if self
             .a_adapters
@@ -0,0 +1,308 @@
+//! Metrics of the engine, which can be rendered in the Prometheus text exposition format.
+
+use std::fmt::Write;
+
+use candle_core::Device;
+
+use crate::PrefixCacheStats;
+
+/// Upper bounds of the step latency histogram buckets, in seconds.
+const STEP_LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30.,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+/// A histogram with cumulative buckets, as in Prometheus.
+pub struct Histogram {
+    /// Upper bound of each bucket and the number of observations less than or equal to it.
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            buckets: bounds.iter().map(|bound| (*bound, 0)).collect(),
+            sum: 0.,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        for (bound, count) in &mut self.buckets {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// A snapshot of the metrics of the engine, updated after every step.
+pub struct EngineMetrics {
+    /// Sequences scheduled in the last step.
+    pub running_sequences: usize,
+    /// Sequences waiting to be scheduled.
+    pub waiting_sequences: usize,
+    /// Tokens sampled since the engine started.
+    pub generated_tokens: u64,
+    pub prefix_cache: PrefixCacheStats,
+    /// Approximate size of the prefix caches on the device.
+    pub prefix_cache_device_bytes: usize,
+    /// Memory used on the device, if it can be queried (CUDA only).
+    pub device_memory_used_bytes: Option<usize>,
+    /// Duration of the engine steps, in seconds.
+    pub step_latency: Histogram,
+}
+
+impl Default for EngineMetrics {
+    fn default() -> Self {
+        Self {
+            running_sequences: 0,
+            waiting_sequences: 0,
+            generated_tokens: 0,
+            prefix_cache: PrefixCacheStats::default(),
+            prefix_cache_device_bytes: 0,
+            device_memory_used_bytes: None,
+            step_latency: Histogram::new(&STEP_LATENCY_BUCKETS),
+        }
+    }
+}
+
+impl EngineMetrics {
+    /// Fraction of prefix cache searches which returned a cache, or 0 if there were none.
+    pub fn prefix_cache_hit_ratio(&self) -> f64 {
+        #![allow(clippy::cast_precision_loss)]
+        let searches = self.prefix_cache.hits + self.prefix_cache.misses;
+        if searches == 0 {
+            0.
+        } else {
+            self.prefix_cache.hits as f64 / searches as f64
+        }
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, tp: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP mistralrs_{name} {help}");
+            let _ = writeln!(out, "# TYPE mistralrs_{name} {tp}");
+            let _ = writeln!(out, "mistralrs_{name} {value}");
+        };
+        metric(
+            "running_sequences",
+            "gauge",
+            "Sequences scheduled in the last step.",
+            self.running_sequences.to_string(),
+        );
+        metric(
+            "waiting_sequences",
+            "gauge",
+            "Sequences waiting to be scheduled.",
+            self.waiting_sequences.to_string(),
+        );
+        metric(
+            "generated_tokens_total",
+            "counter",
+            "Tokens sampled.",
+            self.generated_tokens.to_string(),
+        );
+        metric(
+            "prefix_cache_hits_total",
+            "counter",
+            "Prefix cache searches which returned a cache.",
+            self.prefix_cache.hits.to_string(),
+        );
+        metric(
+            "prefix_cache_misses_total",
+            "counter",
+            "Prefix cache searches which did not return a cache.",
+            self.prefix_cache.misses.to_string(),
+        );
+        metric(
+            "prefix_cache_hit_ratio",
+            "gauge",
+            "Fraction of prefix cache searches which returned a cache.",
+            self.prefix_cache_hit_ratio().to_string(),
+        );
+        metric(
+            "prefix_cache_evictions_total",
+            "counter",
+            "Prefix caches evicted from the device to the offload device.",
+            self.prefix_cache.evictions.to_string(),
+        );
+        metric(
+            "prefix_cache_device_bytes",
+            "gauge",
+            "Approximate size of the prefix caches on the device.",
+            self.prefix_cache_device_bytes.to_string(),
+        );
+        if let Some(used) = self.device_memory_used_bytes {
+            metric(
+                "device_memory_used_bytes",
+                "gauge",
+                "Memory used on the device.",
+                used.to_string(),
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP mistralrs_step_latency_seconds Duration of the engine steps."
+        );
+        let _ = writeln!(out, "# TYPE mistralrs_step_latency_seconds histogram");
+        for (bound, count) in &self.step_latency.buckets {
+            let _ = writeln!(
+                out,
+                "mistralrs_step_latency_seconds_bucket{{le=\"{bound}\"}} {count}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "mistralrs_step_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+            self.step_latency.count
+        );
+        let _ = writeln!(
+            out,
+            "mistralrs_step_latency_seconds_sum {}",
+            self.step_latency.sum
+        );
+        let _ = writeln!(
+            out,
+            "mistralrs_step_latency_seconds_count {}",
+            self.step_latency.count
+        );
+        out
+    }
+}
+
+/// Memory used on the device, if it can be queried.
+#[cfg(feature = "cuda")]
+pub(crate) fn device_memory_used(device: &Device) -> Option<usize> {
+    use candle_core::cuda_backend::cudarc::driver;
+
+    let Device::Cuda(dev) = device else {
+        return None;
+    };
+    dev.cuda_device().bind_to_thread().ok()?;
+    let (free, total) = driver::result::mem_get_info().ok()?;
+    Some(total - free)
+}
+
+#[cfg(not(feature = "cuda"))]
+pub(crate) fn device_memory_used(_device: &Device) -> Option<usize> {
+    None
+}
+
+/// Memory free on the device, if it can be queried.
+#[cfg(feature = "cuda")]
+pub(crate) fn device_memory_free(device: &Device) -> Option<usize> {
+    use candle_core::cuda_backend::cudarc::driver;
+
+    let Device::Cuda(dev) = device else {
+        return None;
+    };
+    dev.cuda_device().bind_to_thread().ok()?;
+    let (free, _total) = driver::result::mem_get_info().ok()?;
+    Some(free)
+}
+
+#[cfg(not(feature = "cuda"))]
+pub(crate) fn device_memory_free(_device: &Device) -> Option<usize> {
+    None
+}
+
+/// Fraction of the free device memory which the KV caches may use, leaving room for the activations.
+const KV_CACHE_MEMORY_FRACTION: f64 = 0.9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How many tokens of KV cache fit in the memory left on the device once the model is loaded.
+pub(crate) struct KvCacheProfile {
+    pub bytes_per_token: usize,
+    pub free_bytes: usize,
+    /// Tokens of KV cache which fit in [`KV_CACHE_MEMORY_FRACTION`] of the free memory.
+    pub token_budget: usize,
+    /// Prefix caches of `max_seq_len` tokens which fit in the budget once the running sequences have theirs.
+    pub n_on_device: usize,
+}
+
+impl KvCacheProfile {
+    pub fn new(
+        bytes_per_token: usize,
+        free_bytes: usize,
+        max_seq_len: usize,
+        max_running: usize,
+    ) -> Self {
+        #![allow(
+            clippy::cast_precision_loss,
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss
+        )]
+        let token_budget =
+            (free_bytes as f64 * KV_CACHE_MEMORY_FRACTION) as usize / bytes_per_token.max(1);
+        Self {
+            bytes_per_token,
+            free_bytes,
+            token_budget,
+            n_on_device: (token_budget / max_seq_len.max(1)).saturating_sub(max_running),
+        }
+    }
+
+    /// Whether the running sequences can all reach `max_seq_len` tokens within the budget.
+    pub fn fits_running(&self, max_seq_len: usize, max_running: usize) -> bool {
+        self.token_budget >= max_seq_len * max_running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EngineMetrics, Histogram, KvCacheProfile};
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new(&[0.1, 1.]);
+        histogram.observe(0.05);
+        histogram.observe(0.5);
+        histogram.observe(5.);
+        assert_eq!(histogram.buckets, vec![(0.1, 1), (1., 2)]);
+        assert_eq!(histogram.count, 3);
+        assert!((histogram.sum - 5.55).abs() < 1e-9);
+    }
+
+    #[test]
+    fn prometheus_rendering() {
+        let mut metrics = EngineMetrics {
+            running_sequences: 2,
+            ..Default::default()
+        };
+        metrics.prefix_cache.hits = 3;
+        metrics.prefix_cache.misses = 1;
+        metrics.step_latency.observe(0.02);
+
+        let out = metrics.to_prometheus();
+        assert!(out
+            .contains("# TYPE mistralrs_running_sequences gauge\nmistralrs_running_sequences 2\n"));
+        assert!(out.contains("mistralrs_prefix_cache_hit_ratio 0.75\n"));
+        assert!(out.contains("mistralrs_step_latency_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(out.contains("mistralrs_step_latency_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(out.contains("mistralrs_step_latency_seconds_bucket{le=\"+Inf\"} 1\n"));
+        // The device memory is only reported when it is known
+        assert!(!out.contains("device_memory_used_bytes"));
+    }
+
+    #[test]
+    fn kv_cache_profile() {
+        // 128 KiB per token and 10 GiB free: 9 GiB for the KV caches
+        let profile = KvCacheProfile::new(128 * 1024, 10 * 1024 * 1024 * 1024, 4096, 4);
+        assert_eq!(profile.token_budget, 73_728);
+        assert_eq!(profile.n_on_device, 18 - 4);
+        assert!(profile.fits_running(4096, 4));
+
+        let profile = KvCacheProfile::new(128 * 1024, 1024 * 1024 * 1024, 4096, 4);
+        assert_eq!(profile.n_on_device, 0);
+        assert!(!profile.fits_running(4096, 4));
+    }
+}
@@ -2,12 +2,13 @@ use std::fs::{self, File};
 
 use crate::{
     get_toml_selected_model_dtype,
+    layers::RopeScaling,
     pipeline::{
         GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig,
         NormalSpecificConfig,
     },
-    Loader, ModelDType, ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector,
-    VisionLoaderBuilder, VisionSpecificConfig,
+    IsqOverride, Loader, ModelDType, ModelSelected, NormalLoaderBuilder, TomlLoaderArgs,
+    TomlSelector, VisionLoaderBuilder, VisionSpecificConfig,
 };
 
 /// A builder for a loader using the selected model.
@@ -16,6 +17,9 @@ pub struct LoaderBuilder {
     no_kv_cache: bool,
     chat_template: Option<String>,
     use_flash_attn: bool,
+    isq_overrides: Vec<IsqOverride>,
+    rope_scaling: Option<RopeScaling>,
+    rope_theta: Option<f64>,
 }
 
 impl LoaderBuilder {
@@ -25,6 +29,9 @@ impl LoaderBuilder {
             no_kv_cache: false,
             chat_template: None,
             use_flash_attn: false,
+            isq_overrides: Vec::new(),
+            rope_scaling: None,
+            rope_theta: None,
         }
     }
 
@@ -40,6 +47,20 @@ impl LoaderBuilder {
         self.use_flash_attn = use_flash_attn;
         self
     }
+    pub fn with_isq_overrides(mut self, isq_overrides: Vec<IsqOverride>) -> Self {
+        self.isq_overrides = isq_overrides;
+        self
+    }
+    /// Scale the rotary embedding to extend the context. Only applied to plain, X-LoRA and LoRA models.
+    pub fn with_rope_scaling(mut self, rope_scaling: Option<RopeScaling>) -> Self {
+        self.rope_scaling = rope_scaling;
+        self
+    }
+    /// Replace the base frequency of the rotary embedding. Only applied to plain, X-LoRA, LoRA and GGUF models.
+    pub fn with_rope_theta(mut self, rope_theta: Option<f64>) -> Self {
+        self.rope_theta = rope_theta;
+        self
+    }
 
     pub fn build(self) -> anyhow::Result<Box<dyn Loader>> {
         loader_from_model_selected(self)
@@ -123,6 +144,9 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             tokenizer_json,
             Some(model_id),
         )
+        .with_isq_overrides(args.isq_overrides)
+        .with_rope_scaling(args.rope_scaling)
+        .with_rope_theta(args.rope_theta)
         .build(arch),
         ModelSelected::XLora {
             model_id,
@@ -151,6 +175,9 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             args.no_kv_cache,
             tgt_non_granular_index,
         )
+        .with_isq_overrides(args.isq_overrides)
+        .with_rope_scaling(args.rope_scaling)
+        .with_rope_theta(args.rope_theta)
         .build(arch),
         ModelSelected::Lora {
             model_id,
@@ -176,6 +203,9 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?,
         )
+        .with_isq_overrides(args.isq_overrides)
+        .with_rope_scaling(args.rope_scaling)
+        .with_rope_theta(args.rope_theta)
         .build(arch),
         ModelSelected::GGUF {
             tok_model_id,
@@ -189,6 +219,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             quantized_model_id,
             quantized_filename,
         )
+        .with_rope_theta(args.rope_theta.map(|theta| theta as f32))
         .build(),
         ModelSelected::XLoraGGUF {
             tok_model_id,
@@ -214,6 +245,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             args.no_kv_cache,
             tgt_non_granular_index,
         )
+        .with_rope_theta(args.rope_theta.map(|theta| theta as f32))
         .build(),
         ModelSelected::LoraGGUF {
             tok_model_id,
@@ -236,6 +268,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                     .unwrap_or_else(|_| panic!("Could not load ordering file at {order}")),
             )?,
         )
+        .with_rope_theta(args.rope_theta.map(|theta| theta as f32))
         .build(),
         ModelSelected::GGML {
             tok_model_id,
@@ -321,6 +354,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             tokenizer_json,
             Some(model_id),
         )
+        .with_isq_overrides(args.isq_overrides)
         .build(arch),
     };
     Ok(loader)
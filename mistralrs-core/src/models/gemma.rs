@@ -8,7 +8,7 @@ use candle_nn::{linear_b as linear, Activation, RotaryEmbedding, VarBuilder};
 use crate::{
     device_map::DeviceMapper,
     layers::{repeat_kv, CausalMasker, MatMul, QLinear, ScaledDotProductAttention},
-    pipeline::{extract_logits, Cache, IsqModel, NormalLoadingMetadata, NormalModel},
+    pipeline::{extract_logits, Cache, IsqModel, IsqTensor, NormalLoadingMetadata, NormalModel},
     utils::progress::NiceProgressBar,
 };
 
@@ -381,7 +381,7 @@ impl Model {
             device: normal_loading_metadata.real_device,
             hidden_size: cfg.hidden_size,
             cache: Cache::new(cfg.num_hidden_layers, false),
-            max_seq_len: default_max_position_embeddings(),
+            max_seq_len: cfg.max_position_embeddings,
             mapper,
         })
     }
@@ -392,6 +392,20 @@ impl Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mut xs = self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+    }
+
+    /// The hidden states after the final norm, before the LM head.
+    pub fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
     ) -> Result<Tensor> {
         let xs = self.embed_tokens.forward(input_ids)?;
         let mut xs = (xs * (self.hidden_size as f64).sqrt())?;
@@ -415,27 +429,50 @@ impl Model {
                 &mut cache[i],
             )?;
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+        xs.to_device(&self.device)?.apply(&self.norm)
     }
 }
 
 impl IsqModel for Model {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, "lm_head".to_string()));
         for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((layer.self_attn.q_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.k_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.v_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.o_proj.inner(), Some(i)));
-            tensors.push((layer.mlp.down_proj.inner(), Some(i)));
-            tensors.push((layer.mlp.gate_proj.inner(), Some(i)));
-            tensors.push((layer.mlp.up_proj.inner(), Some(i)));
+            tensors.push((
+                layer.self_attn.q_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.q_proj"),
+            ));
+            tensors.push((
+                layer.self_attn.k_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.k_proj"),
+            ));
+            tensors.push((
+                layer.self_attn.v_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.v_proj"),
+            ));
+            tensors.push((
+                layer.self_attn.o_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.o_proj"),
+            ));
+            tensors.push((
+                layer.mlp.down_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.mlp.down_proj"),
+            ));
+            tensors.push((
+                layer.mlp.gate_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.mlp.gate_proj"),
+            ));
+            tensors.push((
+                layer.mlp.up_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.mlp.up_proj"),
+            ));
         }
         (tensors, &*self.mapper)
     }
@@ -457,6 +494,22 @@ impl NormalModel for Model {
             context_lens,
         )
     }
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)
+    }
+    fn lm_head(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let mut xs = hidden_states.clone();
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        MatMul.qmatmul(&xs, &self.lm_head)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
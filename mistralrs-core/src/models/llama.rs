@@ -1,16 +1,20 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use candle_core::{quantized::QMatMul, DType, Device, Result, Tensor};
-use candle_nn::{
-    embedding, linear_no_bias as linear, Embedding, Module, RotaryEmbedding, VarBuilder,
-};
+use candle_nn::{embedding, linear_no_bias as linear, Embedding, Module, VarBuilder};
 use serde::Deserialize;
 use std::sync::Arc;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention},
-    pipeline::{extract_logits, IsqModel, NormalLoadingMetadata, NormalModel},
+    layers::{
+        get_attention_implementation, repeat_kv, AttentionImplementation, CausalMasker, MatMul,
+        RmsNorm, RopeScaling, ScaledDotProductAttention, ScaledRotaryEmbedding,
+    },
+    pipeline::{
+        extract_logits, DraftTree, IsqModel, IsqTensor, NormalLoadingMetadata, NormalModel,
+    },
+    tensor_parallel::{Projection, TensorParallel},
     utils::progress::NiceProgressBar,
 };
 
@@ -26,19 +30,20 @@ pub struct Config {
     pub rms_norm_eps: f64,
     pub rope_theta: f32,
     pub max_position_embeddings: usize,
+    pub rope_scaling: Option<RopeScaling>,
 }
 
 #[derive(Debug, Clone)]
 struct CausalSelfAttention {
-    q_proj: QMatMul,
-    k_proj: QMatMul,
-    v_proj: QMatMul,
-    o_proj: QMatMul,
+    q_proj: Projection,
+    k_proj: Projection,
+    v_proj: Projection,
+    o_proj: Projection,
     num_attention_heads: usize,
     num_key_value_heads: usize,
     head_dim: usize,
     use_flash_attn: bool,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: Arc<ScaledRotaryEmbedding>,
     max_seq_len: usize,
 }
 
@@ -56,13 +61,13 @@ impl CausalSelfAttention {
 
         let original_dtype = x.dtype();
         let mut x = x.clone();
-        if matches!(self.q_proj, QMatMul::QTensor(_)) {
+        if self.q_proj.is_quantized() {
             x = x.to_dtype(DType::F32)?;
         }
-        let mut q = MatMul.qmatmul(&x, &self.q_proj)?;
-        let mut k = MatMul.qmatmul(&x, &self.k_proj)?;
-        let mut v = MatMul.qmatmul(&x, &self.v_proj)?;
-        if matches!(self.q_proj, QMatMul::QTensor(_)) {
+        let mut q = self.q_proj.forward(&x)?;
+        let mut k = self.k_proj.forward(&x)?;
+        let mut v = self.v_proj.forward(&x)?;
+        if self.q_proj.is_quantized() {
             q = q.to_dtype(original_dtype)?;
             k = k.to_dtype(original_dtype)?;
             v = v.to_dtype(original_dtype)?;
@@ -78,8 +83,19 @@ impl CausalSelfAttention {
             v.reshape((b_sz, self.num_key_value_heads, seq_len, self.head_dim))?
         };
 
-        self.rotary_emb
-            .forward(seqlen_offsets, &start_offsets_kernel, &mut q, &mut k, b_sz)?;
+        // The tokens of a tree each have their own position, so they are rotated as sequences of one token
+        let rope_b_sz = if seqlen_offsets.len() == b_sz * seq_len {
+            b_sz * seq_len
+        } else {
+            b_sz
+        };
+        self.rotary_emb.forward(
+            seqlen_offsets,
+            &start_offsets_kernel,
+            &mut q,
+            &mut k,
+            rope_b_sz,
+        )?;
 
         if q.rank() == 3 && seq_len != 1 {
             q = q
@@ -118,73 +134,84 @@ impl CausalSelfAttention {
             seq_len,
         )?;
 
-        if matches!(self.q_proj, QMatMul::QTensor(_)) {
+        if self.q_proj.is_quantized() {
             y = y.to_dtype(DType::F32)?;
         }
         let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, hidden_size])?;
-        let mut y = MatMul.qmatmul(&y, &self.o_proj)?;
-        if matches!(self.q_proj, QMatMul::QTensor(_)) {
+        let mut y = self.o_proj.forward(&y)?;
+        if self.q_proj.is_quantized() {
             y = y.to_dtype(original_dtype)?;
         }
         Ok(y)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config, rope: Arc<RotaryEmbedding>) -> Result<Self> {
+    fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        rope: Arc<ScaledRotaryEmbedding>,
+        tp: Option<&TensorParallel>,
+    ) -> Result<Self> {
         let size_in = cfg.hidden_size;
         let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
         let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
-        let q_proj = linear(size_in, size_q, vb.pp("q_proj"))?;
-        let k_proj = linear(size_in, size_kv, vb.pp("k_proj"))?;
-        let v_proj = linear(size_in, size_kv, vb.pp("v_proj"))?;
-        let o_proj = linear(size_q, size_in, vb.pp("o_proj"))?;
         Ok(Self {
-            q_proj: QMatMul::Tensor(q_proj.weight().clone()),
-            k_proj: QMatMul::Tensor(k_proj.weight().clone()),
-            v_proj: QMatMul::Tensor(v_proj.weight().clone()),
-            o_proj: QMatMul::Tensor(o_proj.weight().clone()),
+            q_proj: Projection::column(size_in, size_q, vb.pp("q_proj"), tp)?,
+            k_proj: Projection::column(size_in, size_kv, vb.pp("k_proj"), tp)?,
+            v_proj: Projection::column(size_in, size_kv, vb.pp("v_proj"), tp)?,
+            o_proj: Projection::row(size_q, size_in, vb.pp("o_proj"), tp)?,
             num_attention_heads: cfg.num_attention_heads,
             num_key_value_heads: cfg.num_key_value_heads,
             head_dim: cfg.hidden_size / cfg.num_attention_heads,
             use_flash_attn: cfg.use_flash_attn,
             rotary_emb: rope,
-            max_seq_len: cfg.max_position_embeddings,
+            max_seq_len: cfg
+                .rope_scaling
+                .as_ref()
+                .map_or(cfg.max_position_embeddings, |scaling| {
+                    scaling.context_len(cfg.max_position_embeddings)
+                }),
         })
     }
 }
 
 #[derive(Debug, Clone)]
 struct Mlp {
-    c_fc1: QMatMul,
-    c_fc2: QMatMul,
-    c_proj: QMatMul,
+    c_fc1: Projection,
+    c_fc2: Projection,
+    c_proj: Projection,
 }
 
 impl Mlp {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        if let (Projection::Column(c_fc1), Projection::Column(c_fc2), Projection::Row(c_proj)) =
+            (&self.c_fc1, &self.c_fc2, &self.c_proj)
+        {
+            // The intermediate activations stay on their devices
+            let x = std::iter::zip(c_fc1.forward_sharded(x)?, c_fc2.forward_sharded(x)?)
+                .map(|(gate, up)| candle_nn::ops::silu(&gate)? * up)
+                .collect::<Result<Vec<_>>>()?;
+            return c_proj.forward_sharded(x);
+        }
         let original_dtype = x.dtype();
         let mut x = x.clone();
-        if matches!(self.c_fc1, QMatMul::QTensor(_)) {
+        if self.c_fc1.is_quantized() {
             x = x.to_dtype(DType::F32)?;
         }
-        let x = (candle_nn::ops::silu(&MatMul.qmatmul(&x, &self.c_fc1)?)?
-            * MatMul.qmatmul(&x, &self.c_fc2)?)?;
-        let mut res = MatMul.qmatmul(&x, &self.c_proj)?;
-        if matches!(self.c_fc1, QMatMul::QTensor(_)) {
+        let x = (candle_nn::ops::silu(&self.c_fc1.forward(&x)?)? * self.c_fc2.forward(&x)?)?;
+        let mut res = self.c_proj.forward(&x)?;
+        if self.c_fc1.is_quantized() {
             res = res.to_dtype(original_dtype)?;
         }
         Ok(res)
     }
 
-    fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+    fn load(vb: VarBuilder, cfg: &Config, tp: Option<&TensorParallel>) -> Result<Self> {
         let h_size = cfg.hidden_size;
         let i_size = cfg.intermediate_size;
-        let c_fc1 = linear(h_size, i_size, vb.pp("gate_proj"))?;
-        let c_fc2 = linear(h_size, i_size, vb.pp("up_proj"))?;
-        let c_proj = linear(i_size, h_size, vb.pp("down_proj"))?;
         Ok(Self {
-            c_fc1: QMatMul::Tensor(c_fc1.weight().clone()),
-            c_fc2: QMatMul::Tensor(c_fc2.weight().clone()),
-            c_proj: QMatMul::Tensor(c_proj.weight().clone()),
+            c_fc1: Projection::column(h_size, i_size, vb.pp("gate_proj"), tp)?,
+            c_fc2: Projection::column(h_size, i_size, vb.pp("up_proj"), tp)?,
+            c_proj: Projection::row(i_size, h_size, vb.pp("down_proj"), tp)?,
         })
     }
 }
@@ -228,14 +255,20 @@ impl Block {
         mapper: &dyn DeviceMapper,
         layer_idx: usize,
         loading_isq: bool,
-        rope: Arc<RotaryEmbedding>,
+        rope: Arc<ScaledRotaryEmbedding>,
+        tp: Option<&TensorParallel>,
     ) -> Result<Self> {
         let attn = CausalSelfAttention::load(
             mapper.set_device(layer_idx, vb.pp("self_attn"), loading_isq),
             cfg,
             rope,
+            tp,
+        )?;
+        let mlp = Mlp::load(
+            mapper.set_device(layer_idx, vb.pp("mlp"), loading_isq),
+            cfg,
+            tp,
         )?;
-        let mlp = Mlp::load(mapper.set_device(layer_idx, vb.pp("mlp"), loading_isq), cfg)?;
         let rms_1 = RmsNorm::new(
             cfg.hidden_size,
             cfg.rms_norm_eps,
@@ -273,6 +306,21 @@ impl Llama {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mut x = self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            x = x.to_dtype(DType::F32)?;
+        }
+        let logits = MatMul.qmatmul(&x, &self.lm_head)?;
+        extract_logits(&logits, context_lens)
+    }
+
+    /// The hidden states after the final norm, before the LM head.
+    pub fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
     ) -> Result<Tensor> {
         let mut x = self.wte.forward(input_ids)?;
         let mut cache = self.kv_cache.lock();
@@ -294,12 +342,44 @@ impl Llama {
             )?;
         }
         let x = x.to_device(&self.device)?;
-        let mut x = self.ln_f.forward(&x)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            x = x.to_dtype(DType::F32)?;
+        self.ln_f.forward(&x)
+    }
+
+    /// The hidden states after the final norm of a tree of draft tokens, see [`NormalModel::tree_hidden_states`].
+    pub fn tree_hidden_states(&self, input_ids: &Tensor, tree: &DraftTree) -> Result<Tensor> {
+        let mut x = self.wte.forward(input_ids)?;
+        let mut cache = self.kv_cache.lock();
+        let past_kv_len = CausalMasker.calculate_past_kv_len(&cache)?;
+        let mask = CausalMasker.make_tree_mask_as_attn_bias(
+            tree,
+            &cache,
+            x.dtype(),
+            self.blocks[0].attn.num_attention_heads,
+            input_ids.device(),
+        )?;
+        let positions = tree
+            .position_offsets()
+            .into_iter()
+            .map(|offset| past_kv_len + offset)
+            .collect::<Vec<_>>();
+        let positions_kernel = Tensor::new(
+            positions.iter().map(|pos| *pos as i64).collect::<Vec<_>>(),
+            input_ids.device(),
+        )?
+        .unsqueeze(1)?;
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            x = self.mapper.map(x, block_idx)?;
+            x = block.forward(
+                &x,
+                &Some(mask.to_device(x.device())?),
+                &positions,
+                positions_kernel.to_device(x.device())?,
+                block_idx,
+                &mut cache,
+            )?;
         }
-        let logits = MatMul.qmatmul(&x, &self.lm_head)?;
-        extract_logits(&logits, context_lens)
+        let x = x.to_device(&self.device)?;
+        self.ln_f.forward(&x)
     }
 
     pub fn new(
@@ -312,6 +392,9 @@ impl Llama {
             .mapper
             .into_mapper(cfg.num_hidden_layers, &normal_loading_metadata.real_device)?;
         let vb = vb.set_dtype(mapper.get_min_dtype()?);
+        let tp = normal_loading_metadata
+            .mapper
+            .tensor_parallel(&normal_loading_metadata.real_device)?;
 
         let wte = embedding(
             cfg.vocab_size,
@@ -333,10 +416,11 @@ impl Llama {
             .into_iter()
             .map(|i| {
                 let rotary_emb = Arc::new(
-                    RotaryEmbedding::new(
+                    ScaledRotaryEmbedding::new(
                         cfg.rope_theta,
                         head_dim,
                         cfg.max_position_embeddings,
+                        cfg.rope_scaling.as_ref(),
                         mapper
                             .device_for(i, false)
                             .unwrap_or(&normal_loading_metadata.real_device),
@@ -352,6 +436,7 @@ impl Llama {
                     i,
                     normal_loading_metadata.loading_isq,
                     rotary_emb,
+                    tp.as_ref(),
                 )
                 .expect("Failed to load block.")
             })
@@ -370,17 +455,31 @@ impl Llama {
 }
 
 impl IsqModel for Llama {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, "lm_head".to_string()));
         for (i, layer) in self.blocks.iter_mut().enumerate() {
-            tensors.push((&mut layer.attn.q_proj, Some(i)));
-            tensors.push((&mut layer.attn.k_proj, Some(i)));
-            tensors.push((&mut layer.attn.v_proj, Some(i)));
-            tensors.push((&mut layer.attn.o_proj, Some(i)));
-            tensors.push((&mut layer.mlp.c_fc1, Some(i)));
-            tensors.push((&mut layer.mlp.c_fc2, Some(i)));
-            tensors.push((&mut layer.mlp.c_proj, Some(i)));
+            if let Some(w) = layer.attn.q_proj.isq_weight() {
+                tensors.push((w, Some(i), format!("model.layers.{i}.self_attn.q_proj")));
+            }
+            if let Some(w) = layer.attn.k_proj.isq_weight() {
+                tensors.push((w, Some(i), format!("model.layers.{i}.self_attn.k_proj")));
+            }
+            if let Some(w) = layer.attn.v_proj.isq_weight() {
+                tensors.push((w, Some(i), format!("model.layers.{i}.self_attn.v_proj")));
+            }
+            if let Some(w) = layer.attn.o_proj.isq_weight() {
+                tensors.push((w, Some(i), format!("model.layers.{i}.self_attn.o_proj")));
+            }
+            if let Some(w) = layer.mlp.c_fc1.isq_weight() {
+                tensors.push((w, Some(i), format!("model.layers.{i}.mlp.gate_proj")));
+            }
+            if let Some(w) = layer.mlp.c_fc2.isq_weight() {
+                tensors.push((w, Some(i), format!("model.layers.{i}.mlp.up_proj")));
+            }
+            if let Some(w) = layer.mlp.c_proj.isq_weight() {
+                tensors.push((w, Some(i), format!("model.layers.{i}.mlp.down_proj")));
+            }
         }
         (tensors, &*self.mapper)
     }
@@ -402,6 +501,30 @@ impl NormalModel for Llama {
             context_lens,
         )
     }
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)
+    }
+    fn tree_hidden_states(&self, input_ids: &Tensor, tree: &DraftTree) -> Result<Tensor> {
+        self.tree_hidden_states(input_ids, tree)
+    }
+    fn supports_tree_attention(&self) -> bool {
+        // The flash attention kernel only applies a causal mask
+        !(self.blocks[0].attn.use_flash_attn
+            && get_attention_implementation() == AttentionImplementation::Flash)
+    }
+    fn lm_head(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let mut xs = hidden_states.clone();
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        MatMul.qmatmul(&xs, &self.lm_head)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -2,13 +2,16 @@
 
 /// Mistral LLM, https://github.com/mistralai/mistral-src
 use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor};
-use candle_nn::{linear_no_bias, Activation, RotaryEmbedding, VarBuilder};
+use candle_nn::{linear_no_bias, Activation, VarBuilder};
 use std::sync::Arc;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, MatMul, RmsNorm, ScaledDotProductAttention},
-    pipeline::{extract_logits, Cache, IsqModel, NormalLoadingMetadata, NormalModel},
+    layers::{
+        repeat_kv, CausalMasker, MatMul, RmsNorm, RopeScaling, ScaledDotProductAttention,
+        ScaledRotaryEmbedding,
+    },
+    pipeline::{extract_logits, Cache, IsqModel, IsqTensor, NormalLoadingMetadata, NormalModel},
     utils::progress::NiceProgressBar,
 };
 
@@ -22,6 +25,7 @@ pub struct Config {
     pub(crate) num_key_value_heads: usize,
     pub(crate) hidden_act: Activation,
     pub(crate) max_position_embeddings: usize,
+    pub(crate) rope_scaling: Option<RopeScaling>,
     pub(crate) rms_norm_eps: f64,
     pub(crate) rope_theta: f64,
     pub(crate) sliding_window: Option<usize>,
@@ -81,13 +85,13 @@ struct Attention {
     num_kv_groups: usize,
     head_dim: usize,
     hidden_size: usize,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: Arc<ScaledRotaryEmbedding>,
     use_flash_attn: bool,
     sliding_window: Option<usize>,
 }
 
 impl Attention {
-    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    fn new(rotary_emb: Arc<ScaledRotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let num_heads = cfg.num_attention_heads;
         let num_kv_heads = cfg.num_key_value_heads;
@@ -219,7 +223,7 @@ struct DecoderLayer {
 
 impl DecoderLayer {
     fn new(
-        rotary_emb: Arc<RotaryEmbedding>,
+        rotary_emb: Arc<ScaledRotaryEmbedding>,
         cfg: &Config,
         vb: VarBuilder,
         mapper: &dyn DeviceMapper,
@@ -321,10 +325,11 @@ impl Model {
         let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
         let vb_l = vb_m.pp("layers");
         for layer_idx in NiceProgressBar(0..cfg.num_hidden_layers, "Loading repeating layers") {
-            let rotary_emb = Arc::new(RotaryEmbedding::new(
+            let rotary_emb = Arc::new(ScaledRotaryEmbedding::new(
                 cfg.rope_theta as f32,
                 head_dim,
                 cfg.max_position_embeddings,
+                cfg.rope_scaling.as_ref(),
                 mapper
                     .device_for(layer_idx, false)
                     .unwrap_or(&normal_loading_metadata.real_device),
@@ -359,7 +364,12 @@ impl Model {
             sliding_window: cfg.sliding_window,
             device: normal_loading_metadata.real_device,
             cache: Cache::new(cfg.num_hidden_layers, false),
-            max_seq_len: cfg.max_position_embeddings,
+            max_seq_len: cfg
+                .rope_scaling
+                .as_ref()
+                .map_or(cfg.max_position_embeddings, |scaling| {
+                    scaling.context_len(cfg.max_position_embeddings)
+                }),
             mapper,
         })
     }
@@ -391,6 +401,40 @@ impl Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mut xs = self.hidden_states_embeds(
+            input_ids,
+            input_embeds,
+            seqlen_offsets,
+            start_offsets_kernel,
+        )?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+    }
+
+    /// The hidden states after the final norm, before the LM head.
+    pub fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+    ) -> Result<Tensor> {
+        self.hidden_states_embeds(
+            input_ids,
+            self.embed_tokens.forward(input_ids)?,
+            seqlen_offsets,
+            start_offsets_kernel,
+        )
+    }
+
+    pub fn hidden_states_embeds(
+        &self,
+        input_ids: &Tensor,
+        input_embeds: Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
     ) -> Result<Tensor> {
         let mut xs = input_embeds;
         let mut cache = self.cache.lock();
@@ -414,27 +458,50 @@ impl Model {
                 &mut cache[i],
             )?;
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+        xs.to_device(&self.device)?.apply(&self.norm)
     }
 }
 
 impl IsqModel for Model {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, "lm_head".to_string()));
         for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((&mut layer.self_attn.q_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.k_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.v_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
-            tensors.push((&mut layer.mlp.down_proj, Some(i)));
-            tensors.push((&mut layer.mlp.up_proj, Some(i)));
-            tensors.push((&mut layer.mlp.gate_proj, Some(i)));
+            tensors.push((
+                &mut layer.self_attn.q_proj,
+                Some(i),
+                format!("model.layers.{i}.self_attn.q_proj"),
+            ));
+            tensors.push((
+                &mut layer.self_attn.k_proj,
+                Some(i),
+                format!("model.layers.{i}.self_attn.k_proj"),
+            ));
+            tensors.push((
+                &mut layer.self_attn.v_proj,
+                Some(i),
+                format!("model.layers.{i}.self_attn.v_proj"),
+            ));
+            tensors.push((
+                &mut layer.self_attn.o_proj,
+                Some(i),
+                format!("model.layers.{i}.self_attn.o_proj"),
+            ));
+            tensors.push((
+                &mut layer.mlp.down_proj,
+                Some(i),
+                format!("model.layers.{i}.mlp.down_proj"),
+            ));
+            tensors.push((
+                &mut layer.mlp.up_proj,
+                Some(i),
+                format!("model.layers.{i}.mlp.up_proj"),
+            ));
+            tensors.push((
+                &mut layer.mlp.gate_proj,
+                Some(i),
+                format!("model.layers.{i}.mlp.gate_proj"),
+            ));
         }
         (tensors, &*self.mapper)
     }
@@ -456,6 +523,22 @@ impl NormalModel for Model {
             context_lens,
         )
     }
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)
+    }
+    fn lm_head(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let mut xs = hidden_states.clone();
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        MatMul.qmatmul(&xs, &self.lm_head)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
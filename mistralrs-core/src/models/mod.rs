@@ -7,4 +7,5 @@ pub(crate) mod phi3;
 pub(crate) mod quantized_llama;
 pub(crate) mod quantized_phi2;
 pub(crate) mod quantized_phi3;
+pub(crate) mod quantized_qwen2;
 pub(crate) mod qwen2;
@@ -5,7 +5,7 @@
 /// There is an alternative implementation of the phi model in mixformers.rs.
 /// This corresponds to the model update made with the following commit:
 /// https://huggingface.co/microsoft/phi-2/commit/cb2f4533604d8b67de604e7df03bfe6f3ca22869
-use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor};
+use candle_core::{DType, Device, Module, Result, Tensor};
 use candle_nn::{
     embedding, layer_norm, linear, Activation, Embedding, LayerNorm, RotaryEmbedding, VarBuilder,
 };
@@ -14,7 +14,7 @@ use serde::Deserialize;
 use crate::{
     device_map::DeviceMapper,
     layers::{repeat_kv, CausalMasker, QLinear, ScaledDotProductAttention},
-    pipeline::{extract_logits, Cache, IsqModel, NormalLoadingMetadata, NormalModel},
+    pipeline::{extract_logits, Cache, IsqModel, IsqTensor, NormalLoadingMetadata, NormalModel},
     utils::progress::NiceProgressBar,
 };
 
@@ -365,6 +365,20 @@ impl Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mut xs = self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)?;
+        if self.lm_head.is_quant() {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&xs.apply(&self.lm_head)?, context_lens)
+    }
+
+    /// The hidden states after the final norm, before the LM head.
+    pub fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
     ) -> Result<Tensor> {
         let mut xs = input_ids.apply(&self.embed_tokens)?;
         let mut cache = self.cache.lock();
@@ -386,26 +400,45 @@ impl Model {
                 &mut cache[i],
             )?;
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.final_layernorm)?;
-        if self.lm_head.is_quant() {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-        extract_logits(&xs.apply(&self.lm_head)?, context_lens)
+        xs.to_device(&self.device)?.apply(&self.final_layernorm)
     }
 }
 
 impl IsqModel for Model {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((self.lm_head.inner(), None));
+        tensors.push((self.lm_head.inner(), None, "lm_head".to_string()));
         for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((layer.self_attn.q_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.k_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.v_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.dense.inner(), Some(i)));
-            tensors.push((layer.mlp.fc1.inner(), Some(i)));
-            tensors.push((layer.mlp.fc2.inner(), Some(i)));
+            tensors.push((
+                layer.self_attn.q_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.q_proj"),
+            ));
+            tensors.push((
+                layer.self_attn.k_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.k_proj"),
+            ));
+            tensors.push((
+                layer.self_attn.v_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.v_proj"),
+            ));
+            tensors.push((
+                layer.self_attn.dense.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.dense"),
+            ));
+            tensors.push((
+                layer.mlp.fc1.inner(),
+                Some(i),
+                format!("model.layers.{i}.mlp.fc1"),
+            ));
+            tensors.push((
+                layer.mlp.fc2.inner(),
+                Some(i),
+                format!("model.layers.{i}.mlp.fc2"),
+            ));
         }
         (tensors, &*self.mapper)
     }
@@ -427,6 +460,22 @@ impl NormalModel for Model {
             context_lens,
         )
     }
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)
+    }
+    fn lm_head(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let mut xs = hidden_states.clone();
+        if self.lm_head.is_quant() {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        xs.apply(&self.lm_head)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
@@ -13,7 +13,8 @@ use crate::{
         ScaledDotProductAttention,
     },
     pipeline::{
-        extract_logits, Cache, IsqModel, NormalLoadingMetadata, NormalModel, Phi3RopeScaling,
+        extract_logits, Cache, IsqModel, IsqTensor, NormalLoadingMetadata, NormalModel,
+        Phi3RopeScaling,
     },
     utils::progress::NiceProgressBar,
 };
@@ -362,6 +363,20 @@ impl Model {
         seqlen_offsets: &[usize],
         position_ids: &[usize],
         context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mut xs = self.hidden_states(input_ids, seqlen_offsets, position_ids)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+    }
+
+    /// The hidden states after the final norm, before the LM head.
+    pub fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        position_ids: &[usize],
     ) -> Result<Tensor> {
         let mut xs = self.embed_tokens.forward(input_ids)?;
         let mut cache = self.cache.lock();
@@ -386,24 +401,35 @@ impl Model {
                 &mut cache[i],
             )?
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+        xs.to_device(&self.device)?.apply(&self.norm)
     }
 }
 
 impl IsqModel for Model {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, "lm_head".to_string()));
         for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((&mut layer.self_attn.qkv_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
-            tensors.push((&mut layer.mlp.gate_up_proj, Some(i)));
-            tensors.push((&mut layer.mlp.down_proj, Some(i)));
+            tensors.push((
+                &mut layer.self_attn.qkv_proj,
+                Some(i),
+                format!("model.layers.{i}.self_attn.qkv_proj"),
+            ));
+            tensors.push((
+                &mut layer.self_attn.o_proj,
+                Some(i),
+                format!("model.layers.{i}.self_attn.o_proj"),
+            ));
+            tensors.push((
+                &mut layer.mlp.gate_up_proj,
+                Some(i),
+                format!("model.layers.{i}.mlp.gate_up_proj"),
+            ));
+            tensors.push((
+                &mut layer.mlp.down_proj,
+                Some(i),
+                format!("model.layers.{i}.mlp.down_proj"),
+            ));
         }
         (tensors, &*self.mapper)
     }
@@ -420,6 +446,22 @@ impl NormalModel for Model {
     ) -> Result<Tensor> {
         self.forward(input_ids, seqlen_offsets, &position_ids, context_lens)
     }
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        self.hidden_states(input_ids, seqlen_offsets, &position_ids)
+    }
+    fn lm_head(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let mut xs = hidden_states.clone();
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        MatMul.qmatmul(&xs, &self.lm_head)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
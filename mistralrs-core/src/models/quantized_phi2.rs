@@ -155,6 +155,7 @@ struct PropsGGUF {
     rope_dim: usize,
     ln_eps: f64,
     max_seq_len: usize,
+    rope_freq_base: f32,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
@@ -187,6 +188,7 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
                 .get_value::<u64>("context_length")
                 .ok()
                 .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+            rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(10_000_f32),
         };
 
         Ok(props)
@@ -213,9 +215,10 @@ impl ModelConfig::FromGGUF for ModelWeights {
             rope_dim,
             ln_eps,
             max_seq_len,
+            rope_freq_base,
         } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
 
-        let (cos, sin) = precomput_freqs_cis(rope_dim, 10_000., device, max_seq_len)?;
+        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device, max_seq_len)?;
 
         let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
         let tok_embeddings = tok_embeddings.dequantize(device)?;
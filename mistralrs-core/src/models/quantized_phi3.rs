@@ -180,6 +180,7 @@ pub(crate) struct PropsGGUF {
     pub rope_dim: usize,
     pub rms_eps: f64,
     pub context_window: usize,
+    pub rope_freq_base: f32,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
@@ -211,6 +212,7 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
             rope_dim: c.get_value::<u32>("rope.dimension_count")? as usize,
             rms_eps: c.get_value::<f32>("attention.layer_norm_rms_epsilon")? as f64,
             context_window: c.get_value::<u32>("context_length")? as usize,
+            rope_freq_base: c.get_value("rope.freq_base").ok().unwrap_or(10_000_f32),
         };
 
         Ok(props)
@@ -238,9 +240,10 @@ impl ModelConfig::FromGGUF for ModelWeights {
             rope_dim,
             rms_eps,
             context_window,
+            rope_freq_base,
         } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
 
-        let (cos, sin) = precomput_freqs_cis(rope_dim, 10_000., device, context_window)?;
+        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device, context_window)?;
 
         let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
         let tok_embeddings = tok_embeddings.dequantize(device)?;
@@ -1,13 +1,16 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor};
-use candle_nn::{linear, linear_no_bias, Activation, RotaryEmbedding, VarBuilder};
+use candle_nn::{linear, linear_no_bias, Activation, VarBuilder};
 use std::sync::Arc;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, MatMul, QLinear, RmsNorm, ScaledDotProductAttention},
-    pipeline::{extract_logits, Cache, IsqModel, NormalLoadingMetadata, NormalModel},
+    layers::{
+        repeat_kv, CausalMasker, MatMul, QLinear, RmsNorm, RopeScaling, ScaledDotProductAttention,
+        ScaledRotaryEmbedding,
+    },
+    pipeline::{extract_logits, Cache, IsqModel, IsqTensor, NormalLoadingMetadata, NormalModel},
     utils::progress::NiceProgressBar,
 };
 
@@ -20,6 +23,7 @@ pub struct Config {
     pub num_attention_heads: usize,
     pub num_key_value_heads: usize,
     pub max_position_embeddings: usize,
+    pub rope_scaling: Option<RopeScaling>,
     pub sliding_window: usize,
     pub max_window_layers: usize,
     pub tie_word_embeddings: bool,
@@ -82,12 +86,12 @@ struct Attention {
     num_kv_heads: usize,
     num_kv_groups: usize,
     head_dim: usize,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: Arc<ScaledRotaryEmbedding>,
     use_flash_attn: bool,
 }
 
 impl Attention {
-    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    fn new(rotary_emb: Arc<ScaledRotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let num_heads = cfg.num_attention_heads;
         let num_kv_heads = cfg.num_key_value_heads;
@@ -208,7 +212,7 @@ struct DecoderLayer {
 
 impl DecoderLayer {
     fn new(
-        rotary_emb: Arc<RotaryEmbedding>,
+        rotary_emb: Arc<ScaledRotaryEmbedding>,
         cfg: &Config,
         vb: VarBuilder,
         mapper: &dyn DeviceMapper,
@@ -298,10 +302,11 @@ impl Model {
         let head_dim = cfg.hidden_size / cfg.num_attention_heads;
         let vb_l = vb_m.pp("layers");
         for layer_idx in NiceProgressBar(0..cfg.num_hidden_layers, "Loading repeating layers") {
-            let rotary_emb = Arc::new(RotaryEmbedding::new(
+            let rotary_emb = Arc::new(ScaledRotaryEmbedding::new(
                 cfg.rope_theta as f32,
                 head_dim,
                 cfg.max_position_embeddings,
+                cfg.rope_scaling.as_ref(),
                 mapper
                     .device_for(layer_idx, false)
                     .unwrap_or(&normal_loading_metadata.real_device),
@@ -336,7 +341,12 @@ impl Model {
             sliding_window: cfg.sliding_window,
             device: normal_loading_metadata.real_device,
             cache: Cache::new(cfg.num_hidden_layers, false),
-            max_seq_len: cfg.max_position_embeddings,
+            max_seq_len: cfg
+                .rope_scaling
+                .as_ref()
+                .map_or(cfg.max_position_embeddings, |scaling| {
+                    scaling.context_len(cfg.max_position_embeddings)
+                }),
             mapper,
         })
     }
@@ -347,6 +357,20 @@ impl Model {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         context_lens: Vec<(usize, usize)>,
+    ) -> Result<Tensor> {
+        let mut xs = self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)?;
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+    }
+
+    /// The hidden states after the final norm, before the LM head.
+    pub fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
     ) -> Result<Tensor> {
         let mut xs = self.embed_tokens.forward(input_ids)?;
         let mut cache = self.cache.lock();
@@ -370,27 +394,50 @@ impl Model {
                 &mut cache[i],
             )?
         }
-        let xs = xs.to_device(&self.device)?;
-        let mut xs = xs.apply(&self.norm)?;
-        if matches!(self.lm_head, QMatMul::QTensor(_)) {
-            xs = xs.to_dtype(DType::F32)?;
-        }
-        extract_logits(&MatMul.qmatmul(&xs, &self.lm_head)?, context_lens)
+        xs.to_device(&self.device)?.apply(&self.norm)
     }
 }
 
 impl IsqModel for Model {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, "lm_head".to_string()));
         for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((layer.self_attn.q_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.k_proj.inner(), Some(i)));
-            tensors.push((layer.self_attn.v_proj.inner(), Some(i)));
-            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
-            tensors.push((&mut layer.mlp.down_proj, Some(i)));
-            tensors.push((&mut layer.mlp.gate_proj, Some(i)));
-            tensors.push((&mut layer.mlp.up_proj, Some(i)));
+            tensors.push((
+                layer.self_attn.q_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.q_proj"),
+            ));
+            tensors.push((
+                layer.self_attn.k_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.k_proj"),
+            ));
+            tensors.push((
+                layer.self_attn.v_proj.inner(),
+                Some(i),
+                format!("model.layers.{i}.self_attn.v_proj"),
+            ));
+            tensors.push((
+                &mut layer.self_attn.o_proj,
+                Some(i),
+                format!("model.layers.{i}.self_attn.o_proj"),
+            ));
+            tensors.push((
+                &mut layer.mlp.down_proj,
+                Some(i),
+                format!("model.layers.{i}.mlp.down_proj"),
+            ));
+            tensors.push((
+                &mut layer.mlp.gate_proj,
+                Some(i),
+                format!("model.layers.{i}.mlp.gate_proj"),
+            ));
+            tensors.push((
+                &mut layer.mlp.up_proj,
+                Some(i),
+                format!("model.layers.{i}.mlp.up_proj"),
+            ));
         }
         (tensors, &*self.mapper)
     }
@@ -412,6 +459,22 @@ impl NormalModel for Model {
             context_lens,
         )
     }
+    fn hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+    ) -> Result<Tensor> {
+        self.hidden_states(input_ids, seqlen_offsets, start_offsets_kernel)
+    }
+    fn lm_head(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let mut xs = hidden_states.clone();
+        if matches!(self.lm_head, QMatMul::QTensor(_)) {
+            xs = xs.to_dtype(DType::F32)?;
+        }
+        MatMul.qmatmul(&xs, &self.lm_head)
+    }
     fn xlora_forward(
         &self,
         _input_ids: &Tensor,
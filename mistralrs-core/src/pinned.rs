@@ -0,0 +1,176 @@
+//! Page-locked (pinned) host memory for the KV caches offloaded from a CUDA device to the CPU.
+//!
+//! Copies between a CUDA device and pageable host memory are staged through a driver buffer and block the host. With
+//! the `pinned-memory` feature, the host buffers caches are evicted into are page-locked with `cuMemHostRegister`, so
+//! the eviction is a single asynchronous DMA copy on the stream of the device, and so is the promotion back to the
+//! device. Page-locked memory cannot be swapped out, so the buffers are unregistered once they are no longer needed.
+//!
+//! The copy to the host is not waited for when it is issued: an event is recorded after it, and the host tensor must
+//! not be read before [`PinnedHostBuffer::wait`] returns.
+
+use candle_core::{Device, Result, Tensor};
+
+/// Keeps a host tensor page-locked until it is dropped. The tensor itself stays valid after that, in pageable memory.
+#[cfg_attr(not(feature = "pinned-memory"), allow(dead_code))]
+pub(crate) struct PinnedHostBuffer {
+    #[cfg(feature = "pinned-memory")]
+    ptr: usize,
+    // The `CUevent` recorded after the copy into the buffer
+    #[cfg(feature = "pinned-memory")]
+    event: usize,
+    // The device tensor being copied, kept alive until the copy is done
+    #[cfg(feature = "pinned-memory")]
+    src: Option<Tensor>,
+    // The memory must be unregistered before it is freed, so it is kept alive until then
+    #[cfg(feature = "pinned-memory")]
+    _buf: Tensor,
+}
+
+impl PinnedHostBuffer {
+    /// Copy `xs` to the CPU. With the `pinned-memory` feature, a tensor on a CUDA device is copied into pinned memory
+    /// asynchronously, and the buffer keeping it pinned is returned alongside: the host tensor must not be read before
+    /// [`Self::wait`] returns.
+    pub fn copy_to_host(xs: &Tensor) -> Result<(Tensor, Option<Self>)> {
+        #[cfg(feature = "pinned-memory")]
+        if xs.device().is_cuda() && xs.elem_count() > 0 {
+            let (host, buf) = cuda::copy_to_pinned(xs)?;
+            return Ok((host, Some(buf)));
+        }
+        Ok((xs.to_device(&Device::Cpu)?, None))
+    }
+
+    /// Block until the copy into the host tensor is done.
+    pub fn wait(&mut self) -> Result<()> {
+        #[cfg(feature = "pinned-memory")]
+        if self.src.is_some() {
+            use candle_core::cuda::{cudarc::driver::sys, WrapErr};
+
+            // SAFETY: `event` was created by `copy_to_pinned` and is only destroyed on drop.
+            unsafe { sys::lib().cuEventSynchronize(self.event as sys::CUevent) }
+                .result()
+                .w()?;
+            self.src = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "pinned-memory")]
+impl Drop for PinnedHostBuffer {
+    fn drop(&mut self) {
+        use candle_core::cuda_backend::cudarc::driver::sys;
+
+        // The memory cannot be unregistered while it is being copied into
+        if let Err(e) = self.wait() {
+            tracing::warn!("Failed to wait for the copy into pinned host memory: {e}");
+        }
+        // SAFETY: the event was created by `copy_to_pinned`, and the memory at `ptr` was registered by it and is kept
+        // alive by `_buf`.
+        unsafe {
+            if let Err(e) = sys::lib()
+                .cuEventDestroy_v2(self.event as sys::CUevent)
+                .result()
+            {
+                tracing::warn!("Failed to destroy the event of pinned host memory: {e}");
+            }
+            if let Err(e) = sys::lib()
+                .cuMemHostUnregister(self.ptr as *mut std::ffi::c_void)
+                .result()
+            {
+                tracing::warn!("Failed to unregister pinned host memory: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(feature = "pinned-memory")]
+mod cuda {
+    use std::ffi::c_void;
+
+    use candle_core::{
+        bail,
+        cuda::{
+            cudarc::driver::{sys, DevicePtr},
+            CudaDType, WrapErr,
+        },
+        DType, Device, Result, Storage, Tensor, WithDType,
+    };
+    use half::{bf16, f16};
+
+    use super::PinnedHostBuffer;
+
+    /// Start copying `xs`, on a CUDA device, to a new host tensor which is page-locked until the returned buffer is
+    /// dropped.
+    pub(super) fn copy_to_pinned(xs: &Tensor) -> Result<(Tensor, PinnedHostBuffer)> {
+        match xs.dtype() {
+            DType::U8 => copy_typed::<u8>(xs),
+            DType::U32 => copy_typed::<u32>(xs),
+            DType::I64 => copy_typed::<i64>(xs),
+            DType::BF16 => copy_typed::<bf16>(xs),
+            DType::F16 => copy_typed::<f16>(xs),
+            DType::F32 => copy_typed::<f32>(xs),
+            DType::F64 => copy_typed::<f64>(xs),
+        }
+    }
+
+    fn copy_typed<T: WithDType + CudaDType>(xs: &Tensor) -> Result<(Tensor, PinnedHostBuffer)> {
+        let Device::Cuda(dev) = xs.device() else {
+            bail!("Expected a tensor on a CUDA device.");
+        };
+        let xs = xs.flatten_all()?.contiguous()?;
+        let n_bytes = xs.elem_count() * xs.dtype().size_in_bytes();
+        let mut host = vec![T::from_f64(0.); xs.elem_count()];
+        // Moving `host` into a tensor below does not move its heap buffer
+        let dst = host.as_mut_ptr().cast::<c_void>();
+        let src = {
+            let (storage, layout) = xs.storage_and_layout();
+            let Storage::Cuda(storage) = &*storage else {
+                bail!("Expected a tensor on a CUDA device.");
+            };
+            *storage.as_cuda_slice::<T>()?.device_ptr()
+                + (layout.start_offset() * xs.dtype().size_in_bytes()) as u64
+        };
+
+        let device = dev.cuda_device();
+        device.bind_to_thread().w()?;
+        // SAFETY: `dst` points to the `n_bytes` bytes of `host`, which was just allocated and is owned here.
+        unsafe { sys::lib().cuMemHostRegister_v2(dst, n_bytes, 0) }
+            .result()
+            .w()?;
+        let mut event = std::ptr::null_mut();
+        // SAFETY: `event` is a valid out pointer.
+        if let Err(e) = unsafe {
+            sys::lib().cuEventCreate(
+                &mut event,
+                sys::CUevent_flags::CU_EVENT_DISABLE_TIMING as u32,
+            )
+        }
+        .result()
+        {
+            // SAFETY: the memory was registered above and nothing copies into it.
+            unsafe { sys::lib().cuMemHostUnregister(dst) };
+            return Err(e).w();
+        }
+        let host = Tensor::from_vec(host, xs.elem_count(), &Device::Cpu)?;
+        // From here on, the event is destroyed and the memory unregistered when `buf` is dropped, even if the copy
+        // fails
+        let buf = PinnedHostBuffer {
+            ptr: dst as usize,
+            event: event as usize,
+            src: Some(xs),
+            _buf: host.clone(),
+        };
+        // SAFETY: `dst` is only written by the driver, and nothing reads `host` until the event recorded after the
+        // copy was waited for. The copy is ordered after the kernels which computed `xs` on the stream of the device,
+        // and `buf` keeps `xs` alive until it is done.
+        unsafe {
+            let stream = *device.cu_stream();
+            sys::lib()
+                .cuMemcpyDtoHAsync_v2(dst, src, n_bytes, stream)
+                .result()
+                .w()?;
+            sys::lib().cuEventRecord(event, stream).result().w()?;
+        }
+        Ok((host, buf))
+    }
+}
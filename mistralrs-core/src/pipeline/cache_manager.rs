@@ -1,23 +1,341 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
 
-use candle_core::{Tensor, D};
+use candle_core::{DType, Device, DeviceLocation, Result as CandleResult, Tensor, D};
+use tracing::debug_span;
 
-use crate::{get_mut_arcmutex, sequence::Sequence};
+use crate::{get_mut_arcmutex, pinned::PinnedHostBuffer, sequence::Sequence};
 
 use super::{CacheManagerMixin, MetadataMixin};
 
+/// Moves KV caches between the sequences of a batch and the model.
+///
+/// The models attend over the contiguous `(k, v)` tensors in [`Cache`], so an implementation must leave the model
+/// cache holding one contiguous entry per layer, batched along dim 0, after `clone_in_cache`. How the caches are stored
+/// per sequence in between steps is up to the implementation.
 pub trait CacheManager<T: CacheManagerMixin + MetadataMixin + ?Sized> {
     fn clone_in_cache(
         &self,
         pipeline: &T,
         seqs: &mut [&mut crate::sequence::Sequence],
         modify_draft_cache: bool,
-    );
-    fn clone_out_cache(&self, pipeline: &T, seqs: &mut [&mut Sequence], modify_draft_cache: bool);
+    ) -> CandleResult<()>;
+    fn clone_out_cache(
+        &self,
+        pipeline: &T,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> CandleResult<()>;
     fn set_none_cache(&self, pipeline: &T, modify_draft_cache: bool);
 }
 
-pub type LayerCaches = Vec<Option<(Tensor, Tensor)>>;
+/// Per-layer KV caches. The model-side cache always holds full precision `(k, v)` pairs, while
+/// sequences store their caches as `LayerCaches<KvBlock>` so they may be kept quantized.
+pub type LayerCaches<B = (Tensor, Tensor)> = Vec<Option<B>>;
+
+/// A K/V cache entry for one layer of one sequence.
+///
+/// Quantized blocks use symmetric absmax quantization over the head dimension, storing one scale
+/// (in the original dtype) per head and position. 4 bit blocks pack two values into each byte.
+#[derive(Debug, Clone)]
+pub enum KvBlock {
+    Full(Tensor, Tensor),
+    Quantized {
+        k: Tensor,
+        v: Tensor,
+        k_scale: Tensor,
+        v_scale: Tensor,
+        bits: u8,
+    },
+}
+
+impl KvBlock {
+    /// Create a block from full precision K/V tensors, quantizing them if `bits` is set.
+    /// Only 8 and 4 bit quantization are supported.
+    pub fn new(k: Tensor, v: Tensor, bits: Option<u8>) -> CandleResult<Self> {
+        match bits {
+            None => Ok(Self::Full(k, v)),
+            Some(bits @ (4 | 8)) => {
+                let (k, k_scale) = quantize(&k, bits)?;
+                let (v, v_scale) = quantize(&v, bits)?;
+                Ok(Self::Quantized {
+                    k,
+                    v,
+                    k_scale,
+                    v_scale,
+                    bits,
+                })
+            }
+            Some(bits) => candle_core::bail!("Unsupported KV cache quantization bits {bits}."),
+        }
+    }
+
+    /// Get the full precision K/V tensors, dequantizing if necessary.
+    pub fn kv(&self) -> CandleResult<(Tensor, Tensor)> {
+        match self {
+            Self::Full(k, v) => Ok((k.clone(), v.clone())),
+            Self::Quantized {
+                k,
+                v,
+                k_scale,
+                v_scale,
+                bits,
+            } => Ok((
+                dequantize(k, k_scale, *bits)?,
+                dequantize(v, v_scale, *bits)?,
+            )),
+        }
+    }
+
+    /// The number of positions in this block (dim 2 of the K/V tensors).
+    pub fn seq_len(&self) -> usize {
+        match self {
+            Self::Full(_, v) | Self::Quantized { v, .. } => v.dims()[2],
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        match self {
+            Self::Full(k, _) | Self::Quantized { k, .. } => k.device(),
+        }
+    }
+
+    fn tensors(&self) -> Vec<&Tensor> {
+        match self {
+            Self::Full(k, v) => vec![k, v],
+            Self::Quantized {
+                k,
+                v,
+                k_scale,
+                v_scale,
+                bits: _,
+            } => vec![k, v, k_scale, v_scale],
+        }
+    }
+
+    /// The number of bytes used by the stored tensors.
+    pub fn n_bytes(&self) -> usize {
+        self.tensors()
+            .iter()
+            .map(|t| t.elem_count() * t.dtype().size_in_bytes())
+            .sum()
+    }
+
+    fn map(&self, mut f: impl FnMut(&Tensor) -> CandleResult<Tensor>) -> CandleResult<Self> {
+        match self {
+            Self::Full(k, v) => Ok(Self::Full(f(k)?, f(v)?)),
+            Self::Quantized {
+                k,
+                v,
+                k_scale,
+                v_scale,
+                bits,
+            } => Ok(Self::Quantized {
+                k: f(k)?,
+                v: f(v)?,
+                k_scale: f(k_scale)?,
+                v_scale: f(v_scale)?,
+                bits: *bits,
+            }),
+        }
+    }
+
+    pub fn to_device(&self, device: &Device) -> CandleResult<Self> {
+        self.map(|t| t.to_device(device))
+    }
+
+    /// Move several blocks to `device` with one copy per dtype, rather than one copy per tensor. The tensors are
+    /// flattened and concatenated where they are, copied, and then split back into views of the copied buffer.
+    ///
+    /// The buffer is only freed once all the views into it are, so this moves the blocks of one cache, which are
+    /// moved and dropped together. A block narrowed out of it (see [`Self::narrow_seq`]) is a copy.
+    pub fn to_device_batched<'a>(
+        blocks: impl IntoIterator<Item = &'a mut KvBlock>,
+        device: &Device,
+    ) -> CandleResult<()> {
+        Self::to_device_batched_with(blocks, device, |buf| buf.to_device(device))
+    }
+
+    /// Like [`Self::to_device_batched`], but copies from a CUDA device to the CPU go into pinned host memory, with the
+    /// `pinned-memory` feature. Those copies are asynchronous, so the blocks must not be read before the returned
+    /// buffers are waited for, and the memory stays pinned until they are dropped.
+    pub(crate) fn to_device_batched_pinned<'a>(
+        blocks: impl IntoIterator<Item = &'a mut KvBlock>,
+        device: &Device,
+    ) -> CandleResult<Vec<PinnedHostBuffer>> {
+        let mut pinned = Vec::new();
+        Self::to_device_batched_with(blocks, device, |buf| {
+            if !device.is_cpu() {
+                return buf.to_device(device);
+            }
+            let (host, buf) = PinnedHostBuffer::copy_to_host(&buf)?;
+            pinned.extend(buf);
+            Ok(host)
+        })?;
+        Ok(pinned)
+    }
+
+    fn to_device_batched_with<'a>(
+        blocks: impl IntoIterator<Item = &'a mut KvBlock>,
+        device: &Device,
+        mut copy: impl FnMut(Tensor) -> CandleResult<Tensor>,
+    ) -> CandleResult<()> {
+        let mut blocks = blocks
+            .into_iter()
+            .filter(|block| !block.device().same_device(device))
+            .collect::<Vec<_>>();
+        let mut groups: Vec<(DType, DeviceLocation, Vec<Tensor>)> = Vec::new();
+        for block in &blocks {
+            for t in block.tensors() {
+                let key = (t.dtype(), t.device().location());
+                match groups
+                    .iter_mut()
+                    .find(|(dtype, loc, _)| (*dtype, *loc) == key)
+                {
+                    Some((_, _, ts)) => ts.push(t.flatten_all()?),
+                    None => groups.push((key.0, key.1, vec![t.flatten_all()?])),
+                }
+            }
+        }
+        let mut moved = groups
+            .into_iter()
+            .map(|(dtype, loc, ts)| Ok((dtype, loc, copy(Tensor::cat(&ts, 0)?)?, 0)))
+            .collect::<CandleResult<Vec<_>>>()?;
+        for block in &mut blocks {
+            **block = block.map(|t| {
+                let key = (t.dtype(), t.device().location());
+                let (_, _, buf, offset) = moved
+                    .iter_mut()
+                    .find(|(dtype, loc, _, _)| (*dtype, *loc) == key)
+                    .expect("Every tensor was grouped.");
+                let n = t.elem_count();
+                let out = buf.narrow(0, *offset, n)?.reshape(t.shape())?;
+                *offset += n;
+                Ok(out)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Narrow along the sequence dimension (dim 2) into new tensors. The quantized layout is unaffected by this as
+    /// scales and packing are along the head dimension.
+    pub fn narrow_seq(&self, start: usize, len: usize) -> CandleResult<Self> {
+        self.map(|t| t.narrow(2, start, len)?.copy())
+    }
+
+    /// The dtype of the full precision K/V tensors.
+    pub fn dtype(&self) -> DType {
+        match self {
+            Self::Full(k, _) => k.dtype(),
+            Self::Quantized { k_scale, .. } => k_scale.dtype(),
+        }
+    }
+
+    /// Insert the tensors of this block into `tensors`, with names starting with `prefix`, for serialization.
+    pub(crate) fn insert_named(
+        &self,
+        prefix: &str,
+        tensors: &mut HashMap<String, Tensor>,
+    ) -> CandleResult<()> {
+        match self {
+            Self::Full(k, v) => {
+                tensors.insert(format!("{prefix}.k"), k.clone());
+                tensors.insert(format!("{prefix}.v"), v.clone());
+            }
+            Self::Quantized {
+                k,
+                v,
+                k_scale,
+                v_scale,
+                bits,
+            } => {
+                tensors.insert(format!("{prefix}.k"), k.clone());
+                tensors.insert(format!("{prefix}.v"), v.clone());
+                tensors.insert(format!("{prefix}.k_scale"), k_scale.clone());
+                tensors.insert(format!("{prefix}.v_scale"), v_scale.clone());
+                tensors.insert(format!("{prefix}.bits"), Tensor::new(&[*bits], k.device())?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the block written by [`Self::insert_named`] with `prefix` out of `tensors`, if there is one.
+    pub(crate) fn remove_named(
+        prefix: &str,
+        tensors: &mut HashMap<String, Tensor>,
+    ) -> CandleResult<Option<Self>> {
+        let (Some(k), Some(v)) = (
+            tensors.remove(&format!("{prefix}.k")),
+            tensors.remove(&format!("{prefix}.v")),
+        ) else {
+            return Ok(None);
+        };
+        match (
+            tensors.remove(&format!("{prefix}.k_scale")),
+            tensors.remove(&format!("{prefix}.v_scale")),
+            tensors.remove(&format!("{prefix}.bits")),
+        ) {
+            (Some(k_scale), Some(v_scale), Some(bits)) => Ok(Some(Self::Quantized {
+                k,
+                v,
+                k_scale,
+                v_scale,
+                bits: bits.to_vec1::<u8>()?[0],
+            })),
+            _ => Ok(Some(Self::Full(k, v))),
+        }
+    }
+}
+
+fn quantize(x: &Tensor, bits: u8) -> CandleResult<(Tensor, Tensor)> {
+    let offset = f64::from(1u8 << (bits - 1));
+    let q_max = offset - 1.;
+    let x_f32 = x.to_dtype(DType::F32)?;
+    let scale = (x_f32.abs()?.max_keepdim(D::Minus1)? / q_max)?.clamp(f32::EPSILON, f32::MAX)?;
+    let q = x_f32
+        .broadcast_div(&scale)?
+        .round()?
+        .affine(1., offset)?
+        .clamp(0f32, 2. * offset - 1.)?;
+    let q = if bits == 4 {
+        let mut dims = q.dims().to_vec();
+        let head_dim = dims.pop().unwrap();
+        if head_dim % 2 != 0 {
+            candle_core::bail!("4 bit KV cache quantization requires an even head dimension.");
+        }
+        dims.extend([head_dim / 2, 2]);
+        let q = q.reshape(dims)?;
+        let hi = q.narrow(D::Minus1, 0, 1)?.squeeze(D::Minus1)?;
+        let lo = q.narrow(D::Minus1, 1, 1)?.squeeze(D::Minus1)?;
+        (hi.affine(16., 0.)? + lo)?
+    } else {
+        q
+    };
+    Ok((q.to_dtype(DType::U8)?, scale.to_dtype(x.dtype())?))
+}
+
+fn dequantize(q: &Tensor, scale: &Tensor, bits: u8) -> CandleResult<Tensor> {
+    let offset = f64::from(1u8 << (bits - 1));
+    let q = q.to_dtype(DType::F32)?;
+    let q = if bits == 4 {
+        let mut dims = q.dims().to_vec();
+        let packed = dims.pop().unwrap();
+        dims.push(packed * 2);
+        let hi = (q.clone() / 16.)?.floor()?;
+        let lo = (q - hi.affine(16., 0.)?)?;
+        Tensor::stack(&[hi, lo], D::Minus1)?.reshape(dims)?
+    } else {
+        q
+    };
+    q.affine(1., -offset)?
+        .broadcast_mul(&scale.to_dtype(DType::F32)?)?
+        .to_dtype(scale.dtype())
+}
 
 #[derive(Debug, Clone)]
 pub struct Cache {
@@ -25,6 +343,10 @@ pub struct Cache {
     xlora_cache: Option<Arc<Mutex<LayerCaches>>>,
     draft_cache: Arc<Mutex<LayerCaches>>,
     scalings_cache: Option<Arc<Mutex<Option<Tensor>>>>,
+    // Number of rows along dim 0 contributed by each sequence at the last `clone_in_cache`.
+    seq_rows: Arc<Mutex<Vec<usize>>>,
+    // Set if the KV caches of the sequences are paged.
+    paged: Arc<Mutex<Option<PagedCacheManager>>>,
 }
 
 impl Cache {
@@ -42,6 +364,8 @@ impl Cache {
             } else {
                 None
             },
+            seq_rows: Arc::new(Mutex::new(Vec::new())),
+            paged: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -53,6 +377,10 @@ impl Cache {
         get_mut_arcmutex!(self.draft_cache)
     }
 
+    pub(crate) fn seq_rows(&self) -> MutexGuard<'_, Vec<usize>> {
+        get_mut_arcmutex!(self.seq_rows)
+    }
+
     /// # Panics
     /// If there is no xlora cache
     pub(crate) fn xlora_lock(&self) -> MutexGuard<'_, LayerCaches> {
@@ -72,6 +400,16 @@ impl Cache {
         self.xlora_cache.is_some()
     }
 
+    /// Page the KV caches of the sequences in blocks of `block_size` positions with a [`PagedCacheManager`], or keep
+    /// them contiguous if `None`.
+    pub(crate) fn set_block_size(&self, block_size: Option<usize>) {
+        *get_mut_arcmutex!(self.paged) = block_size.map(PagedCacheManager::new);
+    }
+
+    pub(crate) fn paged(&self) -> Option<PagedCacheManager> {
+        get_mut_arcmutex!(self.paged).clone()
+    }
+
     /// Update the KV cache and return (k,v)
     pub(crate) fn update_kv_cache(
         cache: &mut Option<(Tensor, Tensor)>,
@@ -98,6 +436,10 @@ impl Cache {
     }
 
     /// Update the KV cache and return (k,v,attn_mask)
+    ///
+    /// With a sliding window, the stored cache is truncated to the last `sliding_window` positions, so the cache
+    /// cloned out to the sequences stays bounded. Rotary embeddings are unaffected as the position offsets are
+    /// derived from the sequence's tokens, not the cache length.
     pub(crate) fn update_kv_cache_sliding_window(
         cache: &mut Option<(Tensor, Tensor)>,
         k: Tensor,
@@ -162,12 +504,15 @@ enum SeqCache {
     Draft,
 }
 
+/// Returns the number of rows along dim 0 contributed by each sequence. Fails if a sequence has no cache to clone in,
+/// for example if a prompt sequence was batched with completion sequences.
 fn clone_in_cache(
     num_hidden_layers: usize,
     cache: &mut LayerCaches,
     seqs: &mut [&mut crate::sequence::Sequence],
     src: SeqCache,
-) {
+) -> CandleResult<Vec<usize>> {
+    let mut seq_rows = Vec::new();
     let mut new_cache = Vec::new();
     for layer in 0..num_hidden_layers {
         let mut k_vec = Vec::new();
@@ -178,27 +523,45 @@ fn clone_in_cache(
                 SeqCache::XLora => seq.xlora_cache(),
                 SeqCache::Draft => seq.draft_cache(),
             };
-            let cache = src_cache.get(layer).unwrap();
-            let cache = cache
-                .as_ref()
-                .expect("Not handling completions in `clone_in_cache`.");
-            k_vec.push(cache.0.clone());
-            v_vec.push(cache.1.clone());
+            let Some(Some(cache)) = src_cache.get(layer) else {
+                candle_core::bail!(
+                    "Sequence {} has no KV cache for layer {layer} to clone in.",
+                    seq.id()
+                );
+            };
+            let (k, v) = cache.kv()?;
+            k_vec.push(k);
+            v_vec.push(v);
+        }
+        if layer == 0 {
+            seq_rows = k_vec.iter().map(|k| k.dims()[0]).collect();
         }
         new_cache.push(Some((
             if k_vec.len() > 1 {
-                Tensor::cat(&k_vec, 0).unwrap()
+                Tensor::cat(&k_vec, 0)?
             } else {
                 k_vec[0].clone()
             },
             if v_vec.len() > 1 {
-                Tensor::cat(&v_vec, 0).unwrap()
+                Tensor::cat(&v_vec, 0)?
             } else {
                 v_vec[0].clone()
             },
         )));
     }
     *cache = new_cache;
+    Ok(seq_rows)
+}
+
+/// Split `x` along dim 0 into consecutive spans of `rows` rows.
+fn split_rows(x: &Tensor, rows: &[usize]) -> CandleResult<Vec<Tensor>> {
+    let mut offset = 0;
+    let mut splits = Vec::with_capacity(rows.len());
+    for n in rows {
+        splits.push(x.narrow(0, offset, *n)?);
+        offset += n;
+    }
+    Ok(splits)
 }
 
 fn clone_out_cache(
@@ -206,15 +569,24 @@ fn clone_out_cache(
     cache: &mut LayerCaches,
     seqs: &mut [&mut crate::sequence::Sequence],
     target: SeqCache,
-) {
+    kv_cache_bits: Option<u8>,
+    seq_rows: &[usize],
+) -> CandleResult<()> {
     for layer in 0..num_hidden_layers {
-        let cache = cache.get(layer).unwrap();
-        let k_cache = cache.as_ref().unwrap().0.clone();
-        let v_cache = cache.as_ref().unwrap().1.clone();
+        let Some(Some((k_cache, v_cache))) = cache.get(layer) else {
+            candle_core::bail!("The model has no KV cache for layer {layer} to clone out.");
+        };
 
-        let k_caches = k_cache.chunk(seqs.len(), 0).unwrap();
+        // Without spans from `clone_in_cache` (after a reset), each sequence contributed the same number of rows.
+        let (k_caches, v_caches) = if seq_rows.len() == seqs.len() {
+            (
+                split_rows(k_cache, seq_rows)?,
+                split_rows(v_cache, seq_rows)?,
+            )
+        } else {
+            (k_cache.chunk(seqs.len(), 0)?, v_cache.chunk(seqs.len(), 0)?)
+        };
         debug_assert_eq!(k_caches.len(), seqs.len());
-        let v_caches = v_cache.chunk(seqs.len(), 0).unwrap();
         debug_assert_eq!(v_caches.len(), seqs.len());
 
         for (seq_i, seq) in seqs.iter_mut().enumerate() {
@@ -224,11 +596,50 @@ fn clone_out_cache(
                 SeqCache::Draft => seq.draft_cache(),
             };
             let seq_cache = &mut output_cache[layer];
-            let k = k_caches.get(seq_i).unwrap().clone();
-            let v = v_caches.get(seq_i).unwrap().clone();
-            *seq_cache = Some((k, v));
+            let k = k_caches[seq_i].clone();
+            let v = v_caches[seq_i].clone();
+            *seq_cache = Some(KvBlock::new(k, v, kv_cache_bits)?);
+        }
+    }
+    Ok(())
+}
+
+/// Batch the sequences' X-LoRA scalings along dim 0. The scalings are only reused if every sequence has them.
+fn clone_in_scalings(seqs: &mut [&mut crate::sequence::Sequence]) -> CandleResult<Option<Tensor>> {
+    let mut scalings = Vec::new();
+    for seq in &mut *seqs {
+        match seq.scaling_cache() {
+            Some(s) => scalings.push(s.clone()),
+            None => return Ok(None),
         }
     }
+    if scalings.len() > 1 {
+        Ok(Some(Tensor::cat(&scalings, 0)?))
+    } else {
+        Ok(scalings.pop())
+    }
+}
+
+fn clone_out_scalings(
+    scalings: &Option<Tensor>,
+    seqs: &mut [&mut crate::sequence::Sequence],
+    seq_rows: &[usize],
+) -> CandleResult<()> {
+    let Some(scalings) = scalings else {
+        for seq in seqs {
+            *seq.scaling_cache() = None;
+        }
+        return Ok(());
+    };
+    let scalings = if seq_rows.len() == seqs.len() {
+        split_rows(scalings, seq_rows)?
+    } else {
+        scalings.chunk(seqs.len(), 0)?
+    };
+    for (seq, s) in seqs.iter_mut().zip(scalings) {
+        *seq.scaling_cache() = Some(s);
+    }
+    Ok(())
 }
 
 impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultCacheManager {
@@ -237,36 +648,40 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
         pipeline: &T,
         seqs: &mut [&mut crate::sequence::Sequence],
         modify_draft_cache: bool,
-    ) {
+    ) -> CandleResult<()> {
+        let _span = debug_span!(
+            "cache_clone_in",
+            seqs = seqs.len(),
+            draft = modify_draft_cache
+        )
+        .entered();
         if modify_draft_cache {
-            clone_in_cache(
+            *pipeline.cache().seq_rows() = clone_in_cache(
                 pipeline.get_metadata().num_hidden_layers,
                 &mut pipeline.cache().lock(),
                 seqs,
                 SeqCache::Draft,
-            );
-            return;
+            )?;
+            return Ok(());
         }
-        clone_in_cache(
+        *pipeline.cache().seq_rows() = clone_in_cache(
             pipeline.get_metadata().num_hidden_layers,
             &mut pipeline.cache().lock(),
             seqs,
             SeqCache::Normal,
-        );
+        )?;
         if pipeline.get_metadata().is_xlora && !pipeline.get_metadata().has_no_kv_cache {
             clone_in_cache(
                 pipeline.get_metadata().num_hidden_layers,
                 &mut pipeline.cache().xlora_lock(),
                 seqs,
                 SeqCache::XLora,
-            );
+            )?;
         }
         if pipeline.get_metadata().is_xlora {
-            pipeline
-                .cache()
-                .get_scalings_cache()
-                .clone_from(seqs[0].scaling_cache());
+            *pipeline.cache().get_scalings_cache() = clone_in_scalings(seqs)?;
         }
+        Ok(())
     }
 
     fn clone_out_cache(
@@ -274,35 +689,49 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
         pipeline: &T,
         seqs: &mut [&mut crate::sequence::Sequence],
         modify_draft_cache: bool,
-    ) {
+    ) -> CandleResult<()> {
+        let _span = debug_span!(
+            "cache_clone_out",
+            seqs = seqs.len(),
+            draft = modify_draft_cache
+        )
+        .entered();
         if modify_draft_cache {
-            clone_out_cache(
+            return clone_out_cache(
                 pipeline.get_metadata().num_hidden_layers,
                 &mut pipeline.cache().lock(),
                 seqs,
                 SeqCache::Draft,
+                pipeline.get_metadata().kv_cache_bits,
+                &pipeline.cache().seq_rows(),
             );
-            return;
         }
         clone_out_cache(
             pipeline.get_metadata().num_hidden_layers,
             &mut pipeline.cache().lock(),
             seqs,
             SeqCache::Normal,
-        );
+            pipeline.get_metadata().kv_cache_bits,
+            &pipeline.cache().seq_rows(),
+        )?;
         if pipeline.get_metadata().is_xlora && !pipeline.get_metadata().has_no_kv_cache {
             clone_out_cache(
                 pipeline.get_metadata().num_hidden_layers,
                 &mut pipeline.cache().xlora_lock(),
                 seqs,
                 SeqCache::XLora,
-            );
+                pipeline.get_metadata().kv_cache_bits,
+                &pipeline.cache().seq_rows(),
+            )?;
         }
         if pipeline.get_metadata().is_xlora {
-            seqs[0]
-                .scaling_cache()
-                .clone_from(&pipeline.cache().get_scalings_cache());
+            clone_out_scalings(
+                &pipeline.cache().get_scalings_cache(),
+                seqs,
+                &pipeline.cache().seq_rows(),
+            )?;
         }
+        Ok(())
     }
 
     fn set_none_cache(&self, pipeline: &T, modify_draft_cache: bool) {
@@ -311,6 +740,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
             new_cache.push(None);
         }
         pipeline.cache().lock().clone_from(&new_cache);
+        pipeline.cache().seq_rows().clear();
         if modify_draft_cache {
             pipeline.cache().draft_lock().clone_from(&new_cache);
         }
@@ -319,3 +749,641 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
         }
     }
 }
+
+/// The cache manager of `pipeline`: its [`PagedCacheManager`] if its KV caches are paged, otherwise the
+/// [`DefaultCacheManager`].
+pub(crate) fn cache_manager<T: CacheManagerMixin + MetadataMixin + ?Sized + 'static>(
+    pipeline: &T,
+) -> Box<dyn CacheManager<T>> {
+    match pipeline.cache().paged() {
+        Some(paged) => Box::new(paged),
+        None => Box::new(DefaultCacheManager),
+    }
+}
+
+/// The K and V blocks of every layer, shared by the sequences of a [`PagedCacheManager`]. The pool of a layer has the
+/// shape `(num_blocks, num_kv_heads, block_size, head_dim)`, and a block id indexes dim 0 of the pools of all layers.
+#[derive(Debug)]
+struct BlockPool {
+    block_size: usize,
+    // Allocated from the first model cache cloned out
+    layers: Vec<(Tensor, Tensor)>,
+    num_blocks: usize,
+    free: Vec<usize>,
+}
+
+impl BlockPool {
+    /// Take `n` free blocks, growing the pool to fit them if there are not enough.
+    fn allocate(&mut self, cache: &LayerCaches, n: usize) -> CandleResult<Vec<usize>> {
+        if self.free.len() < n {
+            let num_blocks = (self.num_blocks + n - self.free.len()).max(2 * self.num_blocks);
+            self.grow(cache, num_blocks)?;
+        }
+        Ok(self.free.split_off(self.free.len() - n))
+    }
+
+    /// Reallocate the pools with `num_blocks` blocks, keeping the blocks in use, with the shapes of the model `cache`.
+    fn grow(&mut self, cache: &LayerCaches, num_blocks: usize) -> CandleResult<()> {
+        let mut layers = Vec::with_capacity(cache.len());
+        for (layer, entry) in cache.iter().enumerate() {
+            let Some((k, v)) = entry else {
+                candle_core::bail!("Layer {layer} has no KV cache to page.");
+            };
+            let mut pools = [k, v].into_iter().map(|x| {
+                let (_, num_kv_heads, _, head_dim) = x.dims4()?;
+                Tensor::zeros(
+                    (num_blocks, num_kv_heads, self.block_size, head_dim),
+                    x.dtype(),
+                    x.device(),
+                )
+            });
+            let (new_k, new_v) = (pools.next().unwrap()?, pools.next().unwrap()?);
+            if let Some((old_k, old_v)) = self.layers.get(layer) {
+                new_k.slice_set(old_k, 0, 0)?;
+                new_v.slice_set(old_v, 0, 0)?;
+            }
+            layers.push((new_k, new_v));
+        }
+        self.layers = layers;
+        // Lower ids are taken first
+        self.free.extend((self.num_blocks..num_blocks).rev());
+        self.num_blocks = num_blocks;
+        Ok(())
+    }
+
+    /// Gather the KV caches of `tables` into one `(k, v)` entry per layer batched along dim 0. The tables have the
+    /// same length, as the scheduler batches sequences by their [`crate::sequence::Sequence::len`], which is the
+    /// length of their table.
+    fn gather(&self, tables: &[&BlockTable]) -> CandleResult<LayerCaches> {
+        let len = tables.first().map_or(0, |table| table.len);
+        debug_assert!(tables.iter().all(|table| table.len == len));
+        let rows = tables.iter().map(|table| table.blocks.len()).sum::<usize>();
+        let num_blocks = len.div_ceil(self.block_size);
+        let ids = tables
+            .iter()
+            .flat_map(|table| table.blocks.iter().flatten())
+            .map(|id| u32::try_from(*id).map_err(candle_core::Error::wrap))
+            .collect::<CandleResult<Vec<_>>>()?;
+        let ids = Tensor::new(ids.as_slice(), self.layers[0].0.device())?;
+        let gather = |pool: &Tensor| {
+            let (_, num_kv_heads, block_size, head_dim) = pool.dims4()?;
+            pool.index_select(&ids, 0)?
+                .reshape((rows, num_blocks, num_kv_heads, block_size, head_dim))?
+                .transpose(1, 2)?
+                .reshape((rows, num_kv_heads, num_blocks * block_size, head_dim))?
+                .narrow(2, 0, len)?
+                .contiguous()
+        };
+        self.layers
+            .iter()
+            .map(|(k, v)| Ok(Some((gather(k)?, gather(v)?))))
+            .collect()
+    }
+
+    /// Write the batched model `cache` to the blocks of `tables`, whose sequences contributed `seq_rows` rows each,
+    /// allocating blocks as the caches grow. Only the positions past the cached length of a table are written, unless
+    /// `rewrite` is set or the cache did not grow, as when the cache was reset or a sliding window moved.
+    fn scatter(
+        &mut self,
+        cache: &LayerCaches,
+        tables: &mut [&mut BlockTable],
+        seq_rows: &[usize],
+        rewrite: bool,
+    ) -> CandleResult<()> {
+        let Some(Some((k, _))) = cache.first() else {
+            candle_core::bail!("There is no KV cache to page.");
+        };
+        let len = k.dim(2)?;
+        let num_blocks = len.div_ceil(self.block_size);
+        let mut starts = Vec::with_capacity(tables.len());
+        for (table, rows) in tables.iter_mut().zip(seq_rows) {
+            let start = if rewrite || len <= table.len {
+                0
+            } else {
+                table.len
+            };
+            starts.push(start);
+            if table.blocks.len() > *rows {
+                self.free.extend(table.blocks.drain(*rows..).flatten());
+            }
+            table.blocks.resize_with(*rows, Vec::new);
+            for blocks in &mut table.blocks {
+                if blocks.len() < num_blocks {
+                    blocks.extend(self.allocate(cache, num_blocks - blocks.len())?);
+                } else {
+                    self.free.extend(blocks.drain(num_blocks..));
+                }
+            }
+            table.len = len;
+        }
+        for (entry, (k_pool, v_pool)) in cache.iter().zip(&self.layers) {
+            let Some((k, v)) = entry else {
+                candle_core::bail!("There is no KV cache to page.");
+            };
+            let mut offset = 0;
+            for (table, start) in tables.iter().zip(&starts) {
+                for (row, blocks) in table.blocks.iter().enumerate() {
+                    for (i, block) in blocks.iter().enumerate().skip(start / self.block_size) {
+                        let pos = i * self.block_size;
+                        let n = self.block_size.min(len - pos);
+                        for (pool, x) in [(k_pool, k), (v_pool, v)] {
+                            let src = x.narrow(0, offset + row, 1)?.narrow(2, pos, n)?;
+                            pool.narrow(0, *block, 1)?
+                                .slice_set(&src.contiguous()?, 2, 0)?;
+                        }
+                    }
+                }
+                offset += table.blocks.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The blocks holding the KV cache of a sequence in the pool of a [`PagedCacheManager`]. They return to the pool when
+/// the table is dropped with its sequence.
+#[derive(Debug)]
+pub struct BlockTable {
+    pool: Arc<Mutex<BlockPool>>,
+    // The blocks of each row the sequence contributes along dim 0, in the order of the positions
+    blocks: Vec<Vec<usize>>,
+    // The number of cached positions
+    len: usize,
+}
+
+impl BlockTable {
+    fn new(pool: Arc<Mutex<BlockPool>>) -> Self {
+        Self {
+            pool,
+            blocks: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// The number of cached positions.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// The id, number of rows along dim 0 and cached length of a sequence in the model cache.
+type ResidentSeq = (usize, usize, usize);
+
+/// The rows of the model cache holding the KV caches of `batch`, if every sequence of it is `resident` with the same
+/// number of rows and length.
+fn resident_rows(resident: &[ResidentSeq], batch: &[ResidentSeq]) -> Option<Vec<usize>> {
+    let mut offset = 0;
+    let mut offsets = HashMap::new();
+    for seq in resident {
+        offsets.insert(seq.0, (offset, seq));
+        offset += seq.1;
+    }
+    let mut rows = Vec::new();
+    for seq in batch {
+        let (offset, resident) = offsets.get(&seq.0)?;
+        if *resident != seq {
+            return None;
+        }
+        rows.extend(*offset..offset + seq.1);
+    }
+    Some(rows)
+}
+
+impl Drop for BlockTable {
+    fn drop(&mut self) {
+        get_mut_arcmutex!(self.pool)
+            .free
+            .extend(self.blocks.drain(..).flatten());
+    }
+}
+
+/// Keeps the KV caches of the sequences in a pool of fixed size blocks, rather than in contiguous tensors per
+/// sequence, so that the memory of finished sequences is reused by the others without fragmentation. Each sequence has
+/// a [`BlockTable`] of its blocks: `clone_in_cache` gathers them into the model cache, and `clone_out_cache` only
+/// writes the blocks of the new positions back. The pool grows when it runs out of free blocks.
+///
+/// The sequences left in the model cache by the last step are tracked, so that when the batch changes, the caches of
+/// those still in it are taken from the model cache rather than gathered again: without a copy if they are contiguous
+/// in it, as when the sequences after them finished, and with one otherwise.
+///
+/// The X-LoRA and draft caches are kept per sequence, like the [`DefaultCacheManager`] does. As the sequences hold no
+/// KV cache of their own, they cannot be shared with the prefix cacher, forked, or swapped between beams.
+#[derive(Debug, Clone)]
+pub struct PagedCacheManager {
+    pool: Arc<Mutex<BlockPool>>,
+    // Whether the model cache was reset since the last clone in, so every position is written out
+    rewrite: Arc<AtomicBool>,
+    // The sequences in the model cache since the last clone out, in the order of their rows
+    resident: Arc<Mutex<Vec<ResidentSeq>>>,
+}
+
+impl PagedCacheManager {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            pool: Arc::new(Mutex::new(BlockPool {
+                block_size,
+                layers: Vec::new(),
+                num_blocks: 0,
+                free: Vec::new(),
+            })),
+            rewrite: Arc::new(AtomicBool::new(true)),
+            resident: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for PagedCacheManager {
+    fn clone_in_cache(
+        &self,
+        pipeline: &T,
+        seqs: &mut [&mut crate::sequence::Sequence],
+        modify_draft_cache: bool,
+    ) -> CandleResult<()> {
+        let _span = debug_span!(
+            "paged_cache_clone_in",
+            seqs = seqs.len(),
+            draft = modify_draft_cache
+        )
+        .entered();
+        if modify_draft_cache {
+            get_mut_arcmutex!(self.resident).clear();
+            return DefaultCacheManager.clone_in_cache(pipeline, seqs, true);
+        }
+        {
+            let mut batch = Vec::with_capacity(seqs.len());
+            let mut tables = Vec::with_capacity(seqs.len());
+            for seq in seqs.iter_mut() {
+                let id = *seq.id();
+                match seq.block_table() {
+                    Some(table) => {
+                        batch.push((id, table.blocks.len(), table.len));
+                        tables.push(&*table);
+                    }
+                    None => candle_core::bail!("Sequence {id} has no KV cache to clone in."),
+                }
+            }
+            let mut resident = get_mut_arcmutex!(self.resident);
+            let mut cache = pipeline.cache().lock();
+            match resident_rows(&resident, &batch) {
+                Some(rows) if rows.windows(2).all(|w| w[1] == w[0] + 1) => {
+                    for (k, v) in cache.iter_mut().flatten() {
+                        *k = k.narrow(0, rows[0], rows.len())?;
+                        *v = v.narrow(0, rows[0], rows.len())?;
+                    }
+                }
+                Some(rows) => {
+                    let rows = rows
+                        .into_iter()
+                        .map(|row| u32::try_from(row).map_err(candle_core::Error::wrap))
+                        .collect::<CandleResult<Vec<_>>>()?;
+                    for (k, v) in cache.iter_mut().flatten() {
+                        let rows = Tensor::new(rows.as_slice(), k.device())?;
+                        *k = k.index_select(&rows, 0)?;
+                        *v = v.index_select(&rows, 0)?;
+                    }
+                }
+                None => *cache = get_mut_arcmutex!(self.pool).gather(&tables)?,
+            }
+            *pipeline.cache().seq_rows() = batch.iter().map(|seq| seq.1).collect();
+            *resident = batch;
+        }
+        self.rewrite.store(false, Ordering::Relaxed);
+        if pipeline.get_metadata().is_xlora && !pipeline.get_metadata().has_no_kv_cache {
+            clone_in_cache(
+                pipeline.get_metadata().num_hidden_layers,
+                &mut pipeline.cache().xlora_lock(),
+                seqs,
+                SeqCache::XLora,
+            )?;
+        }
+        if pipeline.get_metadata().is_xlora {
+            *pipeline.cache().get_scalings_cache() = clone_in_scalings(seqs)?;
+        }
+        Ok(())
+    }
+
+    fn clone_out_cache(
+        &self,
+        pipeline: &T,
+        seqs: &mut [&mut crate::sequence::Sequence],
+        modify_draft_cache: bool,
+    ) -> CandleResult<()> {
+        let _span = debug_span!(
+            "paged_cache_clone_out",
+            seqs = seqs.len(),
+            draft = modify_draft_cache
+        )
+        .entered();
+        if modify_draft_cache {
+            return DefaultCacheManager.clone_out_cache(pipeline, seqs, true);
+        }
+        {
+            let cache = pipeline.cache().lock();
+            // Without spans from `clone_in_cache` (after a reset), each sequence contributed the same number of rows.
+            let mut seq_rows = pipeline.cache().seq_rows().clone();
+            if seq_rows.len() != seqs.len() {
+                let Some(Some((k, _))) = cache.first() else {
+                    candle_core::bail!("The model has no KV cache to clone out.");
+                };
+                seq_rows = vec![k.dim(0)? / seqs.len(); seqs.len()];
+            }
+            let ids = seqs.iter().map(|seq| *seq.id()).collect::<Vec<_>>();
+            // Sampling sequences which modify the cache replaces the model cache after this
+            let modifies_cache = seqs.iter().any(|seq| seq.modifies_cache());
+            let mut tables = seqs
+                .iter_mut()
+                .map(|seq| {
+                    seq.block_table()
+                        .get_or_insert_with(|| BlockTable::new(self.pool.clone()))
+                })
+                .collect::<Vec<_>>();
+            let rewrite = self.rewrite.swap(false, Ordering::Relaxed);
+            get_mut_arcmutex!(self.pool).scatter(&cache, &mut tables, &seq_rows, rewrite)?;
+            let mut resident = get_mut_arcmutex!(self.resident);
+            resident.clear();
+            if !modifies_cache {
+                resident.extend(
+                    ids.into_iter()
+                        .zip(&tables)
+                        .map(|(id, table)| (id, table.blocks.len(), table.len)),
+                );
+            }
+        }
+        if pipeline.get_metadata().is_xlora && !pipeline.get_metadata().has_no_kv_cache {
+            clone_out_cache(
+                pipeline.get_metadata().num_hidden_layers,
+                &mut pipeline.cache().xlora_lock(),
+                seqs,
+                SeqCache::XLora,
+                pipeline.get_metadata().kv_cache_bits,
+                &pipeline.cache().seq_rows(),
+            )?;
+        }
+        if pipeline.get_metadata().is_xlora {
+            clone_out_scalings(
+                &pipeline.cache().get_scalings_cache(),
+                seqs,
+                &pipeline.cache().seq_rows(),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn set_none_cache(&self, pipeline: &T, modify_draft_cache: bool) {
+        DefaultCacheManager.set_none_cache(pipeline, modify_draft_cache);
+        get_mut_arcmutex!(self.resident).clear();
+        self.rewrite.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn split_rows_variable_length() {
+        use candle_core::{Device, Tensor};
+
+        use super::split_rows;
+
+        let x = Tensor::arange(0u32, 10, &Device::Cpu)
+            .unwrap()
+            .reshape((10, 1))
+            .unwrap();
+        let splits = split_rows(&x, &[3, 7]).unwrap();
+        assert_eq!(splits.len(), 2);
+        assert_eq!(splits[0].dims(), &[3, 1]);
+        assert_eq!(splits[1].dims(), &[7, 1]);
+        assert_eq!(
+            splits[0].flatten_all().unwrap().to_vec1::<u32>().unwrap(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            splits[1].flatten_all().unwrap().to_vec1::<u32>().unwrap(),
+            (3..10).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn kv_block_quantization_roundtrip() {
+        use candle_core::{Device, Tensor};
+
+        use super::KvBlock;
+
+        let k = Tensor::randn(0f32, 1., (1, 2, 3, 8), &Device::Cpu).unwrap();
+        let v = Tensor::randn(0f32, 1., (1, 2, 3, 8), &Device::Cpu).unwrap();
+        for (bits, tol) in [(8, 0.02), (4, 0.15)] {
+            let block = KvBlock::new(k.clone(), v.clone(), Some(bits)).unwrap();
+            assert_eq!(block.seq_len(), 3);
+            let (k_deq, v_deq) = block.kv().unwrap();
+            for (x, x_deq) in [(&k, k_deq), (&v, v_deq)] {
+                assert_eq!(x.dims(), x_deq.dims());
+                let max = x
+                    .abs()
+                    .unwrap()
+                    .max_all()
+                    .unwrap()
+                    .to_scalar::<f32>()
+                    .unwrap();
+                let err = (x - x_deq)
+                    .unwrap()
+                    .abs()
+                    .unwrap()
+                    .max_all()
+                    .unwrap()
+                    .to_scalar::<f32>()
+                    .unwrap();
+                assert!(
+                    err <= tol * max,
+                    "{bits} bit error {err} exceeds {tol} * {max}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn kv_block_quantization_memory() {
+        use candle_core::{DType, Device, Tensor};
+
+        use super::KvBlock;
+
+        // 8 KV heads, 16 positions and a head dimension of 128, in F16
+        let k = Tensor::zeros((1, 8, 16, 128), DType::F16, &Device::Cpu).unwrap();
+        let full = KvBlock::new(k.clone(), k.clone(), None).unwrap();
+        assert_eq!(full.n_bytes(), 2 * 8 * 16 * 128 * 2);
+        // One byte per value (half a byte for 4 bits) and one F16 scale per head and position
+        let q8 = KvBlock::new(k.clone(), k.clone(), Some(8)).unwrap();
+        assert_eq!(q8.n_bytes(), 2 * 8 * 16 * (128 + 2));
+        let q4 = KvBlock::new(k.clone(), k, Some(4)).unwrap();
+        assert_eq!(q4.n_bytes(), 2 * 8 * 16 * (64 + 2));
+    }
+
+    #[test]
+    fn paged_blocks_round_trip() {
+        use candle_core::{Device, Tensor};
+
+        use super::{BlockTable, LayerCaches, PagedCacheManager};
+
+        fn assert_same(a: &LayerCaches, b: &LayerCaches) {
+            for (a, b) in a.iter().zip(b) {
+                let ((a_k, a_v), (b_k, b_v)) = (a.as_ref().unwrap(), b.as_ref().unwrap());
+                for (x, y) in [(a_k, b_k), (a_v, b_v)] {
+                    assert_eq!(x.dims(), y.dims());
+                    assert_eq!(
+                        x.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                        y.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+                    );
+                }
+            }
+        }
+
+        // 2 layers of 3 rows, the first from one sequence and the others from another, with 2 KV heads and a head
+        // dimension of 3
+        let positions = |len| {
+            (0..2)
+                .map(|_| {
+                    let k = Tensor::randn(0f32, 1., (3, 2, len, 3), &Device::Cpu).unwrap();
+                    let v = Tensor::randn(0f32, 1., (3, 2, len, 3), &Device::Cpu).unwrap();
+                    Some((k, v))
+                })
+                .collect::<LayerCaches>()
+        };
+        let manager = PagedCacheManager::new(4);
+        let mut a = BlockTable::new(manager.pool.clone());
+        let mut b = BlockTable::new(manager.pool.clone());
+
+        let prompt = positions(6);
+        let mut pool = manager.pool.lock().unwrap();
+        pool.scatter(&prompt, &mut [&mut a, &mut b], &[1, 2], true)
+            .unwrap();
+        assert_same(&pool.gather(&[&a, &b]).unwrap(), &prompt);
+        assert_eq!(pool.num_blocks - pool.free.len(), 3 * 2);
+
+        // Only the new positions are written, into the last block and a new one
+        let grown = prompt
+            .iter()
+            .zip(positions(3))
+            .map(|(old, new)| {
+                let ((old_k, old_v), (new_k, new_v)) = (old.as_ref().unwrap(), new.unwrap());
+                Some((
+                    Tensor::cat(&[old_k, &new_k], 2).unwrap(),
+                    Tensor::cat(&[old_v, &new_v], 2).unwrap(),
+                ))
+            })
+            .collect::<LayerCaches>();
+        pool.scatter(&grown, &mut [&mut a, &mut b], &[1, 2], false)
+            .unwrap();
+        assert_same(&pool.gather(&[&a, &b]).unwrap(), &grown);
+        assert_eq!(pool.num_blocks - pool.free.len(), 3 * 3);
+        let num_blocks = pool.num_blocks;
+        drop(pool);
+
+        // The blocks of a dropped table are reused
+        drop(b);
+        assert_eq!(manager.pool.lock().unwrap().free.len(), num_blocks - 3);
+        let mut c = BlockTable::new(manager.pool.clone());
+        let mut pool = manager.pool.lock().unwrap();
+        pool.scatter(&grown, &mut [&mut a, &mut c], &[1, 2], true)
+            .unwrap();
+        assert_same(&pool.gather(&[&a, &c]).unwrap(), &grown);
+        assert_eq!(pool.num_blocks, num_blocks);
+    }
+
+    #[test]
+    fn paged_resident_rows() {
+        use super::resident_rows;
+
+        // Sequences 0, 1 and 2 with 1, 2 and 1 rows in the model cache
+        let resident = [(0, 1, 5), (1, 2, 5), (2, 1, 5)];
+        assert_eq!(
+            resident_rows(&resident, &[(1, 2, 5), (2, 1, 5)]),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(
+            resident_rows(&resident, &[(2, 1, 5), (0, 1, 5)]),
+            Some(vec![3, 0])
+        );
+        // A new sequence, or one whose cache changed since, is gathered from the pool
+        assert_eq!(resident_rows(&resident, &[(0, 1, 5), (3, 1, 5)]), None);
+        assert_eq!(resident_rows(&resident, &[(0, 1, 6)]), None);
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn kv_blocks_to_device_batched() {
+        use candle_core::{Device, Tensor};
+
+        use super::KvBlock;
+
+        let device = Device::new_cuda(0).unwrap();
+        let k = Tensor::randn(0f32, 1., (1, 2, 3, 8), &Device::Cpu).unwrap();
+        let v = Tensor::randn(0f32, 1., (1, 2, 3, 8), &Device::Cpu).unwrap();
+        let mut blocks = vec![
+            KvBlock::new(k.clone(), v.clone(), None).unwrap(),
+            KvBlock::new(k.clone(), v.clone(), Some(8)).unwrap(),
+            KvBlock::new(v.clone(), k.clone(), None).unwrap(),
+        ];
+        let expected = blocks.iter().map(|b| b.kv().unwrap()).collect::<Vec<_>>();
+        KvBlock::to_device_batched(blocks.iter_mut(), &device).unwrap();
+        KvBlock::to_device_batched(blocks.iter_mut(), &Device::Cpu).unwrap();
+        for (block, (k, v)) in blocks.iter().zip(expected) {
+            assert!(block.device().is_cpu());
+            let (k_moved, v_moved) = block.kv().unwrap();
+            assert_eq!(
+                k.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                k_moved.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+            );
+            assert_eq!(
+                v.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                v_moved.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+            );
+        }
+    }
+
+    /// Compare moving the blocks of a cache off and back onto the device in one batch to moving them one tensor at a
+    /// time. Run with `cargo test --release --features cuda -- --ignored --nocapture kv_blocks_to_device_batched`.
+    #[cfg(feature = "cuda")]
+    #[test]
+    #[ignore]
+    fn kv_blocks_to_device_batched_is_faster() {
+        use std::time::{Duration, Instant};
+
+        use candle_core::{Device, Tensor};
+
+        use super::KvBlock;
+
+        let device = Device::new_cuda(0).unwrap();
+        // 32 layers of 256 positions, with 8 KV heads and a head dimension of 128
+        let blocks = (0..32)
+            .map(|_| {
+                let k = Tensor::randn(0f32, 1., (1, 8, 256, 128), &device).unwrap();
+                let v = Tensor::randn(0f32, 1., (1, 8, 256, 128), &device).unwrap();
+                KvBlock::new(k, v, None).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let time = |round_trip: &dyn Fn(&mut [KvBlock])| {
+            // The first round trip warms up the allocators
+            round_trip(&mut blocks.clone());
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..10 {
+                let mut moved = blocks.clone();
+                let start = Instant::now();
+                round_trip(&mut moved);
+                elapsed += start.elapsed();
+            }
+            elapsed / 10
+        };
+        let per_tensor = time(&|blocks| {
+            for target in [&Device::Cpu, &device] {
+                for block in blocks.iter_mut() {
+                    *block = block.to_device(target).unwrap();
+                }
+            }
+        });
+        let batched = time(&|blocks| {
+            for target in [&Device::Cpu, &device] {
+                KvBlock::to_device_batched(blocks.iter_mut(), target).unwrap();
+            }
+        });
+        println!("Round trip of 32 layers: {per_tensor:?} per tensor, {batched:?} batched.");
+        assert!(batched < per_tensor);
+    }
+}
@@ -0,0 +1,160 @@
+use candle_core::{bail, DType, Result, Tensor, D};
+
+use crate::{sampler::Logprobs, sequence::Sequence};
+
+use super::NormalModel;
+
+/// The score of each candidate token: `(1 - penalty_alpha) * p - penalty_alpha * s`, where `p` is its probability and
+/// `s` is the highest cosine similarity between its hidden state (`[k, hidden_size]`) and those of the context
+/// (`[seq_len, hidden_size]`).
+pub(crate) fn contrastive_scores(
+    probs: &[f32],
+    candidates: &Tensor,
+    context: &Tensor,
+    penalty_alpha: f32,
+) -> Result<Vec<f32>> {
+    let normalize = |xs: &Tensor| -> Result<Tensor> {
+        let xs = xs.to_dtype(DType::F32)?;
+        let norm = xs
+            .sqr()?
+            .sum_keepdim(D::Minus1)?
+            .sqrt()?
+            .clamp(f32::EPSILON, f32::MAX)?;
+        xs.broadcast_div(&norm)
+    };
+    let similarity = normalize(candidates)?
+        .matmul(&normalize(context)?.t()?)?
+        .max(D::Minus1)?
+        .to_vec1::<f32>()?;
+    Ok(probs
+        .iter()
+        .zip(similarity)
+        .map(|(p, s)| (1. - penalty_alpha) * p - penalty_alpha * s)
+        .collect())
+}
+
+/// Choose the next token of every sequence using contrastive search, from the logits of the batch (`[bs, 1, vocab]`).
+/// Returns the sample of each such sequence, and `None` for the others.
+///
+/// The candidates of a sequence are run through the model from its KV cache to get their hidden states, which
+/// replaces the KV cache of the model. The hidden states of the prompt are computed at the first step, by running
+/// the prompt again.
+pub(crate) fn sample_contrastive(
+    model: &dyn NormalModel,
+    seqs: &mut [&mut Sequence],
+    logits: &Tensor,
+) -> Result<Vec<Option<Logprobs>>> {
+    let mut samples = vec![None; seqs.len()];
+    for (i, seq) in seqs.iter_mut().enumerate() {
+        let Some(params) = seq.contrastive() else {
+            continue;
+        };
+        let device = model.device();
+        let toks = seq.all_toks().to_vec();
+
+        if seq.context_hidden_states().is_none() {
+            for layer in model.cache().lock().iter_mut() {
+                *layer = None;
+            }
+            #[allow(clippy::cast_possible_wrap)]
+            let positions = (0..toks.len() as i64).collect::<Vec<_>>();
+            let hidden_states = model.hidden_states(
+                &Tensor::new(toks.as_slice(), device)?.unsqueeze(0)?,
+                &[0],
+                Tensor::new(positions, device)?.unsqueeze(0)?,
+                vec![toks.len()],
+            )?;
+            *seq.context_hidden_states() = Some(hidden_states.squeeze(0)?.to_dtype(DType::F32)?);
+        }
+
+        let seq_logits = logits.get(i)?.flatten_all()?.to_dtype(DType::F32)?;
+        let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&seq_logits)?.to_vec1()?;
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+        let candidates = argsort_indices
+            .into_iter()
+            .take(params.top_k)
+            .map(|tok| tok as u32)
+            .collect::<Vec<_>>();
+        let n_candidates = candidates.len();
+
+        // Every candidate continues from the KV cache of the sequence
+        let id = *seq.id();
+        let mut cache = Vec::new();
+        for block in seq.cache().iter() {
+            let Some(block) = block else {
+                bail!("Sequence {id} has no KV cache for contrastive search.");
+            };
+            let (k, v) = block.kv()?;
+            cache.push(Some((
+                Tensor::cat(&vec![k; n_candidates], 0)?,
+                Tensor::cat(&vec![v; n_candidates], 0)?,
+            )));
+        }
+        *model.cache().lock() = cache;
+        let pos = toks.len();
+        #[allow(clippy::cast_possible_wrap)]
+        let hidden_states = model
+            .hidden_states(
+                &Tensor::new(candidates.as_slice(), device)?.unsqueeze(1)?,
+                &vec![pos; n_candidates],
+                Tensor::new(vec![pos as i64; n_candidates], device)?.unsqueeze(1)?,
+                vec![pos + 1; n_candidates],
+            )?
+            .squeeze(1)?
+            .to_dtype(DType::F32)?;
+
+        let context = seq
+            .context_hidden_states()
+            .clone()
+            .expect("The context hidden states were computed.");
+        let candidate_probs = candidates
+            .iter()
+            .map(|tok| probs[*tok as usize])
+            .collect::<Vec<_>>();
+        let scores = contrastive_scores(
+            &candidate_probs,
+            &hidden_states,
+            &context,
+            params.penalty_alpha,
+        )?;
+        let best = scores
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).expect("No ordering."))
+            .map(|(idx, _)| idx)
+            .expect("At least one candidate.");
+
+        *seq.context_hidden_states() = Some(Tensor::cat(
+            &[context, hidden_states.narrow(0, best, 1)?],
+            0,
+        )?);
+        samples[i] = Some(seq.sampler().score_token(&seq_logits, candidates[best])?);
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::contrastive_scores;
+
+    #[test]
+    fn contrastive_scores_penalize_similar_candidates() {
+        let dev = Device::Cpu;
+        let context = Tensor::new(&[[1f32, 0.], [0.6, 0.8]], &dev).unwrap();
+        // The first candidate repeats the context, the second points away from it
+        let candidates = Tensor::new(&[[2f32, 0.], [-1., 0.]], &dev).unwrap();
+
+        let scores = contrastive_scores(&[0.6, 0.4], &candidates, &context, 0.5).unwrap();
+        assert!((scores[0] - (0.5 * 0.6 - 0.5 * 1.)).abs() < 1e-6);
+        assert!((scores[1] - (0.5 * 0.4 + 0.5 * 0.6)).abs() < 1e-6);
+        assert!(scores[1] > scores[0]);
+
+        // Without a penalty, only the probabilities count
+        let scores = contrastive_scores(&[0.6, 0.4], &candidates, &context, 0.).unwrap();
+        assert_eq!(scores, vec![0.6, 0.4]);
+    }
+}
@@ -0,0 +1,176 @@
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// A tree of draft tokens proposed after the last token of a sequence, the root, so that several candidate
+/// continuations are verified by a single forward pass of the target model with tree attention: each token attends to
+/// the cached tokens, the root and its own ancestors, at the position of its depth.
+///
+/// The nodes are stored with their parents before them. A chain of draft tokens is a tree in which every node is the
+/// child of the previous one.
+pub struct DraftTree {
+    tokens: Vec<u32>,
+    // The parent of each node, `None` for the children of the root
+    parents: Vec<Option<usize>>,
+}
+
+impl DraftTree {
+    /// Merge candidate continuations into a tree, sharing their common prefixes.
+    pub fn from_paths<P: AsRef<[u32]>>(paths: &[P]) -> Self {
+        let mut tree = Self::default();
+        for path in paths {
+            let mut parent = None;
+            for &tok in path.as_ref() {
+                let node = match tree.child_with_token(parent, tok) {
+                    Some(node) => node,
+                    None => {
+                        tree.tokens.push(tok);
+                        tree.parents.push(parent);
+                        tree.tokens.len() - 1
+                    }
+                };
+                parent = Some(node);
+            }
+        }
+        tree
+    }
+
+    /// Number of draft tokens, without the root.
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// The draft tokens, in node order.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// The nodes whose parent is `parent`, or the root if `None`.
+    pub fn children(&self, parent: Option<usize>) -> impl Iterator<Item = usize> + '_ {
+        self.parents
+            .iter()
+            .enumerate()
+            .filter(move |(_, p)| **p == parent)
+            .map(|(node, _)| node)
+    }
+
+    /// Number of tokens from the root to `node`, so 1 for the children of the root.
+    pub fn node_depth(&self, node: usize) -> usize {
+        let mut depth = 1;
+        let mut node = node;
+        while let Some(parent) = self.parents[node] {
+            depth += 1;
+            node = parent;
+        }
+        depth
+    }
+
+    /// Number of tokens of the longest path from the root, which is how many draft tokens may be accepted.
+    pub fn depth(&self) -> usize {
+        (0..self.len())
+            .map(|node| self.node_depth(node))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Offset from the position of the root of each input position of the tree: the root, then the nodes in order.
+    pub fn position_offsets(&self) -> Vec<usize> {
+        std::iter::once(0)
+            .chain((0..self.len()).map(|node| self.node_depth(node)))
+            .collect()
+    }
+
+    /// Whether input position `query` attends to input position `key`, where position 0 is the root and position
+    /// `i + 1` is node `i`. A position attends to itself and to its ancestors, of which the root is one.
+    pub fn is_visible(&self, query: usize, key: usize) -> bool {
+        if key == 0 || key == query {
+            return true;
+        }
+        let mut node = query.checked_sub(1);
+        while let Some(current) = node {
+            if current + 1 == key {
+                return true;
+            }
+            node = self.parents[current];
+        }
+        false
+    }
+
+    /// The child of `parent`, or of the root if `None`, with the token `tok`. The target model accepts the path down
+    /// the tree for as long as it samples the token of a child after the input position of its parent.
+    pub fn child_with_token(&self, parent: Option<usize>, tok: u32) -> Option<usize> {
+        self.children(parent).find(|&node| self.tokens[node] == tok)
+    }
+
+    /// The input position of `node`, or of the root if `None`.
+    pub fn input_position(node: Option<usize>) -> usize {
+        node.map_or(0, |node| node + 1)
+    }
+
+    /// The input positions whose KV cache entries are kept after `path` is accepted: the root and the accepted nodes.
+    pub fn kept_positions(path: &[usize]) -> Vec<usize> {
+        std::iter::once(0)
+            .chain(path.iter().map(|node| node + 1))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DraftTree;
+
+    #[test]
+    fn paths_share_prefixes() {
+        let tree = DraftTree::from_paths(&[vec![1, 2, 3], vec![1, 4], vec![5]]);
+        assert_eq!(tree.tokens(), &[1, 2, 3, 4, 5]);
+        assert_eq!(tree.children(None).collect::<Vec<_>>(), vec![0, 4]);
+        assert_eq!(tree.children(Some(0)).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(tree.depth(), 3);
+        assert_eq!(tree.position_offsets(), vec![0, 1, 2, 3, 2, 1]);
+
+        // A single path is a chain
+        let chain = DraftTree::from_paths(&[[7, 8]]);
+        assert_eq!(chain.position_offsets(), vec![0, 1, 2]);
+        assert!(DraftTree::from_paths::<Vec<u32>>(&[]).is_empty());
+    }
+
+    #[test]
+    fn tokens_only_see_their_ancestors() {
+        let tree = DraftTree::from_paths(&[vec![1, 2, 3], vec![1, 4], vec![5]]);
+        let visible = |query| {
+            (0..=tree.len())
+                .filter(|&key| tree.is_visible(query, key))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(visible(0), vec![0]);
+        assert_eq!(visible(3), vec![0, 1, 2, 3]);
+        // The sibling branch `2 3` is not visible from `4`
+        assert_eq!(visible(4), vec![0, 1, 4]);
+        assert_eq!(visible(5), vec![0, 5]);
+    }
+
+    #[test]
+    fn longest_accepted_path() {
+        let tree = DraftTree::from_paths(&[vec![1, 2, 3], vec![1, 4], vec![5]]);
+        // The target model samples `1` after the root, then `4` after `1`, then `9` after `4`
+        let target = [1, 4, 0, 0, 9, 0];
+        let mut path = Vec::new();
+        let mut sampled_positions = Vec::new();
+        loop {
+            let position = DraftTree::input_position(path.last().copied());
+            sampled_positions.push(position);
+            match tree.child_with_token(path.last().copied(), target[position]) {
+                Some(node) => path.push(node),
+                None => break,
+            }
+        }
+        assert_eq!(path, vec![0, 3]);
+        assert_eq!(sampled_positions, vec![0, 1, 4]);
+        assert_eq!(DraftTree::kept_positions(&path), vec![0, 1, 4]);
+
+        // Nothing is accepted when the first token differs
+        assert_eq!(tree.child_with_token(None, 7), None);
+        assert_eq!(DraftTree::kept_positions(&[]), vec![0]);
+    }
+}
@@ -0,0 +1,130 @@
+use std::{iter::repeat, str::FromStr};
+
+use candle_core::{DType, Device, Result, Tensor, D};
+
+/// Maximum number of texts run through the model in one forward pass when embedding.
+pub(crate) const EMBEDDING_BATCH_SIZE: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+/// How the hidden states of a text are pooled into one embedding.
+pub enum EmbeddingPooling {
+    /// Average the hidden states of all tokens.
+    #[default]
+    Mean,
+    /// Use the hidden state of the last token, which has attended to the whole text.
+    LastToken,
+}
+
+impl FromStr for EmbeddingPooling {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(Self::Mean),
+            "last_token" => Ok(Self::LastToken),
+            other => Err(format!(
+                "Unexpected pooling `{other}`, expected `mean` or `last_token`."
+            )),
+        }
+    }
+}
+
+/// A right-padded batch of tokenized texts to embed.
+pub(crate) struct EmbeddingInputs {
+    pub input_ids: Tensor,
+    pub positions_kernel: Tensor,
+    pub lens: Vec<usize>,
+}
+
+impl EmbeddingInputs {
+    pub fn new(toks: &[Vec<u32>], device: &Device) -> Result<Self> {
+        let lens = toks.iter().map(Vec::len).collect::<Vec<_>>();
+        let max_len = lens.iter().copied().max().unwrap_or(0);
+        let mut input_ids = Vec::new();
+        let mut positions = Vec::new();
+        for ctxt in toks {
+            let padded = ctxt
+                .iter()
+                .copied()
+                .chain(repeat(0).take(max_len - ctxt.len()))
+                .collect::<Vec<_>>();
+            input_ids.push(Tensor::new(padded, device)?.unsqueeze(0)?);
+            let pos = (0..max_len as i64).collect::<Vec<_>>();
+            positions.push(Tensor::new(pos, device)?.unsqueeze(0)?);
+        }
+        Ok(Self {
+            input_ids: Tensor::cat(&input_ids, 0)?,
+            positions_kernel: Tensor::cat(&positions, 0)?,
+            lens,
+        })
+    }
+}
+
+/// Pool the hidden states `(batch, seq_len, hidden_size)` of a right-padded batch into one embedding per text,
+/// ignoring the padding. With `normalize`, the embeddings have unit L2 norm.
+pub(crate) fn pool(
+    hidden_states: &Tensor,
+    lens: &[usize],
+    pooling: EmbeddingPooling,
+    normalize: bool,
+) -> Result<Vec<Vec<f32>>> {
+    let hidden_states = hidden_states.to_dtype(DType::F32)?;
+    let mut embeddings = Vec::new();
+    for (i, len) in lens.iter().enumerate() {
+        let xs = hidden_states.get(i)?;
+        let mut embedding = match pooling {
+            EmbeddingPooling::Mean => xs.narrow(0, 0, *len)?.mean(0)?,
+            EmbeddingPooling::LastToken => xs.get(len - 1)?,
+        };
+        if normalize {
+            let norm = embedding.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+            embedding = embedding.broadcast_div(&norm)?;
+        }
+        embeddings.push(embedding.to_vec1::<f32>()?);
+    }
+    Ok(embeddings)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{pool, EmbeddingInputs, EmbeddingPooling};
+
+    #[test]
+    fn pooling_ignores_padding() {
+        // The second text is one token long, padded with a large value
+        let hidden_states = Tensor::new(
+            &[[[1f32, 2.], [3., 4.]], [[3., 4.], [100., 100.]]],
+            &Device::Cpu,
+        )
+        .unwrap();
+
+        let mean = pool(&hidden_states, &[2, 1], EmbeddingPooling::Mean, false).unwrap();
+        assert_eq!(mean, vec![vec![2., 3.], vec![3., 4.]]);
+
+        let last = pool(&hidden_states, &[2, 1], EmbeddingPooling::LastToken, false).unwrap();
+        assert_eq!(last, vec![vec![3., 4.], vec![3., 4.]]);
+
+        let normalized = pool(&hidden_states, &[2, 1], EmbeddingPooling::LastToken, true).unwrap();
+        assert_eq!(normalized[1], vec![0.6, 0.8]);
+    }
+
+    #[test]
+    fn inputs_are_right_padded() {
+        let inputs = EmbeddingInputs::new(&[vec![5, 6, 7], vec![8]], &Device::Cpu).unwrap();
+        assert_eq!(
+            inputs.input_ids.to_vec2::<u32>().unwrap(),
+            vec![vec![5, 6, 7], vec![8, 0, 0]]
+        );
+        assert_eq!(inputs.positions_kernel.dims(), &[2, 3]);
+        assert_eq!(inputs.lens, vec![3, 1]);
+    }
+
+    #[test]
+    fn pooling_from_str() {
+        assert_eq!("mean".parse(), Ok(EmbeddingPooling::Mean));
+        assert_eq!("last_token".parse(), Ok(EmbeddingPooling::LastToken));
+        assert!("cls".parse::<EmbeddingPooling>().is_err());
+    }
+}
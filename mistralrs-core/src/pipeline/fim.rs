@@ -0,0 +1,93 @@
+use tokenizers::Tokenizer;
+
+/// The `(prefix, suffix, middle)` tokens of the fill-in-the-middle formats of the supported model families.
+const SUPPORTED_FIM_TOKENS: [(&str, &str, &str); 4] = [
+    ("▁<PRE>", "▁<SUF>", "▁<MID>"), // Handle CodeLlama case
+    ("<fim_prefix>", "<fim_suffix>", "<fim_middle>"), // Handle StarCoder case
+    ("<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"), // Handle Qwen2.5-Coder and CodeGemma case
+    ("<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"), // Handle DeepSeek-Coder case
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The token ids which mark the prefix, suffix and middle of a fill-in-the-middle (FIM) prompt.
+pub struct FimTokens {
+    pub prefix: u32,
+    pub suffix: u32,
+    pub middle: u32,
+}
+
+impl FimTokens {
+    /// Find the FIM tokens in the vocabulary of the tokenizer, or return `None` if the model was not trained for FIM.
+    pub(crate) fn from_tokenizer(tokenizer: &Tokenizer) -> Option<Self> {
+        let vocab = tokenizer.get_vocab(true);
+        SUPPORTED_FIM_TOKENS
+            .iter()
+            .find_map(|(prefix, suffix, middle)| {
+                Some(Self {
+                    prefix: *vocab.get(*prefix)?,
+                    suffix: *vocab.get(*suffix)?,
+                    middle: *vocab.get(*middle)?,
+                })
+            })
+    }
+
+    /// Assemble the prompt in prefix-suffix-middle order, after which the model generates the middle.
+    pub fn prompt(&self, prefix: &[u32], suffix: &[u32]) -> Vec<u32> {
+        let mut prompt = Vec::with_capacity(prefix.len() + suffix.len() + 3);
+        prompt.push(self.prefix);
+        prompt.extend_from_slice(prefix);
+        prompt.push(self.suffix);
+        prompt.extend_from_slice(suffix);
+        prompt.push(self.middle);
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokenizers::{models::wordlevel::WordLevel, Tokenizer};
+
+    use super::FimTokens;
+
+    fn tokenizer(tokens: &[&str]) -> Tokenizer {
+        let vocab = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, tok)| (tok.to_string(), i as u32))
+            .collect::<HashMap<_, _>>();
+        Tokenizer::new(
+            WordLevel::builder()
+                .vocab(vocab)
+                .unk_token("<unk>".to_string())
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn fim_tokens_from_vocab() {
+        let tok = tokenizer(&[
+            "<unk>",
+            "def",
+            "<|fim_middle|>",
+            "<|fim_prefix|>",
+            "<|fim_suffix|>",
+        ]);
+        let fim = FimTokens::from_tokenizer(&tok).unwrap();
+        assert_eq!(
+            fim,
+            FimTokens {
+                prefix: 3,
+                suffix: 4,
+                middle: 2,
+            }
+        );
+        assert_eq!(fim.prompt(&[1, 1], &[0]), vec![3, 1, 1, 4, 0, 2]);
+
+        // All three tokens of a format are needed
+        let tok = tokenizer(&["<unk>", "▁<PRE>", "▁<SUF>"]);
+        assert_eq!(FimTokens::from_tokenizer(&tok), None);
+    }
+}
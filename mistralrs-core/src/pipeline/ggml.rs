@@ -1,8 +1,8 @@
-use super::cache_manager::DefaultCacheManager;
+use super::cache_manager::cache_manager;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, QuantizationKind, TokenSource,
-    XLoraPaths,
+    CacheManager, FimTokens, GeneralMetadata, Loader, ModelKind, ModelPaths, QuantizationKind,
+    TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, CacheManagerMixin, IsqPipelineMixin, MetadataMixin, ModelCategory,
@@ -11,7 +11,7 @@ use super::{
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
 use crate::lora::Ordering;
-use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
+use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig, PromptSpecialTokens};
 use crate::pipeline::{get_chat_template, Cache};
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
@@ -19,6 +19,7 @@ use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::model_config as ModelConfig;
 use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::varbuilder_utils::load_adapter;
 use crate::xlora_models::NonGranularState;
 use crate::{
     do_sample, get_mut_arcmutex, get_paths, DeviceMapMetadata, Pipeline, TryIntoDType, DEBUG,
@@ -34,7 +35,7 @@ use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use rand_isaac::Isaac64Rng;
 use std::any::Any;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use tokenizers::Tokenizer;
@@ -239,6 +240,9 @@ impl Loader for GGMLLoader {
                 "You are trying to in-situ quantize a GGML model. This will not do anything."
             );
         }
+        if mapper.is_tensor_parallel() {
+            anyhow::bail!("GGML models do not support tensor parallelism.");
+        }
         if !mapper.is_dummy() {
             warn!("GGML models do not support device mapping. Device mapping will not work. Please consider using a GGUF model.");
         }
@@ -318,6 +322,8 @@ impl Loader for GGMLLoader {
             Model::XLoraLlama(ref model) => model.cache.lock().len(),
         };
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let fim_tokens = FimTokens::from_tokenizer(&tokenizer);
+        let prompt_special_tokens = PromptSpecialTokens::new(&chat_template, &tokenizer);
         Ok(Arc::new(Mutex::new(GGMLPipeline {
             model,
             tok_trie: tok_trie.clone(),
@@ -340,6 +346,11 @@ impl Loader for GGMLLoader {
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 is_xlora,
+                kv_cache_bits: None,
+                kv_cache_block_size: None,
+                kv_cache_shape: None,
+                fim_tokens,
+                prompt_special_tokens,
             },
         })))
     }
@@ -397,14 +408,22 @@ impl IsqPipelineMixin for GGMLPipeline {
 }
 
 impl CacheManagerMixin for GGMLPipeline {
-    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_in_cache(self, seqs, modify_draft_cache)
+    fn clone_in_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> Result<(), candle_core::Error> {
+        cache_manager(self).clone_in_cache(self, seqs, modify_draft_cache)
     }
-    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_out_cache(self, seqs, modify_draft_cache)
+    fn clone_out_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> Result<(), candle_core::Error> {
+        cache_manager(self).clone_out_cache(self, seqs, modify_draft_cache)
     }
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
-        DefaultCacheManager.set_none_cache(self, modify_draft_cache);
+        cache_manager(self).set_none_cache(self, modify_draft_cache);
         if reset_non_granular {
             self.reset_non_granular_state()
         }
@@ -431,6 +450,57 @@ impl AdapterActivationMixin for GGMLPipeline {
             _ => unreachable!(),
         }
     }
+    fn add_adapter(&mut self, name: String, path: &Path) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Adding adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        let (vb, config) = load_adapter(path, &self.device())?;
+        match self.model {
+            Model::XLoraLlama(ref mut model) => model
+                .add_adapter(&name, &vb, &config)
+                .map_err(anyhow::Error::msg),
+            _ => unreachable!(),
+        }
+    }
+    fn merge_adapter(&mut self, name: String) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Merging adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        match self.model {
+            Model::XLoraLlama(ref mut model) => {
+                model.merge_adapter(&name).map_err(anyhow::Error::msg)
+            }
+            _ => unreachable!(),
+        }
+    }
+    fn unmerge_adapter(&mut self) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Merging adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        match self.model {
+            Model::XLoraLlama(ref mut model) => model.unmerge_adapter().map_err(anyhow::Error::msg),
+            _ => unreachable!(),
+        }
+    }
+    fn set_lora_scale(&mut self, scale: f64) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Scaling adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        match self.model {
+            Model::XLoraLlama(ref mut model) => {
+                model.set_lora_scale(scale).map_err(anyhow::Error::msg)
+            }
+            _ => unreachable!(),
+        }
+    }
 }
 
 impl MetadataMixin for GGMLPipeline {
@@ -455,6 +525,14 @@ impl MetadataMixin for GGMLPipeline {
     fn get_metadata(&self) -> &GeneralMetadata {
         &self.metadata
     }
+    fn set_kv_cache_bits(&mut self, bits: Option<u8>) {
+        self.metadata.kv_cache_bits = bits;
+    }
+    fn set_kv_cache_block_size(&mut self, block_size: Option<usize>) -> anyhow::Result<()> {
+        self.metadata.kv_cache_block_size = block_size;
+        self.cache().set_block_size(block_size);
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
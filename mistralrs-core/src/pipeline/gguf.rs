@@ -1,8 +1,8 @@
-use super::cache_manager::DefaultCacheManager;
+use super::cache_manager::cache_manager;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, PrettyName, QuantizationKind,
-    TokenSource, XLoraPaths,
+    CacheManager, FimTokens, GeneralMetadata, KvCacheShape, Loader, ModelKind, ModelPaths,
+    PrettyName, QuantizationKind, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, CacheManagerMixin, IsqPipelineMixin, MetadataMixin, ModelCategory,
@@ -14,14 +14,18 @@ use crate::gguf::{
     get_gguf_chat_template, {convert_gguf_to_hf_tokenizer, GgufTokenizerConversion},
 };
 use crate::lora::Ordering;
-use crate::pipeline::chat_template::{calculate_eos_tokens, BeginEndUnkTok, GenerationConfig};
+use crate::pipeline::chat_template::{
+    calculate_eos_tokens, BeginEndUnkTok, GenerationConfig, PromptSpecialTokens,
+};
 use crate::pipeline::ChatTemplate;
 use crate::pipeline::{get_chat_template, Cache};
 use crate::prefix_cacher::PrefixCacheManager;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
+use crate::utils::gguf_metadata::ContentMetadata;
 use crate::utils::model_config as ModelConfig;
 use crate::utils::tokenizer::get_tokenizer;
+use crate::utils::varbuilder_utils::load_adapter;
 use crate::xlora_models::NonGranularState;
 use crate::{
     do_sample, get_mut_arcmutex, get_paths_gguf, DeviceMapMetadata, LocalModelPaths, Pipeline,
@@ -31,6 +35,7 @@ use crate::{
     models::quantized_llama::ModelWeights as QLlama,
     models::quantized_phi2::ModelWeights as QPhi,
     models::quantized_phi3::ModelWeights as QPhi3,
+    models::quantized_qwen2::ModelWeights as QQwen2,
     utils::tokens::get_token,
     xlora_models::{XLoraQLlama, XLoraQPhi3},
 };
@@ -39,13 +44,13 @@ use candle_core::quantized::{
     gguf_file::{self, Value as GgufValue},
     GgmlDType,
 };
-use candle_core::{Device, Tensor};
+use candle_core::{DType, Device, Tensor};
 use either::Either;
 use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use rand_isaac::Isaac64Rng;
 use std::any::Any;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use strum::EnumString;
@@ -59,6 +64,7 @@ enum Model {
     XLoraLlama(XLoraQLlama),
     XLoraPhi3(XLoraQPhi3),
     Phi3(QPhi3),
+    Qwen2(QQwen2),
 }
 
 pub struct GGUFPipeline {
@@ -84,6 +90,7 @@ pub struct GGUFLoader {
     chat_template: Option<String>,
     kind: ModelKind,
     tgt_non_granular_index: Option<usize>,
+    rope_theta: Option<f32>,
 }
 
 #[derive(Debug, EnumString)]
@@ -100,15 +107,45 @@ pub enum GGUFArchitecture {
     Rwkv,
     Phi2,
     Phi3,
+    Qwen2,
 }
 
+/// The shape of the KV cache of a GGUF model, from its metadata. The quantized models run in F32, so their KV cache
+/// is in F32 too.
+fn kv_cache_shape(content: &gguf_file::Content) -> Option<KvCacheShape> {
+    let metadata = ContentMetadata {
+        path_prefix: content
+            .metadata
+            .get("general.architecture")?
+            .to_string()
+            .ok()?,
+        metadata: &content.metadata,
+    };
+    let head_count = metadata.get_value::<u32>("attention.head_count").ok()? as usize;
+    Some(KvCacheShape {
+        num_hidden_layers: metadata.get_value::<u32>("block_count").ok()? as usize,
+        num_kv_heads: metadata
+            .get_value::<u32>("attention.head_count_kv")
+            .map_or(head_count, |n| n as usize),
+        head_dim: metadata.get_value::<u32>("embedding_length").ok()? as usize / head_count.max(1),
+        dtype: DType::F32,
+    })
+}
+
+/// The architectures which have a GGUF model, listed in error messages.
+const SUPPORTED_ARCHITECTURES: &str = "`llama`, `phi2`, `phi3` and `qwen2`";
+/// The architectures which have a GGUF model with adapters, listed in error messages.
+const SUPPORTED_ADAPTER_ARCHITECTURES: &str = "`llama` and `phi3`";
+
 // Wraps from_str() for some convenience:
 // - Case-insensitive variant matching (TODO: is this desirable?)
 // - Customized error until potential upstream support: https://github.com/Peternator7/strum/issues/332
 impl GGUFArchitecture {
     fn from_value<T: AsRef<str> + std::fmt::Display>(value: T) -> Result<Self> {
         Self::from_str(&value.as_ref().to_ascii_lowercase())
-            .with_context(|| format!("Unknown GGUF architecture `{value}`"))
+            .with_context(|| {
+                format!("Unknown GGUF architecture `{value}`, supported architectures are {SUPPORTED_ARCHITECTURES}.")
+            })
             .map_err(anyhow::Error::msg)
     }
 }
@@ -132,6 +169,7 @@ pub struct GGUFLoaderBuilder {
     no_kv_cache: bool,
     chat_template: Option<String>,
     tgt_non_granular_index: Option<usize>,
+    rope_theta: Option<f32>,
 }
 
 impl GGUFLoaderBuilder {
@@ -206,6 +244,12 @@ impl GGUFLoaderBuilder {
         self.with_adapter(lora_model_id, lora_order, false, None)
     }
 
+    /// Use this base frequency for the rotary embedding of the model, replacing the `rope.freq_base` of its metadata.
+    pub fn with_rope_theta(mut self, rope_theta: Option<f32>) -> Self {
+        self.rope_theta = rope_theta;
+        self
+    }
+
     pub fn build(self) -> Box<dyn Loader> {
         Box::new(GGUFLoader {
             model_id: self.model_id,
@@ -218,6 +262,7 @@ impl GGUFLoaderBuilder {
             tgt_non_granular_index: self.tgt_non_granular_index,
             quantized_filename: self.quantized_filename,
             quantized_model_id: self.quantized_model_id,
+            rope_theta: self.rope_theta,
         })
     }
 }
@@ -258,6 +303,7 @@ impl GGUFLoader {
             chat_template,
             kind,
             tgt_non_granular_index,
+            rope_theta: None,
         }
     }
 }
@@ -323,6 +369,9 @@ impl Loader for GGUFLoader {
                 "You are trying to in-situ quantize a GGUF model. This will not do anything."
             );
         }
+        if mapper.is_tensor_parallel() {
+            anyhow::bail!("GGUF models do not support tensor parallelism.");
+        }
         // Otherwise, the device mapper will print it
         if mapper.is_dummy() {
             info!(
@@ -333,12 +382,21 @@ impl Loader for GGUFLoader {
         }
 
         let mut file = std::fs::File::open(paths.get_weight_filenames().first().unwrap())?;
-        let model = gguf_file::Content::read(&mut file)
+        let mut model = gguf_file::Content::read(&mut file)
             .map_err(|e| e.with_path(paths.get_weight_filenames().first().unwrap()))?;
-        let arch = model.metadata["general.architecture"]
+        let arch_name = model.metadata["general.architecture"]
             .to_string()
-            .context("Model metadata should have declared an architecture")
-            .and_then(GGUFArchitecture::from_value)?;
+            .context("Model metadata should have declared an architecture")?
+            .clone();
+        let arch = GGUFArchitecture::from_value(&arch_name)?;
+        if let Some(rope_theta) = self.rope_theta {
+            info!("Using a RoPE theta of {rope_theta}.");
+            model.metadata.insert(
+                format!("{arch_name}.rope.freq_base"),
+                GgufValue::F32(rope_theta),
+            );
+        }
+        let kv_cache_shape = kv_cache_shape(&model);
 
         info!("Model config:");
         let mut sorted_keys = model.metadata.keys().collect::<Vec<_>>();
@@ -372,6 +430,8 @@ impl Loader for GGUFLoader {
             bos,
             eos,
             unk,
+            add_bos_token,
+            add_eos_token,
         } = if paths.get_tokenizer_filename().to_string_lossy().is_empty() {
             convert_gguf_to_hf_tokenizer(&model)?
         } else {
@@ -380,6 +440,8 @@ impl Loader for GGUFLoader {
                 bos: None,
                 eos: None,
                 unk: None,
+                add_bos_token: None,
+                add_eos_token: None,
             }
         };
 
@@ -418,13 +480,16 @@ impl Loader for GGUFLoader {
                 GGUFArchitecture::Llama => Model::Llama(QLlama::try_from(model_config)?),
                 GGUFArchitecture::Phi2 => Model::Phi2(QPhi::try_from(model_config)?),
                 GGUFArchitecture::Phi3 => Model::Phi3(QPhi3::try_from(model_config)?),
-                a => bail!("Unsupported architecture `{a:?}` for GGUF"),
+                GGUFArchitecture::Qwen2 => Model::Qwen2(QQwen2::try_from(model_config)?),
+                a => bail!(
+                    "Unsupported architecture `{a:?}` for GGUF, supported architectures are {SUPPORTED_ARCHITECTURES}."
+                ),
             },
             ModelKind::AdapterQuantized { adapter, .. } => match arch {
                 GGUFArchitecture::Llama => Model::XLoraLlama(XLoraQLlama::try_from(model_config)?),
                 GGUFArchitecture::Phi3 => Model::XLoraPhi3(XLoraQPhi3::try_from(model_config)?),
                 a => bail!(
-                    "Unsupported architecture `{a:?}` for GGUF {kind}",
+                    "Unsupported architecture `{a:?}` for GGUF {kind}, supported architectures are {SUPPORTED_ADAPTER_ARCHITECTURES}.",
                     kind = adapter.pretty_name()
                 ),
             },
@@ -441,6 +506,7 @@ impl Loader for GGUFLoader {
             Model::Phi2(ref p) => p.max_seq_len,
             Model::XLoraLlama(ref xl) => xl.max_seq_len,
             Model::Phi3(ref p) => p.max_seq_len,
+            Model::Qwen2(ref p) => p.max_seq_len,
             Model::XLoraPhi3(ref p) => p.max_seq_len,
         };
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
@@ -449,6 +515,7 @@ impl Loader for GGUFLoader {
             Model::Phi2(ref model) => model.cache.lock().len(),
             Model::XLoraLlama(ref model) => model.cache.lock().len(),
             Model::Phi3(ref model) => model.cache.lock().len(),
+            Model::Qwen2(ref model) => model.cache.lock().len(),
             Model::XLoraPhi3(ref model) => model.cache.lock().len(),
         };
 
@@ -461,8 +528,18 @@ impl Loader for GGUFLoader {
         if chat_template.unk_token.is_none() && unk.is_some() {
             chat_template.unk_token = Some(BeginEndUnkTok(Either::Left(unk.unwrap())));
         }
+        if chat_template.add_bos_token.is_none() {
+            chat_template.add_bos_token = add_bos_token;
+        }
+        if chat_template.add_eos_token.is_none() {
+            chat_template.add_eos_token = add_eos_token;
+        }
 
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+
+        let fim_tokens = FimTokens::from_tokenizer(&tokenizer);
+
+        let prompt_special_tokens = PromptSpecialTokens::new(&chat_template, &tokenizer);
         Ok(Arc::new(Mutex::new(GGUFPipeline {
             model,
             tok_trie: tok_trie.clone(),
@@ -488,6 +565,11 @@ impl Loader for GGUFLoader {
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 is_xlora,
+                kv_cache_bits: None,
+                kv_cache_block_size: None,
+                kv_cache_shape,
+                fim_tokens,
+                prompt_special_tokens,
             },
         })))
     }
@@ -522,14 +604,22 @@ impl IsqPipelineMixin for GGUFPipeline {
 }
 
 impl CacheManagerMixin for GGUFPipeline {
-    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_in_cache(self, seqs, modify_draft_cache)
+    fn clone_in_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> Result<(), candle_core::Error> {
+        cache_manager(self).clone_in_cache(self, seqs, modify_draft_cache)
     }
-    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_out_cache(self, seqs, modify_draft_cache)
+    fn clone_out_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> Result<(), candle_core::Error> {
+        cache_manager(self).clone_out_cache(self, seqs, modify_draft_cache)
     }
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
-        DefaultCacheManager.set_none_cache(self, modify_draft_cache);
+        cache_manager(self).set_none_cache(self, modify_draft_cache);
         if reset_non_granular {
             self.reset_non_granular_state()
         }
@@ -540,6 +630,7 @@ impl CacheManagerMixin for GGUFPipeline {
             Model::Phi2(ref model) => &model.cache,
             Model::XLoraLlama(ref model) => &model.cache,
             Model::Phi3(ref model) => &model.cache,
+            Model::Qwen2(ref model) => &model.cache,
             Model::XLoraPhi3(ref model) => &model.cache,
         }
     }
@@ -562,6 +653,67 @@ impl AdapterActivationMixin for GGUFPipeline {
             _ => unreachable!(),
         }
     }
+    fn add_adapter(&mut self, name: String, path: &Path) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Adding adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        let (vb, config) = load_adapter(path, &self.device())?;
+        match self.model {
+            Model::XLoraLlama(ref mut model) => model
+                .add_adapter(&name, &vb, &config)
+                .map_err(anyhow::Error::msg),
+            Model::XLoraPhi3(ref mut model) => model
+                .add_adapter(&name, &vb, &config)
+                .map_err(anyhow::Error::msg),
+            _ => unreachable!(),
+        }
+    }
+    fn merge_adapter(&mut self, name: String) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Merging adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        match self.model {
+            Model::XLoraLlama(ref mut model) => {
+                model.merge_adapter(&name).map_err(anyhow::Error::msg)
+            }
+            Model::XLoraPhi3(ref mut model) => {
+                model.merge_adapter(&name).map_err(anyhow::Error::msg)
+            }
+            _ => unreachable!(),
+        }
+    }
+    fn unmerge_adapter(&mut self) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Merging adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        match self.model {
+            Model::XLoraLlama(ref mut model) => model.unmerge_adapter().map_err(anyhow::Error::msg),
+            Model::XLoraPhi3(ref mut model) => model.unmerge_adapter().map_err(anyhow::Error::msg),
+            _ => unreachable!(),
+        }
+    }
+    fn set_lora_scale(&mut self, scale: f64) -> anyhow::Result<usize> {
+        let is_lora = self.metadata.kind.is_adapted_and(|a| a.is_lora());
+        if !is_lora {
+            anyhow::bail!("Scaling adapters is only supported for models fine-tuned with LoRA.")
+        }
+
+        match self.model {
+            Model::XLoraLlama(ref mut model) => {
+                model.set_lora_scale(scale).map_err(anyhow::Error::msg)
+            }
+            Model::XLoraPhi3(ref mut model) => {
+                model.set_lora_scale(scale).map_err(anyhow::Error::msg)
+            }
+            _ => unreachable!(),
+        }
+    }
 }
 
 impl MetadataMixin for GGUFPipeline {
@@ -571,6 +723,7 @@ impl MetadataMixin for GGUFPipeline {
             Model::Phi2(ref model) => model.device.clone(),
             Model::XLoraLlama(ref model) => model.device.clone(),
             Model::Phi3(ref model) => model.device.clone(),
+            Model::Qwen2(ref model) => model.device.clone(),
             Model::XLoraPhi3(ref model) => model.device.clone(),
         }
     }
@@ -589,6 +742,14 @@ impl MetadataMixin for GGUFPipeline {
     fn get_metadata(&self) -> &GeneralMetadata {
         &self.metadata
     }
+    fn set_kv_cache_bits(&mut self, bits: Option<u8>) {
+        self.metadata.kv_cache_bits = bits;
+    }
+    fn set_kv_cache_block_size(&mut self, block_size: Option<usize>) -> anyhow::Result<()> {
+        self.metadata.kv_cache_block_size = block_size;
+        self.cache().set_block_size(block_size);
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -624,6 +785,12 @@ impl Pipeline for GGUFPipeline {
                 context_lens,
             ),
             Model::Phi3(ref model) => model.forward(&input_ids, &seqlen_offsets),
+            Model::Qwen2(ref model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+            ),
             Model::XLoraPhi3(ref model) => model.forward(
                 &input_ids,
                 input_ids_full.as_ref().unwrap_or(&input_ids),
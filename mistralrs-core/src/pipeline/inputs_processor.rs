@@ -74,39 +74,41 @@ pub mod text_models_inputs_processor {
         let mut seqlen_offsets = Vec::new();
         let mut context_lens = Vec::new();
         let mut position_ids = Vec::new();
+        // Scoring the prompt tokens needs the logits at every position. All sequences share the same length so that
+        // the logits can be batched.
+        let all_positions = last_n_context_len.is_none()
+            && input_seqs.iter().any(|seq| seq.prompt_logprobs_pending());
         for (seq, mut ctxt) in input_seqs.iter().zip(toks) {
-            let offset = last_n_context_len.unwrap_or_default();
-            seqlen_offsets.push(offset.1);
+            // Chunked prefills continue from the tokens already in the KV cache
+            let offset = last_n_context_len
+                .map(|(_, offset)| offset)
+                .unwrap_or_else(|| seq.prompt_offset());
+            seqlen_offsets.push(offset);
 
             ctxt.extend(repeat(padding_tok).take(max_len.saturating_sub(ctxt.len())));
-            context_lens.push((
-                seq.len() - last_n_context_len.map(|(a, _)| a).unwrap_or(1),
-                last_n_context_len.map(|(a, _)| a).unwrap_or(1),
-            ));
-            position_ids.push(seq.len());
+            if all_positions {
+                context_lens.push((0, max_len));
+            } else {
+                context_lens.push((
+                    seq.len() - last_n_context_len.map(|(a, _)| a).unwrap_or(1),
+                    last_n_context_len.map(|(a, _)| a).unwrap_or(1),
+                ));
+            }
+            position_ids.push(seq.prompt_offset() + seq.len());
 
             seqs_tensors.push(Tensor::new(ctxt, device).unwrap().unsqueeze(0).unwrap());
         }
 
         let mut tmp = Vec::new();
-        if last_n_context_len.is_some() {
-            for pos in (0..seqs_tensors.len())
-                .map(|i| {
-                    (*seqlen_offsets.get(i).unwrap() as i64
-                        ..*seqlen_offsets.get(i).unwrap() as i64 + max_len as i64)
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>()
-            {
-                tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
-            }
-        } else {
-            for pos in (0..seqs_tensors.len())
-                .map(|_| (0..max_len).map(|x| x as i64).collect::<Vec<_>>())
-                .collect::<Vec<_>>()
-            {
-                tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
-            }
+        for pos in (0..seqs_tensors.len())
+            .map(|i| {
+                (*seqlen_offsets.get(i).unwrap() as i64
+                    ..*seqlen_offsets.get(i).unwrap() as i64 + max_len as i64)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+        {
+            tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
         }
         let positions_kernel = Tensor::cat(&tmp, 0)?;
         let input = Tensor::cat(&seqs_tensors, 0).unwrap();
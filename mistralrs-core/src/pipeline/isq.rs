@@ -8,6 +8,7 @@ use candle_core::{
     Device, Tensor,
 };
 use indicatif::{ProgressBar, ProgressStyle};
+use regex_automata::meta::Regex;
 use tracing::{info, warn};
 
 use crate::device_map::DeviceMapper;
@@ -15,6 +16,57 @@ use crate::device_map::DeviceMapper;
 #[cfg(feature = "cuda")]
 const ISQ_THREAD_COUNT: usize = 4;
 
+/// A tensor which may be quantized by ISQ: the matmul, the layer it belongs to (used for device mapping),
+/// and the name of its weight in the checkpoint, such as `model.layers.0.self_attn.o_proj` (used to match overrides).
+pub type IsqTensor<'a> = (&'a mut QMatMul, Option<usize>, String);
+
+/// Quantize the tensors whose name matches a pattern to a different dtype than the rest of the model.
+///
+/// This allows keeping quality-sensitive layers such as `lm_head` in higher precision, for example `F16`.
+#[derive(Clone, Debug)]
+pub struct IsqOverride {
+    pattern: Regex,
+    dtype: GgmlDType,
+}
+
+impl IsqOverride {
+    /// Match tensor names containing a match of the regex, such as `^lm_head$` or `self_attn\.o_proj$`.
+    pub fn regex(pattern: &str, dtype: GgmlDType) -> anyhow::Result<Self> {
+        Ok(Self {
+            pattern: Regex::new(pattern)?,
+            dtype,
+        })
+    }
+
+    /// Match entire tensor names against the glob, where `*` matches any run of characters and `?` matches any
+    /// single character, such as `lm_head` or `*.o_proj`.
+    pub fn glob(pattern: &str, dtype: GgmlDType) -> anyhow::Result<Self> {
+        let mut regex = String::from("^");
+        for c in pattern.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                c => {
+                    if "\\.+()|[]{}^$#&-~".contains(c) {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+            }
+        }
+        regex.push('$');
+        Self::regex(&regex, dtype)
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.pattern.is_match(name)
+    }
+
+    pub fn dtype(&self) -> GgmlDType {
+        self.dtype
+    }
+}
+
 pub enum QuantizationBehaviour {
     Quantize(GgmlDType),
     Skip,
@@ -59,6 +111,14 @@ fn get_quantization_behaviour(tensor: &Tensor, dtype: GgmlDType) -> Quantization
     }
 }
 
+/// Size of the weight held by a `QMatMul`, in bytes.
+fn qmatmul_size_in_bytes(matmul: &QMatMul) -> usize {
+    match matmul {
+        QMatMul::QTensor(q) => q.storage_size_in_bytes(),
+        QMatMul::Tensor(t) | QMatMul::TensorF16(t) => t.elem_count() * t.dtype().size_in_bytes(),
+    }
+}
+
 macro_rules! generate_isq {
     ($tensor:expr, $device:expr, $dtype:expr, $n_quantized:expr) => {
         if let QMatMul::Tensor(t) = $tensor {
@@ -81,11 +141,21 @@ macro_rules! generate_isq {
 }
 
 pub trait IsqModel {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper);
-    /// Quantize the model in-situ.
-    fn quantize(&mut self, dtype: GgmlDType, device: Device) -> candle_core::Result<()> {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper);
+    /// Quantize the model in-situ. Tensors matching one of the `overrides` are quantized to the dtype of the
+    /// first matching override instead of `dtype`.
+    fn quantize(
+        &mut self,
+        dtype: GgmlDType,
+        device: Device,
+        overrides: &[IsqOverride],
+    ) -> candle_core::Result<()> {
         let (tensors, mapper) = self.get_tensors();
         let total_tensors = tensors.len();
+        let size_before = tensors
+            .iter()
+            .map(|(t, _, _)| qmatmul_size_in_bytes(t))
+            .sum::<usize>();
         let n_quantized = AtomicUsize::new(0);
         info!(
             "Applying in-situ quantization into {dtype:?} to {total_tensors} tensors in parallel."
@@ -99,13 +169,25 @@ pub trait IsqModel {
         );
 
         let mut devices = Vec::new();
-        for (_, layer) in &tensors {
+        let mut dtypes = Vec::new();
+        for (_, layer, name) in &tensors {
             let device = if let Some(layer) = layer {
                 mapper.device_for(*layer, false).unwrap_or(&device)
             } else {
                 &device
             };
             devices.push(device.clone());
+            dtypes.push(
+                overrides
+                    .iter()
+                    .find(|o| o.matches(name))
+                    .map(|o| o.dtype())
+                    .unwrap_or(dtype),
+            );
+        }
+        let n_overridden = dtypes.iter().filter(|t| **t != dtype).count();
+        if n_overridden > 0 {
+            info!("Applying ISQ overrides to {n_overridden} tensors.");
         }
 
         let t_start = Instant::now();
@@ -128,8 +210,9 @@ pub trait IsqModel {
             tensors
                 .into_par_iter()
                 .zip(devices)
+                .zip(dtypes)
                 .progress_with(bar)
-                .for_each(|((tensor, _), device)| {
+                .for_each(|(((tensor, _, _), device), dtype)| {
                     generate_isq!(tensor, device, dtype, n_quantized)
                 });
         }
@@ -140,14 +223,59 @@ pub trait IsqModel {
             tensors
                 .into_iter()
                 .zip(devices)
+                .zip(dtypes)
                 .progress_with(bar)
-                .for_each(|((tensor, _), device)| {
+                .for_each(|(((tensor, _, _), device), dtype)| {
                     generate_isq!(tensor, device, dtype, n_quantized)
                 });
         }
         let delta = Instant::now().duration_since(t_start).as_secs_f32();
         info!("Applied in-situ quantization into {dtype:?} to {n_quantized:?} tensors out of {total_tensors} total tensors. Took {delta:.2}s", );
 
+        let size_after = self
+            .get_tensors()
+            .0
+            .iter()
+            .map(|(t, _, _)| qmatmul_size_in_bytes(t))
+            .sum::<usize>();
+        const MB: f32 = 1024. * 1024.;
+        info!(
+            "In-situ quantized tensors use {:.2} MB, down from {:.2} MB.",
+            size_after as f32 / MB,
+            size_before as f32 / MB
+        );
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::quantized::GgmlDType;
+
+    use super::IsqOverride;
+
+    #[test]
+    fn glob_override_matches_entire_name() {
+        let o = IsqOverride::glob("*.self_attn.o_proj", GgmlDType::F16).unwrap();
+        assert!(o.matches("model.layers.0.self_attn.o_proj"));
+        assert!(!o.matches("model.layers.0.self_attn.o_proj_2"));
+        assert!(!o.matches("model.layers.0.self_attnxo_proj"));
+
+        let o = IsqOverride::glob("lm_head", GgmlDType::F16).unwrap();
+        assert!(o.matches("lm_head"));
+        assert!(!o.matches("model.lm_head"));
+
+        let o = IsqOverride::glob("model.layers.?.mlp.*", GgmlDType::Q8_0).unwrap();
+        assert!(o.matches("model.layers.3.mlp.down_proj"));
+        assert!(!o.matches("model.layers.12.mlp.down_proj"));
+    }
+
+    #[test]
+    fn regex_override_matches_substring() {
+        let o = IsqOverride::regex(r"layers\.(0|1)\.", GgmlDType::Q8_0).unwrap();
+        assert!(o.matches("model.layers.1.self_attn.q_proj"));
+        assert!(!o.matches("model.layers.11.self_attn.q_proj"));
+        assert!(IsqOverride::regex("(", GgmlDType::Q8_0).is_err());
+    }
+}
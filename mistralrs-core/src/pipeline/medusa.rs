@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::Linear;
+use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{utils::tokens::get_token, TokenSource};
+
+use super::DraftTree;
+
+/// Name of the weights of the Medusa heads in their repository.
+pub(crate) const MEDUSA_WEIGHTS: &str = "medusa_lm_head.safetensors";
+
+fn default_one() -> usize {
+    1
+}
+
+#[derive(Deserialize)]
+/// The `config.json` of Medusa heads.
+pub(crate) struct MedusaConfig {
+    medusa_num_heads: usize,
+    #[serde(default = "default_one")]
+    medusa_num_layers: usize,
+}
+
+/// A residual block of a Medusa head: `x + silu(linear(x))`.
+struct ResBlock(Linear);
+
+impl Module for ResBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs + candle_nn::ops::silu(&xs.apply(&self.0)?)?
+    }
+}
+
+struct MedusaHead {
+    blocks: Vec<ResBlock>,
+    lm_head: Linear,
+}
+
+/// Medusa heads: <https://arxiv.org/abs/2401.10774>
+///
+/// Extra heads trained on the final hidden state of the base model, where head `k` predicts the token `k + 2`
+/// positions ahead, after the token of the base LM head.
+pub(crate) struct MedusaHeads {
+    heads: Vec<MedusaHead>,
+}
+
+impl MedusaHeads {
+    /// Load the heads from the `config.json` and [`MEDUSA_WEIGHTS`] of their repository.
+    pub fn load(config: &Path, weights: &Path, dtype: DType, device: &Device) -> Result<Self> {
+        let config: MedusaConfig = serde_json::from_str(
+            &std::fs::read_to_string(config).map_err(candle_core::Error::wrap)?,
+        )
+        .map_err(candle_core::Error::wrap)?;
+        let tensors = candle_core::safetensors::load(weights, device)?
+            .into_iter()
+            .map(|(name, tensor)| Ok((name, tensor.to_dtype(dtype)?)))
+            .collect::<Result<_>>()?;
+        Self::from_tensors(&config, tensors)
+    }
+
+    /// Head `i` has the residual blocks `{i}.{j}.linear` and the LM head `{i}.{medusa_num_layers}`.
+    fn from_tensors(config: &MedusaConfig, mut tensors: HashMap<String, Tensor>) -> Result<Self> {
+        let mut take = |name: String| {
+            tensors.remove(&name).ok_or_else(|| {
+                candle_core::Error::Msg(format!("Medusa weight `{name}` not found."))
+            })
+        };
+        let mut heads = Vec::new();
+        for i in 0..config.medusa_num_heads {
+            let mut blocks = Vec::new();
+            for j in 0..config.medusa_num_layers {
+                blocks.push(ResBlock(Linear::new(
+                    take(format!("{i}.{j}.linear.weight"))?,
+                    Some(take(format!("{i}.{j}.linear.bias"))?),
+                )));
+            }
+            let lm_head = Linear::new(
+                take(format!("{i}.{}.weight", config.medusa_num_layers))?,
+                None,
+            );
+            heads.push(MedusaHead { blocks, lm_head });
+        }
+        Ok(Self { heads })
+    }
+
+    pub fn num_heads(&self) -> usize {
+        self.heads.len()
+    }
+
+    /// The logits of each of the first `n` heads from the final hidden state `(hidden_size)` of the last token.
+    fn head_logits(&self, hidden_state: &Tensor, n: usize) -> Result<Vec<Tensor>> {
+        self.heads
+            .iter()
+            .take(n)
+            .map(|head| {
+                let mut xs = hidden_state
+                    .to_dtype(head.lm_head.weight().dtype())?
+                    .unsqueeze(0)?;
+                for block in &head.blocks {
+                    xs = xs.apply(block)?;
+                }
+                xs.apply(&head.lm_head)?.squeeze(0)
+            })
+            .collect()
+    }
+
+    /// Propose the most likely token of each of the first `n` heads from the final hidden state `(hidden_size)` of
+    /// the last token. Together, they form a chain of draft tokens.
+    pub fn propose(&self, hidden_state: &Tensor, n: usize) -> Result<Vec<u32>> {
+        self.head_logits(hidden_state, n)?
+            .iter()
+            .map(|logits| logits.argmax(D::Minus1)?.to_scalar::<u32>())
+            .collect()
+    }
+
+    /// Propose a tree of draft tokens from the first `n` heads: the chain of their most likely tokens and, at each
+    /// depth, the next `width - 1` most likely tokens of that head, each followed by the most likely tokens of the
+    /// later heads.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn propose_tree(&self, hidden_state: &Tensor, n: usize, width: usize) -> Result<DraftTree> {
+        let mut top_k = Vec::new();
+        for logits in self.head_logits(hidden_state, n)? {
+            let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+            let mut toks = (0..logits.len() as u32).collect::<Vec<_>>();
+            toks.sort_by(|a, b| logits[*b as usize].total_cmp(&logits[*a as usize]));
+            toks.truncate(width.max(1));
+            top_k.push(toks);
+        }
+        let chain = top_k.iter().map(|toks| toks[0]).collect::<Vec<_>>();
+        let mut paths = vec![chain.clone()];
+        for (depth, toks) in top_k.iter().enumerate() {
+            for tok in &toks[1..] {
+                let mut path = chain[..depth].to_vec();
+                path.push(*tok);
+                path.extend(&chain[depth + 1..]);
+                paths.push(path);
+            }
+        }
+        Ok(DraftTree::from_paths(&paths))
+    }
+}
+
+/// Get the `config.json` and [`MEDUSA_WEIGHTS`] of the Medusa heads at `model_id`, a Hugging Face repository or a
+/// local directory.
+pub(crate) fn get_medusa_paths(
+    model_id: &str,
+    token_source: &TokenSource,
+    silent: bool,
+) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let api = ApiBuilder::new()
+        .with_progress(!silent)
+        .with_token(get_token(token_source)?)
+        .build()?;
+    let api = api.repo(Repo::with_revision(
+        model_id.to_string(),
+        RepoType::Model,
+        "main".to_string(),
+    ));
+    let model_id = Path::new(model_id);
+    Ok((
+        crate::api_get_file!(api, "config.json", model_id),
+        crate::api_get_file!(api, MEDUSA_WEIGHTS, model_id),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::{Device, Tensor};
+
+    use super::{MedusaConfig, MedusaHeads};
+
+    #[test]
+    fn heads_propose_their_argmax() {
+        let dev = Device::Cpu;
+        let zeros = |dims: &[usize]| Tensor::zeros(dims, candle_core::DType::F32, &dev).unwrap();
+        let mut tensors = HashMap::new();
+        for i in 0..3 {
+            // Zero residual blocks leave the hidden state unchanged
+            tensors.insert(format!("{i}.0.linear.weight"), zeros(&[2, 2]));
+            tensors.insert(format!("{i}.0.linear.bias"), zeros(&[2]));
+        }
+        let lm_head = |w: [[f32; 2]; 3]| Tensor::new(&w, &dev).unwrap();
+        tensors.insert(
+            "0.1.weight".to_string(),
+            lm_head([[1., 0.], [0., 1.], [0., 0.]]),
+        );
+        tensors.insert(
+            "1.1.weight".to_string(),
+            lm_head([[0., 0.], [0., 0.], [1., 1.]]),
+        );
+        tensors.insert(
+            "2.1.weight".to_string(),
+            lm_head([[1., 1.], [0., 0.], [0., 0.]]),
+        );
+        let config = MedusaConfig {
+            medusa_num_heads: 3,
+            medusa_num_layers: 1,
+        };
+        let heads = MedusaHeads::from_tensors(&config, tensors).unwrap();
+        assert_eq!(heads.num_heads(), 3);
+
+        let hidden_state = Tensor::new(&[0f32, 1.], &dev).unwrap();
+        assert_eq!(heads.propose(&hidden_state, 3).unwrap(), vec![1, 2, 0]);
+        assert_eq!(heads.propose(&hidden_state, 2).unwrap(), vec![1, 2]);
+
+        // The chain `1 2`, and the runner-up of each head followed by the most likely tokens of the later heads
+        let tree = heads.propose_tree(&hidden_state, 2, 2).unwrap();
+        assert_eq!(tree.tokens(), &[1, 2, 0, 2, 0]);
+        assert_eq!(tree.position_offsets(), vec![0, 1, 2, 1, 2, 2]);
+        assert_eq!(tree.depth(), 2);
+
+        // Missing weights are an error
+        assert!(MedusaHeads::from_tensors(
+            &MedusaConfig {
+                medusa_num_heads: 4,
+                medusa_num_layers: 1,
+            },
+            HashMap::new(),
+        )
+        .is_err());
+    }
+}
@@ -1,10 +1,15 @@
 mod cache_manager;
 pub mod chat_template;
+mod contrastive;
+mod draft_tree;
+mod embedding;
+mod fim;
 mod ggml;
 mod gguf;
 mod inputs_processor;
 mod isq;
 mod macros;
+mod medusa;
 mod normal;
 mod normal_loaders;
 mod paths;
@@ -19,11 +24,14 @@ mod sampling_pipeline;
 use crate::lora::{LoraConfig, Ordering};
 use crate::{DeviceMapMetadata, TryIntoDType};
 use candle_core::quantized::GgmlDType;
-use chat_template::ChatTemplate;
+use chat_template::{ChatTemplate, PromptSpecialTokens};
 use core::fmt;
+pub use draft_tree::DraftTree;
+pub use embedding::EmbeddingPooling;
+pub use fim::FimTokens;
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
 pub use gguf::{GGUFArchitecture, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig};
-pub use isq::IsqModel;
+pub use isq::{IsqModel, IsqOverride, IsqTensor};
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
 pub use normal_loaders::{
     GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType,
@@ -34,25 +42,38 @@ pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
 use rand_isaac::Isaac64Rng;
-pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
+pub use speculative::{
+    AdaptiveGamma, MedusaLoader, PromptLookupConfig, PromptLookupLoader, SpeculativeConfig,
+    SpeculativeLoader, SpeculativePipeline, SpeculativeStats,
+};
 use std::any::Any;
 use std::fmt::Debug;
 use std::sync::Arc;
-use std::{collections::HashMap, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
+use tracing::{debug_span, Instrument};
 pub use vision::{VisionLoader, VisionLoaderBuilder, VisionSpecificConfig};
 pub use vision_loaders::{Idefics2Loader, Phi3VLoader, VisionLoaderType, VisionModelLoader};
 
 use anyhow::Result;
-use candle_core::{Device, Tensor};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
 
 use crate::{
+    request::TokenizationPrompt,
     sequence::Sequence,
+    tools::Tool,
     xlora_models::{NonGranularState, XLoraConfig},
 };
 
-pub use self::cache_manager::{Cache, CacheManager, LayerCaches};
+pub use self::cache_manager::{
+    BlockTable, Cache, CacheManager, KvBlock, LayerCaches, PagedCacheManager,
+};
 pub use self::inputs_processor::{
     text_models_inputs_processor, InputsProcessor, InputsProcessorType,
 };
@@ -424,6 +445,57 @@ pub struct GeneralMetadata {
     pub kind: ModelKind,
     // TODO: Replace is_xlora queries to check via kind instead:
     pub is_xlora: bool,
+    /// Quantize sequence KV caches to this many bits (4 or 8) when they are cloned out.
+    pub kv_cache_bits: Option<u8>,
+    /// Page the KV caches of the sequences in blocks of this many positions.
+    pub kv_cache_block_size: Option<usize>,
+    /// The shape of the KV cache, if it is known from the config of the model.
+    pub kv_cache_shape: Option<KvCacheShape>,
+    /// The fill-in-the-middle tokens, if the model was trained for it.
+    pub fim_tokens: Option<FimTokens>,
+    /// The BOS and EOS tokens added to prompts.
+    pub prompt_special_tokens: PromptSpecialTokens,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The shape of the full precision KV cache of a model, from which its size per token is known before running it.
+pub struct KvCacheShape {
+    pub num_hidden_layers: usize,
+    pub num_kv_heads: usize,
+    pub head_dim: usize,
+    pub dtype: DType,
+}
+
+impl KvCacheShape {
+    /// The number of bytes of the keys and values of one token, over all layers.
+    pub fn bytes_per_token(&self) -> usize {
+        2 * self.num_hidden_layers * self.num_kv_heads * self.head_dim * self.dtype.size_in_bytes()
+    }
+
+    /// Read the shape from a Hugging Face `config.json`, or return `None` if it does not have the usual fields. The
+    /// number of KV heads defaults to the number of attention heads, and the head dimension to the hidden size divided
+    /// by the number of attention heads.
+    pub(crate) fn from_hf_config(config: &str, dtype: DType) -> Option<Self> {
+        #[derive(serde::Deserialize)]
+        struct Shape {
+            num_hidden_layers: usize,
+            num_attention_heads: usize,
+            num_key_value_heads: Option<usize>,
+            hidden_size: usize,
+            head_dim: Option<usize>,
+        }
+        let shape: Shape = serde_json::from_str(config).ok()?;
+        Some(Self {
+            num_hidden_layers: shape.num_hidden_layers,
+            num_kv_heads: shape
+                .num_key_value_heads
+                .unwrap_or(shape.num_attention_heads),
+            head_dim: shape
+                .head_dim
+                .unwrap_or(shape.hidden_size / shape.num_attention_heads.max(1)),
+            dtype,
+        })
+    }
 }
 
 pub enum AdapterInstruction {
@@ -456,10 +528,19 @@ pub trait IsqPipelineMixin {
 pub trait CacheManagerMixin {
     /// Clone the cache FROM the sequences' cache TO the model cache. Only called for completion seqs.
     /// It is not a guarantee that this will be called for each completion step.
-    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool);
+    /// Fails if a sequence has no cache to clone in.
+    fn clone_in_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> Result<(), candle_core::Error>;
     /// Clone the cache FROM the model cache TO the sequences. Called for prompt and completion seqs.
     /// It is not a guarantee that this will be called for each step.
-    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool);
+    fn clone_out_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> Result<(), candle_core::Error>;
     /// Set the model cache to all None. Only called for prompt seqs.
     /// It is not a guarantee that this will be called for each prompt step.
     /// This may also reset the non granular state if applicable.
@@ -470,6 +551,28 @@ pub trait CacheManagerMixin {
 pub trait AdapterActivationMixin {
     /// Returns the number of activated adapters.
     fn activate_adapters(&mut self, adapters: Vec<String>) -> Result<usize>;
+    /// Load the adapter `name` from a directory holding its `adapter_config.json` and `adapter_model.safetensors`,
+    /// without reloading the base model. It may then be activated like the preloaded adapters. Returns the number of
+    /// LoRA layers it was added to.
+    fn add_adapter(&mut self, _name: String, _path: &Path) -> Result<usize> {
+        anyhow::bail!("Adding adapters is only supported for models fine-tuned with LoRA.");
+    }
+    /// Merge the adapter `name` into the base weights of the LoRA layers and activate it, so that inference runs
+    /// without the LoRA computation. Quantized weights are dequantized, merged and quantized again. Other adapters
+    /// cannot be activated until it is unmerged. Returns the number of LoRA layers merged.
+    fn merge_adapter(&mut self, _name: String) -> Result<usize> {
+        anyhow::bail!("Merging adapters is only supported for models fine-tuned with LoRA.");
+    }
+    /// Restore the base weights from before [`AdapterActivationMixin::merge_adapter`]. Returns the number of LoRA
+    /// layers unmerged.
+    fn unmerge_adapter(&mut self) -> Result<usize> {
+        anyhow::bail!("Merging adapters is only supported for models fine-tuned with LoRA.");
+    }
+    /// Multiply the contribution of the LoRA adapters by `scale`, for the next steps. Returns the number of LoRA
+    /// layers scaled.
+    fn set_lora_scale(&mut self, _scale: f64) -> Result<usize> {
+        anyhow::bail!("Scaling adapters is only supported for models fine-tuned with LoRA.");
+    }
 }
 
 pub trait MetadataMixin {
@@ -478,6 +581,12 @@ pub trait MetadataMixin {
     fn name(&self) -> String;
     fn reset_non_granular_state(&self);
     fn get_metadata(&self) -> &GeneralMetadata;
+    /// Quantize the KV caches of the sequences to `bits` (4 or 8) when they are cloned out, or keep them in full
+    /// precision if `None`.
+    fn set_kv_cache_bits(&mut self, bits: Option<u8>);
+    /// Page the KV caches of the sequences in blocks of `block_size` positions, or keep them contiguous if `None`.
+    /// Errors if the pipeline cannot page its KV caches.
+    fn set_kv_cache_block_size(&mut self, block_size: Option<usize>) -> Result<()>;
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -498,6 +607,26 @@ pub trait Pipeline:
 {
     fn forward_inputs(&self, inputs: Box<dyn Any>) -> Result<Tensor, candle_core::Error>;
 
+    /// Like [`Pipeline::forward_inputs`], but also return the final hidden states at the positions of the logits,
+    /// from which Medusa heads propose draft tokens.
+    fn forward_inputs_with_hidden_states(
+        &self,
+        _inputs: Box<dyn Any>,
+    ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        candle_core::bail!("Hidden states are not supported for this model.");
+    }
+
+    /// Run a tree of draft tokens after `root`, the last token of the sequence whose KV cache is loaded. Returns the
+    /// logits and the final hidden states at every input position of the tree, the root first, see [`DraftTree`].
+    /// The KV cache is left with an entry for every input position.
+    fn forward_tree(
+        &self,
+        _root: u32,
+        _tree: &DraftTree,
+    ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        candle_core::bail!("Tree attention is not supported for this model.");
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn step(
         &mut self,
@@ -536,7 +665,7 @@ pub trait Pipeline:
                     }
                     AdapterInstruction::None => 0,
                 };
-                self.clone_in_cache(input_seqs, false)
+                self.clone_in_cache(input_seqs, false)?
             }
             CacheInstruction::Nothing(adapter_inst) => {
                 match adapter_inst {
@@ -568,11 +697,26 @@ pub trait Pipeline:
             }
             _ => unreachable!("Unreachable PRE cache op."),
         }
+        // Sequences are batched by their LoRA scale
+        if self.get_metadata().kind.is_adapted_and(|a| a.is_lora()) {
+            self.set_lora_scale(f64::from(input_seqs[0].lora_scale()))
+                .map_err(|e| {
+                    candle_core::Error::msg(
+                        <anyhow::Error as AsRef<dyn std::error::Error>>::as_ref(&e),
+                    )
+                })?;
+        }
 
-        let logits = self.forward_inputs(inputs)?;
+        let logits = debug_span!(
+            "forward",
+            prompt = is_prompt,
+            seq_ids = ?input_seqs.iter().map(|seq| *seq.id()).collect::<Vec<_>>(),
+            tokens = input_seqs.iter().map(|seq| seq.len()).sum::<usize>(),
+        )
+        .in_scope(|| self.forward_inputs(inputs))?;
 
         match post_op {
-            CacheInstruction::Out => self.clone_out_cache(input_seqs, false),
+            CacheInstruction::Out => self.clone_out_cache(input_seqs, false)?,
             CacheInstruction::Nothing(_) => (),
             CacheInstruction::Reset {
                 reset_non_granular,
@@ -581,7 +725,26 @@ pub trait Pipeline:
             _ => unreachable!("Unreachable POST cache op."),
         }
 
+        let logits = if is_prompt && input_seqs.iter().any(|seq| seq.prompt_logprobs_pending()) {
+            sampling::gather_prompt_logprobs(logits, input_seqs)?
+        } else {
+            logits
+        };
+
+        // Chunks before the last one of a chunked prefill only fill the KV cache
+        if input_seqs
+            .iter()
+            .any(|seq| seq.is_intermediate_prefill_chunk())
+        {
+            return Ok(());
+        }
+
+        let span = debug_span!(
+            "sample",
+            seq_ids = ?input_seqs.iter().map(|seq| *seq.id()).collect::<Vec<_>>(),
+        );
         self.sample(input_seqs, logits, prefix_cacher, disable_eos_stop, rng)
+            .instrument(span)
             .await?;
         Ok(())
     }
@@ -596,6 +759,83 @@ pub trait Pipeline:
     ) -> Result<(), candle_core::Error>;
 
     fn category(&self) -> ModelCategory;
+
+    /// Embed each text by pooling the final hidden states of the model, running the texts in batches. This leaves
+    /// the KV cache empty.
+    fn embed(
+        &self,
+        _texts: Vec<String>,
+        _pooling: EmbeddingPooling,
+        _normalize: bool,
+    ) -> Result<Vec<Vec<f32>>, candle_core::Error> {
+        candle_core::bail!("Embeddings are not supported for this model.");
+    }
+
+    /// Whether the logprobs of the prompt tokens can be computed, which needs the logits at every prompt position.
+    fn supports_prompt_logprobs(&self) -> bool {
+        matches!(self.category(), ModelCategory::Text)
+    }
+
+    /// Whether beam search may be used, which needs the caches of each sequence to be forked between steps.
+    fn supports_beam_search(&self) -> bool {
+        true
+    }
+
+    /// Whether contrastive search may be used, which runs the candidate tokens through the model for their hidden
+    /// states.
+    fn supports_contrastive_search(&self) -> bool {
+        false
+    }
+
+    /// Whether a tree of draft tokens may be verified in one step with [`Pipeline::forward_tree`].
+    fn supports_tree_attention(&self) -> bool {
+        false
+    }
+
+    /// Whether long prompts may be prefilled in chunks over several steps. This requires a KV cache, and no
+    /// X-LoRA or vision inputs.
+    fn supports_chunked_prefill(&self) -> bool {
+        let metadata = self.get_metadata();
+        !metadata.has_no_kv_cache
+            && !metadata.is_xlora
+            && matches!(self.category(), ModelCategory::Text)
+    }
+}
+
+impl dyn Pipeline {
+    /// Tokenize a prompt as a generation request would, without running the model. The chat template is applied to
+    /// chat messages, with the generation prompt. Images are not expanded to their image tokens.
+    pub fn tokenize_prompt(
+        &self,
+        prompt: TokenizationPrompt,
+        tools: Option<Vec<Tool>>,
+    ) -> Result<Vec<u32>> {
+        match prompt {
+            TokenizationPrompt::Chat(messages) => {
+                if !self.get_chat_template().has_chat_template() {
+                    anyhow::bail!(
+                        "Received messages for a model which does not have a chat template."
+                    );
+                }
+                self.get_processor().process(self, messages, true, tools)
+            }
+            TokenizationPrompt::Text(text) => Ok(self
+                .tokenizer()
+                .encode(text, false)
+                .map_err(|e| anyhow::Error::msg(e.to_string()))?
+                .get_ids()
+                .to_vec()),
+        }
+    }
+
+    /// Number of tokens of a prompt, as counted by [`Self::tokenize_prompt`].
+    pub fn count_tokens(
+        &self,
+        prompt: TokenizationPrompt,
+        tools: Option<Vec<Tool>>,
+    ) -> Result<usize> {
+        Ok(self.tokenize_prompt(prompt, tools)?.len())
+    }
 }
 
 pub trait NormalModel: IsqModel {
@@ -621,6 +861,35 @@ pub trait NormalModel: IsqModel {
         context_lens: Vec<(usize, usize)>,
         position_ids: Vec<usize>,
     ) -> candle_core::Result<Tensor>;
+    /// The hidden states after the final norm at every position, `(batch, seq_len, hidden_size)`. These are pooled
+    /// into embeddings, and compared by contrastive search.
+    fn hidden_states(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Hidden states are not supported for this model.");
+    }
+    /// The hidden states after the final norm of a tree of draft tokens, `(1, n_tokens, hidden_size)`, where the input
+    /// ids are the root and then the nodes of `tree`. They are run after the cached tokens, each at the position of
+    /// its depth and attending only to the cache and its ancestors.
+    fn tree_hidden_states(
+        &self,
+        _input_ids: &Tensor,
+        _tree: &DraftTree,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Tree attention is not supported for this model.");
+    }
+    /// Whether [`NormalModel::tree_hidden_states`] is supported.
+    fn supports_tree_attention(&self) -> bool {
+        false
+    }
+    /// Apply the LM head to hidden states from [`NormalModel::hidden_states`], giving the logits at every position.
+    fn lm_head(&self, _hidden_states: &Tensor) -> candle_core::Result<Tensor> {
+        candle_core::bail!("The LM head cannot be applied on its own for this model.");
+    }
     fn is_xlora(&self) -> bool;
     fn device(&self) -> &Device;
     fn cache(&self) -> &Cache;
@@ -631,6 +900,27 @@ pub trait NormalModel: IsqModel {
             "Activating adapters is only supported for models fine-tuned with LoRA."
         );
     }
+    /// Load a new adapter into the LoRA layers of the model, so that it may be activated.
+    fn add_adapter(
+        &mut self,
+        _name: &str,
+        _vb: &VarBuilder,
+        _cfg: &LoraConfig,
+    ) -> candle_core::Result<usize> {
+        candle_core::bail!("Adding adapters is only supported for models fine-tuned with LoRA.");
+    }
+    /// Merge an adapter into the base weights of the LoRA layers of the model.
+    fn merge_adapter(&mut self, _name: &str) -> candle_core::Result<usize> {
+        candle_core::bail!("Merging adapters is only supported for models fine-tuned with LoRA.");
+    }
+    /// Restore the base weights of the LoRA layers of the model.
+    fn unmerge_adapter(&mut self) -> candle_core::Result<usize> {
+        candle_core::bail!("Merging adapters is only supported for models fine-tuned with LoRA.");
+    }
+    /// Multiply the contribution of the adapters of the LoRA layers of the model.
+    fn set_lora_scale(&mut self, _scale: f64) -> candle_core::Result<usize> {
+        candle_core::bail!("Scaling adapters is only supported for models fine-tuned with LoRA.");
+    }
 }
 
 pub trait VisionModel: IsqModel {
@@ -706,6 +996,7 @@ mod tests {
                     inputs.clone()
                 },
                 true,
+                None,
                 template,
                 Some(bos.to_string()),
                 Some(eos.to_string()),
@@ -904,4 +1195,108 @@ mod tests {
 
         test_with_inputs(&templates, &expected_outputs, inputs);
     }
+
+    #[test]
+    fn test_default_chat_template() {
+        use super::chat_template::{apply_chat_template_to, DEFAULT_CHAT_TEMPLATE};
+
+        let messages = vec![
+            hashmap! {
+                "role".to_string() => Either::Left("system".to_string()),
+                "content".to_string() => Either::Left("You are a helpful assistant".to_string())
+            },
+            hashmap! {
+                "role".to_string() => Either::Left("user".to_string()),
+                "content".to_string() => Either::Left("Hello".to_string())
+            },
+        ];
+        let render = |add_generation_prompt| {
+            apply_chat_template_to(
+                messages.clone(),
+                add_generation_prompt,
+                None,
+                DEFAULT_CHAT_TEMPLATE,
+                None,
+                None,
+                None,
+            )
+            .unwrap()
+        };
+        let prompt = "<|im_start|>system\nYou are a helpful assistant<|im_end|>\n<|im_start|>user\nHello<|im_end|>\n";
+        assert_eq!(render(false), prompt);
+        assert_eq!(render(true), format!("{prompt}<|im_start|>assistant\n"));
+    }
+
+    #[test]
+    fn test_chat_template_tools() {
+        use super::chat_template::apply_chat_template_to;
+        use crate::{Function, Tool, ToolType};
+
+        let tools = vec![Tool {
+            tp: ToolType::Function,
+            function: Function {
+                name: "get_weather".to_string(),
+                description: None,
+                parameters: Some(serde_json::json!({"type": "object"})),
+            },
+        }];
+        let output = apply_chat_template_to(
+            Vec::new(),
+            false,
+            Some(tools),
+            "{% for tool in tools %}{{ tool.function.name }}: {{ tool.function.parameters | tojson }}{% endfor %}",
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(output, r#"get_weather: {"type":"object"}"#);
+    }
+
+    #[test]
+    fn test_add_default_system_prompt() {
+        use super::chat_template::add_default_system_prompt;
+
+        let user = hashmap! {
+            "role".to_string() => Either::Left("user".to_string()),
+            "content".to_string() => Either::Left("Hello".to_string())
+        };
+        let system = |content: &str| {
+            hashmap! {
+                "role".to_string() => Either::Left("system".to_string()),
+                "content".to_string() => Either::Left(content.to_string())
+            }
+        };
+
+        let mut messages = vec![user.clone()];
+        add_default_system_prompt(&mut messages, "Be concise.");
+        assert_eq!(messages, vec![system("Be concise."), user.clone()]);
+
+        // The system message of the client is kept after the default system prompt
+        let mut messages = vec![system("Answer in French."), user.clone()];
+        add_default_system_prompt(&mut messages, "Be concise.");
+        assert_eq!(
+            messages,
+            vec![system("Be concise.\n\nAnswer in French."), user]
+        );
+    }
+
+    #[test]
+    fn kv_cache_shape_from_hf_config() {
+        use super::KvCacheShape;
+        use candle_core::DType;
+
+        // Llama 3 8B
+        let config = r#"{"num_hidden_layers": 32, "num_attention_heads": 32, "num_key_value_heads": 8, "hidden_size": 4096}"#;
+        let shape = KvCacheShape::from_hf_config(config, DType::BF16).unwrap();
+        assert_eq!(shape.head_dim, 128);
+        assert_eq!(shape.bytes_per_token(), 128 * 1024);
+
+        // Without `num_key_value_heads`, every attention head has its own KV head
+        let config = r#"{"num_hidden_layers": 2, "num_attention_heads": 4, "hidden_size": 64, "head_dim": 32}"#;
+        let shape = KvCacheShape::from_hf_config(config, DType::F32).unwrap();
+        assert_eq!((shape.num_kv_heads, shape.head_dim), (4, 32));
+
+        assert!(KvCacheShape::from_hf_config("{}", DType::F32).is_none());
+    }
 }
@@ -1,11 +1,14 @@
-use super::cache_manager::DefaultCacheManager;
+use super::cache_manager::cache_manager;
+use super::contrastive;
+use super::embedding::{self, EmbeddingInputs, EMBEDDING_BATCH_SIZE};
 use super::normal_loaders::{
     GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader, NormalLoaderType, Phi2Loader,
     Phi3Loader, Qwen2Loader,
 };
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
+    extract_logits, get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs,
+    AdapterKind, CacheManager, DraftTree, EmbeddingPooling, FimTokens, GeneralMetadata,
+    IsqOverride, KvCacheShape, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
     TokenSource, XLoraPaths,
 };
 use super::{
@@ -14,15 +17,19 @@ use super::{
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
+use crate::layers::RopeScaling;
 use crate::lora::Ordering;
-use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
+use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig, PromptSpecialTokens};
 use crate::pipeline::{get_chat_template, Cache};
 use crate::pipeline::{ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::tokenizer::get_tokenizer;
-use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
+use crate::utils::{
+    tokens::get_token,
+    varbuilder_utils::{from_mmaped_safetensors, load_adapter},
+};
 use crate::xlora_models::NonGranularState;
 use crate::{
     do_sample, get_mut_arcmutex, get_paths, lora_model_loader, normal_model_loader,
@@ -35,7 +42,7 @@ use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
 use rand_isaac::Isaac64Rng;
 use std::any::Any;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use tokenizers::Tokenizer;
@@ -51,6 +58,7 @@ pub struct NormalPipeline {
     non_granular_state: Option<NonGranularState>,
     model_id: String,
     metadata: GeneralMetadata,
+    isq_overrides: Vec<IsqOverride>,
 }
 
 /// A loader for a "normal" (non-quantized) model.
@@ -65,6 +73,9 @@ pub struct NormalLoader {
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
+    isq_overrides: Vec<IsqOverride>,
+    rope_scaling: Option<RopeScaling>,
+    rope_theta: Option<f64>,
 }
 
 #[derive(Default)]
@@ -79,6 +90,9 @@ pub struct NormalLoaderBuilder {
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
     tgt_non_granular_index: Option<usize>,
+    isq_overrides: Vec<IsqOverride>,
+    rope_scaling: Option<RopeScaling>,
+    rope_theta: Option<f64>,
 }
 
 #[derive(Clone, Copy, Default)]
@@ -153,6 +167,24 @@ impl NormalLoaderBuilder {
         self.with_adapter(lora_model_id, lora_order, false, None)
     }
 
+    /// Quantize the tensors matching these overrides to their dtype instead of the ISQ dtype.
+    pub fn with_isq_overrides(mut self, isq_overrides: Vec<IsqOverride>) -> Self {
+        self.isq_overrides = isq_overrides;
+        self
+    }
+
+    /// Scale the rotary embedding of the model to extend its context, replacing the `rope_scaling` of its config.
+    pub fn with_rope_scaling(mut self, rope_scaling: Option<RopeScaling>) -> Self {
+        self.rope_scaling = rope_scaling;
+        self
+    }
+
+    /// Use this base frequency for the rotary embedding of the model, replacing the `rope_theta` of its config.
+    pub fn with_rope_theta(mut self, rope_theta: Option<f64>) -> Self {
+        self.rope_theta = rope_theta;
+        self
+    }
+
     pub fn build(self, loader: NormalLoaderType) -> Box<dyn Loader> {
         let loader: Box<dyn NormalModelLoader> = match loader {
             NormalLoaderType::Mistral => Box::new(MistralLoader),
@@ -174,6 +206,9 @@ impl NormalLoaderBuilder {
             chat_template: self.chat_template,
             tokenizer_json: self.tokenizer_json,
             tgt_non_granular_index: self.tgt_non_granular_index,
+            isq_overrides: self.isq_overrides,
+            rope_scaling: self.rope_scaling,
+            rope_theta: self.rope_theta,
         })
     }
 }
@@ -212,8 +247,33 @@ impl Loader for NormalLoader {
         mapper: DeviceMapMetadata,
         in_situ_quant: Option<GgmlDType>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
-        let config = std::fs::read_to_string(paths.get_config_filename())?;
+        let mut config = std::fs::read_to_string(paths.get_config_filename())?;
+        if self.rope_scaling.is_some() || self.rope_theta.is_some() {
+            let mut value: serde_json::Value = serde_json::from_str(&config)?;
+            if let Some(rope_scaling) = &self.rope_scaling {
+                if !self.inner.supports_rope_scaling() {
+                    anyhow::bail!(
+                        "RoPE scaling is not supported for the architecture of this model."
+                    );
+                }
+                value["rope_scaling"] = serde_json::to_value(rope_scaling)?;
+            }
+            if let Some(rope_theta) = self.rope_theta {
+                info!("Using a RoPE theta of {rope_theta}.");
+                value["rope_theta"] = serde_json::to_value(rope_theta)?;
+            }
+            config = value.to_string();
+        }
+        if mapper.is_tensor_parallel() {
+            if !self.inner.supports_tensor_parallel() || !matches!(self.kind, ModelKind::Normal) {
+                anyhow::bail!("Tensor parallelism is not supported for this model.");
+            }
+            if in_situ_quant.is_some() {
+                anyhow::bail!("In-situ quantization is not supported with tensor parallelism.");
+            }
+        }
         let dtype = dtype.try_into_dtype(device)?;
+        let kv_cache_shape = KvCacheShape::from_hf_config(&config, dtype);
         // Otherwise, the device mapper will print it
         if mapper.is_dummy() {
             info!(
@@ -288,13 +348,15 @@ impl Loader for NormalLoader {
         let chat_template = get_chat_template(paths, &self.chat_template, None);
 
         if let Some(in_situ_quant) = in_situ_quant {
-            model.quantize(in_situ_quant, device.clone())?;
+            model.quantize(in_situ_quant, device.clone(), &self.isq_overrides)?;
         }
 
         let max_seq_len = model.max_seq_len();
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
         let num_hidden_layers = model.cache().lock().len();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let fim_tokens = FimTokens::from_tokenizer(&tokenizer);
+        let prompt_special_tokens = PromptSpecialTokens::new(&chat_template, &tokenizer);
         Ok(Arc::new(Mutex::new(NormalPipeline {
             model,
             tok_trie: tok_trie.clone(),
@@ -308,6 +370,7 @@ impl Loader for NormalLoader {
                 }
             }),
             model_id: self.model_id.clone(),
+            isq_overrides: self.isq_overrides.clone(),
             metadata: GeneralMetadata {
                 max_seq_len,
                 repeat_last_n: self.config.repeat_last_n,
@@ -317,6 +380,11 @@ impl Loader for NormalLoader {
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 is_xlora,
+                kv_cache_bits: None,
+                kv_cache_block_size: None,
+                kv_cache_shape,
+                fim_tokens,
+                prompt_special_tokens,
             },
         })))
     }
@@ -346,20 +414,28 @@ impl IsqPipelineMixin for NormalPipeline {
     fn re_isq_model(&mut self, dtype: GgmlDType) -> Result<()> {
         let device = self.device().clone();
         self.model
-            .quantize(dtype, device)
+            .quantize(dtype, device, &self.isq_overrides)
             .map_err(anyhow::Error::msg)
     }
 }
 
 impl CacheManagerMixin for NormalPipeline {
-    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_in_cache(self, seqs, modify_draft_cache)
+    fn clone_in_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> Result<(), candle_core::Error> {
+        cache_manager(self).clone_in_cache(self, seqs, modify_draft_cache)
     }
-    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_out_cache(self, seqs, modify_draft_cache)
+    fn clone_out_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> Result<(), candle_core::Error> {
+        cache_manager(self).clone_out_cache(self, seqs, modify_draft_cache)
     }
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
-        DefaultCacheManager.set_none_cache(self, modify_draft_cache);
+        cache_manager(self).set_none_cache(self, modify_draft_cache);
         if reset_non_granular {
             self.reset_non_granular_state()
         }
@@ -375,6 +451,21 @@ impl AdapterActivationMixin for NormalPipeline {
             .activate_adapters(adapter_names)
             .map_err(anyhow::Error::msg)
     }
+    fn add_adapter(&mut self, name: String, path: &Path) -> anyhow::Result<usize> {
+        let (vb, config) = load_adapter(path, self.model.device())?;
+        self.model
+            .add_adapter(&name, &vb, &config)
+            .map_err(anyhow::Error::msg)
+    }
+    fn merge_adapter(&mut self, name: String) -> anyhow::Result<usize> {
+        self.model.merge_adapter(&name).map_err(anyhow::Error::msg)
+    }
+    fn unmerge_adapter(&mut self) -> anyhow::Result<usize> {
+        self.model.unmerge_adapter().map_err(anyhow::Error::msg)
+    }
+    fn set_lora_scale(&mut self, scale: f64) -> anyhow::Result<usize> {
+        self.model.set_lora_scale(scale).map_err(anyhow::Error::msg)
+    }
 }
 
 impl MetadataMixin for NormalPipeline {
@@ -396,6 +487,14 @@ impl MetadataMixin for NormalPipeline {
     fn get_metadata(&self) -> &GeneralMetadata {
         &self.metadata
     }
+    fn set_kv_cache_bits(&mut self, bits: Option<u8>) {
+        self.metadata.kv_cache_bits = bits;
+    }
+    fn set_kv_cache_block_size(&mut self, block_size: Option<usize>) -> anyhow::Result<()> {
+        self.metadata.kv_cache_block_size = block_size;
+        self.cache().set_block_size(block_size);
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -433,6 +532,47 @@ impl Pipeline for NormalPipeline {
             ),
         }
     }
+    fn forward_inputs_with_hidden_states(
+        &self,
+        inputs: Box<dyn Any>,
+    ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        let ModelInputs {
+            input_ids,
+            seqlen_offsets,
+            seqlen_offsets_kernel,
+            context_lens,
+            position_ids,
+            ..
+        } = *inputs.downcast().expect("Downcast failed.");
+        if self.model.is_xlora() {
+            candle_core::bail!("Hidden states are not supported for X-LoRA models.");
+        }
+        let hidden_states = extract_logits(
+            &self.model.hidden_states(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                position_ids,
+            )?,
+            context_lens,
+        )?;
+        let logits = self.model.lm_head(&hidden_states)?;
+        Ok((logits, hidden_states))
+    }
+    fn forward_tree(
+        &self,
+        root: u32,
+        tree: &DraftTree,
+    ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        if !self.supports_tree_attention() {
+            candle_core::bail!("Tree attention is not supported for this model.");
+        }
+        let input_ids =
+            Tensor::new([&[root], tree.tokens()].concat(), self.model.device())?.unsqueeze(0)?;
+        let hidden_states = self.model.tree_hidden_states(&input_ids, tree)?;
+        let logits = self.model.lm_head(&hidden_states)?;
+        Ok((logits, hidden_states))
+    }
     async fn sample(
         &self,
         seqs: &mut [&mut Sequence],
@@ -441,9 +581,72 @@ impl Pipeline for NormalPipeline {
         disable_eos_stop: bool,
         rng: Arc<std::sync::Mutex<Isaac64Rng>>,
     ) -> Result<(), candle_core::Error> {
-        do_sample!(self, seqs, logits, prefix_cacher, disable_eos_stop, rng)
+        let contrastive = contrastive::sample_contrastive(&*self.model, seqs, &logits)?;
+        do_sample!(
+            self,
+            seqs,
+            logits,
+            prefix_cacher,
+            disable_eos_stop,
+            rng,
+            contrastive
+        )
     }
     fn category(&self) -> ModelCategory {
         ModelCategory::Text
     }
+    fn supports_contrastive_search(&self) -> bool {
+        !self.model.is_xlora() && !self.no_kv_cache
+    }
+    fn supports_tree_attention(&self) -> bool {
+        !self.model.is_xlora() && !self.no_kv_cache && self.model.supports_tree_attention()
+    }
+    fn embed(
+        &self,
+        texts: Vec<String>,
+        pooling: EmbeddingPooling,
+        normalize: bool,
+    ) -> Result<Vec<Vec<f32>>, candle_core::Error> {
+        if self.model.is_xlora() {
+            candle_core::bail!("Embeddings are not supported for X-LoRA models.");
+        }
+        let toks = self
+            .tokenizer
+            .encode_batch(texts, true)
+            .map_err(candle_core::Error::msg)?
+            .iter()
+            .map(|encoding| encoding.get_ids().to_vec())
+            .collect::<Vec<_>>();
+        if let Some(len) = toks
+            .iter()
+            .map(Vec::len)
+            .find(|len| *len == 0 || *len > self.model.max_seq_len())
+        {
+            candle_core::bail!(
+                "Cannot embed a text of {len} tokens, the model accepts 1 to {} tokens.",
+                self.model.max_seq_len()
+            );
+        }
+
+        let mut embeddings = Vec::new();
+        for batch in toks.chunks(EMBEDDING_BATCH_SIZE) {
+            let inputs = EmbeddingInputs::new(batch, self.model.device())?;
+            // Each batch starts from an empty cache and must not leave its KV cache behind
+            self.set_none_cache(false, false);
+            let hidden_states = self.model.hidden_states(
+                &inputs.input_ids,
+                &vec![0; batch.len()],
+                inputs.positions_kernel,
+                inputs.lens.clone(),
+            );
+            self.set_none_cache(false, false);
+            embeddings.extend(embedding::pool(
+                &hidden_states?,
+                &inputs.lens,
+                pooling,
+                normalize,
+            )?);
+        }
+        Ok(embeddings)
+    }
 }
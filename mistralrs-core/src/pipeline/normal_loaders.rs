@@ -42,11 +42,22 @@ pub trait NormalModelLoader {
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Box<dyn NormalModel + Send + Sync>>;
     fn is_gptx(&self) -> bool;
+    /// Whether the model supports the RoPE scaling of [`NormalLoaderBuilder::with_rope_scaling`].
+    ///
+    /// [`NormalLoaderBuilder::with_rope_scaling`]: super::NormalLoaderBuilder::with_rope_scaling
+    fn supports_rope_scaling(&self) -> bool {
+        false
+    }
+    /// Whether the model can shard its projections with tensor parallelism.
+    fn supports_tensor_parallel(&self) -> bool {
+        false
+    }
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>>;
 }
 
 use super::NormalModel;
 use crate::{
+    layers::RopeScaling,
     models,
     xlora_models::{self, XLoraConfig},
     DeviceMapMetadata,
@@ -100,6 +111,8 @@ struct MistralBasicConfig {
     num_key_value_heads: usize,
     hidden_act: Activation,
     max_position_embeddings: usize,
+    #[serde(default)]
+    rope_scaling: Option<RopeScaling>,
     rms_norm_eps: f64,
     rope_theta: f64,
     sliding_window: Option<usize>,
@@ -117,6 +130,7 @@ impl MistralBasicConfig {
             num_key_value_heads: basic_config.num_key_value_heads,
             hidden_act: basic_config.hidden_act,
             max_position_embeddings: basic_config.max_position_embeddings,
+            rope_scaling: basic_config.rope_scaling,
             rms_norm_eps: basic_config.rms_norm_eps,
             rope_theta: basic_config.rope_theta,
             sliding_window: basic_config.sliding_window,
@@ -167,6 +181,9 @@ impl NormalModelLoader for MistralLoader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn supports_rope_scaling(&self) -> bool {
+        true
+    }
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
         Ok(Box::new(MistralBasicConfig::deserialize(
             config,
@@ -290,6 +307,8 @@ struct LlamaBasicConfig {
     #[serde(default = "default_rope")]
     rope_theta: f32,
     max_position_embeddings: usize,
+    #[serde(default)]
+    rope_scaling: Option<RopeScaling>,
 }
 
 fn default_rope() -> f32 {
@@ -312,6 +331,7 @@ impl LlamaBasicConfig {
             rope_theta: basic_config.rope_theta,
             use_flash_attn,
             max_position_embeddings: basic_config.max_position_embeddings,
+            rope_scaling: basic_config.rope_scaling,
         })
     }
 }
@@ -361,6 +381,12 @@ impl NormalModelLoader for LlamaLoader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn supports_rope_scaling(&self) -> bool {
+        true
+    }
+    fn supports_tensor_parallel(&self) -> bool {
+        true
+    }
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
         Ok(Box::new(LlamaBasicConfig::deserialize(
             config,
@@ -381,6 +407,8 @@ struct MixtralBasicConfig {
     num_key_value_heads: usize,
     hidden_act: Activation,
     max_position_embeddings: usize,
+    #[serde(default)]
+    rope_scaling: Option<RopeScaling>,
     rms_norm_eps: f64,
     rope_theta: f64,
     sliding_window: usize,
@@ -400,6 +428,7 @@ impl MixtralBasicConfig {
             num_key_value_heads: basic_config.num_key_value_heads,
             hidden_act: basic_config.hidden_act,
             max_position_embeddings: basic_config.max_position_embeddings,
+            rope_scaling: basic_config.rope_scaling,
             rms_norm_eps: basic_config.rms_norm_eps,
             rope_theta: basic_config.rope_theta,
             sliding_window: basic_config.sliding_window,
@@ -452,6 +481,9 @@ impl NormalModelLoader for MixtralLoader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn supports_rope_scaling(&self) -> bool {
+        true
+    }
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
         Ok(Box::new(MixtralBasicConfig::deserialize(
             config,
@@ -666,6 +698,8 @@ struct Qwen2BasicConfig {
     num_attention_heads: usize,
     num_key_value_heads: usize,
     max_position_embeddings: usize,
+    #[serde(default)]
+    rope_scaling: Option<RopeScaling>,
     sliding_window: usize,
     max_window_layers: usize,
     tie_word_embeddings: bool,
@@ -687,6 +721,7 @@ impl Qwen2BasicConfig {
             num_key_value_heads: basic_config.num_key_value_heads,
             hidden_act: basic_config.hidden_act,
             max_position_embeddings: basic_config.max_position_embeddings,
+            rope_scaling: basic_config.rope_scaling,
             rope_theta: basic_config.rope_theta,
             rms_norm_eps: basic_config.rms_norm_eps,
             sliding_window: basic_config.sliding_window,
@@ -734,6 +769,9 @@ impl NormalModelLoader for Qwen2Loader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn supports_rope_scaling(&self) -> bool {
+        true
+    }
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
         Ok(Box::new(Qwen2BasicConfig::deserialize(
             config,
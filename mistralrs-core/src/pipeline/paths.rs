@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
 };
@@ -9,12 +9,17 @@ use hf_hub::{
     api::sync::{ApiBuilder, ApiRepo},
     Repo, RepoType,
 };
+use serde::Deserialize;
 use serde_json::Value;
 use tracing::{info, warn};
 
 use crate::{
-    api_dir_list, api_get_file, lora::LoraConfig, pipeline::chat_template::ChatTemplate,
-    utils::tokens::get_token, xlora_models::XLoraConfig, ModelPaths, Ordering, TokenSource,
+    api_dir_list, api_get_file,
+    lora::LoraConfig,
+    pipeline::chat_template::{ChatTemplate, DEFAULT_CHAT_TEMPLATE},
+    utils::tokens::get_token,
+    xlora_models::XLoraConfig,
+    ModelPaths, Ordering, TokenSource,
 };
 
 pub(crate) struct XLoraPaths {
@@ -273,8 +278,13 @@ pub fn get_model_paths(
             Ok(files)
         }
         None => {
+            let listing = api_dir_list!(api, model_id).collect::<Vec<_>>();
+            if listing.iter().any(|x| x == SAFETENSORS_INDEX) {
+                let index = api_get_file!(api, SAFETENSORS_INDEX, model_id);
+                return get_sharded_safetensors_paths(&index, api, model_id);
+            }
             let mut filenames = vec![];
-            for rfilename in api_dir_list!(api, model_id).filter(|x| x.ends_with(".safetensors")) {
+            for rfilename in listing.into_iter().filter(|x| x.ends_with(".safetensors")) {
                 filenames.push(api_get_file!(api, &rfilename, model_id));
             }
             Ok(filenames)
@@ -282,6 +292,61 @@ pub fn get_model_paths(
     }
 }
 
+/// The file mapping each parameter of a sharded safetensors checkpoint to the shard which contains it.
+const SAFETENSORS_INDEX: &str = "model.safetensors.index.json";
+
+#[derive(Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// Get the shards referenced by the safetensors index at `index`. Other safetensors files in the repository, such as
+/// consolidated copies of the same weights, are not loaded. Fails if a parameter of the index is in none of the shards.
+fn get_sharded_safetensors_paths(
+    index: &Path,
+    api: &ApiRepo,
+    model_id: &Path,
+) -> Result<Vec<PathBuf>> {
+    let index: SafetensorsIndex = serde_json::from_str(&fs::read_to_string(index)?)?;
+    let mut shards = index.weight_map.values().cloned().collect::<Vec<_>>();
+    shards.sort();
+    shards.dedup();
+
+    let mut filenames = Vec::new();
+    let mut shard_tensors = HashMap::new();
+    for shard in shards {
+        let path = api_get_file!(api, &shard, model_id);
+        // Only the header is read here, the tensors are loaded later.
+        let tensors = unsafe { candle_core::safetensors::MmapedSafetensors::new(&path)? };
+        let names = tensors
+            .tensors()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<HashSet<_>>();
+        shard_tensors.insert(shard, names);
+        filenames.push(path);
+    }
+
+    let mut weight_map = index.weight_map.into_iter().collect::<Vec<_>>();
+    weight_map.sort();
+    for (name, shard) in weight_map {
+        if !shard_tensors[&shard].contains(&name) {
+            match shard_tensors
+                .iter()
+                .find(|(_, names)| names.contains(&name))
+            {
+                Some((actual, _)) => warn!(
+                    "Tensor `{name}` is mapped to `{shard}` by `{SAFETENSORS_INDEX}`, but it is in `{actual}`."
+                ),
+                None => anyhow::bail!(
+                    "Tensor `{name}` is mapped to `{shard}` by `{SAFETENSORS_INDEX}`, but it is missing from all shards."
+                ),
+            }
+        }
+    }
+    Ok(filenames)
+}
+
 /// Find and parse the appropriate [`ChatTemplate`], and ensure is has a valid [`ChatTemplate.chat_template`].
 /// If the the provided `tokenizer_config.json` from [`ModelPaths.get_template_filename`] does not
 /// have a `chat_template`, use the provided one.
@@ -391,8 +456,11 @@ pub(crate) fn get_chat_template(
                     }
                 }
                 None => {
-                    info!("No specified chat template. No chat template will be used. Only prompts will be accepted, not messages.");
-                    deser.insert("chat_template".to_string(), Value::Null);
+                    warn!("No specified chat template, using the built-in ChatML chat template.");
+                    deser.insert(
+                        "chat_template".to_string(),
+                        Value::String(DEFAULT_CHAT_TEMPLATE.to_string()),
+                    );
                 }
             }
 
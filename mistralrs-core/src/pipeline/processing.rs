@@ -6,7 +6,7 @@ use indexmap::IndexMap;
 
 use crate::{
     vision_models::{preprocessor_config::PreProcessorConfig, processor_config::ProcessorConfig},
-    MessageContent, Pipeline,
+    MessageContent, Pipeline, Tool,
 };
 
 use super::{chat_template::apply_chat_template_to, text_models_inputs_processor, InputsProcessor};
@@ -22,7 +22,7 @@ pub trait ProcessorCreator {
 pub enum MessagesAction {
     // For idefics2
     Keep,
-    // For everything else
+    // For everything else: the text parts of a message are joined and the image parts are dropped
     FlattenOnlyText,
 }
 
@@ -35,11 +35,13 @@ pub trait Processor {
         pipeline: &dyn Pipeline,
         messages: Vec<IndexMap<String, MessageContent>>,
         add_generation_prompt: bool,
+        tools: Option<Vec<Tool>>,
     ) -> Result<Vec<u32>> {
         let prompt = apply_chat_template(
             pipeline,
             messages,
             add_generation_prompt,
+            tools,
             self.template_action(),
         )?;
         let encoding = pipeline
@@ -57,6 +59,7 @@ pub(crate) fn apply_chat_template(
     pipeline: &dyn Pipeline,
     messages: Vec<IndexMap<String, MessageContent>>,
     add_generation_prompt: bool,
+    tools: Option<Vec<Tool>>,
     action: MessagesAction,
 ) -> Result<String> {
     let messages = match action {
@@ -74,14 +77,13 @@ pub(crate) fn apply_chat_template(
                                 new_message.insert(k, Either::Left(lv));
                             }
                             Either::Right(rv) => {
-                                'outer: for content_row in rv {
-                                    for (content_k, content_v) in content_row {
-                                        if content_k == "text" {
-                                            new_message.insert(k, Either::Left(content_v));
-                                            break 'outer;
-                                        }
-                                    }
-                                }
+                                // Join the text parts, in order
+                                let text = rv
+                                    .into_iter()
+                                    .filter_map(|mut content_row| content_row.swap_remove("text"))
+                                    .collect::<Vec<_>>()
+                                    .join("\n");
+                                new_message.insert(k, Either::Left(text));
                             }
                         }
                     } else {
@@ -122,6 +124,7 @@ pub(crate) fn apply_chat_template(
     apply_chat_template_to(
         messages,
         add_generation_prompt,
+        tools,
         template,
         bos_tok,
         eos_tok,
@@ -1,6 +1,6 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use candle_core::{DType, Device, Result, Tensor};
+use candle_core::{bail, DType, Device, Result, Tensor};
 use rand_isaac::Isaac64Rng;
 
 use crate::{
@@ -10,6 +10,161 @@ use crate::{
     sequence::{Sequence, SequenceRecognizer},
 };
 
+/// The bytes of a token for its logprobs. A byte-level token may hold part of a character, which its decoded `text`
+/// replaces with U+FFFD, so the bytes are those of the token in the vocabulary. Special tokens have none there, so
+/// theirs are those of their text.
+pub(crate) fn logprob_bytes(tok_trie: &TokTrie, token: u32, text: &str) -> Vec<u8> {
+    match tok_trie.token(token) {
+        [] => text.as_bytes().to_vec(),
+        bytes => bytes.to_vec(),
+    }
+}
+
+/// Score the prompt tokens of the sequences which requested prompt logprobs, from the logits at every prompt position
+/// (`[bs, seq_len, vocab]`). Returns the logits at the last position of each sequence (`[bs, 1, vocab]`), which the
+/// next token is sampled from.
+pub(crate) fn gather_prompt_logprobs(logits: Tensor, seqs: &mut [&mut Sequence]) -> Result<Tensor> {
+    let logits = logits.to_device(&Device::Cpu)?;
+    let mut last_logits = Vec::new();
+    for (i, seq) in seqs.iter_mut().enumerate() {
+        let seq_logits = logits.get(i)?;
+        let len = seq.len();
+        if seq.prompt_logprobs_pending() {
+            // The logits at position `pos` of a chunk predict the prompt token after it
+            let start = seq.prompt_offset() + 1;
+            let targets = seq.prompt_toks()[start..]
+                .iter()
+                .take(len)
+                .copied()
+                .collect::<Vec<_>>();
+            let sampler = seq.sampler();
+            let mut logprobs = Vec::new();
+            for (pos, token) in targets.into_iter().enumerate() {
+                logprobs.push(sampler.score_token(&seq_logits.get(pos)?, token)?);
+            }
+            seq.add_prompt_logprobs(logprobs);
+        }
+        last_logits.push(seq_logits.narrow(0, len - 1, 1)?);
+    }
+    Tensor::stack(&last_logits, 0)
+}
+
+/// Choose the next token of every beam in the batch from the logits of each sequence (`[1, 1, vocab]`). Returns the
+/// sample of each beam, and `None` for the sequences which are not beams.
+///
+/// Each running beam of a request is expanded with its `width` most likely tokens, where `width` is the number of
+/// running beams, and the `width` candidates with the highest cumulative logprob are kept. A beam whose own
+/// candidate was not kept is forked from the beam which produced one of the kept candidates, taking over its tokens
+/// and caches. Finished beams are not replaced, so the width shrinks as beams finish.
+pub(crate) fn sample_beams(
+    seqs: &mut [&mut Sequence],
+    logits: &[Tensor],
+) -> Result<Vec<Option<Logprobs>>> {
+    let mut samples = vec![None; seqs.len()];
+    let mut handled = vec![false; seqs.len()];
+    for first in 0..seqs.len() {
+        if handled[first] || !seqs[first].is_beam_search() {
+            continue;
+        }
+        let beams = (first..seqs.len())
+            .filter(|j| seqs[*j].is_beam_search() && seqs[*j].same_group(seqs[first]))
+            .collect::<Vec<_>>();
+        for beam in &beams {
+            handled[*beam] = true;
+        }
+        let width = beams.len();
+
+        // All beams start from the same prompt, so only the first one is expanded at the first step
+        let parents = if seqs[first].logprobs().is_empty() {
+            &beams[..1]
+        } else {
+            &beams[..]
+        };
+        let mut candidates = Vec::new();
+        for parent in parents {
+            let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(
+                &logits[*parent].flatten_all()?.to_dtype(DType::F32)?,
+            )?
+            .to_vec1()?;
+            let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+            argsort_indices
+                .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+            let cumulative_logprob = seqs[*parent].cumulative_logprob();
+            for token in argsort_indices.into_iter().take(width) {
+                candidates.push((
+                    cumulative_logprob + probs[token].log(10.0),
+                    *parent,
+                    token as u32,
+                ));
+            }
+        }
+
+        let selected = select_beams(candidates, &beams)?;
+        let states = selected
+            .iter()
+            .zip(&beams)
+            .filter(|((parent, _), beam)| parent != *beam)
+            .map(|((parent, _), _)| (*parent, seqs[*parent].beam_state()))
+            .collect::<HashMap<_, _>>();
+        for ((parent, token), beam) in selected.into_iter().zip(beams) {
+            if parent != beam {
+                seqs[beam].set_beam_state(states[&parent].clone());
+            }
+            let sampler = seqs[beam].sampler();
+            samples[beam] = Some(sampler.score_token(&logits[parent].flatten_all()?, token)?);
+        }
+    }
+    Ok(samples)
+}
+
+/// Keep the best `beams.len()` candidates `(cumulative logprob, parent beam, token)` and assign them to the beams.
+/// Returns the parent and token of each beam. A beam keeps its own best kept candidate, and the remaining candidates
+/// go to the beams which have none.
+fn select_beams(
+    mut candidates: Vec<(f32, usize, u32)>,
+    beams: &[usize],
+) -> Result<Vec<(usize, u32)>> {
+    if candidates.len() < beams.len() {
+        bail!(
+            "Only {} candidate tokens for {} beams.",
+            candidates.len(),
+            beams.len()
+        );
+    }
+    // Sort by descending cumulative logprob
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
+    candidates.truncate(beams.len());
+
+    let mut selected = vec![None; beams.len()];
+    let mut forks = Vec::new();
+    for (_, parent, token) in candidates {
+        match beams.iter().position(|beam| *beam == parent) {
+            Some(idx) if selected[idx].is_none() => selected[idx] = Some((parent, token)),
+            _ => forks.push((parent, token)),
+        }
+    }
+    let mut forks = forks.into_iter();
+    Ok(selected
+        .into_iter()
+        .map(|x| x.unwrap_or_else(|| forks.next().expect("One candidate per beam.")))
+        .collect())
+}
+
+/// Mask the logits of the banned tokens, so that they are never sampled whatever processing follows.
+fn ban_tokens(logits: Tensor, banned: &[u32]) -> Result<Tensor> {
+    if banned.is_empty() {
+        return Ok(logits);
+    }
+    let mut logits: Vec<f32> = logits.to_vec1()?;
+    for tok in banned {
+        if let Some(logit) = logits.get_mut(*tok as usize) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+    let vocab_size = logits.len();
+    Tensor::from_vec(logits, vocab_size, &Device::Cpu)
+}
+
 /// Async sample optionally adding to trie.
 #[allow(clippy::too_many_arguments)]
 pub async fn sample_sequence(
@@ -23,10 +178,17 @@ pub async fn sample_sequence(
     add_to_trie: bool,
     sample_speculative: bool,
 ) -> Result<Logprobs> {
+    let sampler = seq.sampler();
+    // Seeded sequences do not share the RNG of the engine, so their tokens do not depend on the other requests
+    let rng = seq.rng().unwrap_or(rng);
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    let logits = sampler.apply_repetition_penalty(logits, seq.get_toks())?;
+    let logits = ban_tokens(logits, seq.banned_ngram_tokens())?;
+    let logits = ban_tokens(logits, seq.min_tokens_suppressed())?;
     let start_at = seq.get_toks().len().saturating_sub(repeat_last_n);
 
-    let sampler = seq.sampler();
+    let mirostat = sampler.mirostat();
+    let mirostat_mu = mirostat.map(|mirostat| seq.mirostat_mu().unwrap_or(mirostat.initial_mu()));
     let logits_clone = logits.clone();
     let ctx_clone = seq.get_toks()[start_at..].to_vec();
     let rng_clone = rng.clone();
@@ -37,7 +199,8 @@ pub async fn sample_sequence(
         ctx_clone,
         return_logprobs,
         rng_clone,
-        sample_speculative
+        sample_speculative,
+        mirostat_mu
     );
 
     let bias_if_not_allowed = match &mut seq.recognizer {
@@ -65,12 +228,17 @@ pub async fn sample_sequence(
                 ctx_clone,
                 return_logprobs,
                 rng_clone,
-                sample_speculative
+                sample_speculative,
+                mirostat_mu
             )
         }
         None => first_lobprobs_response,
     };
 
+    if let (Some(mirostat), Some(mu), false) = (mirostat, mirostat_mu, sample_speculative) {
+        *seq.mirostat_mu() = Some(mirostat.next_mu(mu, second_logprobs_response.logprob));
+    }
+
     if add_to_trie {
         match seq.recognizer {
             SequenceRecognizer::Regex(ref mut rx) => {
@@ -119,3 +287,53 @@ pub async fn sample_target_sequence_speculative(
     }
     Ok(sampled)
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{ban_tokens, logprob_bytes, select_beams};
+    use crate::aici::{bytes::TokRxInfo, toktree::TokTrie};
+
+    #[test]
+    fn banned_tokens_stay_masked() {
+        let logits = Tensor::new(&[1f32, 2., 3.], &Device::Cpu).unwrap();
+        let banned = ban_tokens(logits, &[2, 5]).unwrap();
+        // A later bias or penalty does not make a banned token possible again
+        let biased: Vec<f32> = (banned + 100.).unwrap().to_vec1().unwrap();
+        assert_eq!(biased[..2], [101., 102.]);
+        assert!(biased[2].is_infinite() && biased[2] < 0.);
+    }
+
+    #[test]
+    fn select_beams_keeps_best_candidates() {
+        let beams = [3, 5];
+        // Both kept candidates extend beam 5, so beam 3 is forked from it
+        let candidates = vec![(-1.0, 3, 7), (-0.5, 5, 8), (-0.2, 5, 9), (-3.0, 3, 1)];
+        assert_eq!(
+            select_beams(candidates, &beams).unwrap(),
+            vec![(5, 8), (5, 9)]
+        );
+
+        // Each beam keeps its own candidate
+        let candidates = vec![(-1.0, 3, 7), (-0.5, 5, 8), (-2.0, 5, 9)];
+        assert_eq!(
+            select_beams(candidates, &beams).unwrap(),
+            vec![(3, 7), (5, 8)]
+        );
+
+        assert!(select_beams(vec![(-1.0, 3, 7)], &beams).is_err());
+    }
+
+    #[test]
+    fn logprob_bytes_of_partial_characters() {
+        let info = TokRxInfo {
+            vocab_size: 2,
+            tok_eos: 1,
+        };
+        // The second token is special, so it has no bytes in the vocabulary
+        let trie = TokTrie::from(&info, &[vec![0xE4, 0xBD], vec![]]);
+        assert_eq!(logprob_bytes(&trie, 0, "\u{FFFD}"), vec![0xE4, 0xBD]);
+        assert_eq!(logprob_bytes(&trie, 1, "</s>"), b"</s>".to_vec());
+    }
+}
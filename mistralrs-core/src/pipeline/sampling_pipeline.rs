@@ -2,46 +2,78 @@
 #[macro_export]
 macro_rules! finish_and_add_tokens_to_seq {
     ($this:expr, $prefix_cacher:expr, $seq:expr, $logprobs:expr, $eos_tok:expr, $use_prefix_cacher:expr) => {{
-        let is_done = $seq.is_done($logprobs.token, $eos_tok, $this.metadata.max_seq_len);
-        $seq.add_token(
-            $logprobs.clone(),
-            $this.get_metadata().tok_trie.decode(&[$logprobs.token]),
-            &is_done,
+        let tok_bytes = $this.get_metadata().tok_trie.decode(&[$logprobs.token]);
+        let is_done = $seq.is_done(
+            $logprobs.token,
+            &tok_bytes,
+            $eos_tok,
+            $this.metadata.max_seq_len,
         );
+        $seq.add_token($logprobs.clone(), tok_bytes, &is_done);
+        $crate::respond_to_seq!($this, $prefix_cacher, $seq, is_done, $use_prefix_cacher)
+    }};
+}
+
+/// Send the response of a sequence to which a token was added, a chunk if it streams or its choice if it is done.
+/// A sequence finished without a new token, such as a cancelled one, is responded to with the tokens it has.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! respond_to_seq {
+    ($this:expr, $prefix_cacher:expr, $seq:expr, $is_done:expr, $use_prefix_cacher:expr) => {{
+        let is_done: Option<$crate::sequence::StopReason> = $is_done;
         // Handle streaming requests
         if $seq.get_mut_group().is_streaming && $seq.get_mut_group().is_chat {
             let token_index = $seq.get_toks().len();
             let rate_limit_allowed = is_done.is_some() || token_index % 3 == 0;
 
             if rate_limit_allowed {
-                if let Some(delta) =
-                    $crate::handle_seq_error_ok!($seq.get_delta(), $seq.responder())
-                {
+                if let Some(delta) = $crate::handle_seq_error_ok!(
+                    $seq.get_delta(is_done.is_some()),
+                    $seq.responder()
+                ) {
+                    // A chunk may hold several tokens, so it carries the logprobs of all of them
+                    let logprobs = if $seq.return_logprobs() {
+                        let tokenizer = $this.tokenizer();
+                        let mut content = Vec::new();
+                        for logprob in $seq.take_stream_logprobs() {
+                            content.push($crate::ResponseLogprob {
+                                token: $crate::handle_seq_error_ok!(
+                                    tokenizer.decode(&[logprob.token], false),
+                                    $seq.responder()
+                                ),
+                                bytes: $crate::pipeline::sampling::logprob_bytes(
+                                    &$this.get_metadata().tok_trie,
+                                    logprob.token,
+                                    &logprob.bytes,
+                                ),
+                                logprob: logprob.logprob,
+                                top_logprobs: logprob.top_logprobs.unwrap(),
+                            });
+                        }
+                        Some($crate::Logprobs {
+                            content: Some(content),
+                        })
+                    } else {
+                        None
+                    };
                     $seq.add_streaming_chunk_choice_to_group($crate::ChunkChoice {
                         delta: $crate::Delta {
-                            content: delta.clone(),
+                            content: delta,
                             role: "assistant".to_string(),
                         },
                         index: $seq.get_response_index(),
                         finish_reason: is_done.map(|x| x.to_string()),
-                        logprobs: if $seq.return_logprobs() {
-                            Some($crate::ResponseLogprob {
-                                token: delta,
-                                bytes: $logprobs.bytes.clone().into_bytes(),
-                                logprob: $logprobs.logprob,
-                                top_logprobs: $logprobs.top_logprobs.unwrap().clone(),
-                            })
-                        } else {
-                            None
-                        },
+                        logprobs,
                     });
 
                     if let Some(reason) = is_done {
-                        if $use_prefix_cacher {
+                        // Canceled sequences may be incomplete, so they should not be reused
+                        if $use_prefix_cacher && reason != $crate::sequence::StopReason::Canceled {
                             $prefix_cacher.add_sequence($seq);
                             $prefix_cacher.evict_to_cpu()?;
                         }
                         $seq.set_state($crate::sequence::SequenceState::Done(reason));
+                        $seq.add_streaming_usage_to_group();
                         $this.reset_non_granular_state();
                     }
 
@@ -75,13 +107,19 @@ macro_rules! finish_and_add_tokens_to_seq {
 
                 let logprobs = if $seq.return_logprobs() {
                     let mut logprobs = Vec::new();
-                    for logprob in $seq.logprobs() {
+                    // An echoed prompt is preceded by the logprobs of its tokens
+                    let prompt_logprobs = $seq.prompt_logprobs().unwrap_or_default();
+                    for logprob in prompt_logprobs.iter().chain($seq.logprobs()) {
                         let resp_logprob = $crate::ResponseLogprob {
                             token: $crate::handle_seq_error_ok!(
                                 tokenizer.decode(&[logprob.token], false),
                                 $seq.responder()
                             ),
-                            bytes: logprob.bytes.clone().into_bytes(),
+                            bytes: $crate::pipeline::sampling::logprob_bytes(
+                                &$this.get_metadata().tok_trie,
+                                logprob.token,
+                                &logprob.bytes,
+                            ),
                             logprob: logprob.logprob,
                             top_logprobs: logprob.top_logprobs.clone().unwrap(),
                         };
@@ -92,12 +130,18 @@ macro_rules! finish_and_add_tokens_to_seq {
                     None
                 };
 
-                let text = match reason {
+                let text = tracing::debug_span!(
+                    "detokenize",
+                    seq_id = *$seq.id(),
+                    tokens = $seq.get_toks().len() - $seq.prompt_tokens(),
+                )
+                .in_scope(|| match reason {
                     $crate::sequence::StopReason::Length(_)
                     | $crate::sequence::StopReason::ModelLength(_)
                     | $crate::sequence::StopReason::Eos
                     | $crate::sequence::StopReason::StopTok(_)
-                    | $crate::sequence::StopReason::Canceled => {
+                    | $crate::sequence::StopReason::Canceled
+                    | $crate::sequence::StopReason::TimeLimit => {
                         String::from_utf8_lossy($seq.completion_bytes())
                             .trim_start()
                             .to_string()
@@ -106,18 +150,49 @@ macro_rules! finish_and_add_tokens_to_seq {
                         completion_bytes_pos,
                         ..
                     } => {
-                        let txt = String::from_utf8_lossy($seq.completion_bytes());
-                        txt[..completion_bytes_pos].trim_start().to_string()
+                        // The stop string and anything after it are trimmed
+                        String::from_utf8_lossy(&$seq.completion_bytes()[..completion_bytes_pos])
+                            .trim_start()
+                            .to_string()
                     }
-                };
+                });
 
                 if $seq.get_mut_group().is_chat {
+                    // Markers such as `[TOOL_CALLS]` are special tokens, so parse the decoded tokens, without the EOS
+                    let tool_calls = if $seq.parses_tool_calls() {
+                        let mut toks = &$seq.get_toks()[$seq.prompt_tokens()..];
+                        if reason == $crate::sequence::StopReason::Eos {
+                            toks = &toks[..toks.len().saturating_sub(1)];
+                        }
+                        let generated = $crate::handle_seq_error_ok!(
+                            tracing::debug_span!(
+                                "detokenize",
+                                seq_id = *$seq.id(),
+                                tokens = toks.len(),
+                            )
+                            .in_scope(|| tokenizer.decode(toks, false)),
+                            $seq.responder()
+                        );
+                        $crate::tools::parse_tool_calls(
+                            &generated,
+                            &format!("call-{}-{}", $seq.id(), $seq.get_response_index()),
+                        )
+                    } else {
+                        None
+                    };
+                    let (finish_reason, content, tool_calls) = match tool_calls {
+                        Some((content, tool_calls)) => {
+                            ("tool_calls".to_string(), content, tool_calls)
+                        }
+                        None => (reason.to_string(), text, Vec::new()),
+                    };
                     let choice = $crate::Choice {
-                        finish_reason: reason.to_string(),
+                        finish_reason,
                         index: $seq.get_response_index(),
                         message: $crate::ResponseMessage {
-                            content: text,
+                            content,
                             role: "assistant".to_string(),
+                            tool_calls,
                         },
                         logprobs: logprobs.map(|l| $crate::Logprobs { content: Some(l) }),
                     };
@@ -127,12 +202,12 @@ macro_rules! finish_and_add_tokens_to_seq {
                         finish_reason: reason.to_string(),
                         index: $seq.get_response_index(),
                         text,
-                        logprobs: None,
+                        logprobs: logprobs.map(|l| $crate::Logprobs { content: Some(l) }),
                     };
                     $seq.add_completion_choice_to_group(choice);
                 }
 
-                if $use_prefix_cacher {
+                if $use_prefix_cacher && reason != $crate::sequence::StopReason::Canceled {
                     $prefix_cacher.add_sequence($seq);
                     $prefix_cacher.evict_to_cpu()?;
                 }
@@ -143,7 +218,7 @@ macro_rules! finish_and_add_tokens_to_seq {
                         .maybe_send_done_response(
                             $crate::ChatCompletionResponse {
                                 id: $seq.id().to_string(),
-                                choices: group.get_choices().to_vec(),
+                                choices: group.get_choices(),
                                 created: $seq.creation_time(),
                                 model: pipeline_name,
                                 system_fingerprint: $crate::SYSTEM_FINGERPRINT.to_string(),
@@ -182,14 +257,37 @@ macro_rules! finish_and_add_tokens_to_seq {
 #[macro_export]
 macro_rules! do_sample {
     ($this:expr, $seqs:expr, $logits:expr, $prefix_cacher:expr, $disable_eos_stop:expr, $rng:expr) => {{
+        let seqs_len = $seqs.len();
+        $crate::do_sample!(
+            $this,
+            $seqs,
+            $logits,
+            $prefix_cacher,
+            $disable_eos_stop,
+            $rng,
+            vec![None; seqs_len]
+        )
+    }};
+    // The sequences with a sample in `$presampled` already had their next token chosen by the pipeline
+    ($this:expr, $seqs:expr, $logits:expr, $prefix_cacher:expr, $disable_eos_stop:expr, $rng:expr, $presampled:expr) => {{
         let seqs_len = $seqs.len();
         let logits_seq = $logits.to_device(&Device::Cpu)?.chunk(seqs_len, 0)?;
         debug_assert_eq!(logits_seq.len(), seqs_len);
 
         let use_async_pool = seqs_len > 1;
 
+        // Beams are chosen together, the other sequences are sampled on their own
+        let presampled = std::iter::zip(
+            $crate::pipeline::sampling::sample_beams($seqs, &logits_seq)?,
+            $presampled,
+        )
+        .map(|(beam_sample, sample)| beam_sample.or(sample))
+        .collect::<Vec<_>>();
+
         let sampling_futures: Vec<_> = std::iter::zip(logits_seq, $seqs.iter_mut())
-            .map(|(logits_per_seq, seq)| {
+            .zip(&presampled)
+            .filter(|(_, sample)| sample.is_none())
+            .map(|((logits_per_seq, seq), _)| {
                 let return_logprobs = seq.return_logprobs();
                 $crate::pipeline::sampling::sample_sequence(
                     logits_per_seq,
@@ -204,9 +302,15 @@ macro_rules! do_sample {
                 )
             })
             .collect();
-        let sampled_vec = futures::future::join_all(sampling_futures).await;
+        let mut sampled_vec = futures::future::join_all(sampling_futures)
+            .await
+            .into_iter();
 
-        for (sampled, seq) in std::iter::zip(sampled_vec, $seqs.iter_mut()) {
+        for (sample, seq) in std::iter::zip(presampled, $seqs.iter_mut()) {
+            let sampled = match sample {
+                Some(sample) => Ok(sample),
+                None => sampled_vec.next().expect("Expected a sample."),
+            };
             let next_token = $crate::handle_seq_error_stateaware_ok!(sampled, seq);
 
             let eos_tok = if $disable_eos_stop {
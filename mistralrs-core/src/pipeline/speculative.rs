@@ -1,6 +1,6 @@
 use std::{
     any::Any,
-    iter::zip,
+    collections::VecDeque,
     sync::{Arc, Mutex},
 };
 
@@ -8,20 +8,25 @@ use anyhow::Result as anyhowResult;
 use candle_core::{quantized::GgmlDType, Device, IndexOp, Result, Tensor};
 use rand_isaac::Isaac64Rng;
 use tokenizers::Tokenizer;
+use tracing::info;
 
 use crate::{
+    device_map::device_with_ordinal,
     finish_and_add_tokens_to_seq, get_mut_arcmutex,
     pipeline::{
         sampling::{sample_sequence, sample_target_sequence_speculative},
-        AdapterInstruction, Cache,
+        AdapterInstruction, Cache, DraftTree,
     },
     prefix_cacher::PrefixCacheManager,
     sequence::{Sequence, SequenceRecognizer},
+    utils::debug::DeviceRepr,
     DeviceMapMetadata, Loader, ModelKind, Pipeline, TokenSource, TryIntoDType,
 };
 
 use super::{
-    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, sampling::SpeculativeSample,
+    cache_manager::DefaultCacheManager,
+    chat_template::ChatTemplate,
+    medusa::{get_medusa_paths, MedusaHeads, MEDUSA_WEIGHTS},
     AdapterActivationMixin, CacheInstruction, CacheManager, CacheManagerMixin, GeneralMetadata,
     IsqPipelineMixin, MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin,
 };
@@ -31,6 +36,31 @@ pub struct SpeculativeLoader {
     pub target: Box<dyn Loader>,
     pub draft: Box<dyn Loader>,
     pub config: SpeculativeConfig,
+    /// Ordinal of the device to load the draft model on, of the same kind as the device of the target model, so that
+    /// both fit when the target nearly fills its device. Defaults to the device of the target model.
+    pub draft_device_ordinal: Option<usize>,
+}
+
+impl SpeculativeLoader {
+    /// The device and device mapping of the draft model. The device layers of the target model are not applied to a
+    /// draft model on its own device.
+    fn draft_device(
+        &self,
+        device: &Device,
+        mapper: &DeviceMapMetadata,
+    ) -> Result<(Device, DeviceMapMetadata)> {
+        match self.draft_device_ordinal {
+            Some(ordinal) => {
+                let draft_device = device_with_ordinal(device, ordinal)?;
+                info!(
+                    "Loading the draft model on {}.",
+                    draft_device.device_pretty_repr()
+                );
+                Ok((draft_device, DeviceMapMetadata::dummy()))
+            }
+            None => Ok((device.clone(), mapper.clone())),
+        }
+    }
 }
 
 impl Loader for SpeculativeLoader {
@@ -45,22 +75,23 @@ impl Loader for SpeculativeLoader {
         mapper: DeviceMapMetadata,
         in_situ_quant: Option<GgmlDType>,
     ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        let (draft_device, draft_mapper) = self.draft_device(device, &mapper)?;
         let target = self.target.load_model_from_hf(
             revision.clone(),
             token_source.clone(),
             dtype,
             device,
             silent,
-            mapper.clone(),
+            mapper,
             in_situ_quant,
         )?;
         let draft = self.draft.load_model_from_hf(
             revision,
             token_source,
             dtype,
-            device,
+            &draft_device,
             silent,
-            mapper,
+            draft_mapper,
             in_situ_quant,
         )?;
         Ok(Arc::new(tokio::sync::Mutex::new(SpeculativePipeline::new(
@@ -80,20 +111,21 @@ impl Loader for SpeculativeLoader {
         mapper: DeviceMapMetadata,
         in_situ_quant: Option<GgmlDType>,
     ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        let (draft_device, draft_mapper) = self.draft_device(device, &mapper)?;
         let target = self.target.load_model_from_path(
             paths,
             dtype,
             device,
             silent,
-            mapper.clone(),
+            mapper,
             in_situ_quant,
         )?;
         let draft = self.draft.load_model_from_path(
             paths,
             dtype,
-            device,
+            &draft_device,
             silent,
-            mapper.clone(),
+            draft_mapper,
             in_situ_quant,
         )?;
         Ok(Arc::new(tokio::sync::Mutex::new(SpeculativePipeline::new(
@@ -118,6 +150,154 @@ impl Loader for SpeculativeLoader {
     }
 }
 
+/// A loader for a speculative pipeline whose draft tokens are found by prompt lookup, so only the target model is
+/// loaded.
+pub struct PromptLookupLoader {
+    pub target: Box<dyn Loader>,
+    pub config: SpeculativeConfig,
+    pub prompt_lookup: PromptLookupConfig,
+}
+
+impl Loader for PromptLookupLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<GgmlDType>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        let target = self.target.load_model_from_hf(
+            revision,
+            token_source,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            SpeculativePipeline::new_prompt_lookup(target, self.config, self.prompt_lookup)?,
+        )))
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<GgmlDType>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        let target = self.target.load_model_from_path(
+            paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            SpeculativePipeline::new_prompt_lookup(target, self.config, self.prompt_lookup)?,
+        )))
+    }
+    fn get_id(&self) -> String {
+        format!(
+            "Prompt lookup: tgt = `{}`, gamma = `{}`",
+            self.target.get_id(),
+            self.config.gamma,
+        )
+    }
+    fn get_kind(&self) -> ModelKind {
+        self.target.get_kind()
+    }
+}
+
+/// A loader for a speculative pipeline whose draft tokens are proposed by Medusa heads trained for the target model,
+/// so only the target model and its heads are loaded.
+pub struct MedusaLoader {
+    pub target: Box<dyn Loader>,
+    pub config: SpeculativeConfig,
+    /// Hugging Face repository or local directory of the Medusa heads, with their `config.json` and
+    /// `medusa_lm_head.safetensors`.
+    pub medusa_model_id: String,
+}
+
+impl Loader for MedusaLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<GgmlDType>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        let (config, weights) = get_medusa_paths(&self.medusa_model_id, &token_source, silent)?;
+        let target = self.target.load_model_from_hf(
+            revision,
+            token_source,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+        )?;
+        let heads = MedusaHeads::load(&config, &weights, dtype.try_into_dtype(device)?, device)?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            SpeculativePipeline::new_medusa(target, self.config, heads)?,
+        )))
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<GgmlDType>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        let target = self.target.load_model_from_path(
+            paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+        )?;
+        let medusa_dir = std::path::Path::new(&self.medusa_model_id);
+        let heads = MedusaHeads::load(
+            &medusa_dir.join("config.json"),
+            &medusa_dir.join(MEDUSA_WEIGHTS),
+            dtype.try_into_dtype(device)?,
+            device,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            SpeculativePipeline::new_medusa(target, self.config, heads)?,
+        )))
+    }
+    fn get_id(&self) -> String {
+        format!(
+            "Medusa: tgt = `{}`, heads = `{}`, gamma = `{}`",
+            self.target.get_id(),
+            self.medusa_model_id,
+            self.config.gamma,
+        )
+    }
+    fn get_kind(&self) -> ModelKind {
+        self.target.get_kind()
+    }
+}
+
 /// Speculative decoding pipeline: <https://arxiv.org/pdf/2211.17192>
 ///
 /// # Algorithm
@@ -129,19 +309,193 @@ impl Loader for SpeculativeLoader {
 /// - Else (q_i(x) > p_i(x)) accept that token with prob p_i(x)/q_i(x)
 ///     - If rejected, sample token from from p'_i(x) = norm(max(0, p(x) − q(x))) and do not take any more'
 ///
+///
+/// Instead of a draft model, the draft tokens may be found by prompt lookup, see [`PromptLookupConfig`], or proposed by
+/// Medusa heads, see [`MedusaLoader`]. They are then accepted if the target model samples them. With a
+/// [`SpeculativeConfig::tree_width`], several candidates are proposed per position as a [`DraftTree`], verified in one
+/// forward pass of the target model with a tree attention mask, and the longest path which the target model agrees
+/// with is accepted.
 pub struct SpeculativePipeline {
     target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
-    draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    draft: Draft,
     gamma: usize,
+    tree_width: Option<usize>,
     metadata: GeneralMetadata,
     category: ModelCategory,
+    adaptive_gamma: Option<AdaptiveGamma>,
+    stats: SpeculativeStats,
+}
+
+/// Where the draft tokens of a speculative pipeline come from.
+enum Draft {
+    Model(Arc<tokio::sync::Mutex<dyn Pipeline>>),
+    PromptLookup(PromptLookupConfig),
+    Medusa(MedusaHeads),
+}
+
+/// Number of recent steps the rolling acceptance rate is computed over.
+const STATS_WINDOW: usize = 64;
+
+#[derive(Clone, Debug, Default)]
+/// Counts of the draft tokens proposed and accepted by a speculative pipeline.
+pub struct SpeculativeStats {
+    /// Number of speculative steps run.
+    pub steps: usize,
+    /// Total number of draft tokens proposed.
+    pub proposed: usize,
+    /// Total number of draft tokens which the target model accepted.
+    pub accepted: usize,
+    /// The number of draft tokens proposed per step, which changes over time with [`AdaptiveGamma`].
+    pub gamma: usize,
+    // (proposed, accepted) for the last `STATS_WINDOW` steps
+    recent: VecDeque<(usize, usize)>,
+}
+
+impl SpeculativeStats {
+    fn record(&mut self, proposed: usize, accepted: usize) {
+        self.steps += 1;
+        self.proposed += proposed;
+        self.accepted += accepted;
+        if self.recent.len() == STATS_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((proposed, accepted));
+    }
+
+    /// Fraction of all proposed draft tokens which were accepted.
+    pub fn acceptance_rate(&self) -> f64 {
+        Self::rate(self.proposed, self.accepted)
+    }
+
+    /// Fraction of the draft tokens proposed over the last steps which were accepted.
+    pub fn rolling_acceptance_rate(&self) -> f64 {
+        let (proposed, accepted) = self
+            .recent
+            .iter()
+            .fold((0, 0), |(p, a), (proposed, accepted)| {
+                (p + proposed, a + accepted)
+            });
+        Self::rate(proposed, accepted)
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn rate(proposed: usize, accepted: usize) -> f64 {
+        if proposed == 0 {
+            0.
+        } else {
+            accepted as f64 / proposed as f64
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
 /// Metadata for a speculative pipeline
 pub struct SpeculativeConfig {
-    /// γ completions to run of the draft model
+    /// γ completions to run of the draft model. With `adaptive_gamma`, this is the initial value.
     pub gamma: usize,
+    /// Adjust γ based on how many draft tokens are accepted, instead of keeping it fixed.
+    pub adaptive_gamma: Option<AdaptiveGamma>,
+    /// With prompt lookup or Medusa heads, propose up to this many candidates at each draft position as a tree of
+    /// draft tokens, which the target model verifies in one step with tree attention. γ is then the depth of the tree.
+    /// Falls back to a single chain if the target model does not support tree attention.
+    pub tree_width: Option<usize>,
+}
+
+#[derive(Copy, Clone, Debug)]
+/// Bounds for adjusting γ with additive increase/multiplicative decrease: γ is incremented after a step in which all
+/// draft tokens were accepted, and halved after a step in which fewer than half of them were.
+pub struct AdaptiveGamma {
+    pub min_gamma: usize,
+    pub max_gamma: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+/// Prompt lookup decoding: the draft tokens are the ones which followed the last earlier occurrence of the trailing
+/// n-gram of the sequence, in the prompt or the generated tokens. The longest n-gram of at most `max_ngram_size`
+/// tokens with an earlier occurrence is used. No draft model is needed, which suits tasks whose output copies from
+/// their input, like summarization or code editing.
+pub struct PromptLookupConfig {
+    pub max_ngram_size: usize,
+}
+
+/// Propose up to `n` draft tokens for `toks` by prompt lookup, or none if no trailing n-gram occurs earlier.
+fn prompt_lookup(toks: &[u32], max_ngram_size: usize, n: usize) -> Vec<u32> {
+    prompt_lookup_paths(toks, max_ngram_size, n, 1)
+        .pop()
+        .unwrap_or_default()
+}
+
+/// The distinct continuations of up to `n` tokens after the most recent earlier occurrences of the longest trailing
+/// n-gram which occurs earlier, at most `max_paths` of them, most recent first.
+fn prompt_lookup_paths(
+    toks: &[u32],
+    max_ngram_size: usize,
+    n: usize,
+    max_paths: usize,
+) -> Vec<Vec<u32>> {
+    for ngram_size in (1..=max_ngram_size.min(toks.len().saturating_sub(1))).rev() {
+        let ngram = &toks[toks.len() - ngram_size..];
+        let mut paths: Vec<Vec<u32>> = Vec::new();
+        // The occurrences from the most recent, excluding the trailing n-gram itself
+        for (start, _) in toks[..toks.len() - 1]
+            .windows(ngram_size)
+            .enumerate()
+            .rev()
+            .filter(|(_, window)| *window == ngram)
+        {
+            let draft_start = start + ngram_size;
+            let path = &toks[draft_start..(draft_start + n).min(toks.len())];
+            if !paths.iter().any(|p| p == path) {
+                paths.push(path.to_vec());
+            }
+            if paths.len() == max_paths {
+                break;
+            }
+        }
+        if !paths.is_empty() {
+            return paths;
+        }
+    }
+    Vec::new()
+}
+
+/// Keep the KV cache entries of the given input positions of a tree of draft tokens, see [`DraftTree`], which was run
+/// after `initial_len` cached tokens.
+#[allow(clippy::cast_possible_truncation)]
+fn keep_tree_positions(
+    cache: &mut [Option<(Tensor, Tensor)>],
+    initial_len: usize,
+    positions: &[usize],
+) -> Result<()> {
+    let indices = positions
+        .iter()
+        .map(|pos| (initial_len + pos) as u32)
+        .collect::<Vec<_>>();
+    for (k, v) in cache.iter_mut().flatten() {
+        let indices = Tensor::new(indices.as_slice(), k.device())?;
+        *k = Tensor::cat(
+            &[k.narrow(2, 0, initial_len)?, k.index_select(&indices, 2)?],
+            2,
+        )?;
+        *v = Tensor::cat(
+            &[v.narrow(2, 0, initial_len)?, v.index_select(&indices, 2)?],
+            2,
+        )?;
+    }
+    Ok(())
+}
+
+impl AdaptiveGamma {
+    fn next_gamma(&self, gamma: usize, n_accepted: usize) -> usize {
+        let gamma = if n_accepted == gamma {
+            gamma + 1
+        } else if n_accepted < gamma / 2 {
+            gamma / 2
+        } else {
+            gamma
+        };
+        gamma.clamp(self.min_gamma, self.max_gamma)
+    }
 }
 
 impl SpeculativePipeline {
@@ -169,17 +523,91 @@ impl SpeculativePipeline {
         {
             candle_core::bail!("Target and draft models' input processors do not match. This is required for speculative decoding.");
         }
+        Self::with_draft(target, Draft::Model(draft), config)
+    }
+
+    /// A speculative pipeline whose draft tokens are found by prompt lookup instead of a draft model.
+    pub fn new_prompt_lookup(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        config: SpeculativeConfig,
+        prompt_lookup: PromptLookupConfig,
+    ) -> Result<Self> {
+        if prompt_lookup.max_ngram_size == 0 {
+            candle_core::bail!("The maximum n-gram size of prompt lookup must be at least 1.");
+        }
+        Self::with_draft(target, Draft::PromptLookup(prompt_lookup), config)
+    }
+
+    /// A speculative pipeline whose draft tokens are proposed by Medusa heads on the final hidden states of the target
+    /// model. Each head proposes one draft token, so gamma is at most the number of heads.
+    pub(crate) fn new_medusa(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        config: SpeculativeConfig,
+        heads: MedusaHeads,
+    ) -> Result<Self> {
+        let max_gamma = config
+            .adaptive_gamma
+            .map_or(config.gamma, |adaptive| adaptive.max_gamma);
+        if max_gamma > heads.num_heads() {
+            candle_core::bail!(
+                "Gamma cannot be more than the {} Medusa heads, got {max_gamma}.",
+                heads.num_heads()
+            );
+        }
+        if get_mut_arcmutex!(target).category() != ModelCategory::Text {
+            candle_core::bail!("Medusa heads are only supported for text models.");
+        }
+        Self::with_draft(target, Draft::Medusa(heads), config)
+    }
+
+    fn with_draft(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        draft: Draft,
+        config: SpeculativeConfig,
+    ) -> Result<Self> {
+        let gamma = if let Some(adaptive) = config.adaptive_gamma {
+            if adaptive.min_gamma == 0 || adaptive.min_gamma > adaptive.max_gamma {
+                candle_core::bail!(
+                    "Adaptive gamma bounds must satisfy 0 < min_gamma <= max_gamma, got {adaptive:?}."
+                );
+            }
+            config.gamma.clamp(adaptive.min_gamma, adaptive.max_gamma)
+        } else {
+            config.gamma
+        };
+        let tree_width = match config.tree_width {
+            Some(0) => candle_core::bail!("The tree width must be at least 1."),
+            Some(_) if matches!(draft, Draft::Model(_)) => candle_core::bail!(
+                "A tree of draft tokens is only supported with prompt lookup or Medusa heads."
+            ),
+            Some(width) if !get_mut_arcmutex!(target).supports_tree_attention() => {
+                tracing::warn!("The target model does not support tree attention, so a chain of draft tokens is proposed instead of a tree of width {width}.");
+                None
+            }
+            width => width,
+        };
         let metadata = get_mut_arcmutex!(target).get_metadata().clone();
         let category = get_mut_arcmutex!(target).category();
         // TODO: some checks or relaxation here?
         Ok(Self {
             target,
             draft,
-            gamma: config.gamma,
+            gamma,
+            tree_width,
             metadata,
             category,
+            adaptive_gamma: config.adaptive_gamma,
+            stats: SpeculativeStats {
+                gamma,
+                ..Default::default()
+            },
         })
     }
+
+    /// Draft token acceptance statistics, to judge whether the draft model and `gamma` are worth their overhead.
+    pub fn stats(&self) -> &SpeculativeStats {
+        &self.stats
+    }
 }
 
 impl PreProcessingMixin for SpeculativePipeline {
@@ -194,29 +622,32 @@ impl PreProcessingMixin for SpeculativePipeline {
 impl IsqPipelineMixin for SpeculativePipeline {
     fn re_isq_model(&mut self, dtype: GgmlDType) -> anyhow::Result<()> {
         get_mut_arcmutex!(self.target).re_isq_model(dtype)?;
-        get_mut_arcmutex!(self.draft).re_isq_model(dtype)
+        match &self.draft {
+            Draft::Model(draft) => get_mut_arcmutex!(draft).re_isq_model(dtype),
+            Draft::PromptLookup(_) | Draft::Medusa(_) => Ok(()),
+        }
     }
 }
 
+// The draft model always keeps its KV caches in the draft caches of the sequences and the target model in their
+// normal caches, so the two stay independent, even with the models on different devices.
 impl CacheManagerMixin for SpeculativePipeline {
-    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_in_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-        );
-        DefaultCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.target), seqs, false);
+    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], _modify_draft_cache: bool) -> Result<()> {
+        if let Draft::Model(draft) = &self.draft {
+            DefaultCacheManager.clone_in_cache(&*get_mut_arcmutex!(draft), seqs, true)?;
+        }
+        DefaultCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.target), seqs, false)
     }
-    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_out_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-        );
-        DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.target), seqs, false);
+    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], _modify_draft_cache: bool) -> Result<()> {
+        if let Draft::Model(draft) = &self.draft {
+            DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(draft), seqs, true)?;
+        }
+        DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.target), seqs, false)
     }
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
-        DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.draft), modify_draft_cache);
+        if let Draft::Model(draft) = &self.draft {
+            DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(draft), modify_draft_cache);
+        }
         DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.target), false);
         if reset_non_granular {
             self.reset_non_granular_state()
@@ -231,10 +662,56 @@ impl AdapterActivationMixin for SpeculativePipeline {
     /// Returns the number of activated adapters.
     fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
         let mut res = 0;
-        res += get_mut_arcmutex!(self.draft).activate_adapters(adapters.clone())?;
+        if let Draft::Model(draft) = &self.draft {
+            res += get_mut_arcmutex!(draft).activate_adapters(adapters.clone())?;
+        }
         res += get_mut_arcmutex!(self.target).activate_adapters(adapters)?;
         Ok(res)
     }
+    /// Returns the number of LoRA layers the adapter was added to.
+    fn add_adapter(&mut self, name: String, path: &std::path::Path) -> anyhow::Result<usize> {
+        let mut res = 0;
+        if let Draft::Model(draft) = &self.draft {
+            res += get_mut_arcmutex!(draft).add_adapter(name.clone(), path)?;
+        }
+        res += get_mut_arcmutex!(self.target).add_adapter(name, path)?;
+        Ok(res)
+    }
+    /// Returns the number of LoRA layers merged.
+    fn merge_adapter(&mut self, name: String) -> anyhow::Result<usize> {
+        let mut res = 0;
+        if let Draft::Model(draft) = &self.draft {
+            res += get_mut_arcmutex!(draft).merge_adapter(name.clone())?;
+        }
+        res += get_mut_arcmutex!(self.target).merge_adapter(name)?;
+        Ok(res)
+    }
+    /// Returns the number of LoRA layers unmerged.
+    fn unmerge_adapter(&mut self) -> anyhow::Result<usize> {
+        let mut res = 0;
+        if let Draft::Model(draft) = &self.draft {
+            res += get_mut_arcmutex!(draft).unmerge_adapter()?;
+        }
+        res += get_mut_arcmutex!(self.target).unmerge_adapter()?;
+        Ok(res)
+    }
+    /// Returns the number of LoRA layers scaled. Only the LoRA models are scaled.
+    fn set_lora_scale(&mut self, scale: f64) -> anyhow::Result<usize> {
+        let is_lora =
+            |pipeline: &dyn Pipeline| pipeline.get_metadata().kind.is_adapted_and(|a| a.is_lora());
+        let mut res = 0;
+        if let Draft::Model(draft) = &self.draft {
+            let mut draft = get_mut_arcmutex!(draft);
+            if is_lora(&*draft) {
+                res += draft.set_lora_scale(scale)?;
+            }
+        }
+        let mut target = get_mut_arcmutex!(self.target);
+        if is_lora(&*target) {
+            res += target.set_lora_scale(scale)?;
+        }
+        Ok(res)
+    }
 }
 
 impl MetadataMixin for SpeculativePipeline {
@@ -245,20 +722,48 @@ impl MetadataMixin for SpeculativePipeline {
         get_mut_arcmutex!(self.target).tokenizer()
     }
     fn name(&self) -> String {
-        format!(
-            "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
-            get_mut_arcmutex!(self.target).name(),
-            get_mut_arcmutex!(self.draft).name(),
-            self.gamma,
-        )
+        match &self.draft {
+            Draft::Model(draft) => format!(
+                "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                get_mut_arcmutex!(draft).name(),
+                self.gamma,
+            ),
+            Draft::PromptLookup(_) => format!(
+                "Prompt lookup: tgt = `{}`, gamma = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                self.gamma,
+            ),
+            Draft::Medusa(_) => format!(
+                "Medusa: tgt = `{}`, gamma = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                self.gamma,
+            ),
+        }
     }
     fn reset_non_granular_state(&self) {
         get_mut_arcmutex!(self.target).reset_non_granular_state();
-        get_mut_arcmutex!(self.draft).reset_non_granular_state();
+        if let Draft::Model(draft) = &self.draft {
+            get_mut_arcmutex!(draft).reset_non_granular_state();
+        }
     }
     fn get_metadata(&self) -> &GeneralMetadata {
         &self.metadata
     }
+    fn set_kv_cache_bits(&mut self, bits: Option<u8>) {
+        self.metadata.kv_cache_bits = bits;
+        get_mut_arcmutex!(self.target).set_kv_cache_bits(bits);
+        if let Draft::Model(draft) = &self.draft {
+            get_mut_arcmutex!(draft).set_kv_cache_bits(bits);
+        }
+    }
+    fn set_kv_cache_block_size(&mut self, block_size: Option<usize>) -> anyhow::Result<()> {
+        // The speculative steps roll back the KV caches of the sequences themselves
+        if block_size.is_some() {
+            anyhow::bail!("Speculative decoding does not support a paged KV cache.");
+        }
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -298,7 +803,7 @@ impl Pipeline for SpeculativePipeline {
                     }
                     AdapterInstruction::None => 0,
                 };
-                self.clone_in_cache(input_seqs, false)
+                self.clone_in_cache(input_seqs, false)?
             }
             CacheInstruction::Nothing(adapter_inst) => {
                 match adapter_inst {
@@ -330,169 +835,272 @@ impl Pipeline for SpeculativePipeline {
             }
             _ => unreachable!("Unreachable PRE cache op."),
         }
+        if self.metadata.kind.is_adapted_and(|a| a.is_lora()) {
+            self.set_lora_scale(f64::from(input_seqs[0].lora_scale()))
+                .map_err(|e| {
+                    candle_core::Error::msg(
+                        <anyhow::Error as AsRef<dyn std::error::Error>>::as_ref(&e),
+                    )
+                })?;
+        }
 
         assert_eq!(input_seqs.len(), 1);
 
         let seq = &mut input_seqs[0];
+        let repeat_last_n = self.metadata.repeat_last_n;
+        let tok_trie = self.metadata.tok_trie.clone();
+
+        // ======================= Verify a tree of draft tokens ============================
+        // The prompt is verified with a chain of draft tokens, as the tree is run after the cached tokens
+        let (accepted_tokens, n_proposed, n_draft_accepted) = if let Some(width) =
+            self.tree_width.filter(|_| !is_prompt)
+        {
+            let tree = match &self.draft {
+                Draft::PromptLookup(config) => DraftTree::from_paths(&prompt_lookup_paths(
+                    seq.get_toks(),
+                    config.max_ngram_size,
+                    self.gamma,
+                    width,
+                )),
+                Draft::Medusa(heads) => match seq.medusa_hidden_state().clone() {
+                    Some(hidden_state) => heads.propose_tree(&hidden_state, self.gamma, width)?,
+                    None => DraftTree::default(),
+                },
+                Draft::Model(_) => unreachable!("A draft model proposes a chain of draft tokens."),
+            };
+
+            let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
+                .as_ref()
+                .map(|(k, _)| k.dims()[2])
+                .unwrap_or(0);
+            let root = *seq.get_toks().last().unwrap();
+            let (logits, hidden_states) =
+                get_mut_arcmutex!(self.target).forward_tree(root, &tree)?;
+
+            // Walk down the tree while the target model samples the token of a child
+            let mut accepted_tokens = Vec::new();
+            let mut path = Vec::new();
+            loop {
+                let position = DraftTree::input_position(path.last().copied());
+                let sample = sample_sequence(
+                    logits.narrow(1, position, 1)?,
+                    seq,
+                    seq.return_logprobs(),
+                    repeat_last_n,
+                    tok_trie.clone(),
+                    rng.clone(),
+                    true,
+                    false, // Do not append to trie (yet)
+                    true,
+                )
+                .await?;
+                let child = tree.child_with_token(path.last().copied(), sample.token);
+                accepted_tokens.push(sample);
+                match child {
+                    Some(node) => path.push(node),
+                    None => break,
+                }
+            }
+            if matches!(self.draft, Draft::Medusa(_)) {
+                *seq.medusa_hidden_state() =
+                    Some(hidden_states.i((0, DraftTree::input_position(path.last().copied())))?);
+            }
+
+            // ======================= Keep the cache of the accepted path ============================
+            keep_tree_positions(
+                &mut get_mut_arcmutex!(self.target).cache().lock(),
+                initial_cache_len,
+                &DraftTree::kept_positions(&path),
+            )?;
+            (accepted_tokens, tree.depth(), path.len())
+        } else {
+            // ======================= Propose up to gamma draft tokens ============================
+            let draft_toks = match &self.draft {
+                Draft::Model(draft) => {
+                    // Run the draft model gamma times, sampling from its distributions
+                    let mut draft_toks = Vec::new();
+                    let repeat_last_n = get_mut_arcmutex!(draft).get_metadata().repeat_last_n;
+                    for i in 0..self.gamma {
+                        let is_xlora = get_mut_arcmutex!(draft).get_metadata().is_xlora;
+                        // Only the token ids cross between the devices of the draft and target models
+                        let device = get_mut_arcmutex!(draft).device();
+                        let has_no_kv_cache =
+                            get_mut_arcmutex!(draft).get_metadata().has_no_kv_cache;
+                        let inputs = self
+                            .get_processor()
+                            .inputs_processor()
+                            .process_inputs(
+                                self.tokenizer(),
+                                &mut [seq],
+                                is_prompt && i == 0, // Only prompt (no kv cache) if first
+                                is_xlora,
+                                &device,
+                                has_no_kv_cache,
+                                None,
+                                None,
+                            )
+                            .unwrap();
+                        let logits = get_mut_arcmutex!(draft).forward_inputs(Box::new(inputs))?;
+
+                        let sample = sample_sequence(
+                            logits.clone(),
+                            seq,
+                            seq.return_logprobs(),
+                            repeat_last_n,
+                            get_mut_arcmutex!(draft).get_metadata().tok_trie.clone(),
+                            rng.clone(),
+                            false, // todo tune
+                            false, // do not add to tok trie yet
+                            true,
+                        )
+                        .await?;
+                        seq.add_tmp_tok(sample.token);
+                        draft_toks.push(sample.token);
+                    }
+                    seq.remove_tmp_tok(self.gamma);
+                    draft_toks
+                }
+                Draft::PromptLookup(config) => {
+                    prompt_lookup(seq.get_toks(), config.max_ngram_size, self.gamma)
+                }
+                // There is no hidden state to propose from before the first step
+                Draft::Medusa(heads) => match seq.medusa_hidden_state().clone() {
+                    Some(hidden_state) => heads.propose(&hidden_state, self.gamma)?,
+                    None => Vec::new(),
+                },
+            };
+
+            // ======================= Run the target model on the draft tokens ============================
+            // The draft model was not run on its last draft token, so neither is the target: its logits after the other
+            // tokens verify all the draft tokens. Without a draft model, every draft token is run, and the logits after the
+            // last one give a token for free when all of them are accepted.
+            let mut target_prefill_tokens = if is_prompt {
+                seq.get_toks().to_vec()
+            } else {
+                vec![*seq.get_toks().last().unwrap()]
+            };
+            let n_logits = match self.draft {
+                Draft::Model(_) => {
+                    target_prefill_tokens.extend(&draft_toks[..draft_toks.len().saturating_sub(1)]);
+                    draft_toks.len()
+                }
+                Draft::PromptLookup(_) | Draft::Medusa(_) => {
+                    target_prefill_tokens.extend(&draft_toks);
+                    draft_toks.len() + 1
+                }
+            };
+            seq.set_prefill_toks(target_prefill_tokens);
 
-        // ======================= Run draft model gamma times producing tokens ============================
-        // ======================= Sample the `gamma` logits. ============================
-        let mut draft_samples = Vec::new();
-        let repeat_last_n = get_mut_arcmutex!(self.draft).get_metadata().repeat_last_n;
-        for i in 0..self.gamma {
-            let is_xlora = get_mut_arcmutex!(self.draft).get_metadata().is_xlora;
-            let device = get_mut_arcmutex!(self.draft).device();
-            let has_no_kv_cache = get_mut_arcmutex!(self.draft).get_metadata().has_no_kv_cache;
+            let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
+                .as_ref()
+                .map(|(k, _)| k.dims()[2])
+                .unwrap_or(0);
+
+            // ========= Run the model ============
+            let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
+            let device = get_mut_arcmutex!(self.target).device();
+            let has_no_kv_cache = get_mut_arcmutex!(self.target)
+                .get_metadata()
+                .has_no_kv_cache;
             let inputs = self
                 .get_processor()
                 .inputs_processor()
                 .process_inputs(
                     self.tokenizer(),
                     &mut [seq],
-                    is_prompt && i == 0, // Only prompt (no kv cache) if first
+                    true, // use the "prefill" tokens
                     is_xlora,
                     &device,
                     has_no_kv_cache,
-                    None,
+                    Some((n_logits, initial_cache_len)), // Get the last n_logits, see above
                     None,
                 )
                 .unwrap();
-            let logits = get_mut_arcmutex!(self.draft).forward_inputs(Box::new(inputs))?;
 
-            let sample = sample_sequence(
+            // Medusa heads propose the next draft tokens from the hidden state of the last accepted token
+            let (logits, hidden_states) = if matches!(self.draft, Draft::Medusa(_)) {
+                let (logits, hidden_states) = get_mut_arcmutex!(self.target)
+                    .forward_inputs_with_hidden_states(Box::new(inputs))?;
+                (logits, Some(hidden_states))
+            } else {
+                (
+                    get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs))?,
+                    None,
+                )
+            };
+
+            // Reset the prefill tokens
+            seq.reset_prefill_toks();
+
+            // ======================= Rejection sampling. ============================
+            // Map from each target sample to corresponding in draft sample
+            let samples = sample_target_sequence_speculative(
                 logits.clone(),
                 seq,
                 seq.return_logprobs(),
                 repeat_last_n,
-                get_mut_arcmutex!(self.draft)
-                    .get_metadata()
-                    .tok_trie
-                    .clone(),
+                tok_trie,
                 rng.clone(),
-                false, // todo tune
-                false, // do not add to tok trie yet
-                true,
+                n_logits,
             )
             .await?;
-            seq.add_tmp_tok(sample.token);
-            draft_samples.push(SpeculativeSample { sample });
-        }
-        seq.remove_tmp_tok(self.gamma);
 
-        // ======================= Add all draft tokens but the last one. Add the last from the seq. ============================
-        let mut draft_prefill_tokens = if is_prompt {
-            seq.get_toks().to_vec()
-        } else {
-            vec![*seq.get_toks().last().unwrap()]
-        };
-        for (i, sample) in draft_samples.iter().enumerate() {
-            if i == draft_samples.len() - 1 {
-                continue;
+            let mut accepted_tokens = Vec::new();
+            let mut n_draft_accepted = 0;
+            for (i, target_sample) in samples.into_iter().enumerate() {
+                let tok = target_sample.sample.token;
+                accepted_tokens.push(target_sample.sample);
+                if draft_toks.get(i) != Some(&tok) {
+                    break;
+                }
+                n_draft_accepted += 1;
             }
-            draft_prefill_tokens.push(sample.sample.token);
-        }
-        seq.set_prefill_toks(draft_prefill_tokens);
-
-        // ======================= Run the model with all draft tokens. ============================
-
-        let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
-            .as_ref()
-            .map(|(k, _)| k.dims()[2])
-            .unwrap_or(0);
-
-        // ========= Run the model ============
-        let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
-        let device = get_mut_arcmutex!(self.target).device();
-        let has_no_kv_cache = get_mut_arcmutex!(self.target)
-            .get_metadata()
-            .has_no_kv_cache;
-        let inputs = self
-            .get_processor()
-            .inputs_processor()
-            .process_inputs(
-                self.tokenizer(),
-                &mut [seq],
-                true, // use the "prefill" tokens
-                is_xlora,
-                &device,
-                has_no_kv_cache,
-                Some((self.gamma, initial_cache_len)), // Get the last gamma, see above
-                None,
-            )
-            .unwrap();
-
-        let logits = get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs))?;
-
-        // Reset the prefill tokens
-        seq.reset_prefill_toks();
-
-        // ======================= Rejection sampling. ============================
-        // Map from each target sample to corresponding in draft sample
-        let samples = sample_target_sequence_speculative(
-            logits.clone(),
-            seq,
-            seq.return_logprobs(),
-            repeat_last_n,
-            get_mut_arcmutex!(self.draft)
-                .get_metadata()
-                .tok_trie
-                .clone(),
-            rng.clone(),
-            self.gamma,
-        )
-        .await?;
-
-        let mut accepted_tokens = Vec::new();
-        for (target_sample, draft_sample) in zip(samples, draft_samples) {
-            let tok = target_sample.sample.token;
-            accepted_tokens.push(target_sample.sample);
-            if draft_sample.sample.token != tok {
-                break;
+            if let Some(hidden_states) = hidden_states {
+                *seq.medusa_hidden_state() = Some(hidden_states.i((0, accepted_tokens.len() - 1))?);
             }
-        }
 
-        // ======================= Narrow caches to account for rejections ============================
-        let n_not_accepted = self.gamma - accepted_tokens.len();
-        for (k, v) in get_mut_arcmutex!(self.draft)
-            .cache()
-            .lock()
-            .iter_mut()
-            .flatten()
-        {
-            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-        }
-        if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-            for (k, v) in get_mut_arcmutex!(self.draft)
-                .cache()
-                .xlora_lock()
-                .iter_mut()
-                .flatten()
-            {
-                *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+            // ======================= Narrow caches to account for rejections ============================
+            let n_not_accepted = n_logits - accepted_tokens.len();
+            if let Draft::Model(draft) = &self.draft {
+                for (k, v) in get_mut_arcmutex!(draft).cache().lock().iter_mut().flatten() {
+                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                }
+                if get_mut_arcmutex!(draft).get_metadata().is_xlora {
+                    for (k, v) in get_mut_arcmutex!(draft)
+                        .cache()
+                        .xlora_lock()
+                        .iter_mut()
+                        .flatten()
+                    {
+                        *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                        *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                    }
+                }
             }
-        }
-        for (k, v) in get_mut_arcmutex!(self.target)
-            .cache()
-            .lock()
-            .iter_mut()
-            .flatten()
-        {
-            *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-            *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-        }
-        if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
             for (k, v) in get_mut_arcmutex!(self.target)
                 .cache()
-                .xlora_lock()
+                .lock()
                 .iter_mut()
                 .flatten()
             {
                 *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
                 *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
             }
-        }
+            if self.metadata.is_xlora {
+                for (k, v) in get_mut_arcmutex!(self.target)
+                    .cache()
+                    .xlora_lock()
+                    .iter_mut()
+                    .flatten()
+                {
+                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
+                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
+                }
+            }
+            (accepted_tokens, draft_toks.len(), n_draft_accepted)
+        };
+        self.stats.record(n_proposed, n_draft_accepted);
 
         let eos_owned = get_mut_arcmutex!(self.target)
             .get_metadata()
@@ -546,7 +1154,7 @@ impl Pipeline for SpeculativePipeline {
 
         match post_op {
             CacheInstruction::Out => {
-                self.clone_out_cache(input_seqs, true);
+                self.clone_out_cache(input_seqs, true)?;
             }
             CacheInstruction::Nothing(_) => (),
             CacheInstruction::Reset {
@@ -557,17 +1165,97 @@ impl Pipeline for SpeculativePipeline {
         }
 
         // Done! We have:
-        // - Run the draft model gamma times
+        // - Proposed up to gamma draft tokens, with the draft model, by prompt lookup or with Medusa heads, maybe as a
+        //   tree of draft tokens
         // - Reset draft model cache fully
-        // - Sampled draft model's distributions
         // - Run target model
         // - Execute speculative decoding algorithm on the resulting distributions
         // - Added the accepted tokens to buffer and trie
         // - Maybe fixed up cache of base model based on accepted tokens.
 
+        // Prompt lookup and Medusa heads may propose fewer draft tokens than gamma, which says nothing about gamma
+        if let Some(adaptive) = self.adaptive_gamma.filter(|_| n_proposed == self.gamma) {
+            self.gamma = adaptive.next_gamma(self.gamma, n_draft_accepted);
+            self.stats.gamma = self.gamma;
+        }
+
         Ok(())
     }
     fn category(&self) -> ModelCategory {
         self.category
     }
+    fn supports_chunked_prefill(&self) -> bool {
+        // The draft and target caches are only kept in sync by whole prompt steps
+        false
+    }
+    fn supports_prompt_logprobs(&self) -> bool {
+        // Only the last `gamma` logits of the target are computed
+        false
+    }
+    fn supports_beam_search(&self) -> bool {
+        // Tokens are sampled from the draft model and verified, not chosen across beams
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn rolling_acceptance_rate_forgets_old_steps() {
+        use super::{SpeculativeStats, STATS_WINDOW};
+
+        let mut stats = SpeculativeStats::default();
+        stats.record(4, 0);
+        assert_eq!(stats.rolling_acceptance_rate(), 0.);
+        for _ in 0..STATS_WINDOW {
+            stats.record(4, 4);
+        }
+        assert_eq!(stats.steps, STATS_WINDOW + 1);
+        assert_eq!(stats.rolling_acceptance_rate(), 1.);
+        assert!(stats.acceptance_rate() < 1.);
+    }
+
+    #[test]
+    fn adaptive_gamma_aimd() {
+        use super::AdaptiveGamma;
+
+        let adaptive = AdaptiveGamma {
+            min_gamma: 2,
+            max_gamma: 8,
+        };
+        assert_eq!(adaptive.next_gamma(4, 4), 5);
+        assert_eq!(adaptive.next_gamma(8, 8), 8);
+        assert_eq!(adaptive.next_gamma(4, 2), 4);
+        assert_eq!(adaptive.next_gamma(8, 1), 4);
+        assert_eq!(adaptive.next_gamma(3, 0), 2);
+    }
+
+    #[test]
+    fn prompt_lookup_copies_after_last_match() {
+        use super::prompt_lookup;
+
+        // The trailing bigram `1 2` last occurred at position 3, followed by `5 6`
+        let toks = [1, 2, 3, 1, 2, 5, 6, 1, 2];
+        assert_eq!(prompt_lookup(&toks, 2, 2), vec![5, 6]);
+        // The drafts stop at the end of the sequence
+        assert_eq!(prompt_lookup(&toks, 2, 8), vec![5, 6, 1, 2]);
+        // Shorter n-grams are tried when the longer ones do not occur earlier
+        assert_eq!(prompt_lookup(&[7, 8, 9, 4, 9], 3, 2), vec![4, 9]);
+        assert!(prompt_lookup(&[1, 2, 3], 3, 4).is_empty());
+        assert!(prompt_lookup(&[1], 3, 4).is_empty());
+    }
+
+    #[test]
+    fn prompt_lookup_paths_after_several_matches() {
+        use super::prompt_lookup_paths;
+
+        // `1 2` occurred at positions 0, 3 and 6, the last two followed by the same tokens
+        let toks = [1, 2, 3, 1, 2, 5, 1, 2, 5, 1, 2];
+        assert_eq!(
+            prompt_lookup_paths(&toks, 2, 2, 3),
+            vec![vec![5, 1], vec![3, 1]]
+        );
+        assert_eq!(prompt_lookup_paths(&toks, 2, 2, 1), vec![vec![5, 1]]);
+        assert!(prompt_lookup_paths(&[1, 2, 3], 2, 2, 3).is_empty());
+    }
 }
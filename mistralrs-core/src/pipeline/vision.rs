@@ -1,14 +1,14 @@
-use super::cache_manager::DefaultCacheManager;
+use super::cache_manager::cache_manager;
 use super::vision_loaders::{Idefics2Loader, Phi3VLoader, VisionLoaderType};
 use super::{
     get_model_paths, get_xlora_paths, AdapterActivationMixin, Cache, CacheManager,
-    CacheManagerMixin, GeneralMetadata, IsqPipelineMixin, Loader, MetadataMixin, ModelCategory,
-    ModelKind, ModelPaths, PreProcessingMixin, Processor, TokenSource, VisionModel,
-    VisionModelLoader, XLoraPaths,
+    CacheManagerMixin, FimTokens, GeneralMetadata, IsqOverride, IsqPipelineMixin, Loader,
+    MetadataMixin, ModelCategory, ModelKind, ModelPaths, PreProcessingMixin, Processor,
+    TokenSource, VisionModel, VisionModelLoader, XLoraPaths,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
-use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig};
+use crate::pipeline::chat_template::{calculate_eos_tokens, GenerationConfig, PromptSpecialTokens};
 use crate::pipeline::{get_chat_template, ChatTemplate, LocalModelPaths};
 use crate::prefix_cacher::PrefixCacheManager;
 use crate::sequence::Sequence;
@@ -45,6 +45,7 @@ pub struct VisionPipeline {
     metadata: GeneralMetadata,
     processor: Arc<dyn Processor + Send + Sync>,
     preprocessor_config: Arc<PreProcessorConfig>,
+    isq_overrides: Vec<IsqOverride>,
 }
 
 /// A loader for a vision (non-quantized) model.
@@ -57,6 +58,7 @@ pub struct VisionLoader {
     tokenizer_json: Option<String>,
     xlora_model_id: Option<String>,
     xlora_order: Option<Ordering>,
+    isq_overrides: Vec<IsqOverride>,
 }
 
 #[derive(Default)]
@@ -67,6 +69,7 @@ pub struct VisionLoaderBuilder {
     kind: ModelKind,
     chat_template: Option<String>,
     tokenizer_json: Option<String>,
+    isq_overrides: Vec<IsqOverride>,
 }
 
 #[derive(Clone, Copy, Default)]
@@ -89,9 +92,16 @@ impl VisionLoaderBuilder {
             tokenizer_json,
             model_id,
             kind: ModelKind::Normal,
+            isq_overrides: Vec::new(),
         }
     }
 
+    /// Quantize the tensors matching these overrides to their dtype instead of the ISQ dtype.
+    pub fn with_isq_overrides(mut self, isq_overrides: Vec<IsqOverride>) -> Self {
+        self.isq_overrides = isq_overrides;
+        self
+    }
+
     pub fn build(self, loader: VisionLoaderType) -> Box<dyn Loader> {
         let loader: Box<dyn VisionModelLoader> = match loader {
             VisionLoaderType::Phi3V => Box::new(Phi3VLoader),
@@ -106,6 +116,7 @@ impl VisionLoaderBuilder {
             tokenizer_json: self.tokenizer_json,
             xlora_model_id: None,
             xlora_order: None,
+            isq_overrides: self.isq_overrides,
         })
     }
 }
@@ -144,6 +155,9 @@ impl Loader for VisionLoader {
         mapper: DeviceMapMetadata,
         in_situ_quant: Option<GgmlDType>,
     ) -> Result<Arc<Mutex<dyn Pipeline + Send + Sync>>> {
+        if mapper.is_tensor_parallel() {
+            anyhow::bail!("Vision models do not support tensor parallelism.");
+        }
         let config = std::fs::read_to_string(paths.get_config_filename())?;
         let dtype = dtype.try_into_dtype(device)?;
 
@@ -214,13 +228,15 @@ impl Loader for VisionLoader {
         let chat_template = get_chat_template(paths, &self.chat_template, None);
 
         if let Some(in_situ_quant) = in_situ_quant {
-            model.quantize(in_situ_quant, device.clone())?;
+            model.quantize(in_situ_quant, device.clone(), &self.isq_overrides)?;
         }
 
         let max_seq_len = model.max_seq_len();
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
         let num_hidden_layers = model.cache().lock().len();
         let eos = calculate_eos_tokens(&chat_template, gen_conf, &tokenizer);
+        let fim_tokens = FimTokens::from_tokenizer(&tokenizer);
+        let prompt_special_tokens = PromptSpecialTokens::new(&chat_template, &tokenizer);
         Ok(Arc::new(Mutex::new(VisionPipeline {
             model,
             tok_trie: tok_trie.clone(),
@@ -236,9 +252,15 @@ impl Loader for VisionLoader {
                 eos_tok: eos,
                 kind: self.kind.clone(),
                 has_no_kv_cache: false,
+                kv_cache_bits: None,
+                kv_cache_block_size: None,
+                kv_cache_shape: None,
+                fim_tokens,
+                prompt_special_tokens,
             },
             processor,
             preprocessor_config: Arc::new(preprocessor_config),
+            isq_overrides: self.isq_overrides.clone(),
         })))
     }
 
@@ -267,20 +289,28 @@ impl IsqPipelineMixin for VisionPipeline {
     fn re_isq_model(&mut self, dtype: GgmlDType) -> Result<()> {
         let device = self.device().clone();
         self.model
-            .quantize(dtype, device)
+            .quantize(dtype, device, &self.isq_overrides)
             .map_err(anyhow::Error::msg)
     }
 }
 
 impl CacheManagerMixin for VisionPipeline {
-    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_in_cache(self, seqs, modify_draft_cache)
+    fn clone_in_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> Result<(), candle_core::Error> {
+        cache_manager(self).clone_in_cache(self, seqs, modify_draft_cache)
     }
-    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_out_cache(self, seqs, modify_draft_cache)
+    fn clone_out_cache(
+        &self,
+        seqs: &mut [&mut Sequence],
+        modify_draft_cache: bool,
+    ) -> Result<(), candle_core::Error> {
+        cache_manager(self).clone_out_cache(self, seqs, modify_draft_cache)
     }
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
-        DefaultCacheManager.set_none_cache(self, modify_draft_cache);
+        cache_manager(self).set_none_cache(self, modify_draft_cache);
         if reset_non_granular {
             self.reset_non_granular_state()
         }
@@ -303,6 +333,14 @@ impl MetadataMixin for VisionPipeline {
     fn get_metadata(&self) -> &GeneralMetadata {
         &self.metadata
     }
+    fn set_kv_cache_bits(&mut self, bits: Option<u8>) {
+        self.metadata.kv_cache_bits = bits;
+    }
+    fn set_kv_cache_block_size(&mut self, block_size: Option<usize>) -> anyhow::Result<()> {
+        self.metadata.kv_cache_block_size = block_size;
+        self.cache().set_block_size(block_size);
+        Ok(())
+    }
     fn name(&self) -> String {
         self.model_id.clone()
     }
@@ -1,196 +1,1277 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    thread::{self, JoinHandle},
+};
 
-use candle_core::{Device, Result, Tensor};
+use candle_core::{DType, Device, Result, Tensor};
 use radix_trie::{Trie, TrieCommon, TrieKey};
+use tracing::{debug_span, field};
 
-use crate::{get_mut_arcmutex, pipeline::LayerCaches, sequence::Sequence};
+use crate::{
+    get_mut_arcmutex,
+    pinned::PinnedHostBuffer,
+    pipeline::{KvBlock, LayerCaches},
+    sequence::Sequence,
+};
 
-#[derive(PartialEq, Eq)]
-struct Tokens(Vec<u32>);
+/// The tokens of a cache and the adapter set it was computed with. The adapter set comes first in the encoded key,
+/// so a cache is only ever the prefix of caches computed with the same adapters.
+#[derive(Clone, PartialEq, Eq)]
+struct CacheKey {
+    /// Index of the adapter set in `PrefixCacheManager::adapter_sets` plus one, 0 without adapters.
+    adapters: u32,
+    toks: Vec<u32>,
+}
 
-impl TrieKey for Tokens {
+impl TrieKey for CacheKey {
     fn encode_bytes(&self) -> Vec<u8> {
-        self.0
-            .iter()
+        std::iter::once(&self.adapters)
+            .chain(&self.toks)
             .flat_map(|x| bytemuck::bytes_of(x).to_vec())
             .collect::<Vec<u8>>()
     }
 }
 
-impl From<Vec<u32>> for Tokens {
-    fn from(value: Vec<u32>) -> Self {
-        Self(value)
+/// Maps the tokens of a sequence to the key its cache is stored and looked up under. See
+/// [`PrefixCacheManager::key_normalizer`].
+pub type KeyNormalizer = Arc<dyn Fn(&[u32]) -> Vec<u32> + Send + Sync>;
+
+/// The policy used to select which prefix caches are evicted to the offload device first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict the oldest inserted caches first.
+    #[default]
+    Fifo,
+    /// Evict the least recently used caches first.
+    Lru,
+    /// Evict the least frequently used caches first. Ties are broken by recency.
+    Lfu,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fifo" => Ok(Self::Fifo),
+            "lru" => Ok(Self::Lru),
+            "lfu" => Ok(Self::Lfu),
+            _ => Err(format!(
+                "Invalid eviction policy `{s}`, expected one of `fifo`, `lru`, `lfu`"
+            )),
+        }
     }
 }
 
-type EvictionCacheGroup = (Arc<Mutex<LayerCaches>>, Option<Arc<Mutex<LayerCaches>>>);
+struct EvictionCacheGroup {
+    normal: Arc<Mutex<LayerCaches<KvBlock>>>,
+    xlora: Option<Arc<Mutex<LayerCaches<KvBlock>>>>,
+    /// Number of times this cache was matched.
+    hits: usize,
+    /// Value of the access clock at insertion or the last match.
+    last_access: usize,
+    /// Approximate size of the normal and X-LoRA K/V tensors.
+    n_bytes: usize,
+}
 
+/// A snapshot of the prefix cache counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrefixCacheStats {
+    /// Searches which returned a cache.
+    pub hits: usize,
+    /// Searches which did not return a cache.
+    pub misses: usize,
+    /// Hits where the prompt was cached as is.
+    pub verbatim_hits: usize,
+    /// Hits where the prompt is a prefix of a longer cached sequence.
+    pub subset_hits: usize,
+    /// Hits which required copying the cache from the offload device back to the device.
+    pub cpu_promotions: usize,
+    /// Sequences evicted from the device to the offload device.
+    pub evictions: usize,
+    /// Sequences added to the cache.
+    pub insertions: usize,
+    /// Insertions which shared an existing cache for identical tokens instead of storing a copy.
+    pub deduplicated: usize,
+}
+
+const N_SHARDS: usize = 16;
+const DEFAULT_MIN_MATCH_LEN: usize = 16;
+
+/// The caches whose keys start with the same token. Any prefix or extension of a key shares its first token, so a
+/// search only ever needs to look at one shard.
+struct Shard {
+    caches: Trie<CacheKey, Arc<Mutex<LayerCaches<KvBlock>>>>,
+    xlora_caches: Option<Trie<CacheKey, Arc<Mutex<LayerCaches<KvBlock>>>>>,
+}
+
+/// The pinned host buffers holding an evicted cache (and its X-LoRA cache), which stay pinned until the cache is
+/// promoted back to the device or dropped.
+type PinnedCaches = Vec<(Weak<Mutex<LayerCaches<KvBlock>>>, Vec<PinnedHostBuffer>)>;
+
+/// Caches the KV caches of finished sequences so that later prompts sharing a prefix can skip recomputing it.
+///
+/// # Concurrency
+/// All methods take `&self`, so the manager can be shared between threads.
+/// - The tries are split into shards by the first token, each behind a `RwLock`. Searches take a read lock on one
+///   shard, so concurrent searches never block each other, and only block on insertions into the same shard.
+/// - Each cache has its own mutex, held while it is moved between devices. A search matching a cache which is being
+///   promoted or evicted waits for that copy only.
+/// - The eviction metadata is behind a single mutex, which is only held briefly by searches to record the hit.
+///   Eviction holds it while the victims are selected and (for the synchronous methods) copied.
+///
+/// Locks are always acquired in the order adapter sets, shard, eviction metadata, cache, pinned buffers, and the stats
+/// last.
+///
+/// # Pinned memory
+/// With the `pinned-memory` feature, caches evicted from a CUDA device to the CPU are copied into page-locked host
+/// memory, which makes both the eviction and the promotion back to the device faster. The eviction does not wait for
+/// the copy: the first read of the cache does, under its lock. The memory is unpinned when the cache is promoted or
+/// dropped.
+///
+/// # LoRA adapters
+/// The keys and values of a sequence depend on the LoRA adapters which were active when it ran, so a cache is only
+/// valid for those adapters. The caches are keyed by the adapter set of the sequence as well as its tokens, so the
+/// caches of different adapter sets are kept side by side, and a prompt only matches caches computed with the adapters
+/// it runs with.
 pub struct PrefixCacheManager {
-    caches: Trie<Tokens, Arc<Mutex<LayerCaches>>>,
-    xlora_caches: Option<Trie<Tokens, Arc<Mutex<LayerCaches>>>>,
+    shards: Vec<RwLock<Shard>>,
     device: Device,
+    offload_device: Device,
     pub n_on_device: usize,
+    /// Maximum number of bytes of caches to keep on the device, if any.
+    pub memory_budget: Option<usize>,
+    /// Matches covering fewer prompt tokens than this are not worth promoting and are treated as misses.
+    pub min_match_len: usize,
+    /// Applied to the tokens before insertion and lookup, e.g. to strip a leading BOS token, so that sequences which
+    /// only differ in irrelevant tokens share a cache. The uncovered part of a matched prompt is taken from the
+    /// normalized tokens.
+    ///
+    /// The cache holds the keys and values of the tokens that were actually computed, so this is only correct if the
+    /// tokens the normalizer changes do not affect the rest of the sequence. Normalizing away tokens that do (e.g.
+    /// a system prompt or chat template markers) makes different prompts match the same cache and silently produces
+    /// wrong generations. The normalizer should also be idempotent, as saved caches are loaded under their keys.
+    pub key_normalizer: Option<KeyNormalizer>,
     no_prefix_cache: bool,
-    eviction_cache_ptrs: Vec<EvictionCacheGroup>,
+    eviction_policy: EvictionPolicy,
+    // Logical clock, incremented on every insertion and match.
+    access_clock: AtomicUsize,
+    // Kept in insertion order.
+    eviction_cache_ptrs: Mutex<Vec<EvictionCacheGroup>>,
+    stats: Mutex<PrefixCacheStats>,
+    // Shared with the threads of asynchronous evictions.
+    pinned: Arc<Mutex<PinnedCaches>>,
+    // The adapter sets the caches were computed with, indexed by their keys.
+    adapter_sets: Mutex<Vec<Vec<String>>>,
 }
 
 #[derive(Clone)]
 pub struct MatchingCache {
-    pub normal: LayerCaches,
-    pub xlora: Option<LayerCaches>,
+    pub normal: LayerCaches<KvBlock>,
+    pub xlora: Option<LayerCaches<KvBlock>>,
+    /// The prompt tokens which are not covered by the cache and still need to be computed. This is the last prompt
+    /// token, whose logits the first token is sampled from.
     pub toks: Vec<u32>,
+    /// The number of prompt tokens served from the cache.
+    pub matched_len: usize,
 }
 
 impl PrefixCacheManager {
-    pub fn new(device: Device, n_on_device: usize, is_xlora: bool, no_prefix_cache: bool) -> Self {
+    /// Evicted caches are moved to `offload_device`. This is usually the CPU, but may be e.g. a secondary GPU.
+    pub fn new(
+        device: Device,
+        offload_device: Device,
+        n_on_device: usize,
+        is_xlora: bool,
+        no_prefix_cache: bool,
+        eviction_policy: EvictionPolicy,
+    ) -> Self {
         PrefixCacheManager {
-            caches: Trie::new(),
-            xlora_caches: if is_xlora { Some(Trie::new()) } else { None },
+            shards: (0..N_SHARDS)
+                .map(|_| {
+                    RwLock::new(Shard {
+                        caches: Trie::new(),
+                        xlora_caches: if is_xlora { Some(Trie::new()) } else { None },
+                    })
+                })
+                .collect(),
             device,
+            offload_device,
             n_on_device,
+            memory_budget: None,
+            min_match_len: DEFAULT_MIN_MATCH_LEN,
+            key_normalizer: None,
             no_prefix_cache,
-            eviction_cache_ptrs: Vec::new(),
+            eviction_policy,
+            access_clock: AtomicUsize::new(0),
+            eviction_cache_ptrs: Mutex::new(Vec::new()),
+            stats: Mutex::new(PrefixCacheStats::default()),
+            pinned: Arc::new(Mutex::new(Vec::new())),
+            adapter_sets: Mutex::new(Vec::new()),
         }
     }
 
+    /// Create a prefix cache manager which never stores or matches anything, so that prompts are always computed from
+    /// scratch. This is useful to check whether a generation problem comes from cache reuse.
+    pub fn new_disabled(device: Device) -> Self {
+        Self::new(device, Device::Cpu, 0, false, true, EvictionPolicy::Fifo)
+    }
+
+    /// Create a prefix cache manager which evicts caches to the offload device once the caches on the device take up
+    /// more than `memory_budget` bytes, irrespective of how many sequences they belong to.
+    pub fn new_with_memory_budget(
+        device: Device,
+        offload_device: Device,
+        memory_budget: usize,
+        is_xlora: bool,
+        no_prefix_cache: bool,
+        eviction_policy: EvictionPolicy,
+    ) -> Self {
+        let mut this = Self::new(
+            device,
+            offload_device,
+            usize::MAX,
+            is_xlora,
+            no_prefix_cache,
+            eviction_policy,
+        );
+        this.memory_budget = Some(memory_budget);
+        this
+    }
+
+    fn shard(&self, toks: &[u32]) -> &RwLock<Shard> {
+        let first = toks.first().copied().unwrap_or(0) as usize;
+        &self.shards[first % N_SHARDS]
+    }
+
+    /// Approximate number of bytes used by the caches which are currently on the device.
+    pub fn current_device_bytes(&self) -> usize {
+        get_mut_arcmutex!(self.eviction_cache_ptrs)
+            .iter()
+            .filter(|group| !self.is_offloaded(&group.normal))
+            .map(|group| group.n_bytes)
+            .sum()
+    }
+
+    fn cache_bytes(cache: &LayerCaches<KvBlock>) -> usize {
+        cache.iter().flatten().map(KvBlock::n_bytes).sum()
+    }
+
+    /// Get a snapshot of the hit, miss and eviction counters.
+    pub fn stats(&self) -> PrefixCacheStats {
+        *get_mut_arcmutex!(self.stats)
+    }
+
+    /// Reset the hit, miss and eviction counters.
+    pub fn reset_stats(&self) {
+        *get_mut_arcmutex!(self.stats) = PrefixCacheStats::default();
+    }
+
+    /// Fraction of insertions which shared an already stored cache, or 0 if nothing was inserted.
+    pub fn dedup_ratio(&self) -> f64 {
+        #![allow(clippy::cast_precision_loss)]
+        let stats = self.stats();
+        if stats.insertions == 0 {
+            0.
+        } else {
+            stats.deduplicated as f64 / stats.insertions as f64
+        }
+    }
+
+    fn update_stats(&self, f: impl FnOnce(&mut PrefixCacheStats)) {
+        f(&mut get_mut_arcmutex!(self.stats))
+    }
+
+    fn tick(&self) -> usize {
+        self.access_clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn is_offloaded(&self, cache: &Mutex<LayerCaches<KvBlock>>) -> bool {
+        get_mut_arcmutex!(cache)[0]
+            .as_ref()
+            .unwrap()
+            .device()
+            .same_device(&self.offload_device)
+    }
+
     /// This always keeps the cache on the device. If later on, a new seq cannot be allocated due to memory shortage,
     /// some caches will be evicted.
-    pub fn add_sequence(&mut self, seq: &mut Sequence) {
+    pub fn add_sequence(&self, seq: &mut Sequence) {
         if self.no_prefix_cache {
             return;
         }
-        let cache = Arc::new(Mutex::new(seq.cache().clone()));
-        self.caches
-            .insert(seq.get_toks().to_vec().into(), cache.clone());
-        if seq.is_xlora() {
-            let xlora_cache = Arc::new(Mutex::new(seq.xlora_cache().clone()));
-            self.xlora_caches
+        let xlora_cache = if seq.is_xlora() {
+            Some(seq.xlora_cache().clone())
+        } else {
+            None
+        };
+        self.add_cache(
+            self.normalize(seq.get_toks()),
+            seq.get_adapters().as_deref(),
+            seq.cache().clone(),
+            xlora_cache,
+        );
+    }
+
+    /// The key of the caches of `toks` computed with `adapters`, or `None` if no cache was computed with them.
+    fn key(
+        adapter_sets: &[Vec<String>],
+        toks: Vec<u32>,
+        adapters: Option<&[String]>,
+    ) -> Option<CacheKey> {
+        let Some(adapters) = adapters else {
+            return Some(CacheKey { adapters: 0, toks });
+        };
+        let i = adapter_sets.iter().position(|set| set == adapters)?;
+        Some(CacheKey {
+            adapters: u32::try_from(i + 1).expect("Too many adapter sets."),
+            toks,
+        })
+    }
+
+    /// Like [`Self::key`], but registers `adapters` if no cache was computed with them yet.
+    fn insert_key(
+        adapter_sets: &mut Vec<Vec<String>>,
+        toks: Vec<u32>,
+        adapters: Option<&[String]>,
+    ) -> CacheKey {
+        if let Some(adapters) = adapters {
+            if !adapter_sets.iter().any(|set| set == adapters) {
+                adapter_sets.push(adapters.to_vec());
+            }
+        }
+        Self::key(adapter_sets, toks, adapters).expect("The adapter set was registered.")
+    }
+
+    /// Drop all caches, on the device and offloaded. Returns the number of caches dropped.
+    pub fn clear(&self) -> usize {
+        let mut adapter_sets = get_mut_arcmutex!(self.adapter_sets);
+        for shard in &self.shards {
+            let mut shard = shard.write().unwrap();
+            shard.caches = Trie::new();
+            if let Some(xlora_caches) = shard.xlora_caches.as_mut() {
+                *xlora_caches = Trie::new();
+            }
+        }
+        // No key refers to the adapter sets anymore
+        adapter_sets.clear();
+        get_mut_arcmutex!(self.pinned).clear();
+        std::mem::take(&mut *get_mut_arcmutex!(self.eviction_cache_ptrs)).len()
+    }
+
+    fn normalize(&self, toks: &[u32]) -> Vec<u32> {
+        match self.key_normalizer {
+            Some(ref normalizer) => normalizer(toks),
+            None => toks.to_vec(),
+        }
+    }
+
+    fn add_cache(
+        &self,
+        toks: Vec<u32>,
+        adapters: Option<&[String]>,
+        cache: LayerCaches<KvBlock>,
+        xlora_cache: Option<LayerCaches<KvBlock>>,
+    ) {
+        // Held until the cache is inserted, as `clear` resets the adapter sets the key refers to
+        let mut adapter_sets = get_mut_arcmutex!(self.adapter_sets);
+        let key = Self::insert_key(&mut adapter_sets, toks, adapters);
+        let mut shard = self.shard(&key.toks).write().unwrap();
+        self.update_stats(|stats| stats.insertions += 1);
+        // The cache is determined by the tokens, so sequences with identical tokens share the stored cache and its
+        // eviction metadata.
+        if let Some(existing) = shard.caches.get(&key) {
+            self.record_access(existing, false);
+            self.update_stats(|stats| stats.deduplicated += 1);
+            return;
+        }
+        let n_bytes = Self::cache_bytes(&cache) + xlora_cache.as_ref().map_or(0, Self::cache_bytes);
+        let cache = Arc::new(Mutex::new(cache));
+        shard.caches.insert(key.clone(), cache.clone());
+        let xlora_cache = xlora_cache.map(|xlora_cache| {
+            let xlora_cache = Arc::new(Mutex::new(xlora_cache));
+            shard
+                .xlora_caches
                 .as_mut()
                 .unwrap()
-                .insert(seq.get_toks().to_vec().into(), xlora_cache.clone());
-            self.eviction_cache_ptrs.push((cache, Some(xlora_cache)));
-        } else {
-            self.eviction_cache_ptrs.push((cache, None));
+                .insert(key, xlora_cache.clone());
+            xlora_cache
+        });
+        let last_access = self.tick();
+        get_mut_arcmutex!(self.eviction_cache_ptrs).push(EvictionCacheGroup {
+            normal: cache,
+            xlora: xlora_cache,
+            hits: 0,
+            last_access,
+            n_bytes,
+        });
+    }
+
+    /// Update the recency (and for matches, frequency) metadata of a cache.
+    fn record_access(&self, cache: &Arc<Mutex<LayerCaches<KvBlock>>>, is_hit: bool) {
+        let now = self.tick();
+        if let Some(group) = get_mut_arcmutex!(self.eviction_cache_ptrs)
+            .iter_mut()
+            .find(|group| Arc::ptr_eq(&group.normal, cache))
+        {
+            if is_hit {
+                group.hits += 1;
+            }
+            group.last_access = now;
         }
     }
 
     fn cache_to<'a>(
-        cache: impl Iterator<Item = &'a mut Option<(Tensor, Tensor)>>,
+        cache: impl Iterator<Item = &'a mut Option<KvBlock>>,
         device: &Device,
     ) -> Result<()> {
-        for layer in cache {
-            if let Some((ref q, ref k)) = layer {
-                *layer = Some((q.to_device(device)?, k.to_device(device)?));
+        KvBlock::to_device_batched(cache.flatten(), device)
+    }
+
+    /// Select the caches to evict, in eviction order. These are the lowest ranked caches on the device, according to
+    /// the eviction policy, such that the number of sequences (and bytes, if there is a memory budget) on device
+    /// after the eviction is at most the maximum allowed.
+    fn select_for_eviction(&self, groups: &[EvictionCacheGroup]) -> Vec<usize> {
+        let on_device = groups
+            .iter()
+            .enumerate()
+            .filter(|(_, group)| !self.is_offloaded(&group.normal))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        self.select_among(groups, on_device)
+    }
+
+    /// Select the caches to evict among the indices `on_device` of `groups`, in eviction order.
+    fn select_among(&self, groups: &[EvictionCacheGroup], mut on_device: Vec<usize>) -> Vec<usize> {
+        // Order by ascending rank, so the first ones are evicted first
+        match self.eviction_policy {
+            // Already in insertion order
+            EvictionPolicy::Fifo => (),
+            EvictionPolicy::Lru => {
+                on_device.sort_by_key(|i| groups[*i].last_access);
+            }
+            EvictionPolicy::Lfu => on_device.sort_by_key(|i| {
+                let group = &groups[*i];
+                (group.hits, group.last_access)
+            }),
+        }
+        let mut n_remaining = on_device.len();
+        let mut bytes_remaining = on_device.iter().map(|i| groups[*i].n_bytes).sum::<usize>();
+        let mut selected = Vec::new();
+        for i in on_device {
+            if n_remaining <= self.n_on_device
+                && !self.memory_budget.is_some_and(|b| bytes_remaining > b)
+            {
+                break;
+            }
+            n_remaining -= 1;
+            bytes_remaining -= groups[i].n_bytes;
+            selected.push(i);
+        }
+        selected
+    }
+
+    fn evict_group(
+        cache: &Arc<Mutex<LayerCaches<KvBlock>>>,
+        xlora_cache: Option<&Arc<Mutex<LayerCaches<KvBlock>>>>,
+        offload_device: &Device,
+        pinned: &Mutex<PinnedCaches>,
+    ) -> Result<()> {
+        let mut buffers = Vec::new();
+        let mut normal = get_mut_arcmutex!(cache);
+        let mut xlora_cache = xlora_cache.map(|c| get_mut_arcmutex!(c));
+
+        buffers.extend(KvBlock::to_device_batched_pinned(
+            normal.iter_mut().flatten(),
+            offload_device,
+        )?);
+        if let Some(ref mut xlora_cache) = xlora_cache {
+            buffers.extend(KvBlock::to_device_batched_pinned(
+                xlora_cache.iter_mut().flatten(),
+                offload_device,
+            )?);
+        }
+        // The copies into the buffers are still running, so they are registered before the cache is unlocked for
+        // readers to wait for them
+        if !buffers.is_empty() {
+            let mut pinned = get_mut_arcmutex!(pinned);
+            // Drop the buffers of caches which were dropped since
+            pinned.retain(|(cache, _)| cache.strong_count() > 0);
+            pinned.push((Arc::downgrade(cache), buffers));
+        }
+        Ok(())
+    }
+
+    /// Wait for the copies of `cache` (and its X-LoRA cache) into pinned host memory, if it was evicted. The cache
+    /// must be locked, so that no eviction of it is in progress.
+    fn wait_pinned(
+        pinned: &Mutex<PinnedCaches>,
+        cache: &Arc<Mutex<LayerCaches<KvBlock>>>,
+    ) -> Result<()> {
+        let cache = Arc::downgrade(cache);
+        for (pinned, buffers) in get_mut_arcmutex!(pinned).iter_mut() {
+            if pinned.ptr_eq(&cache) {
+                for buffer in buffers {
+                    buffer.wait()?;
+                }
             }
         }
         Ok(())
     }
 
-    /// Evict the caches to CPU. This will evict the first k seqs such that the number of sequences on device after the copy is
-    /// the maximum allowed. Returns the number of evicted sequences.
-    pub fn evict_to_cpu(&mut self) -> Result<usize> {
+    /// Unpin the host memory of `cache`, which was promoted back to the device.
+    fn unpin(&self, cache: &Arc<Mutex<LayerCaches<KvBlock>>>) {
+        let cache = Arc::downgrade(cache);
+        get_mut_arcmutex!(self.pinned).retain(|(pinned, _)| !pinned.ptr_eq(&cache));
+    }
+
+    /// Evict the caches to the offload device, usually the CPU. This will evict the lowest ranked k seqs, according to
+    /// the eviction policy, such that the number of sequences (and bytes, if there is a memory budget) on device after
+    /// the copy is at most the maximum allowed. Returns the number of evicted sequences.
+    pub fn evict_to_cpu(&self) -> Result<usize> {
         if self.no_prefix_cache {
             return Ok(0);
         }
-        let mut n_on_device = 0;
-        for (cache, _) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                n_on_device += 1;
-            }
-        }
-        let mut n_evicted = 0;
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if n_on_device - n_evicted == self.n_on_device {
-                break;
-            }
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
-                n_evicted += 1;
-            }
+        let groups = get_mut_arcmutex!(self.eviction_cache_ptrs);
+        let selected = self.select_for_eviction(&groups);
+        let _span = debug_span!("prefix_cache_evict", seqs = selected.len()).entered();
+        for i in &selected {
+            let group = &groups[*i];
+            Self::evict_group(
+                &group.normal,
+                group.xlora.as_ref(),
+                &self.offload_device,
+                &self.pinned,
+            )?;
+            self.update_stats(|stats| stats.evictions += 1);
+        }
+        Ok(selected.len())
+    }
+
+    /// Like [`Self::evict_to_cpu`], but the copies to the offload device happen on a background thread. The caches to
+    /// evict are selected before returning, and the handle yields the number of evicted sequences.
+    ///
+    /// Synchronization: each cache is locked for the duration of its copy. If a search matches a cache which is
+    /// being evicted, it blocks until that copy is done and then moves the cache back to the device. If the search
+    /// wins the race instead, it returns a copy of the device cache and the eviction proceeds afterwards. Either
+    /// way, the matched cache is never observed half-copied.
+    pub fn evict_to_cpu_async(&self) -> JoinHandle<Result<usize>> {
+        if self.no_prefix_cache {
+            return thread::spawn(|| Ok(0));
         }
-        Ok(self.caches.len().saturating_sub(self.n_on_device))
+        let selected = {
+            let groups = get_mut_arcmutex!(self.eviction_cache_ptrs);
+            self.select_for_eviction(&groups)
+                .into_iter()
+                .map(|i| (groups[i].normal.clone(), groups[i].xlora.clone()))
+                .collect::<Vec<_>>()
+        };
+        self.update_stats(|stats| stats.evictions += selected.len());
+        let offload_device = self.offload_device.clone();
+        let pinned = self.pinned.clone();
+        thread::spawn(move || {
+            for (cache, xlora_cache) in &selected {
+                Self::evict_group(cache, xlora_cache.as_ref(), &offload_device, &pinned)?;
+            }
+            Ok(selected.len())
+        })
     }
 
-    /// Evict all the caches to CPU.
-    pub fn evict_all_to_cpu(&mut self) -> Result<usize> {
+    /// Evict all the caches to the offload device.
+    pub fn evict_all_to_cpu(&self) -> Result<usize> {
         if self.no_prefix_cache {
             return Ok(0);
         }
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
+        let groups = get_mut_arcmutex!(self.eviction_cache_ptrs);
+        for group in groups.iter() {
+            if !self.is_offloaded(&group.normal) {
+                Self::evict_group(
+                    &group.normal,
+                    group.xlora.as_ref(),
+                    &self.offload_device,
+                    &self.pinned,
+                )?;
+                self.update_stats(|stats| stats.evictions += 1);
             }
         }
-        Ok(self.caches.len())
+        Ok(groups.len())
     }
 
-    /// Search for a matching cache given some toks
-    pub fn search_for_matching_cache(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
+    /// Search for a matching cache given some toks, among the caches computed with `adapters`.
+    ///
+    /// The caches are keyed by a radix trie over the token bytes, so the lookup is linear in the length of `toks`
+    /// and independent of the number of cached sequences.
+    pub fn search_for_matching_cache(
+        &self,
+        toks: &[u32],
+        adapters: Option<&[String]>,
+    ) -> Result<Option<MatchingCache>> {
         if self.no_prefix_cache {
             return Ok(None);
         }
+        let span = debug_span!(
+            "prefix_cache_lookup",
+            tokens = toks.len(),
+            matched = field::Empty
+        );
+        let _enter = span.enter();
 
-        let toks = Tokens(toks.to_vec());
-        if let Some(cache) = self.caches.get(&toks) {
-            Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
-            let cache = get_mut_arcmutex!(cache.as_ref()).clone();
-            let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
-                let mut xlora_cache = get_mut_arcmutex!(xlora_caches.get(&toks).unwrap().as_ref());
+        let toks = self.normalize(toks);
+        // Every cached sequence starts with an empty prompt, but none of it can be served from the cache
+        if toks.is_empty() {
+            self.update_stats(|stats| stats.misses += 1);
+            return Ok(None);
+        }
+        let (key, shard) = {
+            let adapter_sets = get_mut_arcmutex!(self.adapter_sets);
+            let Some(key) = Self::key(&adapter_sets, toks, adapters) else {
+                self.update_stats(|stats| stats.misses += 1);
+                return Ok(None);
+            };
+            // `clear` resets the adapter sets while holding every shard, so the key stays valid while this one is
+            let shard = self.shard(&key.toks).read().unwrap();
+            (key, shard)
+        };
+        // Whether the prompt was cached as is or as the prefix of a longer sequence, its last token is recomputed to
+        // sample the first token from its logits
+        if let Some(cache) = shard.caches.get(&key).cloned() {
+            let prefix_len = key.toks.len() - 1;
+            if prefix_len == 0 || prefix_len < self.min_match_len {
+                self.update_stats(|stats| stats.misses += 1);
+                return Ok(None);
+            }
+            let Some(xlora_cache) = self.xlora_cache_for(&shard, &key) else {
+                return Ok(None);
+            };
+            let is_offloaded = self.is_offloaded(&cache);
+            if is_offloaded {
+                self.update_stats(|stats| stats.cpu_promotions += 1);
+            }
+            let normal = {
+                let mut normal = get_mut_arcmutex!(cache.as_ref());
+                Self::wait_pinned(&self.pinned, &cache)?;
+                Self::cache_to(normal.iter_mut(), &self.device)?;
+                Self::narrow_to(&normal, prefix_len, &self.device)?
+            };
+            let xlora = if let Some(xlora_cache) = xlora_cache {
+                let mut xlora_cache = get_mut_arcmutex!(xlora_cache.as_ref());
                 Self::cache_to(xlora_cache.iter_mut(), &self.device)?;
-                Some(xlora_cache.clone())
+                Some(Self::narrow_to(&xlora_cache, prefix_len, &self.device)?)
+            } else {
+                None
+            };
+            if is_offloaded {
+                self.unpin(&cache);
+            }
+            self.record_access(&cache, true);
+            self.update_stats(|stats| {
+                stats.hits += 1;
+                stats.verbatim_hits += 1;
+            });
+            span.record("matched", prefix_len);
+            Ok(Some(MatchingCache {
+                normal,
+                xlora,
+                toks: key.toks[prefix_len..].to_vec(),
+                matched_len: prefix_len,
+            }))
+        } else if let Some((descendant, cache)) =
+            shard.caches.get_raw_descendant(&key).and_then(|d| {
+                d.iter()
+                    .next()
+                    .map(|(key, cache)| (key.clone(), cache.clone()))
+            })
+        {
+            // The prompt is a prefix of a longer cached sequence, so only the matching positions are promoted
+            let prefix_len = key.toks.len() - 1;
+            if prefix_len == 0
+                || prefix_len < self.min_match_len
+                || descendant.adapters != key.adapters
+                || !descendant.toks.starts_with(&key.toks)
+            {
+                self.update_stats(|stats| stats.misses += 1);
+                return Ok(None);
+            }
+            let Some(xlora_cache) = self.xlora_cache_for(&shard, &descendant) else {
+                return Ok(None);
+            };
+            if self.is_offloaded(&cache) {
+                self.update_stats(|stats| stats.cpu_promotions += 1);
+            }
+            let normal = {
+                let normal = get_mut_arcmutex!(cache.as_ref());
+                Self::wait_pinned(&self.pinned, &cache)?;
+                Self::narrow_to(&normal, prefix_len, &self.device)?
+            };
+            let xlora = if let Some(xlora_cache) = xlora_cache {
+                let xlora_cache = get_mut_arcmutex!(xlora_cache.as_ref());
+                Some(Self::narrow_to(&xlora_cache, prefix_len, &self.device)?)
             } else {
                 None
             };
-            let ancestor = &self
-                .caches
-                .get_ancestor(&toks)
-                .expect("No ancestor.")
-                .key()
-                .expect("Cannot get the key.")
-                .0;
-            // Know ancestor.len() < toks.len(), and toks[0..ancestor.len()] == toks
+            self.record_access(&cache, true);
+            self.update_stats(|stats| {
+                stats.hits += 1;
+                stats.subset_hits += 1;
+            });
+            span.record("matched", prefix_len);
             Ok(Some(MatchingCache {
-                normal: cache,
-                xlora: xlora_cache,
-                toks: toks.0[ancestor.len()..].to_vec(),
+                normal,
+                xlora,
+                toks: key.toks[prefix_len..].to_vec(),
+                matched_len: prefix_len,
             }))
         } else {
+            self.update_stats(|stats| stats.misses += 1);
             Ok(None)
         }
     }
+
+    /// The cache of the prompt of `seq`, which was just prefilled, for the sequences forked from it. It covers all
+    /// prompt tokens but the last, which each fork computes to sample its own first token.
+    pub fn fork_cache(&self, seq: &mut Sequence) -> Result<MatchingCache> {
+        let prefix_len = seq.prompt_tokens() - 1;
+        let normal = Self::narrow_to(seq.cache(), prefix_len, &self.device)?;
+        let xlora = if seq.is_xlora() {
+            Some(Self::narrow_to(
+                seq.xlora_cache(),
+                prefix_len,
+                &self.device,
+            )?)
+        } else {
+            None
+        };
+        Ok(MatchingCache {
+            normal,
+            xlora,
+            toks: seq.all_toks()[prefix_len..seq.prompt_tokens()].to_vec(),
+            matched_len: prefix_len,
+        })
+    }
+
+    /// Get the X-LoRA cache stored alongside the normal cache for `key`, if this is an X-LoRA manager. Returns `None`
+    /// and counts a miss if it is missing, so that an inconsistent state degrades to recomputing the prompt.
+    #[allow(clippy::type_complexity)]
+    fn xlora_cache_for(
+        &self,
+        shard: &Shard,
+        key: &CacheKey,
+    ) -> Option<Option<Arc<Mutex<LayerCaches<KvBlock>>>>> {
+        let Some(ref xlora_caches) = shard.xlora_caches else {
+            return Some(None);
+        };
+        match xlora_caches.get(key) {
+            Some(xlora_cache) => Some(Some(xlora_cache.clone())),
+            None => {
+                tracing::warn!("Prefix cache entry has no X-LoRA cache, treating it as a miss.");
+                self.update_stats(|stats| stats.misses += 1);
+                None
+            }
+        }
+    }
+
+    /// Save all the caches to a safetensors file at `path`. The caches are left where they are.
+    pub fn save_to_disk(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut tensors = HashMap::new();
+        let mut i = 0;
+        let adapter_sets = get_mut_arcmutex!(self.adapter_sets);
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            for (key, cache) in shard.caches.iter() {
+                tensors.insert(
+                    format!("{i}.tokens"),
+                    Tensor::new(key.toks.as_slice(), &Device::Cpu)?,
+                );
+                // The names of the adapters, one per line
+                if let Some(adapters) = key.adapters.checked_sub(1) {
+                    let adapters = adapter_sets[adapters as usize].join("\n");
+                    tensors.insert(
+                        format!("{i}.adapters"),
+                        Tensor::new(adapters.as_bytes(), &Device::Cpu)?,
+                    );
+                }
+                let normal = get_mut_arcmutex!(cache.as_ref());
+                Self::wait_pinned(&self.pinned, cache)?;
+                Self::insert_named(&normal, &format!("{i}.normal"), &mut tensors)?;
+                if let Some(ref xlora_caches) = shard.xlora_caches {
+                    if let Some(xlora_cache) = xlora_caches.get(key) {
+                        let xlora_cache = get_mut_arcmutex!(xlora_cache.as_ref());
+                        Self::insert_named(&xlora_cache, &format!("{i}.xlora"), &mut tensors)?;
+                    }
+                }
+                i += 1;
+            }
+        }
+        candle_core::safetensors::save(&tensors, path)
+    }
+
+    /// Load the caches saved by [`Self::save_to_disk`] onto the offload device, so that the first match of each one
+    /// is promoted from there. Caches which do not fit a model with `num_hidden_layers` layers and K/V dtype `dtype`
+    /// are discarded. Returns the number of loaded caches.
+    pub fn load_from_disk(
+        &self,
+        path: impl AsRef<Path>,
+        num_hidden_layers: usize,
+        dtype: DType,
+    ) -> Result<usize> {
+        if self.no_prefix_cache {
+            return Ok(0);
+        }
+        let mut tensors = candle_core::safetensors::load(path, &self.offload_device)?;
+        let is_xlora = self.shards[0].read().unwrap().xlora_caches.is_some();
+        let mut n_loaded = 0;
+        for i in 0.. {
+            let Some(toks) = tensors.remove(&format!("{i}.tokens")) else {
+                break;
+            };
+            let toks = toks.to_vec1::<u32>()?;
+            let adapters: Option<Vec<String>> = match tensors.remove(&format!("{i}.adapters")) {
+                Some(adapters) => {
+                    let adapters = String::from_utf8(adapters.to_vec1::<u8>()?)
+                        .map_err(candle_core::Error::wrap)?;
+                    Some(adapters.lines().map(ToString::to_string).collect())
+                }
+                None => None,
+            };
+            let normal =
+                Self::remove_named(&format!("{i}.normal"), num_hidden_layers, &mut tensors)?;
+            let xlora = Self::remove_named(&format!("{i}.xlora"), num_hidden_layers, &mut tensors)?;
+            let compatible = |cache: &LayerCaches<KvBlock>| {
+                cache.iter().all(|block| {
+                    block.as_ref().is_some_and(|block| {
+                        block.dtype() == dtype && block.seq_len() <= toks.len()
+                    })
+                })
+            };
+            match (normal, xlora) {
+                (Some(normal), xlora)
+                    if compatible(&normal)
+                        && is_xlora == xlora.is_some()
+                        && xlora.iter().all(compatible) =>
+                {
+                    self.add_cache(toks, adapters.as_deref(), normal, xlora);
+                    n_loaded += 1;
+                }
+                _ => tracing::warn!("Discarding incompatible prefix cache {i}."),
+            }
+        }
+        Ok(n_loaded)
+    }
+
+    fn insert_named(
+        cache: &LayerCaches<KvBlock>,
+        prefix: &str,
+        tensors: &mut HashMap<String, Tensor>,
+    ) -> Result<()> {
+        tensors.insert(
+            format!("{prefix}.n_layers"),
+            Tensor::new(&[cache.len() as i64], &Device::Cpu)?,
+        );
+        for (layer, block) in cache.iter().enumerate() {
+            if let Some(block) = block {
+                block
+                    .to_device(&Device::Cpu)?
+                    .insert_named(&format!("{prefix}.{layer}"), tensors)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `None` if there is no cache with `prefix` or it does not have `num_hidden_layers` layers.
+    fn remove_named(
+        prefix: &str,
+        num_hidden_layers: usize,
+        tensors: &mut HashMap<String, Tensor>,
+    ) -> Result<Option<LayerCaches<KvBlock>>> {
+        let Some(n_layers) = tensors.remove(&format!("{prefix}.n_layers")) else {
+            return Ok(None);
+        };
+        let n_layers =
+            usize::try_from(n_layers.to_vec1::<i64>()?[0]).map_err(candle_core::Error::wrap)?;
+        let mut cache = Vec::with_capacity(n_layers);
+        for layer in 0..n_layers {
+            cache.push(KvBlock::remove_named(
+                &format!("{prefix}.{layer}"),
+                tensors,
+            )?);
+        }
+        Ok((n_layers == num_hidden_layers).then_some(cache))
+    }
+
+    /// Copy the first `prefix_len` positions of each layer's K/V cache to the device. Unlike matching a whole
+    /// sequence, this leaves the stored cache where it is.
+    fn narrow_to(
+        cache: &LayerCaches<KvBlock>,
+        prefix_len: usize,
+        device: &Device,
+    ) -> Result<LayerCaches<KvBlock>> {
+        let mut narrowed = Vec::with_capacity(cache.len());
+        for layer in cache {
+            narrowed.push(match layer {
+                Some(block) => Some(block.narrow_seq(0, prefix_len)?),
+                None => None,
+            });
+        }
+        Self::cache_to(narrowed.iter_mut(), device)?;
+        Ok(narrowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use crate::pipeline::{KvBlock, LayerCaches};
+
+    fn dummy_cache() -> LayerCaches<KvBlock> {
+        let kv = Tensor::zeros((1, 2, 4, 8), DType::F32, &Device::Cpu).unwrap();
+        vec![Some(KvBlock::Full(kv.clone(), kv)); 2]
+    }
+
+    /// A cacher on the CPU, offloading to the CPU, which keeps `n_on_device` caches on the device and matches
+    /// prefixes of any length.
+    fn new_cacher(
+        n_on_device: usize,
+        is_xlora: bool,
+        eviction_policy: super::EvictionPolicy,
+    ) -> super::PrefixCacheManager {
+        let mut cacher = super::PrefixCacheManager::new(
+            Device::Cpu,
+            Device::Cpu,
+            n_on_device,
+            is_xlora,
+            false,
+            eviction_policy,
+        );
+        cacher.min_match_len = 0;
+        cacher
+    }
+
+    #[test]
+    fn xlora_cache_does_not_overwrite_normal_cache() {
+        use super::EvictionPolicy;
+
+        let cacher = new_cacher(16, true, EvictionPolicy::Lru);
+        let kv = Tensor::ones((1, 2, 4, 8), DType::F32, &Device::Cpu).unwrap();
+        let xlora_cache = vec![Some(KvBlock::Full(kv.clone(), kv)); 2];
+        cacher.add_cache(vec![1, 2, 3], None, dummy_cache(), Some(xlora_cache));
+
+        let matching = cacher
+            .search_for_matching_cache(&[1, 2, 3], None)
+            .unwrap()
+            .expect("No matching cache.");
+        let xlora = matching.xlora.expect("No X-LoRA cache.");
+        for (normal, xlora) in matching.normal.iter().zip(&xlora) {
+            let (k, v) = normal.as_ref().unwrap().kv().unwrap();
+            assert_eq!(k.sum_all().unwrap().to_scalar::<f32>().unwrap(), 0.);
+            assert_eq!(v.sum_all().unwrap().to_scalar::<f32>().unwrap(), 0.);
+            let (k, v) = xlora.as_ref().unwrap().kv().unwrap();
+            // The 2 positions before the last prompt token
+            assert_eq!(k.sum_all().unwrap().to_scalar::<f32>().unwrap(), 32.);
+            assert_eq!(v.sum_all().unwrap().to_scalar::<f32>().unwrap(), 32.);
+        }
+    }
+
+    #[test]
+    fn caches_are_keyed_by_adapters() {
+        use super::EvictionPolicy;
+
+        let cacher = new_cacher(16, false, EvictionPolicy::Lru);
+        let french = ["french".to_string()];
+        let german = ["german".to_string()];
+        cacher.add_cache(vec![1, 2, 3], Some(&french), dummy_cache(), None);
+        cacher.add_cache(vec![1, 2, 3, 4], None, dummy_cache(), None);
+
+        assert!(cacher
+            .search_for_matching_cache(&[1, 2, 3], Some(&german))
+            .unwrap()
+            .is_none());
+        // Only inserting a cache registers its adapter set
+        assert_eq!(cacher.adapter_sets.lock().unwrap().len(), 1);
+        // The caches of other adapters are kept, so alternating between adapter sets keeps matching
+        cacher.add_cache(vec![1, 2, 3], Some(&german), dummy_cache(), None);
+        for adapters in [&french, &german] {
+            let matching = cacher
+                .search_for_matching_cache(&[1, 2, 3], Some(adapters))
+                .unwrap()
+                .expect("No matching cache.");
+            assert_eq!(matching.matched_len, 2);
+            assert_eq!(matching.toks, vec![3]);
+        }
+        // Without adapters, only the longer cache computed without them matches
+        let matching = cacher
+            .search_for_matching_cache(&[1, 2, 3], None)
+            .unwrap()
+            .expect("No matching cache.");
+        assert_eq!(matching.matched_len, 2);
+        assert_eq!(matching.toks, vec![3]);
+        assert_eq!(cacher.clear(), 3);
+        assert!(cacher.adapter_sets.lock().unwrap().is_empty());
+    }
+
+    /// The caches `cacher` would evict, in eviction order, if every cache was on the device. This tests the selection
+    /// with caches on the CPU, which is also the offload device.
+    fn select_all_for_eviction(cacher: &super::PrefixCacheManager) -> Vec<usize> {
+        let groups = cacher.eviction_cache_ptrs.lock().unwrap();
+        cacher.select_among(&groups, (0..groups.len()).collect())
+    }
+
+    #[test]
+    fn evict_to_cpu_returns_number_evicted() {
+        use super::EvictionPolicy;
+
+        let cacher = new_cacher(3, false, EvictionPolicy::Fifo);
+        for tok in 0..8u32 {
+            cacher.add_cache(vec![tok; 4], None, dummy_cache(), None);
+        }
+        assert_eq!(select_all_for_eviction(&cacher), vec![0, 1, 2, 3, 4]);
+        // The caches on the CPU are already offloaded
+        assert_eq!(cacher.evict_to_cpu().unwrap(), 0);
+    }
+
+    /// The eviction order of four caches inserted in order, after the third is matched twice and then the first once.
+    fn eviction_order(policy: super::EvictionPolicy) -> Vec<usize> {
+        let cacher = new_cacher(0, false, policy);
+        for tok in 0..4u32 {
+            cacher.add_cache(vec![tok; 4], None, dummy_cache(), None);
+        }
+        for tok in [2, 2, 0] {
+            assert!(cacher
+                .search_for_matching_cache(&[tok; 4], None)
+                .unwrap()
+                .is_some());
+        }
+        select_all_for_eviction(&cacher)
+    }
+
+    #[test]
+    fn eviction_order_follows_policy() {
+        use super::EvictionPolicy;
+
+        // By insertion
+        assert_eq!(eviction_order(EvictionPolicy::Fifo), vec![0, 1, 2, 3]);
+        // By last access, the unmatched caches by insertion
+        assert_eq!(eviction_order(EvictionPolicy::Lru), vec![1, 3, 2, 0]);
+        // By number of matches, then last access
+        assert_eq!(eviction_order(EvictionPolicy::Lfu), vec![1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn concurrent_searches() {
+        use std::thread;
+
+        use super::EvictionPolicy;
+
+        let cacher = new_cacher(64, false, EvictionPolicy::Lru);
+        for tok in 0..32u32 {
+            cacher.add_cache(vec![tok; 4], None, dummy_cache(), None);
+        }
+        thread::scope(|s| {
+            for thread_i in 0..8u32 {
+                let cacher = &cacher;
+                s.spawn(move || {
+                    for i in 0..100u32 {
+                        let tok = (thread_i + i) % 32;
+                        let matching = cacher
+                            .search_for_matching_cache(&[tok; 4], None)
+                            .unwrap()
+                            .expect("No matching cache.");
+                        assert_eq!(matching.normal.len(), 2);
+                        // Never inserted
+                        assert!(cacher
+                            .search_for_matching_cache(&[tok + 32; 4], None)
+                            .unwrap()
+                            .is_none());
+                    }
+                });
+            }
+        });
+        let stats = cacher.stats();
+        assert_eq!(stats.hits, 800);
+        assert_eq!(stats.misses, 800);
+    }
+
+    #[test]
+    fn identical_sequences_share_a_cache() {
+        use super::EvictionPolicy;
+
+        let cacher = new_cacher(16, false, EvictionPolicy::Lru);
+        cacher.add_cache(vec![1, 2, 3], None, dummy_cache(), None);
+        cacher.add_cache(vec![1, 2, 3], None, dummy_cache(), None);
+        cacher.add_cache(vec![4, 5, 6], None, dummy_cache(), None);
+        cacher.add_cache(vec![1, 2, 3], None, dummy_cache(), None);
+        assert_eq!(cacher.dedup_ratio(), 0.5);
+        assert_eq!(cacher.evict_all_to_cpu().unwrap(), 2);
+    }
+
+    #[test]
+    fn save_and_load_from_disk() {
+        use super::{EvictionPolicy, PrefixCacheManager};
+
+        let path = std::env::temp_dir().join("mistralrs_prefix_cache_test.safetensors");
+        let cacher = new_cacher(16, false, EvictionPolicy::Lru);
+        let adapters = ["french".to_string(), "german".to_string()];
+        cacher.add_cache(vec![1, 2, 3, 4, 5], Some(&adapters), dummy_cache(), None);
+        let kv = Tensor::ones((1, 2, 4, 8), DType::F32, &Device::Cpu).unwrap();
+        let quantized = KvBlock::new(kv.clone(), kv, Some(8)).unwrap();
+        cacher.add_cache(vec![6, 7, 8, 9, 10], None, vec![Some(quantized); 2], None);
+        cacher.save_to_disk(&path).unwrap();
+
+        let loaded = new_cacher(16, false, EvictionPolicy::Lru);
+        // Wrong number of layers or dtype
+        assert_eq!(loaded.load_from_disk(&path, 3, DType::F32).unwrap(), 0);
+        assert_eq!(loaded.load_from_disk(&path, 2, DType::F16).unwrap(), 0);
+        assert_eq!(loaded.load_from_disk(&path, 2, DType::F32).unwrap(), 2);
+        let matching = loaded
+            .search_for_matching_cache(&[6, 7, 8, 9, 10], None)
+            .unwrap()
+            .expect("No matching cache.");
+        let (k, _) = matching.normal[0].as_ref().unwrap().kv().unwrap();
+        assert!((k.sum_all().unwrap().to_scalar::<f32>().unwrap() - 64.).abs() < 1e-3);
+        // The adapters are saved with the cache
+        assert!(loaded
+            .search_for_matching_cache(&[1, 2, 3, 4, 5], None)
+            .unwrap()
+            .is_none());
+        assert!(loaded
+            .search_for_matching_cache(&[1, 2, 3, 4, 5], Some(&adapters))
+            .unwrap()
+            .is_some());
+
+        let disabled = PrefixCacheManager::new_disabled(Device::Cpu);
+        assert_eq!(disabled.load_from_disk(&path, 2, DType::F32).unwrap(), 0);
+        assert!(disabled
+            .search_for_matching_cache(&[6, 7, 8, 9, 10], None)
+            .unwrap()
+            .is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn short_matches_are_misses() {
+        use super::EvictionPolicy;
+
+        let mut cacher = new_cacher(16, false, EvictionPolicy::Lru);
+        cacher.min_match_len = super::DEFAULT_MIN_MATCH_LEN;
+        let short = (0..8u32).collect::<Vec<_>>();
+        let long = (0..32u32).collect::<Vec<_>>();
+        let kv = Tensor::zeros((1, 2, 32, 8), DType::F32, &Device::Cpu).unwrap();
+        let long_cache = vec![Some(KvBlock::Full(kv.clone(), kv)); 2];
+        cacher.add_cache(short.clone(), None, dummy_cache(), None);
+        cacher.add_cache(long.clone(), None, long_cache, None);
+        assert!(cacher
+            .search_for_matching_cache(&short, None)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            cacher
+                .search_for_matching_cache(&long, None)
+                .unwrap()
+                .expect("No matching cache.")
+                .matched_len,
+            31
+        );
+    }
+
+    #[test]
+    fn empty_prompt_is_a_miss() {
+        use super::EvictionPolicy;
+
+        let cacher = new_cacher(16, false, EvictionPolicy::Lru);
+        cacher.add_cache(vec![1, 2, 3], None, dummy_cache(), None);
+        assert!(cacher
+            .search_for_matching_cache(&[], None)
+            .unwrap()
+            .is_none());
+        assert_eq!(cacher.stats().misses, 1);
+    }
+
+    #[test]
+    fn key_normalizer_is_applied_before_lookup() {
+        use std::sync::Arc;
+
+        use super::EvictionPolicy;
+
+        let mut cacher = new_cacher(16, false, EvictionPolicy::Lru);
+        cacher.add_cache(vec![1, 2, 3], None, dummy_cache(), None);
+        assert!(cacher
+            .search_for_matching_cache(&[0, 1, 2, 3], None)
+            .unwrap()
+            .is_none());
+
+        cacher.key_normalizer = Some(Arc::new(|toks: &[u32]| {
+            toks.strip_prefix(&[0]).unwrap_or(toks).to_vec()
+        }));
+        let matching = cacher
+            .search_for_matching_cache(&[0, 1, 2, 3], None)
+            .unwrap()
+            .expect("No matching cache.");
+        assert_eq!(matching.matched_len, 2);
+        assert_eq!(matching.toks, vec![3]);
+    }
+
+    #[test]
+    fn missing_xlora_cache_is_a_miss() {
+        use super::EvictionPolicy;
+
+        let cacher = new_cacher(16, true, EvictionPolicy::Lru);
+        cacher.add_cache(vec![1, 2, 3], None, dummy_cache(), None);
+        assert!(cacher
+            .search_for_matching_cache(&[1, 2, 3], None)
+            .unwrap()
+            .is_none());
+        assert!(cacher
+            .search_for_matching_cache(&[1, 2], None)
+            .unwrap()
+            .is_none());
+        assert_eq!(cacher.stats().misses, 2);
+        cacher.reset_stats();
+        assert_eq!(cacher.stats().misses, 0);
+    }
+
+    #[test]
+    fn evict_to_cpu_evicts_xlora_caches() {
+        use std::sync::Arc;
+
+        use super::{EvictionPolicy, PrefixCacheManager};
+
+        let cacher = new_cacher(2, true, EvictionPolicy::Fifo);
+        for tok in 0..6u32 {
+            cacher.add_cache(vec![tok; 4], None, dummy_cache(), Some(dummy_cache()));
+        }
+        let selected = select_all_for_eviction(&cacher);
+        assert_eq!(selected, vec![0, 1, 2, 3]);
+
+        // Each cache is evicted together with the X-LoRA cache of the same tokens
+        let evicted = {
+            let groups = cacher.eviction_cache_ptrs.lock().unwrap();
+            selected
+                .iter()
+                .map(|i| (groups[*i].normal.clone(), groups[*i].xlora.clone()))
+                .collect::<Vec<_>>()
+        };
+        for (tok, (normal, xlora)) in (0..4u32).zip(evicted) {
+            let xlora = xlora.expect("No X-LoRA cache.");
+            {
+                let shard = cacher.shard(&[tok]).read().unwrap();
+                let key = super::CacheKey {
+                    adapters: 0,
+                    toks: vec![tok; 4],
+                };
+                assert!(Arc::ptr_eq(&normal, shard.caches.get(&key).unwrap()));
+                assert!(Arc::ptr_eq(
+                    &xlora,
+                    shard.xlora_caches.as_ref().unwrap().get(&key).unwrap()
+                ));
+            }
+            PrefixCacheManager::evict_group(&normal, Some(&xlora), &Device::Cpu, &cacher.pinned)
+                .unwrap();
+        }
+    }
 }
@@ -2,15 +2,17 @@ use candle_core::quantized::GgmlDType;
 use either::Either;
 use indexmap::IndexMap;
 
-use crate::{response::Response, sampler::SamplingParams};
-use std::fmt::Debug;
+use crate::{pipeline::EmbeddingPooling, response::Response, sampler::SamplingParams, tools::Tool};
+use std::{fmt::Debug, path::PathBuf, time::Duration};
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
-/// Control the constraint with Regex or Yacc.
+/// Control the constraint with Regex, Yacc, GBNF or a JSON schema.
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    Gbnf(String),
+    JsonSchema(serde_json::Value),
     None,
 }
 
@@ -23,6 +25,8 @@ pub enum RequestMessage {
     Completion {
         text: String,
         echo_prompt: bool,
+        /// Generate this many choices and return the `n_choices` with the highest mean logprob. Must be at least
+        /// `n_choices`.
         best_of: usize,
     },
     CompletionTokens(Vec<u32>),
@@ -44,6 +48,53 @@ pub struct NormalRequest {
     pub constraint: Constraint,
     pub suffix: Option<String>,
     pub adapters: Option<Vec<String>>,
+    /// Multiplies the contribution of the LoRA adapters: 0 disables them and 1 (the default) is their full strength.
+    pub lora_scale: Option<f32>,
+    /// Sequences with a higher priority are preferred when scheduling. Waiting sequences slowly gain priority, so
+    /// low priority sequences are not starved.
+    pub priority: usize,
+    /// Tools offered to the model in the chat template. The tool calls of a finished chat completion are parsed
+    /// into its `tool_calls`; streamed chunks are left as text.
+    pub tools: Option<Vec<Tool>>,
+}
+
+#[derive(Clone)]
+/// A request to embed texts with the hidden states of the model.
+pub struct EmbeddingRequest {
+    pub inputs: Vec<String>,
+    pub pooling: EmbeddingPooling,
+    /// Scale the embeddings to unit L2 norm.
+    pub normalize: bool,
+    pub response: Sender<Response>,
+    pub id: usize,
+}
+
+#[derive(Clone, Debug)]
+/// The prompt of a [`TokenizationRequest`].
+pub enum TokenizationPrompt {
+    /// Chat messages, formatted with the chat template and the generation prompt, as for a chat completion.
+    Chat(Vec<IndexMap<String, MessageContent>>),
+    /// Text, tokenized as the prompt of a completion.
+    Text(String),
+}
+
+#[derive(Clone)]
+/// A request to tokenize a prompt as a generation request would, without running the model.
+pub struct TokenizationRequest {
+    pub prompt: TokenizationPrompt,
+    /// Tools offered to the model in the chat template.
+    pub tools: Option<Vec<Tool>>,
+    pub response: Sender<Response>,
+    pub id: usize,
+}
+
+#[derive(Clone)]
+/// A request to decode token ids to text.
+pub struct DetokenizationRequest {
+    pub tokens: Vec<u32>,
+    pub skip_special_tokens: bool,
+    pub response: Sender<Response>,
+    pub id: usize,
 }
 
 #[derive(Clone)]
@@ -51,8 +102,24 @@ pub struct NormalRequest {
 /// the `mspc` response `Sender` used to return the [`Response`].
 pub enum Request {
     Normal(NormalRequest),
+    Embedding(EmbeddingRequest),
+    Tokenize(TokenizationRequest),
+    Detokenize(DetokenizationRequest),
     ReIsq(GgmlDType),
     ActivateAdapters(Vec<String>),
+    /// Load a LoRA adapter from a directory holding its `adapter_config.json` and `adapter_model.safetensors`, so that
+    /// it may be activated.
+    AddAdapter {
+        name: String,
+        path: PathBuf,
+    },
+    /// Merge a loaded LoRA adapter into the base weights and activate it, for inference without the LoRA overhead.
+    MergeAdapter(String),
+    /// Restore the base weights from before an adapter was merged.
+    UnmergeAdapter,
+    /// Stop accepting requests, and stop the engine once the running and waiting sequences are finished. Sequences
+    /// still running after the timeout are cancelled.
+    Drain(Duration),
 }
 
 impl Debug for Request {
@@ -68,18 +135,64 @@ impl Debug for Request {
                 constraint: _,
                 suffix: _,
                 adapters,
+                lora_scale,
+                priority,
+                tools: _,
+            }) => {
+                write!(
+                    f,
+                    "Request {id} {{ messages: `{messages:?}`, sampling_params: {sampling_params:?}, is_streaming: {is_streaming}, adapters: {adapters:?}, lora_scale: {lora_scale:?}, priority: {priority}}}",
+                )
+            }
+            Request::Embedding(EmbeddingRequest {
+                inputs,
+                pooling,
+                normalize,
+                response: _,
+                id,
             }) => {
                 write!(
                     f,
-                    "Request {id} {{ messages: `{messages:?}`, sampling_params: {sampling_params:?}, is_streaming: {is_streaming}, adapters: {adapters:?}}}",
+                    "Embedding Request {id} {{ inputs: {inputs:?}, pooling: {pooling:?}, normalize: {normalize}}}",
+                )
+            }
+            Request::Tokenize(TokenizationRequest {
+                prompt,
+                tools: _,
+                response: _,
+                id,
+            }) => {
+                write!(f, "Tokenization Request {id} {{ prompt: {prompt:?}}}",)
+            }
+            Request::Detokenize(DetokenizationRequest {
+                tokens,
+                skip_special_tokens,
+                response: _,
+                id,
+            }) => {
+                write!(
+                    f,
+                    "Detokenization Request {id} {{ tokens: {tokens:?}, skip_special_tokens: {skip_special_tokens}}}",
                 )
             }
             Request::ActivateAdapters(adapters) => {
                 write!(f, "Activate Adapters Request {adapters:?}",)
             }
+            Request::AddAdapter { name, path } => {
+                write!(f, "Add Adapter Request {name} {{ path: {path:?}}}",)
+            }
+            Request::MergeAdapter(name) => {
+                write!(f, "Merge Adapter Request {name}",)
+            }
+            Request::UnmergeAdapter => {
+                write!(f, "Unmerge Adapter Request",)
+            }
             Request::ReIsq(tp) => {
                 write!(f, "Re ISQ Request {tp:?}",)
             }
+            Request::Drain(timeout) => {
+                write!(f, "Drain Request {{ timeout: {timeout:?}}}",)
+            }
         }
     }
 }
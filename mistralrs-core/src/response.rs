@@ -20,6 +20,31 @@ macro_rules! generate_repr {
     };
 }
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// A function called by the model.
+pub struct CalledFunction {
+    pub name: String,
+    /// The arguments, as a JSON string.
+    pub arguments: String,
+}
+
+generate_repr!(CalledFunction);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// A tool call parsed from the output of the model.
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub tp: String,
+    pub function: CalledFunction,
+}
+
+generate_repr!(ToolCall);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -27,6 +52,8 @@ macro_rules! generate_repr {
 pub struct ResponseMessage {
     pub content: String,
     pub role: String,
+    /// Tool calls parsed from the output, if the request offered tools. The text around them is the content.
+    pub tool_calls: Vec<ToolCall>,
 }
 
 generate_repr!(ResponseMessage);
@@ -49,6 +76,7 @@ generate_repr!(Delta);
 pub struct ResponseLogprob {
     pub token: String,
     pub logprob: f32,
+    /// The bytes of the token, which may be part of a UTF-8 character.
     pub bytes: Vec<u8>,
     pub top_logprobs: Vec<TopLogprob>,
 }
@@ -70,6 +98,7 @@ generate_repr!(Logprobs);
 #[derive(Debug, Clone, Serialize)]
 /// Chat completion choice.
 pub struct Choice {
+    /// Why the generation stopped: `eos`, `stop`, `length`, `cancelled`, `time_limit`, `tool_calls` or `error`.
     pub finish_reason: String,
     pub index: usize,
     pub message: ResponseMessage,
@@ -83,10 +112,11 @@ generate_repr!(Choice);
 #[derive(Debug, Clone, Serialize)]
 /// Completion streaming chunk choice.
 pub struct ChunkChoice {
+    /// Set on the last chunk, like the `finish_reason` of a [`Choice`].
     pub finish_reason: Option<String>,
     pub index: usize,
     pub delta: Delta,
-    pub logprobs: Option<ResponseLogprob>,
+    pub logprobs: Option<Logprobs>,
 }
 
 generate_repr!(ChunkChoice);
@@ -101,8 +131,12 @@ pub struct Usage {
     pub total_tokens: usize,
     pub avg_tok_per_sec: f32,
     pub avg_prompt_tok_per_sec: f32,
+    /// Decoding speed, excluding the first token which is sampled at the end of the prefill.
     pub avg_compl_tok_per_sec: f32,
+    /// Time from receiving the request to sampling the first token, including any time spent waiting.
+    pub time_to_first_token_sec: f32,
     pub total_time_sec: f32,
+    /// Time spent prefilling the prompts, excluding any time spent waiting.
     pub total_prompt_time_sec: f32,
     pub total_completion_time_sec: f32,
 }
@@ -136,6 +170,8 @@ pub struct ChatCompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    /// Usage of the request, only on the last chunk, when every choice is done.
+    pub usage: Option<Usage>,
 }
 
 generate_repr!(ChatCompletionChunkResponse);
@@ -145,10 +181,12 @@ generate_repr!(ChatCompletionChunkResponse);
 #[derive(Debug, Clone, Serialize)]
 /// Completion request choice.
 pub struct CompletionChoice {
+    /// Why the generation stopped: `eos`, `stop`, `length`, `cancelled`, `time_limit`, `tool_calls` or `error`.
     pub finish_reason: String,
     pub index: usize,
     pub text: String,
-    pub logprobs: Option<()>,
+    /// With an echoed prompt, this starts with the logprobs of the prompt tokens after the first one.
+    pub logprobs: Option<Logprobs>,
 }
 
 generate_repr!(CompletionChoice);
@@ -169,13 +207,76 @@ pub struct CompletionResponse {
 
 generate_repr!(CompletionResponse);
 
-/// The response enum contains 3 types of variants:
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// The embedding of one input.
+pub struct Embedding {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+    pub object: String,
+}
+
+generate_repr!(Embedding);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Token usage of an embedding request.
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+generate_repr!(EmbeddingUsage);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// An OpenAI compatible embedding response.
+pub struct EmbeddingResponse {
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub object: String,
+    pub usage: EmbeddingUsage,
+}
+
+generate_repr!(EmbeddingResponse);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// The tokens of a prompt, as they would be passed to the model.
+pub struct TokenizationResponse {
+    pub tokens: Vec<u32>,
+    pub count: usize,
+    /// Maximum sequence length of the model, which the prompt and the generated tokens share.
+    pub max_model_len: usize,
+}
+
+generate_repr!(TokenizationResponse);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// The text of decoded tokens.
+pub struct DetokenizationResponse {
+    pub text: String,
+}
+
+generate_repr!(DetokenizationResponse);
+
+/// The response enum contains 5 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no suffix or prefix)
 /// - Completion (Completion- prefix)
+/// - Embedding
+/// - Tokenization
 pub enum Response {
     InternalError(Box<dyn Error + Send + Sync>),
     ValidationError(Box<dyn Error + Send + Sync>),
+    /// The request was rejected because too many sequences are waiting to be scheduled. It may be retried later.
+    Busy(Box<dyn Error + Send + Sync>),
     ModelError(String, ChatCompletionResponse),
     // Chat
     Done(ChatCompletionResponse),
@@ -183,4 +284,9 @@ pub enum Response {
     // Completion
     CompletionModelError(String, CompletionResponse),
     CompletionDone(CompletionResponse),
+    // Embedding
+    Embeddings(EmbeddingResponse),
+    // Tokenization
+    Tokenized(TokenizationResponse),
+    Detokenized(DetokenizationResponse),
 }
@@ -4,9 +4,10 @@ use std::{
     collections::HashMap,
     iter::zip,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use candle_core::{bail, Device, Error, Result, Tensor, D};
+use candle_core::{bail, DType, Device, Error, Result, Tensor, D};
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
 
@@ -28,13 +29,64 @@ pub struct SamplingParams {
     pub temperature: Option<f64>,
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
+    /// Only keep the logits within `top_n_sigma` standard deviations of the max logit. This is applied to the
+    /// penalized and biased logits, before temperature, top-k and top-p.
+    pub top_n_sigma: Option<f64>,
+    /// Target surprise (in bits) for Mirostat v2 sampling, which replaces top-k and top-p when set. This requires a
+    /// temperature above 0, as greedy decoding does not sample.
+    pub mirostat_tau: Option<f32>,
+    /// Learning rate for Mirostat v2 sampling. Defaults to 0.1.
+    pub mirostat_eta: Option<f32>,
+    /// Number of most likely alternatives returned with each token's logprob when logprobs are requested. A value of
+    /// at least the vocab size returns the full distribution, which is costly: every step then detokenizes the whole
+    /// vocab and the responses grow by one entry per vocab token.
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    /// Penalize the tokens among the last `repetition_penalty_range` tokens: their positive logits are divided by
+    /// this, and their negative logits multiplied. Values above 1 discourage repetition. This is applied before the
+    /// frequency and presence penalties.
+    pub repetition_penalty: Option<f32>,
+    /// Number of most recent tokens, including the prompt, which the repetition penalty looks at. Defaults to all of
+    /// them, and 0 disables the repetition penalty.
+    pub repetition_penalty_range: Option<usize>,
+    /// Ban the tokens which would complete an n-gram of this size already present in the generated tokens. The
+    /// banned tokens are masked before any other logits processing, so biases and penalties cannot allow them again.
+    pub no_repeat_ngram_size: Option<usize>,
+    /// Seed of the RNG of each sequence, for reproducible sampling. The `i`-th choice is seeded with `seed + i`, so
+    /// the choices differ from each other. Identical prompts, parameters and seeds then give identical tokens, provided
+    /// the model itself runs deterministically: some GPU kernels accumulate in a nondeterministic order, which can
+    /// change the logits slightly between runs. Without a seed, the sequences share the RNG of the engine.
+    pub seed: Option<u64>,
     pub stop_toks: Option<StopTokens>,
+    /// Token ids which finish the sequence as soon as one is sampled, in addition to `stop_toks`. These are checked
+    /// on the sampled token before detokenization, after EOS and before the length limits and stop strings. So if a
+    /// token is both a stop token id and completes a stop string, the sequence stops with the stop token.
+    pub stop_token_ids: Option<Vec<u32>>,
+    /// Token ids which finish the sequence with the `stop` finish reason, as EOS does, replacing the EOS tokens of
+    /// the model. Any of them finishes the sequence.
+    pub eos_token_ids: Option<Vec<u32>>,
+    /// Minimum number of tokens to generate. Until then, the EOS and stop tokens cannot be sampled, so the
+    /// generation only finishes early on a stop string or a limit. The counterpart of `max_len`.
+    pub min_tokens: Option<usize>,
     pub max_len: Option<usize>,
+    /// Maximum time to spend generating, counted from when the sequence is first scheduled. Once exceeded, the
+    /// sequence finishes with the `time_limit` finish reason.
+    pub max_time: Option<Duration>,
+    /// Added to the logits of the given token ids after the penalties and before any other processing. Token ids
+    /// outside the vocabulary are ignored.
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
+    /// Use beam search with this many beams instead of sampling when greater than 1. The `n_choices` best beams are
+    /// returned, ranked by score. Only the model's distribution is used, so the temperature, top-k/p, penalties and
+    /// logit bias do not apply.
+    pub num_beams: Option<usize>,
+    /// Exponent of the generated length which the cumulative logprob of a finished beam is divided by to get its
+    /// score. Defaults to 1. Values above 0 favor longer beams, values below 0 favor shorter ones.
+    pub length_penalty: Option<f32>,
+    /// Use contrastive search with this degeneration penalty, between 0 and 1, choosing among the `top_k` most likely
+    /// tokens. See [`ContrastiveParams`].
+    pub penalty_alpha: Option<f32>,
 }
 
 impl Default for SamplingParams {
@@ -43,17 +95,112 @@ impl Default for SamplingParams {
             temperature: None,
             top_k: None,
             top_p: None,
+            top_n_sigma: None,
+            mirostat_tau: None,
+            mirostat_eta: None,
             top_n_logprobs: 0,
             frequency_penalty: None,
             presence_penalty: None,
+            repetition_penalty: None,
+            repetition_penalty_range: None,
+            no_repeat_ngram_size: None,
+            seed: None,
             stop_toks: None,
+            stop_token_ids: None,
+            eos_token_ids: None,
+            min_tokens: None,
             max_len: None,
+            max_time: None,
             logits_bias: None,
             n_choices: 1,
+            num_beams: None,
+            length_penalty: None,
+            penalty_alpha: None,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+/// Parameters for Mirostat v2 sampling: <https://arxiv.org/abs/2007.14966>
+///
+/// Tokens with a surprise (`-log2(p)`) above `mu` are discarded before sampling, and `mu` is then moved towards
+/// the target surprise `tau` with learning rate `eta`. `mu` is kept per sequence.
+pub struct MirostatParams {
+    pub tau: f32,
+    pub eta: f32,
+}
+
+impl MirostatParams {
+    pub fn initial_mu(&self) -> f32 {
+        2. * self.tau
+    }
+
+    /// Update `mu` given the base 10 logprob of the sampled token.
+    pub fn next_mu(&self, mu: f32, logprob: f32) -> f32 {
+        let surprise = -logprob / std::f32::consts::LOG10_2;
+        mu - self.eta * (surprise - self.tau)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Parameters for contrastive search: <https://arxiv.org/abs/2202.06417>
+///
+/// Of the `top_k` most likely tokens, the one maximizing `(1 - penalty_alpha) * p - penalty_alpha * s` is chosen,
+/// where `s` is the highest cosine similarity between the hidden state of the token and those of the previous tokens.
+pub struct ContrastiveParams {
+    pub top_k: usize,
+    pub penalty_alpha: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// The classic repetition penalty: <https://arxiv.org/abs/1909.05858>
+///
+/// The logits of the tokens among the last `last_n` tokens of the sequence, or all of them if `None`, are divided by
+/// `penalty` if positive and multiplied by it otherwise.
+pub struct RepetitionPenalty {
+    pub penalty: f32,
+    pub last_n: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+/// The n-grams of the generated tokens, to ban the tokens which would repeat one of them.
+pub struct NgramBan {
+    size: usize,
+    // The tokens which followed each seen n-gram prefix of `size - 1` tokens
+    seen: HashMap<Vec<u32>, Vec<u32>>,
+}
+
+impl NgramBan {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Record the n-gram ending at the last of the generated tokens `toks`.
+    pub fn add(&mut self, toks: &[u32]) {
+        if self.size == 0 || toks.len() < self.size {
+            return;
+        }
+        let (prefix, next) = toks[toks.len() - self.size..].split_at(self.size - 1);
+        let followers = self.seen.entry(prefix.to_vec()).or_default();
+        if !followers.contains(&next[0]) {
+            followers.push(next[0]);
+        }
+    }
+
+    /// The tokens which would complete an n-gram already seen, following the generated tokens `toks`.
+    pub fn banned(&self, toks: &[u32]) -> &[u32] {
+        if self.size == 0 || toks.len() + 1 < self.size {
+            return &[];
+        }
+        self.seen
+            .get(&toks[toks.len() + 1 - self.size..])
+            .map_or(&[], Vec::as_slice)
+    }
+}
+
 /// Sampler for sampling.
 #[derive(Clone)]
 pub struct Sampler {
@@ -65,6 +212,9 @@ pub struct Sampler {
     logits_bias: Option<Tensor>,
     topk: i64,
     topp: f64,
+    top_n_sigma: Option<f64>,
+    mirostat: Option<MirostatParams>,
+    repetition_penalty: Option<RepetitionPenalty>,
 }
 
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
@@ -100,6 +250,9 @@ impl Sampler {
         logits_bias: Option<Tensor>,
         topk: i64,
         topp: f64,
+        top_n_sigma: Option<f64>,
+        mirostat: Option<MirostatParams>,
+        repetition_penalty: Option<RepetitionPenalty>,
     ) -> Self {
         let temperature = if temperature.map_or(true, |v| v < 1e-7) {
             None
@@ -115,9 +268,18 @@ impl Sampler {
             logits_bias,
             topk,
             topp,
+            top_n_sigma,
+            mirostat,
+            repetition_penalty,
         }
     }
 
+    /// The Mirostat parameters, if Mirostat sampling is used. Without a temperature, the tokens are chosen greedily
+    /// instead, so there is no `mu` to update.
+    pub fn mirostat(&self) -> Option<MirostatParams> {
+        self.mirostat.filter(|_| self.temperature.is_some())
+    }
+
     fn get_top_logprobs(
         &self,
         probs: &[f32],
@@ -127,21 +289,16 @@ impl Sampler {
         // Sort by descending prob
         argsort_indices_sorted
             .sort_by(|a, b| probs[*b].partial_cmp(&probs[*a]).expect("No ordering."));
-        // These are where the top n are
-        let top_n_toks_range = 0..self.top_n_logprobs;
+        // These are where the top n are. Asking for at least the vocab size returns the full distribution.
+        let top_n_toks = &argsort_indices_sorted[..self.top_n_logprobs.min(probs.len())];
         // The top n's values
-        let top_n_logprobs = argsort_indices_sorted[top_n_toks_range.clone()]
+        let top_n_logprobs = top_n_toks
             .iter()
             .map(|x| probs[*x].log(10.0))
             .collect::<Vec<_>>();
-        // Find where they actually are in the logits
-        let mut top_n_toks = Vec::new();
-        for val in top_n_toks_range {
-            top_n_toks.push(argsort_indices[val]);
-        }
 
         let mut bytes = Vec::new();
-        for tok in &top_n_toks {
+        for tok in top_n_toks {
             bytes.push(
                 self.tokenizer
                     .decode(&[*tok as u32], false)
@@ -150,7 +307,7 @@ impl Sampler {
         }
         Ok(zip(bytes, zip(top_n_toks, top_n_logprobs))
             .map(|(bytes, (token, logprob))| TopLogprob {
-                token: token as u32,
+                token: *token as u32,
                 logprob,
                 bytes,
             })
@@ -182,6 +339,30 @@ impl Sampler {
         })
     }
 
+    /// Score a given token under the raw distribution of the model, as for the prompt tokens. No penalties, bias or
+    /// temperature are applied.
+    pub fn score_token(&self, logits: &Tensor, token: u32) -> Result<Logprobs> {
+        let probs: Vec<f32> =
+            candle_nn::ops::softmax_last_dim(&logits.to_dtype(DType::F32)?)?.to_vec1()?;
+        let Some(prob) = probs.get(token as usize) else {
+            bail!(
+                "Token {token} is out of range for a vocab of {}",
+                probs.len()
+            );
+        };
+
+        let argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        Ok(Logprobs {
+            token,
+            logprob: prob.log(10.0),
+            top_logprobs: Some(self.get_top_logprobs(&probs, &argsort_indices)?),
+            bytes: self
+                .tokenizer
+                .decode(&[token], false)
+                .map_err(|x| Error::Msg(x.to_string()))?,
+        })
+    }
+
     fn sample_speculative_topkp(
         &self,
         logits: Tensor,
@@ -320,6 +501,64 @@ impl Sampler {
         self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
     }
 
+    fn sample_mirostat(
+        &self,
+        probs: &mut Vec<f32>,
+        mu: f32,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+
+        // Sort by descending probability.
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+
+        // Discard the tokens which are more surprising than mu, but always keep the most likely one.
+        let mut total = 0.;
+        for (i, index) in argsort_indices.iter().enumerate() {
+            if i > 0 && -probs[*index].log2() > mu {
+                probs[*index] = 0.0;
+            } else {
+                total += probs[*index];
+            }
+        }
+        for prob in probs.iter_mut() {
+            *prob /= total;
+        }
+
+        self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
+    }
+
+    /// Apply the repetition penalty, if any, using the tokens of the sequence so far. This is done before sampling,
+    /// so before the frequency and presence penalties which [`Self::sample`] applies over its `penalty_ctxt`.
+    pub fn apply_repetition_penalty(&self, logits: Tensor, toks: &[u32]) -> Result<Tensor> {
+        let Some(RepetitionPenalty { penalty, last_n }) = self.repetition_penalty else {
+            return Ok(logits);
+        };
+        let start = last_n.map_or(0, |last_n| toks.len().saturating_sub(last_n));
+        if start == toks.len() {
+            return Ok(logits);
+        }
+        let device = logits.device().clone();
+        let mut logits: Vec<f32> = logits.to_vec1()?;
+        let mut penalized = vec![false; logits.len()];
+        for tok in &toks[start..] {
+            let tok = *tok as usize;
+            if tok >= logits.len() || penalized[tok] {
+                continue;
+            }
+            penalized[tok] = true;
+            if logits[tok] > 0. {
+                logits[tok] /= penalty;
+            } else {
+                logits[tok] *= penalty;
+            }
+        }
+        let vocab_size = logits.len();
+        Tensor::from_vec(logits, vocab_size, &device)
+    }
+
     fn apply_penalties(&self, mut logits: Vec<f32>, context: Option<&[u32]>) -> Result<Tensor> {
         if self.frequency_penalty.is_some() || self.presence_penalty.is_some() {
             if context.is_none() {
@@ -347,10 +586,45 @@ impl Sampler {
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
     }
 
+    /// Mask the logits more than `top_n_sigma` standard deviations below the max logit. Already masked (infinite)
+    /// logits are not counted in the statistics.
+    fn apply_top_n_sigma(&self, logits: Tensor) -> Result<Tensor> {
+        let Some(n) = self.top_n_sigma else {
+            return Ok(logits);
+        };
+        let mut logits: Vec<f32> = logits.to_vec1()?;
+        let finite = logits.iter().copied().filter(|x| x.is_finite());
+        let (count, sum, max) = finite.fold((0usize, 0f64, f32::NEG_INFINITY), |(c, s, m), x| {
+            (c + 1, s + f64::from(x), m.max(x))
+        });
+        if count > 0 {
+            let mean = sum / count as f64;
+            let var = logits
+                .iter()
+                .filter(|x| x.is_finite())
+                .map(|x| (f64::from(*x) - mean).powi(2))
+                .sum::<f64>()
+                / count as f64;
+            let threshold = f64::from(max) - n * var.sqrt();
+            for logit in logits.iter_mut() {
+                if f64::from(*logit) < threshold {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+        let vocab_size = logits.len();
+        Tensor::from_vec(logits, vocab_size, &Device::Cpu)
+    }
+
     /// Sample the provided tokens.
     ///
     /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
+    /// The logits are processed in this order: frequency and presence penalties, logits bias, top-n-sigma,
+    /// temperature and softmax, top-k and then top-p. The repetition penalty is applied by the caller beforehand,
+    /// with [`Self::apply_repetition_penalty`].
     /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
+    /// If `mirostat_mu` is provided and the sampler uses Mirostat, Mirostat sampling is used instead of top-k and
+    /// top-p. The caller is responsible for updating `mu` with [`MirostatParams::next_mu`].
     /// If `frequency_penalty.is_some()` or `presence_penalty.is_some()`, then `penalty_ctxt` must be provided.
     pub fn sample(
         &self,
@@ -359,6 +633,7 @@ impl Sampler {
         return_logprobs: bool,
         rng: Arc<Mutex<Isaac64Rng>>,
         sample_speculative: bool,
+        mirostat_mu: Option<f32>,
     ) -> Result<Logprobs> {
         let logits = self.apply_penalties(logits.to_vec1()?, penalty_ctxt)?;
         let logits = match self.logits_bias {
@@ -374,7 +649,7 @@ impl Sampler {
                     self.topp as f32,
                 )?,
                 Some(temperature) => {
-                    let logits = (&logits / temperature)?;
+                    let logits = (&self.apply_top_n_sigma(logits)? / temperature)?;
                     let probs = candle_nn::ops::softmax_last_dim(&logits)?;
 
                     self.sample_speculative_topkp(
@@ -389,17 +664,22 @@ impl Sampler {
             match self.temperature {
                 None => self.sample_argmax(logits, return_logprobs)?,
                 Some(temperature) => {
-                    let logits = (&logits / temperature)?;
+                    let logits = (&self.apply_top_n_sigma(logits)? / temperature)?;
                     let probs = candle_nn::ops::softmax_last_dim(&logits)?;
                     let mut probs: Vec<f32> = probs.to_vec1()?;
 
-                    self.sample_topkp(
-                        &mut probs,
-                        self.topk,
-                        self.topp as f32,
-                        return_logprobs,
-                        rng,
-                    )?
+                    match (self.mirostat, mirostat_mu) {
+                        (Some(_), Some(mu)) => {
+                            self.sample_mirostat(&mut probs, mu, return_logprobs, rng)?
+                        }
+                        _ => self.sample_topkp(
+                            &mut probs,
+                            self.topk,
+                            self.topp as f32,
+                            return_logprobs,
+                            rng,
+                        )?,
+                    }
                 }
             }
         };
@@ -433,15 +713,99 @@ mod tests {
         use std::sync::Arc;
         use std::sync::Mutex;
 
-        let sampler = Sampler::new(None, 10, get_tokenizer().into(), None, None, None, 32, 0.1);
+        let sampler = Sampler::new(
+            None,
+            10,
+            get_tokenizer().into(),
+            None,
+            None,
+            None,
+            32,
+            0.1,
+            None,
+            None,
+            None,
+        );
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
-        let res = sampler.sample(logits, None, false, rng, false).unwrap();
+        let res = sampler
+            .sample(logits, None, false, rng, false, None)
+            .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
 
+    #[test]
+    fn test_argmax_top_logprobs() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let tokenizer = Arc::new(get_tokenizer());
+        let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+
+        let sampler = Sampler::new(
+            None,
+            3,
+            tokenizer.clone(),
+            None,
+            None,
+            None,
+            32,
+            0.1,
+            None,
+            None,
+            None,
+        );
+        let res = sampler
+            .sample(logits.clone(), None, true, rng.clone(), false, None)
+            .unwrap();
+        let top = res.top_logprobs.unwrap();
+        assert_eq!(
+            top.iter().map(|t| t.token).collect::<Vec<_>>(),
+            vec![1023, 1022, 1021]
+        );
+        assert_eq!(top[0].logprob, res.logprob);
+
+        // Asking for more than the vocab returns the full distribution
+        let sampler = Sampler::new(
+            None, 4096, tokenizer, None, None, None, 32, 0.1, None, None, None,
+        );
+        let res = sampler
+            .sample(logits, None, true, rng, false, None)
+            .unwrap();
+        assert_eq!(res.top_logprobs.unwrap().len(), 1024);
+    }
+
+    #[test]
+    fn test_score_token() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use std::sync::Arc;
+
+        let tokenizer = Arc::new(get_tokenizer());
+        // Token 1 holds half of the probability mass
+        let logits = Tensor::new(&[0f32, 2f32.ln(), 0f32], &Device::Cpu).unwrap();
+
+        let sampler = Sampler::new(
+            None, 2, tokenizer, None, None, None, 32, 0.1, None, None, None,
+        );
+        let res = sampler.score_token(&logits, 2).unwrap();
+        assert_eq!(res.token, 2);
+        assert!((res.logprob - 0.25f32.log10()).abs() < 1e-6);
+        let top = res.top_logprobs.unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].token, 1);
+        assert!((top[0].logprob - 0.5f32.log10()).abs() < 1e-6);
+
+        assert!(sampler.score_token(&logits, 3).is_err());
+    }
+
     #[test]
     fn test_gumbel_speculative() {
         use super::Sampler;
@@ -451,12 +815,154 @@ mod tests {
         use std::sync::Arc;
         use std::sync::Mutex;
 
-        let sampler = Sampler::new(None, 10, get_tokenizer().into(), None, None, None, 32, 0.1);
+        let sampler = Sampler::new(
+            None,
+            10,
+            get_tokenizer().into(),
+            None,
+            None,
+            None,
+            32,
+            0.1,
+            None,
+            None,
+            None,
+        );
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
         let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
-        let res = sampler.sample(logits, None, false, rng, true).unwrap();
+        let res = sampler
+            .sample(logits, None, false, rng, true, None)
+            .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn test_top_n_sigma() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+
+        let sampler = Sampler::new(
+            Some(1.0),
+            10,
+            get_tokenizer().into(),
+            None,
+            None,
+            None,
+            32,
+            0.1,
+            Some(1.0),
+            None,
+            None,
+        );
+        let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
+        let logits: Vec<f32> = sampler
+            .apply_top_n_sigma(logits)
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        // The std of 0..1024 is ~295.6, so 728..1024 are kept
+        let kept = logits.iter().filter(|x| x.is_finite()).count();
+        assert_eq!(kept, 296);
+        assert!(logits[727].is_infinite());
+        assert_eq!(logits[728], 728.);
+    }
+
+    #[test]
+    fn test_repetition_penalty() {
+        use super::{RepetitionPenalty, Sampler};
+        use candle_core::{Device, Tensor};
+
+        let penalty = |last_n| {
+            Sampler::new(
+                None,
+                0,
+                get_tokenizer().into(),
+                None,
+                None,
+                None,
+                32,
+                0.1,
+                None,
+                None,
+                Some(RepetitionPenalty {
+                    penalty: 2.,
+                    last_n,
+                }),
+            )
+        };
+        let logits = Tensor::new(&[4f32, -4., 4., 4.], &Device::Cpu).unwrap();
+        let apply = |last_n, toks: &[u32]| -> Vec<f32> {
+            penalty(last_n)
+                .apply_repetition_penalty(logits.clone(), toks)
+                .unwrap()
+                .to_vec1()
+                .unwrap()
+        };
+
+        // Repeated tokens are only penalized once
+        assert_eq!(apply(None, &[0, 1, 0]), vec![2., -8., 4., 4.]);
+        // Only the window is penalized
+        assert_eq!(apply(Some(2), &[0, 1, 2]), vec![4., -8., 2., 4.]);
+        assert_eq!(apply(Some(0), &[0, 1, 2]), vec![4., -4., 4., 4.]);
+    }
+
+    #[test]
+    fn test_ngram_ban() {
+        use super::NgramBan;
+
+        let mut ban = NgramBan::new(3);
+        let toks = [1, 2, 3, 1, 2, 4, 1];
+        for end in 1..=toks.len() {
+            ban.add(&toks[..end]);
+        }
+        assert!(ban.banned(&toks).is_empty());
+        // Both 1 2 3 and 1 2 4 were seen
+        assert_eq!(ban.banned(&[5, 1, 2]), &[3, 4]);
+        assert!(ban.banned(&[2]).is_empty());
+
+        // With unigrams, every generated token is banned
+        let mut ban = NgramBan::new(1);
+        ban.add(&[7]);
+        ban.add(&[7, 8]);
+        assert_eq!(ban.banned(&[7, 8]), &[7, 8]);
+    }
+
+    #[test]
+    fn test_mirostat_mu_update() {
+        use super::MirostatParams;
+
+        let mirostat = MirostatParams { tau: 3., eta: 0.1 };
+        assert_eq!(mirostat.initial_mu(), 6.);
+        // A token with p = 0.5 has a surprise of 1 bit, so mu increases
+        let mu = mirostat.next_mu(6., 0.5f32.log10());
+        assert!((mu - 6.2).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_mirostat_requires_temperature() {
+        use super::{MirostatParams, Sampler};
+
+        let tokenizer = std::sync::Arc::new(get_tokenizer());
+        let sampler = |temperature| {
+            Sampler::new(
+                temperature,
+                0,
+                tokenizer.clone(),
+                None,
+                None,
+                None,
+                -1,
+                1.0,
+                None,
+                Some(MirostatParams { tau: 3., eta: 0.1 }),
+                None,
+            )
+        };
+        assert!(sampler(Some(1.0)).mirostat().is_some());
+        // Greedy decoding does not sample, so `mu` is never updated
+        assert!(sampler(Some(0.0)).mirostat().is_none());
+        assert!(sampler(None).mirostat().is_none());
+    }
 }
@@ -1,4 +1,5 @@
 use std::{
+    cmp::Reverse,
     collections::{HashMap, VecDeque},
     sync::atomic::Ordering,
 };
@@ -14,7 +15,8 @@ pub trait FcfsBacker: Default {
     fn add(&mut self, item: Sequence);
     fn into_iter(self) -> impl Iterator<Item = Sequence>;
     fn len(&self) -> usize;
-    fn sort_ascending_ids(&mut self);
+    /// Sort by descending aged priority, then first come first serve.
+    fn sort_by_priority(&mut self);
 }
 
 impl FcfsBacker for VecDeque<Sequence> {
@@ -27,9 +29,9 @@ impl FcfsBacker for VecDeque<Sequence> {
     fn into_iter(self) -> impl Iterator<Item = Sequence> {
         <Self as IntoIterator>::into_iter(self)
     }
-    fn sort_ascending_ids(&mut self) {
+    fn sort_by_priority(&mut self) {
         let slice = self.make_contiguous();
-        slice.sort_by_key(|seq| *seq.id());
+        slice.sort_by_key(|seq| (Reverse(seq.aged_priority()), *seq.id()));
     }
     fn len(&self) -> usize {
         VecDeque::len(self)
@@ -43,8 +45,9 @@ pub struct SchedulerOutput<'a> {
 
 /// The scheduler method controld how sequences are scheduled during each
 /// step of the engine. For each scheduling step, the scheduler method is used if there
-/// are not only running, only waiting sequences, or none. If is it used, then it
-/// is used to allow waiting sequences to run.
+/// are waiting sequences. If is it used, then it is used to allow waiting sequences to run.
+///
+/// `Fixed(n)` runs at most `n` sequences at once, which is the maximum batch size.
 pub enum SchedulerMethod {
     Fixed(UsizeBounded<1, { usize::MAX }, false>),
 }
@@ -75,9 +78,9 @@ pub trait BucketingManager<Backer: FcfsBacker> {
     ) -> BucketedSeqs<Backer>;
 }
 
-// (adapters, cache length, (has_imgs && is_prompt))
+// (adapters, LoRA scale bits, cache length, (has_imgs && is_prompt))
 // Buckey by that metric for images because if we are not a prompt, then this doesn't apply
-type BucketKey = (Option<Vec<String>>, usize, bool);
+type BucketKey = (Option<Vec<String>>, u32, usize, bool);
 
 struct FixedBucketingManager;
 
@@ -91,6 +94,11 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
         mut waiting: Backer,
         discrete: bool,
     ) -> BucketedSeqs<Backer> {
+        // Sequences in the middle of a chunked prefill are run on their own, so they do not need a bucket.
+        let (prefilling, running): (Vec<_>, Vec<_>) = running
+            .into_iter()
+            .partition(|seq| seq.is_prefilling_chunks());
+
         // Now, get the sequences with the smallest sequence lengths, and allow them to catch up.
         let mut seq_buckets: HashMap<BucketKey, Vec<Sequence>> = HashMap::new();
         let mut seq_priorities: HashMap<BucketKey, f64> = HashMap::new();
@@ -98,6 +106,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
             let len = seq.len();
             match seq_buckets.get_mut(&(
                 seq.get_adapters(),
+                seq.lora_scale().to_bits(),
                 len,
                 seq.images().is_some() && seq.is_prompt(),
             )) {
@@ -106,6 +115,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                         *seq_priorities
                             .get_mut(&(
                                 seq.get_adapters(),
+                                seq.lora_scale().to_bits(),
                                 len,
                                 seq.images().is_some() && seq.is_prompt(),
                             ))
@@ -118,6 +128,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                         seq_priorities.insert(
                             (
                                 seq.get_adapters(),
+                                seq.lora_scale().to_bits(),
                                 len,
                                 seq.images().is_some() && seq.is_prompt(),
                             ),
@@ -127,6 +138,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                     seq_buckets.insert(
                         (
                             seq.get_adapters(),
+                            seq.lora_scale().to_bits(),
                             len,
                             seq.images().is_some() && seq.is_prompt(),
                         ),
@@ -135,7 +147,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
                 }
             }
         }
-        let running = if seq_buckets.len() <= 1 {
+        let mut running = if seq_buckets.len() <= 1 {
             // Full steam ahead or have everything
             seq_buckets
                 .into_iter()
@@ -147,7 +159,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
             // Allow the min seqs to catch up.
             let min = seq_buckets
                 .keys()
-                .min_by_key(|(_, x, _)| *x)
+                .min_by_key(|(_, _, x, _)| *x)
                 .expect("No sequence buckets.")
                 .clone();
             let len = if !discrete {
@@ -174,6 +186,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
             // Know min_seqs.len < running.len() <= max
             highest_priority_seqs
         };
+        running.extend(prefilling);
         BucketedSeqs { running, waiting }
     }
 }
@@ -183,6 +196,8 @@ pub struct Scheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: SchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    // After a preemption, the number of sequences which fit on the device, until the preempted ones are re-admitted
+    running_limit: Option<usize>,
 }
 
 impl<Backer: FcfsBacker> Scheduler<Backer> {
@@ -195,6 +210,7 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            running_limit: None,
         }
     }
 
@@ -211,6 +227,11 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
         self.waiting.len()
     }
 
+    /// Remove the waiting sequences, including the preempted ones.
+    pub fn take_waiting(&mut self) -> Vec<Sequence> {
+        std::mem::take(&mut self.waiting).into_iter().collect()
+    }
+
     /// Move the seuqences into buckets, and run the ones with the shortest lengths.
     /// The others are moved to the waiting list (retaining high priority due to start time),
     /// without a state modification.
@@ -224,14 +245,49 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
     }
 
     /// Schedule all sequences based on their state and the available space.
+    ///
+    /// The running batch is re-formed on every step (continuous batching): finished sequences are retired, and
+    /// waiting sequences are admitted into the free slots as prompts while the others keep decoding. Each step, the
+    /// running sequences are bucketed by their LoRA adapters and scale, which apply to the whole batch, and by length,
+    /// since their KV caches are concatenated along the batch dimension by `clone_in_cache` and split again by
+    /// `clone_out_cache`. This copy of the batch's KV caches is the per-step
+    /// overhead, and it is skipped by the engine for completion steps whose batch did not change.
+    ///
+    /// Sequences preempted by the engine after the device ran out of memory are moved back to the waiting list, and
+    /// the number of running sequences is limited to those left, so that the preempted ones are re-admitted as
+    /// completions as soon as enough running sequences finish.
     pub fn schedule(&mut self) -> SchedulerOutput {
-        // Filter out all done sequences
+        // Cancel abandoned sequences, and filter out all done sequences. Dropping them frees their KV caches, and as
+        // they never finish they are not added to the prefix cache.
         let running = std::mem::take(&mut self.running);
-        let mut waiting = std::mem::take(&mut self.waiting);
+        let mut waiting = Backer::new();
+        for seq in std::mem::take(&mut self.waiting).into_iter() {
+            if seq.is_abandoned() {
+                seq.set_state(SequenceState::Done(StopReason::Canceled));
+            } else {
+                waiting.add(seq);
+            }
+        }
+        let mut n_preempted = 0;
         let mut running = running
             .into_iter()
-            .filter(|seq| seq.is_running())
+            .filter_map(|seq| {
+                if seq.is_abandoned() {
+                    seq.set_state(SequenceState::Done(StopReason::Canceled));
+                }
+                if seq.is_preempted() {
+                    n_preempted += 1;
+                    waiting.add(seq);
+                    return None;
+                }
+                seq.is_running().then_some(seq)
+            })
             .collect::<Vec<_>>();
+        if n_preempted > 0 {
+            self.running_limit = Some(running.len().max(1));
+        } else if waiting.len() == 0 {
+            self.running_limit = None;
+        }
 
         match (waiting.len(), running.len()) {
             (0, 0) => {
@@ -241,19 +297,6 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
                     completion: vec![].into(),
                 };
             }
-            (_, 0) => {
-                for seq in waiting.into_iter() {
-                    seq.set_state(SequenceState::RunningPrompt);
-                    self.running.push(seq);
-                }
-                self.waiting = Backer::new();
-                let running = std::mem::take(&mut self.running);
-                self.running = self.bucket_and_waitlist_seqs(running);
-                return SchedulerOutput {
-                    prompt: self.running.iter_mut().collect::<Vec<_>>().into(),
-                    completion: vec![].into(),
-                };
-            }
             (0, _) => {
                 self.running = self.bucket_and_waitlist_seqs(running);
                 if TERMINATE_ALL_NEXT_STEP.load(Ordering::SeqCst) {
@@ -262,29 +305,40 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
                         .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
                     TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
                 }
+                // Only sequences in the middle of a chunked prefill may still be prompts
+                let (prompt, completion): (Vec<_>, Vec<_>) =
+                    self.running.iter_mut().partition(|seq| seq.is_prompt());
                 return SchedulerOutput {
-                    prompt: vec![].into(),
-                    completion: self.running.iter_mut().collect::<Vec<_>>().into(),
+                    prompt: prompt.into(),
+                    completion: completion.into(),
                 };
             }
             _ => {}
         }
 
         // Sort the waiting seqs
-        waiting.sort_ascending_ids();
+        waiting.sort_by_priority();
 
-        // If the waiting sequence will fit, add it. Otherwise remove it
+        // If the waiting sequence will fit, add it. Otherwise remove it, aging it
         let mut new_waiting = Backer::new();
+        let mut still_preempted = false;
         for seq in waiting.into_iter() {
             if self.sequence_fits(&running, &seq) {
                 if seq.is_waiting() {
                     seq.set_state(SequenceState::RunningPrompt);
+                } else if seq.is_preempted() {
+                    // Its KV caches are moved back to the device by the engine
+                    seq.set_state(SequenceState::RunningCompletion);
                 }
                 running.push(seq);
             } else {
-                new_waiting.add(seq);
+                still_preempted |= seq.is_preempted();
+                new_waiting.add(seq.add_urgency());
             }
         }
+        if !still_preempted {
+            self.running_limit = None;
+        }
 
         let BucketedSeqs {
             running,
@@ -313,8 +367,12 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
     }
 
     fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
-        match &self.method {
-            SchedulerMethod::Fixed(n) => (running.len() + 1) <= **n,
-        }
+        let max_running = match &self.method {
+            SchedulerMethod::Fixed(n) => **n,
+        };
+        running.len()
+            < self
+                .running_limit
+                .map_or(max_running, |limit| limit.min(max_running))
     }
 }
@@ -1,7 +1,7 @@
 use std::{
     fmt::Display,
-    sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{Arc, OnceLock, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::{
     mpsc::{error::SendError, Sender},
@@ -15,13 +15,107 @@ use crate::{
 };
 use crate::{
     get_mut_group,
-    pipeline::LayerCaches,
+    pipeline::{BlockTable, KvBlock, LayerCaches},
     response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
-    sampler::{Logprobs, Sampler},
+    sampler::{ContrastiveParams, Logprobs, NgramBan, Sampler},
     ChatCompletionResponse, Usage,
 };
-use candle_core::Tensor;
+use candle_core::{Device, Tensor};
+use rand::SeedableRng;
+use rand_isaac::Isaac64Rng;
 use regex_automata::util::primitives::StateID;
+use tracing::{debug_span, info};
+
+/// Decode the complete UTF-8 characters at the start of `bytes`, returning the text and the number of bytes used.
+/// Invalid bytes are replaced with U+FFFD, while an incomplete character at the end is left undecoded unless `flush`.
+fn decode_complete_utf8(bytes: &[u8], flush: bool) -> (String, usize) {
+    let mut text = String::new();
+    let mut consumed = 0;
+    while consumed < bytes.len() {
+        match std::str::from_utf8(&bytes[consumed..]) {
+            Ok(valid) => {
+                text.push_str(valid);
+                consumed = bytes.len();
+            }
+            Err(e) => {
+                let valid_end = consumed + e.valid_up_to();
+                text.push_str(&String::from_utf8_lossy(&bytes[consumed..valid_end]));
+                match e.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        consumed = valid_end + len;
+                    }
+                    None if flush => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        consumed = bytes.len();
+                    }
+                    None => return (text, valid_end),
+                }
+            }
+        }
+    }
+    (text, consumed)
+}
+
+/// Find the earliest stop string ending in `new_bytes`, the bytes of a new token, which follow `completion_bytes`.
+/// Stop strings ending in the earlier bytes were already searched for, so only the end of `completion_bytes` is
+/// searched, far enough back to catch the longest stop string spanning the boundary between the tokens. Returns the
+/// index of the stop string and its position in the completion bytes.
+fn find_stop_string(
+    completion_bytes: &[u8],
+    new_bytes: &[u8],
+    stop_strings: &[String],
+) -> Option<(usize, usize)> {
+    let max_len = stop_strings.iter().map(String::len).max()?;
+    let start = completion_bytes
+        .len()
+        .saturating_sub(max_len.saturating_sub(1));
+    let mut window = completion_bytes[start..].to_vec();
+    window.extend_from_slice(new_bytes);
+    stop_strings
+        .iter()
+        .enumerate()
+        .filter(|(_, stop)| !stop.is_empty())
+        .filter_map(|(idx, stop)| {
+            galil_seiferas::gs_find(&window, stop.as_bytes()).map(|pos| (idx, start + pos))
+        })
+        .min_by_key(|(_, pos)| *pos)
+}
+
+/// Number of bytes at the end of `bytes` which may be the start of a stop string. They are not streamed until the
+/// next tokens show whether the stop string follows.
+fn stop_string_prefix_len(bytes: &[u8], stop_strings: &[String]) -> usize {
+    stop_strings
+        .iter()
+        .filter_map(|stop| {
+            let stop = stop.as_bytes();
+            (1..stop.len().min(bytes.len() + 1))
+                .rev()
+                .find(|&len| bytes.ends_with(&stop[..len]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Why a sequence of `n_toks` tokens, `prompt_len` of which are the prompt, stops for its length, if it does.
+fn length_stop(
+    n_toks: usize,
+    prompt_len: usize,
+    max_len: Option<usize>,
+    max_model_len: usize,
+) -> Option<StopReason> {
+    match max_len {
+        Some(max_len) if n_toks.saturating_sub(prompt_len) >= max_len => {
+            Some(StopReason::Length(max_len))
+        }
+        // The prompt and generated tokens fill the context of the model
+        _ if n_toks >= max_model_len => Some(StopReason::ModelLength(max_model_len)),
+        _ => None,
+    }
+}
+
+/// The number of scheduling passes a waiting sequence needs to gain one priority level.
+const PRIORITY_AGING_PASSES: usize = 8;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum StopReason {
@@ -34,15 +128,20 @@ pub enum StopReason {
         completion_bytes_pos: usize,
     },
     Canceled,
+    TimeLimit,
 }
 
+/// The `finish_reason` of the responses: `eos` for an EOS token of the model, `stop` for a stop token or string of the
+/// request, `length` for the maximum number of tokens of the request or the context of the model, `cancelled` and
+/// `time_limit`.
 impl Display for StopReason {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            StopReason::Eos => write!(f, "stop"),
+            StopReason::Eos => write!(f, "eos"),
             StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
             StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
-            StopReason::Canceled => write!(f, "canceled"),
+            StopReason::Canceled => write!(f, "cancelled"),
+            StopReason::TimeLimit => write!(f, "time_limit"),
         }
     }
 }
@@ -55,6 +154,8 @@ pub enum SequenceState {
     Waiting,
     Error,
     RunningPrefillPrompt,
+    // Descheduled after the device ran out of memory, with its KV caches on the CPU, until it is re-admitted
+    Preempted,
 }
 
 pub enum SequenceRecognizer {
@@ -68,6 +169,8 @@ pub struct Sequence {
     id: usize,
     prompt_len: usize,
     max_len: Option<usize>,
+    max_time: Option<Duration>,
+    start_time: OnceLock<Instant>,
     timestamp: u128,
     sampler: Arc<Sampler>,
     stop_tokens: Vec<u32>,
@@ -77,32 +180,58 @@ pub struct Sequence {
     response_index: usize,
     creation_time: u64,
     prefill_prompt_toks: Option<Vec<u32>>,
+    prefill_chunk_offset: Option<usize>,
     suffix: Option<String>,
     prefix: Option<String>,
     is_tmp: bool,
     adapters: Option<Vec<String>>,
+    // Multiplies the contribution of the LoRA adapters
+    lora_scale: f32,
+    beam_length_penalty: Option<f32>,
+    contrastive: Option<ContrastiveParams>,
+    // The other choices of the request, scheduled once the prompt of this sequence is prefilled
+    forks: Vec<Sequence>,
+    // Replaces the EOS tokens of the model
+    eos_toks: Option<Vec<u32>>,
+    // Tokens which cannot be sampled, nor finish the sequence, before `min_tokens` tokens are generated
+    min_tokens: usize,
+    min_tokens_suppressed: Vec<u32>,
 
     // Cache
     scaling_cache: Option<Tensor>,
-    cache: LayerCaches,
-    draft_cache: LayerCaches,
-    xlora_cache: Option<LayerCaches>,
+    mirostat_mu: Option<f32>,
+    rng: Option<Arc<std::sync::Mutex<Isaac64Rng>>>,
+    cache: LayerCaches<KvBlock>,
+    draft_cache: LayerCaches<KvBlock>,
+    xlora_cache: Option<LayerCaches<KvBlock>>,
+    // The blocks of the KV cache, if it is paged
+    block_table: Option<BlockTable>,
+    context_hidden_states: Option<Tensor>,
+    medusa_hidden_state: Option<Tensor>,
 
     // Mutables
     tokens: Vec<u32>,
     logprobs: Vec<Logprobs>,
+    prompt_logprobs: Option<Vec<Logprobs>>,
     cumulative_logprob: f32,
+    ngram_ban: Option<NgramBan>,
+    parse_tool_calls: bool,
     last_logprob: f32,
     last_completion_bytes_len: usize,
     last_is_done: Option<StopReason>,
     completion_bytes: Vec<u8>,
     stream_idx: usize,
+    stream_logprobs_idx: usize,
     pub recognizer: SequenceRecognizer,
     scheduling_urgency: usize, // The number of passes since scheduling
+    priority: usize,
     input_images: Option<Vec<image::DynamicImage>>,
 
     // GPU things
     pub prompt_tok_per_sec: f32,
+    /// When the prefill of the prompt started, in ms. The time between the request and this is spent waiting.
+    pub prefill_timestamp: Option<u128>,
+    /// When the prefill of the prompt finished, in ms. This is also when the first token is sampled.
     pub prompt_timestamp: Option<u128>,
     group: Arc<Mutex<SequenceGroup>>,
     state: RwLock<SequenceState>,
@@ -120,6 +249,7 @@ impl Sequence {
         stop_tokens: Vec<u32>,
         stop_strings: Vec<String>,
         max_len: Option<usize>,
+        max_time: Option<Duration>,
         return_logprobs: bool,
         is_xlora: bool,
         group: Arc<Mutex<SequenceGroup>>,
@@ -130,11 +260,13 @@ impl Sequence {
         prefix: Option<String>,
         adapters: Option<Vec<String>>,
         input_images: Option<Vec<image::DynamicImage>>,
+        priority: usize,
     ) -> Self {
         let prompt_len = tokens.len();
         Self {
             tokens,
             logprobs: Vec::new(),
+            prompt_logprobs: None,
             prompt_len,
             id,
             timestamp,
@@ -146,32 +278,52 @@ impl Sequence {
             } else {
                 None
             },
+            block_table: None,
             responder,
             sampler: sampler.into(),
             stop_tokens,
             stop_strings,
             max_len,
+            max_time,
+            start_time: OnceLock::new(),
             return_logprobs,
             prompt_tok_per_sec: 0.,
+            prefill_timestamp: None,
             prompt_timestamp: None,
             group,
             scaling_cache: None,
+            mirostat_mu: None,
             response_index,
             creation_time,
             recognizer,
             prefill_prompt_toks: None,
+            prefill_chunk_offset: None,
             suffix,
             prefix,
             cumulative_logprob: 0.,
             completion_bytes: Vec::new(),
             stream_idx: 0,
+            stream_logprobs_idx: 0,
             last_completion_bytes_len: 0,
             last_logprob: 0.0,
             last_is_done: None,
             is_tmp: false,
             scheduling_urgency: 0,
+            priority,
             adapters,
+            lora_scale: 1.,
             input_images,
+            beam_length_penalty: None,
+            contrastive: None,
+            forks: Vec::new(),
+            eos_toks: None,
+            min_tokens: 0,
+            min_tokens_suppressed: Vec::new(),
+            rng: None,
+            context_hidden_states: None,
+            medusa_hidden_state: None,
+            ngram_ban: None,
+            parse_tool_calls: false,
         }
     }
 
@@ -180,23 +332,97 @@ impl Sequence {
         self
     }
 
+    /// Score every prompt token (after the first) under the model during the prompt step.
+    pub fn with_prompt_logprobs(mut self) -> Self {
+        self.prompt_logprobs = Some(Vec::new());
+        self
+    }
+
+    /// Scale the contribution of the LoRA adapters: 0 disables them and 1 is their full strength.
+    pub fn with_lora_scale(mut self, lora_scale: f32) -> Self {
+        self.lora_scale = lora_scale;
+        self
+    }
+
+    /// Run the prompt of this sequence for the `forks` too, which are the other choices of its request. They are
+    /// scheduled with its KV cache once the prompt is prefilled, and sample independently.
+    pub fn with_forks(mut self, forks: Vec<Sequence>) -> Self {
+        self.forks = forks;
+        self
+    }
+
+    pub fn take_forks(&mut self) -> Vec<Sequence> {
+        std::mem::take(&mut self.forks)
+    }
+
+    /// Finish with EOS on any of `eos_toks` instead of the EOS tokens of the model.
+    pub fn with_eos_toks(mut self, eos_toks: Vec<u32>) -> Self {
+        self.eos_toks = Some(eos_toks);
+        self
+    }
+
+    /// Generate at least `min_tokens` tokens before any of the `suppressed` EOS or stop tokens can be sampled.
+    pub fn with_min_tokens(mut self, min_tokens: usize, suppressed: Vec<u32>) -> Self {
+        self.min_tokens = min_tokens;
+        self.min_tokens_suppressed = suppressed;
+        self
+    }
+
+    /// Make this sequence one beam of a beam search, scored with the given length penalty.
+    pub fn with_beam_search(mut self, length_penalty: f32) -> Self {
+        self.beam_length_penalty = Some(length_penalty);
+        self
+    }
+
+    /// Choose the tokens of this sequence with contrastive search.
+    pub fn with_contrastive_search(mut self, params: ContrastiveParams) -> Self {
+        self.contrastive = Some(params);
+        self
+    }
+
+    /// Sample this sequence with its own RNG, seeded with `seed`, instead of the RNG shared by the engine.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Some(Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(
+            seed,
+        ))));
+        self
+    }
+
+    /// Ban the tokens which would repeat an n-gram of the generated tokens of this size.
+    pub fn with_no_repeat_ngram_size(mut self, size: usize) -> Self {
+        self.ngram_ban = Some(NgramBan::new(size));
+        self
+    }
+
+    /// Parse the tool calls out of the finished chat completion.
+    pub fn with_tool_calls(mut self) -> Self {
+        self.parse_tool_calls = true;
+        self
+    }
+
     pub fn reset_urgency(mut self) -> Self {
         self.scheduling_urgency = 0;
         self
     }
 
-    /// Simple metric: (scheduling urgency) + log2(length)
+    /// Simple metric: (requested priority) + (scheduling urgency) + log2(length)
     /// Takes into account: urgency (scales linear) and length (scales logarithmic)
     /// Scaling urgency is the number of scheduling passes where we have not been scheduled.
     pub fn compute_priority(&self) -> f64 {
         #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
-        (self.scheduling_urgency as f64) + (self.len() as f64).log2()
+        (self.priority as f64) + (self.scheduling_urgency as f64) + (self.len() as f64).log2()
+    }
+
+    /// The priority for admitting waiting sequences: the requested priority, aged by one for every
+    /// `PRIORITY_AGING_PASSES` scheduling passes spent waiting.
+    pub fn aged_priority(&self) -> usize {
+        self.priority + self.scheduling_urgency / PRIORITY_AGING_PASSES
     }
 
     pub fn prefill(
         mut self,
-        cache: LayerCaches,
-        xlora_cache: Option<LayerCaches>,
+        cache: LayerCaches<KvBlock>,
+        xlora_cache: Option<LayerCaches<KvBlock>>,
         toks: Vec<u32>,
     ) -> Self {
         self.cache = cache;
@@ -219,11 +445,13 @@ impl Sequence {
             self.xlora_cache.as_ref().unwrap()[0]
                 .as_ref()
                 .unwrap()
-                .0
-                .dims()[2]
+                .seq_len()
                 + 1
-        } else if let Some((_, x)) = &self.cache[0] {
-            x.dims()[2] + 1
+        } else if let Some(block) = &self.cache[0] {
+            block.seq_len() + 1
+        } else if let Some(table) = &self.block_table {
+            // A paged KV cache is not held in `cache`
+            table.len() + 1
         } else {
             self.tokens.len()
         }
@@ -252,6 +480,10 @@ impl Sequence {
         *self.state.read().unwrap() == SequenceState::Waiting
     }
 
+    pub fn is_preempted(&self) -> bool {
+        *self.state.read().unwrap() == SequenceState::Preempted
+    }
+
     pub fn get_toks(&self) -> &[u32] {
         if let Some(toks) = &self.prefill_prompt_toks {
             return toks;
@@ -259,6 +491,11 @@ impl Sequence {
         &self.tokens
     }
 
+    /// The prompt and generated tokens. Unlike `get_toks`, this does not return the prefill tokens.
+    pub(crate) fn all_toks(&self) -> &[u32] {
+        &self.tokens
+    }
+
     /// This will also set prompt_len
     pub(crate) fn set_toks(&mut self, toks: Vec<u32>) {
         self.tokens = toks;
@@ -269,15 +506,15 @@ impl Sequence {
         &self.completion_bytes
     }
 
-    pub fn cache(&mut self) -> &mut Vec<Option<(Tensor, Tensor)>> {
+    pub fn cache(&mut self) -> &mut LayerCaches<KvBlock> {
         &mut self.cache
     }
 
-    pub fn draft_cache(&mut self) -> &mut Vec<Option<(Tensor, Tensor)>> {
+    pub fn draft_cache(&mut self) -> &mut LayerCaches<KvBlock> {
         &mut self.draft_cache
     }
 
-    pub fn xlora_cache(&mut self) -> &mut Vec<Option<(Tensor, Tensor)>> {
+    pub fn xlora_cache(&mut self) -> &mut LayerCaches<KvBlock> {
         self.xlora_cache.as_mut().expect("No X-LoRA cache.")
     }
 
@@ -285,6 +522,49 @@ impl Sequence {
         &mut self.scaling_cache
     }
 
+    /// The blocks of the KV cache of this sequence if it is paged, in which case [`Self::cache`] is empty.
+    pub fn block_table(&mut self) -> &mut Option<BlockTable> {
+        &mut self.block_table
+    }
+
+    /// Move the KV caches of this sequence to `device`, such as the CPU when it is preempted and the device of the
+    /// model when it runs again. Caches already on `device` are not copied.
+    pub fn move_caches(&mut self, device: &Device) -> candle_core::Result<()> {
+        let blocks = self
+            .cache
+            .iter_mut()
+            .chain(self.draft_cache.iter_mut())
+            .chain(self.xlora_cache.iter_mut().flatten())
+            .flatten();
+        KvBlock::to_device_batched(blocks, device)?;
+        for t in [&mut self.scaling_cache, &mut self.context_hidden_states]
+            .into_iter()
+            .flatten()
+        {
+            if !t.device().same_device(device) {
+                *t = t.to_device(device)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The hidden states of the tokens of this sequence, `(seq_len, hidden_size)`, for contrastive search. They are
+    /// `None` until the first token is chosen.
+    pub fn context_hidden_states(&mut self) -> &mut Option<Tensor> {
+        &mut self.context_hidden_states
+    }
+
+    /// The final hidden state of the target model at the last token of this sequence, `(hidden_size)`, from which
+    /// Medusa heads propose the draft tokens of the next step. It is `None` until the first step.
+    pub fn medusa_hidden_state(&mut self) -> &mut Option<Tensor> {
+        &mut self.medusa_hidden_state
+    }
+
+    /// The Mirostat `mu` state of this sequence. It is `None` until the first token is sampled with Mirostat.
+    pub fn mirostat_mu(&mut self) -> &mut Option<f32> {
+        &mut self.mirostat_mu
+    }
+
     pub fn is_xlora(&self) -> bool {
         self.xlora_cache.is_some()
     }
@@ -303,6 +583,49 @@ impl Sequence {
         self.prefill_prompt_toks = None
     }
 
+    /// Whether the prompt of this sequence is prefilled in chunks of `chunk_size` tokens, over several steps.
+    pub fn is_chunked_prefill(&self, chunk_size: usize) -> bool {
+        *self.state.read().unwrap() == SequenceState::RunningPrompt
+            && self.input_images.is_none()
+            && (self.prefill_chunk_offset.is_some() || self.tokens.len() > chunk_size)
+    }
+
+    /// Whether a chunked prefill was started but not yet completed.
+    pub fn is_prefilling_chunks(&self) -> bool {
+        self.prefill_chunk_offset.is_some()
+    }
+
+    /// The position of the first token returned by `get_toks`.
+    pub fn prompt_offset(&self) -> usize {
+        self.prefill_chunk_offset.unwrap_or(0)
+    }
+
+    /// Set the next chunk of at most `chunk_size` prompt tokens as the prefill tokens. Returns whether this is the
+    /// first chunk, and whether it is the last one, after which a token is sampled as usual.
+    pub fn set_next_prefill_chunk(&mut self, chunk_size: usize) -> (bool, bool) {
+        let is_first = self.prefill_chunk_offset.is_none();
+        let offset = self.prefill_chunk_offset.unwrap_or(0);
+        let end = (offset + chunk_size).min(self.tokens.len());
+        self.prefill_prompt_toks = Some(self.tokens[offset..end].to_vec());
+        self.prefill_chunk_offset = Some(offset);
+        (is_first, end == self.tokens.len())
+    }
+
+    /// Whether the prefill tokens are a chunk of the prompt which is not the last one, so no token is sampled.
+    pub fn is_intermediate_prefill_chunk(&self) -> bool {
+        match (self.prefill_chunk_offset, &self.prefill_prompt_toks) {
+            (Some(offset), Some(toks)) => offset + toks.len() < self.tokens.len(),
+            _ => false,
+        }
+    }
+
+    /// Move the prefill cursor past the current chunk, once its KV cache was stored.
+    pub fn advance_prefill_chunk(&mut self) {
+        if let Some(toks) = self.prefill_prompt_toks.take() {
+            *self.prefill_chunk_offset.get_or_insert(0) += toks.len();
+        }
+    }
+
     /// Internal api to add one raw token.
     pub(crate) fn add_tmp_tok(&mut self, tok: u32) {
         self.is_tmp = true;
@@ -337,14 +660,92 @@ impl Sequence {
 
         self.cumulative_logprob += tok.logprob;
         self.tokens.push(tok.token);
+        if let Some(ngram_ban) = &mut self.ngram_ban {
+            ngram_ban.add(&self.tokens[self.prompt_len..]);
+        }
         self.logprobs.push(tok);
         self.prefill_prompt_toks = None;
+        self.prefill_chunk_offset = None;
+    }
+
+    pub fn is_beam_search(&self) -> bool {
+        self.beam_length_penalty.is_some()
+    }
+
+    pub fn contrastive(&self) -> Option<ContrastiveParams> {
+        self.contrastive
+    }
+
+    /// The RNG of this sequence, if it was seeded.
+    pub fn rng(&self) -> Option<Arc<std::sync::Mutex<Isaac64Rng>>> {
+        self.rng.clone()
+    }
+
+    /// Whether sampling this sequence replaces KV caches, by forking beams or running the candidates of a contrastive
+    /// search through the model. The caches of the sequences are then cloned in at the next step.
+    pub fn modifies_cache(&self) -> bool {
+        self.is_beam_search() || self.contrastive.is_some()
+    }
+
+    /// Whether both sequences belong to the same request.
+    pub fn same_group(&self, other: &Sequence) -> bool {
+        Arc::ptr_eq(&self.group, &other.group)
+    }
+
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
+    /// The score used to rank the choices of a request. For a beam, this is the cumulative logprob divided by the
+    /// generated length to the power of the length penalty. For other sequences, this is the mean logprob of the
+    /// generated tokens.
+    pub fn score(&self) -> f32 {
+        #[allow(clippy::cast_precision_loss)]
+        let generated = self.logprobs.len().max(1) as f32;
+        // Beams are ranked with their length penalty, other sequences by their mean logprob
+        self.cumulative_logprob / generated.powf(self.beam_length_penalty.unwrap_or(1.))
+    }
+
+    /// Snapshot the generated tokens and caches, to fork another beam from this one.
+    pub(crate) fn beam_state(&self) -> BeamState {
+        BeamState {
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            completion_bytes: self.completion_bytes.clone(),
+            last_completion_bytes_len: self.last_completion_bytes_len,
+            last_logprob: self.last_logprob,
+            last_is_done: self.last_is_done,
+            cache: self.cache.clone(),
+            xlora_cache: self.xlora_cache.clone(),
+            scaling_cache: self.scaling_cache.clone(),
+        }
+    }
+
+    /// Replace the generated tokens and caches of this beam with those of another one.
+    pub(crate) fn set_beam_state(&mut self, state: BeamState) {
+        self.tokens = state.tokens;
+        self.logprobs = state.logprobs;
+        self.cumulative_logprob = state.cumulative_logprob;
+        self.completion_bytes = state.completion_bytes;
+        self.last_completion_bytes_len = state.last_completion_bytes_len;
+        self.last_logprob = state.last_logprob;
+        self.last_is_done = state.last_is_done;
+        self.cache = state.cache;
+        self.xlora_cache = state.xlora_cache;
+        self.scaling_cache = state.scaling_cache;
     }
 
     pub fn responder(&self) -> Sender<Response> {
         self.responder.clone()
     }
 
+    /// Whether the receiver of the responses was dropped, such as when the client disconnected, so that nobody is
+    /// waiting for this sequence anymore.
+    pub fn is_abandoned(&self) -> bool {
+        self.responder.is_closed()
+    }
+
     pub fn creation_time(&self) -> u64 {
         self.creation_time
     }
@@ -353,59 +754,125 @@ impl Sequence {
         if matches!(state, SequenceState::Error) {
             get_mut_group!(self).n_choices -= 1;
         }
+        if matches!(
+            state,
+            SequenceState::RunningPrompt | SequenceState::RunningPrefillPrompt
+        ) {
+            // Only the first scheduling counts
+            let _ = self.start_time.set(Instant::now());
+        }
         *self.state.write().unwrap() = state;
     }
 
+    /// Why the sequence stops with the token `tok`, whose bytes are `tok_bytes`, if it does. This is checked before the
+    /// token is added, so the length limits count it as well.
     pub fn is_done(
         &self,
         tok: u32,
+        tok_bytes: &[u8],
         eos_tok: Option<&[u32]>,
         max_model_len: usize,
     ) -> Option<StopReason> {
-        let is_eos = match eos_tok {
-            Some(eos_tok) => eos_tok.iter().any(|t| *t == tok),
-            None => false,
-        };
-        if is_eos {
+        let below_min_tokens = self.below_min_tokens();
+        if !below_min_tokens && is_eos(tok, eos_tok, self.eos_toks.as_deref()) {
             Some(StopReason::Eos)
         } else if matches!(
             &*self.state.read().unwrap(),
             SequenceState::Done(StopReason::Canceled)
         ) {
             Some(StopReason::Canceled)
-        } else if self.stop_tokens.contains(&tok) {
+        } else if !below_min_tokens && self.stop_tokens.contains(&tok) {
             Some(StopReason::StopTok(tok))
-        } else if self.max_len.is_some()
-            && self.tokens.len().saturating_sub(self.prompt_len) == self.max_len.unwrap()
+        } else if let Some((stop_string_idx, completion_bytes_pos)) =
+            find_stop_string(&self.completion_bytes, tok_bytes, &self.stop_strings)
         {
-            // add_token was already called
-            Some(StopReason::Length(self.max_len.unwrap()))
-        } else if self.tokens.len().saturating_sub(self.prompt_len) == max_model_len {
-            Some(StopReason::ModelLength(max_model_len))
+            // Checked before the length, so that the stop string is trimmed from the output
+            Some(StopReason::StopString {
+                stop_string_idx,
+                completion_bytes_pos,
+            })
+        } else if let Some(reason) = length_stop(
+            self.tokens.len() + 1,
+            self.prompt_len,
+            self.max_len,
+            max_model_len,
+        ) {
+            Some(reason)
         } else {
-            if !self.stop_strings.is_empty() {
-                for (idx, s) in self.stop_strings.iter().enumerate() {
-                    if let Some(pos) = galil_seiferas::gs_find(&self.completion_bytes, s.as_bytes())
-                    {
-                        return Some(StopReason::StopString {
-                            stop_string_idx: idx,
-                            completion_bytes_pos: pos,
-                        });
-                    }
-                }
+            if self.max_time.is_some_and(|max_time| {
+                self.start_time
+                    .get()
+                    .is_some_and(|start| start.elapsed() >= max_time)
+            }) {
+                return Some(StopReason::TimeLimit);
             }
             None
         }
     }
 
+    fn below_min_tokens(&self) -> bool {
+        self.tokens.len().saturating_sub(self.prompt_len) < self.min_tokens
+    }
+
+    /// The EOS and stop tokens which must not be sampled next, as fewer than `min_tokens` tokens were generated.
+    pub fn min_tokens_suppressed(&self) -> &[u32] {
+        if self.below_min_tokens() {
+            &self.min_tokens_suppressed
+        } else {
+            &[]
+        }
+    }
+
+    /// The tokens which must not be sampled next, as they would repeat an n-gram of the generated tokens.
+    pub fn banned_ngram_tokens(&self) -> &[u32] {
+        match &self.ngram_ban {
+            Some(ngram_ban) => ngram_ban.banned(&self.tokens[self.prompt_len..]),
+            None => &[],
+        }
+    }
+
+    pub fn parses_tool_calls(&self) -> bool {
+        self.parse_tool_calls
+    }
+
     pub fn logprobs(&self) -> &[Logprobs] {
         &self.logprobs
     }
 
+    /// Logprobs of the tokens generated since the last streamed chunk. These are consumed by this call.
+    pub fn take_stream_logprobs(&mut self) -> Vec<Logprobs> {
+        let start = self.stream_logprobs_idx;
+        self.stream_logprobs_idx = self.logprobs.len();
+        self.logprobs[start..].to_vec()
+    }
+
     pub fn return_logprobs(&self) -> bool {
         self.return_logprobs
     }
 
+    /// Logprobs of the prompt tokens, starting at the second one. `None` if they were not requested.
+    pub fn prompt_logprobs(&self) -> Option<&[Logprobs]> {
+        self.prompt_logprobs.as_deref()
+    }
+
+    /// Whether the prompt logprobs were requested and not all prompt tokens have been scored yet. The prompt step
+    /// then computes the logits at every position instead of only the last.
+    pub fn prompt_logprobs_pending(&self) -> bool {
+        self.prompt_logprobs
+            .as_ref()
+            .is_some_and(|logprobs| logprobs.len() + 1 < self.prompt_len)
+    }
+
+    pub(crate) fn add_prompt_logprobs(&mut self, logprobs: Vec<Logprobs>) {
+        if let Some(prompt_logprobs) = &mut self.prompt_logprobs {
+            prompt_logprobs.extend(logprobs);
+        }
+    }
+
+    pub(crate) fn prompt_toks(&self) -> &[u32] {
+        &self.tokens[..self.prompt_len]
+    }
+
     pub fn prompt_tokens(&self) -> usize {
         self.prompt_len
     }
@@ -414,17 +881,40 @@ impl Sequence {
         &self.stop_strings
     }
 
-    /// Returns the delta between the last two decoded sequences
+    /// Returns the delta between the last two decoded sequences. An incomplete UTF-8 character at the end is held
+    /// back until the tokens completing it arrive, unless `flush` is set, as it is for the last delta. So is the start
+    /// of a stop string, which is not streamed if the stop string completes.
     pub fn get_delta(
         &mut self,
+        flush: bool,
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let _span = debug_span!(
+            "detokenize",
+            seq_id = self.id,
+            bytes = self.completion_bytes.len() - self.stream_idx
+        )
+        .entered();
         let is_first = self.stream_idx == 0;
-        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..]);
-        // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
-        if new_decoded.ends_with('�') {
+        let end = match self.last_is_done {
+            Some(StopReason::StopString {
+                completion_bytes_pos,
+                ..
+            }) => completion_bytes_pos.max(self.stream_idx),
+            _ if flush => self.completion_bytes.len(),
+            _ => {
+                self.completion_bytes.len()
+                    - stop_string_prefix_len(
+                        &self.completion_bytes[self.stream_idx..],
+                        &self.stop_strings,
+                    )
+            }
+        };
+        let (new_decoded, consumed) =
+            decode_complete_utf8(&self.completion_bytes[self.stream_idx..end], flush);
+        if consumed == 0 && !flush {
             return Ok(None);
         }
-        self.stream_idx = self.completion_bytes.len();
+        self.stream_idx += consumed;
 
         // The first token usually starts with a space. We don't want to add that to the delta.
         // Since we're using the completion_bytes, we need to take care of that ourselves.
@@ -450,8 +940,29 @@ impl Sequence {
             .as_millis();
 
         if let Some(ts) = self.prompt_timestamp {
-            get_mut_group!(self).total_completion_time += now - ts;
-            get_mut_group!(self).total_prompt_time += ts - self.timestamp;
+            let prefill_start = self.prefill_timestamp.unwrap_or(self.timestamp);
+            let time_to_first_token = ts - self.timestamp;
+            // The first token is sampled at the end of the prefill, the others while decoding
+            let decode_toks = self.tokens.len().saturating_sub(self.prompt_len + 1);
+            let mut group = get_mut_group!(self);
+            group.total_completion_time += now - ts;
+            group.total_prompt_time += ts - prefill_start;
+            group.total_decode_toks += decode_toks;
+            group.time_to_first_token = Some(
+                group
+                    .time_to_first_token
+                    .map_or(time_to_first_token, |ttft| ttft.min(time_to_first_token)),
+            );
+            #[allow(clippy::cast_precision_loss)]
+            let (prefill_tok_per_sec, decode_tok_per_sec) = (
+                self.prompt_len as f32 / (ts - prefill_start).max(1) as f32 * 1000.,
+                decode_toks as f32 / (now - ts).max(1) as f32 * 1000.,
+            );
+            info!(
+                "Sequence {} finished: time to first token {time_to_first_token}ms, waited {}ms, prefill {prefill_tok_per_sec:.2} tok/s, decode {decode_tok_per_sec:.2} tok/s.",
+                self.id,
+                prefill_start - self.timestamp,
+            );
         }
 
         get_mut_group!(self).total_time += now - self.timestamp;
@@ -461,7 +972,7 @@ impl Sequence {
     }
 
     pub fn add_choice_to_group(&self, choice: Choice) {
-        get_mut_group!(self).choices.push(choice);
+        get_mut_group!(self).choices.push((self.score(), choice));
         self.update_time_info();
     }
 
@@ -474,7 +985,7 @@ impl Sequence {
         );
         get_mut_group!(self)
             .completion_choices
-            .push((self.cumulative_logprob, choice));
+            .push((self.score(), choice));
         self.update_time_info();
     }
 
@@ -490,10 +1001,21 @@ impl Sequence {
         get_mut_group!(self).streaming_chunks.push(chunk);
     }
 
+    /// Count the usage of a finished streaming sequence, as for a choice of a non-streaming request. The usage of the
+    /// group is sent with its last chunk.
+    pub fn add_streaming_usage_to_group(&self) {
+        get_mut_group!(self).finished_streams += 1;
+        self.update_time_info();
+    }
+
     pub fn get_adapters(&self) -> Option<Vec<String>> {
         self.adapters.clone()
     }
 
+    pub fn lora_scale(&self) -> f32 {
+        self.lora_scale
+    }
+
     pub fn take_images(&mut self) -> Option<Vec<image::DynamicImage>> {
         self.input_images.take()
     }
@@ -503,23 +1025,50 @@ impl Sequence {
     }
 }
 
+/// The generated tokens and caches of a beam, which another beam takes over when it is forked from this one.
+#[derive(Clone)]
+pub(crate) struct BeamState {
+    tokens: Vec<u32>,
+    logprobs: Vec<Logprobs>,
+    cumulative_logprob: f32,
+    completion_bytes: Vec<u8>,
+    last_completion_bytes_len: usize,
+    last_logprob: f32,
+    last_is_done: Option<StopReason>,
+    cache: LayerCaches<KvBlock>,
+    xlora_cache: Option<LayerCaches<KvBlock>>,
+    scaling_cache: Option<Tensor>,
+}
+
 pub struct SequenceGroup {
     n_choices: usize, // The target number of choices to return. Can be decreased if an error is thrown.
-    best_of: usize,   // Top n seqs based on cumulative logprobs.
+    n_returned_choices: usize, // The number of choices returned, the best ones by score.
     pub total_prompt_toks: usize,
     pub total_toks: usize,
     pub total_prompt_time: u128,
     pub total_time: u128,
     pub total_completion_time: u128,
-    choices: Vec<Choice>,
+    /// Tokens generated after the first one, which is sampled by the prefill.
+    pub total_decode_toks: usize,
+    /// Time from the request to the first token of the earliest sequence, in ms.
+    pub time_to_first_token: Option<u128>,
+    choices: Vec<(f32, Choice)>,
     completion_choices: Vec<(f32, CompletionChoice)>,
     pub streaming_chunks: Vec<ChunkChoice>,
+    /// Streaming sequences which are done.
+    finished_streams: usize,
     pub is_streaming: bool,
     pub is_chat: bool,
+    is_beam_search: bool,
 }
 
 impl SequenceGroup {
-    pub fn new(n_choices: usize, is_streaming: bool, is_chat: bool, best_of: usize) -> Self {
+    pub fn new(
+        n_choices: usize,
+        is_streaming: bool,
+        is_chat: bool,
+        n_returned_choices: usize,
+    ) -> Self {
         Self {
             choices: Vec::new(),
             completion_choices: Vec::new(),
@@ -529,27 +1078,59 @@ impl SequenceGroup {
             total_prompt_time: 0,
             total_time: 0,
             total_completion_time: 0,
+            total_decode_toks: 0,
+            time_to_first_token: None,
             streaming_chunks: Vec::new(),
+            finished_streams: 0,
             is_streaming,
             is_chat,
-            best_of,
+            n_returned_choices,
+            is_beam_search: false,
         }
     }
 
-    /// This does not apply best_of.
-    pub fn get_choices(&self) -> &[Choice] {
-        &self.choices
+    /// Rank the choices of this group, which holds one sequence per beam, by beam score.
+    pub fn with_beam_search(mut self) -> Self {
+        self.is_beam_search = true;
+        self
     }
 
-    /// This applies the best_of.
+    /// This only ranks the choices for a beam search or when there are more candidates than returned choices. Then,
+    /// the best `n_returned_choices` are returned ranked by score.
+    pub fn get_choices(&self) -> Vec<Choice> {
+        if !self.is_beam_search && self.choices.len() <= self.n_returned_choices {
+            return self.choices.iter().map(|(_, x)| x.clone()).collect();
+        }
+        let mut choices = self.choices.clone();
+        // Sort by descending score
+        choices.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
+        choices
+            .into_iter()
+            .take(self.n_returned_choices)
+            .enumerate()
+            .map(|(index, (_, mut x))| {
+                x.index = index;
+                x
+            })
+            .collect()
+    }
+
+    /// This returns the best `n_returned_choices` choices.
     pub fn get_completion_choices(&self) -> Vec<CompletionChoice> {
         let mut choices = self.completion_choices.clone();
         // Sort by descending logprobs
         choices.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
         choices
             .into_iter()
-            .take(self.best_of)
-            .map(|(_, x)| x)
+            .take(self.n_returned_choices)
+            .enumerate()
+            .map(|(index, (_, mut x))| {
+                // The returned choices are indexed by rank when they are the best of more candidates
+                if self.is_beam_search || self.completion_choices.len() > self.n_returned_choices {
+                    x.index = index;
+                }
+                x
+            })
             .collect::<Vec<_>>()
     }
 
@@ -562,9 +1143,10 @@ impl SequenceGroup {
             avg_tok_per_sec: (self.total_toks as f32 / self.total_time as f32) * 1000.,
             avg_prompt_tok_per_sec: (self.total_prompt_toks as f32 / self.total_prompt_time as f32)
                 * 1000.,
-            avg_compl_tok_per_sec: ((self.total_toks - self.total_prompt_toks) as f32
+            avg_compl_tok_per_sec: (self.total_decode_toks as f32
                 / self.total_completion_time as f32)
                 * 1000.,
+            time_to_first_token_sec: self.time_to_first_token.unwrap_or(0) as f32 / 1000.,
             total_time_sec: self.total_time as f32 / 1000.,
             total_completion_time_sec: self.total_completion_time as f32 / 1000.,
             total_prompt_time_sec: self.total_prompt_time as f32 / 1000.,
@@ -588,7 +1170,8 @@ impl SequenceGroup {
         seq: &Sequence,
         model: String,
     ) -> Result<(), Box<SendError<Response>>> {
-        if self.streaming_chunks.len() == self.n_choices && self.is_streaming {
+        // The choices may finish at different steps, so each chunk is sent right away
+        if !self.streaming_chunks.is_empty() && self.is_streaming {
             let mut swap_streaming_chunks = vec![];
 
             std::mem::swap(&mut swap_streaming_chunks, &mut self.streaming_chunks);
@@ -601,6 +1184,7 @@ impl SequenceGroup {
                     model: model.clone(),
                     system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                     object: "chat.completion.chunk".to_string(),
+                    usage: (self.finished_streams == self.n_choices).then(|| self.get_usage()),
                 }))
                 .await?;
         }
@@ -618,3 +1202,179 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+/// Whether `tok` is one of the EOS tokens, which are those of the sequence if it has any, or else those of the model.
+/// There are none if EOS does not stop generation (`model_eos` is `None`).
+fn is_eos(tok: u32, model_eos: Option<&[u32]>, seq_eos: Option<&[u32]>) -> bool {
+    model_eos
+        .map(|model_eos| seq_eos.unwrap_or(model_eos))
+        .is_some_and(|eos| eos.contains(&tok))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn finish_reasons() {
+        use super::StopReason;
+
+        assert_eq!(StopReason::Eos.to_string(), "eos");
+        assert_eq!(StopReason::StopTok(2).to_string(), "stop");
+        assert_eq!(
+            StopReason::StopString {
+                stop_string_idx: 0,
+                completion_bytes_pos: 3,
+            }
+            .to_string(),
+            "stop"
+        );
+        assert_eq!(StopReason::Length(16).to_string(), "length");
+        assert_eq!(StopReason::ModelLength(4096).to_string(), "length");
+        assert_eq!(StopReason::Canceled.to_string(), "cancelled");
+    }
+
+    #[test]
+    fn any_eos_token_finishes() {
+        use super::is_eos;
+
+        // Like `<|im_end|>` and `</s>`
+        let model_eos = [32000, 2];
+        let generated = [415, 2936, 2, 28705];
+        let stop = generated
+            .iter()
+            .position(|tok| is_eos(*tok, Some(&model_eos), None));
+        assert_eq!(stop, Some(2));
+
+        // The EOS tokens of a request replace those of the model
+        assert!(!is_eos(2, Some(&model_eos), Some(&[28705])));
+        assert!(is_eos(28705, Some(&model_eos), Some(&[28705])));
+        // Unless EOS does not stop generation
+        assert!(!is_eos(28705, None, Some(&[28705])));
+    }
+
+    #[test]
+    fn decode_complete_utf8_buffers_split_characters() {
+        use super::decode_complete_utf8;
+
+        // Byte-level tokens routinely split CJK characters and emoji across tokens
+        let text = "你好, 世界! 👋🏽";
+        let bytes = text.as_bytes();
+        for chunk_size in 1..=5 {
+            let mut pending = Vec::new();
+            let mut streamed = String::new();
+            for chunk in bytes.chunks(chunk_size) {
+                pending.extend_from_slice(chunk);
+                let (delta, consumed) = decode_complete_utf8(&pending, false);
+                assert!(!delta.contains(char::REPLACEMENT_CHARACTER));
+                streamed.push_str(&delta);
+                pending.drain(..consumed);
+            }
+            assert!(pending.is_empty());
+            assert_eq!(streamed, text);
+        }
+    }
+
+    #[test]
+    fn decode_complete_utf8_flushes_and_replaces_invalid_bytes() {
+        use super::decode_complete_utf8;
+
+        let bytes = "a😀".as_bytes();
+        let truncated = &bytes[..bytes.len() - 1];
+        assert_eq!(decode_complete_utf8(truncated, false), ("a".to_string(), 1));
+        assert_eq!(
+            decode_complete_utf8(truncated, true),
+            ("a\u{FFFD}".to_string(), truncated.len())
+        );
+        assert_eq!(
+            decode_complete_utf8(b"a\xffb", false),
+            ("a\u{FFFD}b".to_string(), 3)
+        );
+    }
+
+    #[test]
+    fn length_limits_count_the_new_token() {
+        use super::{length_stop, StopReason};
+
+        // Generate like `Sequence::is_done`, before each token is added, until the sequence stops
+        let generate = |prompt_len: usize, max_len: Option<usize>, max_model_len: usize| {
+            let mut n_toks = prompt_len;
+            loop {
+                let reason = length_stop(n_toks + 1, prompt_len, max_len, max_model_len);
+                n_toks += 1;
+                if let Some(reason) = reason {
+                    return (n_toks - prompt_len, reason);
+                }
+            }
+        };
+        assert_eq!(generate(3, Some(1), 4096), (1, StopReason::Length(1)));
+        assert_eq!(generate(3, Some(5), 4096), (5, StopReason::Length(5)));
+        assert_eq!(generate(3, None, 8), (5, StopReason::ModelLength(8)));
+        assert_eq!(generate(3, Some(5), 6), (3, StopReason::ModelLength(6)));
+    }
+
+    #[test]
+    fn stop_string_straddling_tokens() {
+        use super::find_stop_string;
+
+        let stop_strings = vec!["\n\nUser:".to_string(), "STOP".to_string()];
+        let tokens = ["Sure", ".\n", "\nUs", "er:", " hi"];
+        let mut completion_bytes = Vec::new();
+        let mut found = None;
+        for (i, tok) in tokens.iter().enumerate() {
+            if let Some(stop) = find_stop_string(&completion_bytes, tok.as_bytes(), &stop_strings) {
+                found = Some((i, stop));
+                break;
+            }
+            completion_bytes.extend_from_slice(tok.as_bytes());
+        }
+        // Found with the token completing it, at its start in the earlier tokens
+        assert_eq!(found, Some((3, (0, 5))));
+
+        // A stop string spanning more than two tokens
+        let stop_strings = vec!["abcdef".to_string()];
+        assert_eq!(find_stop_string(b"xxab", b"c", &stop_strings), None);
+        assert_eq!(
+            find_stop_string(b"xxabcd", b"ef", &stop_strings),
+            Some((0, 2))
+        );
+
+        // The earliest stop string wins, whatever its index
+        let stop_strings = vec!["lo".to_string(), "el".to_string()];
+        assert_eq!(find_stop_string(b"h", b"ello", &stop_strings), Some((1, 1)));
+        assert_eq!(find_stop_string(b"hello", b"", &[]), None);
+    }
+
+    #[test]
+    fn stop_string_is_not_streamed() {
+        use super::{find_stop_string, stop_string_prefix_len};
+
+        let stop_strings = vec!["</answer>".to_string()];
+        assert_eq!(stop_string_prefix_len(b"42 </an", &stop_strings), 4);
+        assert_eq!(stop_string_prefix_len(b"42 <", &stop_strings), 1);
+        assert_eq!(stop_string_prefix_len(b"42", &stop_strings), 0);
+
+        // Stream the deltas like `Sequence::get_delta`, holding back the possible start of a stop string
+        let tokens = ["The", " answer", " is 4", "2 </", "ans", "wer>", " extra"];
+        let mut completion_bytes = Vec::new();
+        let mut stream_idx = 0;
+        let mut deltas = Vec::new();
+        for tok in tokens {
+            let stop = find_stop_string(&completion_bytes, tok.as_bytes(), &stop_strings);
+            completion_bytes.extend_from_slice(tok.as_bytes());
+            let end = match stop {
+                Some((_, pos)) => pos,
+                None => {
+                    completion_bytes.len()
+                        - stop_string_prefix_len(&completion_bytes[stream_idx..], &stop_strings)
+                }
+            };
+            deltas.push(String::from_utf8(completion_bytes[stream_idx..end].to_vec()).unwrap());
+            stream_idx = end;
+            if stop.is_some() {
+                break;
+            }
+        }
+        // The start of the stop string was held back, and is never streamed
+        assert_eq!(deltas, ["The", " answer", " is 4", "2 ", "", ""]);
+        assert_eq!(deltas.concat(), "The answer is 42 ");
+    }
+}
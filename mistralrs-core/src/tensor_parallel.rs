@@ -0,0 +1,325 @@
+//! Tensor parallelism: the weights of the attention and MLP projections are sharded across several devices, so that a
+//! model which does not fit on one device can be run. The rest of the model, including the KV cache, is on the first
+//! (primary) device.
+//!
+//! The projections follow Megatron-LM: a column-parallel linear layer splits the output features across the devices,
+//! and a row-parallel linear layer splits the input features, so each device computes a partial result and the
+//! partial results are summed with an all-reduce. The MLP is a column-parallel gate and up projection followed by a
+//! row-parallel down projection, so the intermediate activations stay on their devices and a single all-reduce is
+//! needed. The attention gathers the query, key and value shards on the primary device, which computes the attention,
+//! and its output projection is row-parallel.
+//!
+//! The collectives go through the primary device: the shards of the input are copied to their devices, and the
+//! partial results are copied back to the primary device, where they are summed (all-reduce) or concatenated
+//! (all-gather). The copies use [`Tensor::to_device`], so there is no dependency on NCCL. The kernels of the devices
+//! are launched one after another, and run concurrently on CUDA as they are asynchronous.
+
+use candle_core::{bail, quantized::QMatMul, Device, Module, Result, Tensor, D};
+use candle_nn::{linear_no_bias, Linear, VarBuilder};
+
+use crate::{layers::MatMul, utils::debug::DeviceRepr};
+
+#[derive(Debug, Clone)]
+/// The devices of a model with tensor parallelism, the first of which is the primary device.
+pub struct TensorParallel {
+    devices: Vec<Device>,
+}
+
+impl TensorParallel {
+    pub fn new(devices: Vec<Device>) -> Result<Self> {
+        if devices.is_empty() {
+            bail!("Tensor parallelism needs at least one device.");
+        }
+        Ok(Self { devices })
+    }
+
+    pub fn size(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn primary(&self) -> &Device {
+        &self.devices[0]
+    }
+
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// Copy `xs` to every device.
+    fn broadcast(&self, xs: &Tensor) -> Result<Vec<Tensor>> {
+        self.devices.iter().map(|dev| xs.to_device(dev)).collect()
+    }
+
+    /// Split the last dimension of `xs` into one shard per device.
+    fn scatter(&self, xs: &Tensor) -> Result<Vec<Tensor>> {
+        let size = xs.dim(D::Minus1)? / self.size();
+        self.devices
+            .iter()
+            .enumerate()
+            .map(|(i, dev)| xs.narrow(D::Minus1, i * size, size)?.to_device(dev))
+            .collect()
+    }
+
+    /// Sum the partial results of the devices on the primary device.
+    fn all_reduce(&self, partials: Vec<Tensor>) -> Result<Tensor> {
+        let mut partials = partials.into_iter();
+        let Some(first) = partials.next() else {
+            bail!("All-reduce of no tensors.");
+        };
+        let mut sum = first.to_device(self.primary())?;
+        for partial in partials {
+            sum = (sum + partial.to_device(self.primary())?)?;
+        }
+        Ok(sum)
+    }
+
+    /// Concatenate the shards of the devices along the last dimension on the primary device.
+    fn all_gather(&self, shards: Vec<Tensor>) -> Result<Tensor> {
+        let shards = shards
+            .into_iter()
+            .map(|shard| shard.to_device(self.primary()))
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&shards, D::Minus1)
+    }
+
+    /// Split `weight` along `dim` into one shard per device, and move each shard to its device.
+    fn shard(&self, weight: &Tensor, dim: usize) -> Result<Vec<Tensor>> {
+        let total = weight.dim(dim)?;
+        if total % self.size() != 0 {
+            bail!(
+                "Cannot shard a weight of shape {:?} along dimension {dim} across {} devices.",
+                weight.dims(),
+                self.size()
+            );
+        }
+        let size = total / self.size();
+        self.devices
+            .iter()
+            .enumerate()
+            .map(|(i, dev)| {
+                weight
+                    .narrow(dim, i * size, size)?
+                    .contiguous()?
+                    .to_device(dev)
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for TensorParallel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let devices = self
+            .devices
+            .iter()
+            .map(|dev| dev.device_pretty_repr())
+            .collect::<Vec<_>>();
+        write!(f, "{}", devices.join(", "))
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A linear layer without bias whose output features are split across the devices.
+pub struct ColumnParallelLinear {
+    shards: Vec<Linear>,
+    tp: TensorParallel,
+}
+
+impl ColumnParallelLinear {
+    /// Shard a `(out_features, in_features)` weight.
+    pub fn new(weight: &Tensor, tp: &TensorParallel) -> Result<Self> {
+        Ok(Self {
+            shards: tp
+                .shard(weight, 0)?
+                .into_iter()
+                .map(|w| Linear::new(w, None))
+                .collect(),
+            tp: tp.clone(),
+        })
+    }
+
+    /// The output shard of each device, on that device.
+    pub fn forward_sharded(&self, xs: &Tensor) -> Result<Vec<Tensor>> {
+        std::iter::zip(&self.shards, self.tp.broadcast(xs)?)
+            .map(|(shard, xs)| shard.forward(&xs))
+            .collect()
+    }
+}
+
+impl Module for ColumnParallelLinear {
+    /// The whole output, on the primary device.
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.tp.all_gather(self.forward_sharded(xs)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A linear layer without bias whose input features are split across the devices. The partial results are summed
+/// with an all-reduce.
+pub struct RowParallelLinear {
+    shards: Vec<Linear>,
+    tp: TensorParallel,
+}
+
+impl RowParallelLinear {
+    /// Shard a `(out_features, in_features)` weight.
+    pub fn new(weight: &Tensor, tp: &TensorParallel) -> Result<Self> {
+        Ok(Self {
+            shards: tp
+                .shard(weight, 1)?
+                .into_iter()
+                .map(|w| Linear::new(w, None))
+                .collect(),
+            tp: tp.clone(),
+        })
+    }
+
+    /// Apply the layer to input shards which are already on their devices, such as the output of a
+    /// [`ColumnParallelLinear`]. The output is on the primary device.
+    pub fn forward_sharded(&self, xs: Vec<Tensor>) -> Result<Tensor> {
+        let partials = std::iter::zip(&self.shards, xs)
+            .map(|(shard, xs)| shard.forward(&xs))
+            .collect::<Result<Vec<_>>>()?;
+        self.tp.all_reduce(partials)
+    }
+}
+
+impl Module for RowParallelLinear {
+    /// Apply the layer to a whole input, which is split across the devices.
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_sharded(self.tp.scatter(xs)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A projection without bias, which is sharded with tensor parallelism or else held whole by a `QMatMul`, so that it
+/// may be quantized with ISQ.
+pub enum Projection {
+    Replicated(QMatMul),
+    Column(ColumnParallelLinear),
+    Row(RowParallelLinear),
+}
+
+impl Projection {
+    /// Load a projection which is column-parallel with tensor parallelism.
+    pub fn column(
+        in_dim: usize,
+        out_dim: usize,
+        vb: VarBuilder,
+        tp: Option<&TensorParallel>,
+    ) -> Result<Self> {
+        match tp {
+            Some(tp) => {
+                // The weight is sharded from the CPU, so it is never whole on a device
+                let linear = linear_no_bias(in_dim, out_dim, vb.set_device(Device::Cpu))?;
+                Ok(Self::Column(ColumnParallelLinear::new(
+                    linear.weight(),
+                    tp,
+                )?))
+            }
+            None => Self::replicated(in_dim, out_dim, vb),
+        }
+    }
+
+    /// Load a projection which is row-parallel with tensor parallelism.
+    pub fn row(
+        in_dim: usize,
+        out_dim: usize,
+        vb: VarBuilder,
+        tp: Option<&TensorParallel>,
+    ) -> Result<Self> {
+        match tp {
+            Some(tp) => {
+                let linear = linear_no_bias(in_dim, out_dim, vb.set_device(Device::Cpu))?;
+                Ok(Self::Row(RowParallelLinear::new(linear.weight(), tp)?))
+            }
+            None => Self::replicated(in_dim, out_dim, vb),
+        }
+    }
+
+    fn replicated(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<Self> {
+        let linear = linear_no_bias(in_dim, out_dim, vb)?;
+        Ok(Self::Replicated(QMatMul::Tensor(linear.weight().clone())))
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        matches!(self, Self::Replicated(QMatMul::QTensor(_)))
+    }
+
+    /// The weight to quantize with ISQ, unless the projection is sharded.
+    pub fn isq_weight(&mut self) -> Option<&mut QMatMul> {
+        match self {
+            Self::Replicated(matmul) => Some(matmul),
+            Self::Column(_) | Self::Row(_) => None,
+        }
+    }
+}
+
+impl Module for Projection {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Replicated(matmul) => MatMul.qmatmul(xs, matmul),
+            Self::Column(linear) => linear.forward(xs),
+            Self::Row(linear) => linear.forward(xs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Module, Tensor};
+    use candle_nn::Linear;
+
+    use super::{ColumnParallelLinear, RowParallelLinear, TensorParallel};
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn sharded_layers_match_unsharded() {
+        let dev = Device::Cpu;
+        let tp = TensorParallel::new(vec![Device::Cpu, Device::Cpu]).unwrap();
+        let xs = Tensor::arange(0f32, 24., &dev)
+            .unwrap()
+            .reshape((2, 3, 4))
+            .unwrap()
+            .sin()
+            .unwrap();
+        let w = Tensor::arange(0f32, 24., &dev)
+            .unwrap()
+            .reshape((6, 4))
+            .unwrap()
+            .cos()
+            .unwrap();
+
+        let expected = Linear::new(w.clone(), None).forward(&xs).unwrap();
+        let column = ColumnParallelLinear::new(&w, &tp).unwrap();
+        assert_eq!(column.forward_sharded(&xs).unwrap()[1].dims(), &[2, 3, 3]);
+        assert!(max_diff(&column.forward(&xs).unwrap(), &expected) < 1e-5);
+
+        let w = w.t().unwrap().contiguous().unwrap();
+        let hidden = expected;
+        let expected = Linear::new(w.clone(), None).forward(&hidden).unwrap();
+        let row = RowParallelLinear::new(&w, &tp).unwrap();
+        assert!(max_diff(&row.forward(&hidden).unwrap(), &expected) < 1e-5);
+        // The output shards of a column-parallel layer are the input shards of a row-parallel layer
+        let sharded = row
+            .forward_sharded(column.forward_sharded(&xs).unwrap())
+            .unwrap();
+        assert!(max_diff(&sharded, &expected) < 1e-5);
+
+        // The features must split evenly
+        let tp = TensorParallel::new(vec![Device::Cpu; 4]).unwrap();
+        assert!(ColumnParallelLinear::new(&w, &tp).is_ok());
+        assert!(RowParallelLinear::new(&w, &tp).is_err());
+    }
+}
@@ -3,9 +3,10 @@ use std::fs::File;
 use serde::Deserialize;
 
 use crate::{
-    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader,
-    ModelDType, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
-    SpeculativeLoader, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
+    AdaptiveGamma, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig,
+    Loader, MedusaLoader, ModelDType, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig,
+    PromptLookupConfig, PromptLookupLoader, SpeculativeConfig, SpeculativeLoader,
+    VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
 };
 
 fn default_repeat_last_n() -> usize {
@@ -219,8 +220,28 @@ pub struct SpeculativeTomlModelSelected {
     /// Gamma value for the model
     gamma: usize,
 
-    /// Base model
-    draft_model: TomlModelSelected,
+    /// If set with `max_gamma`, gamma is adjusted between the bounds based on the acceptance rate.
+    min_gamma: Option<usize>,
+
+    /// If set with `min_gamma`, gamma is adjusted between the bounds based on the acceptance rate.
+    max_gamma: Option<usize>,
+
+    /// Base model. Without one or `medusa_model_id`, the draft tokens are found by prompt lookup.
+    draft_model: Option<TomlModelSelected>,
+
+    /// Ordinal of the device to load the draft model on, instead of the device of the model.
+    draft_device_ordinal: Option<usize>,
+
+    /// Hugging Face repository or local directory of Medusa heads trained for the model, which propose the draft
+    /// tokens instead of a draft model.
+    medusa_model_id: Option<String>,
+
+    /// Longest n-gram matched by prompt lookup. Defaults to 3.
+    max_ngram_size: Option<usize>,
+
+    /// With prompt lookup or Medusa heads, the number of candidates proposed at each draft position, verified as a
+    /// tree of draft tokens with tree attention.
+    tree_width: Option<usize>,
 }
 
 #[derive(Deserialize)]
@@ -503,15 +524,47 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
             repeat_last_n: selector.repeat_last_n,
         };
         let loader = loader_from_selected(args.clone(), selector.model)?;
-        let loader = if let Some(speculative) = selector.speculative {
-            let draft_loader = loader_from_selected(args, speculative.draft_model)?;
-            Box::new(SpeculativeLoader {
-                target: loader,
-                draft: draft_loader,
-                config: SpeculativeConfig {
-                    gamma: speculative.gamma,
+        let loader: Box<dyn Loader> = if let Some(speculative) = selector.speculative {
+            let config = SpeculativeConfig {
+                gamma: speculative.gamma,
+                adaptive_gamma: match (speculative.min_gamma, speculative.max_gamma) {
+                    (Some(min_gamma), Some(max_gamma)) => Some(AdaptiveGamma {
+                        min_gamma,
+                        max_gamma,
+                    }),
+                    (None, None) => None,
+                    _ => {
+                        anyhow::bail!("Both or neither of `min_gamma` and `max_gamma` must be set.")
+                    }
                 },
-            })
+                tree_width: speculative.tree_width,
+            };
+            if speculative.draft_device_ordinal.is_some() && speculative.draft_model.is_none() {
+                anyhow::bail!("`draft_device_ordinal` may only be set with a `draft_model`.");
+            }
+            match (speculative.draft_model, speculative.medusa_model_id) {
+                (Some(_), Some(_)) => {
+                    anyhow::bail!("Only one of `draft_model` and `medusa_model_id` may be set.")
+                }
+                (Some(draft_model), None) => Box::new(SpeculativeLoader {
+                    target: loader,
+                    draft: loader_from_selected(args, draft_model)?,
+                    config,
+                    draft_device_ordinal: speculative.draft_device_ordinal,
+                }),
+                (None, Some(medusa_model_id)) => Box::new(MedusaLoader {
+                    target: loader,
+                    config,
+                    medusa_model_id,
+                }),
+                (None, None) => Box::new(PromptLookupLoader {
+                    target: loader,
+                    config,
+                    prompt_lookup: PromptLookupConfig {
+                        max_ngram_size: speculative.max_ngram_size.unwrap_or(3),
+                    },
+                }),
+            }
         } else {
             loader
         };
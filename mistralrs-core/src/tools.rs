@@ -0,0 +1,193 @@
+//! Tool (function) calling: the tools offered to the model by a request, and parsing the calls the
+//! model makes out of its generated text.
+//!
+//! The supported output formats are:
+//! - `[TOOL_CALLS]` followed by a JSON list (or object) of `{"name": ..., "arguments": {...}}`, as generated by Mistral
+//!   models.
+//! - `<function=name>{...}</function>`, where the tag holds the JSON arguments.
+
+use serde::{Deserialize, Serialize};
+
+use crate::response::{CalledFunction, ToolCall};
+
+const MISTRAL_TOOL_CALLS: &str = "[TOOL_CALLS]";
+const FUNCTION_START: &str = "<function=";
+const FUNCTION_END: &str = "</function>";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ToolType {
+    #[serde(rename = "function")]
+    Function,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// A function the model may call.
+pub struct Function {
+    pub name: String,
+    pub description: Option<String>,
+    /// JSON schema of the arguments.
+    pub parameters: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// A tool offered to the model. It is passed to the chat template as `tools`.
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: Function,
+}
+
+#[derive(Deserialize)]
+struct RawCall {
+    name: String,
+    #[serde(default, alias = "parameters")]
+    arguments: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawCalls {
+    Many(Vec<RawCall>),
+    One(RawCall),
+}
+
+impl RawCall {
+    fn into_function(self) -> CalledFunction {
+        let arguments = match self.arguments {
+            serde_json::Value::Null => "{}".to_string(),
+            serde_json::Value::String(arguments) => arguments,
+            arguments => arguments.to_string(),
+        };
+        CalledFunction {
+            name: self.name,
+            arguments,
+        }
+    }
+}
+
+/// Parse the tool calls out of generated text. Returns the remaining content and the calls, or `None` if there
+/// are no tool calls or one of them is malformed, in which case the text should be returned as content. The calls
+/// are given the ids `{id_prefix}-{index}`.
+pub(crate) fn parse_tool_calls(text: &str, id_prefix: &str) -> Option<(String, Vec<ToolCall>)> {
+    let mut content = String::new();
+    let mut functions = Vec::new();
+    let mut rest = text;
+    loop {
+        let mistral = rest.find(MISTRAL_TOOL_CALLS);
+        let tagged = rest.find(FUNCTION_START);
+        match (mistral, tagged) {
+            (Some(start), tagged) if !tagged.is_some_and(|tagged| tagged < start) => {
+                content.push_str(&rest[..start]);
+                let calls = rest[start + MISTRAL_TOOL_CALLS.len()..].trim_start();
+                let mut stream = serde_json::Deserializer::from_str(calls).into_iter::<RawCalls>();
+                match stream.next()?.ok()? {
+                    RawCalls::Many(calls) => {
+                        functions.extend(calls.into_iter().map(RawCall::into_function))
+                    }
+                    RawCalls::One(call) => functions.push(call.into_function()),
+                }
+                rest = &calls[stream.byte_offset()..];
+            }
+            (_, Some(start)) => {
+                content.push_str(&rest[..start]);
+                let call = &rest[start + FUNCTION_START.len()..];
+                let name_end = call.find('>')?;
+                let body = &call[name_end + 1..];
+                let body_end = body.find(FUNCTION_END)?;
+                let arguments: serde_json::Value =
+                    serde_json::from_str(body[..body_end].trim()).ok()?;
+                functions.push(
+                    RawCall {
+                        name: call[..name_end].trim().to_string(),
+                        arguments,
+                    }
+                    .into_function(),
+                );
+                rest = &body[body_end + FUNCTION_END.len()..];
+            }
+            (None, None) => {
+                content.push_str(rest);
+                break;
+            }
+        }
+    }
+    if functions.is_empty() {
+        return None;
+    }
+    let calls = functions
+        .into_iter()
+        .enumerate()
+        .map(|(i, function)| ToolCall {
+            id: format!("{id_prefix}-{i}"),
+            tp: "function".to_string(),
+            function,
+        })
+        .collect();
+    Some((content.trim().to_string(), calls))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_tool_calls;
+
+    fn names_and_arguments(text: &str) -> Option<(String, Vec<(String, String)>)> {
+        parse_tool_calls(text, "call").map(|(content, calls)| {
+            (
+                content,
+                calls
+                    .into_iter()
+                    .map(|call| (call.function.name, call.function.arguments))
+                    .collect(),
+            )
+        })
+    }
+
+    #[test]
+    fn parses_mistral_tool_calls() {
+        let (content, calls) = names_and_arguments(
+            r#"[TOOL_CALLS] [{"name": "get_weather", "arguments": {"city": "Paris"}}, {"name": "get_time", "arguments": {}}]"#,
+        )
+        .unwrap();
+        assert_eq!(content, "");
+        assert_eq!(
+            calls,
+            vec![
+                ("get_weather".to_string(), r#"{"city":"Paris"}"#.to_string()),
+                ("get_time".to_string(), "{}".to_string()),
+            ]
+        );
+
+        let (_, calls) = parse_tool_calls(
+            r#"[TOOL_CALLS]{"name": "get_time", "arguments": "{\"zone\": \"UTC\"}"}"#,
+            "call-3",
+        )
+        .unwrap();
+        assert_eq!(calls[0].id, "call-3-0");
+        assert_eq!(calls[0].tp, "function");
+        assert_eq!(calls[0].function.arguments, r#"{"zone": "UTC"}"#);
+    }
+
+    #[test]
+    fn parses_function_tags() {
+        let (content, calls) = names_and_arguments(
+            r#"Let me check. <function=get_weather>{"city": "Paris"}</function><function=get_weather>{"city": "Rome"}</function>"#,
+        )
+        .unwrap();
+        assert_eq!(content, "Let me check.");
+        assert_eq!(
+            calls,
+            vec![
+                ("get_weather".to_string(), r#"{"city":"Paris"}"#.to_string()),
+                ("get_weather".to_string(), r#"{"city":"Rome"}"#.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_content() {
+        assert!(parse_tool_calls("The weather in Paris is sunny.", "call").is_none());
+        // Malformed calls are returned as content
+        assert!(parse_tool_calls(r#"[TOOL_CALLS] [{"name": "get_weather""#, "call").is_none());
+        assert!(parse_tool_calls(r#"<function=get_weather>{"city": "Paris"}"#, "call").is_none());
+    }
+}
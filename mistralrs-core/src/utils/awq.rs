@@ -0,0 +1,147 @@
+//! Dequantization of AWQ (activation-aware weight quantization) checkpoints.
+//!
+//! An AWQ linear is stored as packed 4-bit `qweight` and `qzeros` tensors plus per-group
+//! `scales`. These are unpacked into a dense `weight` at load time so the existing linear
+//! layers (and ISQ) consume AWQ checkpoints without any model-specific changes.
+
+use candle_core::{DType, Device, Result, Tensor};
+
+/// Number of 4-bit values packed into each 32-bit integer.
+const PACK_FACTOR: usize = 8;
+/// AWQ interleaves the packed values: column `j` of a pack lives in nibble `AWQ_REVERSE_ORDER[j]`.
+const AWQ_REVERSE_ORDER: [usize; PACK_FACTOR] = [0, 4, 1, 5, 2, 6, 3, 7];
+
+pub(crate) const QWEIGHT_SUFFIX: &str = ".qweight";
+pub(crate) const QZEROS_SUFFIX: &str = ".qzeros";
+pub(crate) const SCALES_SUFFIX: &str = ".scales";
+
+/// Unpack a 2D tensor of packed 4-bit values into a row-major buffer with 8x as many columns.
+fn unpack(packed: &Tensor) -> Result<Vec<u8>> {
+    // Safetensors `I32` tensors are widened to `I64` by candle; the low 32 bits hold the pack.
+    let packed = packed
+        .to_device(&Device::Cpu)?
+        .to_dtype(DType::I64)?
+        .flatten_all()?
+        .to_vec1::<i64>()?;
+    Ok(packed
+        .into_iter()
+        .flat_map(|v| AWQ_REVERSE_ORDER.map(|nibble| ((v >> (4 * nibble)) & 0xF) as u8))
+        .collect())
+}
+
+/// Dequantize an AWQ linear into a dense `(out_features, in_features)` weight.
+///
+/// The group size is derived from the shapes: `qweight` is `(in, out / 8)`, `qzeros` is
+/// `(in / group_size, out / 8)` and `scales` is `(in / group_size, out)`.
+pub(crate) fn dequantize(
+    qweight: &Tensor,
+    qzeros: &Tensor,
+    scales: &Tensor,
+    dtype: DType,
+    device: &Device,
+) -> Result<Tensor> {
+    let (in_features, packed_out) = qweight.dims2()?;
+    let out_features = packed_out * PACK_FACTOR;
+    let (n_groups, scales_out) = scales.dims2()?;
+    if n_groups == 0
+        || in_features % n_groups != 0
+        || scales_out != out_features
+        || qzeros.dims2()? != (n_groups, packed_out)
+    {
+        candle_core::bail!(
+            "Inconsistent AWQ tensor shapes: qweight {:?}, qzeros {:?}, scales {:?}",
+            qweight.shape(),
+            qzeros.shape(),
+            scales.shape()
+        );
+    }
+    let group_size = in_features / n_groups;
+
+    let qweight = unpack(qweight)?;
+    let qzeros = unpack(qzeros)?;
+    let scales = scales
+        .to_device(&Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+
+    let mut weight = Vec::with_capacity(in_features * out_features);
+    for (row, qrow) in qweight.chunks_exact(out_features).enumerate() {
+        let group = (row / group_size) * out_features;
+        for (col, q) in qrow.iter().enumerate() {
+            let zero = qzeros[group + col];
+            weight.push((*q as f32 - zero as f32) * scales[group + col]);
+        }
+    }
+    Tensor::from_vec(weight, (in_features, out_features), &Device::Cpu)?
+        .t()?
+        .contiguous()?
+        .to_dtype(dtype)?
+        .to_device(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    /// Pack 8 values in the order AWQ writes them.
+    fn pack(values: [i64; 8]) -> i64 {
+        const AWQ_ORDER: [usize; 8] = [0, 2, 4, 6, 1, 3, 5, 7];
+        AWQ_ORDER
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (nibble, col)| acc | (values[*col] << (4 * nibble)))
+    }
+
+    #[test]
+    fn dequantize_unpacks_interleaved_columns() {
+        use super::dequantize;
+
+        let dev = Device::Cpu;
+        let qweight = Tensor::new(
+            &[
+                [pack([0, 1, 2, 3, 4, 5, 6, 7])],
+                [pack([15, 14, 13, 12, 11, 10, 9, 8])],
+            ],
+            &dev,
+        )
+        .unwrap();
+        // One group spanning both input rows
+        let qzeros = Tensor::new(&[[pack([8; 8])]], &dev).unwrap();
+        let scales = Tensor::new(&[[0.5f32, 1., 1., 1., 1., 1., 1., 2.]], &dev).unwrap();
+
+        let w = dequantize(&qweight, &qzeros, &scales, DType::F32, &dev).unwrap();
+        assert_eq!(w.dims(), &[8, 2]);
+        let w = w.to_vec2::<f32>().unwrap();
+        assert_eq!(w[0], vec![-4., 3.5]);
+        assert_eq!(w[3], vec![-5., 4.]);
+        assert_eq!(w[7], vec![-2., 0.]);
+    }
+
+    #[test]
+    fn dequantize_applies_zeros_and_scales_per_group() {
+        use super::dequantize;
+
+        let dev = Device::Cpu;
+        let qweight = Tensor::new(&[[pack([3; 8])], [pack([3; 8])]], &dev).unwrap();
+        // Group size 1: each input row has its own zero point and scale
+        let qzeros = Tensor::new(&[[pack([1; 8])], [pack([2; 8])]], &dev).unwrap();
+        let scales = Tensor::new(&[[2f32; 8], [3f32; 8]], &dev).unwrap();
+
+        let w = dequantize(&qweight, &qzeros, &scales, DType::F32, &dev).unwrap();
+        for row in w.to_vec2::<f32>().unwrap() {
+            assert_eq!(row, vec![4., 3.]);
+        }
+    }
+
+    #[test]
+    fn dequantize_rejects_mismatched_shapes() {
+        use super::dequantize;
+
+        let dev = Device::Cpu;
+        let qweight = Tensor::zeros((4, 1), DType::I64, &dev).unwrap();
+        let qzeros = Tensor::zeros((3, 1), DType::I64, &dev).unwrap();
+        let scales = Tensor::zeros((3, 8), DType::F32, &dev).unwrap();
+        assert!(dequantize(&qweight, &qzeros, &scales, DType::F32, &dev).is_err());
+    }
+}
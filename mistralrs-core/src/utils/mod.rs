@@ -1,3 +1,4 @@
+pub(crate) mod awq;
 pub(crate) mod debug;
 pub(crate) mod gguf_metadata;
 pub(crate) mod model_config;
@@ -109,6 +110,7 @@ macro_rules! handle_pipeline_forward_error {
                             message: ResponseMessage {
                                 content: res,
                                 role: "assistant".to_string(),
+                                tool_calls: Vec::new(),
                             },
                             logprobs: None,
                         };
@@ -130,7 +132,7 @@ macro_rules! handle_pipeline_forward_error {
                     if group.is_chat {
                         let partial_completion_response = ChatCompletionResponse {
                             id: seq.id().to_string(),
-                            choices: group.get_choices().to_vec(),
+                            choices: group.get_choices(),
                             created: seq.creation_time(),
                             model: pipeline_name.clone(),
                             system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
@@ -219,7 +221,8 @@ macro_rules! sample_async {
         $ctx: expr,
         $return_logprobs: expr,
         $rng: expr,
-        $sample_speculative: expr
+        $sample_speculative: expr,
+        $mirostat_mu: expr
      ) => {
         if $use_async_pool {
             tokio_rayon::spawn(move || {
@@ -229,6 +232,7 @@ macro_rules! sample_async {
                     $return_logprobs,
                     $rng,
                     $sample_speculative,
+                    $mirostat_mu,
                 )
             })
             .await?
@@ -239,6 +243,7 @@ macro_rules! sample_async {
                 $return_logprobs,
                 $rng,
                 $sample_speculative,
+                $mirostat_mu,
             )?
         }
     };
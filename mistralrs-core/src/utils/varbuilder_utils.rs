@@ -1,6 +1,10 @@
 //! Utilities for creating a VarBuilder from a VarMap loaded from tensor storage formats.
 
-use std::{collections::HashMap, path::PathBuf, thread::JoinHandle};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+};
 
 use candle_core::{DType, Device, Result, Tensor};
 use candle_nn::{
@@ -13,6 +17,7 @@ use crate::lora::LoraConfig;
 use crate::utils::progress::IterWithProgress;
 use derive_new::new;
 
+use super::awq;
 use super::progress::{Joinable, NonThreadingHandle, Parellelize};
 
 /// Load tensors into a VarBuilder backed by a VarMap using MmapedSafetensors.
@@ -86,6 +91,25 @@ pub(crate) fn load_preload_adapters<'a>(
     }
 }
 
+/// Load a LoRA adapter added at runtime, from a directory holding its `adapter_config.json` and
+/// `adapter_model.safetensors`. The weights are loaded as F32 and moved to the dtype of the adapters of each layer.
+pub(crate) fn load_adapter<'a>(
+    path: &Path,
+    device: &Device,
+) -> Result<(VarBuilder<'a>, LoraConfig)> {
+    let config = std::fs::read_to_string(path.join("adapter_config.json"))
+        .map_err(candle_core::Error::wrap)?;
+    let config: LoraConfig = serde_json::from_str(&config).map_err(candle_core::Error::wrap)?;
+    let vb = from_mmaped_safetensors(
+        vec![path.join("adapter_model.safetensors")],
+        vec![],
+        DType::F32,
+        device,
+        true,
+    )?;
+    Ok((vb, config))
+}
+
 // Presently this logic only needs to diverge for X-LoRA support via `get_name_key_pairs()`
 trait LoadTensors {
     fn load_tensors_from_path(
@@ -101,9 +125,39 @@ trait LoadTensors {
         let names_only = tensors.tensors().into_iter().map(|(name, _)| name);
         let iter = self.get_name_key_pairs(names_only);
 
+        // AWQ linears are identified by their packed weight and dequantized into a dense weight:
+        let awq_prefixes = tensors
+            .tensors()
+            .into_iter()
+            .filter_map(|(name, _)| name.strip_suffix(awq::QWEIGHT_SUFFIX).map(String::from))
+            .collect::<HashSet<_>>();
+        let awq_prefix = |name: &str| {
+            [awq::QWEIGHT_SUFFIX, awq::QZEROS_SUFFIX, awq::SCALES_SUFFIX]
+                .iter()
+                .find_map(|suffix| name.strip_suffix(suffix))
+                .filter(|prefix| awq_prefixes.contains(*prefix))
+                .map(String::from)
+        };
+
         // Take the filtered list of tensors to load, store with derived lookup key:
         let mut loaded_tensors = HashMap::new();
         for (load_name, key_name) in iter.with_progress(is_silent) {
+            if let Some(prefix) = awq_prefix(&load_name) {
+                // Only the `qweight` produces a tensor, the `qzeros` and `scales` are consumed with it.
+                let Some(key_prefix) = key_name.strip_suffix(awq::QWEIGHT_SUFFIX) else {
+                    continue;
+                };
+                let load = |suffix: &str| tensors.load(&format!("{prefix}{suffix}"), &Device::Cpu);
+                let tensor = awq::dequantize(
+                    &load(awq::QWEIGHT_SUFFIX)?,
+                    &load(awq::QZEROS_SUFFIX)?,
+                    &load(awq::SCALES_SUFFIX)?,
+                    dtype,
+                    device,
+                )?;
+                loaded_tensors.insert(format!("{key_prefix}.weight"), tensor);
+                continue;
+            }
             let tensor = tensors.load(&load_name, device)?.to_dtype(dtype)?;
 
             loaded_tensors.insert(key_name, tensor);
@@ -1,6 +1,6 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use candle_core::{quantized::QMatMul, DType, Device, IndexOp, Result, Tensor, D};
+use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
 use candle_nn::{
     conv2d, embedding, layer_norm, linear, linear_no_bias, Activation, Conv2d, Conv2dConfig,
     Embedding, LayerNorm, Module, VarBuilder,
@@ -12,7 +12,7 @@ use crate::{
     device_map::DeviceMapper,
     layers::{repeat_kv, CausalMasker, QLinear, RmsNorm},
     models::mistral::Model as Mistral,
-    pipeline::{Cache, IsqModel, NormalLoadingMetadata, NormalModel, VisionModel},
+    pipeline::{Cache, IsqModel, IsqTensor, NormalLoadingMetadata, NormalModel, VisionModel},
 };
 
 use crate::models::mistral;
@@ -177,6 +177,7 @@ impl From<TextConfig> for mistral::Config {
             num_attention_heads: val.num_attention_heads,
             num_key_value_heads: val.num_key_value_heads,
             max_position_embeddings: val.max_position_embeddings,
+            rope_scaling: None,
             rms_norm_eps: val.rms_norm_eps,
             rope_theta: val.rope_theta,
             sliding_window: val.sliding_window,
@@ -1051,8 +1052,14 @@ impl Idefics2 {
 }
 
 impl IsqModel for Idefics2 {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
-        self.text_model.get_tensors()
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
+        let (tensors, mapper) = self.text_model.get_tensors();
+        // The text model weights are nested under `model.text_model`
+        let tensors = tensors
+            .into_iter()
+            .map(|(t, layer, name)| (t, layer, name.replacen("model.", "model.text_model.", 1)))
+            .collect();
+        (tensors, mapper)
     }
 }
 
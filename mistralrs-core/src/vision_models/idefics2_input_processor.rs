@@ -1,6 +1,6 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use std::{any::Any, sync::Arc};
+use std::{any::Any, collections::HashMap, sync::Arc};
 
 use candle_core::{Device, Result, Tensor};
 use image::{DynamicImage, GenericImageView};
@@ -16,12 +16,12 @@ use crate::{
     },
     sequence::Sequence,
     vision_models::ModelInputs,
-    MessageContent, Pipeline,
+    MessageContent, Pipeline, Tool,
 };
 
 use super::{
     image_processor::{ImagePreProcessor, PreprocessedImages},
-    preprocessor_config::{PreProcessorConfig, ToFilter},
+    preprocessor_config::{PreProcessorConfig, ToFilter, DEFAULT_RESCALE_FACTOR},
     processor_config::ProcessorConfig,
 };
 
@@ -52,11 +52,13 @@ impl Processor for Idefics2Processor {
         pipeline: &dyn Pipeline,
         messages: Vec<IndexMap<String, MessageContent>>,
         add_generation_prompt: bool,
+        tools: Option<Vec<Tool>>,
     ) -> anyhow::Result<Vec<u32>> {
         let mut prompt = apply_chat_template(
             pipeline,
             messages,
             add_generation_prompt,
+            tools,
             self.template_action(),
         )?;
 
@@ -225,8 +227,14 @@ impl ImagePreProcessor for Idefics2ImageProcessor {
 
         for image in images.iter_mut() {
             // Resize
-            if config.do_resize.is_some_and(|x| x) {
-                let size = config.size.as_ref().unwrap();
+            if config.do_resize.unwrap_or(true) {
+                let size = config.size.clone().unwrap_or_else(|| {
+                    // The defaults of Idefics2
+                    HashMap::from([
+                        ("shortest_edge".to_string(), 378),
+                        ("longest_edge".to_string(), 980),
+                    ])
+                });
                 let (h, w) = if size.contains_key("shortest_edge")
                     && size.contains_key("longest_edge")
                 {
@@ -261,23 +269,19 @@ impl ImagePreProcessor for Idefics2ImageProcessor {
 
         for image in images.iter_mut() {
             // Convert to rgb
-            if config.do_convert_rgb.is_some_and(|x| x) {
+            if config.do_convert_rgb.unwrap_or(true) {
                 *image = DynamicImage::ImageRgb8(image.to_rgb8());
             }
 
             let transforms = Transforms {
                 input: &ToTensorNoNorm,
                 inner_transforms: &[
-                    &config
-                        .do_rescale
-                        .is_some_and(|x| x)
-                        .then_some(())
-                        .map(|_| Rescale {
-                            factor: config.rescale_factor,
-                        }),
+                    &config.do_rescale.unwrap_or(true).then_some(Rescale {
+                        factor: Some(config.rescale_factor.unwrap_or(DEFAULT_RESCALE_FACTOR)),
+                    }),
                     &config
                         .do_normalize
-                        .is_some_and(|x| x)
+                        .unwrap_or(true)
                         .then_some(())
                         .map(|_| Normalize {
                             mean: config.image_mean.unwrap_or(Self::DEFAULT_MEAN).to_vec(),
@@ -288,7 +292,7 @@ impl ImagePreProcessor for Idefics2ImageProcessor {
 
             let mut image = image.apply(transforms, device)?;
             // Pad images, calculating attention mask.
-            if config.do_pad.is_some_and(|x| x) {
+            if config.do_pad.unwrap_or(true) {
                 let (_c, h, w) = image.dims3()?;
                 let padded = mistralrs_vision::pad(&image, max_h as usize, max_w as usize)?;
                 let mask = mistralrs_vision::make_pixel_mask(&padded, h, w)?;
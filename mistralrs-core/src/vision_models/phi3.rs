@@ -18,7 +18,8 @@ use crate::{
     },
     ops::{BitWiseOp, NonZeroOp},
     pipeline::{
-        extract_logits, Cache, IsqModel, NormalLoadingMetadata, Phi3RopeScaling, VisionModel,
+        extract_logits, Cache, IsqModel, IsqTensor, NormalLoadingMetadata, Phi3RopeScaling,
+        VisionModel,
     },
     serde_default_fn,
     utils::progress::NiceProgressBar,
@@ -873,15 +874,31 @@ impl Model {
 }
 
 impl IsqModel for Model {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         // TODO(EricLBuehler): more?
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, "lm_head".to_string()));
         for (i, layer) in self.layers.iter_mut().enumerate() {
-            tensors.push((&mut layer.self_attn.qkv_proj, Some(i)));
-            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
-            tensors.push((&mut layer.mlp.gate_up_proj, Some(i)));
-            tensors.push((&mut layer.mlp.down_proj, Some(i)));
+            tensors.push((
+                &mut layer.self_attn.qkv_proj,
+                Some(i),
+                format!("model.layers.{i}.self_attn.qkv_proj"),
+            ));
+            tensors.push((
+                &mut layer.self_attn.o_proj,
+                Some(i),
+                format!("model.layers.{i}.self_attn.o_proj"),
+            ));
+            tensors.push((
+                &mut layer.mlp.gate_up_proj,
+                Some(i),
+                format!("model.layers.{i}.mlp.gate_up_proj"),
+            ));
+            tensors.push((
+                &mut layer.mlp.down_proj,
+                Some(i),
+                format!("model.layers.{i}.mlp.down_proj"),
+            ));
         }
         (tensors, &*self.mapper)
     }
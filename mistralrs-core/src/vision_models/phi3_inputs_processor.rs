@@ -3,24 +3,29 @@
 use std::{any::Any, sync::Arc};
 
 use candle_core::{Device, Result, Tensor};
+use either::Either;
 use image::{imageops::FilterType, DynamicImage, GenericImage, GenericImageView, Rgba};
+use indexmap::IndexMap;
 use itertools::Itertools;
-use mistralrs_vision::{ApplyTransforms, Normalize, ToTensor, Transforms};
+use mistralrs_vision::{ApplyTransforms, Normalize, Rescale, ToTensorNoNorm, Transforms};
 use regex_automata::meta::Regex;
 use tokenizers::Tokenizer;
 
 use crate::{
     pipeline::{
+        apply_chat_template,
         text_models_inputs_processor::{self, get_completion_input, get_prompt_input},
         InputsProcessor, InputsProcessorType, MessagesAction, Processor, ProcessorCreator,
     },
     sequence::Sequence,
+    MessageContent, Pipeline, Tool,
 };
 
 use super::{
     image_processor::{ImagePreProcessor, PreprocessedImages},
     phi3::Phi3VisionSpecificArgs,
     preprocessor_config::PreProcessorConfig,
+    preprocessor_config::{ToFilter, DEFAULT_RESCALE_FACTOR},
     processor_config::ProcessorConfig,
     ModelInputs,
 };
@@ -48,7 +53,68 @@ impl ProcessorCreator for Phi3Processor {
     }
 }
 
+impl Phi3Processor {
+    /// Replace the image parts of the messages with the `<|image_{id}|>` tags, numbered from 1 in the order of the
+    /// images, unless the text already places the tags.
+    fn tag_images(
+        &self,
+        messages: Vec<IndexMap<String, MessageContent>>,
+    ) -> Vec<IndexMap<String, MessageContent>> {
+        let has_tags = messages
+            .iter()
+            .filter_map(|message| message.get("content")?.as_ref().right())
+            .flatten()
+            .filter_map(|content_row| content_row.get("text"))
+            .any(|text| {
+                self.inputs_processor
+                    .image_tag_splitter
+                    .is_match(text.as_str())
+            });
+        if has_tags {
+            return messages;
+        }
+        let mut image_id = 0;
+        let mut messages = messages;
+        for content in messages
+            .iter_mut()
+            .filter_map(|message| message.get_mut("content"))
+        {
+            let Either::Right(content) = content else {
+                continue;
+            };
+            for content_row in content {
+                if content_row.get("type").is_some_and(|tp| tp == "image") {
+                    image_id += 1;
+                    content_row.insert("type".to_string(), "text".to_string());
+                    content_row.insert("text".to_string(), format!("<|image_{image_id}|>"));
+                }
+            }
+        }
+        messages
+    }
+}
+
 impl Processor for Phi3Processor {
+    fn process(
+        &self,
+        pipeline: &dyn Pipeline,
+        messages: Vec<IndexMap<String, MessageContent>>,
+        add_generation_prompt: bool,
+        tools: Option<Vec<Tool>>,
+    ) -> anyhow::Result<Vec<u32>> {
+        let prompt = apply_chat_template(
+            pipeline,
+            self.tag_images(messages),
+            add_generation_prompt,
+            tools,
+            self.template_action(),
+        )?;
+        let encoding = pipeline
+            .tokenizer()
+            .encode(prompt, false)
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        Ok(encoding.get_ids().to_vec())
+    }
     fn inputs_processor(&self) -> Arc<dyn InputsProcessor> {
         self.inputs_processor.clone()
     }
@@ -296,9 +362,10 @@ impl Phi3InputsProcessor {
         new_image
     }
 
-    fn padding_336(img: &DynamicImage) -> DynamicImage {
+    /// Pad the height to a multiple of the crop size.
+    fn pad_to_crop_size(img: &DynamicImage, crop_size: u32) -> DynamicImage {
         let (_width, height) = img.dimensions();
-        let tar = ((height as f64 / 336.0).ceil() * 336.0) as u32;
+        let tar = ((height as f64 / crop_size as f64).ceil() * crop_size as f64) as u32;
         let top_padding = ((tar as f64 - height as f64 + 1.) / 2.) as u32;
         let bottom_padding = tar - height - top_padding;
         let left_padding = 0u32;
@@ -313,7 +380,12 @@ impl Phi3InputsProcessor {
         )
     }
 
-    fn hd_transform(img: &DynamicImage, hd_num: usize) -> DynamicImage {
+    fn hd_transform(
+        img: &DynamicImage,
+        hd_num: usize,
+        crop_size: u32,
+        filter: FilterType,
+    ) -> DynamicImage {
         let (mut width, mut height) = img.dimensions();
         let mut transposed = false;
 
@@ -335,11 +407,11 @@ impl Phi3InputsProcessor {
         }
         scale -= 1.0;
 
-        let new_width = (scale * 336.0) as u32;
+        let new_width = (scale * crop_size as f64) as u32;
         let new_height = (new_width as f64 / ratio) as u32;
 
-        let resized_img = img.resize_exact(new_width, new_height, FilterType::Nearest);
-        let padded_img = Self::padding_336(&resized_img);
+        let resized_img = img.resize_exact(new_width, new_height, filter);
+        let padded_img = Self::pad_to_crop_size(&resized_img, crop_size);
 
         if transposed {
             return padded_img.rotate270();
@@ -349,6 +421,21 @@ impl Phi3InputsProcessor {
     }
 }
 
+/// Size of the crops if the config has no `size`, as for Phi 3 Vision.
+const DEFAULT_CROP_SIZE: usize = 336;
+const DEFAULT_NUM_IMG_TOKENS: usize = 144;
+
+/// The crops are squares, with the `height` and `width` of the `size` of the config.
+fn crop_size(config: &PreProcessorConfig) -> Result<usize> {
+    let Some(size) = &config.size else {
+        return Ok(DEFAULT_CROP_SIZE);
+    };
+    match (size.get("height"), size.get("width")) {
+        (Some(h), Some(w)) if h == w => Ok(*h as usize),
+        _ => candle_core::bail!("Size must be a map of equal `height` and `width`."),
+    }
+}
+
 fn pad_to_max_num_crops_tensor(image: &Tensor, max_crops: usize) -> Result<Tensor> {
     let (b, _, h, w) = image.dims4()?;
     if b < max_crops {
@@ -374,6 +461,12 @@ impl ImagePreProcessor for Phi3InputsProcessor {
         // If no images, will not call this.
         assert!(!images.is_empty());
 
+        let crop_size = crop_size(config)?;
+        let filter = config.resampling.to_filter()?;
+        // Each crop is embedded as a square grid of tokens
+        let crop_tokens = config.num_img_tokens.unwrap_or(DEFAULT_NUM_IMG_TOKENS);
+        let crop_side_tokens = (crop_tokens as f64).sqrt() as usize;
+
         let mut image_sizes = Vec::new();
         let mut padded_images = Vec::new();
         let mut num_img_tokens = Vec::new();
@@ -390,7 +483,7 @@ impl ImagePreProcessor for Phi3InputsProcessor {
         }
         let (max_h, max_w) = max_size.unwrap();
         for image in images.iter_mut() {
-            *image = image.resize_exact(max_w as u32, max_h as u32, FilterType::Nearest);
+            *image = image.resize_exact(max_w as u32, max_h as u32, filter);
         }
 
         for image in images.iter_mut() {
@@ -399,41 +492,44 @@ impl ImagePreProcessor for Phi3InputsProcessor {
                 *image = DynamicImage::ImageRgb8(image.to_rgb8());
             }
 
-            let hd_image = Self::hd_transform(image, config.num_crops.expect("Need `num_crops`"));
+            let hd_image = Self::hd_transform(
+                image,
+                config.num_crops.expect("Need `num_crops`"),
+                crop_size as u32,
+                filter,
+            );
 
             // Both hd and global have a normalization
             // Transforms for the HD image
             let transforms_hd = Transforms {
-                input: &ToTensor,
-                inner_transforms: &[&Normalize {
-                    mean: config.image_mean.unwrap_or(Self::DEFAULT_MEAN).to_vec(),
-                    std: config.image_std.unwrap_or(Self::DEFAULT_STD).to_vec(),
-                }],
+                input: &ToTensorNoNorm,
+                inner_transforms: &[
+                    &config.do_rescale.unwrap_or(true).then_some(Rescale {
+                        factor: Some(config.rescale_factor.unwrap_or(DEFAULT_RESCALE_FACTOR)),
+                    }),
+                    &config.do_normalize.unwrap_or(true).then_some(Normalize {
+                        mean: config.image_mean.unwrap_or(Self::DEFAULT_MEAN).to_vec(),
+                        std: config.image_std.unwrap_or(Self::DEFAULT_STD).to_vec(),
+                    }),
+                ],
             };
 
             // (3,h,w)
             let hd_image = hd_image.apply(transforms_hd, device)?;
 
             // Resize with bicubic interpolation
-            // (3,336,336)
-            let global_image = hd_image.unsqueeze(0)?.interpolate2d(336, 336)?;
+            // (3,crop_size,crop_size)
+            let global_image = hd_image.unsqueeze(0)?.interpolate2d(crop_size, crop_size)?;
 
             let (_, h, w) = hd_image.dims3()?;
-            let num_image_tokens = ((h as f32 / 336. * w as f32 / 336. + 1.) * 144.
-                + ((h as f32 / 336.) + 1.) * 12.
-                + 1.) as usize;
+            let (h_crops, w_crops) = (h / crop_size, w / crop_size);
+            let num_image_tokens =
+                (h_crops * w_crops + 1) * crop_tokens + (h_crops + 1) * crop_side_tokens + 1;
 
             let hd_image_reshape = hd_image
-                .reshape((
-                    1,
-                    3,
-                    (h as f32 / 336.) as usize,
-                    336,
-                    (w as f32 / 336.) as usize,
-                    336,
-                ))?
+                .reshape((1, 3, h_crops, crop_size, w_crops, crop_size))?
                 .permute((0, 2, 4, 1, 3, 5))?
-                .reshape(((), 3, 336, 336))?;
+                .reshape(((), 3, crop_size, crop_size))?;
             let hd_image_reshape = Tensor::cat(&[global_image, hd_image_reshape], 0)?;
             let image_transformed = pad_to_max_num_crops_tensor(
                 &hd_image_reshape,
@@ -456,3 +552,157 @@ impl ImagePreProcessor for Phi3InputsProcessor {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use either::Either;
+    use indexmap::IndexMap;
+    use regex_automata::meta::Regex;
+
+    use super::{Phi3InputsProcessor, Phi3Processor};
+    use crate::{
+        vision_models::{
+            image_processor::ImagePreProcessor, preprocessor_config::PreProcessorConfig,
+        },
+        MessageContent,
+    };
+
+    fn user_message(parts: &[(&str, &str)]) -> IndexMap<String, MessageContent> {
+        let content = parts
+            .iter()
+            .map(|(tp, text)| {
+                let mut part = IndexMap::new();
+                part.insert("type".to_string(), tp.to_string());
+                if *tp == "text" {
+                    part.insert("text".to_string(), text.to_string());
+                }
+                part
+            })
+            .collect();
+        let mut message = IndexMap::new();
+        message.insert("role".to_string(), Either::Left("user".to_string()));
+        message.insert("content".to_string(), Either::Right(content));
+        message
+    }
+
+    fn texts(messages: &[IndexMap<String, MessageContent>]) -> Vec<Vec<String>> {
+        messages
+            .iter()
+            .map(|message| {
+                message["content"]
+                    .as_ref()
+                    .unwrap_right()
+                    .iter()
+                    .map(|part| part["text"].clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn image_parts_are_tagged_in_order() {
+        let processor = Phi3Processor {
+            inputs_processor: Arc::new(Phi3InputsProcessor {
+                image_tag_splitter: Regex::new(r"<\|image_\d+\|>").unwrap(),
+            }),
+        };
+        let messages = processor.tag_images(vec![
+            user_message(&[("image", ""), ("text", "Compare with"), ("image", "")]),
+            user_message(&[("text", "And this one?"), ("image", "")]),
+        ]);
+        assert_eq!(
+            texts(&messages),
+            vec![
+                vec!["<|image_1|>", "Compare with", "<|image_2|>"],
+                vec!["And this one?", "<|image_3|>"],
+            ]
+        );
+
+        // Tags placed by the text are kept as they are
+        let messages = processor.tag_images(vec![user_message(&[
+            ("text", "<|image_1|>\nWhat is this?"),
+            ("image", ""),
+        ])]);
+        assert_eq!(
+            messages[0]["content"].as_ref().unwrap_right()[1]["type"],
+            "image"
+        );
+    }
+
+    #[test]
+    fn preprocessing_follows_the_config() {
+        use candle_core::{Device, IndexOp, D};
+        use image::{DynamicImage, Rgb, RgbImage};
+
+        // The `preprocessor_config.json` of microsoft/Phi-3-vision-128k-instruct
+        let mut config: PreProcessorConfig = serde_json::from_str(
+            r#"{
+                "auto_map": {
+                    "AutoProcessor": "processing_phi3_v.Phi3VProcessor",
+                    "AutoImageProcessor": "image_processing_phi3_v.Phi3VImageProcessor"
+                },
+                "num_crops": 16,
+                "image_mean": [0.48145466, 0.4578275, 0.40821073],
+                "image_processor_type": "Phi3VImageProcessor",
+                "image_std": [0.26862954, 0.26130258, 0.27577711],
+                "processor_class": "Phi3VProcessor",
+                "num_img_tokens": 144
+            }"#,
+        )
+        .unwrap();
+        // Smaller crops keep the test fast
+        config.num_crops = Some(4);
+        config.size = Some([("height".to_string(), 16), ("width".to_string(), 16)].into());
+
+        let processor = Phi3InputsProcessor {
+            image_tag_splitter: Regex::new(r"<\|image_\d+\|>").unwrap(),
+        };
+        let color = [255u8, 128, 0];
+        let image = || DynamicImage::ImageRgb8(RgbImage::from_pixel(32, 16, Rgb(color)));
+        let channel_means = |pixel_values: &candle_core::Tensor| {
+            pixel_values
+                .flatten_from(D::Minus2)
+                .unwrap()
+                .mean(D::Minus1)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap()
+        };
+
+        let preprocessed = processor
+            .preprocess(vec![image()], &config, &Device::Cpu)
+            .unwrap();
+        // The global image and two crops, padded to `num_crops + 1`
+        assert_eq!(preprocessed.pixel_values.dims(), &[1, 5, 3, 16, 16]);
+        assert_eq!(preprocessed.image_sizes, Some((16, 32)));
+        assert_eq!(
+            preprocessed.num_img_tokens,
+            Some(vec![(2 + 1) * 144 + 2 * 12 + 1])
+        );
+        let pixel_values = preprocessed.pixel_values.i(0).unwrap();
+        let mean = config.image_mean.unwrap();
+        let std = config.image_std.unwrap();
+        for (i, crop) in channel_means(&pixel_values).into_iter().enumerate() {
+            for (c, value) in crop.into_iter().enumerate() {
+                let expected = if i < 3 {
+                    (f64::from(color[c]) / 255. - mean[c]) / std[c]
+                } else {
+                    0.
+                };
+                assert!((f64::from(value) - expected).abs() < 2e-2);
+            }
+        }
+
+        // Without normalization, only the rescaled pixel values remain
+        config.do_normalize = Some(false);
+        let preprocessed = processor
+            .preprocess(vec![image()], &config, &Device::Cpu)
+            .unwrap();
+        let crop = &channel_means(&preprocessed.pixel_values.i(0).unwrap())[1];
+        for (value, color) in crop.iter().zip(color) {
+            assert!((f64::from(*value) - f64::from(color) / 255.).abs() < 1e-2);
+        }
+    }
+}
@@ -22,6 +22,9 @@ pub struct PreProcessorConfig {
     pub(crate) num_crops: Option<usize>,
 }
 
+/// Factor to rescale the pixel values from `[0, 255]` to `[0, 1]` if the config has no `rescale_factor`.
+pub(crate) const DEFAULT_RESCALE_FACTOR: f64 = 1. / 255.;
+
 #[allow(dead_code)]
 pub(crate) trait ToFilter {
     fn to_filter(self) -> Result<FilterType>;
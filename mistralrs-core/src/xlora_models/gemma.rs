@@ -5,10 +5,10 @@ use std::{collections::HashMap, sync::Arc};
 use crate::{
     layers::ScaledDotProductAttention,
     lora::{linear_b as linear, LinearLayerLike, LoraConfig, Ordering},
-    pipeline::{IsqModel, NormalLoadingMetadata},
+    pipeline::{IsqModel, IsqTensor, NormalLoadingMetadata},
     utils::progress::NiceProgressBar,
 };
-use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor, D};
+use candle_core::{DType, Device, Module, Result, Tensor, D};
 use candle_nn::{RotaryEmbedding, VarBuilder};
 use tqdm::Iter;
 use tracing::info;
@@ -22,10 +22,6 @@ use crate::{
 
 use super::{classifier::XLoraClassifier, NonGranularState, ScalingsMaker, XLoraConfig};
 
-fn default_max_position_embeddings() -> usize {
-    4096
-}
-
 #[derive(Debug, Clone)]
 struct RmsNorm {
     weight: Tensor,
@@ -568,7 +564,7 @@ impl XLoraModel {
             dtype: vb.dtype(),
             hidden_size: cfg.hidden_size,
             cache: Cache::new(cfg.num_hidden_layers, true),
-            max_seq_len: default_max_position_embeddings(),
+            max_seq_len: cfg.max_position_embeddings,
             xlora_classifier: xlora_config.map(|xlora_config| {
                 XLoraClassifier::new(xlora_config, count, lora_config.len(), vb, false).unwrap()
             }),
@@ -712,37 +708,44 @@ impl XLoraModel {
 }
 
 impl IsqModel for XLoraModel {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((self.lm_head.inner(), None));
+        tensors.push((self.lm_head.inner(), None, "lm_head".to_string()));
         for (i, layer) in self.layers.iter_mut().enumerate() {
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.q_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.q_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.k_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.k_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.v_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.v_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.o_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.o_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.mlp.down_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.mlp.down_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.mlp.gate_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.mlp.gate_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.mlp.up_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.mlp.up_proj"),
             ));
         }
         (tensors, &*self.mapper)
@@ -825,6 +828,118 @@ impl NormalModel for XLoraModel {
         }
         Ok(sum)
     }
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
+    fn merge_adapter(&mut self, name: &str) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+        }
+        Ok(sum)
+    }
+    fn unmerge_adapter(&mut self) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+        }
+        Ok(sum)
+    }
+    fn set_lora_scale(&mut self, scale: f64) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+        }
+        Ok(sum)
+    }
 }
 
 impl ScalingsMaker for XLoraModel {
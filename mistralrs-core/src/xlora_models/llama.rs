@@ -3,18 +3,18 @@
 use crate::{
     layers::ScaledDotProductAttention,
     lora::{linear_no_bias as linear, LinearLayerLike, LoraConfig, Ordering},
-    pipeline::IsqModel,
+    pipeline::{IsqModel, IsqTensor},
     utils::progress::NiceProgressBar,
 };
-use candle_core::{quantized::QMatMul, DType, Device, Result, Tensor};
-use candle_nn::{embedding, Embedding, Module, RotaryEmbedding, VarBuilder};
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{embedding, Embedding, Module, VarBuilder};
 use std::{collections::HashMap, sync::Arc};
 use tqdm::Iter;
 use tracing::info;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, QLinear, RmsNorm},
+    layers::{repeat_kv, CausalMasker, QLinear, RmsNorm, ScaledRotaryEmbedding},
     models::llama::Config,
     pipeline::{self, extract_logits, LayerCaches, NormalLoadingMetadata, NormalModel},
 };
@@ -31,7 +31,7 @@ struct CausalSelfAttention {
     num_key_value_heads: usize,
     head_dim: usize,
     use_flash_attn: bool,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: Arc<ScaledRotaryEmbedding>,
     max_seq_len: usize,
 }
 
@@ -156,7 +156,7 @@ impl CausalSelfAttention {
         mapper: &dyn DeviceMapper,
         layer_idx: usize,
         loading_isq: bool,
-        rope: Arc<RotaryEmbedding>,
+        rope: Arc<ScaledRotaryEmbedding>,
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Self> {
         let size_in = cfg.hidden_size;
@@ -212,7 +212,12 @@ impl CausalSelfAttention {
             head_dim: cfg.hidden_size / cfg.num_attention_heads,
             use_flash_attn: cfg.use_flash_attn,
             rotary_emb: rope,
-            max_seq_len: cfg.max_position_embeddings,
+            max_seq_len: cfg
+                .rope_scaling
+                .as_ref()
+                .map_or(cfg.max_position_embeddings, |scaling| {
+                    scaling.context_len(cfg.max_position_embeddings)
+                }),
         })
     }
 }
@@ -367,7 +372,7 @@ impl Block {
         mapper: &dyn DeviceMapper,
         layer_idx: usize,
         loading_isq: bool,
-        rope: Arc<RotaryEmbedding>,
+        rope: Arc<ScaledRotaryEmbedding>,
         preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
     ) -> Result<Self> {
         let attn = CausalSelfAttention::load(
@@ -595,10 +600,11 @@ impl XLoraLlama {
                 .into_iter()
                 .map(|i| {
                     let rotary_emb = Arc::new(
-                        RotaryEmbedding::new(
+                        ScaledRotaryEmbedding::new(
                             cfg.rope_theta,
                             head_dim,
                             cfg.max_position_embeddings,
+                            cfg.rope_scaling.as_ref(),
                             mapper
                                 .device_for(i, false)
                                 .unwrap_or(&normal_loading_metadata.real_device),
@@ -668,31 +674,44 @@ impl XLoraLlama {
 }
 
 impl IsqModel for XLoraLlama {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((self.lm_head.inner(), None));
+        tensors.push((self.lm_head.inner(), None, "lm_head".to_string()));
         for (i, layer) in self.blocks.iter_mut().enumerate() {
             tensors.push((
                 Arc::get_mut(&mut layer.attn.q_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.q_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.attn.k_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.k_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.attn.v_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.v_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.attn.o_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.o_proj"),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.c_fc1).unwrap().inner(),
+                Some(i),
+                format!("model.layers.{i}.mlp.gate_proj"),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.c_fc2).unwrap().inner(),
+                Some(i),
+                format!("model.layers.{i}.mlp.up_proj"),
             ));
-            tensors.push((Arc::get_mut(&mut layer.mlp.c_fc1).unwrap().inner(), Some(i)));
-            tensors.push((Arc::get_mut(&mut layer.mlp.c_fc2).unwrap().inner(), Some(i)));
             tensors.push((
                 Arc::get_mut(&mut layer.mlp.c_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.mlp.down_proj"),
             ));
         }
         (tensors, &*self.mapper)
@@ -775,6 +794,118 @@ impl NormalModel for XLoraLlama {
         }
         Ok(sum)
     }
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
+    fn merge_adapter(&mut self, name: &str) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+        }
+        Ok(sum)
+    }
+    fn unmerge_adapter(&mut self) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+        }
+        Ok(sum)
+    }
+    fn set_lora_scale(&mut self, scale: f64) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+        }
+        Ok(sum)
+    }
 }
 
 impl ScalingsMaker for XLoraLlama {
@@ -3,19 +3,19 @@
 use crate::{
     layers::ScaledDotProductAttention,
     lora::{linear_no_bias, LinearLayerLike, LoraConfig, Ordering},
-    pipeline::{IsqModel, NormalLoadingMetadata},
+    pipeline::{IsqModel, IsqTensor, NormalLoadingMetadata},
     utils::progress::NiceProgressBar,
 };
 /// Mistral LLM, https://github.com/mistralai/mistral-src
-use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor};
-use candle_nn::{Activation, RotaryEmbedding, VarBuilder};
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, VarBuilder};
 use std::{collections::HashMap, sync::Arc};
 use tqdm::Iter;
 use tracing::info;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, QLinear, RmsNorm},
+    layers::{repeat_kv, CausalMasker, QLinear, RmsNorm, ScaledRotaryEmbedding},
     models::mistral::Config,
     pipeline::{extract_logits, Cache, NormalModel},
 };
@@ -134,7 +134,7 @@ struct Attention {
     num_kv_heads: usize,
     num_kv_groups: usize,
     head_dim: usize,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: Arc<ScaledRotaryEmbedding>,
     use_flash_attn: bool,
     sliding_window: Option<usize>,
 }
@@ -142,7 +142,7 @@ struct Attention {
 impl Attention {
     #[allow(clippy::too_many_arguments)]
     fn new(
-        rotary_emb: Arc<RotaryEmbedding>,
+        rotary_emb: Arc<ScaledRotaryEmbedding>,
         cfg: &Config,
         vb: VarBuilder,
         lora_config: &[((String, String), LoraConfig)],
@@ -339,7 +339,7 @@ struct DecoderLayer {
 impl DecoderLayer {
     #[allow(clippy::too_many_arguments)]
     fn new(
-        rotary_emb: Arc<RotaryEmbedding>,
+        rotary_emb: Arc<ScaledRotaryEmbedding>,
         cfg: &Config,
         vb: VarBuilder,
         lora_config: &[((String, String), LoraConfig)],
@@ -469,10 +469,11 @@ impl XLoraModel {
         let vb_l = vb_m.pp("layers");
         let mut count = 0;
         for layer_idx in NiceProgressBar(0..cfg.num_hidden_layers, "Loading repeating layers") {
-            let rotary_emb = Arc::new(RotaryEmbedding::new(
+            let rotary_emb = Arc::new(ScaledRotaryEmbedding::new(
                 cfg.rope_theta as f32,
                 head_dim,
                 cfg.max_position_embeddings,
+                cfg.rope_scaling.as_ref(),
                 mapper
                     .device_for(layer_idx, false)
                     .unwrap_or(&normal_loading_metadata.real_device),
@@ -540,7 +541,12 @@ impl XLoraModel {
             device: normal_loading_metadata.real_device,
             dtype: vb.dtype(),
             cache: Cache::new(cfg.num_hidden_layers, true),
-            max_seq_len: cfg.max_position_embeddings,
+            max_seq_len: cfg
+                .rope_scaling
+                .as_ref()
+                .map_or(cfg.max_position_embeddings, |scaling| {
+                    scaling.context_len(cfg.max_position_embeddings)
+                }),
             xlora_classifier: xlora_config.map(|xlora_config| {
                 XLoraClassifier::new(xlora_config, count, lora_config.len(), vb, false).unwrap()
             }),
@@ -684,37 +690,44 @@ impl XLoraModel {
 }
 
 impl IsqModel for XLoraModel {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((self.lm_head.inner(), None));
+        tensors.push((self.lm_head.inner(), None, "lm_head".to_string()));
         for (i, layer) in self.layers.iter_mut().enumerate() {
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.q_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.q_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.k_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.k_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.v_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.v_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.o_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.o_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.mlp.down_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.mlp.down_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.mlp.gate_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.mlp.gate_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.mlp.up_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.mlp.up_proj"),
             ));
         }
         (tensors, &*self.mapper)
@@ -797,6 +810,118 @@ impl NormalModel for XLoraModel {
         }
         Ok(sum)
     }
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
+    fn merge_adapter(&mut self, name: &str) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+        }
+        Ok(sum)
+    }
+    fn unmerge_adapter(&mut self) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+        }
+        Ok(sum)
+    }
+    fn set_lora_scale(&mut self, scale: f64) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+        }
+        Ok(sum)
+    }
 }
 
 impl ScalingsMaker for XLoraModel {
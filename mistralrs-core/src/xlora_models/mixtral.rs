@@ -3,21 +3,21 @@
 use crate::{
     layers::{MatMul, ScaledDotProductAttention},
     lora::{linear_no_bias, LinearLayerLike, LoraConfig, Ordering},
-    pipeline::{IsqModel, NormalLoadingMetadata},
+    pipeline::{IsqModel, IsqTensor, NormalLoadingMetadata},
     utils::progress::NiceProgressBar,
 };
 /// Mixtral Model
 /// https://github.com/huggingface/transformers/blob/main/src/transformers/models/mixtral/modeling_mixtral.py
 /// https://mistral.ai/news/mixtral-of-experts/
 use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor};
-use candle_nn::{Activation, RotaryEmbedding, VarBuilder};
+use candle_nn::{Activation, VarBuilder};
 use std::{collections::HashMap, sync::Arc};
 use tqdm::Iter;
 use tracing::info;
 
 use crate::{
     device_map::DeviceMapper,
-    layers::{repeat_kv, CausalMasker, RmsNorm},
+    layers::{repeat_kv, CausalMasker, RmsNorm, ScaledRotaryEmbedding},
     models::mixtral::Config,
     pipeline::{extract_logits, Cache, NormalModel},
 };
@@ -34,7 +34,7 @@ struct Attention {
     num_kv_heads: usize,
     num_kv_groups: usize,
     head_dim: usize,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: Arc<ScaledRotaryEmbedding>,
     use_flash_attn: bool,
     sliding_window: Option<usize>,
 }
@@ -42,7 +42,7 @@ struct Attention {
 impl Attention {
     #[allow(clippy::too_many_arguments)]
     fn new(
-        rotary_emb: Arc<RotaryEmbedding>,
+        rotary_emb: Arc<ScaledRotaryEmbedding>,
         cfg: &Config,
         vb: VarBuilder,
         lora_config: &[((String, String), LoraConfig)],
@@ -477,7 +477,7 @@ struct DecoderLayer {
 impl DecoderLayer {
     #[allow(clippy::too_many_arguments)]
     fn new(
-        rotary_emb: Arc<RotaryEmbedding>,
+        rotary_emb: Arc<ScaledRotaryEmbedding>,
         cfg: &Config,
         vb: VarBuilder,
         lora_config: &[((String, String), LoraConfig)],
@@ -607,10 +607,11 @@ impl XLoraModel {
         let vb_l = vb_m.pp("layers");
         let mut count = 0;
         for layer_idx in NiceProgressBar(0..cfg.num_hidden_layers, "Loading repeating layers") {
-            let rotary_emb = Arc::new(RotaryEmbedding::new(
+            let rotary_emb = Arc::new(ScaledRotaryEmbedding::new(
                 cfg.rope_theta as f32,
                 head_dim,
                 cfg.max_position_embeddings,
+                cfg.rope_scaling.as_ref(),
                 mapper
                     .device_for(layer_idx, false)
                     .unwrap_or(&normal_loading_metadata.real_device),
@@ -677,7 +678,12 @@ impl XLoraModel {
             device: normal_loading_metadata.real_device,
             dtype: vb.dtype(),
             cache: Cache::new(cfg.num_hidden_layers, false),
-            max_seq_len: cfg.max_position_embeddings,
+            max_seq_len: cfg
+                .rope_scaling
+                .as_ref()
+                .map_or(cfg.max_position_embeddings, |scaling| {
+                    scaling.context_len(cfg.max_position_embeddings)
+                }),
             xlora_classifier: xlora_config.map(|xlora_config| {
                 XLoraClassifier::new(xlora_config, count, lora_config.len(), vb, false).unwrap()
             }),
@@ -821,36 +827,53 @@ impl XLoraModel {
 }
 
 impl IsqModel for XLoraModel {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((&mut self.lm_head, None));
+        tensors.push((&mut self.lm_head, None, "lm_head".to_string()));
         for (i, layer) in self.layers.iter_mut().enumerate() {
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.q_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.q_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.k_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.k_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.v_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.v_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.o_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.o_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.block_sparse_moe.gate)
                     .unwrap()
                     .inner(),
                 Some(i),
+                format!("model.layers.{i}.block_sparse_moe.gate"),
             ));
-            for expert in &mut layer.block_sparse_moe.experts {
-                tensors.push((Arc::get_mut(&mut expert.w1).unwrap().inner(), Some(i)));
-                tensors.push((Arc::get_mut(&mut expert.w2).unwrap().inner(), Some(i)));
-                tensors.push((Arc::get_mut(&mut expert.w3).unwrap().inner(), Some(i)));
+            for (j, expert) in layer.block_sparse_moe.experts.iter_mut().enumerate() {
+                tensors.push((
+                    Arc::get_mut(&mut expert.w1).unwrap().inner(),
+                    Some(i),
+                    format!("model.layers.{i}.block_sparse_moe.experts.{j}.w1"),
+                ));
+                tensors.push((
+                    Arc::get_mut(&mut expert.w2).unwrap().inner(),
+                    Some(i),
+                    format!("model.layers.{i}.block_sparse_moe.experts.{j}.w2"),
+                ));
+                tensors.push((
+                    Arc::get_mut(&mut expert.w3).unwrap().inner(),
+                    Some(i),
+                    format!("model.layers.{i}.block_sparse_moe.experts.{j}.w3"),
+                ));
             }
         }
         (tensors, &*self.mapper)
@@ -938,6 +961,126 @@ impl NormalModel for XLoraModel {
         }
         Ok(sum)
     }
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.block_sparse_moe.gate)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            for expert in &mut layer.block_sparse_moe.experts {
+                sum += Arc::get_mut(&mut expert.w1)
+                    .unwrap()
+                    .add_adapter(name, vb, cfg)?;
+                sum += Arc::get_mut(&mut expert.w2)
+                    .unwrap()
+                    .add_adapter(name, vb, cfg)?;
+                sum += Arc::get_mut(&mut expert.w3)
+                    .unwrap()
+                    .add_adapter(name, vb, cfg)?;
+            }
+        }
+        Ok(sum)
+    }
+    fn merge_adapter(&mut self, name: &str) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+
+            sum += Arc::get_mut(&mut layer.block_sparse_moe.gate)
+                .unwrap()
+                .merge_adapter(name)?;
+            for expert in &mut layer.block_sparse_moe.experts {
+                sum += Arc::get_mut(&mut expert.w1).unwrap().merge_adapter(name)?;
+                sum += Arc::get_mut(&mut expert.w2).unwrap().merge_adapter(name)?;
+                sum += Arc::get_mut(&mut expert.w3).unwrap().merge_adapter(name)?;
+            }
+        }
+        Ok(sum)
+    }
+    fn unmerge_adapter(&mut self) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+
+            sum += Arc::get_mut(&mut layer.block_sparse_moe.gate)
+                .unwrap()
+                .unmerge_adapter()?;
+            for expert in &mut layer.block_sparse_moe.experts {
+                sum += Arc::get_mut(&mut expert.w1).unwrap().unmerge_adapter()?;
+                sum += Arc::get_mut(&mut expert.w2).unwrap().unmerge_adapter()?;
+                sum += Arc::get_mut(&mut expert.w3).unwrap().unmerge_adapter()?;
+            }
+        }
+        Ok(sum)
+    }
+    fn set_lora_scale(&mut self, scale: f64) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+
+            sum += Arc::get_mut(&mut layer.block_sparse_moe.gate)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            for expert in &mut layer.block_sparse_moe.experts {
+                sum += Arc::get_mut(&mut expert.w1)
+                    .unwrap()
+                    .set_lora_scale(scale)?;
+                sum += Arc::get_mut(&mut expert.w2)
+                    .unwrap()
+                    .set_lora_scale(scale)?;
+                sum += Arc::get_mut(&mut expert.w3)
+                    .unwrap()
+                    .set_lora_scale(scale)?;
+            }
+        }
+        Ok(sum)
+    }
 }
 
 impl ScalingsMaker for XLoraModel {
@@ -5,7 +5,7 @@ use std::{collections::HashMap, sync::Arc};
 use crate::{
     layers::ScaledDotProductAttention,
     lora::{linear, LinearLayerLike, LoraConfig, Ordering},
-    pipeline::{IsqModel, NormalLoadingMetadata},
+    pipeline::{IsqModel, IsqTensor, NormalLoadingMetadata},
     utils::progress::NiceProgressBar,
 };
 /// Phi model.
@@ -13,7 +13,7 @@ use crate::{
 /// There is an alternative implementation of the phi model in mixformers.rs.
 /// This corresponds to the model update made with the following commit:
 /// https://huggingface.co/microsoft/phi-2/commit/cb2f4533604d8b67de604e7df03bfe6f3ca22869
-use candle_core::{quantized::QMatMul, DType, Device, Result, Tensor};
+use candle_core::{DType, Device, Result, Tensor};
 use candle_nn::{
     embedding, layer_norm, Activation, Embedding, LayerNorm, RotaryEmbedding, VarBuilder,
 };
@@ -652,28 +652,40 @@ impl Model {
 }
 
 impl IsqModel for Model {
-    fn get_tensors(&mut self) -> (Vec<(&mut QMatMul, Option<usize>)>, &dyn DeviceMapper) {
+    fn get_tensors(&mut self) -> (Vec<IsqTensor<'_>>, &dyn DeviceMapper) {
         let mut tensors = Vec::new();
-        tensors.push((self.lm_head.inner(), None));
+        tensors.push((self.lm_head.inner(), None, "lm_head".to_string()));
         for (i, layer) in self.layers.iter_mut().enumerate() {
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.q_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.q_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.k_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.k_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.v_proj).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.v_proj"),
             ));
             tensors.push((
                 Arc::get_mut(&mut layer.self_attn.dense).unwrap().inner(),
                 Some(i),
+                format!("model.layers.{i}.self_attn.dense"),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.fc1).unwrap().inner(),
+                Some(i),
+                format!("model.layers.{i}.mlp.fc1"),
+            ));
+            tensors.push((
+                Arc::get_mut(&mut layer.mlp.fc2).unwrap().inner(),
+                Some(i),
+                format!("model.layers.{i}.mlp.fc2"),
             ));
-            tensors.push((Arc::get_mut(&mut layer.mlp.fc1).unwrap().inner(), Some(i)));
-            tensors.push((Arc::get_mut(&mut layer.mlp.fc2).unwrap().inner(), Some(i)));
         }
         (tensors, &*self.mapper)
     }
@@ -752,6 +764,106 @@ impl NormalModel for Model {
         }
         Ok(sum)
     }
+    fn add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.dense)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.fc1)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.fc2)
+                .unwrap()
+                .add_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
+    fn merge_adapter(&mut self, name: &str) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.dense)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .merge_adapter(name)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.fc1)
+                .unwrap()
+                .merge_adapter(name)?;
+            sum += Arc::get_mut(&mut layer.mlp.fc2)
+                .unwrap()
+                .merge_adapter(name)?;
+        }
+        Ok(sum)
+    }
+    fn unmerge_adapter(&mut self) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.dense)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .unmerge_adapter()?;
+
+            sum += Arc::get_mut(&mut layer.mlp.fc1)
+                .unwrap()
+                .unmerge_adapter()?;
+            sum += Arc::get_mut(&mut layer.mlp.fc2)
+                .unwrap()
+                .unmerge_adapter()?;
+        }
+        Ok(sum)
+    }
+    fn set_lora_scale(&mut self, scale: f64) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.dense)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .set_lora_scale(scale)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.fc1)
+                .unwrap()
+                .set_lora_scale(scale)?;
+            sum += Arc::get_mut(&mut layer.mlp.fc2)
+                .unwrap()
+                .set_lora_scale(scale)?;
+        }
+        Ok(sum)
+    }
 }
 
 impl ScalingsMaker for Model {
@@ -748,6 +748,118 @@ impl ModelWeights {
         }
         Ok(sum)
     }
+    pub fn add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attention_wk.add_adapter(name, vb, cfg)?;
+            sum += layer.attention_wo.add_adapter(name, vb, cfg)?;
+            sum += layer.attention_wq.add_adapter(name, vb, cfg)?;
+            sum += layer.attention_wv.add_adapter(name, vb, cfg)?;
+            match &mut layer.mlp_or_moe {
+                MlpOrMoe::Mlp(ref mut m) => {
+                    sum += m.feed_forward_w1.add_adapter(name, vb, cfg)?;
+                    sum += m.feed_forward_w2.add_adapter(name, vb, cfg)?;
+                    sum += m.feed_forward_w3.add_adapter(name, vb, cfg)?;
+                }
+                MlpOrMoe::MoE {
+                    n_expert_used: _,
+                    feed_forward_gate_inp: _,
+                    experts,
+                } => {
+                    for expert in experts {
+                        sum += expert.feed_forward_w1.add_adapter(name, vb, cfg)?;
+                        sum += expert.feed_forward_w2.add_adapter(name, vb, cfg)?;
+                        sum += expert.feed_forward_w3.add_adapter(name, vb, cfg)?;
+                    }
+                }
+            }
+        }
+        Ok(sum)
+    }
+    pub fn merge_adapter(&mut self, name: &str) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attention_wk.merge_adapter(name)?;
+            sum += layer.attention_wo.merge_adapter(name)?;
+            sum += layer.attention_wq.merge_adapter(name)?;
+            sum += layer.attention_wv.merge_adapter(name)?;
+            match &mut layer.mlp_or_moe {
+                MlpOrMoe::Mlp(ref mut m) => {
+                    sum += m.feed_forward_w1.merge_adapter(name)?;
+                    sum += m.feed_forward_w2.merge_adapter(name)?;
+                    sum += m.feed_forward_w3.merge_adapter(name)?;
+                }
+                MlpOrMoe::MoE {
+                    n_expert_used: _,
+                    feed_forward_gate_inp: _,
+                    experts,
+                } => {
+                    for expert in experts {
+                        sum += expert.feed_forward_w1.merge_adapter(name)?;
+                        sum += expert.feed_forward_w2.merge_adapter(name)?;
+                        sum += expert.feed_forward_w3.merge_adapter(name)?;
+                    }
+                }
+            }
+        }
+        Ok(sum)
+    }
+    pub fn unmerge_adapter(&mut self) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attention_wk.unmerge_adapter()?;
+            sum += layer.attention_wo.unmerge_adapter()?;
+            sum += layer.attention_wq.unmerge_adapter()?;
+            sum += layer.attention_wv.unmerge_adapter()?;
+            match &mut layer.mlp_or_moe {
+                MlpOrMoe::Mlp(ref mut m) => {
+                    sum += m.feed_forward_w1.unmerge_adapter()?;
+                    sum += m.feed_forward_w2.unmerge_adapter()?;
+                    sum += m.feed_forward_w3.unmerge_adapter()?;
+                }
+                MlpOrMoe::MoE {
+                    n_expert_used: _,
+                    feed_forward_gate_inp: _,
+                    experts,
+                } => {
+                    for expert in experts {
+                        sum += expert.feed_forward_w1.unmerge_adapter()?;
+                        sum += expert.feed_forward_w2.unmerge_adapter()?;
+                        sum += expert.feed_forward_w3.unmerge_adapter()?;
+                    }
+                }
+            }
+        }
+        Ok(sum)
+    }
+    pub fn set_lora_scale(&mut self, scale: f64) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attention_wk.set_lora_scale(scale)?;
+            sum += layer.attention_wo.set_lora_scale(scale)?;
+            sum += layer.attention_wq.set_lora_scale(scale)?;
+            sum += layer.attention_wv.set_lora_scale(scale)?;
+            match &mut layer.mlp_or_moe {
+                MlpOrMoe::Mlp(ref mut m) => {
+                    sum += m.feed_forward_w1.set_lora_scale(scale)?;
+                    sum += m.feed_forward_w2.set_lora_scale(scale)?;
+                    sum += m.feed_forward_w3.set_lora_scale(scale)?;
+                }
+                MlpOrMoe::MoE {
+                    n_expert_used: _,
+                    feed_forward_gate_inp: _,
+                    experts,
+                } => {
+                    for expert in experts {
+                        sum += expert.feed_forward_w1.set_lora_scale(scale)?;
+                        sum += expert.feed_forward_w2.set_lora_scale(scale)?;
+                        sum += expert.feed_forward_w3.set_lora_scale(scale)?;
+                    }
+                }
+            }
+        }
+        Ok(sum)
+    }
 
     #[allow(clippy::too_many_arguments)]
     fn inner_forward(
@@ -252,9 +252,10 @@ impl ModelConfig::FromAdapterGGUF for ModelWeights {
             rope_dim,
             rms_eps,
             context_window,
+            rope_freq_base,
         } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
 
-        let (cos, sin) = precomput_freqs_cis(rope_dim, 10_000., device, context_window)?;
+        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device, context_window)?;
 
         let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
         let tok_embeddings = tok_embeddings.dequantize(device)?;
@@ -377,6 +378,46 @@ impl ModelWeights {
         }
         Ok(sum)
     }
+    pub fn add_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attn_qkv.add_adapter(name, vb, cfg)?;
+            sum += layer.attn_output.add_adapter(name, vb, cfg)?;
+            sum += layer.mlp.ffn_down.add_adapter(name, vb, cfg)?;
+            sum += layer.mlp.ffn_up.add_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
+    pub fn merge_adapter(&mut self, name: &str) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attn_qkv.merge_adapter(name)?;
+            sum += layer.attn_output.merge_adapter(name)?;
+            sum += layer.mlp.ffn_down.merge_adapter(name)?;
+            sum += layer.mlp.ffn_up.merge_adapter(name)?;
+        }
+        Ok(sum)
+    }
+    pub fn unmerge_adapter(&mut self) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attn_qkv.unmerge_adapter()?;
+            sum += layer.attn_output.unmerge_adapter()?;
+            sum += layer.mlp.ffn_down.unmerge_adapter()?;
+            sum += layer.mlp.ffn_up.unmerge_adapter()?;
+        }
+        Ok(sum)
+    }
+    pub fn set_lora_scale(&mut self, scale: f64) -> Result<usize> {
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += layer.attn_qkv.set_lora_scale(scale)?;
+            sum += layer.attn_output.set_lora_scale(scale)?;
+            sum += layer.mlp.ffn_down.set_lora_scale(scale)?;
+            sum += layer.mlp.ffn_up.set_lora_scale(scale)?;
+        }
+        Ok(sum)
+    }
 
     pub fn inner_forward(
         &self,
@@ -30,6 +30,23 @@ class ChatCompletionRequest:
     grammar: str | None = None
     grammar_type: str | None = None
     adapters: list[str] | None = None
+    lora_scale: float | None = None
+    top_n_sigma: float | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    stop_token_ids: list[int] | None = None
+    eos_token_ids: list[int] | None = None
+    min_tokens: int | None = None
+    max_time: float | None = None
+    num_beams: int | None = None
+    length_penalty: float | None = None
+    penalty_alpha: float | None = None
+    repetition_penalty: float | None = None
+    repetition_penalty_range: int | None = None
+    no_repeat_ngram_size: int | None = None
+    seed: int | None = None
+    priority: int = 0
+    tools: str | None = None
 
 @dataclass
 class CompletionRequest:
@@ -44,7 +61,7 @@ class CompletionRequest:
     logit_bias: dict[int, float] | None = None
     max_tokens: int | None = None
     n_choices: int = 1
-    best_of: int = 1
+    best_of: int | None = None
     presence_penalty: float | None = None
     frequency_penalty: float | None = None
     stop_seqs: list[str] | None = None
@@ -55,6 +72,22 @@ class CompletionRequest:
     grammar: str | None = None
     grammar_type: str | None = None
     adapters: list[str] | None = None
+    lora_scale: float | None = None
+    top_n_sigma: float | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    stop_token_ids: list[int] | None = None
+    eos_token_ids: list[int] | None = None
+    min_tokens: int | None = None
+    max_time: float | None = None
+    num_beams: int | None = None
+    length_penalty: float | None = None
+    penalty_alpha: float | None = None
+    repetition_penalty: float | None = None
+    repetition_penalty_range: int | None = None
+    no_repeat_ngram_size: int | None = None
+    seed: int | None = None
+    priority: int = 0
 
 @dataclass
 class Architecture(Enum):
@@ -162,13 +195,28 @@ class Runner:
         which: Which,
         max_seqs: int = 16,
         no_kv_cache: bool = False,
-        prefix_cache_n: int = 16,
+        prefix_cache_n: int | None = None,
         token_source: str = "cache",
         speculative_gamma: int = 32,
         which_draft: Which | None = None,
+        draft_device_ordinal: int | None = None,
         chat_template: str | None = None,
         num_device_layers: int | list[str] | None = None,
         in_situ_quant: str | None = None,
+        default_system_prompt: str | None = None,
+        cpu: bool = False,
+        prompt_lookup_max_ngram_size: int | None = None,
+        medusa_model_id: str | None = None,
+        speculative_tree_width: int | None = None,
+        attention_impl: str | None = None,
+        tensor_parallel_size: int | None = None,
+        kv_cache_bits: int | None = None,
+        add_bos_token: bool | None = None,
+        add_eos_token: bool | None = None,
+        disable_special_token_parsing: bool = False,
+        warmup: bool = False,
+        warmup_prompt_len: int | None = None,
+        max_queued_requests: int | None = None,
     ) -> None:
         """
         Load a model.
@@ -177,12 +225,15 @@ class Runner:
         - `max_seqs` specifies how many sequences may be running at any time.
         - `no_kv_cache` disables the KV cache.
         - `prefix_cache_n` sets the number of sequences to hold in the device prefix cache, others will be evicted to CPU.
+            By default, it is the number which fit in the free device memory after loading the model (CUDA only), or 16.
         - `token_source` specifies where to load the HF token from.
             The token source follows the following format: "literal:<value>", "env:<value>", "path:<value>", "cache" to use a cached token or "none" to use no token.
         - `speculative_gamma` specifies the `gamma` parameter for specuative decoding, the ratio of draft tokens to generate before calling
             the target model. If `which_draft` is not specified, this is ignored.
         - `which_draft` specifies which draft model to load. Setting this parameter will cause a speculative decoding model to be loaded,
             with `which` as the target (higher quality) model and `which_draft` as the draft (lower quality) model.
+        - `draft_device_ordinal` loads the draft model on the device with this ordinal, such as a second GPU, instead of
+            the device of the target model. The device layers of the target model do not apply to it.
         - `chat_template` specifies an optional JINJA chat template.
             The JINJA template should have `messages`, `add_generation_prompt`, `bos_token`, `eos_token`, and `unk_token` as inputs.
             It is used if the automatic deserialization fails. If this ends with `.json` (ie., it is a file) then that template is loaded.
@@ -190,6 +241,41 @@ class Runner:
             If it is a list of strings, each element follows the format ORD:NUM where ORD is the device ordinal and NUM is
             the corresponding number of layers.
         - `in_situ_quant` sets the optional in-situ quantization for models that are not quantized (not GGUF or GGML).
+        - `default_system_prompt` sets a system prompt added to every chat request, merged with the system message of the
+            request if it has one.
+        - `cpu` runs the model on the CPU even if a GPU is available. The CPU kernels are deterministic, so with a seed the
+            outputs are reproducible bit for bit. This ignores `num_device_layers`.
+        - `prompt_lookup_max_ngram_size` enables speculative decoding without a draft model: up to `speculative_gamma` draft
+            tokens are copied from after the last earlier occurrence of the trailing n-gram (of at most this many tokens)
+            of the sequence. This suits tasks whose output copies from the input, such as summarization or code editing.
+            It cannot be combined with `which_draft`.
+        - `medusa_model_id` enables speculative decoding with Medusa heads trained for the `which` model: the Hugging Face
+            repository or local directory holding their `config.json` and `medusa_lm_head.safetensors`. Each head proposes
+            one draft token, so `speculative_gamma` must not exceed the number of heads. It cannot be combined with
+            `which_draft` or `prompt_lookup_max_ngram_size`.
+        - `speculative_tree_width` proposes up to this many candidates at each draft position with prompt lookup or
+            Medusa heads, as a tree of draft tokens which the target model verifies in one step with tree attention. The
+            longest path the target model agrees with is accepted. Falls back to a single chain of draft tokens if the
+            model does not support tree attention.
+        - `attention_impl` sets the attention implementation of models loaded with flash attention: `flash` for the fused
+            kernel or `eager` for standard softmax attention. Defaults to `flash` when built with the `flash-attn` feature.
+            It applies to every model of the process.
+        - `tensor_parallel_size` shards the attention and MLP projections of the model across this many GPUs, of ordinals
+            0 to N - 1, with tensor parallelism. The rest of the model is on GPU 0. Only supported by plain Llama models,
+            without `in_situ_quant`. It cannot be combined with `num_device_layers`.
+        - `kv_cache_bits` stores the KV caches of the sequences and the prefix cache quantized to this many bits, 8 or 4,
+            which roughly halves (or quarters) their memory at some cost in accuracy and speed.
+        - `add_bos_token` adds the BOS token to the start of prompts which do not already start with it. Defaults to
+            `add_bos_token` of the tokenizer config of the model.
+        - `add_eos_token` adds the EOS token to the end of prompts. Defaults to `add_eos_token` of the tokenizer config.
+        - `disable_special_token_parsing` does not parse special tokens in the text of requests, so that users cannot
+            inject control tokens. They are tokenized as plain text in completions, and chat requests whose messages
+            contain one are rejected.
+        - `warmup` runs a batch of `max_seqs` short dummy requests through a prefill and a decode step before serving, so
+            that the first requests do not pay for compiling kernels and allocating caches.
+        - `warmup_prompt_len` sets the number of prompt tokens of each warmup request. Defaults to 32.
+        - `max_queued_requests` rejects requests with an error instead of queueing them while this many sequences are
+            waiting to be scheduled. A request with `n` choices queues `n` sequences. By default, requests are always queued.
         """
         ...
 
@@ -211,9 +297,35 @@ class Runner:
         Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML then nothing will happen.
         """
 
+    def set_attention_impl(self, attention_impl: str) -> None:
+        """
+        Set the attention implementation of models loaded with flash attention, `flash` or `eager`. This applies from the
+        next step, to every model of the process.
+        """
+
     def activate_adapters(self, adapter_names: list[str]) -> None:
         """
-        Send a request to make the specified adapters the active adapters for the model.
+        Send a request to make the specified adapters the active adapters for the model. Changing the active adapters
+        clears the prefix cache.
+        """
+
+    def add_adapter(self, name: str, path: str) -> None:
+        """
+        Send a request to load a LoRA adapter from the local directory `path`, which holds its `adapter_config.json`
+        and `adapter_model.safetensors`. The adapter can then be activated by `name`. The model must have been loaded
+        with `preload_adapters` in its ordering file.
+        """
+
+    def merge_adapter(self, name: str) -> None:
+        """
+        Send a request to merge the adapter `name` into the base weights and activate it, so that inference runs without
+        the LoRA computation. Quantized weights are dequantized, merged and quantized again. Other adapters cannot be
+        activated until it is unmerged.
+        """
+
+    def unmerge_adapter(self) -> None:
+        """
+        Send a request to restore the base weights from before an adapter was merged.
         """
 
 @dataclass
@@ -224,14 +336,27 @@ class Usage:
     avg_tok_per_sec: float
     avg_prompt_tok_per_sec: float
     avg_compl_tok_per_sec: float
+    time_to_first_token_sec: float
     total_time_sec: float
     total_prompt_time_sec: float
     total_completion_time_sec: float
 
+@dataclass
+class CalledFunction:
+    name: str
+    arguments: str
+
+@dataclass
+class ToolCall:
+    id: str
+    tp: str
+    function: CalledFunction
+
 @dataclass
 class ResponseMessage:
     content: str
     role: str
+    tool_calls: list[ToolCall]
 
 @dataclass
 class TopLogprob:
@@ -287,13 +412,14 @@ class ChatCompletionChunkResponse:
     model: str
     system_fingerprint: str
     object: str
+    usage: Usage | None
 
 @dataclass
 class CompletionChoice:
     finish_reason: str
     index: int
     text: str
-    # NOTE(EricLBuehler): `logprobs` in undocumented
+    logprobs: Logprobs | None
 
 @dataclass
 class CompletionResponse:
@@ -12,18 +12,21 @@ use std::{
     io::Read,
     str::FromStr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use stream::ChatCompletionStreamer;
 use tokio::sync::mpsc::channel;
 
 use candle_core::Device;
 use mistralrs_core::{
-    initialize_logging, ChatCompletionResponse, CompletionResponse, Constraint,
-    DeviceLayerMapMetadata, DeviceMapMetadata, GGMLLoaderBuilder, GGMLSpecificConfig,
-    GGUFLoaderBuilder, GGUFSpecificConfig, Loader, MistralRs, MistralRsBuilder, ModelDType,
-    NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, Request as _Request, RequestMessage,
-    Response, SamplingParams, SchedulerMethod, SpeculativeConfig, SpeculativeLoader, StopTokens,
-    TokenSource, VisionLoaderBuilder, VisionSpecificConfig,
+    initialize_logging, set_attention_implementation, AttentionImplementation,
+    ChatCompletionResponse, CompletionResponse, Constraint, DeviceLayerMapMetadata,
+    DeviceMapMetadata, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder,
+    GGUFSpecificConfig, Loader, MedusaLoader, MistralRs, MistralRsBuilder, ModelDType,
+    NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, PromptLookupConfig,
+    PromptLookupLoader, Request as _Request, RequestMessage, Response, SamplingParams,
+    SchedulerMethod, SpeculativeConfig, SpeculativeLoader, StopTokens, TokenSource, Tool,
+    VisionLoaderBuilder, VisionSpecificConfig,
 };
 use pyo3::{
     exceptions::{PyTypeError, PyValueError},
@@ -91,14 +94,12 @@ fn parse_which(
     which: Which,
     no_kv_cache: bool,
     chat_template: Option<String>,
+    cpu: bool,
 ) -> PyResult<Box<dyn Loader>> {
     const REPEAT_LAST_N_DEFAULT: usize = 64;
     const GQA_DEFAULT: usize = 1;
 
-    #[cfg(not(feature = "flash-attn"))]
-    let use_flash_attn = false;
-    #[cfg(feature = "flash-attn")]
-    let use_flash_attn = true;
+    let use_flash_attn = cfg!(feature = "flash-attn") && !cpu;
 
     Ok(match which {
         Which::Plain {
@@ -335,6 +336,13 @@ fn parse_which(
     })
 }
 
+fn apply_attention_impl(attention_impl: &str) -> PyResult<()> {
+    let attention_impl = attention_impl
+        .parse::<AttentionImplementation>()
+        .map_err(PyValueError::new_err)?;
+    set_attention_implementation(attention_impl).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
 #[pymethods]
 impl Runner {
     // TODO(EricLBuehler): on version 0.2.0 remove the Either for device layers.
@@ -343,25 +351,55 @@ impl Runner {
         which,
         max_seqs = 16,
         no_kv_cache = false,
-        prefix_cache_n = 16,
+        prefix_cache_n = None,
         token_source = "cache",
         speculative_gamma = 32,
         which_draft = None,
+        draft_device_ordinal = None,
         chat_template = None,
         num_device_layers = None,
-        in_situ_quant = None
+        in_situ_quant = None,
+        default_system_prompt = None,
+        cpu = false,
+        prompt_lookup_max_ngram_size = None,
+        medusa_model_id = None,
+        speculative_tree_width = None,
+        attention_impl = None,
+        tensor_parallel_size = None,
+        kv_cache_bits = None,
+        add_bos_token = None,
+        add_eos_token = None,
+        disable_special_token_parsing = false,
+        warmup = false,
+        warmup_prompt_len = None,
+        max_queued_requests = None
     ))]
     fn new(
         which: Which,
         max_seqs: usize,
         no_kv_cache: bool,
-        prefix_cache_n: usize,
+        prefix_cache_n: Option<usize>,
         token_source: &str,
         speculative_gamma: usize,
         which_draft: Option<Which>,
+        draft_device_ordinal: Option<usize>,
         chat_template: Option<String>,
         num_device_layers: Option<Either<usize, Vec<String>>>,
         in_situ_quant: Option<String>,
+        default_system_prompt: Option<String>,
+        cpu: bool,
+        prompt_lookup_max_ngram_size: Option<usize>,
+        medusa_model_id: Option<String>,
+        speculative_tree_width: Option<usize>,
+        attention_impl: Option<String>,
+        tensor_parallel_size: Option<usize>,
+        kv_cache_bits: Option<u8>,
+        add_bos_token: Option<bool>,
+        add_eos_token: Option<bool>,
+        disable_special_token_parsing: bool,
+        warmup: bool,
+        warmup_prompt_len: Option<usize>,
+        max_queued_requests: Option<usize>,
     ) -> PyResult<Self> {
         let tgt_non_granular_index = match which {
             Which::Plain { .. }
@@ -390,28 +428,78 @@ impl Runner {
             max_seqs
         };
 
-        let loader = parse_which(which, no_kv_cache, chat_template.clone())?;
-        let loader = if let Some(draft_which) = which_draft {
-            let draft = parse_which(draft_which, no_kv_cache, chat_template)?;
+        if let Some(attention_impl) = attention_impl {
+            apply_attention_impl(&attention_impl)?;
+        }
+        if kv_cache_bits.is_some_and(|bits| !matches!(bits, 4 | 8)) {
+            return Err(PyValueError::new_err("`kv_cache_bits` must be 8 or 4."));
+        }
+
+        let loader = parse_which(which, no_kv_cache, chat_template.clone(), cpu)?;
+        let speculative_config = SpeculativeConfig {
+            gamma: speculative_gamma,
+            adaptive_gamma: None,
+            tree_width: speculative_tree_width,
+        };
+        let n_draft_sources = [
+            which_draft.is_some(),
+            prompt_lookup_max_ngram_size.is_some(),
+            medusa_model_id.is_some(),
+        ]
+        .into_iter()
+        .filter(|is_set| *is_set)
+        .count();
+        if n_draft_sources > 1 {
+            return Err(PyValueError::new_err(
+                "Only one of `which_draft`, `prompt_lookup_max_ngram_size` and `medusa_model_id` may be set.",
+            ));
+        }
+        if draft_device_ordinal.is_some() && which_draft.is_none() {
+            return Err(PyValueError::new_err(
+                "`draft_device_ordinal` may only be set with `which_draft`.",
+            ));
+        }
+        let loader: Box<dyn Loader> = if let Some(draft_which) = which_draft {
+            let draft = parse_which(draft_which, no_kv_cache, chat_template, cpu)?;
             Box::new(SpeculativeLoader {
                 target: loader,
                 draft,
-                config: SpeculativeConfig {
-                    gamma: speculative_gamma,
-                },
+                config: speculative_config,
+                draft_device_ordinal,
+            })
+        } else if let Some(max_ngram_size) = prompt_lookup_max_ngram_size {
+            Box::new(PromptLookupLoader {
+                target: loader,
+                config: speculative_config,
+                prompt_lookup: PromptLookupConfig { max_ngram_size },
+            })
+        } else if let Some(medusa_model_id) = medusa_model_id {
+            Box::new(MedusaLoader {
+                target: loader,
+                config: speculative_config,
+                medusa_model_id,
             })
         } else {
             loader
         };
 
-        let device = get_device().map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let device = if cpu {
+            Device::Cpu
+        } else {
+            get_device().map_err(|e| PyValueError::new_err(e.to_string()))?
+        };
         let isq = if let Some(isq) = in_situ_quant {
             Some(parse_isq(&isq).map_err(|e| PyValueError::new_err(e.to_string()))?)
         } else {
             None
         };
 
-        let mapper = match num_device_layers {
+        if num_device_layers.is_some() && tensor_parallel_size.is_some() {
+            return Err(PyValueError::new_err(
+                "Only one of `num_device_layers` and `tensor_parallel_size` may be set.",
+            ));
+        }
+        let mapper = match num_device_layers.filter(|_| !cpu) {
             Some(Either::Right(device_layers)) => {
                 if device_layers.len() == 1 && device_layers[0].parse::<usize>().is_ok() {
                     let layers = device_layers[0].parse::<usize>().unwrap();
@@ -451,7 +539,10 @@ impl Runner {
                     layers: n_device_layers,
                 }])
             }
-            None => DeviceMapMetadata::dummy(),
+            None => match tensor_parallel_size.filter(|_| !cpu) {
+                Some(size) => DeviceMapMetadata::from_tensor_parallel_size(size),
+                None => DeviceMapMetadata::dummy(),
+            },
         };
 
         let pipeline = loader
@@ -476,8 +567,17 @@ impl Runner {
             ),
         )
         .with_no_kv_cache(no_kv_cache)
-        .with_prefix_cache_n(prefix_cache_n)
-        .build();
+        .with_opt_prefix_cache_n(prefix_cache_n)
+        .with_opt_default_system_prompt(default_system_prompt)
+        .with_opt_kv_cache_bits(kv_cache_bits)
+        .with_opt_add_bos_token(add_bos_token)
+        .with_opt_add_eos_token(add_eos_token)
+        .with_disable_special_token_parsing(disable_special_token_parsing)
+        .with_warmup(warmup)
+        .with_opt_warmup_prompt_len(warmup_prompt_len)
+        .with_opt_max_queued_requests(max_queued_requests)
+        .build()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
 
         Ok(Self { runner: mistralrs })
     }
@@ -508,13 +608,37 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("gbnf".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::Gbnf(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::JsonSchema(
+                    serde_json::from_str(request.grammar.as_ref().unwrap())
+                        .map_err(|e| PyValueError::new_err(e.to_string()))?,
+                )
             } else if request.grammar_type.is_some() {
                 return Err(PyValueError::new_err(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc`, `gbnf` or `json_schema`",
                 ));
             } else {
                 Constraint::None
             };
+            // The tools are a JSON list of tool definitions
+            let tools = request
+                .tools
+                .as_ref()
+                .map(|tools| serde_json::from_str::<Vec<Tool>>(tools))
+                .transpose()
+                .map_err(|e| PyValueError::new_err(e.to_string()))?;
             let model_request = _Request::Normal(NormalRequest {
                 id: {
                     let l = NEXT_REQUEST_ID.lock().unwrap();
@@ -701,13 +825,29 @@ impl Runner {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
+                    top_n_sigma: request.top_n_sigma,
+                    mirostat_tau: request.mirostat_tau,
+                    mirostat_eta: request.mirostat_eta,
                     top_n_logprobs: request.top_logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
                     max_len: request.max_tokens,
+                    max_time: request.max_time.map(|secs| {
+                        Duration::try_from_secs_f64(secs.max(0.)).unwrap_or(Duration::MAX)
+                    }),
                     stop_toks,
+                    stop_token_ids: request.stop_token_ids.clone(),
+                    eos_token_ids: request.eos_token_ids.clone(),
+                    min_tokens: request.min_tokens,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
+                    num_beams: request.num_beams,
+                    length_penalty: request.length_penalty,
+                    penalty_alpha: request.penalty_alpha,
+                    repetition_penalty: request.repetition_penalty,
+                    repetition_penalty_range: request.repetition_penalty_range,
+                    no_repeat_ngram_size: request.no_repeat_ngram_size,
+                    seed: request.seed,
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -715,6 +855,9 @@ impl Runner {
                 constraint,
                 suffix: None,
                 adapters: request.adapters.clone(),
+                lora_scale: request.lora_scale,
+                priority: request.priority,
+                tools,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -727,14 +870,17 @@ impl Runner {
                 let response = rx.blocking_recv().unwrap();
 
                 match response {
-                    Response::ValidationError(e) | Response::InternalError(e) => {
-                        Err(PyValueError::new_err(e.to_string()))
-                    }
+                    Response::ValidationError(e)
+                    | Response::InternalError(e)
+                    | Response::Busy(e) => Err(PyValueError::new_err(e.to_string())),
                     Response::Done(response) => Ok(Either::Left(response)),
                     Response::ModelError(msg, _) => Err(PyValueError::new_err(msg.to_string())),
                     Response::Chunk(_) => unreachable!(),
                     Response::CompletionDone(_) => unreachable!(),
                     Response::CompletionModelError(_, _) => unreachable!(),
+                    Response::Embeddings(_) => unreachable!(),
+                    Response::Tokenized(_) => unreachable!(),
+                    Response::Detokenized(_) => unreachable!(),
                 }
             }
         })
@@ -766,9 +912,26 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("gbnf".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::Gbnf(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyValueError::new_err(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::JsonSchema(
+                    serde_json::from_str(request.grammar.as_ref().unwrap())
+                        .map_err(|e| PyValueError::new_err(e.to_string()))?,
+                )
             } else if request.grammar_type.is_some() {
                 return Err(PyValueError::new_err(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc`, `gbnf` or `json_schema`",
                 ));
             } else {
                 Constraint::None
@@ -784,19 +947,35 @@ impl Runner {
                 messages: RequestMessage::Completion {
                     text: request.prompt.clone(),
                     echo_prompt: request.echo_prompt,
-                    best_of: request.best_of,
+                    best_of: request.best_of.unwrap_or(request.n_choices),
                 },
                 sampling_params: SamplingParams {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
+                    top_n_sigma: request.top_n_sigma,
+                    mirostat_tau: request.mirostat_tau,
+                    mirostat_eta: request.mirostat_eta,
                     top_n_logprobs: 1,
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
                     max_len: request.max_tokens,
+                    max_time: request.max_time.map(|secs| {
+                        Duration::try_from_secs_f64(secs.max(0.)).unwrap_or(Duration::MAX)
+                    }),
                     stop_toks,
+                    stop_token_ids: request.stop_token_ids.clone(),
+                    eos_token_ids: request.eos_token_ids.clone(),
+                    min_tokens: request.min_tokens,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
+                    num_beams: request.num_beams,
+                    length_penalty: request.length_penalty,
+                    penalty_alpha: request.penalty_alpha,
+                    repetition_penalty: request.repetition_penalty,
+                    repetition_penalty_range: request.repetition_penalty_range,
+                    no_repeat_ngram_size: request.no_repeat_ngram_size,
+                    seed: request.seed,
                 },
                 response: tx,
                 return_logprobs: false,
@@ -804,6 +983,9 @@ impl Runner {
                 constraint,
                 suffix: request.suffix.clone(),
                 adapters: request.adapters.clone(),
+                lora_scale: request.lora_scale,
+                priority: request.priority,
+                tools: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -812,7 +994,7 @@ impl Runner {
             let response = rx.blocking_recv().unwrap();
 
             match response {
-                Response::ValidationError(e) | Response::InternalError(e) => {
+                Response::ValidationError(e) | Response::InternalError(e) | Response::Busy(e) => {
                     Err(PyValueError::new_err(e.to_string()))
                 }
                 Response::CompletionDone(response) => Ok(response),
@@ -822,6 +1004,9 @@ impl Runner {
                 Response::Chunk(_) => unreachable!(),
                 Response::Done(_) => unreachable!(),
                 Response::ModelError(_, _) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
+                Response::Tokenized(_) => unreachable!(),
+                Response::Detokenized(_) => unreachable!(),
             }
         })
     }
@@ -835,6 +1020,12 @@ impl Runner {
         Ok(())
     }
 
+    /// Set the attention implementation of models loaded with flash attention, `flash` or `eager`. This applies from the
+    /// next step, to every model of the process.
+    fn set_attention_impl(&self, attention_impl: String) -> PyResult<()> {
+        apply_attention_impl(&attention_impl)
+    }
+
     /// Send a request to make the specified adapters the active adapters for the model.
     fn activate_adapters(&self, adapter_names: Vec<String>) {
         let request = _Request::ActivateAdapters(adapter_names);
@@ -844,6 +1035,38 @@ impl Runner {
             .blocking_send(request)
             .unwrap();
     }
+
+    /// Send a request to load a LoRA adapter from a local directory, which can then be activated by its name.
+    fn add_adapter(&self, name: String, path: String) {
+        let request = _Request::AddAdapter {
+            name,
+            path: path.into(),
+        };
+        self.runner
+            .get_sender()
+            .unwrap()
+            .blocking_send(request)
+            .unwrap();
+    }
+
+    /// Send a request to merge the specified adapter into the base weights and activate it.
+    fn merge_adapter(&self, name: String) {
+        let request = _Request::MergeAdapter(name);
+        self.runner
+            .get_sender()
+            .unwrap()
+            .blocking_send(request)
+            .unwrap();
+    }
+
+    /// Send a request to restore the base weights from before an adapter was merged.
+    fn unmerge_adapter(&self) {
+        self.runner
+            .get_sender()
+            .unwrap()
+            .blocking_send(_Request::UnmergeAdapter)
+            .unwrap();
+    }
 }
 
 #[pyclass]
@@ -852,7 +1075,7 @@ impl Runner {
 struct CompletionRequest {
     _model: String,
     prompt: String,
-    best_of: usize,
+    best_of: Option<usize>,
     echo_prompt: bool,
     presence_penalty: Option<f32>,
     frequency_penalty: Option<f32>,
@@ -867,6 +1090,22 @@ struct CompletionRequest {
     grammar: Option<String>,
     grammar_type: Option<String>,
     adapters: Option<Vec<String>>,
+    top_n_sigma: Option<f64>,
+    mirostat_tau: Option<f32>,
+    mirostat_eta: Option<f32>,
+    stop_token_ids: Option<Vec<u32>>,
+    eos_token_ids: Option<Vec<u32>>,
+    min_tokens: Option<usize>,
+    max_time: Option<f64>,
+    num_beams: Option<usize>,
+    length_penalty: Option<f32>,
+    penalty_alpha: Option<f32>,
+    repetition_penalty: Option<f32>,
+    repetition_penalty_range: Option<usize>,
+    no_repeat_ngram_size: Option<usize>,
+    seed: Option<u64>,
+    lora_scale: Option<f32>,
+    priority: usize,
 }
 
 #[pymethods]
@@ -875,7 +1114,7 @@ impl CompletionRequest {
     #[pyo3(signature = (
         prompt,
         model,
-        best_of = 1,
+        best_of = None,
         echo_prompt = false,
         presence_penalty=None,
         frequency_penalty=None,
@@ -889,12 +1128,28 @@ impl CompletionRequest {
         top_k=None,
         grammar = None,
         grammar_type = None,
-        adapters = None
+        adapters = None,
+        top_n_sigma = None,
+        mirostat_tau = None,
+        mirostat_eta = None,
+        stop_token_ids = None,
+        eos_token_ids = None,
+        min_tokens = None,
+        max_time = None,
+        num_beams = None,
+        length_penalty = None,
+        penalty_alpha = None,
+        repetition_penalty = None,
+        repetition_penalty_range = None,
+        no_repeat_ngram_size = None,
+        seed = None,
+        lora_scale = None,
+        priority = 0
     ))]
     fn new(
         prompt: String,
         model: String,
-        best_of: usize,
+        best_of: Option<usize>,
         echo_prompt: bool,
         presence_penalty: Option<f32>,
         frequency_penalty: Option<f32>,
@@ -909,6 +1164,22 @@ impl CompletionRequest {
         grammar: Option<String>,
         grammar_type: Option<String>,
         adapters: Option<Vec<String>>,
+        top_n_sigma: Option<f64>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        stop_token_ids: Option<Vec<u32>>,
+        eos_token_ids: Option<Vec<u32>>,
+        min_tokens: Option<usize>,
+        max_time: Option<f64>,
+        num_beams: Option<usize>,
+        length_penalty: Option<f32>,
+        penalty_alpha: Option<f32>,
+        repetition_penalty: Option<f32>,
+        repetition_penalty_range: Option<usize>,
+        no_repeat_ngram_size: Option<usize>,
+        seed: Option<u64>,
+        lora_scale: Option<f32>,
+        priority: usize,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -928,6 +1199,22 @@ impl CompletionRequest {
             grammar,
             grammar_type,
             adapters,
+            top_n_sigma,
+            mirostat_tau,
+            mirostat_eta,
+            stop_token_ids,
+            eos_token_ids,
+            min_tokens,
+            max_time,
+            num_beams,
+            length_penalty,
+            penalty_alpha,
+            repetition_penalty,
+            repetition_penalty_range,
+            no_repeat_ngram_size,
+            seed,
+            lora_scale,
+            priority,
         })
     }
 }
@@ -962,6 +1249,23 @@ struct ChatCompletionRequest {
     grammar: Option<String>,
     grammar_type: Option<String>,
     adapters: Option<Vec<String>>,
+    top_n_sigma: Option<f64>,
+    mirostat_tau: Option<f32>,
+    mirostat_eta: Option<f32>,
+    stop_token_ids: Option<Vec<u32>>,
+    eos_token_ids: Option<Vec<u32>>,
+    min_tokens: Option<usize>,
+    max_time: Option<f64>,
+    num_beams: Option<usize>,
+    length_penalty: Option<f32>,
+    penalty_alpha: Option<f32>,
+    repetition_penalty: Option<f32>,
+    repetition_penalty_range: Option<usize>,
+    no_repeat_ngram_size: Option<usize>,
+    seed: Option<u64>,
+    lora_scale: Option<f32>,
+    priority: usize,
+    tools: Option<String>,
 }
 
 #[pymethods]
@@ -984,7 +1288,24 @@ impl ChatCompletionRequest {
         stream=false,
         grammar = None,
         grammar_type = None,
-        adapters = None
+        adapters = None,
+        top_n_sigma = None,
+        mirostat_tau = None,
+        mirostat_eta = None,
+        stop_token_ids = None,
+        eos_token_ids = None,
+        min_tokens = None,
+        max_time = None,
+        num_beams = None,
+        length_penalty = None,
+        penalty_alpha = None,
+        repetition_penalty = None,
+        repetition_penalty_range = None,
+        no_repeat_ngram_size = None,
+        seed = None,
+        lora_scale = None,
+        priority = 0,
+        tools = None
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -1004,6 +1325,23 @@ impl ChatCompletionRequest {
         grammar: Option<String>,
         grammar_type: Option<String>,
         adapters: Option<Vec<String>>,
+        top_n_sigma: Option<f64>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        stop_token_ids: Option<Vec<u32>>,
+        eos_token_ids: Option<Vec<u32>>,
+        min_tokens: Option<usize>,
+        max_time: Option<f64>,
+        num_beams: Option<usize>,
+        length_penalty: Option<f32>,
+        penalty_alpha: Option<f32>,
+        repetition_penalty: Option<f32>,
+        repetition_penalty_range: Option<usize>,
+        no_repeat_ngram_size: Option<usize>,
+        seed: Option<u64>,
+        lora_scale: Option<f32>,
+        priority: usize,
+        tools: Option<String>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -1071,6 +1409,23 @@ impl ChatCompletionRequest {
             grammar,
             grammar_type,
             adapters,
+            top_n_sigma,
+            mirostat_tau,
+            mirostat_eta,
+            stop_token_ids,
+            eos_token_ids,
+            min_tokens,
+            max_time,
+            num_beams,
+            length_penalty,
+            penalty_alpha,
+            repetition_penalty,
+            repetition_penalty_range,
+            no_repeat_ngram_size,
+            seed,
+            lora_scale,
+            priority,
+            tools,
         })
     }
 }
@@ -1086,6 +1441,8 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Architecture>()?;
     m.add_class::<VisionArchitecture>()?;
 
+    m.add_class::<mistralrs_core::CalledFunction>()?;
+    m.add_class::<mistralrs_core::ToolCall>()?;
     m.add_class::<mistralrs_core::ResponseMessage>()?;
     m.add_class::<mistralrs_core::Delta>()?;
     m.add_class::<mistralrs_core::ResponseLogprob>()?;
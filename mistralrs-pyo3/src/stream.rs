@@ -29,8 +29,10 @@ impl ChatCompletionStreamer {
                 Response::ModelError(msg, _) => Some(Err(PyValueError::new_err(msg.to_string()))),
                 Response::ValidationError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
                 Response::InternalError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
+                Response::Busy(e) => Some(Err(PyValueError::new_err(e.to_string()))),
                 Response::Chunk(response) => {
-                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                    // Only the last chunk, when every choice is done, has the usage
+                    if response.usage.is_some() {
                         this.is_done = true;
                     }
                     Some(Ok(response))
@@ -38,6 +40,9 @@ impl ChatCompletionStreamer {
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
+                Response::Tokenized(_) => unreachable!(),
+                Response::Detokenized(_) => unreachable!(),
             },
             None => Some(Err(PyValueError::new_err(
                 "Received none in ChatCompletionStreamer".to_string(),
@@ -0,0 +1,8 @@
+fn main() {
+    // Compiling the protobuf schema of the gRPC interface needs `protoc`
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/mistralrs.proto"], &["proto"])
+        .expect("Failed to compile the protobuf schema.");
+}
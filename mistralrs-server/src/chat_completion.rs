@@ -1,6 +1,5 @@
 use base64::{engine::general_purpose, Engine};
 use std::{
-    collections::HashMap,
     env,
     error::Error,
     fs::{self, File},
@@ -13,7 +12,9 @@ use std::{
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::openai::{ChatCompletionRequest, Grammar, MessageInnerContent, StopTokens};
+use crate::openai::{
+    ChatCompletionRequest, Grammar, Message, ResponseFormat, StopTokens, Tool, ToolType,
+};
 use anyhow::Result;
 use axum::{
     extract::{Json, State},
@@ -24,10 +25,12 @@ use axum::{
     },
 };
 use either::Either;
+use image::DynamicImage;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, MistralRs, NormalRequest, Request, RequestMessage,
-    Response, SamplingParams, StopTokens as InternalStopTokens,
+    ChatCompletionResponse, Constraint, Function as InternalFunction, MessageContent, MistralRs,
+    NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens, Tool as InternalTool, ToolType as InternalToolType,
 };
 use serde::Serialize;
 
@@ -42,6 +45,8 @@ impl std::error::Error for ModelErrorMessage {}
 pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
+    /// Keep the usage of the last chunk, as requested by `stream_options`.
+    include_usage: bool,
     state: Arc<MistralRs>,
 }
 
@@ -61,23 +66,30 @@ impl futures::Stream for Streamer {
                     );
                     Poll::Ready(Some(Ok(Event::default().data(msg))))
                 }
-                Response::ValidationError(e) => {
+                Response::ValidationError(e) | Response::Busy(e) => {
                     Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
                 }
                 Response::InternalError(e) => {
                     MistralRs::maybe_log_error(self.state.clone(), &*e);
                     Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
                 }
-                Response::Chunk(response) => {
-                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                Response::Chunk(mut response) => {
+                    // Only the last chunk, when every choice is done, has the usage
+                    if response.usage.is_some() {
                         self.is_done = true;
                     }
+                    if !self.include_usage {
+                        response.usage = None;
+                    }
                     MistralRs::maybe_log_response(self.state.clone(), &response);
                     Poll::Ready(Some(Event::default().json_data(response)))
                 }
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
+                Response::Tokenized(_) => unreachable!(),
+                Response::Detokenized(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -90,6 +102,7 @@ pub enum ChatCompletionResponder {
     ModelError(String, ChatCompletionResponse),
     InternalError(Box<dyn Error>),
     ValidationError(Box<dyn Error>),
+    Busy(Box<dyn Error>),
 }
 
 trait ErrorToResponse: Serialize {
@@ -140,6 +153,9 @@ impl IntoResponse for ChatCompletionResponder {
             ChatCompletionResponder::ValidationError(e) => {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
             }
+            ChatCompletionResponder::Busy(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::SERVICE_UNAVAILABLE)
+            }
             ChatCompletionResponder::ModelError(msg, response) => {
                 JsonModelError::new(msg, response)
                     .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
@@ -148,7 +164,143 @@ impl IntoResponse for ChatCompletionResponder {
     }
 }
 
-async fn parse_request(
+/// Load the image of an `image_url` content part, which may be:
+/// - a data URI, `data:image/<format>;base64,<data>`
+/// - an `http(s)://` URL
+/// - a path to a local file
+/// - base64 encoded image data
+async fn load_image(url: &str) -> Result<DynamicImage> {
+    let bytes = if let Some(data_uri) = url.strip_prefix("data:") {
+        let Some((media_type, data)) = data_uri.split_once(',') else {
+            anyhow::bail!("Malformed data URI, expected `data:image/<format>;base64,<data>`.");
+        };
+        let Some(mime) = media_type.strip_suffix(";base64") else {
+            anyhow::bail!("Only base64 encoded data URIs are supported for images.");
+        };
+        if !mime.starts_with("image/") {
+            anyhow::bail!("Unsupported media type `{mime}` for an image.");
+        }
+        general_purpose::STANDARD
+            .decode(data)
+            .map_err(|e| anyhow::anyhow!("Failed to decode the base64 data of an image: {e}"))?
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        reqwest::get(url)
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec()
+    } else if let Ok(mut f) = File::open(url) {
+        // Read from local file
+        let metadata = fs::metadata(url)?;
+        let mut buffer = vec![0; metadata.len() as usize];
+        f.read_exact(&mut buffer)?;
+        buffer
+    } else {
+        // Decode with base64
+        general_purpose::STANDARD.decode(url).map_err(|_| {
+            anyhow::anyhow!(
+                "Expected the image to be a data URI, a URL, a path, or base64 encoded data."
+            )
+        })?
+    };
+    image::load_from_memory(&bytes).map_err(|e| anyhow::anyhow!("Unsupported image: {e}"))
+}
+
+/// Convert the messages of a request to the messages passed to the chat template. Image parts are replaced by
+/// `{"type": "image"}` parts, and the URLs of their images are returned in order.
+pub(crate) fn parse_messages(
+    req_messages: Vec<Message>,
+) -> Result<(Vec<IndexMap<String, MessageContent>>, Vec<String>)> {
+    let mut messages = Vec::new();
+    let mut image_urls = Vec::new();
+    for message in req_messages {
+        match message.content.deref() {
+            Either::Left(content) => {
+                let mut message_map: IndexMap<
+                    String,
+                    Either<String, Vec<IndexMap<String, String>>>,
+                > = IndexMap::new();
+                message_map.insert("role".to_string(), Either::Left(message.role));
+                message_map.insert("content".to_string(), Either::Left(content.to_string()));
+                messages.push(message_map);
+            }
+            Either::Right(parts) => {
+                // Text and image parts are kept in order, so the processor of the model can place the
+                // image tokens between the text
+                let mut content_map = Vec::new();
+                for part in parts {
+                    let Some(Either::Left(tp)) = part.get("type").map(Deref::deref) else {
+                        anyhow::bail!("Expected a string `type` in each content part.");
+                    };
+                    let mut content_part = IndexMap::new();
+                    match tp.as_str() {
+                        "text" => {
+                            let Some(Either::Left(text)) =
+                                part.get("text").map(Deref::deref)
+                            else {
+                                anyhow::bail!(
+                                    "Expected a string `text` in a `text` content part."
+                                );
+                            };
+                            content_part.insert("type".to_string(), "text".to_string());
+                            content_part.insert("text".to_string(), text.clone());
+                        }
+                        "image_url" => {
+                            let Some(url) = part
+                                .get("image_url")
+                                .and_then(|image_url| image_url.as_ref().right())
+                                .and_then(|image_url| image_url.get("url"))
+                            else {
+                                anyhow::bail!("Expected `image_url` of format {{`url`: ...}} in an `image_url` content part.");
+                            };
+                            if message.role != "user" {
+                                anyhow::bail!(
+                                    "Role for an image message must be `user`, but it is {}",
+                                    message.role
+                                );
+                            }
+                            content_part.insert("type".to_string(), "image".to_string());
+                            image_urls.push(url.clone());
+                        }
+                        other => anyhow::bail!(
+                            "Unsupported content part type `{other}`, expected `text` or `image_url`."
+                        ),
+                    }
+                    content_map.push(content_part);
+                }
+
+                let mut message_map: IndexMap<
+                    String,
+                    Either<String, Vec<IndexMap<String, String>>>,
+                > = IndexMap::new();
+                message_map.insert("role".to_string(), Either::Left(message.role));
+                message_map.insert("content".to_string(), Either::Right(content_map));
+                messages.push(message_map);
+            }
+        }
+    }
+    Ok((messages, image_urls))
+}
+
+/// Convert the tools of a request to the tools passed to the chat template.
+pub(crate) fn parse_tools(tools: Vec<Tool>) -> Vec<InternalTool> {
+    tools
+        .into_iter()
+        .map(|tool| InternalTool {
+            tp: match tool.tp {
+                ToolType::Function => InternalToolType::Function,
+            },
+            function: InternalFunction {
+                name: tool.function.name,
+                description: tool.function.description,
+                parameters: tool.function.parameters,
+            },
+        })
+        .collect()
+}
+
+pub(crate) async fn parse_request(
     oairequest: ChatCompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
@@ -163,118 +315,11 @@ async fn parse_request(
     };
     let messages = match oairequest.messages {
         Either::Left(req_messages) => {
-            let mut messages = Vec::new();
-            let mut image_urls = Vec::new();
-            for message in req_messages {
-                match message.content.deref() {
-                    Either::Left(content) => {
-                        let mut message_map: IndexMap<
-                            String,
-                            Either<String, Vec<IndexMap<String, String>>>,
-                        > = IndexMap::new();
-                        message_map.insert("role".to_string(), Either::Left(message.role));
-                        message_map
-                            .insert("content".to_string(), Either::Left(content.to_string()));
-                        messages.push(message_map);
-                    }
-                    Either::Right(image_messages) => {
-                        if image_messages.len() != 2 {
-                            anyhow::bail!(
-                                "Expected 2 items for the content of a message with an image."
-                            );
-                        }
-                        if message.role != "user" {
-                            anyhow::bail!(
-                                "Role for an image message must be `user`, but it is {}",
-                                message.role
-                            );
-                        }
-
-                        let mut items = Vec::new();
-                        for image_message in image_messages {
-                            if image_message.len() != 2 {
-                                anyhow::bail!("Expected 2 items for the sub-content of a message with an image.");
-                            }
-                            if !image_message.contains_key("type") {
-                                anyhow::bail!("Expected `type` key in input message.");
-                            }
-                            if image_message["type"].is_right() {
-                                anyhow::bail!("Expected string value in `type`.");
-                            }
-                            items.push(image_message["type"].as_ref().unwrap_left().clone())
-                        }
-
-                        fn get_content_and_url(
-                            text_idx: usize,
-                            url_idx: usize,
-                            image_messages: &[HashMap<String, MessageInnerContent>],
-                        ) -> Result<(String, String)> {
-                            if image_messages[text_idx]["text"].is_right() {
-                                anyhow::bail!("Expected string value in `text`.");
-                            }
-                            let content = image_messages[text_idx]["text"]
-                                .as_ref()
-                                .unwrap_left()
-                                .clone();
-                            if image_messages[url_idx]["image_url"].is_left()
-                                || !image_messages[url_idx]["image_url"]
-                                    .as_ref()
-                                    .unwrap_right()
-                                    .contains_key("url")
-                            {
-                                anyhow::bail!("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `url`, `image_url`: {{`url`: ...}}}}")
-                            }
-                            let url = image_messages[url_idx]["image_url"].as_ref().unwrap_right()
-                                ["url"]
-                                .clone();
-                            Ok((content, url))
-                        }
-                        let mut message_map: IndexMap<
-                            String,
-                            Either<String, Vec<IndexMap<String, String>>>,
-                        > = IndexMap::new();
-                        message_map.insert("role".to_string(), Either::Left(message.role));
-                        let (content, url) = if items[0] == "text" {
-                            get_content_and_url(0, 1, image_messages)?
-                        } else {
-                            get_content_and_url(1, 0, image_messages)?
-                        };
-
-                        let mut content_map = Vec::new();
-                        let mut content_image_map = IndexMap::new();
-                        content_image_map.insert("type".to_string(), "image".to_string());
-                        content_map.push(content_image_map);
-                        let mut content_text_map = IndexMap::new();
-                        content_text_map.insert("type".to_string(), "text".to_string());
-                        content_text_map.insert("text".to_string(), content);
-                        content_map.push(content_text_map);
-
-                        message_map.insert("content".to_string(), Either::Right(content_map));
-                        messages.push(message_map);
-                        image_urls.push(url);
-                    }
-                }
-            }
+            let (messages, image_urls) = parse_messages(req_messages)?;
             if !image_urls.is_empty() {
                 let mut images = Vec::new();
                 for url in image_urls {
-                    let bytes = if url.contains("http") {
-                        // Read from http
-                        match reqwest::get(url.clone()).await {
-                            Ok(http_resp) => http_resp.bytes().await?.to_vec(),
-                            Err(e) => anyhow::bail!(e),
-                        }
-                    } else if let Ok(mut f) = File::open(&url) {
-                        // Read from local file
-                        let metadata = fs::metadata(&url)?;
-                        let mut buffer = vec![0; metadata.len() as usize];
-                        f.read_exact(&mut buffer)?;
-                        buffer
-                    } else {
-                        // Decode with base64
-                        general_purpose::STANDARD.decode(url)?
-                    };
-                    images.push(image::load_from_memory(&bytes)?);
+                    images.push(load_image(&url).await?);
                 }
                 RequestMessage::VisionChat { messages, images }
             } else {
@@ -292,6 +337,19 @@ async fn parse_request(
         }
     };
 
+    let constraint = match (oairequest.grammar, oairequest.response_format) {
+        (Some(_), Some(ResponseFormat::JsonSchema { .. })) => {
+            anyhow::bail!("A `grammar` cannot be combined with a `json_schema` response format.")
+        }
+        (_, Some(ResponseFormat::JsonSchema { schema })) => Constraint::JsonSchema(schema),
+        (Some(Grammar::Yacc(yacc)), _) => Constraint::Yacc(yacc),
+        (Some(Grammar::Regex(regex)), _) => Constraint::Regex(regex),
+        (Some(Grammar::Gbnf(gbnf)), _) => Constraint::Gbnf(gbnf),
+        (None, _) => Constraint::None,
+    };
+
+    let tools = oairequest.tools.map(parse_tools);
+
     let is_streaming = oairequest.stream.unwrap_or(false);
     Ok((
         Request::Normal(NormalRequest {
@@ -301,24 +359,39 @@ async fn parse_request(
                 temperature: oairequest.temperature,
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
+                top_n_sigma: oairequest.top_n_sigma,
+                mirostat_tau: oairequest.mirostat_tau,
+                mirostat_eta: oairequest.mirostat_eta,
                 top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
                 max_len: oairequest.max_tokens,
+                max_time: oairequest
+                    .max_time
+                    .map(|secs| Duration::try_from_secs_f64(secs.max(0.)).unwrap_or(Duration::MAX)),
                 stop_toks,
+                stop_token_ids: oairequest.stop_token_ids,
+                eos_token_ids: oairequest.eos_token_ids,
+                min_tokens: oairequest.min_tokens,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
+                num_beams: oairequest.num_beams,
+                length_penalty: oairequest.length_penalty,
+                penalty_alpha: oairequest.penalty_alpha,
+                repetition_penalty: oairequest.repetition_penalty,
+                repetition_penalty_range: oairequest.repetition_penalty_range,
+                no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
+                seed: oairequest.seed,
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
             is_streaming,
             suffix: None,
-            constraint: match oairequest.grammar {
-                Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-                None => Constraint::None,
-            },
+            constraint,
             adapters: oairequest.adapters,
+            lora_scale: oairequest.lora_scale,
+            priority: oairequest.priority.unwrap_or(0),
+            tools,
         }),
         is_streaming,
     ))
@@ -336,12 +409,16 @@ pub async fn chatcompletions(
     Json(oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
     let (tx, mut rx) = channel(10_000);
+    let include_usage = oairequest
+        .stream_options
+        .as_ref()
+        .is_some_and(|options| options.include_usage);
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
         Ok(x) => x,
         Err(e) => {
             let e = anyhow::Error::msg(e.to_string());
             MistralRs::maybe_log_error(state, &*e);
-            return ChatCompletionResponder::InternalError(e.into());
+            return ChatCompletionResponder::ValidationError(e.into());
         }
     };
     let sender = state.get_sender().unwrap();
@@ -356,6 +433,7 @@ pub async fn chatcompletions(
         let streamer = Streamer {
             rx,
             is_done: false,
+            include_usage,
             state,
         };
 
@@ -391,6 +469,7 @@ pub async fn chatcompletions(
                 ChatCompletionResponder::ModelError(msg, response)
             }
             Response::ValidationError(e) => ChatCompletionResponder::ValidationError(e),
+            Response::Busy(e) => ChatCompletionResponder::Busy(e),
             Response::Done(response) => {
                 MistralRs::maybe_log_response(state, &response);
                 ChatCompletionResponder::Json(response)
@@ -398,6 +477,9 @@ pub async fn chatcompletions(
             Response::Chunk(_) => unreachable!(),
             Response::CompletionDone(_) => unreachable!(),
             Response::CompletionModelError(_, _) => unreachable!(),
+            Response::Embeddings(_) => unreachable!(),
+            Response::Tokenized(_) => unreachable!(),
+            Response::Detokenized(_) => unreachable!(),
         }
     }
 }
@@ -1,4 +1,4 @@
-use std::{error::Error, sync::Arc};
+use std::{error::Error, sync::Arc, time::Duration};
 use tokio::sync::mpsc::{channel, Sender};
 
 use crate::openai::{CompletionRequest, Grammar, StopTokens};
@@ -27,6 +27,7 @@ pub enum CompletionResponder {
     ModelError(String, CompletionResponse),
     InternalError(Box<dyn Error>),
     ValidationError(Box<dyn Error>),
+    Busy(Box<dyn Error>),
 }
 
 trait ErrorToResponse: Serialize {
@@ -76,13 +77,16 @@ impl IntoResponse for CompletionResponder {
             CompletionResponder::ValidationError(e) => {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
             }
+            CompletionResponder::Busy(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::SERVICE_UNAVAILABLE)
+            }
             CompletionResponder::ModelError(msg, response) => JsonModelError::new(msg, response)
                 .to_response(http::StatusCode::INTERNAL_SERVER_ERROR),
         }
     }
 }
 
-fn parse_request(
+pub(crate) fn parse_request(
     oairequest: CompletionRequest,
     state: Arc<MistralRs>,
     tx: Sender<Response>,
@@ -96,10 +100,6 @@ fn parse_request(
         None => None,
     };
 
-    if oairequest.logprobs.is_some() {
-        warn!("Completion requests do not support logprobs.");
-    }
-
     if oairequest._stream.is_some_and(|x| x) {
         warn!("Completion requests do not support streaming.");
     }
@@ -109,30 +109,50 @@ fn parse_request(
         messages: RequestMessage::Completion {
             text: oairequest.prompt,
             echo_prompt: oairequest.echo_prompt,
-            best_of: oairequest.best_of,
+            best_of: oairequest.best_of.unwrap_or(oairequest.n_choices),
         },
         sampling_params: SamplingParams {
             temperature: oairequest.temperature,
             top_k: oairequest.top_k,
             top_p: oairequest.top_p,
-            top_n_logprobs: 1,
+            top_n_sigma: oairequest.top_n_sigma,
+            mirostat_tau: oairequest.mirostat_tau,
+            mirostat_eta: oairequest.mirostat_eta,
+            top_n_logprobs: oairequest.logprobs.unwrap_or(1),
             frequency_penalty: oairequest.frequency_penalty,
             presence_penalty: oairequest.presence_penalty,
             max_len: oairequest.max_tokens,
+            max_time: oairequest
+                .max_time
+                .map(|secs| Duration::try_from_secs_f64(secs.max(0.)).unwrap_or(Duration::MAX)),
             stop_toks,
+            stop_token_ids: oairequest.stop_token_ids,
+            eos_token_ids: oairequest.eos_token_ids,
+            min_tokens: oairequest.min_tokens,
             logits_bias: oairequest.logit_bias,
             n_choices: oairequest.n_choices,
+            num_beams: oairequest.num_beams,
+            length_penalty: oairequest.length_penalty,
+            penalty_alpha: oairequest.penalty_alpha,
+            repetition_penalty: oairequest.repetition_penalty,
+            repetition_penalty_range: oairequest.repetition_penalty_range,
+            no_repeat_ngram_size: oairequest.no_repeat_ngram_size,
+            seed: oairequest.seed,
         },
         response: tx,
-        return_logprobs: false,
+        return_logprobs: oairequest.logprobs.is_some(),
         is_streaming: false,
         suffix: oairequest.suffix,
         constraint: match oairequest.grammar {
             Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
             Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
+            Some(Grammar::Gbnf(gbnf)) => Constraint::Gbnf(gbnf),
             None => Constraint::None,
         },
         adapters: oairequest.adapters,
+        lora_scale: oairequest.lora_scale,
+        priority: oairequest.priority.unwrap_or(0),
+        tools: None,
     })
 }
 
@@ -148,12 +168,6 @@ pub async fn completions(
     Json(oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
     let (tx, mut rx) = channel(10_000);
-    if oairequest.logprobs.is_some() {
-        return CompletionResponder::ValidationError(
-            "Completion requests do not support logprobs.".into(),
-        );
-    }
-
     if oairequest._stream.is_some_and(|s| s) {
         return CompletionResponder::ValidationError(
             "Completion requests do not support streaming.".into(),
@@ -188,6 +202,7 @@ pub async fn completions(
             CompletionResponder::ModelError(msg, response)
         }
         Response::ValidationError(e) => CompletionResponder::ValidationError(e),
+        Response::Busy(e) => CompletionResponder::Busy(e),
         Response::CompletionDone(response) => {
             MistralRs::maybe_log_response(state, &response);
             CompletionResponder::Json(response)
@@ -195,5 +210,8 @@ pub async fn completions(
         Response::Chunk(_) => unreachable!(),
         Response::Done(_) => unreachable!(),
         Response::ModelError(_, _) => unreachable!(),
+        Response::Embeddings(_) => unreachable!(),
+        Response::Tokenized(_) => unreachable!(),
+        Response::Detokenized(_) => unreachable!(),
     }
 }
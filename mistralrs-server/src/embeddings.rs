@@ -0,0 +1,135 @@
+use std::{error::Error, sync::Arc};
+use tokio::sync::mpsc::{channel, Sender};
+
+use crate::openai::{EmbeddingInput, EmbeddingPooling, EmbeddingRequest};
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{
+    EmbeddingPooling as InternalEmbeddingPooling, EmbeddingRequest as InternalEmbeddingRequest,
+    EmbeddingResponse, MistralRs, Request, Response,
+};
+use serde::Serialize;
+
+pub enum EmbeddingResponder {
+    Json(EmbeddingResponse),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+impl IntoResponse for EmbeddingResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            EmbeddingResponder::Json(s) => Json(s).into_response(),
+            EmbeddingResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            EmbeddingResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+fn parse_request(
+    oairequest: EmbeddingRequest,
+    state: Arc<MistralRs>,
+    tx: Sender<Response>,
+) -> Request {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    Request::Embedding(InternalEmbeddingRequest {
+        id: state.next_request_id(),
+        inputs: match oairequest.input {
+            EmbeddingInput::Multi(inputs) => inputs,
+            EmbeddingInput::Single(input) => vec![input],
+        },
+        pooling: match oairequest.pooling {
+            Some(EmbeddingPooling::Mean) | None => InternalEmbeddingPooling::Mean,
+            Some(EmbeddingPooling::LastToken) => InternalEmbeddingPooling::LastToken,
+        },
+        normalize: oairequest.normalize.unwrap_or(true),
+        response: tx,
+    })
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/embeddings",
+    request_body = EmbeddingRequest,
+    responses((status = 200, description = "Embeddings"))
+)]
+pub async fn embeddings(
+    State(state): State<Arc<MistralRs>>,
+    Json(oairequest): Json<EmbeddingRequest>,
+) -> EmbeddingResponder {
+    let (tx, mut rx) = channel(10_000);
+    if oairequest
+        .encoding_format
+        .as_ref()
+        .is_some_and(|format| format != "float")
+    {
+        return EmbeddingResponder::ValidationError(
+            "Only the `float` encoding format is supported.".into(),
+        );
+    }
+
+    let request = parse_request(oairequest, state.clone(), tx);
+    let sender = state.get_sender().unwrap();
+
+    if let Err(e) = sender.send(request).await {
+        let e = anyhow::Error::msg(e.to_string());
+        MistralRs::maybe_log_error(state, &*e);
+        return EmbeddingResponder::InternalError(e.into());
+    }
+
+    let response = match rx.recv().await {
+        Some(response) => response,
+        None => {
+            let e = anyhow::Error::msg("No response received from the model.");
+            MistralRs::maybe_log_error(state, &*e);
+            return EmbeddingResponder::InternalError(e.into());
+        }
+    };
+
+    match response {
+        Response::InternalError(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            EmbeddingResponder::InternalError(e)
+        }
+        Response::ValidationError(e) => EmbeddingResponder::ValidationError(e),
+        Response::Embeddings(response) => {
+            MistralRs::maybe_log_response(state, &response);
+            EmbeddingResponder::Json(response)
+        }
+        Response::Busy(_) => unreachable!(),
+        Response::Chunk(_) => unreachable!(),
+        Response::Done(_) => unreachable!(),
+        Response::ModelError(_, _) => unreachable!(),
+        Response::CompletionDone(_) => unreachable!(),
+        Response::CompletionModelError(_, _) => unreachable!(),
+        Response::Tokenized(_) => unreachable!(),
+        Response::Detokenized(_) => unreachable!(),
+    }
+}
@@ -0,0 +1,518 @@
+use std::{collections::HashMap, future::Future, net::SocketAddr, pin::Pin, sync::Arc};
+
+use either::Either;
+use futures::{stream, Stream, StreamExt};
+use mistralrs_core::{
+    ChatCompletionChunkResponse, ChatCompletionResponse, Choice, ChunkChoice, CompletionChoice,
+    CompletionResponse, EngineHandle, Logprobs, MistralRs, Request, Response, ResponseLogprob,
+    ToolCall, TopLogprob, Usage,
+};
+use tokio::sync::mpsc::channel;
+use tonic::Status;
+
+use crate::{
+    chat_completion, completions,
+    openai::{
+        ChatCompletionRequest, CompletionRequest, Function, Grammar, Message, MessageContent,
+        MessageInnerContent, ResponseFormat, StopTokens, Tool, ToolType,
+    },
+};
+
+pub mod proto {
+    tonic::include_proto!("mistralrs.v1");
+}
+
+use proto::{
+    chat_completion_request, completion_request, content_part, message,
+    mistral_rs_server::{MistralRs as MistralRsService, MistralRsServer},
+};
+
+/// Serve the gRPC interface until `shutdown` completes, after which the running calls are finished.
+pub async fn serve(
+    state: Arc<MistralRs>,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(MistralRsServer::new(GrpcService { state }))
+        .serve_with_shutdown(addr, shutdown)
+        .await
+}
+
+struct GrpcService {
+    state: Arc<MistralRs>,
+}
+
+impl GrpcService {
+    /// Submit a request on a handle of its own. Dropping the handle, for example when the client disconnects,
+    /// cancels the request.
+    async fn submit(&self, request: Request) -> Result<EngineHandle, Status> {
+        let mut handle = EngineHandle::new(self.state.clone());
+        if let Err(e) = handle.add_request(request).await {
+            MistralRs::maybe_log_error(self.state.clone(), &e);
+            return Err(Status::internal(e.to_string()));
+        }
+        Ok(handle)
+    }
+
+    async fn submit_chat(
+        &self,
+        request: proto::ChatCompletionRequest,
+        stream: bool,
+    ) -> Result<EngineHandle, Status> {
+        // The handle replaces the response channel of the request
+        let (tx, _) = channel(1);
+        let request = chat_completion_request(request, stream)?;
+        let (request, _) = chat_completion::parse_request(request, self.state.clone(), tx)
+            .await
+            .map_err(|e| self.validation_error(e))?;
+        self.submit(request).await
+    }
+
+    fn validation_error(&self, e: anyhow::Error) -> Status {
+        MistralRs::maybe_log_error(self.state.clone(), &*e);
+        Status::invalid_argument(e.to_string())
+    }
+}
+
+/// The status of a response which is not the expected result, with the same meaning as the status codes of the
+/// HTTP server.
+fn error_status(state: &Arc<MistralRs>, response: Response) -> Status {
+    match response {
+        Response::ValidationError(e) => Status::invalid_argument(e.to_string()),
+        Response::Busy(e) => Status::unavailable(e.to_string()),
+        Response::InternalError(e) => {
+            MistralRs::maybe_log_error(state.clone(), &*e);
+            Status::internal(e.to_string())
+        }
+        Response::ModelError(msg, _) | Response::CompletionModelError(msg, _) => {
+            MistralRs::maybe_log_error(state.clone(), &*anyhow::Error::msg(msg.clone()));
+            Status::internal(msg)
+        }
+        _ => Status::internal("Unexpected response to the request."),
+    }
+}
+
+/// Wait for the response to a request which is not streamed.
+async fn single_response(handle: &mut EngineHandle) -> Response {
+    loop {
+        let outputs = handle.step().await;
+        if outputs.is_empty() {
+            return Response::InternalError("No response received from the model.".into());
+        }
+        if let Some(output) = outputs.into_iter().find(|output| output.finished) {
+            return output.response;
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl MistralRsService for GrpcService {
+    type ChatCompletionStreamStream =
+        Pin<Box<dyn Stream<Item = Result<proto::ChatCompletionChunk, Status>> + Send>>;
+
+    async fn chat_completion(
+        &self,
+        request: tonic::Request<proto::ChatCompletionRequest>,
+    ) -> Result<tonic::Response<proto::ChatCompletionResponse>, Status> {
+        let mut handle = self.submit_chat(request.into_inner(), false).await?;
+        match single_response(&mut handle).await {
+            Response::Done(response) => {
+                MistralRs::maybe_log_response(self.state.clone(), &response);
+                Ok(tonic::Response::new(response.into()))
+            }
+            response => Err(error_status(&self.state, response)),
+        }
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        request: tonic::Request<proto::ChatCompletionRequest>,
+    ) -> Result<tonic::Response<Self::ChatCompletionStreamStream>, Status> {
+        let request = request.into_inner();
+        let include_usage = request.include_usage;
+        let handle = self.submit_chat(request, true).await?;
+        let state = self.state.clone();
+        // The stream owns the handle, so the request is cancelled when the client disconnects
+        let chunks = stream::unfold(handle, |mut handle| async move {
+            let outputs = handle.step().await;
+            (!outputs.is_empty()).then_some((outputs, handle))
+        })
+        .flat_map(move |outputs| {
+            let chunks = outputs
+                .into_iter()
+                .map(|output| match output.response {
+                    Response::Chunk(mut response) => {
+                        // Only the last chunk has the usage
+                        if !include_usage {
+                            response.usage = None;
+                        }
+                        MistralRs::maybe_log_response(state.clone(), &response);
+                        Ok(response.into())
+                    }
+                    response => Err(error_status(&state, response)),
+                })
+                .collect::<Vec<_>>();
+            stream::iter(chunks)
+        });
+        Ok(tonic::Response::new(Box::pin(chunks)))
+    }
+
+    async fn completion(
+        &self,
+        request: tonic::Request<proto::CompletionRequest>,
+    ) -> Result<tonic::Response<proto::CompletionResponse>, Status> {
+        let request = completion_request(request.into_inner())?;
+        let (tx, _) = channel(1);
+        let request = completions::parse_request(request, self.state.clone(), tx);
+        let mut handle = self.submit(request).await?;
+        match single_response(&mut handle).await {
+            Response::CompletionDone(response) => {
+                MistralRs::maybe_log_response(self.state.clone(), &response);
+                Ok(tonic::Response::new(response.into()))
+            }
+            response => Err(error_status(&self.state, response)),
+        }
+    }
+}
+
+fn parse_json(json: &str, what: &str) -> Result<serde_json::Value, Status> {
+    serde_json::from_str(json)
+        .map_err(|e| Status::invalid_argument(format!("Invalid JSON in the {what}: {e}")))
+}
+
+fn opt_usize(x: Option<u32>) -> Option<usize> {
+    x.map(|x| x as usize)
+}
+
+fn opt_vec<T>(xs: Vec<T>) -> Option<Vec<T>> {
+    (!xs.is_empty()).then_some(xs)
+}
+
+fn stop_tokens(stop: Vec<String>) -> Option<StopTokens> {
+    opt_vec(stop).map(StopTokens::Multi)
+}
+
+fn logit_bias(bias: HashMap<u32, f32>) -> Option<HashMap<u32, f32>> {
+    (!bias.is_empty()).then_some(bias)
+}
+
+fn chat_message(message: proto::Message) -> Result<Message, Status> {
+    let content = match message.content {
+        Some(message::Content::Text(text)) => Either::Left(text),
+        Some(message::Content::Parts(parts)) => Either::Right(
+            parts
+                .parts
+                .into_iter()
+                .map(content_part)
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => Either::Left(String::new()),
+    };
+    Ok(Message {
+        content: MessageContent(content),
+        role: message.role,
+        name: message.name,
+    })
+}
+
+fn content_part(part: proto::ContentPart) -> Result<HashMap<String, MessageInnerContent>, Status> {
+    let text = |s: &str| MessageInnerContent(Either::Left(s.to_string()));
+    match part.part {
+        Some(content_part::Part::Text(t)) => Ok(HashMap::from([
+            ("type".to_string(), text("text")),
+            ("text".to_string(), text(&t)),
+        ])),
+        Some(content_part::Part::ImageUrl(url)) => Ok(HashMap::from([
+            ("type".to_string(), text("image_url")),
+            (
+                "image_url".to_string(),
+                MessageInnerContent(Either::Right(HashMap::from([("url".to_string(), url)]))),
+            ),
+        ])),
+        None => Err(Status::invalid_argument(
+            "Expected a `text` or an `image_url` in each content part.",
+        )),
+    }
+}
+
+fn tool(tool: proto::Tool) -> Result<Tool, Status> {
+    let Some(function) = tool.function else {
+        return Err(Status::invalid_argument(
+            "Expected a `function` in each tool.",
+        ));
+    };
+    Ok(Tool {
+        tp: ToolType::Function,
+        function: Function {
+            name: function.name,
+            description: function.description,
+            parameters: function
+                .parameters
+                .map(|parameters| parse_json(&parameters, "parameters of a function"))
+                .transpose()?,
+        },
+    })
+}
+
+/// Convert a chat completion request to the request of the HTTP server, so that both are validated alike.
+fn chat_completion_request(
+    request: proto::ChatCompletionRequest,
+    stream: bool,
+) -> Result<ChatCompletionRequest, Status> {
+    let (grammar, response_format) = match request.grammar {
+        Some(chat_completion_request::Grammar::Regex(regex)) => (Some(Grammar::Regex(regex)), None),
+        Some(chat_completion_request::Grammar::Yacc(yacc)) => (Some(Grammar::Yacc(yacc)), None),
+        Some(chat_completion_request::Grammar::Gbnf(gbnf)) => (Some(Grammar::Gbnf(gbnf)), None),
+        Some(chat_completion_request::Grammar::JsonSchema(schema)) => (
+            None,
+            Some(ResponseFormat::JsonSchema {
+                schema: parse_json(&schema, "JSON schema")?,
+            }),
+        ),
+        None => (None, None),
+    };
+    Ok(ChatCompletionRequest {
+        messages: Either::Left(
+            request
+                .messages
+                .into_iter()
+                .map(chat_message)
+                .collect::<Result<_, _>>()?,
+        ),
+        model: request.model,
+        logit_bias: logit_bias(request.logit_bias),
+        logprobs: request.logprobs,
+        top_logprobs: opt_usize(request.top_logprobs),
+        max_tokens: opt_usize(request.max_tokens),
+        n_choices: opt_usize(request.n).unwrap_or(1),
+        presence_penalty: request.presence_penalty,
+        frequency_penalty: request.frequency_penalty,
+        stop_seqs: stop_tokens(request.stop),
+        stop_token_ids: opt_vec(request.stop_token_ids),
+        eos_token_ids: opt_vec(request.eos_token_ids),
+        min_tokens: opt_usize(request.min_tokens),
+        temperature: request.temperature,
+        top_p: request.top_p,
+        stream: Some(stream),
+        stream_options: None,
+        response_format,
+        tools: opt_vec(
+            request
+                .tools
+                .into_iter()
+                .map(tool)
+                .collect::<Result<_, _>>()?,
+        ),
+        top_k: opt_usize(request.top_k),
+        top_n_sigma: request.top_n_sigma,
+        mirostat_tau: request.mirostat_tau,
+        mirostat_eta: request.mirostat_eta,
+        max_time: request.max_time,
+        num_beams: opt_usize(request.num_beams),
+        length_penalty: request.length_penalty,
+        penalty_alpha: request.penalty_alpha,
+        repetition_penalty: request.repetition_penalty,
+        repetition_penalty_range: opt_usize(request.repetition_penalty_range),
+        no_repeat_ngram_size: opt_usize(request.no_repeat_ngram_size),
+        seed: request.seed,
+        grammar,
+        adapters: opt_vec(request.adapters),
+        lora_scale: request.lora_scale,
+        priority: opt_usize(request.priority),
+    })
+}
+
+/// Convert a completion request to the request of the HTTP server, so that both are validated alike.
+fn completion_request(request: proto::CompletionRequest) -> Result<CompletionRequest, Status> {
+    Ok(CompletionRequest {
+        model: request.model,
+        prompt: request.prompt,
+        best_of: opt_usize(request.best_of),
+        echo_prompt: request.echo,
+        presence_penalty: request.presence_penalty,
+        frequency_penalty: request.frequency_penalty,
+        logit_bias: logit_bias(request.logit_bias),
+        logprobs: opt_usize(request.logprobs),
+        max_tokens: opt_usize(request.max_tokens),
+        n_choices: opt_usize(request.n).unwrap_or(1),
+        stop_seqs: stop_tokens(request.stop),
+        stop_token_ids: opt_vec(request.stop_token_ids),
+        eos_token_ids: opt_vec(request.eos_token_ids),
+        min_tokens: opt_usize(request.min_tokens),
+        _stream: None,
+        temperature: request.temperature,
+        top_p: request.top_p,
+        suffix: request.suffix,
+        _user: None,
+        top_k: opt_usize(request.top_k),
+        top_n_sigma: request.top_n_sigma,
+        mirostat_tau: request.mirostat_tau,
+        mirostat_eta: request.mirostat_eta,
+        max_time: request.max_time,
+        num_beams: opt_usize(request.num_beams),
+        length_penalty: request.length_penalty,
+        penalty_alpha: request.penalty_alpha,
+        repetition_penalty: request.repetition_penalty,
+        repetition_penalty_range: opt_usize(request.repetition_penalty_range),
+        no_repeat_ngram_size: opt_usize(request.no_repeat_ngram_size),
+        seed: request.seed,
+        grammar: match request.grammar {
+            Some(completion_request::Grammar::Regex(regex)) => Some(Grammar::Regex(regex)),
+            Some(completion_request::Grammar::Yacc(yacc)) => Some(Grammar::Yacc(yacc)),
+            Some(completion_request::Grammar::Gbnf(gbnf)) => Some(Grammar::Gbnf(gbnf)),
+            None => None,
+        },
+        adapters: opt_vec(request.adapters),
+        lora_scale: request.lora_scale,
+        priority: opt_usize(request.priority),
+    })
+}
+
+impl From<Usage> for proto::Usage {
+    fn from(usage: Usage) -> Self {
+        Self {
+            completion_tokens: usage.completion_tokens as u64,
+            prompt_tokens: usage.prompt_tokens as u64,
+            total_tokens: usage.total_tokens as u64,
+            avg_tok_per_sec: usage.avg_tok_per_sec,
+            avg_prompt_tok_per_sec: usage.avg_prompt_tok_per_sec,
+            avg_compl_tok_per_sec: usage.avg_compl_tok_per_sec,
+            time_to_first_token_sec: usage.time_to_first_token_sec,
+            total_time_sec: usage.total_time_sec,
+            total_prompt_time_sec: usage.total_prompt_time_sec,
+            total_completion_time_sec: usage.total_completion_time_sec,
+        }
+    }
+}
+
+impl From<TopLogprob> for proto::TopLogprob {
+    fn from(logprob: TopLogprob) -> Self {
+        Self {
+            token: logprob.token,
+            logprob: logprob.logprob,
+            bytes: logprob.bytes,
+        }
+    }
+}
+
+impl From<ResponseLogprob> for proto::ResponseLogprob {
+    fn from(logprob: ResponseLogprob) -> Self {
+        Self {
+            token: logprob.token,
+            logprob: logprob.logprob,
+            bytes: logprob.bytes,
+            top_logprobs: logprob.top_logprobs.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<Logprobs> for proto::Logprobs {
+    fn from(logprobs: Logprobs) -> Self {
+        Self {
+            content: logprobs
+                .content
+                .unwrap_or_default()
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        }
+    }
+}
+
+impl From<ToolCall> for proto::ToolCall {
+    fn from(call: ToolCall) -> Self {
+        Self {
+            id: call.id,
+            r#type: call.tp,
+            function: Some(proto::CalledFunction {
+                name: call.function.name,
+                arguments: call.function.arguments,
+            }),
+        }
+    }
+}
+
+impl From<Choice> for proto::Choice {
+    fn from(choice: Choice) -> Self {
+        Self {
+            finish_reason: choice.finish_reason,
+            index: choice.index as u64,
+            message: Some(proto::ResponseMessage {
+                content: choice.message.content,
+                role: choice.message.role,
+                tool_calls: choice
+                    .message
+                    .tool_calls
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            }),
+            logprobs: choice.logprobs.map(Into::into),
+        }
+    }
+}
+
+impl From<ChatCompletionResponse> for proto::ChatCompletionResponse {
+    fn from(response: ChatCompletionResponse) -> Self {
+        Self {
+            id: response.id,
+            choices: response.choices.into_iter().map(Into::into).collect(),
+            created: response.created,
+            model: response.model,
+            system_fingerprint: response.system_fingerprint,
+            usage: Some(response.usage.into()),
+        }
+    }
+}
+
+impl From<ChunkChoice> for proto::ChunkChoice {
+    fn from(choice: ChunkChoice) -> Self {
+        Self {
+            finish_reason: choice.finish_reason,
+            index: choice.index as u64,
+            delta: Some(proto::Delta {
+                content: choice.delta.content,
+                role: choice.delta.role,
+            }),
+            logprobs: choice.logprobs.map(Into::into),
+        }
+    }
+}
+
+impl From<ChatCompletionChunkResponse> for proto::ChatCompletionChunk {
+    fn from(response: ChatCompletionChunkResponse) -> Self {
+        Self {
+            id: response.id,
+            choices: response.choices.into_iter().map(Into::into).collect(),
+            created: u64::try_from(response.created).unwrap_or(u64::MAX),
+            model: response.model,
+            system_fingerprint: response.system_fingerprint,
+            usage: response.usage.map(Into::into),
+        }
+    }
+}
+
+impl From<CompletionChoice> for proto::CompletionChoice {
+    fn from(choice: CompletionChoice) -> Self {
+        Self {
+            finish_reason: choice.finish_reason,
+            index: choice.index as u64,
+            text: choice.text,
+            logprobs: choice.logprobs.map(Into::into),
+        }
+    }
+}
+
+impl From<CompletionResponse> for proto::CompletionResponse {
+    fn from(response: CompletionResponse) -> Self {
+        Self {
+            id: response.id,
+            choices: response.choices.into_iter().map(Into::into).collect(),
+            created: response.created,
+            model: response.model,
+            system_fingerprint: response.system_fingerprint,
+            usage: Some(response.usage.into()),
+        }
+    }
+}
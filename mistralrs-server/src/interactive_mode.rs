@@ -31,13 +31,27 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
         temperature: Some(0.1),
         top_k: Some(32),
         top_p: Some(0.1),
+        top_n_sigma: None,
+        mirostat_tau: None,
+        mirostat_eta: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
         max_len: Some(4096),
+        max_time: None,
         stop_toks: None,
+        stop_token_ids: None,
+        eos_token_ids: None,
+        min_tokens: None,
         logits_bias: None,
         n_choices: 1,
+        num_beams: None,
+        length_penalty: None,
+        penalty_alpha: None,
+        repetition_penalty: None,
+        repetition_penalty_range: None,
+        no_repeat_ngram_size: None,
+        seed: None,
     };
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
 
@@ -80,6 +94,9 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
             constraint: Constraint::None,
             suffix: None,
             adapters: None,
+            lora_scale: None,
+            priority: 0,
+            tools: None,
         });
         sender.send(req).await.unwrap();
 
@@ -111,9 +128,16 @@ pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
                     error!("Got a validation error: {e:?}");
                     break 'outer;
                 }
+                Response::Busy(e) => {
+                    error!("The engine is busy: {e:?}");
+                    break 'outer;
+                }
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
+                Response::Tokenized(_) => unreachable!(),
+                Response::Detokenized(_) => unreachable!(),
             }
         }
         let mut assistant_message: IndexMap<String, Either<String, Vec<IndexMap<String, String>>>> =
@@ -1,23 +1,38 @@
 use anyhow::Result;
 use axum::{
     extract::{DefaultBodyLimit, Json, State},
-    http::{self, Method},
+    http::{self, Method, StatusCode},
+    response::IntoResponse,
     routing::{get, post},
     Router,
 };
 use candle_core::{quantized::GgmlDType, Device};
 use clap::Parser;
 use mistralrs_core::{
-    get_model_dtype, get_tgt_non_granular_index, initialize_logging, DeviceLayerMapMetadata,
-    DeviceMapMetadata, Loader, LoaderBuilder, MistralRs, MistralRsBuilder, ModelSelected, Request,
-    SchedulerMethod, TokenSource,
+    get_attention_implementation, get_model_dtype, get_tgt_non_granular_index, initialize_logging,
+    set_attention_implementation, AttentionImplementation, DeviceLayerMapMetadata,
+    DeviceMapMetadata, EvictionPolicy, IsqOverride, Loader, LoaderBuilder, MistralRs,
+    MistralRsBuilder, ModelSelected, Request, RopeScaling, SchedulerMethod, TokenSource,
+};
+use openai::{
+    ChatCompletionRequest, DetokenizeRequest, EmbeddingInput, EmbeddingPooling, EmbeddingRequest,
+    Function, Message, ModelObjects, StopTokens, StreamOptions, TokenizeRequest, Tool,
 };
-use openai::{ChatCompletionRequest, Message, ModelObjects, StopTokens};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 mod chat_completion;
 mod completions;
+mod embeddings;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod tokenization;
+mod websocket;
 use crate::{chat_completion::__path_chatcompletions, completions::completions};
+use crate::{embeddings::__path_embeddings, embeddings::embeddings};
+use crate::{
+    tokenization::__path_detokenize, tokenization::__path_tokenize, tokenization::detokenize,
+    tokenization::tokenize,
+};
 
 use crate::{chat_completion::chatcompletions, openai::ModelObject};
 mod interactive_mode;
@@ -55,6 +70,30 @@ fn parse_isq(s: &str) -> Result<GgmlDType, String> {
     }
 }
 
+fn parse_kv_cache_bits(s: &str) -> Result<u8, String> {
+    match s {
+        "8" => Ok(8),
+        "4" => Ok(4),
+        _ => Err(format!("KV cache bits `{s}` should be 8 or 4")),
+    }
+}
+
+fn parse_isq_override(s: &str) -> Result<IsqOverride, String> {
+    let (pattern, dtype) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("ISQ override `{s}` should be formatted like `PATTERN=DTYPE`"))?;
+    let dtype = match dtype {
+        "F16" => GgmlDType::F16,
+        "F32" => GgmlDType::F32,
+        dtype => parse_isq(dtype)?,
+    };
+    match pattern.strip_prefix("re:") {
+        Some(regex) => IsqOverride::regex(regex, dtype),
+        None => IsqOverride::glob(pattern, dtype),
+    }
+    .map_err(|e| e.to_string())
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -66,13 +105,19 @@ struct Args {
     #[arg(short, long)]
     port: Option<String>,
 
+    /// Port to serve the gRPC interface on, alongside the HTTP server, on the same IP.
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    grpc_port: Option<String>,
+
     /// Log all responses and requests to this file
     #[clap(long, short)]
     log: Option<String>,
 
-    /// If a sequence is larger than the maximum model length, truncate the number
-    /// of tokens such that the sequence will fit at most the maximum length.
-    /// If `max_tokens` is not specified in the request, space for 10 tokens will be reserved instead.
+    /// If a prompt and its `max_tokens` do not fit in the maximum model length, truncate the prompt
+    /// instead of rejecting the request. The oldest turns of a chat are dropped first, then the
+    /// first tokens of the prompt. If `max_tokens` is not specified in the request, space for 10
+    /// tokens will be reserved instead.
     #[clap(long, short, action)]
     truncate_sequence: bool,
 
@@ -103,19 +148,122 @@ struct Args {
     #[clap(long, short, action)]
     interactive_mode: bool,
 
-    /// Number of prefix caches to hold on the device. Other caches are evicted to the CPU based on a LRU strategy.
-    #[arg(long, default_value_t = 16)]
-    prefix_cache_n: usize,
+    /// Number of prefix caches to hold on the device. Other caches are evicted to the CPU based on the eviction policy.
+    /// Defaults to the number of caches which fit in the free device memory after loading the model (CUDA only), or 16.
+    #[arg(long)]
+    prefix_cache_n: Option<usize>,
+
+    /// Policy used to select which prefix caches are evicted to the CPU first: `fifo`, `lru` or `lfu`.
+    #[arg(long, default_value = "fifo")]
+    prefix_cache_eviction: EvictionPolicy,
+
+    /// Prefill prompts longer than this many tokens in chunks of this size, interleaved with the decoding of other
+    /// sequences. By default, prompts are prefilled at once.
+    #[arg(long)]
+    prefill_chunk_size: Option<usize>,
+
+    /// Store the KV caches of the sequences and the prefix cache quantized to this many bits, 8 or 4. This roughly
+    /// halves (or quarters) their memory, at some cost in accuracy and speed.
+    #[arg(long, value_parser = parse_kv_cache_bits)]
+    kv_cache_bits: Option<u8>,
+
+    /// Keep the KV caches of the sequences in a pool of blocks of this many positions, rather than in a tensor per
+    /// sequence, so that the memory of finished sequences is reused without fragmentation. This disables the prefix
+    /// cache, beam search and speculative decoding.
+    #[arg(long, conflicts_with = "kv_cache_bits")]
+    kv_cache_block_size: Option<usize>,
+
+    /// Add the BOS token to the start of prompts which do not already start with it (`true` or `false`). Defaults
+    /// to `add_bos_token` of the tokenizer config of the model.
+    #[arg(long)]
+    add_bos_token: Option<bool>,
+
+    /// Add the EOS token to the end of prompts (`true` or `false`). Defaults to `add_eos_token` of the tokenizer
+    /// config of the model.
+    #[arg(long)]
+    add_eos_token: Option<bool>,
+
+    /// Do not parse special tokens in the text of requests, so that users cannot inject control tokens. They are
+    /// tokenized as plain text in completions, and chat requests whose messages contain one are rejected.
+    #[arg(long)]
+    disable_special_token_parsing: bool,
+
+    /// Before serving, run a batch of `--max-seqs` short dummy requests through a prefill and a decode step, so that
+    /// the first requests do not pay for compiling kernels and allocating caches.
+    #[arg(long)]
+    warmup: bool,
+
+    /// Number of prompt tokens of each warmup request. Defaults to 32.
+    #[arg(long)]
+    warmup_prompt_len: Option<usize>,
+
+    /// Reject requests with a 503 status instead of queueing them while this many sequences are waiting to be
+    /// scheduled. A request with `n` choices queues `n` sequences. By default, requests are always queued.
+    #[arg(long)]
+    max_queued_requests: Option<usize>,
+
+    /// On SIGTERM or Ctrl-C, seconds to let the running requests finish before they are cancelled. No new requests
+    /// are accepted meanwhile.
+    #[arg(long, default_value_t = 30)]
+    drain_timeout: u64,
+
+    /// System prompt added to every chat request, merged with the system message of the request if it has one.
+    /// As it starts every prompt, its KV cache is reused by the prefix cacher.
+    #[arg(long)]
+    default_system_prompt: Option<String>,
 
     /// Number of device layers to load and run on GPU(s). All others will be on the CPU.
     /// If one GPU is used, then this value should be an integer. Otherwise, it follows the following pattern:
     /// ORD:NUM;... Where ORD is a unique device ordinal and NUM is the number of layers for that device.
-    #[arg(short, long, value_parser, value_delimiter = ';')]
+    /// The activations are copied across devices at the boundaries between the layers in each forward pass.
+    #[arg(
+        short,
+        long,
+        visible_alias = "num-gpu-layers",
+        value_parser,
+        value_delimiter = ';'
+    )]
     num_device_layers: Option<Vec<String>>,
 
+    /// Shard the attention and MLP projections of the model across this many GPUs, of ordinals 0 to N - 1, with
+    /// tensor parallelism. The rest of the model and the KV cache are on GPU 0. Only supported by plain Llama models.
+    #[arg(long, conflicts_with = "num_device_layers")]
+    tensor_parallel_size: Option<usize>,
+
     /// In-situ quantization to apply. You may specify one of the GGML data type (except F32 or F16): formatted like this: `Q4_0` or `Q4K`.
     #[arg(long = "isq", value_parser = parse_isq)]
     in_situ_quant: Option<GgmlDType>,
+
+    /// Override the in-situ quantization of the tensors matching a pattern, formatted like `PATTERN=DTYPE`.
+    /// The pattern is a glob matched against tensor names such as `model.layers.0.self_attn.o_proj`, or a regex if
+    /// prefixed with `re:`. Use `F16` or `F32` to keep tensors in higher precision, for example `lm_head=F16`.
+    /// May be given multiple times, the first matching override applies.
+    #[arg(long = "isq-override", value_parser = parse_isq_override)]
+    isq_overrides: Vec<IsqOverride>,
+
+    /// Scale the rotary embedding to extend the context of the model, replacing the `rope_scaling` of its config.
+    /// Formatted like `TYPE:FACTOR`, where the type is `linear`, `dynamic` or `yarn`, for example `yarn:4`, or as the
+    /// JSON object of the config, such as `{"type": "yarn", "factor": 4.0, "original_max_position_embeddings": 32768}`.
+    /// Supported by plain, X-LoRA and LoRA Mistral, Mixtral, Llama and Qwen2 models.
+    #[arg(long)]
+    rope_scaling: Option<RopeScaling>,
+
+    /// Base frequency of the rotary embedding, replacing the `rope_theta` of the config of the model, or the
+    /// `rope.freq_base` of a GGUF model. For fine-tunes which changed it without updating their config.
+    #[arg(long)]
+    rope_theta: Option<f64>,
+
+    /// Attention implementation of models loaded with flash attention: `flash` for the fused kernel or `eager` for
+    /// standard softmax attention, for example to check whether a correctness issue is in the fused kernel. Defaults
+    /// to `flash` when built with the `flash-attn` feature.
+    #[arg(long)]
+    attention_impl: Option<AttentionImplementation>,
+
+    /// Run the model on the CPU, even if a GPU is available. The CPU kernels are deterministic, so with a seed the
+    /// outputs are reproducible bit for bit, for example to compute reference outputs in tests. This disables flash
+    /// attention and ignores the device layers.
+    #[arg(long)]
+    cpu: bool,
 }
 
 #[utoipa::path(
@@ -132,6 +280,7 @@ async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
             object: "model",
             created: state.get_creation_time(),
             owned_by: "local",
+            max_model_len: state.get_max_model_len(),
         }],
     })
 }
@@ -146,6 +295,39 @@ async fn health() -> &'static str {
     "OK"
 }
 
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/ready",
+    responses(
+        (status = 200, description = "The model is loaded and warmed up, and requests are served right away"),
+        (status = 503, description = "The engine is not ready to serve requests yet")
+    )
+)]
+async fn ready(State(state): State<Arc<MistralRs>>) -> impl IntoResponse {
+    if state.is_ready() {
+        (StatusCode::OK, "OK")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "Not ready")
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/metrics",
+    responses((status = 200, description = "Engine metrics in the Prometheus text format"))
+)]
+async fn metrics(State(state): State<Arc<MistralRs>>) -> impl IntoResponse {
+    (
+        [(
+            http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        state.get_metrics().to_prometheus(),
+    )
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 struct AdapterActivationRequest {
     #[schema(example = json!(vec!["adapter_1","adapter_2"]))]
@@ -170,6 +352,78 @@ async fn activate_adapters(
     repr
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+struct AddAdapterRequest {
+    #[schema(example = "adapter_4")]
+    name: String,
+    /// Local directory with the `adapter_config.json` and `adapter_model.safetensors` of the adapter.
+    #[schema(example = "/path/to/adapter_4")]
+    path: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/add_adapter",
+    request_body = AddAdapterRequest,
+    responses((status = 200, description = "Load a LoRA adapter, which can then be activated"))
+)]
+async fn add_adapter(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<AddAdapterRequest>,
+) -> String {
+    let repr = format!("Add adapter: {} from {}", request.name, request.path);
+    MistralRs::maybe_log_request(state.clone(), repr.clone());
+    let request = Request::AddAdapter {
+        name: request.name,
+        path: request.path.into(),
+    };
+    state.get_sender().unwrap().send(request).await.unwrap();
+    repr
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+struct MergeAdapterRequest {
+    #[schema(example = "adapter_1")]
+    name: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/merge_adapter",
+    request_body = MergeAdapterRequest,
+    responses((status = 200, description = "Merge a LoRA adapter into the base weights"))
+)]
+async fn merge_adapter(
+    State(state): State<Arc<MistralRs>>,
+    Json(request): Json<MergeAdapterRequest>,
+) -> String {
+    let repr = format!("Merge adapter: {}", request.name);
+    MistralRs::maybe_log_request(state.clone(), repr.clone());
+    let request = Request::MergeAdapter(request.name);
+    state.get_sender().unwrap().send(request).await.unwrap();
+    repr
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/unmerge_adapter",
+    responses((status = 200, description = "Restore the base weights from before an adapter was merged"))
+)]
+async fn unmerge_adapter(State(state): State<Arc<MistralRs>>) -> String {
+    let repr = "Unmerge adapter".to_string();
+    MistralRs::maybe_log_request(state.clone(), repr.clone());
+    state
+        .get_sender()
+        .unwrap()
+        .send(Request::UnmergeAdapter)
+        .await
+        .unwrap();
+    repr
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 struct ReIsqRequest {
     #[schema(example = "Q4K")]
@@ -197,9 +451,11 @@ async fn re_isq(
 fn get_router(state: Arc<MistralRs>) -> Router {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions),
+        paths(models, health, ready, metrics, chatcompletions, embeddings, tokenize, detokenize),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, Message,
+                EmbeddingRequest, EmbeddingInput, EmbeddingPooling, Tool, Function, StreamOptions,
+                TokenizeRequest, DetokenizeRequest)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -225,15 +481,47 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .layer(cors_layer)
         .route("/v1/chat/completions", post(chatcompletions))
         .route("/v1/completions", post(completions))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/tokenize", post(tokenize))
+        .route("/detokenize", post(detokenize))
+        .route("/ws", get(websocket::websocket))
         .route("/v1/models", get(models))
         .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/metrics", get(metrics))
         .route("/", get(health))
         .route("/activate_adapters", post(activate_adapters))
+        .route("/add_adapter", post(add_adapter))
+        .route("/merge_adapter", post(merge_adapter))
+        .route("/unmerge_adapter", post(unmerge_adapter))
         .route("/re_isq", post(re_isq))
         .layer(DefaultBodyLimit::max(N_INPUT_SIZE * MB_TO_B))
         .with_state(state)
 }
 
+/// Wait for a SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install the Ctrl-C handler.");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install the SIGTERM handler.")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let mut args = Args::parse();
@@ -242,7 +530,20 @@ async fn main() -> Result<()> {
     #[cfg(not(feature = "flash-attn"))]
     let use_flash_attn = false;
     #[cfg(feature = "flash-attn")]
-    let use_flash_attn = true;
+    let use_flash_attn = !args.cpu;
+
+    if let Some(attention_impl) = args.attention_impl {
+        set_attention_implementation(attention_impl)?;
+    }
+
+    if args.cpu && args.num_device_layers.is_some() {
+        warn!("Ignoring the device layers, the model runs on the CPU.");
+        args.num_device_layers = None;
+    }
+    if args.cpu && args.tensor_parallel_size.is_some() {
+        warn!("Ignoring the tensor parallel size, the model runs on the CPU.");
+        args.tensor_parallel_size = None;
+    }
 
     let tgt_non_granular_index = get_tgt_non_granular_index(&args.model);
     let dtype = get_model_dtype(&args.model)?;
@@ -255,12 +556,23 @@ async fn main() -> Result<()> {
         .with_no_kv_cache(args.no_kv_cache)
         .with_chat_template(args.chat_template)
         .with_use_flash_attn(use_flash_attn)
+        .with_isq_overrides(args.isq_overrides)
+        .with_rope_scaling(args.rope_scaling)
+        .with_rope_theta(args.rope_theta)
         .build()?;
 
     #[cfg(feature = "metal")]
-    let device = Device::new_metal(0)?;
+    let device = if args.cpu {
+        Device::Cpu
+    } else {
+        Device::new_metal(0)?
+    };
     #[cfg(not(feature = "metal"))]
-    let device = Device::cuda_if_available(0)?;
+    let device = if args.cpu {
+        Device::Cpu
+    } else {
+        Device::cuda_if_available(0)?
+    };
 
     info!(
         "avx: {}, neon: {}, simd128: {}, f16c: {}",
@@ -270,7 +582,7 @@ async fn main() -> Result<()> {
         candle_core::utils::with_f16c()
     );
     info!("Sampling method: penalties -> temperature -> topk -> topp -> multinomial");
-    if use_flash_attn {
+    if use_flash_attn && get_attention_implementation() == AttentionImplementation::Flash {
         info!("Using flash attention.");
     }
     if use_flash_attn && loader.get_kind().is_quantized() {
@@ -279,7 +591,9 @@ async fn main() -> Result<()> {
     info!("Model kind is: {}", loader.get_kind().to_string());
 
     // Parse device mapper
-    let mapper = if let Some(device_layers) = args.num_device_layers {
+    let mapper = if let Some(tensor_parallel_size) = args.tensor_parallel_size {
+        DeviceMapMetadata::from_tensor_parallel_size(tensor_parallel_size)
+    } else if let Some(device_layers) = args.num_device_layers {
         if device_layers.len() == 1 && device_layers[0].parse::<usize>().is_ok() {
             let layers = device_layers[0].parse::<usize>().unwrap();
             DeviceMapMetadata::from_num_device_layers_multi_gpu(vec![DeviceLayerMapMetadata {
@@ -333,8 +647,19 @@ async fn main() -> Result<()> {
     .with_opt_log(args.log)
     .with_truncate_sequence(args.truncate_sequence)
     .with_no_kv_cache(args.no_kv_cache)
-    .with_prefix_cache_n(args.prefix_cache_n)
-    .build();
+    .with_opt_prefix_cache_n(args.prefix_cache_n)
+    .with_prefix_cache_eviction_policy(args.prefix_cache_eviction)
+    .with_opt_prefill_chunk_size(args.prefill_chunk_size)
+    .with_opt_default_system_prompt(args.default_system_prompt)
+    .with_opt_kv_cache_bits(args.kv_cache_bits)
+    .with_opt_kv_cache_block_size(args.kv_cache_block_size)
+    .with_opt_add_bos_token(args.add_bos_token)
+    .with_opt_add_eos_token(args.add_eos_token)
+    .with_disable_special_token_parsing(args.disable_special_token_parsing)
+    .with_warmup(args.warmup)
+    .with_opt_warmup_prompt_len(args.warmup_prompt_len)
+    .with_opt_max_queued_requests(args.max_queued_requests)
+    .build()?;
 
     if args.interactive_mode {
         interactive_mode(mistralrs).await;
@@ -343,7 +668,7 @@ async fn main() -> Result<()> {
 
     let port = args.port.expect("Expected port to be specified.");
 
-    let app = get_router(mistralrs);
+    let app = get_router(mistralrs.clone());
 
     let ip = if let Some(ref ip) = args.serve_ip {
         ip.to_string()
@@ -352,7 +677,65 @@ async fn main() -> Result<()> {
     };
     let listener = tokio::net::TcpListener::bind(format!("{ip}:{}", port)).await?;
     info!("Serving on http://{ip}:{}.", port);
-    axum::serve(listener, app).await?;
+
+    // On a shutdown signal, the servers stop accepting connections, and the engine drains the running requests
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    #[cfg(feature = "grpc")]
+    let grpc_shutdown = Arc::new(tokio::sync::Notify::new());
+    #[cfg(feature = "grpc")]
+    let grpc_server = match &args.grpc_port {
+        Some(grpc_port) => {
+            let addr = format!("{ip}:{grpc_port}").parse()?;
+            let grpc_shutdown = grpc_shutdown.clone();
+            info!("Serving gRPC on {addr}.");
+            Some(tokio::spawn(grpc::serve(
+                mistralrs.clone(),
+                addr,
+                async move { grpc_shutdown.notified().await },
+            )))
+        }
+        None => None,
+    };
+    #[cfg(feature = "grpc")]
+    let http_shutdown = grpc_shutdown.clone();
+    let mut server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                shutdown_signal().await;
+                #[cfg(feature = "grpc")]
+                http_shutdown.notify_one();
+                let _ = shutdown_tx.send(());
+            })
+            .await
+    });
+    tokio::select! {
+        res = &mut server => return Ok(res??),
+        Ok(()) = shutdown_rx => {}
+    }
+    info!(
+        "Shutting down, letting the running requests finish for up to {}s.",
+        args.drain_timeout
+    );
+    mistralrs
+        .drain(Duration::from_secs(args.drain_timeout))
+        .await?;
+    // The last responses may still be being written
+    if tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .is_err()
+    {
+        warn!("Closing the connections which are still open.");
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_server) = grpc_server {
+        if tokio::time::timeout(Duration::from_secs(5), grpc_server)
+            .await
+            .is_err()
+        {
+            warn!("Closing the gRPC calls which are still running.");
+        }
+    }
+    info!("Shut down.");
 
     Ok(())
 }
@@ -5,7 +5,7 @@ use utoipa::ToSchema;
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct MessageInnerContent(
-    #[serde(with = "either::serde_untagged")] Either<String, HashMap<String, String>>,
+    #[serde(with = "either::serde_untagged")] pub(crate) Either<String, HashMap<String, String>>,
 );
 
 impl Deref for MessageInnerContent {
@@ -18,7 +18,7 @@ impl Deref for MessageInnerContent {
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct MessageContent(
     #[serde(with = "either::serde_untagged")]
-    Either<String, Vec<HashMap<String, MessageInnerContent>>>,
+    pub(crate)  Either<String, Vec<HashMap<String, MessageInnerContent>>>,
 );
 
 impl Deref for MessageContent {
@@ -57,6 +57,46 @@ pub enum Grammar {
     Regex(String),
     #[serde(rename = "yacc")]
     Yacc(String),
+    #[serde(rename = "gbnf")]
+    Gbnf(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct StreamOptions {
+    /// Report the token usage of the request in the last chunk.
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type")]
+pub enum ResponseFormat {
+    #[serde(rename = "text")]
+    Text,
+    /// Constrain the output to JSON values matching the schema.
+    #[serde(rename = "json_schema")]
+    JsonSchema { schema: serde_json::Value },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub enum ToolType {
+    #[serde(rename = "function")]
+    Function,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct Function {
+    pub name: String,
+    pub description: Option<String>,
+    /// JSON schema of the arguments.
+    pub parameters: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: Function,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -71,6 +111,8 @@ pub struct ChatCompletionRequest {
     #[serde(default = "default_false")]
     #[schema(example = false)]
     pub logprobs: bool,
+    /// Number of most likely alternatives to return for each token when `logprobs` is set. A value of at least the
+    /// vocab size returns the full distribution, at a large cost in latency and response size.
     #[schema(example = json!(Option::None::<usize>))]
     pub top_logprobs: Option<usize>,
     #[schema(example = 256)]
@@ -86,20 +128,75 @@ pub struct ChatCompletionRequest {
     #[serde(rename = "stop")]
     #[schema(example = json!(Option::None::<StopTokens>))]
     pub stop_seqs: Option<StopTokens>,
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub stop_token_ids: Option<Vec<u32>>,
+    /// Token ids which end the generation like EOS, replacing the EOS tokens of the model.
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub eos_token_ids: Option<Vec<u32>>,
+    /// Minimum number of tokens to generate before EOS or a stop token may end the generation.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub min_tokens: Option<usize>,
     #[schema(example = 0.7)]
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
     pub top_p: Option<f64>,
     #[schema(example = true)]
     pub stream: Option<bool>,
+    #[schema(example = json!(Option::None::<StreamOptions>))]
+    pub stream_options: Option<StreamOptions>,
+    #[schema(example = json!(Option::None::<ResponseFormat>))]
+    pub response_format: Option<ResponseFormat>,
+    /// Tools the model may call. Calls in the output of a non-streaming request are returned as `tool_calls`.
+    #[schema(example = json!(Option::None::<Vec<Tool>>))]
+    pub tools: Option<Vec<Tool>>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_n_sigma: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    /// Maximum generation time in seconds.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub max_time: Option<f64>,
+    /// Use beam search with this many beams, returning the best `n` of them.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub num_beams: Option<usize>,
+    /// Exponent of the length which the beam logprobs are divided by when ranking beams. Defaults to 1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    /// Use contrastive search with this degeneration penalty between 0 and 1, choosing among the `top_k` most
+    /// likely tokens.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub penalty_alpha: Option<f32>,
+    /// Divide the positive logits of recently seen tokens by this, and multiply their negative logits.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    /// Number of most recent tokens the repetition penalty looks at. Defaults to all tokens, 0 disables it.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repetition_penalty_range: Option<usize>,
+    /// Never repeat an n-gram of this size within the generated text.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+    /// Seed of the sampling RNG, so that repeated requests with the same parameters give the same tokens.
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
+    /// LoRA adapters to run the request with. Requests are only batched with requests with the same adapters.
+    /// Defaults to the adapters of the last adapter activation.
     #[schema(example = json!(Option::None::<Vec<String>>))]
     pub adapters: Option<Vec<String>>,
+    /// Strength of the LoRA adapters, which multiplies their contribution: 0 disables them and 1 is their full
+    /// strength. Defaults to 1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub lora_scale: Option<f32>,
+    /// Requests with a higher priority are scheduled first when the server is busy. Defaults to 0.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub priority: Option<usize>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -108,6 +205,8 @@ pub struct ModelObject {
     pub object: &'static str,
     pub created: u64,
     pub owned_by: &'static str,
+    /// Context length of the model, shared by the prompt and the generated tokens.
+    pub max_model_len: usize,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -122,9 +221,10 @@ pub struct CompletionRequest {
     pub model: String,
     #[schema(example = "Say this is a test.")]
     pub prompt: String,
-    #[serde(default = "default_1usize")]
-    #[schema(example = 1)]
-    pub best_of: usize,
+    /// Generate this many choices and return the `n` with the highest mean logprob. Must be at least `n`, which it
+    /// defaults to. Every candidate is generated, so this costs as much as `best_of` choices.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub best_of: Option<usize>,
     #[serde(rename = "echo")]
     #[serde(default = "default_false")]
     #[schema(example = false)]
@@ -135,6 +235,8 @@ pub struct CompletionRequest {
     pub frequency_penalty: Option<f32>,
     #[schema(example = json!(Option::None::<HashMap<u32, f32>>))]
     pub logit_bias: Option<HashMap<u32, f32>>,
+    /// Return the logprobs of the generated tokens with this many top alternatives. With `echo`, the prompt tokens
+    /// are scored as well.
     #[schema(example = json!(Option::None::<usize>))]
     pub logprobs: Option<usize>,
     #[schema(example = 16)]
@@ -146,6 +248,14 @@ pub struct CompletionRequest {
     #[serde(rename = "stop")]
     #[schema(example = json!(Option::None::<StopTokens>))]
     pub stop_seqs: Option<StopTokens>,
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub stop_token_ids: Option<Vec<u32>>,
+    /// Token ids which end the generation like EOS, replacing the EOS tokens of the model.
+    #[schema(example = json!(Option::None::<Vec<u32>>))]
+    pub eos_token_ids: Option<Vec<u32>>,
+    /// Minimum number of tokens to generate before EOS or a stop token may end the generation.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub min_tokens: Option<usize>,
     #[serde(rename = "stream")]
     pub _stream: Option<bool>,
     #[schema(example = 0.7)]
@@ -160,8 +270,110 @@ pub struct CompletionRequest {
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_n_sigma: Option<f64>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    /// Maximum generation time in seconds.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub max_time: Option<f64>,
+    /// Use beam search with this many beams, returning the best `n` of them.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub num_beams: Option<usize>,
+    /// Exponent of the length which the beam logprobs are divided by when ranking beams. Defaults to 1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    /// Use contrastive search with this degeneration penalty between 0 and 1, choosing among the `top_k` most
+    /// likely tokens.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub penalty_alpha: Option<f32>,
+    /// Divide the positive logits of recently seen tokens by this, and multiply their negative logits.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    /// Number of most recent tokens the repetition penalty looks at. Defaults to all tokens, 0 disables it.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repetition_penalty_range: Option<usize>,
+    /// Never repeat an n-gram of this size within the generated text.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub no_repeat_ngram_size: Option<usize>,
+    /// Seed of the sampling RNG, so that repeated requests with the same parameters give the same tokens.
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
+    /// LoRA adapters to run the request with. Requests are only batched with requests with the same adapters.
+    /// Defaults to the adapters of the last adapter activation.
     #[schema(example = json!(Option::None::<Vec<String>>))]
     pub adapters: Option<Vec<String>>,
+    /// Strength of the LoRA adapters, which multiplies their contribution: 0 disables them and 1 is their full
+    /// strength. Defaults to 1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub lora_scale: Option<f32>,
+    /// Requests with a higher priority are scheduled first when the server is busy. Defaults to 0.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub priority: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Multi(Vec<String>),
+    Single(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingPooling {
+    Mean,
+    LastToken,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingRequest {
+    #[schema(example = "mistral")]
+    pub model: String,
+    #[schema(example = "The food was delicious.")]
+    pub input: EmbeddingInput,
+    /// Only `float` is supported.
+    #[schema(example = json!(Option::None::<String>))]
+    pub encoding_format: Option<String>,
+    #[serde(rename = "user")]
+    pub _user: Option<String>,
+
+    // mistral.rs additional
+    /// How the hidden states are pooled, `mean` (the default) or `last_token`.
+    #[schema(example = json!(Option::None::<EmbeddingPooling>))]
+    pub pooling: Option<EmbeddingPooling>,
+    /// Scale the embeddings to unit L2 norm. Defaults to true.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub normalize: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct TokenizeRequest {
+    #[schema(example = "mistral")]
+    pub model: String,
+    /// Chat messages, formatted with the chat template and the generation prompt as for a chat completion. Exactly
+    /// one of `messages` and `prompt` must be given.
+    #[schema(example = json!(Option::None::<Vec<Message>>))]
+    pub messages: Option<Vec<Message>>,
+    /// Text, tokenized as the prompt of a completion.
+    #[schema(example = "Say this is a test.")]
+    pub prompt: Option<String>,
+    /// Tools offered to the model in the chat template.
+    #[schema(example = json!(Option::None::<Vec<Tool>>))]
+    pub tools: Option<Vec<Tool>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DetokenizeRequest {
+    #[schema(example = "mistral")]
+    pub model: String,
+    #[schema(example = json!(vec![1, 15043]))]
+    pub tokens: Vec<u32>,
+    /// Defaults to true.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub skip_special_tokens: Option<bool>,
 }
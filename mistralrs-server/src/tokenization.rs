@@ -0,0 +1,171 @@
+use std::{error::Error, sync::Arc};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::{
+    chat_completion::{parse_messages, parse_tools},
+    openai::{DetokenizeRequest, TokenizeRequest},
+};
+use anyhow::Result;
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{
+    DetokenizationRequest, DetokenizationResponse, MistralRs, Request, Response,
+    TokenizationPrompt, TokenizationRequest, TokenizationResponse,
+};
+use serde::Serialize;
+
+pub enum TokenizationResponder<T> {
+    Json(T),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+impl<T: Serialize> IntoResponse for TokenizationResponder<T> {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            TokenizationResponder::Json(s) => Json(s).into_response(),
+            TokenizationResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            TokenizationResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+fn parse_request(
+    oairequest: TokenizeRequest,
+    state: Arc<MistralRs>,
+    tx: Sender<Response>,
+) -> Result<Request> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let prompt = match (oairequest.messages, oairequest.prompt) {
+        (Some(messages), None) => {
+            // Images are not loaded, they do not change the tokens of the chat template
+            let (messages, _image_urls) = parse_messages(messages)?;
+            TokenizationPrompt::Chat(messages)
+        }
+        (None, Some(prompt)) => TokenizationPrompt::Text(prompt),
+        _ => anyhow::bail!("Exactly one of `messages` and `prompt` must be given."),
+    };
+    Ok(Request::Tokenize(TokenizationRequest {
+        id: state.next_request_id(),
+        prompt,
+        tools: oairequest.tools.map(parse_tools),
+        response: tx,
+    }))
+}
+
+/// Send a request to the engine and wait for its response.
+async fn send_request(
+    state: Arc<MistralRs>,
+    request: Request,
+    rx: &mut Receiver<Response>,
+) -> Result<Response, Box<dyn Error>> {
+    let sender = state.get_sender().unwrap();
+    if let Err(e) = sender.send(request).await {
+        let e = anyhow::Error::msg(e.to_string());
+        MistralRs::maybe_log_error(state, &*e);
+        return Err(e.into());
+    }
+    match rx.recv().await {
+        Some(response) => Ok(response),
+        None => {
+            let e = anyhow::Error::msg("No response received from the model.");
+            MistralRs::maybe_log_error(state, &*e);
+            Err(e.into())
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/tokenize",
+    request_body = TokenizeRequest,
+    responses((status = 200, description = "Tokens of the prompt"))
+)]
+pub async fn tokenize(
+    State(state): State<Arc<MistralRs>>,
+    Json(oairequest): Json<TokenizeRequest>,
+) -> TokenizationResponder<TokenizationResponse> {
+    let (tx, mut rx) = channel(1);
+    let request = match parse_request(oairequest, state.clone(), tx) {
+        Ok(request) => request,
+        Err(e) => return TokenizationResponder::ValidationError(e.into()),
+    };
+
+    match send_request(state.clone(), request, &mut rx).await {
+        Err(e) => TokenizationResponder::InternalError(e),
+        Ok(Response::InternalError(e)) => {
+            MistralRs::maybe_log_error(state, &*e);
+            TokenizationResponder::InternalError(e)
+        }
+        Ok(Response::ValidationError(e)) => TokenizationResponder::ValidationError(e),
+        Ok(Response::Tokenized(response)) => {
+            MistralRs::maybe_log_response(state, &response);
+            TokenizationResponder::Json(response)
+        }
+        Ok(_) => unreachable!(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/detokenize",
+    request_body = DetokenizeRequest,
+    responses((status = 200, description = "Text of the tokens"))
+)]
+pub async fn detokenize(
+    State(state): State<Arc<MistralRs>>,
+    Json(oairequest): Json<DetokenizeRequest>,
+) -> TokenizationResponder<DetokenizationResponse> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let (tx, mut rx) = channel(1);
+    let request = Request::Detokenize(DetokenizationRequest {
+        id: state.next_request_id(),
+        tokens: oairequest.tokens,
+        skip_special_tokens: oairequest.skip_special_tokens.unwrap_or(true),
+        response: tx,
+    });
+
+    match send_request(state.clone(), request, &mut rx).await {
+        Err(e) => TokenizationResponder::InternalError(e),
+        Ok(Response::InternalError(e)) => {
+            MistralRs::maybe_log_error(state, &*e);
+            TokenizationResponder::InternalError(e)
+        }
+        Ok(Response::ValidationError(e)) => TokenizationResponder::ValidationError(e),
+        Ok(Response::Detokenized(response)) => {
+            MistralRs::maybe_log_response(state, &response);
+            TokenizationResponder::Json(response)
+        }
+        Ok(_) => unreachable!(),
+    }
+}
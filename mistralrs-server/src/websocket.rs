@@ -0,0 +1,279 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::StatusCode,
+    response::IntoResponse,
+};
+use mistralrs_core::{
+    ChatCompletionChunkResponse, EngineHandle, MistralRs, RequestId, Response, Usage,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::channel;
+
+use crate::{chat_completion::parse_request, openai::ChatCompletionRequest};
+
+/// Version of the message schema of the socket, documented in `examples/http.md`. It is sent in every frame, and
+/// frames from the client with another version are rejected.
+const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrameBody {
+    /// Start streaming a chat completion, identified by `id` in the frames about it.
+    Generate {
+        id: String,
+        request: Box<ChatCompletionRequest>,
+    },
+    /// Cancel a running generation.
+    Cancel { id: String },
+}
+
+#[derive(Deserialize)]
+struct ClientFrame {
+    version: Option<u32>,
+    #[serde(flatten)]
+    body: ClientFrameBody,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrameBody {
+    /// A chunk of token deltas of the choices.
+    Delta {
+        id: String,
+        chunk: ChatCompletionChunkResponse,
+    },
+    /// The usage of a finished generation, which is its last frame.
+    Usage { id: String, usage: Usage },
+    /// An error, which ends the generation `id` if set, with the status code of the HTTP endpoint.
+    Error {
+        id: Option<String>,
+        status: u16,
+        message: String,
+    },
+    /// The generation was cancelled, and no more frames about it follow.
+    Cancelled { id: String },
+}
+
+#[derive(Serialize)]
+struct ServerFrame {
+    version: u32,
+    #[serde(flatten)]
+    body: ServerFrameBody,
+}
+
+fn error_frame(id: Option<String>, status: StatusCode, message: String) -> ServerFrameBody {
+    ServerFrameBody::Error {
+        id,
+        status: status.as_u16(),
+        message,
+    }
+}
+
+/// Open a WebSocket over which any number of chat completions are streamed at once, each one cancellable.
+pub async fn websocket(
+    State(state): State<Arc<MistralRs>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, state))
+}
+
+struct Generations {
+    state: Arc<MistralRs>,
+    handle: EngineHandle,
+    ids: HashMap<RequestId, String>,
+}
+
+impl Generations {
+    fn request_id(&self, id: &str) -> Option<RequestId> {
+        self.ids
+            .iter()
+            .find_map(|(request_id, other)| (other == id).then_some(*request_id))
+    }
+
+    async fn handle_frame(&mut self, text: &str) -> Vec<ServerFrameBody> {
+        let frame = match serde_json::from_str::<ClientFrame>(text) {
+            Ok(frame) => frame,
+            Err(e) => {
+                return vec![error_frame(
+                    None,
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("Invalid frame: {e}"),
+                )]
+            }
+        };
+        if frame
+            .version
+            .is_some_and(|version| version != PROTOCOL_VERSION)
+        {
+            return vec![error_frame(
+                None,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Only version {PROTOCOL_VERSION} of the protocol is supported."),
+            )];
+        }
+        match frame.body {
+            ClientFrameBody::Generate { id, request } => {
+                if self.request_id(&id).is_some() {
+                    return vec![error_frame(
+                        Some(id.clone()),
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        format!("A generation with the id `{id}` is already running."),
+                    )];
+                }
+                let mut request = *request;
+                request.stream = Some(true);
+                // Only the usage frame carries the usage
+                request.stream_options = None;
+                // The handle replaces the response channel of the request
+                let (tx, _) = channel(1);
+                let request = match parse_request(request, self.state.clone(), tx).await {
+                    Ok((request, _)) => request,
+                    Err(e) => {
+                        MistralRs::maybe_log_error(self.state.clone(), &*e);
+                        return vec![error_frame(
+                            Some(id),
+                            StatusCode::UNPROCESSABLE_ENTITY,
+                            e.to_string(),
+                        )];
+                    }
+                };
+                match self.handle.add_request(request).await {
+                    Ok(request_id) => {
+                        self.ids.insert(request_id, id);
+                        vec![]
+                    }
+                    Err(e) => {
+                        MistralRs::maybe_log_error(self.state.clone(), &e);
+                        vec![error_frame(
+                            Some(id),
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            e.to_string(),
+                        )]
+                    }
+                }
+            }
+            ClientFrameBody::Cancel { id } => match self.request_id(&id) {
+                Some(request_id) => {
+                    // The engine cancels the sequences of the request at its next step
+                    self.handle.abort(request_id);
+                    self.ids.remove(&request_id);
+                    vec![ServerFrameBody::Cancelled { id }]
+                }
+                None => vec![error_frame(
+                    Some(id.clone()),
+                    StatusCode::NOT_FOUND,
+                    format!("No generation with the id `{id}` is running."),
+                )],
+            },
+        }
+    }
+
+    async fn step(&mut self) -> Vec<ServerFrameBody> {
+        let mut frames = Vec::new();
+        for output in self.handle.step().await {
+            let id = if output.finished {
+                self.ids.remove(&output.id)
+            } else {
+                self.ids.get(&output.id).cloned()
+            }
+            .expect("Every running generation has an id.");
+            match output.response {
+                Response::Chunk(mut chunk) => {
+                    MistralRs::maybe_log_response(self.state.clone(), &chunk);
+                    // The last chunk has the usage, after the last deltas of the choices
+                    let usage = chunk.usage.take();
+                    frames.push(ServerFrameBody::Delta {
+                        id: id.clone(),
+                        chunk,
+                    });
+                    if let Some(usage) = usage {
+                        frames.push(ServerFrameBody::Usage { id, usage });
+                    }
+                }
+                Response::ModelError(msg, _) => {
+                    MistralRs::maybe_log_error(
+                        self.state.clone(),
+                        &*anyhow::Error::msg(msg.clone()),
+                    );
+                    frames.push(error_frame(
+                        Some(id),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        msg,
+                    ));
+                }
+                Response::ValidationError(e) => frames.push(error_frame(
+                    Some(id),
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    e.to_string(),
+                )),
+                Response::Busy(e) => frames.push(error_frame(
+                    Some(id),
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    e.to_string(),
+                )),
+                Response::InternalError(e) => {
+                    MistralRs::maybe_log_error(self.state.clone(), &*e);
+                    frames.push(error_frame(
+                        Some(id),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        e.to_string(),
+                    ));
+                }
+                Response::Done(_)
+                | Response::CompletionModelError(_, _)
+                | Response::CompletionDone(_)
+                | Response::Embeddings(_)
+                | Response::Tokenized(_)
+                | Response::Detokenized(_) => unreachable!(),
+            }
+        }
+        frames
+    }
+}
+
+async fn send_frames(
+    socket: &mut WebSocket,
+    frames: Vec<ServerFrameBody>,
+) -> Result<(), axum::Error> {
+    for body in frames {
+        let frame = ServerFrame {
+            version: PROTOCOL_VERSION,
+            body,
+        };
+        let text = serde_json::to_string(&frame).expect("Serialization of a frame failed.");
+        socket.send(Message::Text(text)).await?;
+    }
+    Ok(())
+}
+
+async fn handle_socket(mut socket: WebSocket, state: Arc<MistralRs>) {
+    let mut generations = Generations {
+        handle: EngineHandle::new(state.clone()),
+        state,
+        ids: HashMap::new(),
+    };
+    loop {
+        let frames = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => generations.handle_frame(&text).await,
+                Some(Ok(Message::Binary(_))) => vec![error_frame(
+                    None,
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Frames must be JSON text.".to_string(),
+                )],
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+            },
+            frames = generations.step(), if generations.handle.num_unfinished_requests() > 0 => frames,
+        };
+        if send_frames(&mut socket, frames).await.is_err() {
+            break;
+        }
+    }
+    // Dropping the handle cancels the generations which are still running
+}
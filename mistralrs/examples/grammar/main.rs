@@ -30,7 +30,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         None,
     )?;
     // Create the MistralRs, which is a runner
-    Ok(MistralRsBuilder::new(pipeline, SchedulerMethod::Fixed(5.try_into().unwrap())).build())
+    Ok(MistralRsBuilder::new(pipeline, SchedulerMethod::Fixed(5.try_into().unwrap())).build()?)
 }
 
 fn main() -> anyhow::Result<()> {
@@ -51,6 +51,9 @@ fn main() -> anyhow::Result<()> {
         constraint: Constraint::Regex("(- [^\n]*\n)+(- [^\n]*)(\n\n)?".to_string()), // Bullet list regex
         suffix: None,
         adapters: None,
+        lora_scale: None,
+        priority: 0,
+        tools: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -30,7 +30,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         Some(GgmlDType::Q4K), // In-situ quantize the model into q4k
     )?;
     // Create the MistralRs, which is a runner
-    Ok(MistralRsBuilder::new(pipeline, SchedulerMethod::Fixed(5.try_into().unwrap())).build())
+    Ok(MistralRsBuilder::new(pipeline, SchedulerMethod::Fixed(5.try_into().unwrap())).build()?)
 }
 
 fn main() -> anyhow::Result<()> {
@@ -51,6 +51,9 @@ fn main() -> anyhow::Result<()> {
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        lora_scale: None,
+        priority: 0,
+        tools: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -37,7 +37,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         None,
     )?;
     // Create the MistralRs, which is a runner
-    Ok(MistralRsBuilder::new(pipeline, SchedulerMethod::Fixed(5.try_into().unwrap())).build())
+    Ok(MistralRsBuilder::new(pipeline, SchedulerMethod::Fixed(5.try_into().unwrap())).build()?)
 }
 
 fn main() -> anyhow::Result<()> {
@@ -58,6 +58,9 @@ fn main() -> anyhow::Result<()> {
         constraint: Constraint::None,
         suffix: None,
         adapters: None,
+        lora_scale: None,
+        priority: 0,
+        tools: None,
     });
 
     // Example: Make adapter_3 the active adapter
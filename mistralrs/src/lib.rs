@@ -34,6 +34,9 @@
 //!         constraint: Constraint::None,
 //!         suffix: None,
 //!         adapters: None,
+//!         lora_scale: None,
+//!         priority: 0,
+//!         tools: None,
 //!     });
 //!     mistralrs.get_sender()?.blocking_send(request)?;
 //!